@@ -0,0 +1,188 @@
+// Ownership and visibility rules for tutors, chat sessions, knowledge base files and study
+// groups. User content has no implicit admin access; support staff go through the audited
+// impersonation flow instead. Visibility only opens up reads; writes stay with the owner.
+
+use candid::Principal;
+use crate::models::tutor::{Tutor, TutorCourse, ChatSession, KnowledgeBaseFile, Visibility};
+use crate::models::study_group::{StudyGroup, GroupMembership};
+use crate::state::{TUTORS, TUTOR_COURSES, CHAT_SESSIONS, KNOWLEDGE_BASE_FILES, STUDY_GROUPS, GROUP_MEMBERSHIPS, CONNECTIONS};
+use crate::rbac::{has_permission, Permission};
+use std::collections::HashSet;
+
+pub trait Owned {
+    const KIND: &'static str;
+    fn owner(&self) -> Principal;
+}
+
+impl Owned for Tutor {
+    const KIND: &'static str = "tutor";
+    fn owner(&self) -> Principal {
+        self.user_id
+    }
+}
+
+impl Owned for ChatSession {
+    const KIND: &'static str = "session";
+    fn owner(&self) -> Principal {
+        self.user_id
+    }
+}
+
+pub trait Shared: Owned {
+    fn visibility(&self) -> Visibility;
+}
+
+impl Shared for Tutor {
+    fn visibility(&self) -> Visibility {
+        self.visibility
+    }
+}
+
+impl Shared for ChatSession {
+    fn visibility(&self) -> Visibility {
+        self.visibility
+    }
+}
+
+impl Owned for KnowledgeBaseFile {
+    const KIND: &'static str = "file";
+    fn owner(&self) -> Principal {
+        self.user_id
+    }
+}
+
+impl Owned for StudyGroup {
+    const KIND: &'static str = "study group";
+    fn owner(&self) -> Principal {
+        self.creator_id
+    }
+}
+
+pub fn ensure_owner<T: Owned>(resource: &T, caller: Principal) -> Result<(), String> {
+    if resource.owner() == caller {
+        Ok(())
+    } else {
+        Err(format!("You don't have permission to access this {}", T::KIND))
+    }
+}
+
+pub fn are_connected(a: Principal, b: Principal) -> bool {
+    CONNECTIONS.with(|connections| {
+        connections.borrow().iter().any(|(_, c)| {
+            c.status == "active" && ((c.user1_id == a && c.user2_id == b) || (c.user1_id == b && c.user2_id == a))
+        })
+    })
+}
+
+pub fn share_active_group(a: Principal, b: Principal) -> bool {
+    GROUP_MEMBERSHIPS.with(|memberships| {
+        let memberships = memberships.borrow();
+        let groups_of = |user: Principal| -> HashSet<u64> {
+            memberships.iter()
+                .map(|(_, m)| m)
+                .filter(|m| m.user_id == user && m.status == "active")
+                .map(|m| m.group_id)
+                .collect()
+        };
+        !groups_of(a).is_disjoint(&groups_of(b))
+    })
+}
+
+pub fn can_view<T: Shared>(resource: &T, caller: Principal) -> bool {
+    let owner = resource.owner();
+    owner == caller || match resource.visibility() {
+        Visibility::Private => false,
+        Visibility::Connections => are_connected(owner, caller),
+        Visibility::Group => share_active_group(owner, caller),
+        Visibility::Public => true,
+    }
+}
+
+// Accepts a public id, slug or legacy id
+pub fn owned_tutor(public_id: &str, caller: Principal) -> Result<(u64, Tutor), String> {
+    let public_id = crate::canonical_public_id("tutor", public_id);
+    let (id, tutor) = crate::cache::tutor_by_public_id(&public_id).ok_or("Tutor not found")?;
+    ensure_owner(&tutor, caller)?;
+    Ok((id, tutor))
+}
+
+// Resources the caller can't see are reported as missing rather than forbidden
+pub fn visible_tutor(public_id: &str, caller: Principal) -> Result<(u64, Tutor), String> {
+    let public_id = crate::canonical_public_id("tutor", public_id);
+    crate::cache::tutor_by_public_id(&public_id)
+        .filter(|(_, tutor)| can_view(tutor, caller))
+        .ok_or_else(|| "Tutor not found".to_string())
+}
+
+pub fn visible_session(session_id: &str, caller: Principal) -> Result<ChatSession, String> {
+    CHAT_SESSIONS.with(|sessions| sessions.borrow().get(&session_id.to_string()))
+        .filter(|session| can_view(session, caller) || session.active_co_learner().is_some_and(|c| c.user_id == caller))
+        .ok_or_else(|| "Session not found".to_string())
+}
+
+// The owner or the active co-learner of a shared session
+pub fn participant_session(session_id: &str, caller: Principal) -> Result<ChatSession, String> {
+    let session = CHAT_SESSIONS.with(|sessions| sessions.borrow().get(&session_id.to_string()))
+        .ok_or("Session not found")?;
+    if session.is_participant(caller) {
+        return Ok(session);
+    }
+    if session.user_id == caller {
+        return Err("You left this session; rejoin it to keep chatting".to_string());
+    }
+    ensure_owner(&session, caller)?;
+    Ok(session)
+}
+
+pub fn owned_session(session_id: &str, caller: Principal) -> Result<ChatSession, String> {
+    let session = CHAT_SESSIONS.with(|sessions| sessions.borrow().get(&session_id.to_string()))
+        .ok_or("Session not found")?;
+    ensure_owner(&session, caller)?;
+    Ok(session)
+}
+
+pub fn owned_kb_file(file_id: u64, caller: Principal) -> Result<KnowledgeBaseFile, String> {
+    let file = KNOWLEDGE_BASE_FILES.with(|files| files.borrow().get(&file_id))
+        .ok_or("Knowledge base file not found")?;
+    ensure_owner(&file, caller)?;
+    Ok(file)
+}
+
+// Courses belong to whoever owns their tutor
+pub fn owned_course(course_id: u64, caller: Principal) -> Result<TutorCourse, String> {
+    let course = TUTOR_COURSES.with(|courses| courses.borrow().get(&course_id)).ok_or("Course not found")?;
+    let tutor = TUTORS.with(|tutors| tutors.borrow().get(&course.tutor_id)).ok_or("Course not found")?;
+    ensure_owner(&tutor, caller)?;
+    Ok(course)
+}
+
+pub fn active_group_membership(group_id: u64, user_id: Principal) -> Option<GroupMembership> {
+    GROUP_MEMBERSHIPS.with(|memberships| {
+        memberships.borrow()
+            .iter()
+            .map(|(_, m)| m)
+            .find(|m| m.group_id == group_id && m.user_id == user_id && m.status == "active")
+    })
+}
+
+// Public groups are visible to everyone, private ones to their members and moderators
+pub fn can_view_group(group: &StudyGroup, caller: Principal) -> bool {
+    !group.is_private || active_group_membership(group.id, caller).is_some() || has_permission(caller, Permission::ModerateContent)
+}
+
+pub fn can_manage_group(group: &StudyGroup, caller: Principal) -> bool {
+    group.creator_id == caller
+        || has_permission(caller, Permission::ModerateContent)
+        || active_group_membership(group.id, caller).is_some_and(|m| m.role == "admin" || m.role == "moderator")
+}
+
+pub fn visible_group(group_id: u64, caller: Principal) -> Result<StudyGroup, String> {
+    STUDY_GROUPS.with(|groups| groups.borrow().get(&group_id))
+        .filter(|group| can_view_group(group, caller))
+        .ok_or_else(|| "Study group not found.".to_string())
+}
+
+pub fn ensure_group_member(group_id: u64, caller: Principal) -> Result<GroupMembership, String> {
+    active_group_membership(group_id, caller)
+        .ok_or_else(|| "You must be an active member of this group".to_string())
+}
@@ -0,0 +1,190 @@
+// Bounded heap caches for small records that are read on almost every call: users, tutors
+// looked up by public id, and the canister config. Stable memory stays the source of truth;
+// every write goes through the store_/remove_ helpers here, which update stable memory and
+// drop the cached copy. The caches start empty after an upgrade and fill as records are
+// read. Heap changes made during a query are discarded, so only update calls warm them.
+
+use candid::Principal;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use crate::models::cache::CacheStats;
+use crate::models::config::CanisterConfig;
+use crate::models::tutor::Tutor;
+use crate::models::user::User;
+use crate::state::{CONFIG, TUTORS, USERS};
+
+const USER_CACHE_CAPACITY: usize = 1024;
+const TUTOR_CACHE_CAPACITY: usize = 512;
+
+pub struct LruCache<K, V> {
+    name: &'static str,
+    capacity: usize,
+    entries: HashMap<K, (V, u64)>,
+    recency: BTreeMap<u64, K>, // last use -> key, oldest first
+    tick: u64,
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+    invalidations: u64,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> LruCache<K, V> {
+    pub fn new(name: &'static str, capacity: usize) -> Self {
+        LruCache {
+            name,
+            capacity,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            tick: 0,
+            hits: 0,
+            misses: 0,
+            evictions: 0,
+            invalidations: 0,
+        }
+    }
+
+    fn touch(&mut self, key: &K) {
+        self.tick += 1;
+        if let Some((_, used)) = self.entries.get_mut(key) {
+            self.recency.remove(used);
+            *used = self.tick;
+            self.recency.insert(self.tick, key.clone());
+        }
+    }
+
+    pub fn get(&mut self, key: &K) -> Option<V> {
+        if self.entries.contains_key(key) {
+            self.hits += 1;
+            self.touch(key);
+            self.entries.get(key).map(|(value, _)| value.clone())
+        } else {
+            self.misses += 1;
+            None
+        }
+    }
+
+    pub fn insert(&mut self, key: K, value: V) {
+        self.tick += 1;
+        if let Some((_, used)) = self.entries.insert(key.clone(), (value, self.tick)) {
+            self.recency.remove(&used);
+        } else if self.entries.len() > self.capacity {
+            if let Some((_, oldest)) = self.recency.pop_first() {
+                self.entries.remove(&oldest);
+                self.evictions += 1;
+            }
+        }
+        self.recency.insert(self.tick, key);
+    }
+
+    pub fn remove(&mut self, key: &K) {
+        if let Some((_, used)) = self.entries.remove(key) {
+            self.recency.remove(&used);
+            self.invalidations += 1;
+        }
+    }
+
+    pub fn retain<F: Fn(&K, &V) -> bool>(&mut self, keep: F) {
+        let stale: Vec<K> = self.entries.iter().filter(|(k, (v, _))| !keep(k, v)).map(|(k, _)| k.clone()).collect();
+        for key in stale {
+            self.remove(&key);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.invalidations += self.entries.len() as u64;
+        self.entries.clear();
+        self.recency.clear();
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            name: self.name.to_string(),
+            capacity: self.capacity as u64,
+            entries: self.entries.len() as u64,
+            hits: self.hits,
+            misses: self.misses,
+            evictions: self.evictions,
+            invalidations: self.invalidations,
+        }
+    }
+}
+
+thread_local! {
+    static USER_CACHE: RefCell<LruCache<Principal, User>> = RefCell::new(LruCache::new("users", USER_CACHE_CAPACITY));
+    // Keyed by public id, the way tutors are looked up; the value carries the stable map key
+    static TUTOR_CACHE: RefCell<LruCache<String, (u64, Tutor)>> = RefCell::new(LruCache::new("tutors", TUTOR_CACHE_CAPACITY));
+    static CONFIG_CACHE: RefCell<LruCache<(), CanisterConfig>> = RefCell::new(LruCache::new("config", 1));
+}
+
+pub fn user(user_id: Principal) -> Option<User> {
+    if let Some(user) = USER_CACHE.with(|cache| cache.borrow_mut().get(&user_id)) {
+        return Some(user);
+    }
+    let user = USERS.with(|users| users.borrow().get(&user_id))?;
+    USER_CACHE.with(|cache| cache.borrow_mut().insert(user_id, user.clone()));
+    Some(user)
+}
+
+pub fn store_user(user: User) {
+    USER_CACHE.with(|cache| cache.borrow_mut().remove(&user.id));
+    USERS.with(|users| users.borrow_mut().insert(user.id, user));
+}
+
+pub fn remove_user(user_id: Principal) -> Option<User> {
+    USER_CACHE.with(|cache| cache.borrow_mut().remove(&user_id));
+    USERS.with(|users| users.borrow_mut().remove(&user_id))
+}
+
+pub fn tutor_by_public_id(public_id: &str) -> Option<(u64, Tutor)> {
+    let key = public_id.to_string();
+    if let Some(entry) = TUTOR_CACHE.with(|cache| cache.borrow_mut().get(&key)) {
+        return Some(entry);
+    }
+    let entry = TUTORS.with(|tutors| tutors.borrow().iter().find(|(_, t)| t.public_id == public_id))?;
+    TUTOR_CACHE.with(|cache| cache.borrow_mut().insert(key, entry.clone()));
+    Some(entry)
+}
+
+// A tutor's public id can change, so entries are dropped by stable key rather than by public id
+fn invalidate_tutor(id: u64) {
+    TUTOR_CACHE.with(|cache| cache.borrow_mut().retain(|_, (cached_id, _)| *cached_id != id));
+}
+
+pub fn store_tutor(id: u64, tutor: Tutor) {
+    invalidate_tutor(id);
+    TUTORS.with(|tutors| tutors.borrow_mut().insert(id, tutor));
+}
+
+pub fn remove_tutor(id: u64) -> Option<Tutor> {
+    invalidate_tutor(id);
+    TUTORS.with(|tutors| tutors.borrow_mut().remove(&id))
+}
+
+pub fn config() -> CanisterConfig {
+    if let Some(config) = CONFIG_CACHE.with(|cache| cache.borrow_mut().get(&())) {
+        return config;
+    }
+    let config = CONFIG.with(|config| config.borrow().get().clone());
+    CONFIG_CACHE.with(|cache| cache.borrow_mut().insert((), config.clone()));
+    config
+}
+
+pub fn store_config(updated: CanisterConfig) -> Result<(), String> {
+    CONFIG_CACHE.with(|cache| cache.borrow_mut().remove(&()));
+    CONFIG.with(|config| config.borrow_mut().set(updated).map(|_| ()).map_err(|_| "Failed to store config".to_string()))
+}
+
+pub fn stats() -> Vec<CacheStats> {
+    vec![
+        USER_CACHE.with(|cache| cache.borrow().stats()),
+        TUTOR_CACHE.with(|cache| cache.borrow().stats()),
+        CONFIG_CACHE.with(|cache| cache.borrow().stats()),
+    ]
+}
+
+pub fn clear() {
+    USER_CACHE.with(|cache| cache.borrow_mut().clear());
+    TUTOR_CACHE.with(|cache| cache.borrow_mut().clear());
+    CONFIG_CACHE.with(|cache| cache.borrow_mut().clear());
+}
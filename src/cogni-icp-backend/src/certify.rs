@@ -0,0 +1,158 @@
+// Certifies a hash of each user's chat messages so a client can check that a query reply
+// from an untrusted boundary node matches canister state. All writes to CHAT_MESSAGES go
+// through store_messages/remove_messages, which update the owner's hash and the certified
+// root. Hashes, all SHA-256, with lengths as u64 big-endian:
+//
+//   message      = len|id, len|sender, len|content, len|delivery_status, timestamp
+//   session      = H(message, message, ...) in stored order
+//   user root    = H(len|session_id, session, ...) sorted by session id
+//   bucket       = first byte of H(principal bytes)
+//   bucket hash  = H(len|principal text, user root, ...) sorted by principal text
+//   certified    = H(bucket hash 0, ..., bucket hash 255)
+
+use candid::Principal;
+use sha2::{Digest, Sha256};
+use crate::models::certification::{SessionDigest, UserDataHash, BucketEntry, UserDataProof};
+use crate::models::tutor::{ChatMessage, ChatMessageList};
+use crate::state::{CHAT_MESSAGES, USER_DATA_HASHES, CERTIFIED_BUCKETS};
+
+const BUCKETS: usize = 256;
+
+fn put(hasher: &mut Sha256, bytes: &[u8]) {
+    hasher.update((bytes.len() as u64).to_be_bytes());
+    hasher.update(bytes);
+}
+
+pub fn session_digest(messages: &[ChatMessage]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    for message in messages {
+        let mut entry = Sha256::new();
+        put(&mut entry, message.id.as_bytes());
+        put(&mut entry, message.sender.as_bytes());
+        put(&mut entry, message.content.as_bytes());
+        put(&mut entry, message.delivery_status.as_bytes());
+        entry.update(message.timestamp.to_be_bytes());
+        hasher.update(entry.finalize());
+    }
+    hasher.finalize().to_vec()
+}
+
+fn user_root(sessions: &[SessionDigest]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    for session in sessions {
+        put(&mut hasher, session.session_id.as_bytes());
+        hasher.update(&session.digest);
+    }
+    hasher.finalize().to_vec()
+}
+
+pub fn bucket_of(user_id: Principal) -> u8 {
+    Sha256::digest(user_id.as_slice())[0]
+}
+
+fn hash_key(user_id: Principal) -> String {
+    format!("{:02x}:{}", bucket_of(user_id), user_id)
+}
+
+fn bucket_entries(bucket: u8) -> Vec<BucketEntry> {
+    let start = format!("{:02x}:", bucket);
+    let end = format!("{:02x};", bucket); // ';' sorts right after ':'
+    USER_DATA_HASHES.with(|hashes| {
+        hashes.borrow().range(start..end).map(|(_, h)| BucketEntry { user_id: h.user_id, root: h.root }).collect()
+    })
+}
+
+fn bucket_hash(entries: &[BucketEntry]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    for entry in entries {
+        put(&mut hasher, entry.user_id.to_text().as_bytes());
+        hasher.update(&entry.root);
+    }
+    hasher.finalize().to_vec()
+}
+
+fn bucket_hashes() -> Vec<Vec<u8>> {
+    let empty = bucket_hash(&[]);
+    CERTIFIED_BUCKETS.with(|buckets| {
+        let buckets = buckets.borrow();
+        (0..BUCKETS).map(|b| buckets.get(&(b as u8)).unwrap_or_else(|| empty.clone())).collect()
+    })
+}
+
+fn certified_root() -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    for hash in bucket_hashes() {
+        hasher.update(hash);
+    }
+    hasher.finalize().to_vec()
+}
+
+// Run after an upgrade so the certified root always matches the stored hashes
+pub fn restore_certified_data() {
+    ic_cdk::api::set_certified_data(&certified_root());
+}
+
+fn update_user_hash(user_id: Principal, session_id: &str, digest: Option<Vec<u8>>) {
+    let key = hash_key(user_id);
+    let mut hash = USER_DATA_HASHES.with(|hashes| hashes.borrow().get(&key)).unwrap_or(UserDataHash {
+        user_id,
+        sessions: Vec::new(),
+        root: Vec::new(),
+        updated_at: 0,
+    });
+    hash.sessions.retain(|s| s.session_id != session_id);
+    if let Some(digest) = digest {
+        let index = hash.sessions.partition_point(|s| s.session_id.as_str() < session_id);
+        hash.sessions.insert(index, SessionDigest { session_id: session_id.to_string(), digest });
+    }
+    hash.root = user_root(&hash.sessions);
+    hash.updated_at = ic_cdk::api::time();
+    USER_DATA_HASHES.with(|hashes| {
+        let mut hashes = hashes.borrow_mut();
+        if hash.sessions.is_empty() {
+            hashes.remove(&key);
+        } else {
+            hashes.insert(key, hash);
+        }
+    });
+
+    let bucket = bucket_of(user_id);
+    let bucket_hash = bucket_hash(&bucket_entries(bucket));
+    CERTIFIED_BUCKETS.with(|buckets| buckets.borrow_mut().insert(bucket, bucket_hash));
+    ic_cdk::api::set_certified_data(&certified_root());
+}
+
+pub fn store_messages(user_id: Principal, session_id: &str, list: ChatMessageList) {
+    let digest = session_digest(&list.0);
+    CHAT_MESSAGES.with(|messages| messages.borrow_mut().insert(session_id.to_string(), list));
+    update_user_hash(user_id, session_id, Some(digest));
+}
+
+pub fn remove_messages(user_id: Principal, session_id: &str) -> Option<ChatMessageList> {
+    let removed = CHAT_MESSAGES.with(|messages| messages.borrow_mut().remove(&session_id.to_string()));
+    update_user_hash(user_id, session_id, None);
+    removed
+}
+
+// Recomputes a session's digest from stored messages, for data written before hashing existed
+pub fn rehash_session(user_id: Principal, session_id: &str) {
+    let digest = CHAT_MESSAGES.with(|messages| messages.borrow().get(&session_id.to_string())).map(|list| session_digest(&list.0));
+    update_user_hash(user_id, session_id, digest);
+}
+
+pub fn user_proof_has_session(user_id: Principal, session_id: &str) -> bool {
+    USER_DATA_HASHES.with(|hashes| hashes.borrow().get(&hash_key(user_id)))
+        .is_some_and(|hash| hash.sessions.iter().any(|s| s.session_id == session_id))
+}
+
+// Everything a client needs to go from a session's messages to the certified root
+pub fn user_proof(user_id: Principal) -> UserDataProof {
+    let bucket = bucket_of(user_id);
+    UserDataProof {
+        user_hash: USER_DATA_HASHES.with(|hashes| hashes.borrow().get(&hash_key(user_id))),
+        bucket,
+        bucket_entries: bucket_entries(bucket),
+        bucket_hashes: bucket_hashes(),
+        certificate: ic_cdk::api::data_certificate(),
+    }
+}
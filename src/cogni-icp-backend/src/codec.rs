@@ -0,0 +1,48 @@
+// Versioned encoding for the largest and most frequently written records. Compact records are
+// a version byte followed by packed CBOR, which keys struct fields by position instead of by
+// name. Records written before this are plain CBOR; they always start with a map or array
+// header, never with a byte below 0x20, so the two can be told apart on read.
+//
+// Packed fields are matched by declaration order: new fields go at the end of a struct with
+// #[serde(default)], and existing fields must not be removed or reordered.
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::cell::Cell;
+
+pub const COMPACT_V1: u8 = 1;
+pub const CURRENT_VERSION: u8 = COMPACT_V1;
+
+// Below the first CBOR map/array header
+const MAX_VERSION_BYTE: u8 = 0x1f;
+
+thread_local! {
+    static LEGACY_READS: Cell<u64> = const { Cell::new(0) };
+}
+
+pub fn encode<T: Serialize>(value: &T) -> Vec<u8> {
+    let mut bytes = vec![CURRENT_VERSION];
+    let mut serializer = serde_cbor::Serializer::new(serde_cbor::ser::IoWrite::new(&mut bytes)).packed_format();
+    value.serialize(&mut serializer).unwrap();
+    bytes
+}
+
+pub fn decode<T: DeserializeOwned>(bytes: &[u8]) -> T {
+    match bytes.first() {
+        Some(&COMPACT_V1) => serde_cbor::from_slice(&bytes[1..]).unwrap(),
+        Some(&version) if version <= MAX_VERSION_BYTE => panic!("Unsupported record encoding version {}", version),
+        _ => {
+            LEGACY_READS.with(|reads| reads.set(reads.get() + 1));
+            serde_cbor::from_slice(bytes).unwrap()
+        }
+    }
+}
+
+pub fn legacy_encode<T: Serialize>(value: &T) -> Vec<u8> {
+    serde_cbor::to_vec(value).unwrap()
+}
+
+// Legacy records decoded since the last upgrade; drops to zero once re-encoding finishes
+pub fn legacy_reads() -> u64 {
+    LEGACY_READS.with(|reads| reads.get())
+}
@@ -0,0 +1,78 @@
+// Keeps list and export endpoints inside the IC's execution limits. A reply over the message
+// size limit traps while it is being encoded, so responses are estimated first and rejected
+// with a PayloadTooLarge error that says how to page instead. Long scans check the
+// instruction counter as they go and stop with QueryTooExpensive before the limit is hit.
+
+use serde::Serialize;
+
+// Replies are capped at 2 MiB; the rest is headroom for candid's type table and the envelope
+pub const MAX_RESPONSE_BYTES: usize = 1_800_000;
+// Queries may run 5B instructions; leave room to build and encode the reply
+const SCAN_INSTRUCTION_BUDGET: u64 = 4_000_000_000;
+// Reading the counter is cheap but not free, so scans only check it this often
+const SCAN_CHECK_INTERVAL: usize = 256;
+
+struct ByteCounter(usize);
+
+impl std::io::Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// Encoded size of a value without allocating it. CBOR is close enough to candid for stored
+// records, which is all this is used on.
+pub fn estimated_size<T: Serialize>(value: &T) -> usize {
+    let mut counter = ByteCounter(0);
+    let _ = serde_cbor::to_writer(&mut counter, value);
+    counter.0
+}
+
+pub fn payload_too_large(estimated_bytes: usize, fitting_items: usize, hint: &str) -> String {
+    format!(
+        "PayloadTooLarge: the response would be about {} KB, over the {} KB limit; about {} items fit in one reply. {}",
+        estimated_bytes / 1024,
+        MAX_RESPONSE_BYTES / 1024,
+        fitting_items,
+        hint
+    )
+}
+
+pub fn ensure_fits<T: Serialize>(items: &[T], hint: &str) -> Result<(), String> {
+    let mut total = 0;
+    let mut fitting = None;
+    for (index, item) in items.iter().enumerate() {
+        total += estimated_size(item);
+        if total > MAX_RESPONSE_BYTES && fitting.is_none() {
+            fitting = Some(index);
+        }
+    }
+    match fitting {
+        Some(fitting) => Err(payload_too_large(total, fitting, hint)),
+        None => Ok(()),
+    }
+}
+
+// For responses that are already a single blob, such as JSON exports
+pub fn ensure_bytes_fit(len: usize, items: usize, hint: &str) -> Result<(), String> {
+    if len <= MAX_RESPONSE_BYTES {
+        return Ok(());
+    }
+    Err(payload_too_large(len, items * MAX_RESPONSE_BYTES / len, hint))
+}
+
+// Call with the number of items scanned so far
+pub fn scan_checkpoint(scanned: usize, hint: &str) -> Result<(), String> {
+    if !scanned.is_multiple_of(SCAN_CHECK_INTERVAL) || ic_cdk::api::performance_counter(0) < SCAN_INSTRUCTION_BUDGET {
+        return Ok(());
+    }
+    Err(format!(
+        "QueryTooExpensive: stopped after scanning {} records to stay within the instruction limit. {}",
+        scanned, hint
+    ))
+}
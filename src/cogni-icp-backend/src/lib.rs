@@ -1,59 +1,159 @@
 mod models;
 mod state;
+mod authz;
+mod time;
+mod guards;
+mod cache;
+mod codec;
+mod certify;
+mod password;
+mod rate_limit;
+mod rbac;
 
-use models::user::{User, UserSettings};
-use models::tutor::{Tutor, ChatSession, ChatMessage, ChatMessageList, LearningProgress, LearningMetrics, ModuleCompletion, KnowledgeBaseFile, CourseOutline, ComprehensionAnalysis, TopicSuggestion, TopicValidation};
-use state::{USERS, TUTORS, CHAT_SESSIONS, CHAT_MESSAGES, LEARNING_PROGRESS, LEARNING_METRICS, MODULE_COMPLETIONS, KNOWLEDGE_BASE_FILES, next_id};
+use models::user::{User, UserSettings, DailyGoalProgress};
+use models::xapi::{LrsConfig, XapiOutboxEntry, LrsOutboxStatus};
+use models::guest::{GuestConfig, GuestSession};
+use models::invite::{RegistrationConfig, InviteCode, WaitlistEntry};
+use models::undo::{UndoAction, UndoSnapshot, StagedAction};
+use models::cache::CacheStats;
+use models::certification::{CertifiedSessionMessages, UserDataProof};
+use models::encoding::{EncodingProgress, EncodingStatus, EncodingBenchmark};
+use state::REENCODE_PROGRESS;
+use state::UNDO_QUEUE;
+use state::{LOW_CONFIDENCE_REPLIES, FACT_CHECKS};
+use models::confidence::LowConfidenceReply;
+use models::fact_check::{FactCheckRecord, FactCheckVerdict};
+use state::{INVITE_CODES, WAITLIST};
+use models::curriculum::{CourseAttribution, ImportIssue, CurriculumImportReport};
+use models::tutor::{Tutor, TutorCourse, CourseModule, ChatSession, CoLearner, ChatMessage, ChatMessageList, IntakeAnswer, Visibility, LearningProgress, LearningMetrics, ModuleCompletion, KnowledgeBaseFile, KnowledgeBaseLink, TutorKnowledgeBaseEntry, KnowledgeBaseFileVersion, KnowledgeBaseVersionPin, KnowledgeBaseReindexPlan, KnowledgeBaseExcerpt, MessageCitation, CourseOutline, ComprehensionAnalysis, TopicSuggestion, TopicValidation};
+use state::{USERS, TUTORS, TUTOR_SESSIONS, TUTOR_COURSES, XAPI_OUTBOX, GUEST_SESSIONS, CHAT_SESSIONS, CHAT_MESSAGES, LEARNING_PROGRESS, LEARNING_METRICS, MODULE_COMPLETIONS, KNOWLEDGE_BASE_FILES, KNOWLEDGE_BASE_LINKS, KNOWLEDGE_BASE_FILE_VERSIONS, next_id};
 use std::collections::HashMap;
 use models::connections::{UserConnection, ConnectionRequest};
 use state::{CONNECTIONS, CONNECTION_REQUESTS};
-use candid::Principal;
+use candid::{Nat, Principal};
 use models::study_group::{StudyGroup, GroupMembership};
 use state::{STUDY_GROUPS, GROUP_MEMBERSHIPS};
-use models::gamification::{Task, UserTaskCompletion};
+use time::{NANOS_PER_DAY, iso8601, parse_iso8601, parse_utc_offset, user_offset_ns, local_day, local_day_start, next_local_midnight};
+use guards::{MAX_RESPONSE_BYTES, estimated_size, ensure_fits, ensure_bytes_fit, scan_checkpoint};
+use rbac::{Role, Permission, InitArgs, ROLE_NAMES, role_of, has_permission, require};
+use authz::{are_connected, can_view, owned_tutor, owned_course, visible_tutor, owned_session, visible_session, participant_session, owned_kb_file, active_group_membership, can_view_group, can_manage_group, visible_group, ensure_group_member};
+use models::gamification::{Task, UserTaskCompletion, Achievement, UserAchievement};
 use state::{TASKS, USER_TASK_COMPLETIONS};
 use ic_stable_structures::{StableBTreeMap, memory_manager::MemoryId};
 use std::cell::RefCell;
 use serde_json::json;
-use ic_cdk::api::management_canister::http_request::{http_request, CanisterHttpRequestArgument, HttpMethod, HttpResponse, TransformArgs};
-
-// Simple password hashing (in production, use proper crypto)
-fn hash_password(password: &str) -> String {
-    use std::collections::hash_map::DefaultHasher;
-    use std::hash::{Hash, Hasher};
-    let mut hasher = DefaultHasher::new();
-    password.hash(&mut hasher);
-    format!("{:x}", hasher.finish())
-}
-
-// Generate a secure random string ID
-fn generate_secure_id() -> String {
-    use std::collections::hash_map::DefaultHasher;
-    use std::hash::{Hash, Hasher};
-    
-    // Use current time and a random component to generate a unique ID
-    let timestamp = ic_cdk::api::time();
-    let mut hasher = DefaultHasher::new();
-    timestamp.hash(&mut hasher);
-    
-    // Add some randomness by hashing the timestamp multiple times
-    let hash1 = hasher.finish();
-    let mut hasher2 = DefaultHasher::new();
-    hash1.hash(&mut hasher2);
-    let hash2 = hasher2.finish();
-    
-    // Convert to a base36 string for better readability and shorter length
-    format!("tutor_{:x}", hash2)
-}
-
-fn verify_password(password: &str, hash: &str) -> bool {
-    hash_password(password) == hash
-}
+use ic_cdk::api::management_canister::http_request::{CanisterHttpRequestArgument, HttpHeader, HttpMethod, TransformArgs, TransformContext};
+use models::placement::{PlacementTest, PlacementItem, PlacementQuestion, PlacementResult};
+use state::PLACEMENT_TESTS;
+use models::certificate::{Certificate, CertificateVerification};
+use models::http::{HttpRequest, HttpResponse};
+use state::{CERTIFICATES, CERTIFICATE_SIGNING_KEY};
+use models::announcements::{Announcement, AnnouncementDismissal};
+use state::{ANNOUNCEMENTS, ANNOUNCEMENT_DISMISSALS};
+use models::notifications::{Notification, NotificationConfig, NotificationLedger, DigestItem, SourceCount};
+use models::billing::{PaymentTransaction, GiftSubscription, PlanGrant, TrialRecord};
+use models::billing::{SubscriptionPlan, RegionalPrice, BillingConfig, InvoiceTax, PlanPrice, RegionRevenue};
+use state::{SUBSCRIPTION_PLANS, PAYMENT_TRANSACTIONS, GIFT_SUBSCRIPTIONS, PLAN_GRANTS, TRIAL_HISTORY};
+use models::educator::{EducatorVerification, EducatorConfig, EducatorStatus, TutorListing};
+use state::EDUCATOR_VERIFICATIONS;
+use models::creator::{CreatorUsage, CreatorPayout, CreatorRevenueConfig, CreatorEarnings};
+use models::ledger::{Account, TransferArg, TransferError, TransferFromArgs, TransferFromError};
+use state::{CREATOR_USAGE, CREATOR_PAYOUTS};
+use models::experiment::{Experiment, ExperimentVariant, ExperimentAssignment, VariantResult, ExperimentResults};
+use state::{EXPERIMENTS, EXPERIMENT_ASSIGNMENTS};
+use models::history_import::HistoryImportReport;
+use models::email::{EmailConfig, TutorEmailAddress, EmailExchange, InboundEmail};
+use state::{TUTOR_EMAIL_ADDRESSES, EMAIL_EXCHANGES, EMAIL_QUEUE, EMAIL_EXPIRY};
+use models::bot_bridge::{BotBridge, BotLinkCode, BotLink, BotThread, BridgeUpdate};
+use state::{BOT_BRIDGES, BOT_LINK_CODES, BOT_LINKS, BOT_THREADS};
+use models::public_api::{API_SCOPES, MAX_EXTERNAL_USER_BATCH, ApiToken, ApiUsageDay, ExternalApiConfig, ExternalUserInput};
+use state::{API_TOKENS, API_USAGE};
+use models::embed::{EmbedToken, EmbedUsageDay};
+use state::{EMBED_TOKENS, EMBED_USAGE};
+use models::license::{LICENSES, AttributionEntry, license_terms, license_name};
+use models::refund::{RefundConfig, RefundRequest};
+use state::REFUND_REQUESTS;
+use models::study_pack::{StudyPack, Flashcard};
+use state::{STUDY_PACKS, STUDY_PACK_CHUNKS};
+use models::concept_map::{ConceptMap, ConceptNode, ConceptEdge};
+use state::CONCEPT_MAPS;
+use models::glossary::GlossaryEntry;
+use state::COURSE_GLOSSARY;
+use state::AI_DIAGNOSTICS;
+use models::read_position::{ReadCursor, ReadPositions};
+use state::READ_CURSORS;
+use models::auth_session::{AuthSession, PrincipalRekey, SessionLogin};
+use state::SESSIONS;
+use models::chat_signal::{ChatSignals, ReplySignal};
+use models::study_group::flashcards::{FlashcardDeck, DeckCard, CardSchedule, DueCard, HardCard, DeckStats};
+use state::{FLASHCARD_DECKS, DECK_CARDS, CARD_SCHEDULES};
+use models::event::{SeasonalEvent, SeasonalEventSpec, EventParticipation, EventLeaderboardEntry, EventLeaderboard};
+use state::{SEASONAL_EVENTS, EVENT_PARTICIPATION, ACHIEVEMENTS, USER_ACHIEVEMENTS};
+use models::support::{SupportTicket, TicketMessage, SupportMetrics};
+use state::{NOTIFICATIONS, SUPPORT_TICKETS};
+use models::feedback::FeedbackItem;
+use state::FEEDBACK_ITEMS;
+use models::config::{CanisterConfig, RetentionPolicy, RetentionHold};
+use models::retention::{MetricsAggregate, RetentionRunReport, CompactionRunReport, AccountDeletionSummary};
+use state::METRICS_AGGREGATES;
+use models::config::{PlanLimits, ResponseProcessingConfig, OutcallBudget, SessionArchivalConfig, ConfidenceConfig, TrialConfig};
+use models::storage::{StorageUsage, StorageUsageReport, MemoryLayout};
+use state::STORAGE_USAGE;
+use models::sharding::{ShardingConfig, ShardInfo, ShardRoute, UserDataBundle, MigrationReport};
+use state::USER_SHARDS;
+use models::delivery::{PendingDelivery, ReplySource, AsyncReplyConfig};
+use state::PENDING_DELIVERIES;
+use models::preview::{CoursePreview, TutorPersonaPreview};
+use models::quota::{QuotaStatus, AiConcurrencyStatus, PlanUsage, PlanUpgrade};
+use state::{AI_CALL_COUNTS, USER_SUBSCRIPTIONS};
+use models::audit::{AuditEntry, ImpersonationSession};
+use state::{AUDIT_LOG, IMPERSONATION_SESSIONS};
+use models::ai_providers::{AiProviderConfig, AiProviderHealth, AiProviderStatus, CircuitBreakerSettings, ModelParams, ModelDefaults, AiCallDiagnostic, AiLatencyStats, DegradationSettings, ServiceStatus};
+use models::study_group::activity::{StudyResource, SessionPublishDraft};
+use state::STUDY_RESOURCES;
+use models::mastery::{SkillProficiency, ReviewQuiz, ReviewQuestion, ReviewResult, ReviewReminder, ReviewReminderConfig};
+use state::{SKILL_PROFICIENCY, REVIEW_REMINDERS};
+use models::oauth::OAuthConfig;
+use state::OAUTH_IDENTITIES;
+use state::{NOTIFICATION_LEDGERS, PUSH_QUEUE};
+use models::topic_drift::{TopicDriftConfig, DriftDecision};
+use models::rate_limit::RateLimitConfig;
+use models::persona_eval::{PersonaEvalConfig, PersonaEvaluation, PersonaConsistencyReport, RevisionScore};
+use state::PERSONA_EVALUATIONS;
+use models::daily_plan::{DailyPlan, PlanItem, PlanFraming};
+use state::PLAN_FRAMINGS;
+use models::milestone::{Milestone, AchievementCard};
+use models::activity::{ActivityPost, ActivityReaction, ActivityFeedPage};
+use state::{MILESTONES, ACTIVITY_POSTS};
+use models::login_attempt::{LoginLockoutConfig, LoginAttempts, LoginAttempt};
+use state::LOGIN_ATTEMPTS;
+use models::focus::{FocusConfig, FocusSession, FocusWeek, FocusStats};
+use state::{FOCUS_SESSIONS, FOCUS_WEEKS};
+use models::daily_question::{DailyQuestionConfig, DailyQuestion, DailyAnswer, DailyQuestionStats, DailyQuestionView, DailyQuestionLeaderboardEntry, DailyQuestionLeaderboard};
+use state::{DAILY_QUESTIONS, DAILY_ANSWERS, DAILY_QUESTION_STATS};
+use models::peer_tutoring::{PeerTutoringConfig, PeerTutorProfile, PeerSlotSpec, PeerSlot, PeerDispute, PeerBooking};
+use state::{PEER_TUTORS, PEER_SLOTS, PEER_BOOKINGS};
+use models::data_export::{DataExport, DataExportCursor, ExportedMessages};
+use models::organization::{MAX_ORG_DOMAINS, MAX_ORG_MEMBER_BATCH, ORG_IMPORT_ROLES, Organization, OrgMember, OrgMemberInput, OrgMemberResult, OrgMemberView, OrgImport, OrgImportRow};
+use state::{ORGANIZATIONS, ORG_MEMBERS, ORG_IMPORTS};
+use models::learner_risk::{LearnerRiskConfig, LearnerRiskFlag, LearnerRiskView, LearnerRiskStats};
+use state::LEARNER_RISK_FLAGS;
+use state::{PRINCIPAL_REKEYS, StableMemory};
+use models::org_report::{ORG_REPORT_SCOPES, OrgLearnerReport, OrgProgressReport, OrgModuleProgress, OrgReportCsvChunk};
+use models::user::{QuietHours, NotificationPreferences};
+use models::exam::{Exam, ExamSection, ExamQuestion, ExamFlag, ExamSectionSpec, ExamView, ExamQuestionView, ExamSectionScore, ExamResult};
+use state::EXAMS;
+use models::cohort::{Cohort, CohortModule, CohortEnrollment, CohortModuleView, DiscussionPost, DiscussionThread, DiscussionPage, CohortModuleStats, CohortStats};
+use state::{COHORTS, COHORT_ENROLLMENTS, DISCUSSION_POSTS};
+use models::tagging::{TaggingConfig, EntityTags, EntityTag};
+use state::{ENTITY_TAGS, TAGGING_QUEUE};
+use models::alias::IdAlias;
+use state::ID_ALIASES;
 
 #[ic_cdk::query]
 fn get_self() -> Option<User> {
     let principal = ic_cdk::caller();
-    USERS.with(|users| users.borrow().get(&principal))
+    cache::user(principal)
 }
 
 #[ic_cdk::update]
@@ -71,8 +171,18 @@ fn create_user(username: String, email: String) -> User {
         font_size: "medium".to_string(),
         contrast: "normal".to_string(),
         ai_interaction_style: "casual".to_string(),
+        ai_emoji_policy: "allow".to_string(),
         profile_visibility: "public".to_string(),
         activity_sharing: "connections".to_string(),
+        allow_support_access: false,
+        timezone: "UTC".to_string(),
+        accessibility_mode: false,
+        dyslexia_friendly: false,
+        target_reading_level: None,
+        quiet_hours: None,
+        review_reminders_muted: false,
+        notification_preferences: NotificationPreferences::default(),
+        publish_activity: false,
     };
 
     let new_user = User {
@@ -103,36 +213,48 @@ fn create_user(username: String, email: String) -> User {
         last_active: ic_cdk::api::time(),
         settings: default_settings,
         password_hash: None,
+        verified_educator: false,
+        billing_region: None,
+        password_salt: None,
     };
 
-    USERS.with(|users| {
-        users.borrow_mut().insert(principal, new_user.clone());
-    });
+    cache::store_user(new_user.clone());
+    start_trial(&new_user);
+    claim_pending_admin(principal);
 
-    new_user
+    cache::user(principal).unwrap_or(new_user)
 }
 
-#[ic_cdk::update]
-fn register_user(username: String, email: String, password: String) -> Result<User, String> {
-    // Check if email already exists
-    let email_exists = USERS.with(|users| {
-        users.borrow().values().any(|user| user.email == email)
-    });
-    
-    if email_exists {
+// Shared by registration and the email and username changes; `except` is the account being changed
+fn ensure_email_available(email: &str, except: Option<Principal>) -> Result<(), String> {
+    if USERS.with(|users| users.borrow().values().any(|user| user.email == email && Some(user.id) != except)) {
         return Err("Email already registered".to_string());
     }
+    Ok(())
+}
 
-    // Check if username already exists
-    let username_exists = USERS.with(|users| {
-        users.borrow().values().any(|user| user.username == username)
-    });
-    
-    if username_exists {
+fn ensure_username_available(username: &str, except: Option<Principal>) -> Result<(), String> {
+    if USERS.with(|users| users.borrow().values().any(|user| user.username == username && Some(user.id) != except)) {
         return Err("Username already taken".to_string());
     }
+    Ok(())
+}
+
+#[ic_cdk::update]
+async fn register_user(username: String, email: String, password: String, invite_code: Option<String>) -> Result<User, String> {
+    // Fetched before the uniqueness checks so nothing can interleave between them and the insert
+    let salt = random_bytes().await?;
+
+    ensure_email_available(&email, None)?;
+    ensure_username_available(&username, None)?;
+    
+    let invite = if get_config().registration.invite_only {
+        Some(valid_invite_code(invite_code.as_deref().unwrap_or_default(), &email)?)
+    } else {
+        None
+    };
 
-    let password_hash = hash_password(&password);
+    let (password_hash, password_salt) = password::hash(&password, &salt[..password::SALT_BYTES]);
     
     // Generate a unique ID for traditional users
     let user_id = next_id("user");
@@ -155,8 +277,18 @@ fn register_user(username: String, email: String, password: String) -> Result<Us
         font_size: "medium".to_string(),
         contrast: "normal".to_string(),
         ai_interaction_style: "casual".to_string(),
+        ai_emoji_policy: "allow".to_string(),
         profile_visibility: "public".to_string(),
         activity_sharing: "connections".to_string(),
+        allow_support_access: false,
+        timezone: "UTC".to_string(),
+        accessibility_mode: false,
+        dyslexia_friendly: false,
+        target_reading_level: None,
+        quiet_hours: None,
+        review_reminders_muted: false,
+        notification_preferences: NotificationPreferences::default(),
+        publish_activity: false,
     };
 
     let new_user = User {
@@ -187,53 +319,71 @@ fn register_user(username: String, email: String, password: String) -> Result<Us
         last_active: ic_cdk::api::time(),
         settings: default_settings,
         password_hash: Some(password_hash),
+        verified_educator: false,
+        billing_region: None,
+        password_salt: Some(password_salt),
     };
 
-    USERS.with(|users| {
-        users.borrow_mut().insert(principal, new_user.clone());
-    });
+    cache::store_user(new_user.clone());
+    start_trial(&new_user);
+    if let Some(invite) = invite {
+        redeem_invite_code(invite, &new_user.email);
+    }
 
     Ok(new_user)
 }
 
 #[ic_cdk::update]
-fn login_user(email: String, password: String) -> Result<User, String> {
+async fn login_user(email: String, password: String) -> Result<User, String> {
+    password_login(email, password).await
+}
+
+async fn password_login(email: String, password: String) -> Result<User, String> {
     let user = USERS.with(|users| {
         users.borrow().values().find(|user| user.email == email).map(|user| user.clone())
-    });
+    }).ok_or("User not found")?;
+    let password_hash = user.password_hash.as_deref().ok_or("Account not set up for password authentication")?;
+    let now = ic_cdk::api::time();
+    ensure_not_locked(&user.email, now)?;
+    if !password::verify(&password, password_hash, user.password_salt.as_deref()) {
+        record_login_failure(&user.email, now);
+        return Err("Invalid password".to_string());
+    }
+    record_login_success(&user.email, now);
 
-    match user {
-        Some(user) => {
-            if let Some(password_hash) = &user.password_hash {
-                if verify_password(&password, password_hash) {
-                    // Update last login
-                    let mut updated_user = user.clone();
-                    updated_user.last_login = Some(ic_cdk::api::time());
-                    updated_user.last_active = ic_cdk::api::time();
-                    
-                    USERS.with(|users| {
-                        users.borrow_mut().insert(user.id, updated_user.clone());
-                    });
-                    
-                    Ok(updated_user)
-                } else {
-                    Err("Invalid password".to_string())
-                }
-            } else {
-                Err("Account not set up for password authentication".to_string())
-            }
+    // Legacy and weaker hashes are replaced the first time the password is seen
+    let rehashed = if password::needs_rehash(password_hash, user.password_salt.as_deref()) {
+        let salt = random_bytes().await?;
+        Some(password::hash(&password, &salt[..password::SALT_BYTES]))
+    } else {
+        None
+    };
+
+    // Re-read in case the account changed during the await
+    let mut updated_user = cache::user(user.id).ok_or("User not found")?;
+    if let Some((hash, salt)) = rehashed {
+        if updated_user.password_hash == user.password_hash {
+            updated_user.password_hash = Some(hash);
+            updated_user.password_salt = Some(salt);
         }
-        None => Err("User not found".to_string())
     }
+    updated_user.last_login = Some(ic_cdk::api::time());
+    updated_user.last_active = ic_cdk::api::time();
+    cache::store_user(updated_user.clone());
+
+    Ok(updated_user)
 }
 
 #[ic_cdk::query]
-fn get_user_by_email(email: String) -> Option<User> {
-    USERS.with(|users| {
+fn get_user_by_email(email: String, api_key: Option<String>) -> Result<Option<User>, String> {
+    authorize_external(ic_cdk::caller(), api_key, "users:read", "get_user_by_email")?;
+    Ok(USERS.with(|users| {
         users.borrow().values().find(|user| user.email == email).map(|user| user.clone())
-    })
+    }))
 }
 
+// For the Python backend: callers present an API key with the users:write scope unless
+// their principal is allowlisted in config.external_api
 #[ic_cdk::update]
 fn upsert_external_user(
     email: String,
@@ -242,14 +392,52 @@ fn upsert_external_user(
     last_name: Option<String>,
     avatar_url: Option<String>,
     is_verified: Option<bool>,
-) -> User {
+    api_key: Option<String>,
+) -> Result<User, String> {
+    authorize_external(ic_cdk::caller(), api_key, "users:write", "upsert_external_user")?;
+    if email.trim().is_empty() {
+        return Err("Email is required".to_string());
+    }
+    let input = ExternalUserInput { email, username, first_name, last_name, avatar_url, is_verified };
+    check_external_usernames(std::slice::from_ref(&input))?;
+    Ok(upsert_external(input))
+}
+
+#[ic_cdk::update]
+fn bulk_upsert_external_users(users: Vec<ExternalUserInput>, api_key: Option<String>) -> Result<Vec<User>, String> {
+    authorize_external(ic_cdk::caller(), api_key, "users:write", "bulk_upsert_external_users")?;
+    if users.is_empty() || users.len() > MAX_EXTERNAL_USER_BATCH {
+        return Err(format!("Send between 1 and {} users per batch", MAX_EXTERNAL_USER_BATCH));
+    }
+    if users.iter().any(|user| user.email.trim().is_empty()) {
+        return Err("Every user needs an email".to_string());
+    }
+    check_external_usernames(&users)?;
+    Ok(users.into_iter().map(upsert_external).collect())
+}
+
+// Usernames must be free, and distinct within a batch, before anything is written
+fn check_external_usernames(inputs: &[ExternalUserInput]) -> Result<(), String> {
+    let mut seen = std::collections::HashSet::new();
+    for input in inputs {
+        let Some(username) = input.username.as_deref().filter(|u| !u.trim().is_empty()) else { continue };
+        let existing = USERS.with(|users| users.borrow().values().find(|u| u.email == input.email).map(|u| u.id));
+        ensure_username_available(username, existing)?;
+        if !seen.insert(username) {
+            return Err(format!("Username '{}' appears more than once in the batch", username));
+        }
+    }
+    Ok(())
+}
+
+fn upsert_external(input: ExternalUserInput) -> User {
+    let ExternalUserInput { email, username, first_name, last_name, avatar_url, is_verified } = input;
     // Try to find an existing user by email
     let existing = USERS.with(|users| {
         users
             .borrow()
             .values()
             .find(|user| user.email == email)
-            .cloned()
     });
 
     match existing {
@@ -258,13 +446,10 @@ fn upsert_external_user(
             if let Some(f) = first_name { if !f.trim().is_empty() { user.first_name = Some(f); } }
             if let Some(l) = last_name { if !l.trim().is_empty() { user.last_name = Some(l); } }
             if let Some(a) = avatar_url { if !a.trim().is_empty() { user.avatar_url = Some(a); } }
-            if let Some(v) = is_verified { user.is_verified = v; }
             user.updated_at = ic_cdk::api::time();
             user.last_active = ic_cdk::api::time();
 
-            USERS.with(|users| {
-                users.borrow_mut().insert(user.id, user.clone());
-            });
+            cache::store_user(user.clone());
             user
         }
         None => {
@@ -289,13 +474,25 @@ fn upsert_external_user(
                 font_size: "medium".to_string(),
                 contrast: "normal".to_string(),
                 ai_interaction_style: "casual".to_string(),
+                ai_emoji_policy: "allow".to_string(),
                 profile_visibility: "public".to_string(),
                 activity_sharing: "connections".to_string(),
+                allow_support_access: false,
+                timezone: "UTC".to_string(),
+                accessibility_mode: false,
+                dyslexia_friendly: false,
+                target_reading_level: None,
+                quiet_hours: None,
+                review_reminders_muted: false,
+                notification_preferences: NotificationPreferences::default(),
+                publish_activity: false,
             };
 
-            let derived_username = username.unwrap_or_else(|| {
+            // A username derived from the email gets the user number appended when it's taken
+            let derived_username = username.filter(|u| !u.trim().is_empty()).unwrap_or_else(|| {
                 let at = email.find('@').unwrap_or(0);
-                if at > 0 { email[..at].to_string() } else { email.clone() }
+                let base = if at > 0 { email[..at].to_string() } else { email.clone() };
+                if ensure_username_available(&base, None).is_ok() { base } else { format!("{}{}", base, user_id) }
             });
 
             let new_user = User {
@@ -326,11 +523,13 @@ fn upsert_external_user(
                 last_active: ic_cdk::api::time(),
                 settings: default_settings,
                 password_hash: None,
+                verified_educator: false,
+                billing_region: None,
+                password_salt: None,
             };
 
-            USERS.with(|users| {
-                users.borrow_mut().insert(principal, new_user.clone());
-            });
+            cache::store_user(new_user.clone());
+            start_trial(&new_user);
 
             new_user
         }
@@ -338,7 +537,7 @@ fn upsert_external_user(
 }
 
 #[ic_cdk::update]
-fn create_tutor(
+async fn create_tutor(
     name: String,
     description: String,
     teaching_style: String,
@@ -348,8 +547,9 @@ fn create_tutor(
     voice_id: Option<String>,
     voice_settings: Option<HashMap<String, String>>,
     avatar_url: Option<String>,
+    session_token: Option<String>,
 ) -> Result<Tutor, String> {
-    let caller = ic_cdk::caller();
+    let caller = session_caller(session_token)?;
     
     // Validate required fields
     if name.trim().is_empty() {
@@ -374,10 +574,9 @@ fn create_tutor(
     
     let knowledge_base = knowledge_base.unwrap_or_default();
     
+    let public_id = random_public_id("tutor").await?;
+    enforce_quota(caller, "tutors", 1)?;
     let tutor_id = next_id("tutor");
-    
-    // Generate a secure random string for public_id
-    let public_id = generate_secure_id();
 
     let new_tutor = Tutor {
         id: tutor_id,
@@ -395,30 +594,34 @@ fn create_tutor(
         voice_settings: voice_settings.unwrap_or_default(),
         created_at: ic_cdk::api::time(),
         updated_at: ic_cdk::api::time(),
+        default_topic: None,
+        intake_questions: Vec::new(),
+        welcome_mode: "ai".to_string(),
+        welcome_template: None,
+        slug: None,
+        visibility: Visibility::Private,
+        kb_version_pins: Vec::new(),
+        high_stakes_domain: None,
+        fact_check: false,
+        model_params: None,
+        license: None,
+        derived_from: Vec::new(),
     };
 
-    TUTORS.with(|tutors| {
-        tutors.borrow_mut().insert(tutor_id, new_tutor.clone());
-    });
+    cache::store_tutor(tutor_id, new_tutor.clone());
+    queue_tagging("tutor", &new_tutor.public_id);
 
     Ok(new_tutor)
 }
 
 #[ic_cdk::query]
 fn get_tutor(id: u64) -> Option<Tutor> {
-    TUTORS.with(|tutors| tutors.borrow().get(&id))
+    TUTORS.with(|tutors| tutors.borrow().get(&id)).filter(|tutor| can_view(tutor, ic_cdk::caller()))
 }
 
 #[ic_cdk::query]
 fn get_tutor_by_public_id(public_id: String) -> Option<Tutor> {
-    let caller = ic_cdk::caller();
-    TUTORS.with(|tutors| {
-        tutors
-            .borrow()
-            .iter()
-            .find(|(_, tutor)| tutor.public_id == public_id && tutor.user_id == caller)
-            .map(|(_, tutor)| tutor.clone())
-    })
+    visible_tutor(&public_id, ic_cdk::caller()).ok().map(|(_, tutor)| tutor)
 }
 
 #[ic_cdk::update]
@@ -433,16 +636,10 @@ fn update_tutor(
     voice_id: Option<String>,
     voice_settings: Option<HashMap<String, String>>,
     avatar_url: Option<String>,
+    session_token: Option<String>,
 ) -> Result<Tutor, String> {
-    let caller = ic_cdk::caller();
-    
-    let mut tutor = TUTORS.with(|tutors| {
-        tutors
-            .borrow()
-            .iter()
-            .find(|(_, t)| t.public_id == public_id && t.user_id == caller)
-            .map(|(id, t)| (id, t.clone()))
-    }).ok_or("Tutor not found or you don't have permission to update it")?;
+    let caller = session_caller(session_token)?;
+    let mut tutor = owned_tutor(&public_id, caller)?;
     
     // Update fields if provided
     if let Some(name) = name {
@@ -499,58 +696,43 @@ fn update_tutor(
     tutor.1.updated_at = ic_cdk::api::time();
     
     // Update the tutor in storage
-    TUTORS.with(|tutors| {
-        tutors.borrow_mut().insert(tutor.0, tutor.1.clone());
-    });
+    cache::store_tutor(tutor.0, tutor.1.clone());
+    queue_tagging("tutor", &tutor.1.public_id);
     
     Ok(tutor.1)
 }
 
 #[ic_cdk::update]
-fn delete_tutor(public_id: String) -> Result<String, String> {
-    let caller = ic_cdk::caller();
-    
-    let tutor_id = TUTORS.with(|tutors| {
-        tutors
-            .borrow()
-            .iter()
-            .find(|(_, t)| t.public_id == public_id && t.user_id == caller)
-            .map(|(id, _)| id)
-    }).ok_or("Tutor not found or you don't have permission to delete it")?;
+fn delete_tutor(public_id: String, session_token: Option<String>) -> Result<String, String> {
+    let caller = session_caller(session_token)?;
+    let (tutor_id, tutor) = owned_tutor(&public_id, caller)?;
     
-    TUTORS.with(|tutors| {
-        tutors.borrow_mut().remove(&tutor_id);
-    });
+    cache::remove_tutor(tutor_id);
+    // Tags are removed once the undo window closes
+    let (target_id, description) = (tutor.public_id.clone(), format!("Deleted tutor {}", tutor.name));
+    stage_undo(caller, "delete_tutor", &target_id, description, UndoSnapshot::Tutor(Box::new(tutor)));
     
     Ok("Tutor deleted successfully".to_string())
 }
 
 #[ic_cdk::update]
 fn toggle_tutor_pin(public_id: String) -> Result<Tutor, String> {
-    let caller = ic_cdk::caller();
-    
-    let mut tutor = TUTORS.with(|tutors| {
-        tutors
-            .borrow()
-            .iter()
-            .find(|(_, t)| t.public_id == public_id && t.user_id == caller)
-            .map(|(id, t)| (id, t.clone()))
-    }).ok_or("Tutor not found or you don't have permission to modify it")?;
+    let mut tutor = owned_tutor(&public_id, ic_cdk::caller())?;
     
     tutor.1.is_pinned = !tutor.1.is_pinned;
     tutor.1.updated_at = ic_cdk::api::time();
     
     // Update the tutor in storage
-    TUTORS.with(|tutors| {
-        tutors.borrow_mut().insert(tutor.0, tutor.1.clone());
-    });
+    cache::store_tutor(tutor.0, tutor.1.clone());
     
     Ok(tutor.1)
 }
 
 #[ic_cdk::query]
-fn get_tutors() -> Vec<Tutor> {
-    let caller = ic_cdk::caller();
+fn get_tutors(session_token: Option<String>) -> Vec<Tutor> {
+    let Ok(caller) = session_reader(session_token) else {
+        return Vec::new();
+    };
     TUTORS.with(|tutors| {
         tutors
             .borrow()
@@ -646,7 +828,7 @@ fn get_connections() -> Vec<UserConnection> {
 }
 
 #[ic_cdk::update]
-fn create_study_group(
+async fn create_study_group(
     name: String,
     description: Option<String>,
     is_private: bool,
@@ -654,11 +836,14 @@ fn create_study_group(
     learning_level: String,
 ) -> Result<StudyGroup, String> {
     let caller = ic_cdk::caller();
+    // The creator becomes the group's first member
+    enforce_quota(caller, "group_memberships", 1)?;
+    let public_id = random_public_id("group").await?;
     let group_id = next_id("study_group");
 
     let new_group = StudyGroup {
         id: group_id,
-        public_id: group_id.to_string(),
+        public_id,
         name,
         description,
         creator_id: caller,
@@ -670,11 +855,13 @@ fn create_study_group(
         goals: None,
         created_at: ic_cdk::api::time(),
         updated_at: ic_cdk::api::time(),
+        slug: None,
     };
 
     STUDY_GROUPS.with(|groups| {
         groups.borrow_mut().insert(group_id, new_group.clone());
     });
+    queue_tagging("study_group", &group_id.to_string());
     
     // Automatically add the creator as the first member and admin
     let membership_id = next_id("group_membership");
@@ -700,11 +887,21 @@ fn create_study_group(
 fn join_study_group(group_id: u64) -> Result<GroupMembership, String> {
     let caller = ic_cdk::caller();
     
-    // Check if group exists
-    let _group = STUDY_GROUPS.with(|groups| groups.borrow().get(&group_id))
-        .ok_or("Study group not found.".to_string())?;
-
-    // TODO: Add checks for private groups, max members, etc.
+    // Private groups stay hidden from non-members, so they can't be joined directly
+    let group = visible_group(group_id, caller)?;
+    if active_group_membership(group_id, caller).is_some() {
+        return Err("You are already a member of this group".to_string());
+    }
+    if group.is_private && !has_permission(caller, Permission::ModerateContent) {
+        return Err("This group is private".to_string());
+    }
+    let member_count = GROUP_MEMBERSHIPS.with(|memberships| {
+        memberships.borrow().iter().filter(|(_, m)| m.group_id == group_id && m.status == "active").count()
+    });
+    if group.max_members > 0 && member_count >= group.max_members as usize {
+        return Err("This group is full".to_string());
+    }
+    enforce_quota(caller, "group_memberships", 1)?;
     
     let membership_id = next_id("group_membership");
     let new_membership = GroupMembership {
@@ -727,11 +924,11 @@ fn join_study_group(group_id: u64) -> Result<GroupMembership, String> {
 
 #[ic_cdk::query]
 fn get_study_group(id: u64) -> Option<StudyGroup> {
-    STUDY_GROUPS.with(|groups| groups.borrow().get(&id))
+    visible_group(id, ic_cdk::caller()).ok()
 }
 
 #[ic_cdk::update]
-fn create_task(
+async fn create_task(
     title: String,
     description: String,
     category: String,
@@ -742,10 +939,11 @@ fn create_task(
     let caller = ic_cdk::caller();
     // TODO: Add check to ensure caller is an admin
 
+    let public_id = random_public_id("task").await?;
     let task_id = next_id("task");
     let new_task = Task {
         id: task_id,
-        public_id: task_id.to_string(),
+        public_id,
         title,
         description,
         category,
@@ -760,6 +958,7 @@ fn create_task(
         created_at: ic_cdk::api::time(),
         expires_at: None,
         metadata: None,
+        slug: None,
     };
 
     TASKS.with(|tasks| {
@@ -775,17 +974,22 @@ fn complete_task(task_id: u64) -> Result<UserTaskCompletion, String> {
     
     let task = TASKS.with(|tasks| tasks.borrow().get(&task_id))
         .ok_or("Task not found.".to_string())?;
+    // Event tasks are switched off outside their event
+    if !task.is_active {
+        return Err("This task is not available right now".to_string());
+    }
 
     // TODO: Add validation to check if user has already completed the task
 
+    let now = ic_cdk::api::time();
     let completion_id = next_id("user_task_completion");
     let new_completion = UserTaskCompletion {
         id: completion_id,
         user_id: caller,
         task_id,
-        completed_at: ic_cdk::api::time(),
+        completed_at: now,
         tokens_earned: task.token_reward,
-        points_earned: task.points_reward,
+        points_earned: score_event_points(caller, task.points_reward, now),
         completion_count: 1,
         proof_data: None,
         metadata: None,
@@ -810,24 +1014,35 @@ fn get_tasks() -> Vec<Task> {
 // --- Admin Methods ---
 
 #[ic_cdk::query]
-fn get_all_users_admin() -> Result<Vec<User>, String> {
-    if !is_admin(ic_cdk::caller()) {
-        return Err("Only admins can perform this action.".to_string());
-    }
-    Ok(USERS.with(|users| users.borrow().iter().map(|(_, user)| user.clone()).collect()))
+fn get_all_users_admin(offset: Option<u32>, limit: Option<u32>) -> Result<Vec<User>, String> {
+    require(ic_cdk::caller(), Permission::ManageUsers)?;
+    let hint = "Pass offset and limit to fetch users in pages.";
+    let (offset, limit) = (offset.unwrap_or(0) as usize, limit.map_or(usize::MAX, |l| l as usize));
+    let mut page = Vec::new();
+    USERS.with(|users| {
+        for (scanned, (_, user)) in users.borrow().iter().enumerate() {
+            if page.len() >= limit {
+                break;
+            }
+            scan_checkpoint(scanned, hint)?;
+            if scanned >= offset {
+                page.push(user);
+            }
+        }
+        Ok::<(), String>(())
+    })?;
+    ensure_fits(&page, hint)?;
+    Ok(page)
 }
 
 #[ic_cdk::update]
 fn update_user_status_admin(user_id: Principal, status: String) -> Result<User, String> {
-    if !is_admin(ic_cdk::caller()) {
-        return Err("Only admins can perform this action.".to_string());
-    }
+    require(ic_cdk::caller(), Permission::ManageUsers)?;
     
     USERS.with(|users| {
-        let mut users_mut = users.borrow_mut();
-        if let Some(mut user) = users_mut.get(&user_id) {
+        if let Some(mut user) = users.borrow().get(&user_id) {
             user.status = status;
-            users_mut.insert(user_id, user.clone());
+            cache::store_user(user.clone());
             Ok(user)
         } else {
             Err("User not found.".to_string())
@@ -837,14 +1052,34 @@ fn update_user_status_admin(user_id: Principal, status: String) -> Result<User,
 
 // --- Billing Methods (Placeholders) ---
 
-// TODO: Implement full logic for creating subscription plans
+// TODO: Sync plans to Paystack once subscriptions are created through it
 #[ic_cdk::update]
-fn create_subscription_plan_admin(/* params */) -> Result<(), String> {
-    if !is_admin(ic_cdk::caller()) {
-        return Err("Only admins can perform this action.".to_string());
+fn create_subscription_plan_admin(name: String, billing_cycle: String, price_naira: u64, features: Vec<String>, regional_prices: Vec<RegionalPrice>) -> Result<SubscriptionPlan, String> {
+    let caller = ic_cdk::caller();
+    require(caller, Permission::ManageBilling)?;
+    if plan_rank(&name.to_lowercase()).is_none() {
+        return Err(format!("'{}' has no plan limits configured", name));
     }
-    // Placeholder
-    Ok(())
+    if billing_cycle != "monthly" && billing_cycle != "yearly" {
+        return Err("Billing cycle must be monthly or yearly".to_string());
+    }
+    let regional_prices = validated_regional_prices(regional_prices)?;
+    
+    let plan = SubscriptionPlan {
+        id: next_id("subscription_plan"),
+        name,
+        price_naira,
+        billing_cycle,
+        features,
+        limits: HashMap::new(),
+        paystack_plan_code: None,
+        is_active: true,
+        created_at: ic_cdk::api::time(),
+        regional_prices,
+    };
+    SUBSCRIPTION_PLANS.with(|plans| plans.borrow_mut().insert(plan.id, plan.clone()));
+    record_audit(caller, "subscription_plan_created", None, format!("{} {} ({})", plan.name, plan.billing_cycle, plan.id));
+    Ok(plan)
 }
 
 // TODO: Implement logic for creating a new subscription (HTTPS outcall to Paystack)
@@ -873,15 +1108,6 @@ fn verify_zk_proof(/* params */) -> Result<bool, String> {
 
 // --- Private Helper Functions ---
 
-fn is_admin(principal: Principal) -> bool {
-    USERS.with(|users| {
-        if let Some(user) = users.borrow().get(&principal) {
-            user.role == "admin"
-        } else {
-            false
-        }
-    })
-}
 
 // --- AI Topic Suggestions ---
 
@@ -890,14 +1116,79 @@ struct TopicSuggestionsResponse {
     suggestions: Vec<TopicSuggestion>,
 }
 
-async fn call_groq_ai(_prompt: &str) -> Result<String, String> {
-    // External AI calls are disabled on the canister. Return a simple message
-    // so frontend fallbacks or Python backend can handle AI instead.
+async fn call_groq_ai(prompt: &str, operation: &str, acting: Principal) -> Result<String, String> {
+    call_ai_with_params(prompt, operation, None, None, acting).await
+}
+
+// Tries each configured provider in order, skipping any whose circuit is open. When none
+// answer, returns a simple message so frontend fallbacks or the Python backend take over.
+// The operation's outcall budget caps cycles per attempt, retries, and total wall time.
+// Tutor replies pass the tutor's model params and the reply's message id; other calls use
+// the global defaults. Every call that reaches a provider is recorded in AI_DIAGNOSTICS.
+// `acting` is the user whose quota and concurrency slot the call uses: the caller, or the
+// session token's user; jobs pass the canister and aren't limited.
+async fn call_ai_with_params(prompt: &str, operation: &str, params: Option<&ModelParams>, message_id: Option<&str>, acting: Principal) -> Result<String, String> {
+    let config = get_config();
+    ensure_ai_available(&config.ai_degradation, ic_cdk::api::time())?;
+    let _slot = acquire_ai_slot(acting, operation)?;
+    consume_ai_call(acting)?;
+    let budget = outcall_budget(&config, operation);
+    let params = resolve_model_params(&config.model_defaults, params);
+    let started = ic_cdk::api::time();
+    let deadline = started + budget.max_duration_ms * 1_000_000;
+    let mut trace = AiCallTrace { operation, message_id, started, provider: None, model: None, attempts: 0 };
+    let mut last_error = None;
+    
+    for provider in config.ai_providers.iter().filter(|p| p.enabled) {
+        for attempt in 0..=budget.max_retries {
+            if ai_circuit_open(&provider.name, ic_cdk::api::time()) {
+                break;
+            }
+            if ic_cdk::api::time() >= deadline {
+                trace.record("budget_exceeded", 0);
+                return Err(budget_exceeded_error(&budget, started));
+            }
+            
+            let attempt_started = ic_cdk::api::time();
+            trace.provider = Some(provider.name.clone());
+            trace.model = Some(params.model.clone().unwrap_or_else(|| provider.model.clone()));
+            trace.attempts += 1;
+            let result = call_ai_provider(provider, prompt, &params, budget.cycles as u128).await;
+            let latency_ms = ic_cdk::api::time().saturating_sub(attempt_started) / 1_000_000;
+            let result = match result {
+                Ok(_) if latency_ms > provider.timeout_ms => Err(format!("Timed out after {} ms", latency_ms)),
+                other => other,
+            };
+            
+            record_ai_provider_result(&provider.name, latency_ms, result.as_ref().err(), &config.ai_circuit_breaker);
+            match result {
+                Ok(text) if ic_cdk::api::time() <= deadline => {
+                    trace.record("ok", text.len());
+                    record_ai_availability(&config.ai_degradation, None);
+                    return Ok(text);
+                }
+                Ok(_) => {
+                    trace.record("budget_exceeded", 0);
+                    record_ai_availability(&config.ai_degradation, None);
+                    return Err(budget_exceeded_error(&budget, started));
+                }
+                Err(e) => {
+                    ic_cdk::println!("AI provider {} failed (attempt {}): {}", provider.name, attempt + 1, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+    }
+    
+    if trace.attempts > 0 {
+        trace.record("failed", 0);
+    }
+    record_ai_availability(&config.ai_degradation, Some(last_error.unwrap_or_else(|| "No AI provider is enabled or reachable".to_string())));
     Ok("AI service is handled by the Python backend now.".to_string())
 }
 
 // Enhanced AI functions for comprehensive tutoring
-async fn generate_course_outline(tutor_data: &Tutor, topic: &str, user_preferences: &UserSettings) -> Result<CourseOutline, String> {
+async fn generate_course_outline(tutor_data: &Tutor, topic: &str, user_preferences: &UserSettings, acting: Principal) -> Result<CourseOutline, String> {
     let learning_style = &user_preferences.learning_style;
     let difficulty = &user_preferences.difficulty_level;
     
@@ -907,20 +1198,22 @@ async fn generate_course_outline(tutor_data: &Tutor, topic: &str, user_preferenc
         Return JSON:
         {{\"title\":\"Course Title\",\"description\":\"Brief description\",\"learning_objectives\":[\"obj1\",\"obj2\"],\"estimated_duration\":\"X weeks\",\"difficulty_level\":\"{}\",\"modules\":[{{\"title\":\"Module\",\"description\":\"Brief\",\"order\":1,\"content\":\"Content\",\"status\":\"pending\"}}]}}
         
-        Keep descriptions under 100 chars. Max 3 modules.",
+        Keep descriptions under 100 chars. Max 3 modules.{}",
         topic,
         learning_style,
         difficulty,
-        difficulty
+        difficulty,
+        reading_level_instructions(user_preferences)
     );
     
-    let ai_response = call_groq_ai(&system_prompt).await?;
+    let ai_response = ai_unless_degraded(call_groq_ai(&system_prompt, "course_outline", acting).await)?
+        .map(|response| process_ai_response(response, &response_processing_for(acting, "json")));
     
     // Parse the JSON response
-    match serde_json::from_str::<CourseOutline>(&ai_response) {
-        Ok(outline) => Ok(outline),
-        Err(_) => {
-            // Fallback if JSON parsing fails
+    match ai_response.as_deref().map(serde_json::from_str::<CourseOutline>) {
+        Some(Ok(outline)) => Ok(outline),
+        _ => {
+            // Fallback if JSON parsing fails or AI is unavailable
             Ok(CourseOutline {
                 title: format!("Course on {}", topic),
                 description: format!("A comprehensive course about {}", topic),
@@ -937,12 +1230,13 @@ async fn generate_course_outline(tutor_data: &Tutor, topic: &str, user_preferenc
                         status: "pending".to_string(),
                     }
                 ],
+                degraded: ai_response.is_none(),
             })
         }
     }
 }
 
-async fn generate_topic_suggestions(tutor_data: &Tutor) -> Result<Vec<TopicSuggestion>, String> {
+async fn generate_topic_suggestions(tutor_data: &Tutor, acting: Principal) -> Result<Vec<TopicSuggestion>, String> {
     let system_prompt = format!(
         "Generate 3 topic suggestions for a tutor with expertise in: {}
         Teaching style: {}
@@ -955,7 +1249,7 @@ async fn generate_topic_suggestions(tutor_data: &Tutor) -> Result<Vec<TopicSugge
         tutor_data.teaching_style
     );
     
-    let ai_response = call_groq_ai(&system_prompt).await?;
+    let ai_response = process_ai_response(call_groq_ai(&system_prompt, "topic_suggestions", acting).await?, &response_processing_for(acting, "json"));
     
     match serde_json::from_str::<Vec<TopicSuggestion>>(&ai_response) {
         Ok(suggestions) => {
@@ -970,12 +1264,13 @@ async fn generate_topic_suggestions(tutor_data: &Tutor) -> Result<Vec<TopicSugge
                 description: format!("Learn the basics of {}", exp),
                 difficulty: "beginner".to_string(),
                 expertise_area: exp.clone(),
+                degraded: false,
             }).collect())
         }
     }
 }
 
-async fn validate_topic(tutor_data: &Tutor, topic: &str) -> Result<TopicValidation, String> {
+async fn validate_topic(tutor_data: &Tutor, topic: &str, acting: Principal) -> Result<TopicValidation, String> {
     let system_prompt = format!(
         "Evaluate if the topic '{}' is relevant to a tutor with expertise in: {}
         
@@ -992,11 +1287,12 @@ async fn validate_topic(tutor_data: &Tutor, topic: &str) -> Result<TopicValidati
         tutor_data.expertise.join(", ")
     );
     
-    let ai_response = call_groq_ai(&system_prompt).await?;
+    let ai_response = ai_unless_degraded(call_groq_ai(&system_prompt, "topic_validation", acting).await)?
+        .map(|response| process_ai_response(response, &response_processing_for(acting, "json")));
     
-    match serde_json::from_str::<TopicValidation>(&ai_response) {
-        Ok(validation) => Ok(validation),
-        Err(_) => {
+    match ai_response.as_deref().map(serde_json::from_str::<TopicValidation>) {
+        Some(Ok(validation)) => Ok(validation),
+        _ => {
             // Fallback validation
             let is_relevant = tutor_data.expertise.iter().any(|exp| topic.to_lowercase().contains(&exp.to_lowercase()));
             Ok(TopicValidation {
@@ -1004,25 +1300,48 @@ async fn validate_topic(tutor_data: &Tutor, topic: &str) -> Result<TopicValidati
                 confidence: if is_relevant { 0.7 } else { 0.3 },
                 reasoning: "Fallback validation based on keyword matching".to_string(),
                 suggested_alternatives: if is_relevant { vec![] } else { tutor_data.expertise.clone() },
+                degraded: ai_response.is_none(),
             })
         }
     }
 }
 
 async fn generate_tutor_chat_response(
-    session_id: &str,
-    user_message: &str,
+    delivery: &PendingDelivery,
     session_history: &[ChatMessage],
     tutor_data: &Tutor,
     user_preferences: &UserSettings,
 ) -> Result<(String, ComprehensionAnalysis), String> {
+    let (user_id, session_id, user_message) = (delivery.requester(), delivery.session_id.as_str(), delivery.user_content.as_str());
+    let sources = reply_sources_prompt(&delivery.sources);
     let learning_style = &user_preferences.learning_style;
     let ai_style = &user_preferences.ai_interaction_style;
     
+    let session = CHAT_SESSIONS.with(|sessions| sessions.borrow().get(&session_id.to_string()));
+    let shared = session.as_ref().is_some_and(|s| !s.co_learners.is_empty());
+    
     // Build context from session history (limit to last 3 messages)
     let mut context = String::new();
     for msg in session_history.iter().rev().take(3) {
-        context.push_str(&format!("{}: {}\n", msg.sender, msg.content));
+        let speaker = match msg.author {
+            Some(author) if shared => learner_name(author).unwrap_or_else(|| msg.sender.clone()),
+            _ => msg.sender.clone(),
+        };
+        context.push_str(&format!("{}: {}\n", speaker, msg.content));
+    }
+    if let Some(session) = session {
+        if let Some(background) = intake_background(&session) {
+            context = format!("{}\n{}", background, context);
+        }
+        if let Some(note) = co_learning_note(&session, user_id) {
+            context = format!("{}\n{}", note, context);
+        }
+        if let Some(summary) = session.summary {
+            context = format!("Earlier in this session: {}\n{}", summary, context);
+        }
+    }
+    if let Some(sources) = sources {
+        context = format!("{}\n{}", sources, context);
     }
     
     let system_prompt = format!(
@@ -1031,15 +1350,17 @@ async fn generate_tutor_chat_response(
         Context: {}
         Student: {}
         
-        Respond briefly and helpfully. Use emojis! Keep under 200 chars.",
+        Respond briefly and helpfully. Use emojis! Keep under 200 chars.{}",
         tutor_data.name,
         tutor_data.teaching_style,
         learning_style,
         context,
-        user_message
+        user_message,
+        output_instructions(user_preferences)
     );
     
-    let ai_response = call_groq_ai(&system_prompt).await?;
+    let ai_response = process_ai_response(call_tutor_ai(&system_prompt, "chat", tutor_data, user_id, Some(&delivery.message_id), ic_cdk::id()).await?, &response_processing_for(user_id, "chat"));
+    let ai_response = enforce_reading_level(user_id, ai_response).await;
     
     // Simple comprehension analysis
     let comprehension_score = if user_message.len() > 50 { 0.7 } else { 0.5 };
@@ -1049,17 +1370,29 @@ async fn generate_tutor_chat_response(
         comprehension_score,
         difficulty_adjustment: difficulty_adjustment.to_string(),
         timestamp: ic_cdk::api::time().to_string(),
+        quota_warning: quota_status(user_id, "ai_calls").warning,
+        degraded: false,
+        pending_message_id: None,
     };
     
     Ok((ai_response, analysis))
 }
 
-async fn generate_welcome_message(tutor_data: &Tutor, topic: &str, course_outline: Option<&CourseOutline>) -> Result<String, String> {
+async fn generate_welcome_message(user_id: Principal, tutor_data: &Tutor, topic: &str, course_outline: Option<&CourseOutline>, intake: &[IntakeAnswer], message_id: &str, acting: Principal) -> Result<String, String> {
+    let intake_note = if intake.is_empty() {
+        String::new()
+    } else {
+        format!(
+            "\n        Before starting, the student answered your intake questions:\n        {}\n        Refer to their answers so the greeting feels personal.\n",
+            intake_summary(intake)
+        )
+    };
+    
     let system_prompt = format!(
         "You are {} an AI tutor with expertise in {}. Your teaching style is {} and your personality is {}.
         
         Write a warm, personalized welcome message to a student who wants to learn about '{}'.
-        
+        {}
         Your message should:
         1. Introduce yourself briefly as the tutor
         2. Show enthusiasm for teaching the topic
@@ -1074,33 +1407,31 @@ async fn generate_welcome_message(tutor_data: &Tutor, topic: &str, course_outlin
         - Encouraging and positive
         - Use emojis to make it engaging! 🎉
         
-        DO NOT include any markdown, quotes, or extra formatting.",
+        DO NOT include any markdown, quotes, or extra formatting.{}",
         tutor_data.name,
         tutor_data.expertise.join(", "),
         tutor_data.teaching_style,
         tutor_data.personality,
         topic,
+        intake_note,
         tutor_data.personality,
-        tutor_data.teaching_style
+        tutor_data.teaching_style,
+        user_output_instructions(user_id)
     );
     
-    call_groq_ai(&system_prompt).await
+    let welcome = call_tutor_ai(&system_prompt, "welcome_message", tutor_data, user_id, Some(message_id), acting).await?;
+    Ok(process_ai_response(welcome, &response_processing_for(user_id, "plain")))
 }
 
 // Groq API is now configured by default - no user configuration needed
 
 #[ic_cdk::update]
-async fn get_ai_topic_suggestions(tutor_id: String) -> Result<Vec<TopicSuggestion>, String> {
-    let caller = ic_cdk::caller();
+async fn get_ai_topic_suggestions(tutor_id: String, session_token: Option<String>) -> Result<Vec<TopicSuggestion>, String> {
+    let caller = session_caller(session_token)?;
+    rate_limit::check(caller, "generation")?;
     
     // Get the tutor to understand their expertise and personality
-    let tutor = TUTORS.with(|tutors| {
-        tutors
-            .borrow()
-            .iter()
-            .find(|(_, t)| t.public_id == tutor_id && t.user_id == caller)
-            .map(|(_, t)| t.clone())
-    }).ok_or("Tutor not found or you don't have permission to access it")?;
+    let (_, tutor) = owned_tutor(&tutor_id, caller)?;
     
     // Prepare a simplified prompt for better reliability
     let prompt = format!(
@@ -1113,13 +1444,43 @@ Suggest 3 learning topics as JSON array:
         tutor.personality
     );
     
-    // Call AI service
-    let ai_response = call_groq_ai(&prompt).await?;
-    ic_cdk::println!("Raw AI response: {}", ai_response);
+    // Call AI service, or suggest the tutor's expertise areas while it is unavailable
+    let mut suggestions: Vec<TopicSuggestion> = match ai_unless_degraded(call_groq_ai(&prompt, "topic_suggestions", caller).await)? {
+        Some(response) => {
+            let ai_response = process_ai_response(response, &response_processing_for(caller, "json"));
+            ic_cdk::println!("Raw AI response: {}", ai_response);
+            
+            // Parse the JSON response
+            serde_json::from_str(&ai_response).map_err(|e| format!("Failed to parse AI response: {}", e))?
+        }
+        None => tutor.expertise.iter().take(3).map(|exp| TopicSuggestion {
+            topic: format!("Introduction to {}", exp),
+            description: format!("Learn the basics of {}", exp),
+            difficulty: "beginner".to_string(),
+            expertise_area: exp.clone(),
+            degraded: true,
+        }).collect(),
+    };
     
-    // Parse the JSON response
-    let suggestions: Vec<TopicSuggestion> = serde_json::from_str(&ai_response)
-        .map_err(|e| format!("Failed to parse AI response: {}", e))?;
+    // Skills in this tutor's area that are due for review come first
+    let expertise: Vec<String> = tutor.expertise.iter().map(|e| e.to_lowercase()).collect();
+    let reviews: Vec<TopicSuggestion> = user_skills(caller, ic_cdk::api::time())
+        .into_iter()
+        .filter(|p| p.due_for_review)
+        .filter_map(|p| {
+            let skill = p.skill.to_lowercase();
+            let area = expertise.iter().position(|e| skill.contains(e.as_str()) || e.contains(skill.as_str()))?;
+            Some(TopicSuggestion {
+                description: format!("Due for review: proficiency has dropped to {:.0}%", p.score),
+                topic: p.skill,
+                difficulty: "beginner".to_string(),
+                expertise_area: tutor.expertise[area].clone(),
+                degraded: false,
+            })
+        })
+        .collect();
+    suggestions.retain(|s| !reviews.iter().any(|r| r.topic.eq_ignore_ascii_case(&s.topic)));
+    suggestions.splice(0..0, reviews);
     
     Ok(suggestions)
 }
@@ -1130,8 +1491,11 @@ Suggest 3 learning topics as JSON array:
 
 #[ic_cdk::update]
 async fn test_groq_api() -> Result<String, String> {
+    let caller = ic_cdk::caller();
+    require(caller, Permission::ManageSystem)?;
+    rate_limit::check(caller, "generation")?;
     let prompt = "Say 'Hello from Groq!' in exactly 5 words.";
-    call_groq_ai(&prompt).await
+    call_groq_ai(prompt, "default", caller).await
 }
 
 // --- Chat Session Management ---
@@ -1144,17 +1508,15 @@ async fn test_groq_api() -> Result<String, String> {
 // Chat sessions and messages are now stored in stable memory via state.rs
 
 #[ic_cdk::update]
-async fn send_tutor_message(session_id: String, content: String) -> Result<String, String> {
-    let caller = ic_cdk::caller();
-    
-    // Verify session exists and user has access
-    let session = CHAT_SESSIONS.with(|sessions| {
-        sessions.borrow().get(&session_id)
-    }).ok_or("Session not found")?;
+async fn send_tutor_message(session_id: String, content: String, sources: Option<Vec<KnowledgeBaseExcerpt>>, session_token: Option<String>) -> Result<String, String> {
+    let caller = session_caller(session_token)?;
+    rate_limit::check(caller, "chat")?;
     
-    if session.user_id != caller {
-        return Err("You don't have permission to access this session".to_string());
-    }
+    let session = participant_session(&session_id, caller)?;
+    ensure_session_writable(&session)?;
+    // Messages are stored and counted against the owner, whoever sent them
+    let owner = session.user_id;
+    let sources = resolve_reply_sources(&session, sources.unwrap_or_default())?;
     
     // Create user message
     let user_message = ChatMessage {
@@ -1164,54 +1526,43 @@ async fn send_tutor_message(session_id: String, content: String) -> Result<Strin
         content: content.clone(),
         timestamp: ic_cdk::api::time(),
         has_audio: Some(false),
+        delivery_status: "delivered".to_string(),
+        reading_grade: None,
+        author: Some(caller),
+        citations: Vec::new(),
+        confidence: None,
+        rating: None,
+        bookmarked: false,
     };
+    check_storage_quota(owner, chat_message_bytes(&user_message))?;
+    record_storage_change(owner, "messages", chat_message_bytes(&user_message) as i64);
     
     // Store user message
-    CHAT_MESSAGES.with(|messages| {
-        let mut messages = messages.borrow_mut();
-        let mut session_messages = messages.get(&session_id).unwrap_or_else(|| ChatMessageList(Vec::new()));
-        session_messages.0.push(user_message);
-        messages.insert(session_id.clone(), session_messages);
-    });
-    
-    // Generate AI response using the tutor's expertise
-    let tutor = TUTORS.with(|tutors| {
-        tutors.borrow().iter().find(|(_, t)| t.public_id == session.tutor_id).map(|(_, t)| t.clone())
-    }).ok_or("Tutor not found")?;
-    
-    // Create AI prompt for tutor response
-    let prompt = format!(
-        "Expert in: {}. Style: {}. Personality: {}.
-        
-Student: \"{}\"
-
-Give a helpful, educational response in 2-3 sentences.",
-        tutor.expertise.join(", "),
-        tutor.teaching_style,
-        tutor.personality,
-        content
-    );
+    let mut session_messages = CHAT_MESSAGES.with(|messages| messages.borrow().get(&session_id)).unwrap_or_else(|| ChatMessageList(Vec::new()));
+    session_messages.0.push(user_message);
+    certify::store_messages(owner, &session_id, session_messages);
     
-    // Get AI response
-    let ai_response = call_groq_ai(&prompt).await?;
-    
-    // Create tutor message
-    let tutor_message = ChatMessage {
-        id: format!("msg_{}", next_id("message")),
-        session_id: session_id.clone(),
-        sender: "tutor".to_string(),
-        content: ai_response,
-        timestamp: ic_cdk::api::time(),
-        has_audio: Some(false),
-    };
+    if cache::tutor_by_public_id(&session.tutor_id).is_none() {
+        return Err("Tutor not found".to_string());
+    }
     
-    // Store tutor message
-    CHAT_MESSAGES.with(|messages| {
-        let mut messages = messages.borrow_mut();
-        let mut session_messages = messages.get(&session_id).unwrap_or_else(|| ChatMessageList(Vec::new()));
-        session_messages.0.push(tutor_message.clone());
-        messages.insert(session_id.clone(), session_messages);
-    });
+    // The reply is stored as pending first so a failed AI call leaves a retryable message
+    let tutor_message_id = format!("msg_{}", next_id("message"));
+    start_pending_delivery(&session_id, &tutor_message_id, owner, (caller != owner).then_some(caller), "quick", &content, "");
+    set_delivery_sources(&tutor_message_id, sources);
+    if reply_in_background(&tutor_message_id) {
+        let message_id = tutor_message_id.clone();
+        ic_cdk::spawn(async move {
+            if let Err(e) = deliver_tutor_reply(&message_id, caller).await {
+                ic_cdk::println!("Background reply {} not delivered yet: {}", message_id, e);
+            }
+        });
+    } else {
+        match deliver_tutor_reply(&tutor_message_id, caller).await {
+            Err(e) if e != REPLY_QUEUED_MESSAGE && e != REPLY_DEFERRED_MESSAGE => return Err(e),
+            _ => {}
+        }
+    }
     
     // Update session timestamp
     CHAT_SESSIONS.with(|sessions| {
@@ -1222,26 +1573,24 @@ Give a helpful, educational response in 2-3 sentences.",
         }
     });
     
-    Ok(tutor_message.id)
+    Ok(tutor_message_id)
 }
 
 #[ic_cdk::query]
-fn get_session_messages(session_id: String) -> Result<Vec<ChatMessage>, String> {
-    let caller = ic_cdk::caller();
-    
-    // Verify session exists and user has access
-    let session = CHAT_SESSIONS.with(|sessions| {
-        sessions.borrow().get(&session_id)
-    }).ok_or("Session not found")?;
+fn get_session_messages(session_id: String, offset: Option<u32>, limit: Option<u32>, session_token: Option<String>) -> Result<Vec<ChatMessage>, String> {
+    let caller = session_reader(session_token)?;
     
-    if session.user_id != caller {
-        return Err("You don't have permission to access this session".to_string());
-    }
+    visible_session(&session_id, caller)?;
     
-    // Get messages for the session
-    let messages = CHAT_MESSAGES.with(|messages| {
+    // Get messages for the session, optionally one page at a time
+    let messages: Vec<ChatMessage> = CHAT_MESSAGES.with(|messages| {
         messages.borrow().get(&session_id).map(|list| list.0).unwrap_or_default()
-    });
+    })
+    .into_iter()
+    .skip(offset.unwrap_or(0) as usize)
+    .take(limit.map_or(usize::MAX, |l| l as usize))
+    .collect();
+    ensure_fits(&messages, "Pass offset and limit to fetch the messages in pages.")?;
     
     Ok(messages)
 }
@@ -1250,14 +1599,7 @@ fn get_session_messages(session_id: String) -> Result<Vec<ChatMessage>, String>
 fn get_session_progress(session_id: String) -> Result<ProgressUpdate, String> {
     let caller = ic_cdk::caller();
     
-    // Verify session exists and user has access
-    let session = CHAT_SESSIONS.with(|sessions| {
-        sessions.borrow().get(&session_id)
-    }).ok_or("Session not found")?;
-    
-    if session.user_id != caller {
-        return Err("You don't have permission to access this session".to_string());
-    }
+    owned_session(&session_id, caller)?;
     
     // For now, return a simple progress update
     // In a real implementation, you'd track actual progress
@@ -1279,42 +1621,42 @@ fn get_session_progress(session_id: String) -> Result<ProgressUpdate, String> {
 }
 
 #[ic_cdk::query]
-fn get_chat_session(session_id: String) -> Result<ChatSession, String> {
-    let caller = ic_cdk::caller();
+fn get_chat_session(session_id: String, session_token: Option<String>) -> Result<ChatSession, String> {
+    let caller = session_reader(session_token)?;
     
     ic_cdk::println!("Getting chat session: {} for caller: {}", session_id, caller);
     
     // Get the session
-    let session = CHAT_SESSIONS.with(|sessions| {
-        let sessions = sessions.borrow();
-        ic_cdk::println!("Available sessions: {:?}", sessions.keys().collect::<Vec<_>>());
-        sessions.get(&session_id)
-    }).ok_or("Session not found")?;
-    
-    // Verify user has access to this session
-    if session.user_id != caller {
-        ic_cdk::println!("Access denied: session user {} != caller {}", session.user_id, caller);
-        return Err("You don't have permission to access this session".to_string());
-    }
+    let session = visible_session(&session_id, caller)?;
     
     ic_cdk::println!("Successfully retrieved session: {:?}", session);
     Ok(session)
 }
 
 #[ic_cdk::query]
-fn get_user_sessions() -> Result<Vec<ChatSession>, String> {
-    let caller = ic_cdk::caller();
+fn get_user_sessions(include_archived: Option<bool>, session_token: Option<String>) -> Result<Vec<ChatSession>, String> {
+    let caller = session_reader(session_token)?;
+    let include_archived = include_archived.unwrap_or(false);
     
     ic_cdk::println!("Getting all sessions for user: {}", caller);
     
-    // Get all sessions for the current user
-    let user_sessions = CHAT_SESSIONS.with(|sessions| {
-        let sessions = sessions.borrow();
-        sessions.iter()
-            .filter(|(_, session)| session.user_id == caller)
-            .map(|(_, session)| session.clone())
-            .collect::<Vec<_>>()
-    });
+    // Get all sessions for the current user; archived ones only when asked for
+    let hint = "Archive or delete old sessions, or leave include_archived unset.";
+    let mut user_sessions = Vec::new();
+    CHAT_SESSIONS.with(|sessions| {
+        for (scanned, (_, session)) in sessions.borrow().iter().enumerate() {
+            scan_checkpoint(scanned, hint)?;
+            if session.user_id == caller && (include_archived || session.status != "archived") {
+                user_sessions.push(session);
+            }
+        }
+        Ok::<(), String>(())
+    })?;
+    for (counted, session) in user_sessions.iter_mut().enumerate() {
+        scan_checkpoint(counted, hint)?;
+        session.unread_count = session_unread_count(&session.id, caller);
+    }
+    ensure_fits(&user_sessions, hint)?;
     
     ic_cdk::println!("Found {} sessions for user", user_sessions.len());
     Ok(user_sessions)
@@ -1323,21 +1665,12 @@ fn get_user_sessions() -> Result<Vec<ChatSession>, String> {
 #[ic_cdk::update]
 async fn generate_course_modules(session_id: String) -> Result<Vec<String>, String> {
     let caller = ic_cdk::caller();
+    rate_limit::check(caller, "generation")?;
     
-    // Get the session
-    let session = CHAT_SESSIONS.with(|sessions| {
-        sessions.borrow().get(&session_id)
-    }).ok_or("Session not found")?;
-    
-    // Verify user has access to this session
-    if session.user_id != caller {
-        return Err("You don't have permission to access this session".to_string());
-    }
+    let session = owned_session(&session_id, caller)?;
     
     // Get tutor information
-    let tutor = TUTORS.with(|tutors| {
-        tutors.borrow().iter().find(|(_, t)| t.public_id == session.tutor_id).map(|(_, t)| t.clone())
-    }).ok_or("Tutor not found")?;
+    let (_, tutor) = cache::tutor_by_public_id(&session.tutor_id).ok_or("Tutor not found")?;
     
     ic_cdk::println!("Generating modules for topic: {}", session.topic);
     ic_cdk::println!("Tutor expertise: {}", tutor.expertise.join(", "));
@@ -1362,10 +1695,10 @@ async fn generate_course_modules(session_id: String) -> Result<Vec<String>, Stri
     );
     
     // Call AI to generate modules with fallback
-    let ai_response = match call_groq_ai(&prompt).await {
+    let ai_response = match call_groq_ai(&prompt, "course_modules", caller).await {
         Ok(response) => {
             ic_cdk::println!("Raw AI response for modules: {}", response);
-            response
+            process_ai_response(response, &response_processing_for(ic_cdk::caller(), "json"))
         },
         Err(e) => {
             ic_cdk::println!("AI call failed: {}, using fallback modules", e);
@@ -1451,17 +1784,21 @@ async fn generate_course_modules(session_id: String) -> Result<Vec<String>, Stri
 // Duplicate function removed - using the enhanced async version above
 
 #[ic_cdk::update]
-async fn create_chat_session(tutor_id: String, topic: String) -> Result<String, String> {
-    let caller = ic_cdk::caller();
+async fn create_chat_session(tutor_id: String, topic: String, intake_answers: Option<Vec<String>>, session_token: Option<String>) -> Result<String, String> {
+    let caller = session_caller(session_token)?;
+    rate_limit::check(caller, "generation")?;
     
     ic_cdk::println!("Creating chat session for tutor: {}, topic: {}, caller: {}", tutor_id, topic, caller);
     
-    // Verify the tutor exists and user has access
-    let tutor = TUTORS.with(|tutors| {
-        tutors.borrow().iter().find(|(_, t)| t.public_id == tutor_id).map(|(_, t)| t.clone())
-    }).ok_or("Tutor not found")?;
+    // Verify the tutor exists and user has access; public tutors can be used by anyone
+    let (_, tutor) = visible_tutor(&tutor_id, caller)
+        .ok()
+        .filter(|(_, t)| t.user_id == caller || t.visibility == Visibility::Public)
+        .ok_or("Tutor not found")?;
+    let tutor_id = tutor.public_id.clone();
     
     ic_cdk::println!("Found tutor: {:?}", tutor);
+    let (topic, intake_answers) = resolve_session_intake(&tutor, &topic, intake_answers)?;
     
     // Create a new chat session with a simple ID
     let session_id = format!("session_{}", ic_cdk::api::time());
@@ -1473,59 +1810,61 @@ async fn create_chat_session(tutor_id: String, topic: String) -> Result<String,
         status: "active".to_string(),
         created_at: ic_cdk::api::time(),
         updated_at: ic_cdk::api::time(),
+        summary: None,
+        intake_answers: intake_answers.clone(),
+        visibility: Visibility::Private,
+        archived_at: None,
+        co_learners: Vec::new(),
+        owner_left_at: None,
+        imported_from: None,
+        unread_count: 0,
+        topic_drift: None,
     };
     
     ic_cdk::println!("Created session: {:?}", session);
     
     // Store the session
     CHAT_SESSIONS.with(|sessions| {
-        sessions.borrow_mut().insert(session_id.clone(), session);
+        sessions.borrow_mut().insert(session_id.clone(), session.clone());
     });
     
-    // Create a personalized welcome message from the tutor
-    let welcome_content = generate_welcome_message(&tutor, &topic, None).await?;
-    let welcome_message = ChatMessage {
-        id: format!("welcome_{}", ic_cdk::api::time()),
-        session_id: session_id.clone(),
-        sender: "tutor".to_string(),
-        content: welcome_content,
-        timestamp: ic_cdk::api::time(),
-        has_audio: Some(false),
-    };
-    
-    // Initialize messages with the welcome message
-    CHAT_MESSAGES.with(|messages| {
-        messages.borrow_mut().insert(session_id.clone(), ChatMessageList(vec![welcome_message]));
-    });
+    // Greet the student the way this tutor is configured to
+    post_welcome_message(&tutor, &session, None, caller).await;
     
     ic_cdk::println!("Session stored successfully with ID: {} and welcome message", session_id);
     Ok(session_id)
 }
 
 #[ic_cdk::update]
-async fn delete_chat_session(session_id: String) -> Result<String, String> {
-    let caller = ic_cdk::caller();
+async fn delete_chat_session(session_id: String, session_token: Option<String>) -> Result<String, String> {
+    let caller = session_caller(session_token)?;
     
     ic_cdk::println!("Deleting chat session: {}, caller: {}", session_id, caller);
     
-    // Verify session exists and user has access
-    let session = CHAT_SESSIONS.with(|sessions| {
-        sessions.borrow().get(&session_id)
-    }).ok_or("Session not found")?;
-    
-    if session.user_id != caller {
-        return Err("You don't have permission to delete this session".to_string());
-    }
+    let session = owned_session(&session_id, caller)?;
     
     // Remove the session from storage
     CHAT_SESSIONS.with(|sessions| {
         sessions.borrow_mut().remove(&session_id);
     });
     
+    remove_session_read_cursors(&session_id);
+    
     // Remove the messages for this session
-    CHAT_MESSAGES.with(|messages| {
-        messages.borrow_mut().remove(&session_id);
+    let removed_messages = certify::remove_messages(caller, &session_id).map(|list| list.0).unwrap_or_default();
+    let bytes: u64 = removed_messages.iter().map(chat_message_bytes).sum();
+    record_storage_change(caller, "messages", -(bytes as i64));
+    let removed_deliveries: Vec<PendingDelivery> = PENDING_DELIVERIES.with(|deliveries| {
+        let mut deliveries = deliveries.borrow_mut();
+        removed_messages.iter().filter_map(|message| deliveries.remove(&message.id)).collect()
     });
+    stage_undo(
+        caller,
+        "delete_chat_session",
+        &session_id,
+        format!("Deleted session \"{}\"", session.topic),
+        UndoSnapshot::ChatSession { session: Box::new(session), messages: removed_messages, deliveries: removed_deliveries },
+    );
     
     ic_cdk::println!("Successfully deleted session: {}", session_id);
     Ok(format!("Session {} deleted successfully", session_id))
@@ -1551,99 +1890,97 @@ struct ProgressData {
 
 // Enhanced AI Functions
 #[ic_cdk::update]
-async fn validate_ai_topic(tutor_id: String, topic: String) -> Result<TopicValidation, String> {
-    let caller = ic_cdk::caller();
+async fn validate_ai_topic(tutor_id: String, topic: String, session_token: Option<String>) -> Result<TopicValidation, String> {
+    let caller = session_caller(session_token)?;
+    rate_limit::check(caller, "generation")?;
     
-    let tutor = TUTORS.with(|tutors| {
-        tutors.borrow().iter()
-            .find(|(_, t)| t.public_id == tutor_id && t.user_id == caller)
-            .map(|(_, t)| t.clone())
-    }).ok_or("Tutor not found or you don't have permission to access it")?;
+    let (_, tutor) = owned_tutor(&tutor_id, caller)?;
     
-    let validation = validate_topic(&tutor, &topic).await?;
+    let validation = validate_topic(&tutor, &topic, caller).await?;
     Ok(validation)
 }
 
 #[ic_cdk::update]
-async fn generate_ai_course_outline(tutor_id: String, topic: String) -> Result<CourseOutline, String> {
-    let caller = ic_cdk::caller();
+async fn generate_ai_course_outline(tutor_id: String, topic: String, session_token: Option<String>) -> Result<CourseOutline, String> {
+    let caller = session_caller(session_token)?;
+    rate_limit::check(caller, "generation")?;
     
-    let tutor = TUTORS.with(|tutors| {
-        tutors.borrow().iter()
-            .find(|(_, t)| t.public_id == tutor_id && t.user_id == caller)
-            .map(|(_, t)| t.clone())
-    }).ok_or("Tutor not found or you don't have permission to access it")?;
+    let (_, tutor) = owned_tutor(&tutor_id, caller)?;
     
-    let user = get_self().ok_or("User not found")?;
-    let outline = generate_course_outline(&tutor, &topic, &user.settings).await?;
+    let user = cache::user(caller).ok_or("User not found")?;
+    let outline = generate_course_outline(&tutor, &topic, &user.settings, caller).await?;
     Ok(outline)
 }
 
 #[ic_cdk::update]
-async fn send_ai_tutor_message(session_id: String, message: String) -> Result<(String, ComprehensionAnalysis), String> {
-    let caller = ic_cdk::caller();
+async fn send_ai_tutor_message(session_id: String, message: String, sources: Option<Vec<KnowledgeBaseExcerpt>>, session_token: Option<String>) -> Result<(String, ComprehensionAnalysis), String> {
+    let caller = session_caller(session_token)?;
+    rate_limit::check(caller, "chat")?;
     
-    // Get session
-    let session = CHAT_SESSIONS.with(|sessions| {
-        sessions.borrow().get(&session_id)
-    }).ok_or("Session not found")?;
+    let session = participant_session(&session_id, caller)?;
+    ensure_session_writable(&session)?;
+    let owner = session.user_id;
+    let sources = resolve_reply_sources(&session, sources.unwrap_or_default())?;
     
-    if session.user_id != caller {
-        return Err("You don't have permission to access this session".to_string());
+    // Tutor and user must still exist
+    if cache::tutor_by_public_id(&session.tutor_id).is_none() {
+        return Err("Tutor not found".to_string());
     }
-    
-    // Get tutor
-    let tutor = TUTORS.with(|tutors| {
-        tutors.borrow().iter()
-            .find(|(_, t)| t.public_id == session.tutor_id)
-            .map(|(_, t)| t.clone())
-    }).ok_or("Tutor not found")?;
-    
-    // Get user
-    let user = get_self().ok_or("User not found")?;
-    
-    // Get session history
-    let session_history = CHAT_MESSAGES.with(|messages| {
-        messages.borrow().get(&session_id).map(|msg_list| msg_list.0).unwrap_or_default()
-    });
-    
-    // Generate AI response
-    let (response, analysis) = generate_tutor_chat_response(
-        &session_id,
-        &message,
-        &session_history,
-        &tutor,
-        &user.settings,
-    ).await?;
+    cache::user(caller).ok_or("User not found")?;
+    check_storage_quota(owner, message.len() as u64)?;
     
     // Save user message
     let user_message = ChatMessage {
         id: ic_cdk::api::time().to_string(),
         session_id: session_id.clone(),
         sender: "user".to_string(),
-        content: message,
-        timestamp: ic_cdk::api::time(),
-        has_audio: Some(false),
-    };
-    
-    // Save tutor response
-    let tutor_message = ChatMessage {
-        id: (ic_cdk::api::time() + 1).to_string(),
-        session_id: session_id.clone(),
-        sender: "tutor".to_string(),
-        content: response.clone(),
+        content: message.clone(),
         timestamp: ic_cdk::api::time(),
         has_audio: Some(false),
+        delivery_status: "delivered".to_string(),
+        reading_grade: None,
+        author: Some(caller),
+        citations: Vec::new(),
+        confidence: None,
+        rating: None,
+        bookmarked: false,
     };
+    record_storage_change(owner, "messages", chat_message_bytes(&user_message) as i64);
     
-    // Update session history
-    let mut updated_history = session_history;
-    updated_history.push(user_message);
-    updated_history.push(tutor_message);
-    
-    CHAT_MESSAGES.with(|messages| {
-        messages.borrow_mut().insert(session_id.clone(), ChatMessageList(updated_history));
-    });
+    let mut session_messages = CHAT_MESSAGES.with(|messages| messages.borrow().get(&session_id)).unwrap_or_else(|| ChatMessageList(Vec::new()));
+    session_messages.0.push(user_message);
+    certify::store_messages(owner, &session_id, session_messages);
+    
+    // Generate AI response into a pending tutor message
+    let tutor_message_id = (ic_cdk::api::time() + 1).to_string();
+    start_pending_delivery(&session_id, &tutor_message_id, owner, (caller != owner).then_some(caller), "guided", &message, "");
+    set_delivery_sources(&tutor_message_id, sources);
+    if reply_in_background(&tutor_message_id) {
+        let message_id = tutor_message_id.clone();
+        ic_cdk::spawn(async move {
+            match deliver_tutor_reply(&message_id, caller).await {
+                Ok((message, Some(analysis))) => record_guided_reply(caller, &session_id, &message, &analysis),
+                Ok(_) => {}
+                Err(e) => ic_cdk::println!("Background reply {} not delivered yet: {}", message_id, e),
+            }
+        });
+        return Ok((REPLY_PENDING_CONTENT.to_string(), placeholder_analysis(Some(tutor_message_id), false)));
+    }
+    let (tutor_message, analysis) = match deliver_tutor_reply(&tutor_message_id, caller).await {
+        Err(e) if e == REPLY_DEFERRED_MESSAGE => return Ok((REPLY_DEFERRED_CONTENT.to_string(), placeholder_analysis(None, true))),
+        result => result?,
+    };
+    let analysis = analysis.ok_or("Missing comprehension analysis")?;
+    record_guided_reply(caller, &session_id, &tutor_message, &analysis);
+    Ok((tutor_message.content, analysis))
+}
+
+// Experiment outcomes and learning metrics for a delivered guided reply
+fn record_guided_reply(caller: Principal, session_id: &str, tutor_message: &ChatMessage, analysis: &ComprehensionAnalysis) {
+    record_experiment_outcome(caller, tutor_message.timestamp, |a| {
+        a.comprehension_sum += analysis.comprehension_score;
+        a.comprehension_count += 1;
+    });
     
     // Update learning metrics
     let metrics_id = next_id("learning_metrics");
@@ -1668,61 +2005,67 @@ async fn send_ai_tutor_message(session_id: String, message: String) -> Result<(S
     };
     
     LEARNING_METRICS.with(|metrics_storage| {
-        metrics_storage.borrow_mut().insert(metrics_id, metrics);
+        metrics_storage.borrow_mut().insert(metrics_id, metrics.clone());
     });
-    
-    Ok((response, analysis))
+    queue_xapi_statement(caller, session_activity_statement(&get_config(), &metrics));
+    queue_streak_check(caller);
 }
 
 #[ic_cdk::update]
-async fn create_ai_learning_session(tutor_id: String, topic: String) -> Result<(String, String), String> {
-    let caller = ic_cdk::caller();
+async fn create_ai_learning_session(tutor_id: String, topic: String, intake_answers: Option<Vec<String>>, session_token: Option<String>) -> Result<(String, String), String> {
+    let caller = session_caller(session_token)?;
+    rate_limit::check(caller, "generation")?;
     
     // Get tutor
-    let tutor = TUTORS.with(|tutors| {
-        tutors.borrow().iter()
-            .find(|(_, t)| t.public_id == tutor_id && t.user_id == caller)
-            .map(|(_, t)| t.clone())
-    }).ok_or("Tutor not found or you don't have permission to access it")?;
+    let (_, tutor) = owned_tutor(&tutor_id, caller)?;
+    let (topic, intake_answers) = resolve_session_intake(&tutor, &topic, intake_answers)?;
     
     // Get user
-    let user = get_self().ok_or("User not found")?;
+    let user = cache::user(caller).ok_or("User not found")?;
     
     // Generate course outline
-    let course_outline = generate_course_outline(&tutor, &topic, &user.settings).await?;
+    let course_outline = generate_course_outline(&tutor, &topic, &user.settings, caller).await?;
     
+    start_learning_session(caller, &tutor, topic, intake_answers, course_outline).await
+}
+
+// Creates the session, welcome message and progress record for an already generated outline
+async fn start_learning_session(
+    caller: Principal,
+    tutor: &Tutor,
+    topic: String,
+    intake_answers: Vec<IntakeAnswer>,
+    course_outline: CourseOutline,
+) -> Result<(String, String), String> {
     // Create session
     let session_id = format!("session_{}", ic_cdk::api::time());
     let session = ChatSession {
         id: session_id.clone(),
-        tutor_id: tutor_id.clone(),
+        tutor_id: tutor.public_id.clone(),
         user_id: caller,
         topic: topic.clone(),
         status: "active".to_string(),
         created_at: ic_cdk::api::time(),
         updated_at: ic_cdk::api::time(),
+        summary: None,
+        intake_answers,
+        visibility: Visibility::Private,
+        archived_at: None,
+        co_learners: Vec::new(),
+        owner_left_at: None,
+        imported_from: None,
+        unread_count: 0,
+        topic_drift: None,
     };
     
     CHAT_SESSIONS.with(|sessions| {
-        sessions.borrow_mut().insert(session_id.clone(), session);
+        sessions.borrow_mut().insert(session_id.clone(), session.clone());
     });
     
     // Generate welcome message
-    let welcome_message = generate_welcome_message(&tutor, &topic, Some(&course_outline)).await?;
-    
-    // Save welcome message
-    let welcome_msg = ChatMessage {
-        id: ic_cdk::api::time().to_string(),
-        session_id: session_id.clone(),
-        sender: "tutor".to_string(),
-        content: welcome_message.clone(),
-        timestamp: ic_cdk::api::time(),
-        has_audio: Some(false),
-    };
-    
-    CHAT_MESSAGES.with(|messages| {
-        messages.borrow_mut().insert(session_id.clone(), ChatMessageList(vec![welcome_msg]));
-    });
+    let welcome_message = post_welcome_message(tutor, &session, Some(&course_outline), caller).await
+        .map(|m| m.content)
+        .unwrap_or_default();
     
     // Create learning progress
     let progress_id = next_id("learning_progress");
@@ -1775,6 +2118,7 @@ fn get_learning_metrics(session_id: String) -> Result<Vec<LearningMetrics>, Stri
 #[ic_cdk::update]
 async fn complete_module(module_id: u64) -> Result<String, String> {
     let caller = ic_cdk::caller();
+    rate_limit::check(caller, "generation")?;
     
     // Create or update module completion
     let completion_id = next_id("module_completion");
@@ -1789,8 +2133,12 @@ async fn complete_module(module_id: u64) -> Result<String, String> {
     };
     
     MODULE_COMPLETIONS.with(|completions| {
-        completions.borrow_mut().insert(completion_id, completion);
+        completions.borrow_mut().insert(completion_id, completion.clone());
     });
+    queue_xapi_statement(caller, module_completion_statement(&get_config(), &completion));
+    record_focus_activity(caller, None, Some(module_id), ic_cdk::api::time());
+    update_concept_maps_for_module(caller, module_id).await;
+    check_course_milestone(caller, module_id).await;
     
     Ok("Module marked as completed".to_string())
 }
@@ -1809,5 +2157,20001 @@ fn get_module_completions(session_id: String) -> Result<Vec<ModuleCompletion>, S
     Ok(completions)
 }
 
+// --- Placement Tests ---
+
+const PLACEMENT_LEVELS: u32 = 5;
+
+#[derive(serde::Deserialize)]
+struct AiPlacementItem {
+    question: String,
+    options: Vec<String>,
+    correct_option: u32,
+    difficulty: u32,
+}
+
+fn fallback_placement_items(topic: &str) -> Vec<PlacementItem> {
+    let descriptors = [
+        format!("the basic vocabulary of {}", topic),
+        format!("the core concepts of {}", topic),
+        format!("solving standard problems in {}", topic),
+        format!("applying {} to unfamiliar problems", topic),
+        format!("explaining advanced {} ideas to someone else", topic),
+    ];
+    descriptors.iter().enumerate().map(|(i, descriptor)| PlacementItem {
+        question: format!("How confident are you with {}?", descriptor),
+        options: vec![
+            "Not at all".to_string(),
+            "A little".to_string(),
+            "Fairly confident".to_string(),
+            "Very confident".to_string(),
+        ],
+        correct_option: 2,
+        difficulty: i as u32 + 1,
+        kind: "self_assessment".to_string(),
+        answer: None,
+        is_correct: None,
+    }).collect()
+}
+
+async fn generate_placement_items(tutor_data: &Tutor, topic: &str, acting: Principal) -> Vec<PlacementItem> {
+    let prompt = format!(
+        "Create a placement test on '{}' for a tutor with expertise in: {}.
+        
+        Return ONLY a JSON array of 10 multiple-choice questions, two for each difficulty from 1 (easiest) to 5 (hardest):
+        [{{\"question\":\"Question\",\"options\":[\"a\",\"b\",\"c\",\"d\"],\"correct_option\":0,\"difficulty\":1}}]",
+        topic,
+        tutor_data.expertise.join(", ")
+    );
+    
+    let ai_response = match call_groq_ai(&prompt, "placement", acting).await {
+        Ok(response) => process_ai_response(response, &response_processing_for(acting, "json")),
+        Err(e) => {
+            ic_cdk::println!("Placement generation failed: {}, using self-assessment", e);
+            return fallback_placement_items(topic);
+        }
+    };
+    
+    let items: Vec<PlacementItem> = serde_json::from_str::<Vec<AiPlacementItem>>(&ai_response)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|item| {
+            item.options.len() >= 2
+                && (item.correct_option as usize) < item.options.len()
+                && (1..=PLACEMENT_LEVELS).contains(&item.difficulty)
+        })
+        .map(|item| PlacementItem {
+            question: item.question,
+            options: item.options,
+            correct_option: item.correct_option,
+            difficulty: item.difficulty,
+            kind: "quiz".to_string(),
+            answer: None,
+            is_correct: None,
+        })
+        .collect();
+    
+    // Every level needs at least one question for the test to be adaptive
+    let covers_all_levels = (1..=PLACEMENT_LEVELS).all(|level| items.iter().any(|i| i.difficulty == level));
+    if covers_all_levels {
+        items
+    } else {
+        fallback_placement_items(topic)
+    }
+}
+
+fn placement_difficulty(levels_passed: u32) -> String {
+    match levels_passed {
+        0..=1 => "beginner".to_string(),
+        2..=3 => "intermediate".to_string(),
+        _ => "advanced".to_string(),
+    }
+}
+
+fn next_placement_item(test: &PlacementTest, difficulty: u32) -> Option<usize> {
+    test.items.iter().position(|item| item.difficulty == difficulty && item.answer.is_none())
+}
+
+fn placement_result(test: &PlacementTest) -> PlacementResult {
+    let next_question = if test.status == "in_progress" {
+        test.items.get(test.current_item as usize).map(|item| PlacementQuestion {
+            test_id: test.id,
+            item_index: test.current_item,
+            question: item.question.clone(),
+            options: item.options.clone(),
+            difficulty: item.difficulty,
+        })
+    } else {
+        None
+    };
+    
+    PlacementResult {
+        test_id: test.id,
+        topic: test.topic.clone(),
+        status: test.status.clone(),
+        levels_passed: test.levels_passed,
+        questions_answered: test.items.iter().filter(|i| i.answer.is_some()).count() as u32,
+        recommended_difficulty: test.recommended_difficulty.clone(),
+        next_question,
+    }
+}
+
+fn get_owned_placement_test(test_id: u64, caller: Principal) -> Result<PlacementTest, String> {
+    let test = PLACEMENT_TESTS.with(|tests| tests.borrow().get(&test_id))
+        .ok_or("Placement test not found")?;
+    
+    if test.user_id != caller {
+        return Err("You don't have permission to access this placement test".to_string());
+    }
+    
+    Ok(test)
+}
+
+#[ic_cdk::update]
+async fn start_placement_test(tutor_id: String, topic: String) -> Result<PlacementResult, String> {
+    let caller = ic_cdk::caller();
+    rate_limit::check(caller, "generation")?;
+    
+    if topic.trim().is_empty() {
+        return Err("Topic is required".to_string());
+    }
+    
+    let (_, tutor) = owned_tutor(&tutor_id, caller)?;
+    let tutor_id = tutor.public_id.clone();
+    
+    let mut items = generate_placement_items(&tutor, topic.trim(), caller).await;
+    items.sort_by_key(|item| item.difficulty);
+    
+    let test_id = next_id("placement_test");
+    let mut test = PlacementTest {
+        id: test_id,
+        user_id: caller,
+        tutor_id,
+        topic: topic.trim().to_string(),
+        items,
+        current_item: 0,
+        levels_passed: 0,
+        status: "in_progress".to_string(),
+        recommended_difficulty: None,
+        created_at: ic_cdk::api::time(),
+        updated_at: ic_cdk::api::time(),
+        completed_at: None,
+    };
+    test.current_item = next_placement_item(&test, 1).unwrap_or(0) as u32;
+    
+    PLACEMENT_TESTS.with(|tests| {
+        tests.borrow_mut().insert(test_id, test.clone());
+    });
+    
+    Ok(placement_result(&test))
+}
+
+#[ic_cdk::update]
+fn submit_placement_answer(test_id: u64, answer: u32) -> Result<PlacementResult, String> {
+    let caller = ic_cdk::caller();
+    let mut test = get_owned_placement_test(test_id, caller)?;
+    
+    if test.status != "in_progress" {
+        return Err("This placement test is already completed".to_string());
+    }
+    
+    let index = test.current_item as usize;
+    let item = test.items.get_mut(index).ok_or("Placement question not found")?;
+    if answer as usize >= item.options.len() {
+        return Err("Invalid answer option".to_string());
+    }
+    
+    let is_correct = if item.kind == "self_assessment" {
+        answer >= item.correct_option
+    } else {
+        answer == item.correct_option
+    };
+    item.answer = Some(answer);
+    item.is_correct = Some(is_correct);
+    let difficulty = item.difficulty;
+    
+    // Correct answers move up a level; a miss gets one more try at the same level
+    // before the test exits early
+    let next = if is_correct {
+        test.levels_passed = difficulty;
+        next_placement_item(&test, difficulty + 1)
+    } else {
+        next_placement_item(&test, difficulty)
+    };
+    
+    match next {
+        Some(next_index) => test.current_item = next_index as u32,
+        None => {
+            test.status = "completed".to_string();
+            test.recommended_difficulty = Some(placement_difficulty(test.levels_passed));
+            test.completed_at = Some(ic_cdk::api::time());
+            let score = test.levels_passed as f64 * 100.0 / PLACEMENT_LEVELS as f64;
+            record_skill_assessment(caller, &test.topic, score, false, ic_cdk::api::time());
+        }
+    }
+    test.updated_at = ic_cdk::api::time();
+    
+    PLACEMENT_TESTS.with(|tests| {
+        tests.borrow_mut().insert(test_id, test.clone());
+    });
+    if let Some(statement) = placement_statement(&get_config(), &test) {
+        queue_xapi_statement(caller, statement);
+    }
+    
+    Ok(placement_result(&test))
+}
+
+#[ic_cdk::query]
+fn get_placement_result(test_id: u64) -> Result<PlacementResult, String> {
+    let test = get_owned_placement_test(test_id, ic_cdk::caller())?;
+    Ok(placement_result(&test))
+}
+
+// Sets the course's difficulty from a completed test on the same tutor and marks a share of the
+// leading pending modules as known, in proportion to the levels passed
+#[ic_cdk::update]
+fn apply_placement_to_course(test_id: u64, course_id: u64) -> Result<TutorCourse, String> {
+    let caller = ic_cdk::caller();
+    let test = get_owned_placement_test(test_id, caller)?;
+    let mut course = owned_course(course_id, caller)?;
+    
+    if test.status != "completed" {
+        return Err("Placement test is not completed yet".to_string());
+    }
+    if cache::tutor_by_public_id(&test.tutor_id).map(|(key, _)| key) != Some(course.tutor_id) {
+        return Err("The placement test was taken with a different tutor".to_string());
+    }
+    
+    course.modules.sort_by_key(|m| m.order);
+    let known_count = course.modules.len() * test.levels_passed as usize / PLACEMENT_LEVELS as usize;
+    for module in course.modules.iter_mut().take(known_count).filter(|m| m.status == "pending") {
+        module.status = "known".to_string();
+    }
+    if let Some(difficulty) = test.recommended_difficulty {
+        course.difficulty_level = difficulty;
+    }
+    // Keep the stored outline JSON in step
+    if let Ok(mut outline) = serde_json::from_str::<CourseOutline>(&course.outline) {
+        outline.difficulty_level = course.difficulty_level.clone();
+        outline.modules = course.modules.clone();
+        course.outline = serde_json::to_string(&outline).unwrap_or(course.outline);
+    }
+    
+    TUTOR_COURSES.with(|courses| courses.borrow_mut().insert(course_id, course.clone()));
+    Ok(course)
+}
+
+// --- Certificates ---
+
+fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn hmac_sha256(key: &[u8], message: &[u8]) -> Vec<u8> {
+    use sha2::{Digest, Sha256};
+    
+    const BLOCK_SIZE: usize = 64;
+    let mut block_key = if key.len() > BLOCK_SIZE { Sha256::digest(key).to_vec() } else { key.to_vec() };
+    block_key.resize(BLOCK_SIZE, 0);
+    
+    let inner_pad: Vec<u8> = block_key.iter().map(|b| b ^ 0x36).collect();
+    let outer_pad: Vec<u8> = block_key.iter().map(|b| b ^ 0x5c).collect();
+    
+    let mut inner = Sha256::new();
+    inner.update(&inner_pad);
+    inner.update(message);
+    let inner_hash = inner.finalize();
+    
+    let mut outer = Sha256::new();
+    outer.update(&outer_pad);
+    outer.update(inner_hash);
+    outer.finalize().to_vec()
+}
+
+async fn random_bytes() -> Result<Vec<u8>, String> {
+    let (bytes,) = ic_cdk::api::management_canister::main::raw_rand().await
+        .map_err(|(code, msg)| format!("Failed to get randomness: {:?} {}", code, msg))?;
+    Ok(bytes)
+}
+
+async fn certificate_signing_key() -> Result<Vec<u8>, String> {
+    let existing = CERTIFICATE_SIGNING_KEY.with(|key| key.borrow().get().clone());
+    if !existing.is_empty() {
+        return Ok(existing);
+    }
+    
+    let key = random_bytes().await?;
+    CERTIFICATE_SIGNING_KEY.with(|cell| {
+        let mut cell = cell.borrow_mut();
+        // Another call may have initialized the key while we were waiting on raw_rand
+        if cell.get().is_empty() {
+            cell.set(key).map_err(|_| "Failed to store certificate signing key".to_string())?;
+        }
+        Ok(cell.get().clone())
+    })
+}
+
+fn certificate_signature(key: &[u8], certificate: &Certificate) -> String {
+    let payload = format!(
+        "{}|{}|{}|{}|{}|{}",
+        certificate.public_id,
+        certificate.user_id,
+        certificate.display_name,
+        certificate.course_title,
+        certificate.issued_at,
+        certificate.artifact_hash.clone().unwrap_or_default()
+    );
+    hex_encode(&hmac_sha256(key, payload.as_bytes()))
+}
+
+fn certificate_verification(certificate: &Certificate) -> CertificateVerification {
+    let key = CERTIFICATE_SIGNING_KEY.with(|key| key.borrow().get().clone());
+    let signature_valid = !key.is_empty() && certificate_signature(&key, certificate) == certificate.signature;
+    
+    let status = if certificate.revoked {
+        "revoked"
+    } else if !signature_valid {
+        "invalid_signature"
+    } else {
+        "valid"
+    };
+    
+    CertificateVerification {
+        certificate_id: certificate.public_id.clone(),
+        display_name: certificate.display_name.clone(),
+        course_title: certificate.course_title.clone(),
+        issued_at: certificate.issued_at,
+        artifact_url: certificate.artifact_url.clone(),
+        signature_valid,
+        revoked: certificate.revoked,
+        revocation_reason: certificate.revocation_reason.clone(),
+        status: status.to_string(),
+    }
+}
+
+fn find_certificate(public_id: &str) -> Option<(u64, Certificate)> {
+    CERTIFICATES.with(|certificates| {
+        certificates.borrow().iter().find(|(_, c)| c.public_id == public_id)
+    })
+}
+
+// Certificates are only issued for a course the caller owns and has completed every module of;
+// the title comes from the course
+#[ic_cdk::update]
+async fn issue_certificate(
+    display_name: String,
+    course_id: u64,
+    artifact_url: Option<String>,
+    artifact_hash: Option<String>,
+) -> Result<Certificate, String> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return Err("Sign in to earn certificates".to_string());
+    }
+    if display_name.trim().is_empty() {
+        return Err("Display name is required".to_string());
+    }
+    let course = owned_course(course_id, caller)?;
+    if !course_completed(caller, &course) {
+        return Err("Complete every module of the course first".to_string());
+    }
+    
+    let key = certificate_signing_key().await?;
+    let nonce = random_bytes().await?;
+    
+    let certificate_id = next_id("certificate");
+    let mut certificate = Certificate {
+        id: certificate_id,
+        public_id: format!("cert_{}", hex_encode(&nonce[..12])),
+        user_id: caller,
+        display_name: display_name.trim().to_string(),
+        course_title: course.topic.clone(),
+        session_id: None,
+        course_id: Some(course.id),
+        artifact_url,
+        artifact_hash,
+        signature: String::new(),
+        issued_at: ic_cdk::api::time(),
+        revoked: false,
+        revoked_at: None,
+        revoked_by: None,
+        revocation_reason: None,
+    };
+    certificate.signature = certificate_signature(&key, &certificate);
+    
+    CERTIFICATES.with(|certificates| {
+        certificates.borrow_mut().insert(certificate_id, certificate.clone());
+    });
+    
+    Ok(certificate)
+}
+
+#[ic_cdk::query]
+fn get_my_certificates() -> Vec<Certificate> {
+    let caller = ic_cdk::caller();
+    CERTIFICATES.with(|certificates| {
+        certificates
+            .borrow()
+            .iter()
+            .filter(|(_, c)| c.user_id == caller)
+            .map(|(_, c)| c)
+            .collect()
+    })
+}
+
+#[ic_cdk::query]
+fn verify_certificate(certificate_id: String) -> Result<CertificateVerification, String> {
+    let (_, certificate) = find_certificate(&certificate_id).ok_or("Certificate not found")?;
+    Ok(certificate_verification(&certificate))
+}
+
+#[ic_cdk::update]
+fn revoke_certificate_admin(certificate_id: String, reason: String) -> Result<Certificate, String> {
+    let caller = ic_cdk::caller();
+    require(caller, Permission::ModerateContent)?;
+    if reason.trim().is_empty() {
+        return Err("A revocation reason is required".to_string());
+    }
+    
+    let (id, mut certificate) = find_certificate(&certificate_id).ok_or("Certificate not found")?;
+    if certificate.revoked {
+        return Err("Certificate is already revoked".to_string());
+    }
+    
+    certificate.revoked = true;
+    certificate.revoked_at = Some(ic_cdk::api::time());
+    certificate.revoked_by = Some(caller);
+    certificate.revocation_reason = Some(reason.trim().to_string());
+    
+    CERTIFICATES.with(|certificates| {
+        certificates.borrow_mut().insert(id, certificate.clone());
+    });
+    
+    Ok(certificate)
+}
+
+fn escape_html(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+fn certificate_html(verification: &CertificateVerification) -> String {
+    let status_line = match verification.status.as_str() {
+        "valid" => "This certificate is valid.".to_string(),
+        "revoked" => format!(
+            "This certificate has been revoked{}.",
+            verification.revocation_reason.as_ref().map(|r| format!(": {}", escape_html(r))).unwrap_or_default()
+        ),
+        _ => "This certificate could not be verified.".to_string(),
+    };
+    
+    format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>Certificate {id}</title></head>\
+        <body><h1>Certificate of Completion</h1>\
+        <p><strong>{name}</strong> completed <strong>{course}</strong>.</p>\
+        <p>Issued at: {issued_at} (ns since epoch)</p>\
+        <p>Certificate ID: {id}</p>\
+        <p>{status}</p></body></html>",
+        id = escape_html(&verification.certificate_id),
+        name = escape_html(&verification.display_name),
+        course = escape_html(&verification.course_title),
+        issued_at = verification.issued_at,
+        status = status_line
+    )
+}
+
+// --- HTTP Gateway ---
+
+fn http_response(status_code: u16, content_type: &str, body: Vec<u8>) -> HttpResponse {
+    HttpResponse {
+        status_code,
+        headers: vec![("Content-Type".to_string(), content_type.to_string())],
+        body,
+        upgrade: None,
+    }
+}
+
+fn http_json<T: serde::Serialize>(status_code: u16, value: &T) -> HttpResponse {
+    http_response(status_code, "application/json", serde_json::to_vec(value).unwrap_or_default())
+}
+
+fn http_not_found() -> HttpResponse {
+    http_json(404, &json!({ "error": "Not found" }))
+}
+
+fn wants_html(req: &HttpRequest, query: &str) -> bool {
+    query.split('&').any(|param| param == "format=html")
+        || req.headers.iter().any(|(name, value)| name.eq_ignore_ascii_case("accept") && value.contains("text/html"))
+}
+
+#[ic_cdk::query]
+fn http_request(req: HttpRequest) -> HttpResponse {
+    let (path, query) = match req.url.split_once('?') {
+        Some((path, query)) => (path, query),
+        None => (req.url.as_str(), ""),
+    };
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    
+    if is_catalog_path(&segments) {
+        if req.method == "OPTIONS" {
+            return catalog_preflight();
+        }
+        return HttpResponse { status_code: 200, headers: Vec::new(), body: Vec::new(), upgrade: Some(true) };
+    }
+    
+    match segments.as_slice() {
+        ["cert", certificate_id] => match find_certificate(certificate_id) {
+            Some((_, certificate)) => {
+                let verification = certificate_verification(&certificate);
+                if wants_html(&req, query) {
+                    http_response(200, "text/html; charset=utf-8", certificate_html(&verification).into_bytes())
+                } else {
+                    http_json(200, &verification)
+                }
+            }
+            None => http_not_found(),
+        },
+        ["study-pack", pack_id] => study_pack_response(pack_id),
+        ["achievement", public_id] => achievement_response(&req, query, public_id),
+        _ => http_not_found(),
+    }
+}
+
+// --- Announcements ---
+
+const ANNOUNCEMENT_KINDS: [&str; 3] = ["maintenance", "feature", "promo"];
+const ANNOUNCEMENT_AUDIENCES: [&str; 3] = ["all", "plan", "role"];
+
+fn validate_announcement(announcement: &Announcement) -> Result<(), String> {
+    if announcement.title.trim().is_empty() {
+        return Err("Title is required".to_string());
+    }
+    if announcement.body.trim().is_empty() {
+        return Err("Body is required".to_string());
+    }
+    if !ANNOUNCEMENT_KINDS.contains(&announcement.kind.as_str()) {
+        return Err(format!("Kind must be one of: {}", ANNOUNCEMENT_KINDS.join(", ")));
+    }
+    if !ANNOUNCEMENT_AUDIENCES.contains(&announcement.audience_type.as_str()) {
+        return Err(format!("Audience must be one of: {}", ANNOUNCEMENT_AUDIENCES.join(", ")));
+    }
+    if announcement.audience_type != "all" && announcement.audience_values.is_empty() {
+        return Err("At least one plan or role is required for a targeted announcement".to_string());
+    }
+    if announcement.ends_at <= announcement.starts_at {
+        return Err("End time must be after start time".to_string());
+    }
+    Ok(())
+}
+
+fn announcement_targets(announcement: &Announcement, user: Option<&User>) -> bool {
+    match announcement.audience_type.as_str() {
+        "all" => true,
+        "plan" => user.map(|u| announcement.audience_values.contains(&user_plan(u.id))).unwrap_or(false),
+        "role" => user.map(|u| announcement.audience_values.contains(&u.role)).unwrap_or(false),
+        _ => false,
+    }
+}
+
+#[ic_cdk::update]
+fn create_announcement_admin(
+    title: String,
+    body: String,
+    kind: String,
+    audience_type: String,
+    audience_values: Vec<String>,
+    starts_at: u64,
+    ends_at: u64,
+) -> Result<Announcement, String> {
+    let caller = ic_cdk::caller();
+    require(caller, Permission::ManageAnnouncements)?;
+    
+    let announcement_id = next_id("announcement");
+    let announcement = Announcement {
+        id: announcement_id,
+        title: title.trim().to_string(),
+        body: body.trim().to_string(),
+        kind,
+        audience_type,
+        audience_values,
+        starts_at,
+        ends_at,
+        is_active: true,
+        created_by: caller,
+        created_at: ic_cdk::api::time(),
+        updated_at: ic_cdk::api::time(),
+    };
+    validate_announcement(&announcement)?;
+    
+    ANNOUNCEMENTS.with(|announcements| {
+        announcements.borrow_mut().insert(announcement_id, announcement.clone());
+    });
+    
+    Ok(announcement)
+}
+
+#[ic_cdk::update]
+fn update_announcement_admin(
+    announcement_id: u64,
+    title: Option<String>,
+    body: Option<String>,
+    starts_at: Option<u64>,
+    ends_at: Option<u64>,
+    is_active: Option<bool>,
+) -> Result<Announcement, String> {
+    require(ic_cdk::caller(), Permission::ManageAnnouncements)?;
+    
+    let mut announcement = ANNOUNCEMENTS.with(|announcements| announcements.borrow().get(&announcement_id))
+        .ok_or("Announcement not found")?;
+    
+    if let Some(title) = title {
+        announcement.title = title.trim().to_string();
+    }
+    if let Some(body) = body {
+        announcement.body = body.trim().to_string();
+    }
+    if let Some(starts_at) = starts_at {
+        announcement.starts_at = starts_at;
+    }
+    if let Some(ends_at) = ends_at {
+        announcement.ends_at = ends_at;
+    }
+    if let Some(is_active) = is_active {
+        announcement.is_active = is_active;
+    }
+    validate_announcement(&announcement)?;
+    announcement.updated_at = ic_cdk::api::time();
+    
+    ANNOUNCEMENTS.with(|announcements| {
+        announcements.borrow_mut().insert(announcement_id, announcement.clone());
+    });
+    
+    Ok(announcement)
+}
+
+#[ic_cdk::query]
+fn get_announcements_admin() -> Result<Vec<Announcement>, String> {
+    require(ic_cdk::caller(), Permission::ManageAnnouncements)?;
+    Ok(ANNOUNCEMENTS.with(|announcements| announcements.borrow().iter().map(|(_, a)| a).collect()))
+}
+
+#[ic_cdk::query]
+fn get_active_announcements() -> Vec<Announcement> {
+    let caller = ic_cdk::caller();
+    let now = ic_cdk::api::time();
+    let user = cache::user(caller);
+    
+    let dismissed: Vec<u64> = ANNOUNCEMENT_DISMISSALS.with(|dismissals| {
+        dismissals
+            .borrow()
+            .iter()
+            .filter(|(_, d)| d.user_id == caller)
+            .map(|(_, d)| d.announcement_id)
+            .collect()
+    });
+    
+    ANNOUNCEMENTS.with(|announcements| {
+        announcements
+            .borrow()
+            .iter()
+            .filter(|(id, a)| {
+                a.is_active
+                    && a.starts_at <= now
+                    && now < a.ends_at
+                    && !dismissed.contains(id)
+                    && announcement_targets(a, user.as_ref())
+            })
+            .map(|(_, a)| a)
+            .collect()
+    })
+}
+
+#[ic_cdk::update]
+fn dismiss_announcement(announcement_id: u64) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    
+    if !ANNOUNCEMENTS.with(|announcements| announcements.borrow().contains_key(&announcement_id)) {
+        return Err("Announcement not found".to_string());
+    }
+    
+    let already_dismissed = ANNOUNCEMENT_DISMISSALS.with(|dismissals| {
+        dismissals.borrow().iter().any(|(_, d)| d.user_id == caller && d.announcement_id == announcement_id)
+    });
+    if already_dismissed {
+        return Ok(());
+    }
+    
+    let dismissal_id = next_id("announcement_dismissal");
+    let dismissal = AnnouncementDismissal {
+        id: dismissal_id,
+        announcement_id,
+        user_id: caller,
+        dismissed_at: ic_cdk::api::time(),
+    };
+    
+    ANNOUNCEMENT_DISMISSALS.with(|dismissals| {
+        dismissals.borrow_mut().insert(dismissal_id, dismissal);
+    });
+    
+    Ok(())
+}
+
+// --- Notifications ---
+
+fn notify_user(user_id: Principal, notification_type: &str, source: &str, content: String, related_id: Option<u64>) {
+    let item = DigestItem {
+        notification_type: notification_type.to_string(),
+        source: source.to_string(),
+        content,
+        related_id,
+        timestamp: ic_cdk::api::time(),
+    };
+    if !admit_notification(user_id, item.clone()) {
+        return;
+    }
+    store_notification(Notification {
+        id: next_id("notification"),
+        user_id,
+        notification_type: item.notification_type,
+        content: item.content,
+        is_read: false,
+        source: item.source,
+        related_id: item.related_id,
+        timestamp: item.timestamp,
+    });
+}
+
+#[ic_cdk::query]
+fn get_my_notifications(unread_only: bool) -> Vec<Notification> {
+    let caller = ic_cdk::caller();
+    let mut notifications: Vec<Notification> = NOTIFICATIONS.with(|notifications| {
+        notifications
+            .borrow()
+            .iter()
+            .filter(|(_, n)| n.user_id == caller && (!unread_only || !n.is_read))
+            .map(|(_, n)| n)
+            .collect()
+    });
+    notifications.sort_by_key(|n| std::cmp::Reverse(n.timestamp));
+    notifications
+}
+
+#[ic_cdk::update]
+fn mark_notification_read(notification_id: u64) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    
+    NOTIFICATIONS.with(|notifications| {
+        let mut notifications = notifications.borrow_mut();
+        let mut notification = notifications.get(&notification_id).ok_or("Notification not found")?;
+        if notification.user_id != caller {
+            return Err("You don't have permission to modify this notification".to_string());
+        }
+        notification.is_read = true;
+        notifications.insert(notification_id, notification);
+        Ok(())
+    })
+}
+
+#[ic_cdk::update]
+fn mark_all_notifications_read() -> u64 {
+    let caller = ic_cdk::caller();
+    
+    NOTIFICATIONS.with(|notifications| {
+        let mut notifications = notifications.borrow_mut();
+        let unread: Vec<(u64, Notification)> = notifications
+            .iter()
+            .filter(|(_, n)| n.user_id == caller && !n.is_read)
+            .collect();
+        let count = unread.len() as u64;
+        for (id, mut notification) in unread {
+            notification.is_read = true;
+            notifications.insert(id, notification);
+        }
+        count
+    })
+}
+
+// --- Support Tickets ---
+
+const TICKET_CATEGORIES: [&str; 5] = ["billing", "technical", "account", "content", "other"];
+const TICKET_STATUSES: [&str; 3] = ["open", "in_progress", "resolved"];
+const TICKET_FIRST_RESPONSE_SLA_NS: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+fn get_accessible_ticket(ticket_id: u64, caller: Principal) -> Result<SupportTicket, String> {
+    let ticket = SUPPORT_TICKETS.with(|tickets| tickets.borrow().get(&ticket_id))
+        .ok_or("Support ticket not found")?;
+    
+    if ticket.user_id != caller && !has_permission(caller, Permission::ManageSupport) {
+        return Err("You don't have permission to access this ticket".to_string());
+    }
+    
+    Ok(ticket)
+}
+
+#[ic_cdk::update]
+fn create_support_ticket(
+    category: String,
+    subject: String,
+    description: String,
+    related_entity_id: Option<String>,
+) -> Result<SupportTicket, String> {
+    let caller = ic_cdk::caller();
+    
+    if !TICKET_CATEGORIES.contains(&category.as_str()) {
+        return Err(format!("Category must be one of: {}", TICKET_CATEGORIES.join(", ")));
+    }
+    if subject.trim().is_empty() {
+        return Err("Subject is required".to_string());
+    }
+    if description.trim().is_empty() {
+        return Err("Description is required".to_string());
+    }
+    
+    let ticket_id = next_id("support_ticket");
+    let ticket = SupportTicket {
+        id: ticket_id,
+        user_id: caller,
+        category,
+        subject: subject.trim().to_string(),
+        description: description.trim().to_string(),
+        related_entity_id,
+        status: "open".to_string(),
+        messages: Vec::new(),
+        created_at: ic_cdk::api::time(),
+        updated_at: ic_cdk::api::time(),
+        first_response_at: None,
+        resolved_at: None,
+    };
+    
+    SUPPORT_TICKETS.with(|tickets| {
+        tickets.borrow_mut().insert(ticket_id, ticket.clone());
+    });
+    
+    Ok(ticket)
+}
+
+#[ic_cdk::query]
+fn get_my_support_tickets() -> Vec<SupportTicket> {
+    let caller = ic_cdk::caller();
+    SUPPORT_TICKETS.with(|tickets| {
+        tickets
+            .borrow()
+            .iter()
+            .filter(|(_, t)| t.user_id == caller)
+            .map(|(_, t)| t)
+            .collect()
+    })
+}
+
+#[ic_cdk::query]
+fn get_support_ticket(ticket_id: u64) -> Result<SupportTicket, String> {
+    get_accessible_ticket(ticket_id, ic_cdk::caller())
+}
+
+#[ic_cdk::update]
+fn reply_to_support_ticket(ticket_id: u64, content: String) -> Result<SupportTicket, String> {
+    let caller = ic_cdk::caller();
+    let mut ticket = get_accessible_ticket(ticket_id, caller)?;
+    
+    if content.trim().is_empty() {
+        return Err("Reply cannot be empty".to_string());
+    }
+    
+    let is_staff = ticket.user_id != caller;
+    let now = ic_cdk::api::time();
+    ticket.messages.push(TicketMessage {
+        id: ticket.messages.len() as u64 + 1,
+        author_id: caller,
+        is_staff,
+        content: content.trim().to_string(),
+        created_at: now,
+    });
+    
+    if is_staff {
+        if ticket.first_response_at.is_none() {
+            ticket.first_response_at = Some(now);
+        }
+        if ticket.status == "open" {
+            ticket.status = "in_progress".to_string();
+        }
+    } else if ticket.status == "resolved" {
+        // A follow-up from the user reopens the ticket
+        ticket.status = "open".to_string();
+        ticket.resolved_at = None;
+    }
+    ticket.updated_at = now;
+    
+    SUPPORT_TICKETS.with(|tickets| {
+        tickets.borrow_mut().insert(ticket_id, ticket.clone());
+    });
+    
+    if is_staff {
+        notify_user(
+            ticket.user_id,
+            "info",
+            "support",
+            format!("Support replied to your ticket \"{}\"", ticket.subject),
+            Some(ticket_id),
+        );
+    }
+    
+    Ok(ticket)
+}
+
+#[ic_cdk::update]
+fn update_support_ticket_status_admin(ticket_id: u64, status: String) -> Result<SupportTicket, String> {
+    require(ic_cdk::caller(), Permission::ManageSupport)?;
+    if !TICKET_STATUSES.contains(&status.as_str()) {
+        return Err(format!("Status must be one of: {}", TICKET_STATUSES.join(", ")));
+    }
+    
+    let mut ticket = SUPPORT_TICKETS.with(|tickets| tickets.borrow().get(&ticket_id))
+        .ok_or("Support ticket not found")?;
+    if ticket.status == status {
+        return Err(format!("Ticket is already {}", status));
+    }
+    
+    let now = ic_cdk::api::time();
+    ticket.resolved_at = if status == "resolved" { Some(now) } else { None };
+    ticket.status = status;
+    ticket.updated_at = now;
+    
+    SUPPORT_TICKETS.with(|tickets| {
+        tickets.borrow_mut().insert(ticket_id, ticket.clone());
+    });
+    
+    notify_user(
+        ticket.user_id,
+        if ticket.status == "resolved" { "success" } else { "info" },
+        "support",
+        format!("Your ticket \"{}\" is now {}", ticket.subject, ticket.status.replace('_', " ")),
+        Some(ticket_id),
+    );
+    
+    Ok(ticket)
+}
+
+#[ic_cdk::query]
+fn get_support_tickets_admin(status: Option<String>) -> Result<Vec<SupportTicket>, String> {
+    require(ic_cdk::caller(), Permission::ManageSupport)?;
+    
+    let hint = "Filter by status to narrow the list.";
+    let mut matching = Vec::new();
+    SUPPORT_TICKETS.with(|tickets| {
+        for (scanned, (_, ticket)) in tickets.borrow().iter().enumerate() {
+            scan_checkpoint(scanned, hint)?;
+            if status.as_ref().map(|s| &ticket.status == s).unwrap_or(true) {
+                matching.push(ticket);
+            }
+        }
+        Ok::<(), String>(())
+    })?;
+    ensure_fits(&matching, hint)?;
+    Ok(matching)
+}
+
+#[ic_cdk::query]
+fn get_support_metrics_admin() -> Result<SupportMetrics, String> {
+    require(ic_cdk::caller(), Permission::ManageSupport)?;
+    
+    let now = ic_cdk::api::time();
+    let mut metrics = SupportMetrics {
+        open: 0,
+        in_progress: 0,
+        resolved: 0,
+        avg_first_response_secs: None,
+        avg_resolution_secs: None,
+        breaching_first_response_sla: 0,
+    };
+    let mut first_response_times = Vec::new();
+    let mut resolution_times = Vec::new();
+    
+    SUPPORT_TICKETS.with(|tickets| {
+        for (_, ticket) in tickets.borrow().iter() {
+            match ticket.status.as_str() {
+                "open" => metrics.open += 1,
+                "in_progress" => metrics.in_progress += 1,
+                _ => metrics.resolved += 1,
+            }
+            match ticket.first_response_at {
+                Some(at) => first_response_times.push(at.saturating_sub(ticket.created_at)),
+                None if ticket.status != "resolved" && now.saturating_sub(ticket.created_at) > TICKET_FIRST_RESPONSE_SLA_NS => {
+                    metrics.breaching_first_response_sla += 1;
+                }
+                None => {}
+            }
+            if let Some(at) = ticket.resolved_at {
+                resolution_times.push(at.saturating_sub(ticket.created_at));
+            }
+        }
+    });
+    
+    let average_secs = |times: &[u64]| {
+        if times.is_empty() {
+            None
+        } else {
+            Some(times.iter().sum::<u64>() / times.len() as u64 / 1_000_000_000)
+        }
+    };
+    metrics.avg_first_response_secs = average_secs(&first_response_times);
+    metrics.avg_resolution_secs = average_secs(&resolution_times);
+    
+    Ok(metrics)
+}
+
+// --- Feedback Board ---
+
+const FEEDBACK_KINDS: [&str; 2] = ["feature", "bug"];
+const FEEDBACK_ADMIN_STATUSES: [&str; 4] = ["open", "planned", "shipped", "declined"];
+
+#[ic_cdk::update]
+fn submit_feedback(kind: String, title: String, description: String) -> Result<FeedbackItem, String> {
+    let caller = ic_cdk::caller();
+    
+    if !FEEDBACK_KINDS.contains(&kind.as_str()) {
+        return Err(format!("Kind must be one of: {}", FEEDBACK_KINDS.join(", ")));
+    }
+    if title.trim().is_empty() {
+        return Err("Title is required".to_string());
+    }
+    if description.trim().is_empty() {
+        return Err("Description is required".to_string());
+    }
+    
+    // The author's submission counts as the first vote
+    let item_id = next_id("feedback_item");
+    let item = FeedbackItem {
+        id: item_id,
+        user_id: caller,
+        kind,
+        title: title.trim().to_string(),
+        description: description.trim().to_string(),
+        status: "open".to_string(),
+        voters: vec![caller],
+        vote_count: 1,
+        merged_into: None,
+        admin_note: None,
+        created_at: ic_cdk::api::time(),
+        updated_at: ic_cdk::api::time(),
+    };
+    
+    FEEDBACK_ITEMS.with(|items| {
+        items.borrow_mut().insert(item_id, item.clone());
+    });
+    
+    Ok(item)
+}
+
+fn set_feedback_vote(item_id: u64, voted: bool) -> Result<FeedbackItem, String> {
+    let caller = ic_cdk::caller();
+    
+    FEEDBACK_ITEMS.with(|items| {
+        let mut items = items.borrow_mut();
+        let mut item = items.get(&item_id).ok_or("Feedback item not found")?;
+        if item.status == "merged" {
+            return Err("This item was merged into another one".to_string());
+        }
+        
+        let has_voted = item.voters.contains(&caller);
+        if voted && !has_voted {
+            item.voters.push(caller);
+        } else if !voted && has_voted {
+            item.voters.retain(|v| *v != caller);
+        }
+        item.vote_count = item.voters.len() as u64;
+        items.insert(item_id, item.clone());
+        Ok(item)
+    })
+}
+
+#[ic_cdk::update]
+fn upvote_feedback(item_id: u64) -> Result<FeedbackItem, String> {
+    set_feedback_vote(item_id, true)
+}
+
+#[ic_cdk::update]
+fn remove_feedback_vote(item_id: u64) -> Result<FeedbackItem, String> {
+    set_feedback_vote(item_id, false)
+}
+
+#[ic_cdk::query]
+fn get_feedback_items(status: Option<String>, kind: Option<String>, sort_by: String) -> Vec<FeedbackItem> {
+    let mut items: Vec<FeedbackItem> = FEEDBACK_ITEMS.with(|items| {
+        items
+            .borrow()
+            .iter()
+            .map(|(_, item)| item)
+            .filter(|item| match &status {
+                Some(status) => &item.status == status,
+                None => item.status != "merged",
+            })
+            .filter(|item| kind.as_ref().map(|k| &item.kind == k).unwrap_or(true))
+            .collect()
+    });
+    
+    if sort_by == "newest" {
+        items.sort_by_key(|item| std::cmp::Reverse(item.created_at));
+    } else {
+        items.sort_by_key(|item| std::cmp::Reverse((item.vote_count, item.created_at)));
+    }
+    items
+}
+
+#[ic_cdk::update]
+fn set_feedback_status_admin(item_id: u64, status: String, note: Option<String>) -> Result<FeedbackItem, String> {
+    require(ic_cdk::caller(), Permission::ModerateContent)?;
+    if !FEEDBACK_ADMIN_STATUSES.contains(&status.as_str()) {
+        return Err(format!("Status must be one of: {}", FEEDBACK_ADMIN_STATUSES.join(", ")));
+    }
+    
+    let mut item = FEEDBACK_ITEMS.with(|items| items.borrow().get(&item_id))
+        .ok_or("Feedback item not found")?;
+    if item.status == "merged" {
+        return Err("Merged items can't change status".to_string());
+    }
+    
+    item.status = status;
+    if note.is_some() {
+        item.admin_note = note;
+    }
+    item.updated_at = ic_cdk::api::time();
+    
+    FEEDBACK_ITEMS.with(|items| {
+        items.borrow_mut().insert(item_id, item.clone());
+    });
+    
+    notify_user(
+        item.user_id,
+        "info",
+        "feedback",
+        format!("Your feedback \"{}\" is now {}", item.title, item.status),
+        Some(item_id),
+    );
+    
+    Ok(item)
+}
+
+#[ic_cdk::update]
+fn merge_feedback_admin(duplicate_id: u64, target_id: u64) -> Result<FeedbackItem, String> {
+    require(ic_cdk::caller(), Permission::ModerateContent)?;
+    if duplicate_id == target_id {
+        return Err("Cannot merge an item into itself".to_string());
+    }
+    
+    FEEDBACK_ITEMS.with(|items| {
+        let mut items = items.borrow_mut();
+        let mut duplicate = items.get(&duplicate_id).ok_or("Duplicate feedback item not found")?;
+        let mut target = items.get(&target_id).ok_or("Target feedback item not found")?;
+        if duplicate.status == "merged" || target.status == "merged" {
+            return Err("Items that were already merged can't be merged again".to_string());
+        }
+        
+        // Votes carry over so the target reflects total demand
+        for voter in &duplicate.voters {
+            if !target.voters.contains(voter) {
+                target.voters.push(*voter);
+            }
+        }
+        target.vote_count = target.voters.len() as u64;
+        target.updated_at = ic_cdk::api::time();
+        
+        duplicate.status = "merged".to_string();
+        duplicate.merged_into = Some(target_id);
+        duplicate.updated_at = ic_cdk::api::time();
+        
+        items.insert(duplicate_id, duplicate.clone());
+        items.insert(target_id, target.clone());
+        
+        notify_user(
+            duplicate.user_id,
+            "info",
+            "feedback",
+            format!("Your feedback \"{}\" was merged into \"{}\"", duplicate.title, target.title),
+            Some(target_id),
+        );
+        
+        Ok(target)
+    })
+}
+
+// --- Canister Config ---
+
+fn get_config() -> CanisterConfig {
+    cache::config()
+}
+
+fn update_config<F: FnOnce(&mut CanisterConfig) -> Result<(), String>>(update: F) -> Result<CanisterConfig, String> {
+    let mut updated = cache::config();
+    update(&mut updated)?;
+    cache::store_config(updated.clone())?;
+    Ok(updated.redacted())
+}
+
+#[ic_cdk::query]
+fn get_config_admin() -> Result<CanisterConfig, String> {
+    require(ic_cdk::caller(), Permission::ManageSystem)?;
+    Ok(get_config().redacted())
+}
+
+// --- Data Retention ---
+
+const RETENTION_DATA_CLASSES: [&str; 5] = ["raw_metrics", "metric_aggregates", "read_notifications", "failed_replies", "ai_diagnostics"];
+// Upper bound on records touched per run so a pass stays well inside the instruction limit
+const RETENTION_BATCH_SIZE: usize = 500;
+
+thread_local! {
+    static LAST_RETENTION_REPORT: RefCell<Option<RetentionRunReport>> = const { RefCell::new(None) };
+}
+
+fn retention_cutoff(config: &CanisterConfig, data_class: &str, now: u64) -> Option<u64> {
+    config
+        .retention_policies
+        .iter()
+        .find(|p| p.data_class == data_class)
+        .and_then(|p| p.retention_days)
+        .map(|days| now.saturating_sub(days as u64 * NANOS_PER_DAY))
+}
+
+fn aggregate_metrics(metrics: &LearningMetrics) {
+    let day = local_day(metrics.created_at, user_offset_ns(metrics.user_id));
+    let key = format!("{}:{}", metrics.user_id, day);
+    
+    METRICS_AGGREGATES.with(|aggregates| {
+        let mut aggregates = aggregates.borrow_mut();
+        let mut aggregate = aggregates.get(&key).unwrap_or(MetricsAggregate {
+            user_id: metrics.user_id,
+            day,
+            entries: 0,
+            time_spent_minutes: 0,
+            messages_sent: 0,
+            comprehension_sum: 0.0,
+            comprehension_samples: 0,
+            updated_at: 0,
+        });
+        aggregate.entries += 1;
+        aggregate.time_spent_minutes += metrics.time_spent_minutes as u64;
+        aggregate.messages_sent += metrics.messages_sent as u64;
+        aggregate.comprehension_sum += metrics.comprehension_scores.values().sum::<f64>();
+        aggregate.comprehension_samples += metrics.comprehension_scores.len() as u32;
+        aggregate.updated_at = ic_cdk::api::time();
+        aggregates.insert(key, aggregate);
+    });
+}
+
+fn run_retention(now: u64) -> RetentionRunReport {
+    let config = get_config();
+    let on_hold = |user_id: &Principal| config.retention_holds.iter().any(|h| &h.user_id == user_id);
+    let mut report = RetentionRunReport { started_at: now, ..Default::default() };
+    
+    // Raw metrics are rolled into daily aggregates before they are deleted
+    if let Some(cutoff) = retention_cutoff(&config, "raw_metrics", now) {
+        let mut expired: Vec<(u64, LearningMetrics)> = Vec::new();
+        LEARNING_METRICS.with(|metrics| {
+            for (id, m) in metrics.borrow().iter().filter(|(_, m)| m.created_at < cutoff) {
+                if on_hold(&m.user_id) {
+                    report.skipped_on_hold += 1;
+                } else if expired.len() < RETENTION_BATCH_SIZE {
+                    expired.push((id, m));
+                } else {
+                    report.has_more = true;
+                }
+            }
+        });
+        for (id, metrics) in expired {
+            aggregate_metrics(&metrics);
+            LEARNING_METRICS.with(|m| m.borrow_mut().remove(&id));
+            report.metrics_aggregated += 1;
+            report.metrics_deleted += 1;
+        }
+    }
+    
+    if let Some(cutoff) = retention_cutoff(&config, "metric_aggregates", now) {
+        let cutoff_day = cutoff / NANOS_PER_DAY;
+        let expired: Vec<String> = METRICS_AGGREGATES.with(|aggregates| {
+            aggregates
+                .borrow()
+                .iter()
+                .filter(|(_, a)| a.day < cutoff_day && !on_hold(&a.user_id))
+                .map(|(key, _)| key)
+                .take(RETENTION_BATCH_SIZE)
+                .collect()
+        });
+        report.has_more |= expired.len() == RETENTION_BATCH_SIZE;
+        for key in expired {
+            METRICS_AGGREGATES.with(|a| a.borrow_mut().remove(&key));
+            report.aggregates_deleted += 1;
+        }
+    }
+    
+    if let Some(cutoff) = retention_cutoff(&config, "read_notifications", now) {
+        let expired: Vec<u64> = NOTIFICATIONS.with(|notifications| {
+            notifications
+                .borrow()
+                .iter()
+                .filter(|(_, n)| n.is_read && n.timestamp < cutoff && !on_hold(&n.user_id))
+                .map(|(id, _)| id)
+                .take(RETENTION_BATCH_SIZE)
+                .collect()
+        });
+        report.has_more |= expired.len() == RETENTION_BATCH_SIZE;
+        for id in expired {
+            NOTIFICATIONS.with(|n| n.borrow_mut().remove(&id));
+            report.notifications_deleted += 1;
+        }
+    }
+    
+    if let Some(cutoff) = retention_cutoff(&config, "ai_diagnostics", now) {
+        report.ai_diagnostics_deleted = prune_ai_diagnostics(cutoff);
+        report.has_more |= report.ai_diagnostics_deleted == RETENTION_BATCH_SIZE as u64;
+    }
+    
+    LAST_RETENTION_REPORT.with(|last| *last.borrow_mut() = Some(report.clone()));
+    report
+}
+
+#[ic_cdk::update]
+fn set_retention_policy_admin(data_class: String, retention_days: Option<u32>) -> Result<CanisterConfig, String> {
+    require(ic_cdk::caller(), Permission::ManageSystem)?;
+    if !RETENTION_DATA_CLASSES.contains(&data_class.as_str()) {
+        return Err(format!("Data class must be one of: {}", RETENTION_DATA_CLASSES.join(", ")));
+    }
+    if retention_days == Some(0) {
+        return Err("Retention must be at least one day".to_string());
+    }
+    
+    update_config(|config| {
+        config.retention_policies.retain(|p| p.data_class != data_class);
+        config.retention_policies.push(RetentionPolicy { data_class, retention_days });
+        Ok(())
+    })
+}
+
+#[ic_cdk::update]
+fn set_retention_hold_admin(user_id: Principal, hold: bool, reason: Option<String>) -> Result<CanisterConfig, String> {
+    let caller = ic_cdk::caller();
+    require(caller, Permission::ManageUsers)?;
+    
+    update_config(|config| {
+        config.retention_holds.retain(|h| h.user_id != user_id);
+        if hold {
+            let reason = reason.filter(|r| !r.trim().is_empty()).ok_or("A reason is required to place a hold")?;
+            config.retention_holds.push(RetentionHold {
+                user_id,
+                reason,
+                set_by: caller,
+                set_at: ic_cdk::api::time(),
+            });
+        }
+        Ok(())
+    })
+}
+
+#[ic_cdk::update]
+fn run_retention_now_admin() -> Result<RetentionRunReport, String> {
+    require(ic_cdk::caller(), Permission::ManageSystem)?;
+    Ok(run_retention(ic_cdk::api::time()))
+}
+
+#[ic_cdk::query]
+fn get_last_retention_report_admin() -> Result<Option<RetentionRunReport>, String> {
+    require(ic_cdk::caller(), Permission::ManageSystem)?;
+    Ok(LAST_RETENTION_REPORT.with(|last| last.borrow().clone()))
+}
+
+#[ic_cdk::query]
+fn get_my_metric_aggregates() -> Vec<MetricsAggregate> {
+    let caller = ic_cdk::caller();
+    METRICS_AGGREGATES.with(|aggregates| {
+        aggregates
+            .borrow()
+            .iter()
+            .filter(|(_, a)| a.user_id == caller)
+            .map(|(_, a)| a)
+            .collect()
+    })
+}
+
+// --- Scheduled Jobs ---
+
+const RETENTION_JOB_INTERVAL_NS: u64 = 60 * 60 * 1_000_000_000;
+const DELIVERY_RETRY_JOB_INTERVAL_NS: u64 = 60 * 1_000_000_000;
+
+thread_local! {
+    // Last run per job; kept on the heap so every job runs once shortly after an upgrade
+    static LAST_JOB_RUNS: RefCell<HashMap<String, u64>> = RefCell::new(HashMap::new());
+}
+
+fn job_due(job: &str, interval_ns: u64, now: u64) -> bool {
+    LAST_JOB_RUNS.with(|runs| {
+        let mut runs = runs.borrow_mut();
+        let due = runs.get(job).map(|last| now.saturating_sub(*last) >= interval_ns).unwrap_or(true);
+        if due {
+            runs.insert(job.to_string(), now);
+        }
+        due
+    })
+}
+
+fn reschedule_job(job: &str) {
+    LAST_JOB_RUNS.with(|runs| runs.borrow_mut().remove(job));
+}
+
+#[ic_cdk::heartbeat]
+fn heartbeat() {
+    let now = ic_cdk::api::time();
+    
+    if job_due("retention", RETENTION_JOB_INTERVAL_NS, now) {
+        prune_ai_call_counts(now);
+        prune_guest_sessions(now);
+        prune_auth_sessions(now);
+        rate_limit::prune(now, RETENTION_BATCH_SIZE);
+        prune_login_attempts(now);
+        let report = run_retention(now);
+        if report.has_more {
+            reschedule_job("retention");
+        }
+    }
+    
+    if job_due("delivery_retry", DELIVERY_RETRY_JOB_INTERVAL_NS, now) {
+        retry_due_deliveries(now);
+    }
+    
+    if job_due("mastery_decay", MASTERY_DECAY_JOB_INTERVAL_NS, now) {
+        apply_mastery_decay(now);
+    }
+    
+    if job_due("auto_tagging", TAGGING_JOB_INTERVAL_NS, now) {
+        run_tagging_batch(now);
+    }
+    
+    if job_due("xapi_delivery", XAPI_JOB_INTERVAL_NS, now) {
+        run_xapi_delivery(now);
+    }
+    
+    if job_due("session_archival", SESSION_ARCHIVAL_JOB_INTERVAL_NS, now) {
+        archive_idle_sessions(now);
+    }
+    
+    if job_due("undo_finalize", UNDO_JOB_INTERVAL_NS, now) {
+        finalize_expired_undo_actions(now);
+    }
+    
+    if job_due("reencode", REENCODE_JOB_INTERVAL_NS, now) && run_reencoding(now) {
+        reschedule_job("reencode");
+    }
+    
+    if job_due("compaction", COMPACTION_JOB_INTERVAL_NS, now) {
+        run_compaction(now);
+    }
+    
+    if job_due("creator_payouts", CREATOR_PAYOUT_JOB_INTERVAL_NS, now) {
+        compute_creator_payouts(now);
+    }
+    
+    if job_due("plan_expiry", PLAN_EXPIRY_JOB_INTERVAL_NS, now) {
+        notify_plan_expiry(now);
+    }
+    
+    if job_due("email_delivery", EMAIL_JOB_INTERVAL_NS, now) {
+        run_email_delivery(now);
+    }
+    
+    if job_due("seasonal_events", EVENT_JOB_INTERVAL_NS, now) {
+        run_event_transitions(now);
+    }
+    
+    if job_due("review_reminders", REVIEW_REMINDER_JOB_INTERVAL_NS, now) {
+        send_review_reminders(now);
+    }
+    
+    if job_due("notification_digests", NOTIFICATION_DIGEST_JOB_INTERVAL_NS, now) {
+        send_notification_digests(now);
+    }
+    
+    if job_due("topic_drift", DRIFT_JOB_INTERVAL_NS, now) {
+        run_drift_checks();
+    }
+    
+    if job_due("persona_eval", PERSONA_EVAL_JOB_INTERVAL_NS, now) {
+        run_persona_evaluations(now);
+    }
+    
+    if job_due("streak_milestones", STREAK_JOB_INTERVAL_NS, now) {
+        run_streak_checks(now);
+    }
+    
+    if job_due("focus_sessions", FOCUS_JOB_INTERVAL_NS, now) {
+        run_focus_checks(now);
+    }
+    
+    if job_due("daily_questions", DAILY_QUESTION_JOB_INTERVAL_NS, now) {
+        run_daily_questions(now);
+    }
+    
+    if job_due("peer_tutoring", PEER_TUTORING_JOB_INTERVAL_NS, now) {
+        run_peer_tutoring_checks(now);
+    }
+    
+    if job_due("org_imports", ORG_IMPORT_JOB_INTERVAL_NS, now) {
+        run_org_imports(now);
+    }
+    
+    if job_due("learner_risk", LEARNER_RISK_JOB_INTERVAL_NS, now) {
+        run_learner_risk(now);
+    }
+    
+    if job_due("principal_rekey", REKEY_JOB_INTERVAL_NS, now) && run_principal_rekeys() {
+        reschedule_job("principal_rekey");
+    }
+}
+
+// --- Storage Accounting ---
+
+fn chat_message_bytes(message: &ChatMessage) -> u64 {
+    (message.id.len() + message.session_id.len() + message.sender.len() + message.content.len() + 16) as u64
+}
+
+// A running gift or comp grant overrides the user's own subscription
+fn user_plan(user_id: Principal) -> String {
+    granted_plan(user_id, ic_cdk::api::time()).unwrap_or_else(|| {
+        cache::user(user_id)
+            .map(|u| u.subscription)
+            .unwrap_or_else(|| "free".to_string())
+    })
+}
+
+fn plan_limits(plan: &str) -> Option<PlanLimits> {
+    get_config().plan_limits.into_iter().find(|l| l.plan == plan)
+}
+
+fn storage_usage(user_id: Principal) -> StorageUsage {
+    STORAGE_USAGE.with(|usage| usage.borrow().get(&user_id)).unwrap_or_default()
+}
+
+fn record_storage_change(user_id: Principal, category: &str, delta: i64) {
+    STORAGE_USAGE.with(|usage| {
+        let mut usage = usage.borrow_mut();
+        let mut current = usage.get(&user_id).unwrap_or_default();
+        let apply = |value: u64| if delta >= 0 { value.saturating_add(delta as u64) } else { value.saturating_sub(delta.unsigned_abs()) };
+        match category {
+            "messages" => current.message_bytes = apply(current.message_bytes),
+            "knowledge_base" => current.knowledge_base_bytes = apply(current.knowledge_base_bytes),
+            _ => return,
+        }
+        current.updated_at = ic_cdk::api::time();
+        usage.insert(user_id, current);
+    });
+}
+
+fn check_storage_quota(user_id: Principal, additional_bytes: u64) -> Result<(), String> {
+    enforce_quota(user_id, "storage", additional_bytes)
+}
+
+fn storage_report(user_id: Principal, usage: StorageUsage) -> StorageUsageReport {
+    let plan = user_plan(user_id);
+    let quota_bytes = plan_limits(&plan).map(|l| l.storage_bytes).unwrap_or(0);
+    StorageUsageReport {
+        user_id,
+        plan,
+        message_bytes: usage.message_bytes,
+        knowledge_base_bytes: usage.knowledge_base_bytes,
+        total_bytes: usage.total_bytes(),
+        quota_bytes,
+        warning: quota_status(user_id, "storage").warning,
+    }
+}
+
+#[ic_cdk::query]
+fn get_my_storage_usage() -> StorageUsageReport {
+    let caller = ic_cdk::caller();
+    storage_report(caller, storage_usage(caller))
+}
+
+#[ic_cdk::query]
+fn get_storage_report_admin(limit: u32) -> Result<Vec<StorageUsageReport>, String> {
+    require(ic_cdk::caller(), Permission::ManageSystem)?;
+    
+    let mut usages: Vec<(Principal, StorageUsage)> = STORAGE_USAGE.with(|usage| usage.borrow().iter().collect());
+    usages.sort_by_key(|(_, u)| std::cmp::Reverse(u.total_bytes()));
+    
+    let reports: Vec<StorageUsageReport> = usages
+        .into_iter()
+        .take(limit as usize)
+        .map(|(user_id, usage)| storage_report(user_id, usage))
+        .collect();
+    ensure_fits(&reports, "Lower the limit.")?;
+    Ok(reports)
+}
+
+// Rebuilds usage from stored data, e.g. for records written before accounting existed
+#[ic_cdk::update]
+fn recompute_storage_usage_admin() -> Result<u64, String> {
+    require(ic_cdk::caller(), Permission::ManageSystem)?;
+    
+    let now = ic_cdk::api::time();
+    let mut totals: HashMap<Principal, StorageUsage> = HashMap::new();
+    
+    let session_owners: HashMap<String, Principal> = CHAT_SESSIONS.with(|sessions| {
+        sessions.borrow().iter().map(|(id, s)| (id, s.user_id)).collect()
+    });
+    CHAT_MESSAGES.with(|messages| {
+        for (session_id, list) in messages.borrow().iter() {
+            if let Some(owner) = session_owners.get(&session_id) {
+                let bytes: u64 = list.0.iter().map(chat_message_bytes).sum();
+                totals.entry(*owner).or_default().message_bytes += bytes;
+            }
+        }
+    });
+    KNOWLEDGE_BASE_FILES.with(|files| {
+        for (_, file) in files.borrow().iter() {
+            totals.entry(file.user_id).or_default().knowledge_base_bytes += file.file_size;
+        }
+    });
+    
+    let count = totals.len() as u64;
+    STORAGE_USAGE.with(|usage| {
+        let mut usage = usage.borrow_mut();
+        let stale: Vec<Principal> = usage.iter().map(|(id, _)| id).collect();
+        for user_id in stale {
+            usage.remove(&user_id);
+        }
+        for (user_id, mut total) in totals {
+            total.updated_at = now;
+            usage.insert(user_id, total);
+        }
+    });
+    
+    Ok(count)
+}
+
+// --- Knowledge Base Files ---
+
+#[ic_cdk::update]
+fn add_knowledge_base_file(
+    tutor_id: String,
+    file_name: String,
+    file_size: u64,
+    file_type: String,
+    chunks_processed: u32,
+    processing_time: f64,
+    chunk_hashes: Option<Vec<String>>,
+) -> Result<KnowledgeBaseFile, String> {
+    let caller = ic_cdk::caller();
+    let (tutor_key, _) = owned_tutor(&tutor_id, caller)?;
+    
+    if file_name.trim().is_empty() {
+        return Err("File name is required".to_string());
+    }
+    if let Some(hashes) = &chunk_hashes {
+        validate_chunk_hashes(hashes)?;
+    }
+    check_storage_quota(caller, file_size)?;
+    
+    let file_id = next_id("knowledge_base_file");
+    let file = KnowledgeBaseFile {
+        id: file_id,
+        public_id: file_id.to_string(),
+        tutor_id: tutor_key,
+        user_id: caller,
+        file_name: file_name.trim().to_string(),
+        file_size,
+        file_type,
+        chunks_processed,
+        processing_time,
+        status: "completed".to_string(),
+        error_message: None,
+        created_at: ic_cdk::api::time(),
+        updated_at: ic_cdk::api::time(),
+        deleted_at: None,
+        version: 0,
+    };
+    let file = match chunk_hashes {
+        Some(hashes) => store_kb_file_version(file, file_size, hashes),
+        None => file,
+    };
+    
+    KNOWLEDGE_BASE_FILES.with(|files| {
+        files.borrow_mut().insert(file_id, file.clone());
+    });
+    record_storage_change(caller, "knowledge_base", file_size as i64);
+    
+    Ok(file)
+}
+
+#[ic_cdk::query]
+fn get_knowledge_base_files(tutor_id: String) -> Result<Vec<KnowledgeBaseFile>, String> {
+    let (tutor_key, _) = owned_tutor(&tutor_id, ic_cdk::caller())?;
+    Ok(KNOWLEDGE_BASE_FILES.with(|files| {
+        files
+            .borrow()
+            .iter()
+            .filter(|(_, f)| f.tutor_id == tutor_key && f.deleted_at.is_none())
+            .map(|(_, f)| f)
+            .collect()
+    }))
+}
+
+#[ic_cdk::update]
+fn delete_knowledge_base_file(file_id: u64) -> Result<String, String> {
+    let caller = ic_cdk::caller();
+    
+    let mut file = owned_kb_file(file_id, caller)?;
+    if file.deleted_at.is_some() {
+        return Err("Knowledge base file not found".to_string());
+    }
+    
+    // Linked tutors keep using the file until their links drop it
+    let references = kb_ref_count(&file);
+    if references > 0 {
+        file.deleted_at = Some(ic_cdk::api::time());
+        KNOWLEDGE_BASE_FILES.with(|files| files.borrow_mut().insert(file_id, file));
+        return Ok(format!("Knowledge base file deleted; it is kept until {} linked tutor(s) stop using it", references));
+    }
+    
+    KNOWLEDGE_BASE_FILES.with(|files| {
+        files.borrow_mut().remove(&file_id);
+    });
+    remove_kb_file_versions(file_id);
+    record_storage_change(caller, "knowledge_base", -(file.file_size as i64));
+    
+    Ok("Knowledge base file deleted successfully".to_string())
+}
+
+// --- User Sharding ---
+//
+// Users are pinned to one shard canister. Assignment is local data first, then an explicit
+// migration record, then a stable hash of the principal over shards accepting new users.
+// The registry canister, when configured, is the source of truth for other services.
+//
+// Migration only moves accounts that hold nothing beyond what UserDataBundle carries: the
+// profile, tutors, knowledge base, chat sessions, storage usage and educator verification. An
+// account with learning records, billing, social or organization data of any kind can't be
+// moved, which in practice means new or nearly empty accounts. While the copy is in flight the
+// user's ingress calls are turned away; since that check isn't replicated, the source exports
+// the account again once the target answers and undoes the move if anything changed.
+
+const SHARD_MIGRATING: &str = "This account is being moved to another shard; try again in a moment";
+
+thread_local! {
+    // Users being copied to another shard, with the session keys that act for them. Their writes
+    // are refused until the import call returns, so nothing written meanwhile is left behind.
+    static MIGRATING_USERS: RefCell<HashMap<Principal, Vec<Principal>>> = RefCell::new(HashMap::new());
+}
+
+fn ensure_not_migrating(user_id: Principal) -> Result<(), String> {
+    if MIGRATING_USERS.with(|migrating| migrating.borrow().contains_key(&user_id)) {
+        return Err(SHARD_MIGRATING.to_string());
+    }
+    Ok(())
+}
+
+// Turns away ingress update calls from a migrating user or their session keys; session-token
+// endpoints and chat sessions check again in replicated execution, and migrate_user_admin
+// checks the account didn't change before it commits
+#[ic_cdk::inspect_message]
+fn inspect_message() {
+    let caller = ic_cdk::caller();
+    let migrating = MIGRATING_USERS.with(|migrating| {
+        migrating.borrow().iter().any(|(user_id, keys)| *user_id == caller || keys.contains(&caller))
+    });
+    if !migrating {
+        ic_cdk::api::call::accept_message();
+    }
+}
+
+macro_rules! holds_records {
+    ($map:ident, $user:expr) => {
+        $map.with(|map| map.borrow().values().any(|record| record.user_id == $user))
+    };
+}
+
+// Stores the migration bundle doesn't carry. Certificates are signed with this shard's key, and
+// the rest have per-canister ids or references that import_user_data can't remap, so users who
+// have any of them stay where they are.
+fn unmigratable_user_data(user_id: Principal) -> Vec<&'static str> {
+    let tutor_ids: Vec<u64> = TUTORS.with(|tutors| tutors.borrow().iter().filter(|(_, t)| t.user_id == user_id).map(|(id, _)| id).collect());
+    let stores = [
+        ("tutor courses", TUTOR_COURSES.with(|c| c.borrow().values().any(|c| tutor_ids.contains(&c.tutor_id)))),
+        ("persona evaluations", PERSONA_EVALUATIONS.with(|e| {
+            let evaluations = e.borrow();
+            tutor_ids.iter().any(|id| evaluations.range(format!("{:020}:", id)..format!("{:020};", id)).next().is_some())
+        })),
+        ("a principal link still moving records", PRINCIPAL_REKEYS.with(|r| r.borrow().values().any(|r| r.new == user_id))),
+        ("certificates", holds_records!(CERTIFICATES, user_id)),
+        ("placement tests", holds_records!(PLACEMENT_TESTS, user_id)),
+        ("exams", holds_records!(EXAMS, user_id)),
+        ("notifications", holds_records!(NOTIFICATIONS, user_id)),
+        ("subscriptions", holds_records!(USER_SUBSCRIPTIONS, user_id)),
+        ("payments", holds_records!(PAYMENT_TRANSACTIONS, user_id)),
+        ("plan grants", holds_records!(PLAN_GRANTS, user_id)),
+        ("refund requests", holds_records!(REFUND_REQUESTS, user_id)),
+        ("achievements", holds_records!(USER_ACHIEVEMENTS, user_id)),
+        ("task completions", holds_records!(USER_TASK_COMPLETIONS, user_id)),
+        ("learning progress", holds_records!(LEARNING_PROGRESS, user_id)),
+        ("learning metrics", holds_records!(LEARNING_METRICS, user_id)),
+        ("module completions", holds_records!(MODULE_COMPLETIONS, user_id)),
+        ("skill proficiency", holds_records!(SKILL_PROFICIENCY, user_id)),
+        ("tutor sessions", holds_records!(TUTOR_SESSIONS, user_id)),
+        ("support tickets", holds_records!(SUPPORT_TICKETS, user_id)),
+        ("feedback", holds_records!(FEEDBACK_ITEMS, user_id)),
+        ("group memberships", holds_records!(GROUP_MEMBERSHIPS, user_id)),
+        ("cohort enrollments", holds_records!(COHORT_ENROLLMENTS, user_id)),
+        ("organization memberships", holds_records!(ORG_MEMBERS, user_id)),
+        ("event participation", holds_records!(EVENT_PARTICIPATION, user_id)),
+        ("pending AI replies", holds_records!(PENDING_DELIVERIES, user_id)),
+        ("connections", CONNECTIONS.with(|c| c.borrow().values().any(|c| c.user1_id == user_id || c.user2_id == user_id))),
+        ("peer tutoring bookings", PEER_BOOKINGS.with(|b| b.borrow().values().any(|b| b.tutor_id == user_id || b.learner_id == user_id))),
+        ("creator earnings", CREATOR_USAGE.with(|u| u.borrow().values().any(|u| u.creator_id == user_id))),
+        ("gift subscriptions", GIFT_SUBSCRIPTIONS.with(|g| g.borrow().values().any(|g| g.purchaser_id == user_id || g.recipient_principal == Some(user_id)))),
+        ("trial history", holds_records!(TRIAL_HISTORY, user_id)),
+        ("milestones", holds_records!(MILESTONES, user_id)),
+        ("activity posts", holds_records!(ACTIVITY_POSTS, user_id)),
+        ("focus sessions", holds_records!(FOCUS_SESSIONS, user_id)),
+        ("daily answers", holds_records!(DAILY_ANSWERS, user_id)),
+        ("flashcard reviews", holds_records!(CARD_SCHEDULES, user_id)),
+        ("study resources", holds_records!(STUDY_RESOURCES, user_id)),
+        ("study groups", STUDY_GROUPS.with(|g| g.borrow().values().any(|g| g.creator_id == user_id))),
+        ("cohorts", COHORTS.with(|c| c.borrow().values().any(|c| c.created_by == user_id))),
+        ("discussion posts", holds_records!(DISCUSSION_POSTS, user_id)),
+        ("co-learning sessions", CHAT_SESSIONS.with(|s| s.borrow().values().any(|s| s.co_learners.iter().any(|c| c.user_id == user_id)))),
+        ("organizations", ORGANIZATIONS.with(|o| o.borrow().values().any(|o| o.admins.contains(&user_id)))),
+        ("learner risk flags", holds_records!(LEARNER_RISK_FLAGS, user_id)),
+        ("peer tutor profile", PEER_TUTORS.with(|p| p.borrow().contains_key(&user_id))),
+        ("experiment assignments", holds_records!(EXPERIMENT_ASSIGNMENTS, user_id)),
+        ("embed tokens", EMBED_TOKENS.with(|t| t.borrow().values().any(|t| t.owner == user_id))),
+        ("email exchanges", holds_records!(EMAIL_EXCHANGES, user_id)),
+        ("low-confidence replies", holds_records!(LOW_CONFIDENCE_REPLIES, user_id)),
+        ("xAPI statements", holds_records!(XAPI_OUTBOX, user_id)),
+    ];
+    stores.into_iter().filter(|(_, held)| *held).map(|(name, _)| name).collect()
+}
+
+fn shard_peers() -> Vec<Principal> {
+    get_config().sharding.shards.iter().map(|s| s.canister_id).collect()
+}
+
+fn hashed_shard(user_id: Principal, shards: &[ShardInfo]) -> Option<Principal> {
+    use sha2::{Digest, Sha256};
+    
+    let open: Vec<&ShardInfo> = shards.iter().filter(|s| s.accepting_new_users).collect();
+    if open.is_empty() {
+        return None;
+    }
+    
+    let digest = Sha256::digest(user_id.as_slice());
+    let mut prefix = [0u8; 8];
+    prefix.copy_from_slice(&digest[..8]);
+    let index = (u64::from_be_bytes(prefix) % open.len() as u64) as usize;
+    Some(open[index].canister_id)
+}
+
+fn route_user(user_id: Principal) -> ShardRoute {
+    let local = ic_cdk::id();
+    let canister_id = if USERS.with(|users| users.borrow().contains_key(&user_id)) {
+        local
+    } else if let Some(shard) = USER_SHARDS.with(|shards| shards.borrow().get(&user_id)) {
+        shard
+    } else {
+        hashed_shard(user_id, &get_config().sharding.shards).unwrap_or(local)
+    };
+    
+    ShardRoute { user_id, canister_id, is_local: canister_id == local }
+}
+
+async fn registry_lookup_shard(registry: Principal, user_id: Principal) -> Result<Option<Principal>, String> {
+    let (shard,): (Option<Principal>,) = ic_cdk::call(registry, "lookup_user_shard", (user_id,))
+        .await
+        .map_err(|(code, msg)| format!("Registry lookup failed: {:?} - {}", code, msg))?;
+    Ok(shard)
+}
+
+async fn registry_assign_shard(registry: Principal, user_id: Principal, shard: Principal) -> Result<(), String> {
+    ic_cdk::call::<_, ()>(registry, "assign_user_shard", (user_id, shard))
+        .await
+        .map_err(|(code, msg)| format!("Registry update failed: {:?} - {}", code, msg))
+}
+
+#[ic_cdk::query]
+fn get_user_shard(user_id: Principal) -> ShardRoute {
+    route_user(user_id)
+}
+
+// Like get_user_shard, but asks the registry when this shard has no record of the user
+#[ic_cdk::update]
+async fn resolve_user_shard(user_id: Principal) -> Result<ShardRoute, String> {
+    let route = route_user(user_id);
+    let known = route.is_local && USERS.with(|users| users.borrow().contains_key(&user_id))
+        || USER_SHARDS.with(|shards| shards.borrow().contains_key(&user_id));
+    
+    let Some(registry) = get_config().sharding.registry_canister else {
+        return Ok(route);
+    };
+    if known {
+        return Ok(route);
+    }
+    
+    match registry_lookup_shard(registry, user_id).await? {
+        Some(canister_id) => Ok(ShardRoute { user_id, canister_id, is_local: canister_id == ic_cdk::id() }),
+        None => Ok(route),
+    }
+}
+
+// Forwards a call for a user who lives on another shard. The target shard acts on behalf
+// of the original caller because only configured peers may use handle_forwarded_call.
+#[ic_cdk::update]
+async fn forward_user_call(method: String, args: Vec<u8>) -> Result<Vec<u8>, String> {
+    let caller = ic_cdk::caller();
+    let route = route_user(caller);
+    if route.is_local {
+        return Err("This user is served by this canister; call the method directly".to_string());
+    }
+    
+    let (result,): (Result<Vec<u8>, String>,) = ic_cdk::call(route.canister_id, "handle_forwarded_call", (caller, method, args))
+        .await
+        .map_err(|(code, msg)| format!("Forwarded call failed: {:?} - {}", code, msg))?;
+    result
+}
+
+#[ic_cdk::update]
+fn handle_forwarded_call(user_id: Principal, method: String, _args: Vec<u8>) -> Result<Vec<u8>, String> {
+    if !shard_peers().contains(&ic_cdk::caller()) {
+        return Err("Only peer shards can forward calls".to_string());
+    }
+    if !route_user(user_id).is_local {
+        return Err("User is not served by this shard".to_string());
+    }
+    
+    read_only_view_as(user_id, &method)
+}
+
+// Read-only methods that take no arguments beyond the acting user, answered as that user.
+// Shared by shard forwarding and support impersonation.
+fn read_only_view_as(user_id: Principal, method: &str) -> Result<Vec<u8>, String> {
+    let encoded = match method {
+        "get_self" => candid::encode_one(cache::user(user_id)), // without the password hash and salt
+        "get_user_sessions" => candid::encode_one(CHAT_SESSIONS.with(|sessions| {
+            sessions.borrow().iter().filter(|(_, s)| s.user_id == user_id).map(|(_, s)| s).collect::<Vec<ChatSession>>()
+        })),
+        "get_my_storage_usage" => candid::encode_one(storage_report(user_id, storage_usage(user_id))),
+        "get_my_quota_status" => candid::encode_one(QUOTA_RESOURCES.iter().map(|r| quota_status(user_id, r)).collect::<Vec<_>>()),
+        "get_my_plan_usage" => candid::encode_one(plan_usage(user_id)),
+        "get_my_support_tickets" => candid::encode_one(SUPPORT_TICKETS.with(|tickets| {
+            tickets.borrow().iter().filter(|(_, t)| t.user_id == user_id).map(|(_, t)| t).collect::<Vec<SupportTicket>>()
+        })),
+        "get_my_notifications" => candid::encode_one(NOTIFICATIONS.with(|notifications| {
+            notifications.borrow().iter().filter(|(_, n)| n.user_id == user_id).map(|(_, n)| n).collect::<Vec<Notification>>()
+        })),
+        _ => return Err(format!("Method '{}' is not available as a read-only view", method)),
+    };
+    
+    encoded.map_err(|e| format!("Failed to encode response: {}", e))
+}
+
+#[ic_cdk::update]
+fn set_sharding_config_admin(sharding: ShardingConfig) -> Result<CanisterConfig, String> {
+    require(ic_cdk::caller(), Permission::ManageSystem)?;
+    
+    let mut seen = std::collections::HashSet::new();
+    if !sharding.shards.iter().all(|s| seen.insert(s.canister_id)) {
+        return Err("Each shard can only be listed once".to_string());
+    }
+    
+    update_config(|config| {
+        config.sharding = sharding;
+        Ok(())
+    })
+}
+
+fn export_user_data(user_id: Principal) -> UserDataBundle {
+    let tutors: Vec<Tutor> = TUTORS.with(|tutors| {
+        tutors.borrow().iter().filter(|(_, t)| t.user_id == user_id).map(|(_, t)| t).collect()
+    });
+    let knowledge_base_files: Vec<KnowledgeBaseFile> = KNOWLEDGE_BASE_FILES.with(|files| {
+        files.borrow().iter().filter(|(_, f)| f.user_id == user_id).map(|(_, f)| f).collect()
+    });
+    let knowledge_base_links = KNOWLEDGE_BASE_LINKS.with(|links| {
+        links.borrow().iter().filter(|(_, l)| l.user_id == user_id).map(|(_, l)| l).collect()
+    });
+    let knowledge_base_file_versions = knowledge_base_files.iter().flat_map(|f| kb_file_versions(f.id)).collect();
+    let chat_sessions: Vec<ChatSession> = CHAT_SESSIONS.with(|sessions| {
+        sessions.borrow().iter().filter(|(_, s)| s.user_id == user_id).map(|(_, s)| s).collect()
+    });
+    let chat_messages = CHAT_MESSAGES.with(|messages| {
+        let messages = messages.borrow();
+        chat_sessions
+            .iter()
+            .filter_map(|s| messages.get(&s.id).map(|list| (s.id.clone(), list.0)))
+            .collect()
+    });
+    
+    let user = cache::user(user_id);
+    UserDataBundle {
+        user_id,
+        password_hash: user.as_ref().and_then(|u| u.password_hash.clone()),
+        password_salt: user.as_ref().and_then(|u| u.password_salt.clone()),
+        user,
+        tutors,
+        knowledge_base_files,
+        chat_sessions,
+        chat_messages,
+        storage_usage: STORAGE_USAGE.with(|usage| usage.borrow().get(&user_id)),
+        exported_at: ic_cdk::api::time(),
+        knowledge_base_links,
+        knowledge_base_file_versions,
+        educator_verification: EDUCATOR_VERIFICATIONS.with(|v| v.borrow().get(&user_id)),
+    }
+}
+
+fn remove_user_data(bundle: &UserDataBundle) {
+    if let Some(user) = cache::remove_user(bundle.user_id) {
+        remove_user_oauth_identity(&user);
+    }
+    remove_user_auth_sessions(bundle.user_id);
+    NOTIFICATION_LEDGERS.with(|ledgers| ledgers.borrow_mut().remove(&bundle.user_id));
+    PLAN_FRAMINGS.with(|framings| framings.borrow_mut().remove(&bundle.user_id));
+    remove_user_milestones(bundle.user_id);
+    remove_user_activity(bundle.user_id);
+    remove_user_focus(bundle.user_id);
+    remove_user_daily_answers(bundle.user_id);
+    for tutor in &bundle.tutors {
+        cache::remove_tutor(tutor.id);
+    }
+    KNOWLEDGE_BASE_FILES.with(|files| {
+        let mut files = files.borrow_mut();
+        for file in &bundle.knowledge_base_files {
+            files.remove(&file.id);
+        }
+    });
+    for file in &bundle.knowledge_base_files {
+        remove_kb_file_versions(file.id);
+    }
+    KNOWLEDGE_BASE_LINKS.with(|links| {
+        let mut links = links.borrow_mut();
+        for link in &bundle.knowledge_base_links {
+            links.remove(&link.id);
+        }
+    });
+    CHAT_SESSIONS.with(|sessions| {
+        let mut sessions = sessions.borrow_mut();
+        for session in &bundle.chat_sessions {
+            sessions.remove(&session.id);
+        }
+    });
+    for (session_id, _) in &bundle.chat_messages {
+        certify::remove_messages(bundle.user_id, session_id);
+    }
+    for session in &bundle.chat_sessions {
+        remove_session_read_cursors(&session.id);
+    }
+    // Study packs and concept maps don't travel in the bundle; the user can generate them again after moving
+    let packs: Vec<StudyPack> = STUDY_PACKS.with(|packs| packs.borrow().values().filter(|p| p.user_id == bundle.user_id).collect());
+    for pack in packs {
+        remove_study_pack(&pack);
+    }
+    CONCEPT_MAPS.with(|maps| {
+        let mut maps = maps.borrow_mut();
+        let keys: Vec<String> = maps.range(format!("{}:", bundle.user_id)..format!("{};", bundle.user_id)).map(|(key, _)| key).collect();
+        for key in keys {
+            maps.remove(&key);
+        }
+    });
+    REVIEW_REMINDERS.with(|reminders| {
+        let mut reminders = reminders.borrow_mut();
+        let keys: Vec<String> = reminders.range(format!("{}:", bundle.user_id)..format!("{};", bundle.user_id)).map(|(key, _)| key).collect();
+        for key in keys {
+            reminders.remove(&key);
+        }
+    });
+    // Cards stay with their group; only the learner's own review schedules go
+    CARD_SCHEDULES.with(|schedules| {
+        let mut schedules = schedules.borrow_mut();
+        let keys: Vec<String> = schedules.iter().filter(|(_, s)| s.user_id == bundle.user_id).map(|(key, _)| key).collect();
+        for key in keys {
+            schedules.remove(&key);
+        }
+    });
+    STORAGE_USAGE.with(|usage| {
+        usage.borrow_mut().remove(&bundle.user_id);
+    });
+    EDUCATOR_VERIFICATIONS.with(|v| {
+        v.borrow_mut().remove(&bundle.user_id);
+    });
+    // Tutor addresses belong to this canister's inbound domain and don't move with the user
+    TUTOR_EMAIL_ADDRESSES.with(|addresses| {
+        let mut addresses = addresses.borrow_mut();
+        let owned: Vec<String> = addresses.iter().filter(|(_, a)| a.user_id == bundle.user_id).map(|(token, _)| token).collect();
+        for token in owned {
+            addresses.remove(&token);
+        }
+    });
+    let links: Vec<BotLink> = BOT_LINKS.with(|links| links.borrow().iter().map(|(_, l)| l).filter(|l| l.user_id == bundle.user_id).collect());
+    for link in links {
+        remove_bot_link(&link.platform, &link.external_user_id);
+    }
+}
+
+// Receives a user from another shard. Numeric ids are per-canister, so tutors and files are
+// re-keyed here; public ids and session ids are kept as they are what clients hold.
+#[ic_cdk::update]
+fn import_user_data(bundle: UserDataBundle) -> Result<MigrationReport, String> {
+    let caller = ic_cdk::caller();
+    if !shard_peers().contains(&caller) && !has_permission(caller, Permission::ManageUsers) {
+        return Err("Only peer shards or admins can import user data".to_string());
+    }
+    if USERS.with(|users| users.borrow().contains_key(&bundle.user_id)) {
+        return Err("User already exists on this shard".to_string());
+    }
+    
+    let mut tutor_ids = HashMap::new();
+    for tutor in &bundle.tutors {
+        let mut tutor = tutor.clone();
+        let new_id = next_id("tutor");
+        tutor_ids.insert(tutor.id, new_id);
+        tutor.id = new_id;
+        cache::store_tutor(new_id, tutor);
+    }
+    let mut file_ids = HashMap::new();
+    KNOWLEDGE_BASE_FILES.with(|files| {
+        let mut files = files.borrow_mut();
+        for file in &bundle.knowledge_base_files {
+            let bundle_id = file.id;
+            let mut file = file.clone();
+            file.id = next_id("knowledge_base_file");
+            file_ids.insert(bundle_id, file.id);
+            file.tutor_id = tutor_ids.get(&file.tutor_id).copied().unwrap_or(file.tutor_id);
+            files.insert(file.id, file);
+        }
+    });
+    KNOWLEDGE_BASE_LINKS.with(|links| {
+        let mut links = links.borrow_mut();
+        for link in &bundle.knowledge_base_links {
+            let mut link = link.clone();
+            link.id = next_id("knowledge_base_link");
+            link.tutor_id = tutor_ids.get(&link.tutor_id).copied().unwrap_or(link.tutor_id);
+            link.source_tutor_id = tutor_ids.get(&link.source_tutor_id).copied().unwrap_or(link.source_tutor_id);
+            link.excluded_file_ids = link.excluded_file_ids.iter().filter_map(|id| file_ids.get(id).copied()).collect();
+            links.insert(link.id, link);
+        }
+    });
+    KNOWLEDGE_BASE_FILE_VERSIONS.with(|versions| {
+        let mut versions = versions.borrow_mut();
+        for version in &bundle.knowledge_base_file_versions {
+            if let Some(file_id) = file_ids.get(&version.file_id) {
+                let version = KnowledgeBaseFileVersion { file_id: *file_id, ..version.clone() };
+                versions.insert(kb_version_key(version.file_id, version.version), version);
+            }
+        }
+    });
+    for new_id in tutor_ids.into_values() {
+        if let Some(mut tutor) = TUTORS.with(|tutors| tutors.borrow().get(&new_id)).filter(|t| !t.kb_version_pins.is_empty()) {
+            tutor.kb_version_pins = tutor.kb_version_pins.iter()
+                .filter_map(|pin| file_ids.get(&pin.file_id).map(|file_id| KnowledgeBaseVersionPin { file_id: *file_id, version: pin.version }))
+                .collect();
+            cache::store_tutor(new_id, tutor);
+        }
+    }
+    CHAT_SESSIONS.with(|sessions| {
+        let mut sessions = sessions.borrow_mut();
+        for session in &bundle.chat_sessions {
+            sessions.insert(session.id.clone(), session.clone());
+        }
+    });
+    let mut message_count = 0;
+    for (session_id, list) in &bundle.chat_messages {
+        message_count += list.len() as u64;
+        certify::store_messages(bundle.user_id, session_id, ChatMessageList(list.clone()));
+    }
+    if let Some(usage) = &bundle.storage_usage {
+        STORAGE_USAGE.with(|u| {
+            u.borrow_mut().insert(bundle.user_id, usage.clone());
+        });
+    }
+    if let Some(verification) = &bundle.educator_verification {
+        EDUCATOR_VERIFICATIONS.with(|v| {
+            v.borrow_mut().insert(bundle.user_id, verification.clone());
+        });
+    }
+    if let Some(user) = &bundle.user {
+        let mut user = user.clone();
+        user.password_hash = bundle.password_hash.clone();
+        user.password_salt = bundle.password_salt.clone();
+        index_user_oauth_identity(&user);
+        cache::store_user(user);
+    }
+    USER_SHARDS.with(|shards| {
+        shards.borrow_mut().remove(&bundle.user_id);
+    });
+    
+    Ok(MigrationReport {
+        user_id: bundle.user_id,
+        source_canister: caller,
+        target_canister: ic_cdk::id(),
+        tutors: bundle.tutors.len() as u64,
+        chat_sessions: bundle.chat_sessions.len() as u64,
+        chat_messages: message_count,
+        knowledge_base_files: bundle.knowledge_base_files.len() as u64,
+        registry_updated: false,
+    })
+}
+
+fn same_user_data(sent: &UserDataBundle, current: &UserDataBundle) -> bool {
+    let encode = |bundle: &UserDataBundle| serde_cbor::to_vec(&UserDataBundle { exported_at: 0, ..bundle.clone() }).ok();
+    encode(sent) == encode(current)
+}
+
+// Undoes an import the source shard couldn't commit and routes the user back to it
+#[ic_cdk::update]
+fn discard_user_import(user_id: Principal) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    if !shard_peers().contains(&caller) {
+        return Err("Only peer shards can discard an import".to_string());
+    }
+    remove_user_data(&export_user_data(user_id));
+    USER_SHARDS.with(|shards| shards.borrow_mut().insert(user_id, caller));
+    Ok(())
+}
+
+#[ic_cdk::update]
+async fn migrate_user_admin(user_id: Principal, target_shard: Principal) -> Result<MigrationReport, String> {
+    require(ic_cdk::caller(), Permission::ManageUsers)?;
+    if target_shard == ic_cdk::id() {
+        return Err("User is already on this shard".to_string());
+    }
+    if !shard_peers().contains(&target_shard) {
+        return Err("Target canister is not a configured shard".to_string());
+    }
+    if !route_user(user_id).is_local {
+        return Err("User is not served by this shard".to_string());
+    }
+    ensure_not_migrating(user_id)?;
+    let left_behind = unmigratable_user_data(user_id);
+    if !left_behind.is_empty() {
+        return Err(format!("Only accounts without other data can move between shards; this one has {}", left_behind.join(", ")));
+    }
+    if AI_IN_FLIGHT.with(|in_flight| in_flight.borrow().contains_key(&user_id)) {
+        return Err("This user has AI requests in progress; try again once they finish".to_string());
+    }
+    
+    let session_keys: Vec<Principal> = SESSIONS.with(|sessions| {
+        sessions.borrow().values().filter(|s| s.user_id == user_id).map(|s| s.issued_to).collect()
+    });
+    MIGRATING_USERS.with(|migrating| migrating.borrow_mut().insert(user_id, session_keys));
+    let bundle = export_user_data(user_id);
+    let call: Result<(Result<MigrationReport, String>,), _> = ic_cdk::call(target_shard, "import_user_data", (bundle.clone(),)).await;
+    // Nothing local has changed yet, so a failed import only has to lift the freeze
+    MIGRATING_USERS.with(|migrating| migrating.borrow_mut().remove(&user_id));
+    let (result,) = call.map_err(|(code, msg)| format!("Migration call failed: {:?} - {}", code, msg))?;
+    let mut report = result?;
+    
+    // Writes that got past inspect_message while the copy was in flight show up here
+    let unchanged = same_user_data(&bundle, &export_user_data(user_id)) && unmigratable_user_data(user_id).is_empty();
+    if !unchanged {
+        let discarded: Result<(Result<(), String>,), _> = ic_cdk::call(target_shard, "discard_user_import", (user_id,)).await;
+        let discarded = discarded.map_err(|(code, msg)| format!("{:?} - {}", code, msg)).and_then(|(r,)| r);
+        return Err(match discarded {
+            Ok(()) => "The account changed while it was being copied, so it stays on this shard; try again".to_string(),
+            Err(e) => format!("The account changed while it was being copied, so it stays on this shard, but the copy on the target couldn't be discarded: {}", e),
+        });
+    }
+    
+    // The target now owns the data; drop the local copy and leave a forwarding record
+    remove_user_data(&bundle);
+    USER_SHARDS.with(|shards| {
+        shards.borrow_mut().insert(user_id, target_shard);
+    });
+    report.source_canister = ic_cdk::id();
+    
+    if let Some(registry) = get_config().sharding.registry_canister {
+        report.registry_updated = registry_assign_shard(registry, user_id, target_shard).await.is_ok();
+    }
+    
+    Ok(report)
+}
+
+// --- AI Provider Failover ---
+
+thread_local! {
+    static AI_PROVIDER_HEALTH: RefCell<HashMap<String, AiProviderHealth>> = RefCell::new(HashMap::new());
+}
+
+fn ai_circuit_open(provider: &str, now: u64) -> bool {
+    AI_PROVIDER_HEALTH.with(|health| {
+        health
+            .borrow()
+            .get(provider)
+            .and_then(|h| h.circuit_open_until)
+            .is_some_and(|until| now < until)
+    })
+}
+
+// After the cooldown one call is let through; a failure there re-opens the circuit
+fn record_ai_provider_result(provider: &str, latency_ms: u64, error: Option<&String>, breaker: &CircuitBreakerSettings) {
+    let now = ic_cdk::api::time();
+    AI_PROVIDER_HEALTH.with(|health| {
+        let mut health = health.borrow_mut();
+        let entry = health.entry(provider.to_string()).or_insert_with(|| AiProviderHealth {
+            name: provider.to_string(),
+            ..Default::default()
+        });
+        
+        entry.total_calls += 1;
+        entry.total_latency_ms += latency_ms;
+        entry.last_latency_ms = latency_ms;
+        match error {
+            Some(e) => {
+                entry.failed_calls += 1;
+                entry.consecutive_failures += 1;
+                entry.last_error = Some(e.clone());
+                if entry.consecutive_failures >= breaker.failure_threshold.max(1) {
+                    entry.circuit_open_until = Some(now + breaker.cooldown_seconds * 1_000_000_000);
+                }
+            }
+            None => {
+                entry.consecutive_failures = 0;
+                entry.last_success_at = Some(now);
+                entry.circuit_open_until = None;
+            }
+        }
+    });
+}
+
+fn outcall_budget(config: &CanisterConfig, operation: &str) -> OutcallBudget {
+    let find = |name: &str| config.outcall_budgets.iter().find(|b| b.operation == name).cloned();
+    find(operation)
+        .or_else(|| find("default"))
+        .unwrap_or_else(|| OutcallBudget::new("default", 30_000_000_000, 30_000, 1))
+}
+
+fn budget_exceeded_error(budget: &OutcallBudget, started: u64) -> String {
+    format!(
+        "AI request exceeded the '{}' budget after {} ms (limit {} ms, {} cycles per attempt, {} retries)",
+        budget.operation,
+        ic_cdk::api::time().saturating_sub(started) / 1_000_000,
+        budget.max_duration_ms,
+        budget.cycles,
+        budget.max_retries
+    )
+}
+
+fn resolve_model_params(defaults: &ModelDefaults, params: Option<&ModelParams>) -> ModelParams {
+    let params = params.cloned().unwrap_or_default();
+    ModelParams {
+        temperature: params.temperature.or(Some(defaults.temperature)),
+        top_p: params.top_p.or(defaults.top_p),
+        // An override removed from the allowlist after it was set falls back to the provider's model
+        model: params.model.filter(|m| defaults.allowed_models.contains(m)),
+    }
+}
+
+fn validate_model_params(params: &ModelParams, defaults: &ModelDefaults) -> Result<(), String> {
+    if params.temperature.is_some_and(|t| !(0.0..=2.0).contains(&t)) {
+        return Err("Temperature must be between 0 and 2".to_string());
+    }
+    if params.top_p.is_some_and(|p| !(p > 0.0 && p <= 1.0)) {
+        return Err("top_p must be above 0 and at most 1".to_string());
+    }
+    if let Some(model) = &params.model {
+        if !defaults.allowed_models.contains(model) {
+            return Err(format!("Model '{}' is not available. Choose one of: {}", model, defaults.allowed_models.join(", ")));
+        }
+    }
+    Ok(())
+}
+
+// Same prompt, same seed, so every replica samples the same reply
+fn prompt_seed(prompt: &str) -> u64 {
+    use sha2::{Digest, Sha256};
+    let digest = Sha256::digest(prompt.as_bytes());
+    u64::from_be_bytes(digest[..8].try_into().unwrap()) >> 1
+}
+
+async fn call_ai_provider(provider: &AiProviderConfig, prompt: &str, params: &ModelParams, cycles: u128) -> Result<String, String> {
+    let temperature = params.temperature.unwrap_or(0.0);
+    let mut body = json!({
+        "model": params.model.as_deref().unwrap_or(&provider.model),
+        "messages": [{ "role": "user", "content": prompt }],
+        "temperature": temperature,
+    });
+    if let Some(top_p) = params.top_p {
+        body["top_p"] = json!(top_p);
+    }
+    if temperature > 0.0 {
+        body["seed"] = json!(prompt_seed(prompt));
+    }
+    
+    let request = CanisterHttpRequestArgument {
+        url: provider.endpoint_url.clone(),
+        max_response_bytes: Some(provider.max_response_bytes),
+        method: HttpMethod::POST,
+        headers: vec![
+            HttpHeader { name: "Content-Type".to_string(), value: "application/json".to_string() },
+            HttpHeader { name: "Authorization".to_string(), value: format!("Bearer {}", provider.api_key) },
+        ],
+        body: Some(body.to_string().into_bytes()),
+        transform: Some(TransformContext::from_name("transform_ai_response".to_string(), vec![])),
+    };
+    
+    let (response,) = ic_cdk::api::management_canister::http_request::http_request(request, cycles)
+        .await
+        .map_err(|(code, msg)| format!("HTTP outcall failed: {:?} - {}", code, msg))?;
+    
+    let status: u32 = response.status.0.try_into().unwrap_or(0);
+    if !(200..300).contains(&status) {
+        return Err(format!("Provider returned status {}", status));
+    }
+    
+    let parsed: serde_json::Value = serde_json::from_slice(&response.body)
+        .map_err(|e| format!("Invalid provider response: {}", e))?;
+    parsed["choices"][0]["message"]["content"]
+        .as_str()
+        .map(|content| content.to_string())
+        .ok_or_else(|| "Provider response had no message content".to_string())
+}
+
+// Strips headers so replicas agree on the response
+#[ic_cdk::query]
+fn transform_ai_response(args: TransformArgs) -> ic_cdk::api::management_canister::http_request::HttpResponse {
+    ic_cdk::api::management_canister::http_request::HttpResponse {
+        status: args.response.status,
+        headers: Vec::new(),
+        body: args.response.body,
+    }
+}
+
+#[ic_cdk::update]
+fn set_ai_providers_admin(providers: Vec<AiProviderConfig>, circuit_breaker: CircuitBreakerSettings) -> Result<CanisterConfig, String> {
+    require(ic_cdk::caller(), Permission::ManageSystem)?;
+    
+    let mut seen = std::collections::HashSet::new();
+    for provider in &providers {
+        if provider.name.trim().is_empty() || !seen.insert(provider.name.clone()) {
+            return Err("Each provider needs a unique name".to_string());
+        }
+        if !provider.endpoint_url.starts_with("https://") {
+            return Err(format!("Provider '{}' must use an https endpoint", provider.name));
+        }
+    }
+    
+    update_config(|config| {
+        // Keys come back redacted from get_config_admin, so an empty key keeps the stored one
+        let mut providers = providers;
+        for provider in providers.iter_mut().filter(|p| p.api_key.is_empty()) {
+            if let Some(existing) = config.ai_providers.iter().find(|p| p.name == provider.name) {
+                provider.api_key = existing.api_key.clone();
+            }
+        }
+        config.ai_providers = providers;
+        config.ai_circuit_breaker = circuit_breaker;
+        Ok(())
+    })
+}
+
+#[ic_cdk::update]
+fn set_outcall_budgets_admin(budgets: Vec<OutcallBudget>) -> Result<CanisterConfig, String> {
+    require(ic_cdk::caller(), Permission::ManageSystem)?;
+    
+    let mut seen = std::collections::HashSet::new();
+    for budget in &budgets {
+        if !seen.insert(budget.operation.clone()) {
+            return Err(format!("Budget for '{}' is listed twice", budget.operation));
+        }
+        if budget.cycles == 0 || budget.max_duration_ms == 0 {
+            return Err(format!("Budget for '{}' needs positive cycles and duration", budget.operation));
+        }
+    }
+    if !seen.contains("default") {
+        return Err("A 'default' budget is required".to_string());
+    }
+    
+    update_config(|config| {
+        config.outcall_budgets = budgets;
+        Ok(())
+    })
+}
+
+#[ic_cdk::update]
+fn set_tutor_model_params(tutor_id: String, model_params: Option<ModelParams>) -> Result<Tutor, String> {
+    let (key, mut tutor) = owned_tutor(&tutor_id, ic_cdk::caller())?;
+    if let Some(params) = &model_params {
+        validate_model_params(params, &get_config().model_defaults)?;
+    }
+    tutor.model_params = model_params.filter(|p| *p != ModelParams::default());
+    tutor.updated_at = ic_cdk::api::time();
+    cache::store_tutor(key, tutor.clone());
+    Ok(tutor)
+}
+
+#[ic_cdk::update]
+fn set_model_defaults_admin(model_defaults: ModelDefaults) -> Result<CanisterConfig, String> {
+    require(ic_cdk::caller(), Permission::ManageSystem)?;
+    let params = ModelParams { temperature: Some(model_defaults.temperature), top_p: model_defaults.top_p, model: None };
+    validate_model_params(&params, &model_defaults)?;
+    update_config(|config| {
+        config.model_defaults = model_defaults;
+        Ok(())
+    })
+}
+
+#[ic_cdk::query]
+fn get_ai_provider_status_admin() -> Result<Vec<AiProviderStatus>, String> {
+    require(ic_cdk::caller(), Permission::ManageSystem)?;
+    
+    let now = ic_cdk::api::time();
+    Ok(get_config()
+        .ai_providers
+        .into_iter()
+        .map(|provider| {
+            let health = AI_PROVIDER_HEALTH.with(|h| h.borrow().get(&provider.name).cloned())
+                .unwrap_or_else(|| AiProviderHealth { name: provider.name.clone(), ..Default::default() });
+            AiProviderStatus {
+                circuit_open: ai_circuit_open(&provider.name, now),
+                error_rate: health.error_rate(),
+                average_latency_ms: health.average_latency_ms(),
+                name: provider.name,
+                vendor: provider.vendor,
+                model: provider.model,
+                enabled: provider.enabled,
+                health,
+            }
+        })
+        .collect())
+}
+
+#[ic_cdk::update]
+fn reset_ai_provider_circuit_admin(name: String) -> Result<(), String> {
+    require(ic_cdk::caller(), Permission::ManageSystem)?;
+    
+    AI_PROVIDER_HEALTH.with(|health| {
+        if let Some(entry) = health.borrow_mut().get_mut(&name) {
+            entry.consecutive_failures = 0;
+            entry.circuit_open_until = None;
+        }
+    });
+    Ok(())
+}
+
+// --- AI Response Processing ---
+//
+// Every AI response passes through these stages before it is parsed or stored. Each stage
+// is a pure function of its input so it can be exercised on its own.
+
+struct ResponseProcessing {
+    strip_fences: bool,
+    profanity_words: Vec<String>,
+    emoji_policy: String,
+    max_chars: Option<usize>,
+    max_paragraph_sentences: Option<usize>,
+}
+
+const ACCESSIBLE_PARAGRAPH_SENTENCES: usize = 3;
+
+// Chat replies keep markdown and are length-limited; plain text and JSON lose code fences
+// and are never trimmed, since a cut JSON document would fail to parse.
+fn response_processing_for(user_id: Principal, format: &str) -> ResponseProcessing {
+    let config = get_config().response_processing;
+    let settings = cache::user(user_id).map(|u| u.settings);
+    let emoji_policy = settings.as_ref().map(|s| s.ai_emoji_policy.clone()).unwrap_or_else(|| "allow".to_string());
+    let accessible = settings.is_some_and(|s| s.accessibility_mode) && format != "json";
+    
+    ResponseProcessing {
+        strip_fences: config.strip_markdown_fences && format != "chat",
+        profanity_words: if config.mask_profanity { config.profanity_words } else { Vec::new() },
+        emoji_policy: if format == "json" { "allow".to_string() } else { emoji_policy },
+        max_chars: if format == "chat" { config.max_chat_response_chars.map(|c| c as usize) } else { None },
+        max_paragraph_sentences: if accessible { Some(ACCESSIBLE_PARAGRAPH_SENTENCES) } else { None },
+    }
+}
+
+fn process_ai_response(text: String, processing: &ResponseProcessing) -> String {
+    let mut text = text;
+    if processing.strip_fences {
+        text = strip_markdown_fences(&text);
+    }
+    if !processing.profanity_words.is_empty() {
+        text = mask_profanity(&text, &processing.profanity_words);
+    }
+    if processing.emoji_policy == "none" {
+        text = strip_emoji(&text);
+    }
+    if let Some(max_sentences) = processing.max_paragraph_sentences {
+        text = split_long_paragraphs(&text, max_sentences);
+    }
+    if let Some(max_chars) = processing.max_chars {
+        text = trim_to_length(&text, max_chars);
+    }
+    text.trim().to_string()
+}
+
+// Removes ``` fence lines (with or without a language tag), keeping what was inside them
+fn strip_markdown_fences(text: &str) -> String {
+    text.lines()
+        .filter(|line| !line.trim_start().starts_with("```"))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// Masks listed words case-insensitively, keeping the first letter: "damn" -> "d***"
+fn mask_profanity(text: &str, words: &[String]) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut word = String::new();
+    let flush = |word: &mut String, result: &mut String| {
+        let lower = word.to_lowercase();
+        if words.iter().any(|w| w.eq_ignore_ascii_case(&lower)) {
+            let mut chars = word.chars();
+            if let Some(first) = chars.next() {
+                result.push(first);
+            }
+            result.extend(chars.map(|_| '*'));
+        } else {
+            result.push_str(word);
+        }
+        word.clear();
+    };
+    
+    for c in text.chars() {
+        if c.is_alphanumeric() {
+            word.push(c);
+        } else {
+            flush(&mut word, &mut result);
+            result.push(c);
+        }
+    }
+    flush(&mut word, &mut result);
+    result
+}
+
+fn is_emoji(c: char) -> bool {
+    matches!(c as u32,
+        0x1F000..=0x1FAFF // pictographs, emoticons, transport, flags
+        | 0x2600..=0x27BF // misc symbols and dingbats
+        | 0x2B00..=0x2BFF // arrows and stars
+        | 0xFE0F | 0x200D // variation selector and zero-width joiner
+    )
+}
+
+fn strip_emoji(text: &str) -> String {
+    let stripped: String = text.chars().filter(|c| !is_emoji(*c)).collect();
+    // Collapse the double spaces left where an emoji sat between words
+    stripped.split(' ').filter(|s| !s.is_empty()).collect::<Vec<_>>().join(" ")
+}
+
+// Cuts at a word boundary and ends with a single ellipsis. An unclosed code fence is closed
+// so the remaining markdown still renders.
+fn trim_to_length(text: &str, max_chars: usize) -> String {
+    if text.chars().count() <= max_chars {
+        return text.to_string();
+    }
+    
+    let budget = max_chars.saturating_sub(1);
+    let mut cut: String = text.chars().take(budget).collect();
+    if let Some(boundary) = cut.rfind(char::is_whitespace) {
+        if boundary > cut.len() * 4 / 5 {
+            cut.truncate(boundary);
+        }
+    }
+    
+    let mut trimmed = cut.trim_end_matches(|c: char| c.is_whitespace() || matches!(c, '.' | ',' | ';' | ':' | '…' | '-')).to_string();
+    trimmed.push('…');
+    if trimmed.matches("```").count() % 2 == 1 {
+        trimmed.push_str("\n```");
+    }
+    trimmed
+}
+
+fn sentences(text: &str) -> Vec<&str> {
+    let mut sentences = Vec::new();
+    let mut start = 0;
+    for (i, c) in text.char_indices() {
+        let end = i + c.len_utf8();
+        let at_boundary = text[end..].chars().next().is_none_or(char::is_whitespace);
+        if matches!(c, '.' | '!' | '?') && at_boundary {
+            sentences.push(text[start..end].trim());
+            start = end;
+        }
+    }
+    sentences.push(text[start..].trim());
+    sentences.retain(|s| !s.is_empty());
+    sentences
+}
+
+// Breaks prose paragraphs after every `max_sentences` sentences; code blocks and list items
+// are left alone
+fn split_long_paragraphs(text: &str, max_sentences: usize) -> String {
+    let mut in_code = false;
+    text.split("\n\n")
+        .map(|paragraph| {
+            let fences = paragraph.matches("```").count();
+            let skip = in_code || fences > 0 || paragraph.lines().any(|l| {
+                let l = l.trim_start();
+                l.starts_with("- ") || l.starts_with("* ") || l.starts_with('#') || l.starts_with('|')
+            });
+            in_code ^= fences % 2 == 1;
+            if skip {
+                return paragraph.to_string();
+            }
+            sentences(paragraph)
+                .chunks(max_sentences.max(1))
+                .map(|chunk| chunk.join(" "))
+                .collect::<Vec<_>>()
+                .join("\n\n")
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+fn count_syllables(word: &str) -> usize {
+    let word = word.to_lowercase();
+    let mut count = 0;
+    let mut previous_vowel = false;
+    for c in word.chars() {
+        let vowel = matches!(c, 'a' | 'e' | 'i' | 'o' | 'u' | 'y');
+        if vowel && !previous_vowel {
+            count += 1;
+        }
+        previous_vowel = vowel;
+    }
+    if word.ends_with('e') && !word.ends_with("le") && count > 1 {
+        count -= 1;
+    }
+    count.max(1)
+}
+
+// Flesch-Kincaid grade level, rounded to one decimal. Code blocks are not prose and are skipped.
+fn estimate_reading_grade(text: &str) -> f32 {
+    let mut in_code = false;
+    let prose: Vec<&str> = text.lines()
+        .filter(|line| {
+            if line.trim_start().starts_with("```") {
+                in_code = !in_code;
+                return false;
+            }
+            !in_code
+        })
+        .collect();
+    let prose = prose.join("\n");
+    
+    let words: Vec<&str> = prose.split_whitespace()
+        .map(|w| w.trim_matches(|c: char| !c.is_alphanumeric()))
+        .filter(|w| w.chars().any(char::is_alphabetic))
+        .collect();
+    if words.is_empty() {
+        return 0.0;
+    }
+    let sentence_count = sentences(&prose).len().max(1) as f32;
+    let syllables: usize = words.iter().map(|w| count_syllables(w)).sum();
+    let words_per_sentence = words.len() as f32 / sentence_count;
+    let syllables_per_word = syllables as f32 / words.len() as f32;
+    let grade = 0.39 * words_per_sentence + 11.8 * syllables_per_word - 15.59;
+    (grade.max(0.0) * 10.0).round() / 10.0
+}
+
+// Extra prompt rules for users who turned on accessibility mode
+fn accessibility_instructions(settings: &UserSettings) -> String {
+    if !settings.accessibility_mode {
+        return String::new();
+    }
+    let mut rules = vec![
+        "Write short paragraphs of no more than three short sentences.",
+        "Whenever you refer to an image, diagram, chart or other visual, describe it in words as alt text, like [Image: a bar chart showing ...].",
+        "Prefer plain words over jargon and explain any technical term the first time you use it.",
+    ];
+    if settings.dyslexia_friendly {
+        rules.push("Use simple sentence structure, one idea per sentence, and bulleted lists for steps.");
+        rules.push("Do not use italics, all-caps words or long runs of numbers; spell out abbreviations.");
+    }
+    format!("\n\nAccessibility requirements:\n- {}", rules.join("\n- "))
+}
+
+const READING_LEVELS: [&str; 3] = ["grade_6", "grade_9", "college"];
+
+fn target_reading_grade(settings: &UserSettings) -> Option<f32> {
+    match settings.target_reading_level.as_deref()? {
+        "grade_6" => Some(6.0),
+        "grade_9" => Some(9.0),
+        "college" => Some(13.0),
+        _ => None,
+    }
+}
+
+fn reading_level_instructions(settings: &UserSettings) -> String {
+    match settings.target_reading_level.as_deref() {
+        Some("grade_6") => "\n\nWrite at a 6th-grade reading level: short sentences and everyday words.".to_string(),
+        Some("grade_9") => "\n\nWrite at a 9th-grade reading level: clear sentences, defining any specialist terms.".to_string(),
+        Some("college") => "\n\nWrite at a college reading level; precise technical vocabulary is fine.".to_string(),
+        _ => String::new(),
+    }
+}
+
+// Accessibility and reading-level rules appended to prompts for user-facing text
+fn output_instructions(settings: &UserSettings) -> String {
+    format!("{}{}", accessibility_instructions(settings), reading_level_instructions(settings))
+}
+
+fn user_output_instructions(user_id: Principal) -> String {
+    cache::user(user_id)
+        .map(|user| output_instructions(&user.settings))
+        .unwrap_or_default()
+}
+
+async fn ai_reading_grade(text: &str) -> Option<f32> {
+    let prompt = format!(
+        "Estimate the US school grade level needed to read the following text. Reply with a single number only.\n\nText:\n{}",
+        text
+    );
+    let response = call_groq_ai(&prompt, "readability", ic_cdk::id()).await.ok()?;
+    response.trim().trim_end_matches('.').parse::<f32>().ok().filter(|grade| (0.0..=20.0).contains(grade))
+}
+
+// Rewrites a reply once when it misses the user's target reading level by more than the
+// configured margin. The cheap formula estimate screens replies; only those it flags are
+// confirmed by the AI check before paying for a rewrite.
+async fn enforce_reading_level(user_id: Principal, text: String) -> String {
+    let config = get_config().response_processing;
+    let Some(settings) = cache::user(user_id).map(|u| u.settings) else {
+        return text;
+    };
+    let Some(target) = target_reading_grade(&settings) else {
+        return text;
+    };
+    let off_target = |grade: f32| (grade - target).abs() > config.reading_level_margin;
+    if !config.rewrite_off_level_replies || !off_target(estimate_reading_grade(&text)) {
+        return text;
+    }
+    if !ai_reading_grade(&text).await.is_some_and(off_target) {
+        return text;
+    }
+    
+    let prompt = format!(
+        "Rewrite the following tutor reply so it reads at about a grade {} level. Keep the meaning, facts, any code and the markdown formatting. Reply with the rewritten text only.{}\n\nReply:\n{}",
+        target,
+        accessibility_instructions(&settings),
+        text
+    );
+    match call_groq_ai(&prompt, "readability", ic_cdk::id()).await {
+        Ok(rewritten) => process_ai_response(rewritten, &response_processing_for(user_id, "chat")),
+        Err(e) => {
+            ic_cdk::println!("Reading level rewrite failed: {}", e);
+            text
+        }
+    }
+}
+
+#[ic_cdk::update]
+fn set_target_reading_level(level: Option<String>) -> Result<User, String> {
+    let caller = ic_cdk::caller();
+    if level.as_ref().is_some_and(|l| !READING_LEVELS.contains(&l.as_str())) {
+        return Err(format!("Reading level must be one of: {}", READING_LEVELS.join(", ")));
+    }
+    
+    let mut user = cache::user(caller).ok_or("User not found")?;
+    user.settings.target_reading_level = level;
+    user.updated_at = ic_cdk::api::time();
+    cache::store_user(user.clone());
+    Ok(user)
+}
+
+#[ic_cdk::update]
+fn set_accessibility_mode(enabled: bool, dyslexia_friendly: bool) -> Result<User, String> {
+    let caller = ic_cdk::caller();
+    let mut user = cache::user(caller).ok_or("User not found")?;
+    user.settings.accessibility_mode = enabled;
+    user.settings.dyslexia_friendly = enabled && dyslexia_friendly;
+    user.updated_at = ic_cdk::api::time();
+    cache::store_user(user.clone());
+    Ok(user)
+}
+
+#[ic_cdk::update]
+fn set_ai_emoji_policy(policy: String) -> Result<User, String> {
+    let caller = ic_cdk::caller();
+    if !["allow", "none"].contains(&policy.as_str()) {
+        return Err("Emoji policy must be 'allow' or 'none'".to_string());
+    }
+    
+    let mut user = cache::user(caller).ok_or("User not found")?;
+    user.settings.ai_emoji_policy = policy;
+    user.updated_at = ic_cdk::api::time();
+    cache::store_user(user.clone());
+    Ok(user)
+}
+
+#[ic_cdk::update]
+fn set_response_processing_admin(processing: ResponseProcessingConfig) -> Result<CanisterConfig, String> {
+    require(ic_cdk::caller(), Permission::ManageSystem)?;
+    if processing.max_chat_response_chars == Some(0) {
+        return Err("Maximum response length must be positive".to_string());
+    }
+    if processing.reading_level_margin.is_nan() || processing.reading_level_margin <= 0.0 {
+        return Err("Reading level margin must be positive".to_string());
+    }
+    
+    update_config(|config| {
+        config.response_processing = processing;
+        Ok(())
+    })
+}
+
+// --- Message Delivery ---
+
+const MAX_DELIVERY_ATTEMPTS: u32 = 3;
+const DELIVERY_RETRY_BASE_NS: u64 = 60 * 1_000_000_000;
+// Upper bound on replies regenerated per heartbeat run
+const DELIVERY_RETRY_BATCH_SIZE: usize = 5;
+
+fn tutor_reply_prompt(tutor: &Tutor, content: &str, background: Option<String>, accessibility: &str) -> String {
+    format!(
+        "Expert in: {}. Style: {}. Personality: {}.
+        {}
+Student: \"{}\"
+
+Give a helpful, educational response in 2-3 sentences.{}",
+        tutor.expertise.join(", "),
+        tutor.teaching_style,
+        tutor.personality,
+        background.unwrap_or_default(),
+        content,
+        accessibility
+    )
+}
+
+fn start_pending_delivery(session_id: &str, message_id: &str, user_id: Principal, requested_by: Option<Principal>, kind: &str, user_content: &str, placeholder: &str) -> ChatMessage {
+    let now = ic_cdk::api::time();
+    record_focus_activity(requested_by.unwrap_or(user_id), Some(session_id), None, now);
+    let placeholder = ChatMessage {
+        id: message_id.to_string(),
+        session_id: session_id.to_string(),
+        sender: "tutor".to_string(),
+        content: placeholder.to_string(),
+        timestamp: now,
+        has_audio: Some(false),
+        delivery_status: "pending".to_string(),
+        reading_grade: None,
+        author: None,
+        citations: Vec::new(),
+        confidence: None,
+        rating: None,
+        bookmarked: false,
+    };
+    record_storage_change(user_id, "messages", chat_message_bytes(&placeholder) as i64);
+    
+    let mut session_messages = CHAT_MESSAGES.with(|messages| messages.borrow().get(&session_id.to_string())).unwrap_or_else(|| ChatMessageList(Vec::new()));
+    session_messages.0.push(placeholder.clone());
+    certify::store_messages(user_id, session_id, session_messages);
+    
+    PENDING_DELIVERIES.with(|deliveries| {
+        deliveries.borrow_mut().insert(message_id.to_string(), PendingDelivery {
+            message_id: message_id.to_string(),
+            session_id: session_id.to_string(),
+            user_id,
+            kind: kind.to_string(),
+            user_content: user_content.to_string(),
+            status: "in_flight".to_string(),
+            attempts: 0,
+            last_error: None,
+            next_retry_at: None,
+            created_at: now,
+            requested_by,
+            sources: Vec::new(),
+            email_reply_to: None,
+            notify_when_ready: false,
+        });
+    });
+    
+    placeholder
+}
+
+// Stores a message a learner sent from outside the web app (email, chat bots) and starts the
+// tutor's reply. Relays call as themselves, so the learner's daily AI calls are charged here.
+fn relay_user_message(session: &ChatSession, author: Principal, content: &str, email_reply_to: Option<String>) -> Result<(ChatMessage, String), String> {
+    ensure_session_writable(session)?;
+    let owner = session.user_id;
+    let now = ic_cdk::api::time();
+    let user_message = ChatMessage {
+        id: format!("msg_{}", next_id("message")),
+        session_id: session.id.clone(),
+        sender: "user".to_string(),
+        content: content.to_string(),
+        timestamp: now,
+        has_audio: Some(false),
+        delivery_status: "delivered".to_string(),
+        reading_grade: None,
+        author: Some(author),
+        citations: Vec::new(),
+        confidence: None,
+        rating: None,
+        bookmarked: false,
+    };
+    check_storage_quota(owner, chat_message_bytes(&user_message))?;
+    consume_ai_call(author)?;
+    record_storage_change(owner, "messages", chat_message_bytes(&user_message) as i64);
+    let mut session_messages = CHAT_MESSAGES.with(|messages| messages.borrow().get(&session.id)).unwrap_or_else(|| ChatMessageList(Vec::new()));
+    session_messages.0.push(user_message.clone());
+    certify::store_messages(owner, &session.id, session_messages);
+    
+    let reply_message_id = format!("msg_{}", next_id("message"));
+    start_pending_delivery(&session.id, &reply_message_id, owner, (author != owner).then_some(author), "quick", content, "");
+    if email_reply_to.is_some() {
+        PENDING_DELIVERIES.with(|deliveries| {
+            let mut deliveries = deliveries.borrow_mut();
+            if let Some(mut delivery) = deliveries.get(&reply_message_id) {
+                delivery.email_reply_to = email_reply_to;
+                deliveries.insert(reply_message_id.clone(), delivery);
+            }
+        });
+    }
+    CHAT_SESSIONS.with(|sessions| {
+        let mut sessions = sessions.borrow_mut();
+        if let Some(mut session) = sessions.get(&session.id) {
+            session.updated_at = now;
+            sessions.insert(session.id.clone(), session);
+        }
+    });
+    Ok((user_message, reply_message_id))
+}
+
+// Applies the change to a stored message and keeps storage accounting in step
+fn update_chat_message<F: FnOnce(&mut ChatMessage)>(user_id: Principal, session_id: &str, message_id: &str, update: F) -> Option<ChatMessage> {
+    let mut session_messages = CHAT_MESSAGES.with(|messages| messages.borrow().get(&session_id.to_string()))?;
+    let message = session_messages.0.iter_mut().find(|m| m.id == message_id)?;
+    let old_bytes = chat_message_bytes(message);
+    update(message);
+    let updated = message.clone();
+    certify::store_messages(user_id, session_id, session_messages);
+    
+    record_storage_change(user_id, "messages", chat_message_bytes(&updated) as i64 - old_bytes as i64);
+    Some(updated)
+}
+
+async fn generate_pending_reply(delivery: &PendingDelivery) -> Result<(String, Option<ComprehensionAnalysis>, Option<f32>), String> {
+    let session = CHAT_SESSIONS.with(|sessions| sessions.borrow().get(&delivery.session_id))
+        .ok_or("Session not found")?;
+    let (_, tutor) = cache::tutor_by_public_id(&session.tutor_id).ok_or("Tutor not found")?;
+    
+    if delivery.kind == "welcome" {
+        let welcome = generate_welcome_message(delivery.user_id, &tutor, &session.topic, None, &session.intake_answers, &delivery.message_id, ic_cdk::id()).await?;
+        return Ok((welcome, None, None));
+    }
+    
+    let requester = delivery.requester();
+    if delivery.kind == "guided" {
+        let user = cache::user(requester).ok_or("User not found")?;
+        // History as it was before the student's message
+        let mut history: Vec<ChatMessage> = CHAT_MESSAGES.with(|messages| {
+            messages.borrow().get(&delivery.session_id).map(|list| list.0).unwrap_or_default()
+        })
+        .into_iter()
+        .take_while(|m| m.id != delivery.message_id)
+        .collect();
+        history.pop();
+        
+        let (response, analysis) = generate_tutor_chat_response(delivery, &history, &tutor, &user.settings).await?;
+        let (response, confidence) = finish_reply(&tutor, delivery, response).await;
+        return Ok((response, Some(analysis), confidence));
+    }
+    
+    let background: Vec<String> = [intake_background(&session), co_learning_note(&session, requester), reply_sources_prompt(&delivery.sources)]
+        .into_iter()
+        .flatten()
+        .collect();
+    let background = (!background.is_empty()).then(|| background.join("\n"));
+    let prompt = tutor_reply_prompt(&tutor, &delivery.user_content, background, &user_output_instructions(requester));
+    let response = call_tutor_ai(&prompt, "chat", &tutor, requester, Some(&delivery.message_id), ic_cdk::id()).await?;
+    let response = process_ai_response(response, &response_processing_for(requester, "chat"));
+    let (response, confidence) = finish_reply(&tutor, delivery, enforce_reading_level(requester, response).await).await;
+    Ok((response, None, confidence))
+}
+
+// Missing sessions, tutors or users will not come back by retrying
+fn is_transient_delivery_error(error: &str) -> bool {
+    !matches!(error, "Session not found" | "Tutor not found" | "User not found")
+}
+
+async fn deliver_tutor_reply(message_id: &str, acting: Principal) -> Result<(ChatMessage, Option<ComprehensionAnalysis>), String> {
+    let mut delivery = PENDING_DELIVERIES.with(|deliveries| deliveries.borrow().get(&message_id.to_string()))
+        .ok_or("No undelivered reply for this message")?;
+    delivery.status = "in_flight".to_string();
+    delivery.attempts += 1;
+    PENDING_DELIVERIES.with(|deliveries| {
+        deliveries.borrow_mut().insert(message_id.to_string(), delivery.clone());
+    });
+    update_chat_message(delivery.user_id, &delivery.session_id, message_id, |m| m.delivery_status = "pending".to_string());
+    mark_generation_started(&delivery);
+    
+    // The student's slot is held for the whole reply, and the AI calls inside it run as the
+    // canister. A reply counts as one AI call when the student asked for it; automatic retries
+    // and jobs (`acting` is the canister) are free.
+    let requester = delivery.requester();
+    let result = match acquire_ai_slot(requester, "chat") {
+        Ok(_slot) if acting == requester => match consume_ai_call(requester) {
+            Ok(()) => generate_pending_reply(&delivery).await,
+            Err(e) => Err(e),
+        },
+        Ok(_slot) => generate_pending_reply(&delivery).await,
+        Err(e) => Err(e),
+    };
+    clear_generation(&delivery);
+    let now = ic_cdk::api::time();
+    dispatch_queued_delivery(requester);
+    
+    match result {
+        Ok((content, analysis, confidence)) => {
+            PENDING_DELIVERIES.with(|deliveries| {
+                deliveries.borrow_mut().remove(&message_id.to_string());
+            });
+            // The session may have been deleted while the AI call was in flight
+            let citations = cited_sources(&delivery.sources, &content);
+            let message = update_chat_message(delivery.user_id, &delivery.session_id, message_id, |m| {
+                m.citations = citations;
+                m.confidence = confidence;
+                m.reading_grade = Some(estimate_reading_grade(&content));
+                m.content = content;
+                m.delivery_status = "delivered".to_string();
+                m.timestamp = now;
+            }).ok_or("Message no longer exists")?;
+            flag_if_low_confidence(&delivery, &message);
+            accrue_creator_usage(&delivery, now);
+            settle_email_exchange(&delivery, None, now);
+            notify_reply_settled(&delivery, None);
+            if delivery.kind != "welcome" {
+                queue_drift_check(&delivery.session_id);
+            }
+            Ok((message, analysis))
+        }
+        Err(e) if is_ai_busy_error(&e) => {
+            delivery.status = "queued".to_string();
+            delivery.attempts -= 1;
+            PENDING_DELIVERIES.with(|deliveries| {
+                deliveries.borrow_mut().insert(message_id.to_string(), delivery.clone());
+            });
+            update_chat_message(delivery.user_id, &delivery.session_id, message_id, |m| m.delivery_status = "queued".to_string());
+            Err(REPLY_QUEUED_MESSAGE.to_string())
+        }
+        Err(e) if is_ai_degraded_error(&e) => {
+            delivery.status = "deferred".to_string();
+            delivery.attempts -= 1;
+            PENDING_DELIVERIES.with(|deliveries| {
+                deliveries.borrow_mut().insert(message_id.to_string(), delivery.clone());
+            });
+            update_chat_message(delivery.user_id, &delivery.session_id, message_id, |m| {
+                if m.content.is_empty() {
+                    m.content = REPLY_DEFERRED_CONTENT.to_string();
+                }
+                m.delivery_status = "deferred".to_string();
+            });
+            Err(REPLY_DEFERRED_MESSAGE.to_string())
+        }
+        Err(e) => {
+            delivery.status = "failed".to_string();
+            delivery.last_error = Some(e.clone());
+            delivery.next_retry_at = if is_transient_delivery_error(&e) && delivery.attempts < MAX_DELIVERY_ATTEMPTS {
+                Some(now + DELIVERY_RETRY_BASE_NS * (1 << (delivery.attempts - 1)))
+            } else {
+                None
+            };
+            PENDING_DELIVERIES.with(|deliveries| {
+                deliveries.borrow_mut().insert(message_id.to_string(), delivery.clone());
+            });
+            update_chat_message(delivery.user_id, &delivery.session_id, message_id, |m| m.delivery_status = "failed".to_string());
+            if delivery.next_retry_at.is_none() {
+                settle_email_exchange(&delivery, Some(&e), now);
+                notify_reply_settled(&delivery, Some(&e));
+            }
+            Err(e)
+        }
+    }
+}
+
+fn retry_due_deliveries(now: u64) {
+    let due: Vec<String> = PENDING_DELIVERIES.with(|deliveries| {
+        deliveries
+            .borrow()
+            .iter()
+            .filter(|(_, d)| d.status == "failed" && d.next_retry_at.is_some_and(|at| at <= now))
+            .take(DELIVERY_RETRY_BATCH_SIZE)
+            .map(|(id, _)| id)
+            .collect()
+    });
+    
+    for message_id in due {
+        ic_cdk::spawn(async move {
+            if let Err(e) = deliver_tutor_reply(&message_id, ic_cdk::id()).await {
+                ic_cdk::println!("Retry of reply {} failed: {}", message_id, e);
+            }
+        });
+    }
+    
+    // Queued replies whose user has since freed a slot, oldest first
+    let mut users: Vec<Principal> = Vec::new();
+    for delivery in queued_deliveries(None) {
+        if !users.contains(&delivery.requester()) {
+            users.push(delivery.requester());
+        }
+    }
+    for user_id in users.into_iter().take(DELIVERY_RETRY_BATCH_SIZE) {
+        dispatch_queued_delivery(user_id);
+    }
+    
+    dispatch_deferred_deliveries(now);
+}
+
+#[ic_cdk::update]
+async fn retry_failed_response(message_id: String) -> Result<ChatMessage, String> {
+    rate_limit::check(ic_cdk::caller(), "chat")?;
+    let delivery = PENDING_DELIVERIES.with(|deliveries| deliveries.borrow().get(&message_id))
+        .ok_or("No undelivered reply for this message")?;
+    if delivery.user_id != ic_cdk::caller() {
+        return Err("You don't have permission to access this message".to_string());
+    }
+    if delivery.status != "failed" {
+        return Err("This reply is already being generated".to_string());
+    }
+    
+    deliver_tutor_reply(&message_id, ic_cdk::caller()).await.map(|(message, _)| message)
+}
+
+// --- Session Pruning ---
+
+const SUMMARY_MAX_CHARS: usize = 1200;
+
+// Folds pruned messages into the session's rolling summary. Falls back to the student's
+// last few questions when the AI call fails, so some context always survives.
+async fn summarize_pruned_messages(user_id: Principal, previous: Option<String>, pruned: &[ChatMessage]) -> String {
+    let transcript: String = pruned
+        .iter()
+        .map(|m| format!("{}: {}\n", m.sender, m.content))
+        .collect();
+    let prompt = format!(
+        "Summarize this tutoring conversation in under 150 words, keeping what the student has \
+        learned, struggled with and asked about.
+        
+        Previous summary: {}
+        
+        Conversation:
+        {}",
+        previous.clone().unwrap_or_else(|| "none".to_string()),
+        transcript
+    );
+    
+    match call_groq_ai(&prompt, "summary", ic_cdk::caller()).await {
+        Ok(summary) => process_ai_response(summary, &ResponseProcessing {
+            max_chars: Some(SUMMARY_MAX_CHARS),
+            ..response_processing_for(user_id, "plain")
+        }),
+        Err(e) => {
+            ic_cdk::println!("Summary generation failed: {}, keeping recent questions", e);
+            let questions: Vec<String> = pruned
+                .iter()
+                .filter(|m| m.sender == "user")
+                .rev()
+                .take(3)
+                .map(|m| m.content.clone())
+                .collect();
+            let mut summary = previous.unwrap_or_default();
+            if !questions.is_empty() {
+                summary = format!("{} Student asked about: {}", summary, questions.join("; "));
+            }
+            trim_to_length(summary.trim(), SUMMARY_MAX_CHARS)
+        }
+    }
+}
+
+// Removes matching messages from a session, leaving replies that are still being delivered
+fn prune_session_messages<F: Fn(usize, &ChatMessage) -> bool>(session: &ChatSession, should_remove: F) -> Vec<ChatMessage> {
+    let list = CHAT_MESSAGES.with(|messages| messages.borrow().get(&session.id)).map(|list| list.0).unwrap_or_default();
+    let mut pruned = Vec::new();
+    let mut kept = Vec::new();
+    for (index, message) in list.into_iter().enumerate() {
+        if message.delivery_status == "delivered" && should_remove(index, &message) {
+            pruned.push(message);
+        } else {
+            kept.push(message);
+        }
+    }
+    if !pruned.is_empty() {
+        certify::store_messages(session.user_id, &session.id, ChatMessageList(kept));
+    }
+    
+    let bytes: u64 = pruned.iter().map(chat_message_bytes).sum();
+    record_storage_change(session.user_id, "messages", -(bytes as i64));
+    pruned
+}
+
+async fn refresh_session_summary(session: ChatSession, pruned: Vec<ChatMessage>) -> ChatSession {
+    let summary = summarize_pruned_messages(session.user_id, session.summary.clone(), &pruned).await;
+    
+    // Re-read in case the session changed while the summary was generated
+    CHAT_SESSIONS.with(|sessions| {
+        let mut sessions = sessions.borrow_mut();
+        match sessions.get(&session.id) {
+            Some(mut current) => {
+                current.summary = Some(summary);
+                current.updated_at = ic_cdk::api::time();
+                sessions.insert(current.id.clone(), current.clone());
+                current
+            }
+            None => session,
+        }
+    })
+}
+
+#[ic_cdk::update]
+async fn clear_session_messages(session_id: String, keep_last_n: u32) -> Result<ChatSession, String> {
+    let caller = ic_cdk::caller();
+    rate_limit::check(caller, "generation")?;
+    let session = owned_session(&session_id, caller)?;
+    
+    let total = CHAT_MESSAGES.with(|messages| messages.borrow().get(&session_id).map(|l| l.0.len()).unwrap_or(0));
+    let cutoff = total.saturating_sub(keep_last_n as usize);
+    let pruned = prune_session_messages(&session, |index, _| index < cutoff);
+    if pruned.is_empty() {
+        return Ok(session);
+    }
+    
+    Ok(refresh_session_summary(session, pruned).await)
+}
+
+// Prunes every session of the caller; returns the number of messages removed
+#[ic_cdk::update]
+async fn delete_messages_before(timestamp: u64) -> Result<u64, String> {
+    let caller = ic_cdk::caller();
+    rate_limit::check(caller, "generation")?;
+    let sessions: Vec<ChatSession> = CHAT_SESSIONS.with(|sessions| {
+        sessions.borrow().iter().filter(|(_, s)| s.user_id == caller).map(|(_, s)| s).collect()
+    });
+    
+    let mut pruned_sessions = Vec::new();
+    for session in sessions {
+        let pruned = prune_session_messages(&session, |_, m| m.timestamp < timestamp);
+        if !pruned.is_empty() {
+            pruned_sessions.push((session, pruned));
+        }
+    }
+    
+    let removed = pruned_sessions.iter().map(|(_, p)| p.len() as u64).sum();
+    for (session, pruned) in pruned_sessions {
+        refresh_session_summary(session, pruned).await;
+    }
+    
+    Ok(removed)
+}
+
+// --- Session Intake ---
+
+const MIN_INTAKE_QUESTIONS: usize = 3;
+const MAX_INTAKE_QUESTIONS: usize = 5;
+// Intake answers are injected into prompts until the student has sent this many messages
+const INTAKE_PROMPT_TURNS: usize = 3;
+
+// Falls back to the tutor's default topic and pairs answers with the tutor's questions.
+// Answers are optional so clients without the questionnaire keep working.
+fn resolve_session_intake(tutor: &Tutor, topic: &str, answers: Option<Vec<String>>) -> Result<(String, Vec<IntakeAnswer>), String> {
+    let topic = match topic.trim() {
+        "" => tutor.default_topic.clone().ok_or("A topic is required")?,
+        topic => topic.to_string(),
+    };
+    
+    let answers = answers.unwrap_or_default();
+    if answers.is_empty() {
+        return Ok((topic, Vec::new()));
+    }
+    if answers.len() != tutor.intake_questions.len() {
+        return Err(format!("Expected {} intake answers", tutor.intake_questions.len()));
+    }
+    if answers.iter().any(|a| a.len() > 500) {
+        return Err("Intake answers must be 500 characters or fewer".to_string());
+    }
+    
+    let intake = tutor
+        .intake_questions
+        .iter()
+        .zip(answers)
+        .filter(|(_, answer)| !answer.trim().is_empty())
+        .map(|(question, answer)| IntakeAnswer { question: question.clone(), answer: answer.trim().to_string() })
+        .collect();
+    Ok((topic, intake))
+}
+
+fn intake_summary(intake: &[IntakeAnswer]) -> String {
+    intake
+        .iter()
+        .map(|a| format!("Q: {} A: {}", a.question, a.answer))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn intake_background(session: &ChatSession) -> Option<String> {
+    if session.intake_answers.is_empty() {
+        return None;
+    }
+    
+    let user_turns = CHAT_MESSAGES.with(|messages| {
+        messages.borrow().get(&session.id).map(|list| list.0.iter().filter(|m| m.sender == "user").count()).unwrap_or(0)
+    });
+    if user_turns > INTAKE_PROMPT_TURNS {
+        return None;
+    }
+    Some(format!("Student background:\n{}", intake_summary(&session.intake_answers)))
+}
+
+#[ic_cdk::update]
+fn set_tutor_intake(tutor_id: String, default_topic: Option<String>, questions: Vec<String>) -> Result<Tutor, String> {
+    let caller = ic_cdk::caller();
+    let (key, mut tutor) = owned_tutor(&tutor_id, caller)?;
+    
+    let questions: Vec<String> = questions.into_iter().map(|q| q.trim().to_string()).collect();
+    if !questions.is_empty() && !(MIN_INTAKE_QUESTIONS..=MAX_INTAKE_QUESTIONS).contains(&questions.len()) {
+        return Err(format!("An intake questionnaire needs {} to {} questions", MIN_INTAKE_QUESTIONS, MAX_INTAKE_QUESTIONS));
+    }
+    if questions.iter().any(|q| q.is_empty() || q.len() > 200) {
+        return Err("Each intake question must be between 1 and 200 characters".to_string());
+    }
+    
+    tutor.default_topic = default_topic.map(|t| t.trim().to_string()).filter(|t| !t.is_empty());
+    tutor.intake_questions = questions;
+    tutor.updated_at = ic_cdk::api::time();
+    cache::store_tutor(key, tutor.clone());
+    
+    Ok(tutor)
+}
+
+// --- Welcome Messages ---
+
+const DEFAULT_WELCOME_TEMPLATE: &str = "Hi {student_name}! I'm {tutor_name}, and I'm excited to help you learn {topic}. What would you like to start with?";
+const WELCOME_TEMPLATE_VARIABLES: [&str; 5] = ["student_name", "tutor_name", "topic", "expertise", "teaching_style"];
+const WELCOME_MODES: [&str; 4] = ["ai", "async_ai", "template", "none"];
+
+fn render_welcome_template(template: &str, tutor: &Tutor, topic: &str, student_name: &str) -> String {
+    template
+        .replace("{student_name}", student_name)
+        .replace("{tutor_name}", &tutor.name)
+        .replace("{topic}", topic)
+        .replace("{expertise}", &tutor.expertise.join(", "))
+        .replace("{teaching_style}", &tutor.teaching_style)
+}
+
+// Returns the placeholders in a template that are not known variables
+fn unknown_template_variables(template: &str) -> Vec<String> {
+    template
+        .split('{')
+        .skip(1)
+        .filter_map(|part| part.split_once('}').map(|(name, _)| name))
+        .filter(|name| !WELCOME_TEMPLATE_VARIABLES.contains(name))
+        .map(|name| name.to_string())
+        .collect()
+}
+
+fn learner_name(user_id: Principal) -> Option<String> {
+    cache::user(user_id).map(|u| u.first_name.unwrap_or(u.username))
+}
+
+fn templated_welcome(tutor: &Tutor, session: &ChatSession) -> String {
+    let student_name = learner_name(session.user_id).unwrap_or_else(|| "there".to_string());
+    let template = tutor.welcome_template.as_deref().unwrap_or(DEFAULT_WELCOME_TEMPLATE);
+    render_welcome_template(template, tutor, &session.topic, &student_name)
+}
+
+fn append_chat_message(user_id: Principal, message: ChatMessage) {
+    record_storage_change(user_id, "messages", chat_message_bytes(&message) as i64);
+    let mut session_messages = CHAT_MESSAGES.with(|messages| messages.borrow().get(&message.session_id)).unwrap_or_else(|| ChatMessageList(Vec::new()));
+    let session_id = message.session_id.clone();
+    session_messages.0.push(message);
+    certify::store_messages(user_id, &session_id, session_messages);
+}
+
+// Posts the opening message for a new session. "async_ai" shows the template right away and
+// swaps in the AI greeting when it arrives; "ai" waits for it and falls back to the template.
+async fn post_welcome_message(tutor: &Tutor, session: &ChatSession, course_outline: Option<&CourseOutline>, acting: Principal) -> Option<ChatMessage> {
+    let message_id = format!("welcome_{}", ic_cdk::api::time());
+    let content = match tutor.welcome_mode.as_str() {
+        "none" => return None,
+        "template" => templated_welcome(tutor, session),
+        "async_ai" => {
+            let placeholder = start_pending_delivery(&session.id, &message_id, session.user_id, None, "welcome", "", &templated_welcome(tutor, session));
+            ic_cdk::spawn(async move {
+                if let Err(e) = deliver_tutor_reply(&message_id, ic_cdk::id()).await {
+                    ic_cdk::println!("Welcome message generation failed: {}", e);
+                }
+            });
+            return Some(placeholder);
+        }
+        _ => match generate_welcome_message(session.user_id, tutor, &session.topic, course_outline, &session.intake_answers, &message_id, acting).await {
+            Ok(content) => content,
+            Err(e) => {
+                ic_cdk::println!("Welcome message generation failed: {}, using template", e);
+                templated_welcome(tutor, session)
+            }
+        },
+    };
+    
+    let reading_grade = Some(estimate_reading_grade(&content));
+    let message = ChatMessage {
+        id: message_id,
+        session_id: session.id.clone(),
+        sender: "tutor".to_string(),
+        content,
+        timestamp: ic_cdk::api::time(),
+        has_audio: Some(false),
+        delivery_status: "delivered".to_string(),
+        reading_grade,
+        author: None,
+        citations: Vec::new(),
+        confidence: None,
+        rating: None,
+        bookmarked: false,
+    };
+    append_chat_message(session.user_id, message.clone());
+    Some(message)
+}
+
+#[ic_cdk::update]
+fn set_tutor_welcome(tutor_id: String, mode: String, template: Option<String>) -> Result<Tutor, String> {
+    let caller = ic_cdk::caller();
+    let (key, mut tutor) = owned_tutor(&tutor_id, caller)?;
+    
+    if !WELCOME_MODES.contains(&mode.as_str()) {
+        return Err(format!("Welcome mode must be one of: {}", WELCOME_MODES.join(", ")));
+    }
+    let template = template.map(|t| t.trim().to_string()).filter(|t| !t.is_empty());
+    if let Some(template) = &template {
+        if template.len() > 1000 {
+            return Err("Welcome template must be 1000 characters or fewer".to_string());
+        }
+        let unknown = unknown_template_variables(template);
+        if !unknown.is_empty() {
+            return Err(format!(
+                "Unknown template variables: {}. Available: {}",
+                unknown.join(", "),
+                WELCOME_TEMPLATE_VARIABLES.join(", ")
+            ));
+        }
+    }
+    
+    tutor.welcome_mode = mode;
+    tutor.welcome_template = template;
+    tutor.updated_at = ic_cdk::api::time();
+    cache::store_tutor(key, tutor.clone());
+    
+    Ok(tutor)
+}
+
+#[ic_cdk::query]
+fn preview_tutor_welcome(tutor_id: String, topic: String) -> Result<String, String> {
+    let caller = ic_cdk::caller();
+    let (_, tutor) = owned_tutor(&tutor_id, caller)?;
+    let student_name = get_self().map(|u| u.first_name.unwrap_or(u.username)).unwrap_or_else(|| "there".to_string());
+    let template = tutor.welcome_template.as_deref().unwrap_or(DEFAULT_WELCOME_TEMPLATE);
+    Ok(render_welcome_template(template, &tutor, &topic, &student_name))
+}
+
+// --- Previews ---
+
+const PREVIEW_TTL_NS: u64 = 30 * 60 * 1_000_000_000;
+// Per user, oldest previews are dropped beyond this
+const MAX_PREVIEWS_PER_USER: usize = 10;
+
+thread_local! {
+    static COURSE_PREVIEWS: RefCell<HashMap<String, CoursePreview>> = RefCell::new(HashMap::new());
+    static PERSONA_PREVIEWS: RefCell<HashMap<String, TutorPersonaPreview>> = RefCell::new(HashMap::new());
+}
+
+async fn preview_token() -> Result<String, String> {
+    Ok(hex_encode(&random_bytes().await?[..16]))
+}
+
+// Drops expired previews and keeps each user under the cap before a new one is added
+fn prune_previews<T, F: Fn(&T) -> (Principal, u64)>(previews: &mut HashMap<String, T>, user_id: Principal, now: u64, meta: F) {
+    previews.retain(|_, p| meta(p).1 > now);
+    let mut own: Vec<(String, u64)> = previews
+        .iter()
+        .filter(|(_, p)| meta(p).0 == user_id)
+        .map(|(token, p)| (token.clone(), meta(p).1))
+        .collect();
+    if own.len() >= MAX_PREVIEWS_PER_USER {
+        own.sort_by_key(|(_, expires_at)| *expires_at);
+        for (token, _) in own.iter().take(own.len() + 1 - MAX_PREVIEWS_PER_USER) {
+            previews.remove(token);
+        }
+    }
+}
+
+#[ic_cdk::update]
+async fn preview_course_outline(tutor_id: String, topic: String) -> Result<CoursePreview, String> {
+    let caller = ic_cdk::caller();
+    rate_limit::check(caller, "generation")?;
+    let (_, tutor) = owned_tutor(&tutor_id, caller)?;
+    let tutor_id = tutor.public_id.clone();
+    let user = get_self().ok_or("User not found")?;
+    
+    let outline = generate_course_outline(&tutor, &topic, &user.settings, caller).await?;
+    let now = ic_cdk::api::time();
+    let preview = CoursePreview {
+        token: preview_token().await?,
+        user_id: caller,
+        tutor_id,
+        topic,
+        outline,
+        created_at: now,
+        expires_at: now + PREVIEW_TTL_NS,
+    };
+    
+    COURSE_PREVIEWS.with(|previews| {
+        let mut previews = previews.borrow_mut();
+        prune_previews(&mut previews, caller, now, |p| (p.user_id, p.expires_at));
+        previews.insert(preview.token.clone(), preview.clone());
+    });
+    Ok(preview)
+}
+
+#[ic_cdk::update]
+async fn confirm_course_preview(token: String, intake_answers: Option<Vec<String>>) -> Result<(String, String), String> {
+    let caller = ic_cdk::caller();
+    rate_limit::check(caller, "generation")?;
+    let preview = COURSE_PREVIEWS.with(|previews| previews.borrow().get(&token).cloned())
+        .filter(|p| p.user_id == caller && p.expires_at > ic_cdk::api::time())
+        .ok_or("Preview not found or expired")?;
+    let (_, tutor) = owned_tutor(&preview.tutor_id, caller)?;
+    let (topic, intake_answers) = resolve_session_intake(&tutor, &preview.topic, intake_answers)?;
+    
+    COURSE_PREVIEWS.with(|previews| previews.borrow_mut().remove(&token));
+    start_learning_session(caller, &tutor, topic, intake_answers, preview.outline).await
+}
+
+#[derive(serde::Deserialize)]
+struct AiTutorPersona {
+    description: String,
+    teaching_style: String,
+    personality: String,
+    expertise: Vec<String>,
+}
+
+#[ic_cdk::update]
+async fn preview_tutor_persona(name: String, brief: String) -> Result<TutorPersonaPreview, String> {
+    let caller = ic_cdk::caller();
+    rate_limit::check(caller, "generation")?;
+    if name.trim().is_empty() || brief.trim().is_empty() {
+        return Err("Name and a short description of the tutor are required".to_string());
+    }
+    
+    let prompt = format!(
+        "Design an AI tutor named '{}' from this brief: \"{}\".
+        
+        Return JSON:
+        {{\"description\":\"One or two sentences\",\"teaching_style\":\"Style\",\"personality\":\"Personality\",\"expertise\":[\"area1\",\"area2\"]}}
+        
+        Keep each field under 150 chars. Max 5 expertise areas.",
+        name.trim(),
+        brief.trim()
+    );
+    let response = call_groq_ai(&prompt, "default", caller).await
+        .map(|r| process_ai_response(r, &response_processing_for(caller, "json")));
+    
+    let persona = response.ok()
+        .and_then(|r| serde_json::from_str::<AiTutorPersona>(&r).ok())
+        .filter(|p| !p.description.trim().is_empty() && !p.expertise.is_empty());
+    let ai_generated = persona.is_some();
+    let persona = persona.unwrap_or_else(|| AiTutorPersona {
+        description: brief.trim().to_string(),
+        teaching_style: "Patient and structured".to_string(),
+        personality: "Friendly and encouraging".to_string(),
+        expertise: vec![brief.trim().chars().take(60).collect()],
+    });
+    
+    let now = ic_cdk::api::time();
+    let preview = TutorPersonaPreview {
+        token: preview_token().await?,
+        user_id: caller,
+        name: name.trim().to_string(),
+        description: persona.description,
+        teaching_style: persona.teaching_style,
+        personality: persona.personality,
+        expertise: persona.expertise.into_iter().take(5).collect(),
+        ai_generated,
+        created_at: now,
+        expires_at: now + PREVIEW_TTL_NS,
+    };
+    
+    PERSONA_PREVIEWS.with(|previews| {
+        let mut previews = previews.borrow_mut();
+        prune_previews(&mut previews, caller, now, |p| (p.user_id, p.expires_at));
+        previews.insert(preview.token.clone(), preview.clone());
+    });
+    Ok(preview)
+}
+
+#[ic_cdk::update]
+async fn confirm_tutor_persona(token: String) -> Result<Tutor, String> {
+    let caller = ic_cdk::caller();
+    let preview = PERSONA_PREVIEWS.with(|previews| previews.borrow().get(&token).cloned())
+        .filter(|p| p.user_id == caller && p.expires_at > ic_cdk::api::time())
+        .ok_or("Preview not found or expired")?;
+    
+    // Taken out while the tutor is created so the same preview can't be confirmed twice
+    PERSONA_PREVIEWS.with(|previews| previews.borrow_mut().remove(&token));
+    let result = create_tutor(
+        preview.name.clone(),
+        preview.description.clone(),
+        preview.teaching_style.clone(),
+        preview.personality.clone(),
+        preview.expertise.clone(),
+        None,
+        None,
+        None,
+        None,
+        None,
+    ).await;
+    if result.is_err() {
+        PERSONA_PREVIEWS.with(|previews| previews.borrow_mut().insert(token, preview));
+    }
+    result
+}
+
+// --- Quotas ---
+//
+// Usage at or above the soft limit still succeeds but carries a warning and notifies the
+// user once per period; only going past the plan limit is rejected.
+
+const QUOTA_SOFT_LIMIT_PERCENT: u64 = 80;
+const QUOTA_RESOURCES: [&str; 4] = ["ai_calls", "storage", "tutors", "group_memberships"];
+
+thread_local! {
+    // (user, resource) -> period the soft-limit notification was last sent for
+    static QUOTA_WARNINGS_SENT: RefCell<HashMap<(Principal, String), u64>> = RefCell::new(HashMap::new());
+}
+
+fn ai_call_key(user_id: Principal, day: u64) -> String {
+    format!("{:010}:{}", day, user_id)
+}
+
+fn quota_status(user_id: Principal, resource: &str) -> QuotaStatus {
+    let plan = user_plan(user_id);
+    let limits = plan_limits(&plan);
+    let now = ic_cdk::api::time();
+    
+    let (used, limit, reset_at) = match resource {
+        "ai_calls" => {
+            let offset = user_offset_ns(user_id);
+            let used = AI_CALL_COUNTS.with(|counts| counts.borrow().get(&ai_call_key(user_id, local_day(now, offset)))).unwrap_or(0);
+            (used, limits.and_then(|l| l.ai_calls_per_day), Some(next_local_midnight(now, offset)))
+        }
+        "storage" => (storage_usage(user_id).total_bytes(), limits.map(|l| l.storage_bytes), None),
+        "group_memberships" => {
+            let used = GROUP_MEMBERSHIPS.with(|memberships| {
+                memberships.borrow().values().filter(|m| m.user_id == user_id && m.status == "active").count()
+            }) as u64;
+            (used, limits.and_then(|l| l.max_group_memberships), None)
+        }
+        _ => {
+            let used = TUTORS.with(|tutors| tutors.borrow().iter().filter(|(_, t)| t.user_id == user_id).count()) as u64;
+            (used, limits.and_then(|l| l.max_tutors), None)
+        }
+    };
+    
+    let mut status = QuotaStatus { resource: resource.to_string(), plan, used, limit, reset_at, warning: None };
+    if limit.is_some_and(|limit| used * 100 >= limit * QUOTA_SOFT_LIMIT_PERCENT) {
+        status.warning = Some(quota_message(&status, "You are close to your"));
+    }
+    status
+}
+
+fn quota_message(status: &QuotaStatus, prefix: &str) -> String {
+    let resource = match status.resource.as_str() {
+        "ai_calls" => "daily AI call limit",
+        "storage" => "storage limit",
+        "group_memberships" => "study group limit",
+        _ => "tutor limit",
+    };
+    let reset = match status.reset_at {
+        Some(reset_at) => {
+            let minutes = reset_at.saturating_sub(ic_cdk::api::time()) / 60_000_000_000;
+            format!("resets in {}h {}m", minutes / 60, minutes % 60)
+        }
+        None => "does not reset; free up usage or upgrade your plan".to_string(),
+    };
+    format!(
+        "{} {}: {} of {} used on the {} plan ({})",
+        prefix,
+        resource,
+        status.used,
+        status.limit.unwrap_or(0),
+        status.plan,
+        reset
+    )
+}
+
+// Rejects usage past the limit; otherwise sends the soft-limit notification if now due
+fn enforce_quota(user_id: Principal, resource: &str, additional: u64) -> Result<(), String> {
+    let mut status = quota_status(user_id, resource);
+    let Some(limit) = status.limit else {
+        return Ok(());
+    };
+    
+    if status.used + additional > limit {
+        return Err(quota_message(&status, "You have reached your"));
+    }
+    
+    status.used += additional;
+    if status.used * 100 >= limit * QUOTA_SOFT_LIMIT_PERCENT {
+        let period = status.reset_at.unwrap_or(0);
+        let first_warning = QUOTA_WARNINGS_SENT.with(|sent| {
+            sent.borrow_mut().insert((user_id, resource.to_string()), period) != Some(period)
+        });
+        if first_warning {
+            notify_user(user_id, "quota_warning", "system", quota_message(&status, "You are close to your"), None);
+        }
+    }
+    Ok(())
+}
+
+// Counts an AI call for a signed-up user; internal jobs such as delivery retries are not counted
+fn consume_ai_call(user_id: Principal) -> Result<(), String> {
+    if !USERS.with(|users| users.borrow().contains_key(&user_id)) {
+        return Ok(());
+    }
+    enforce_quota(user_id, "ai_calls", 1)?;
+    
+    let key = ai_call_key(user_id, local_day(ic_cdk::api::time(), user_offset_ns(user_id)));
+    AI_CALL_COUNTS.with(|counts| {
+        let mut counts = counts.borrow_mut();
+        let count = counts.get(&key).unwrap_or(0);
+        counts.insert(key, count + 1);
+    });
+    Ok(())
+}
+
+// Counters from before yesterday (in any time zone) are no longer needed; removes a bounded batch per run
+fn prune_ai_call_counts(now: u64) {
+    let cutoff = ai_call_key(Principal::anonymous(), (now / NANOS_PER_DAY).saturating_sub(1));
+    let expired: Vec<String> = AI_CALL_COUNTS.with(|counts| {
+        counts.borrow().iter().map(|(key, _)| key).take_while(|key| *key < cutoff).take(RETENTION_BATCH_SIZE).collect()
+    });
+    AI_CALL_COUNTS.with(|counts| {
+        let mut counts = counts.borrow_mut();
+        for key in expired {
+            counts.remove(&key);
+        }
+    });
+}
+
+#[ic_cdk::query]
+fn get_my_quota_status() -> Vec<QuotaStatus> {
+    let caller = ic_cdk::caller();
+    QUOTA_RESOURCES.iter().map(|resource| quota_status(caller, resource)).collect()
+}
+
+// "1000 AI calls a day instead of 50" when the other plan allows more; None means unlimited
+fn limit_gain(label: &str, current: Option<u64>, other: Option<u64>) -> Option<String> {
+    match (current, other) {
+        (Some(current), None) => Some(format!("unlimited {} instead of {}", label, current)),
+        (Some(current), Some(other)) if other > current => Some(format!("{} {} instead of {}", other, label, current)),
+        _ => None,
+    }
+}
+
+fn plan_gains(current: &PlanLimits, other: &PlanLimits) -> Vec<String> {
+    let mb = |bytes: u64| bytes / (1024 * 1024);
+    [
+        limit_gain("MB of storage", Some(mb(current.storage_bytes)), Some(mb(other.storage_bytes))),
+        limit_gain("AI calls a day", current.ai_calls_per_day, other.ai_calls_per_day),
+        limit_gain("tutors", current.max_tutors, other.max_tutors),
+        limit_gain("study groups", current.max_group_memberships, other.max_group_memberships),
+        limit_gain("simultaneous AI requests", current.max_concurrent_ai.map(u64::from), other.max_concurrent_ai.map(u64::from)),
+    ]
+    .into_iter()
+    .flatten()
+    .collect()
+}
+
+fn plan_usage(user_id: Principal) -> PlanUsage {
+    let config = get_config();
+    let plan = user_plan(user_id);
+    let resources: Vec<QuotaStatus> = QUOTA_RESOURCES.iter().map(|resource| quota_status(user_id, resource)).collect();
+    let current = plan_limits(&plan);
+    
+    // Plans listed after the current one are upgrades; only those that add something are shown
+    let upgrades = match &current {
+        Some(current) => config.plan_limits.iter()
+            .skip_while(|l| l.plan != plan)
+            .skip(1)
+            .map(|l| PlanUpgrade { limits: l.clone(), gains: plan_gains(current, l) })
+            .filter(|u| !u.gains.is_empty())
+            .collect(),
+        None => Vec::new(),
+    };
+    let cycles_per_ai_call = outcall_budget(&config, "chat").cycles;
+    let ai_calls_today = resources.iter().find(|r| r.resource == "ai_calls").map(|r| r.used).unwrap_or(0);
+    let subscription_renews_at = USER_SUBSCRIPTIONS.with(|subscriptions| {
+        subscriptions.borrow().values()
+            .find(|s| s.user_id == user_id && s.status == "active")
+            .and_then(|s| s.next_payment_date.or(s.end_date))
+    });
+    
+    PlanUsage {
+        plan,
+        resources,
+        max_concurrent_ai: current.and_then(|l| l.max_concurrent_ai),
+        cycles_per_ai_call,
+        estimated_ai_cycles_today: ai_calls_today * cycles_per_ai_call,
+        subscription_renews_at,
+        upgrades,
+    }
+}
+
+#[ic_cdk::query]
+fn get_my_plan_usage() -> PlanUsage {
+    plan_usage(ic_cdk::caller())
+}
+
+// --- Audit Log ---
+
+fn record_audit(actor: Principal, action: &str, target_user: Option<Principal>, details: String) {
+    let id = next_id("audit_entry");
+    AUDIT_LOG.with(|log| {
+        log.borrow_mut().insert(id, AuditEntry {
+            id,
+            actor,
+            action: action.to_string(),
+            target_user,
+            details,
+            timestamp: ic_cdk::api::time(),
+        });
+    });
+}
+
+#[ic_cdk::query]
+fn get_audit_log_admin(target_user: Option<Principal>, limit: u32) -> Result<Vec<AuditEntry>, String> {
+    require(ic_cdk::caller(), Permission::ViewAuditLog)?;
+    
+    // Newest first; ids are sequential so reverse iteration is chronological
+    let hint = "Lower the limit or filter by target user.";
+    let mut entries = Vec::new();
+    AUDIT_LOG.with(|log| {
+        for (scanned, (_, entry)) in log.borrow().iter().rev().enumerate() {
+            if entries.len() >= limit as usize {
+                break;
+            }
+            scan_checkpoint(scanned, hint)?;
+            if target_user.is_none() || entry.target_user == target_user {
+                entries.push(entry);
+            }
+        }
+        Ok::<(), String>(())
+    })?;
+    ensure_fits(&entries, hint)?;
+    Ok(entries)
+}
+
+// Lets users see every time support staff accessed their account
+#[ic_cdk::query]
+fn get_my_support_access_log() -> Vec<AuditEntry> {
+    let caller = ic_cdk::caller();
+    AUDIT_LOG.with(|log| {
+        log.borrow()
+            .iter()
+            .rev()
+            .map(|(_, e)| e)
+            .filter(|e| e.target_user == Some(caller) && e.action.starts_with("impersonation"))
+            .collect()
+    })
+}
+
+// --- Support Impersonation ---
+
+const MAX_IMPERSONATION_MINUTES: u32 = 60;
+// A request the user hasn't answered in this long can no longer be approved
+const IMPERSONATION_REQUEST_TTL_NS: u64 = 30 * 60 * 1_000_000_000;
+
+fn activate_impersonation(session: &mut ImpersonationSession, now: u64) {
+    session.status = "active".to_string();
+    session.started_at = Some(now);
+    session.expires_at = Some(now + session.duration_minutes as u64 * 60 * 1_000_000_000);
+}
+
+fn save_impersonation(session: &ImpersonationSession) {
+    IMPERSONATION_SESSIONS.with(|sessions| {
+        sessions.borrow_mut().insert(session.id, session.clone());
+    });
+}
+
+// Starts immediately when the user has standing consent; otherwise waits for the user's approval
+#[ic_cdk::update]
+fn impersonate_start(user_id: Principal, reason: String, duration_minutes: u32) -> Result<ImpersonationSession, String> {
+    let caller = ic_cdk::caller();
+    require(caller, Permission::ImpersonateUsers)?;
+    if reason.trim().is_empty() {
+        return Err("A reason is required".to_string());
+    }
+    if duration_minutes == 0 || duration_minutes > MAX_IMPERSONATION_MINUTES {
+        return Err(format!("Duration must be between 1 and {} minutes", MAX_IMPERSONATION_MINUTES));
+    }
+    let user = cache::user(user_id).ok_or("User not found")?;
+    
+    let now = ic_cdk::api::time();
+    let mut session = ImpersonationSession {
+        id: next_id("impersonation_session"),
+        admin_id: caller,
+        user_id,
+        reason: reason.trim().to_string(),
+        status: "pending_approval".to_string(),
+        duration_minutes,
+        consent: if user.settings.allow_support_access { "standing" } else { "explicit" }.to_string(),
+        created_at: now,
+        started_at: None,
+        expires_at: None,
+        ended_at: None,
+    };
+    
+    if user.settings.allow_support_access {
+        activate_impersonation(&mut session, now);
+        notify_user(user_id, "info", "support", format!("Support started a {}-minute read-only review of your account: {}", duration_minutes, session.reason), Some(session.id));
+    } else {
+        notify_user(user_id, "warning", "support", format!("Support is asking for {} minutes of read-only access to your account (answer within 30 minutes): {}", duration_minutes, session.reason), Some(session.id));
+    }
+    save_impersonation(&session);
+    record_audit(caller, "impersonation_started", Some(user_id), format!("session {} ({}, {}): {}", session.id, session.status, session.consent, session.reason));
+    
+    Ok(session)
+}
+
+#[ic_cdk::update]
+fn respond_to_impersonation(session_id: u64, approve: bool) -> Result<ImpersonationSession, String> {
+    let caller = ic_cdk::caller();
+    let mut session = IMPERSONATION_SESSIONS.with(|sessions| sessions.borrow().get(&session_id))
+        .filter(|s| s.user_id == caller)
+        .ok_or("Access request not found")?;
+    if session.status != "pending_approval" {
+        return Err("This request has already been answered".to_string());
+    }
+    let now = ic_cdk::api::time();
+    if now >= session.created_at + IMPERSONATION_REQUEST_TTL_NS {
+        session.status = "expired".to_string();
+        session.ended_at = Some(now);
+        save_impersonation(&session);
+        return Err("This request has expired; support has to ask again".to_string());
+    }
+    
+    if approve {
+        activate_impersonation(&mut session, now);
+    } else {
+        session.status = "declined".to_string();
+        session.ended_at = Some(now);
+    }
+    save_impersonation(&session);
+    record_audit(caller, if approve { "impersonation_approved" } else { "impersonation_declined" }, Some(caller), format!("session {}", session_id));
+    
+    Ok(session)
+}
+
+// Runs a read-only view as the user; every call is audited, including refused ones
+#[ic_cdk::update]
+fn impersonate_query(session_id: u64, method: String) -> Result<Vec<u8>, String> {
+    let caller = ic_cdk::caller();
+    let session = IMPERSONATION_SESSIONS.with(|sessions| sessions.borrow().get(&session_id))
+        .filter(|s| s.admin_id == caller)
+        .ok_or("Impersonation session not found")?;
+    
+    let now = ic_cdk::api::time();
+    let active = session.status == "active" && session.expires_at.is_some_and(|at| now < at) && has_permission(caller, Permission::ImpersonateUsers);
+    record_audit(
+        caller,
+        "impersonation_access",
+        Some(session.user_id),
+        format!("session {}: {}{}", session_id, method, if active { "" } else { " (refused)" }),
+    );
+    if !active {
+        return Err("Impersonation session is not active".to_string());
+    }
+    
+    read_only_view_as(session.user_id, &method)
+}
+
+#[ic_cdk::update]
+fn impersonate_end(session_id: u64) -> Result<ImpersonationSession, String> {
+    let caller = ic_cdk::caller();
+    let mut session = IMPERSONATION_SESSIONS.with(|sessions| sessions.borrow().get(&session_id))
+        .filter(|s| s.admin_id == caller || s.user_id == caller)
+        .ok_or("Impersonation session not found")?;
+    if session.status == "ended" || session.status == "declined" || session.status == "expired" {
+        return Ok(session);
+    }
+    
+    session.status = "ended".to_string();
+    session.ended_at = Some(ic_cdk::api::time());
+    save_impersonation(&session);
+    record_audit(caller, "impersonation_ended", Some(session.user_id), format!("session {}", session_id));
+    
+    Ok(session)
+}
+
+#[ic_cdk::update]
+fn set_support_access_consent(allowed: bool) -> Result<User, String> {
+    let caller = ic_cdk::caller();
+    let mut user = cache::user(caller).ok_or("User not found")?;
+    user.settings.allow_support_access = allowed;
+    user.updated_at = ic_cdk::api::time();
+    cache::store_user(user.clone());
+    Ok(user)
+}
+
+// --- Group Resource Library ---
+
+const PUBLISH_TRANSCRIPT_MAX_CHARS: usize = 20_000;
+
+thread_local! {
+    static PUBLISH_DRAFTS: RefCell<HashMap<String, SessionPublishDraft>> = RefCell::new(HashMap::new());
+}
+
+// Keeps only delivered user/tutor turns, without markdown fences or blank lines
+fn clean_transcript(messages: &[ChatMessage], tutor_name: &str) -> String {
+    let mut transcript = String::new();
+    for message in messages.iter().filter(|m| m.delivery_status == "delivered") {
+        let content = strip_markdown_fences(message.content.trim());
+        let content: Vec<&str> = content.lines().map(str::trim).filter(|l| !l.is_empty()).collect();
+        if content.is_empty() {
+            continue;
+        }
+        let speaker = if message.sender == "user" { "Student" } else { tutor_name };
+        transcript.push_str(&format!("{}: {}\n\n", speaker, content.join("\n")));
+    }
+    trim_to_length(transcript.trim_end(), PUBLISH_TRANSCRIPT_MAX_CHARS)
+}
+
+#[derive(serde::Deserialize)]
+struct AiStudyDocument {
+    title: String,
+    summary: String,
+}
+
+#[ic_cdk::update]
+async fn publish_session_to_group(session_id: String, group_id: u64) -> Result<SessionPublishDraft, String> {
+    let caller = ic_cdk::caller();
+    rate_limit::check(caller, "generation")?;
+    let session = owned_session(&session_id, caller)?;
+    visible_group(group_id, caller)?;
+    ensure_group_member(group_id, caller)?;
+    
+    let messages = CHAT_MESSAGES.with(|messages| {
+        messages.borrow().get(&session_id).map(|list| list.0).unwrap_or_default()
+    });
+    let tutor_name = cache::tutor_by_public_id(&session.tutor_id).map(|(_, t)| t.name).unwrap_or_else(|| "Tutor".to_string());
+    let transcript = clean_transcript(&messages, &tutor_name);
+    if transcript.is_empty() {
+        return Err("Session has no messages to publish".to_string());
+    }
+    
+    let prompt = format!(
+        "Turn this tutoring session on '{}' into a study document for classmates. Leave out \
+        personal details and small talk.
+        
+        Return JSON:
+        {{\"title\":\"Short title\",\"summary\":\"Key concepts, explanations and examples, under 300 words\"}}
+        
+        Earlier summary: {}
+        
+        Transcript:
+        {}",
+        session.topic,
+        session.summary.clone().unwrap_or_else(|| "none".to_string()),
+        transcript
+    );
+    let document = call_groq_ai(&prompt, "summary", caller).await
+        .map(|r| process_ai_response(r, &response_processing_for(caller, "json"))).ok()
+        .and_then(|r| serde_json::from_str::<AiStudyDocument>(&r).ok())
+        .filter(|d| !d.summary.trim().is_empty());
+    let ai_generated = document.is_some();
+    let (title, summary) = match document {
+        Some(d) if !d.title.trim().is_empty() => (d.title.trim().to_string(), d.summary.trim().to_string()),
+        Some(d) => (format!("Study notes: {}", session.topic), d.summary.trim().to_string()),
+        None => (
+            format!("Study notes: {}", session.topic),
+            session.summary.clone().unwrap_or_else(|| format!("Tutoring session on {}.", session.topic)),
+        ),
+    };
+    
+    let now = ic_cdk::api::time();
+    let draft = SessionPublishDraft {
+        token: preview_token().await?,
+        user_id: caller,
+        session_id,
+        group_id,
+        title,
+        summary,
+        transcript,
+        ai_generated,
+        created_at: now,
+        expires_at: now + PREVIEW_TTL_NS,
+    };
+    PUBLISH_DRAFTS.with(|drafts| {
+        let mut drafts = drafts.borrow_mut();
+        prune_previews(&mut drafts, caller, now, |d| (d.user_id, d.expires_at));
+        drafts.insert(draft.token.clone(), draft.clone());
+    });
+    Ok(draft)
+}
+
+// The author may edit the generated title and summary before approving publication
+#[ic_cdk::update]
+fn approve_session_publication(token: String, title: Option<String>, summary: Option<String>) -> Result<StudyResource, String> {
+    let caller = ic_cdk::caller();
+    let draft = PUBLISH_DRAFTS.with(|drafts| drafts.borrow().get(&token).cloned())
+        .filter(|d| d.user_id == caller && d.expires_at > ic_cdk::api::time())
+        .ok_or("Draft not found or expired")?;
+    active_group_membership(draft.group_id, caller).ok_or("You must be an active member of the group to publish to it")?;
+    
+    let title = title.filter(|t| !t.trim().is_empty()).unwrap_or(draft.title);
+    let summary = summary.filter(|s| !s.trim().is_empty()).unwrap_or(draft.summary);
+    let resource = StudyResource {
+        id: next_id("study_resource"),
+        group_id: draft.group_id,
+        user_id: caller,
+        title: title.trim().to_string(),
+        description: Some(summary.trim().to_string()),
+        resource_type: "session_notes".to_string(),
+        resource_url: None,
+        content: Some(format!("## Summary\n\n{}\n\n## Transcript\n\n{}", summary.trim(), draft.transcript)),
+        created_at: ic_cdk::api::time(),
+        source_session_id: Some(draft.session_id),
+    };
+    STUDY_RESOURCES.with(|resources| resources.borrow_mut().insert(resource.id, resource.clone()));
+    PUBLISH_DRAFTS.with(|drafts| drafts.borrow_mut().remove(&token));
+    credit_group_contribution(resource.group_id, caller, resource.created_at);
+    Ok(resource)
+}
+
+#[ic_cdk::query]
+fn get_group_resources(group_id: u64) -> Result<Vec<StudyResource>, String> {
+    visible_group(group_id, ic_cdk::caller())?;
+    
+    let mut resources: Vec<StudyResource> = STUDY_RESOURCES.with(|resources| {
+        resources.borrow().iter().map(|(_, r)| r).filter(|r| r.group_id == group_id).collect()
+    });
+    resources.sort_by_key(|r| std::cmp::Reverse(r.created_at));
+    Ok(resources)
+}
+
+// --- Mastery Decay ---
+//
+// A skill's score halves every half_life_days without practice. Passing a review quiz
+// restores it and doubles the half-life, so well-known skills come up for review less often.
+
+const MASTERY_DECAY_JOB_INTERVAL_NS: u64 = 6 * 60 * 60 * 1_000_000_000;
+const NS_PER_DAY: f64 = 24.0 * 60.0 * 60.0 * 1_000_000_000.0;
+const REVIEW_THRESHOLD: f64 = 60.0;
+const REVIEW_PASS_SCORE: f64 = 70.0;
+const INITIAL_HALF_LIFE_DAYS: f64 = 7.0;
+const MAX_HALF_LIFE_DAYS: f64 = 180.0;
+const REVIEW_QUIZ_QUESTIONS: usize = 5;
+const REVIEW_QUIZ_TTL_NS: u64 = 60 * 60 * 1_000_000_000;
+
+thread_local! {
+    // Quiz id -> (quiz as shown to the learner, answer key)
+    static REVIEW_QUIZZES: RefCell<HashMap<String, (ReviewQuiz, Vec<u32>)>> = RefCell::new(HashMap::new());
+}
+
+fn skill_key(user_id: Principal, skill: &str) -> String {
+    format!("{}:{}", user_id, skill.trim().to_lowercase())
+}
+
+fn decayed_score(proficiency: &SkillProficiency, now: u64) -> f64 {
+    let days = now.saturating_sub(proficiency.last_assessed_at) as f64 / NS_PER_DAY;
+    let score = proficiency.assessed_score * 0.5f64.powf(days / proficiency.half_life_days.max(1.0));
+    (score * 10.0).round() / 10.0
+}
+
+fn user_skills(user_id: Principal, now: u64) -> Vec<SkillProficiency> {
+    let prefix = format!("{}:", user_id);
+    SKILL_PROFICIENCY.with(|skills| {
+        skills.borrow()
+            .range(prefix.clone()..)
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .map(|(_, mut p)| {
+                p.score = decayed_score(&p, now);
+                p.due_for_review = p.score < REVIEW_THRESHOLD;
+                p
+            })
+            .collect()
+    })
+}
+
+// Records a fresh assessment; `review` marks it as a spaced-repetition review of a known skill
+fn record_skill_assessment(user_id: Principal, skill: &str, score: f64, review: bool, now: u64) -> SkillProficiency {
+    let key = skill_key(user_id, skill);
+    let score = score.clamp(0.0, 100.0);
+    SKILL_PROFICIENCY.with(|skills| {
+        let mut skills = skills.borrow_mut();
+        let mut proficiency = skills.get(&key).unwrap_or(SkillProficiency {
+            user_id,
+            skill: skill.trim().to_string(),
+            score,
+            assessed_score: score,
+            half_life_days: INITIAL_HALF_LIFE_DAYS,
+            review_count: 0,
+            last_assessed_at: now,
+            due_for_review: false,
+            updated_at: now,
+        });
+        if review {
+            proficiency.review_count += 1;
+            proficiency.half_life_days = if score >= REVIEW_PASS_SCORE {
+                (proficiency.half_life_days * 2.0).min(MAX_HALF_LIFE_DAYS)
+            } else {
+                INITIAL_HALF_LIFE_DAYS
+            };
+        }
+        proficiency.score = score;
+        proficiency.assessed_score = score;
+        proficiency.last_assessed_at = now;
+        proficiency.due_for_review = score < REVIEW_THRESHOLD;
+        proficiency.updated_at = now;
+        skills.insert(key, proficiency.clone());
+        proficiency
+    })
+}
+
+// Stores decayed scores and flags skills that drop below the review threshold; the reminder
+// job decides when to tell the user
+fn apply_mastery_decay(now: u64) {
+    let updates: Vec<(String, SkillProficiency)> = SKILL_PROFICIENCY.with(|skills| {
+        skills.borrow()
+            .iter()
+            .filter_map(|(key, mut p)| {
+                let score = decayed_score(&p, now);
+                if score == p.score {
+                    return None;
+                }
+                p.score = score;
+                p.updated_at = now;
+                Some((key, p))
+            })
+            .collect()
+    });
+    
+    for (key, mut proficiency) in updates {
+        if proficiency.score < REVIEW_THRESHOLD {
+            proficiency.due_for_review = true;
+        }
+        SKILL_PROFICIENCY.with(|skills| skills.borrow_mut().insert(key, proficiency));
+    }
+}
+
+#[ic_cdk::query]
+fn get_my_skill_proficiency() -> Vec<SkillProficiency> {
+    user_skills(ic_cdk::caller(), ic_cdk::api::time())
+}
+
+// Skills due for review, weakest first
+#[ic_cdk::query]
+fn get_review_recommendations() -> Vec<SkillProficiency> {
+    let mut due: Vec<SkillProficiency> = user_skills(ic_cdk::caller(), ic_cdk::api::time())
+        .into_iter()
+        .filter(|p| p.due_for_review)
+        .collect();
+    due.sort_by(|a, b| a.score.total_cmp(&b.score));
+    due
+}
+
+#[derive(serde::Deserialize)]
+struct AiReviewQuestion {
+    question: String,
+    options: Vec<String>,
+    correct_option: u32,
+}
+
+#[ic_cdk::update]
+async fn start_review_quiz(skill: String) -> Result<ReviewQuiz, String> {
+    rate_limit::check(ic_cdk::caller(), "generation")?;
+    build_review_quiz(ic_cdk::caller(), &skill).await
+}
+
+async fn build_review_quiz(caller: Principal, skill: &str) -> Result<ReviewQuiz, String> {
+    let proficiency = SKILL_PROFICIENCY.with(|skills| skills.borrow().get(&skill_key(caller, skill)))
+        .ok_or("No proficiency recorded for this skill")?;
+    
+    // A course glossary on this skill answers the quiz without an AI call
+    let glossary = glossary_for_skill(&proficiency.skill, caller);
+    let items: Vec<AiReviewQuestion> = if glossary.len() >= GLOSSARY_QUIZ_MIN_TERMS {
+        glossary_questions(&glossary, &random_bytes().await?, REVIEW_QUIZ_QUESTIONS)
+    } else {
+        let prompt = format!(
+            "Create a short review quiz on '{}' for a learner whose proficiency was last assessed at {:.0}%.
+            
+            Return ONLY a JSON array of {} multiple-choice questions:
+            [{{\"question\":\"Question\",\"options\":[\"a\",\"b\",\"c\",\"d\"],\"correct_option\":0}}]",
+            proficiency.skill,
+            proficiency.assessed_score,
+            REVIEW_QUIZ_QUESTIONS
+        );
+        let response = process_ai_response(call_groq_ai(&prompt, "placement", caller).await?, &response_processing_for(caller, "json"));
+        serde_json::from_str::<Vec<AiReviewQuestion>>(&response)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|q| q.options.len() >= 2 && (q.correct_option as usize) < q.options.len())
+            .take(REVIEW_QUIZ_QUESTIONS)
+            .collect()
+    };
+    if items.is_empty() {
+        return Err("Could not generate a review quiz, please try again".to_string());
+    }
+    
+    let now = ic_cdk::api::time();
+    let answers = items.iter().map(|q| q.correct_option).collect();
+    let quiz = ReviewQuiz {
+        id: preview_token().await?,
+        user_id: caller,
+        skill: proficiency.skill,
+        questions: items.into_iter().map(|q| ReviewQuestion { question: q.question, options: q.options }).collect(),
+        created_at: now,
+        expires_at: now + REVIEW_QUIZ_TTL_NS,
+    };
+    REVIEW_QUIZZES.with(|quizzes| {
+        let mut quizzes = quizzes.borrow_mut();
+        prune_previews(&mut quizzes, caller, now, |(q, _)| (q.user_id, q.expires_at));
+        quizzes.insert(quiz.id.clone(), (quiz.clone(), answers));
+    });
+    Ok(quiz)
+}
+
+#[ic_cdk::update]
+fn submit_review_quiz(quiz_id: String, answers: Vec<u32>) -> Result<ReviewResult, String> {
+    let caller = ic_cdk::caller();
+    let now = ic_cdk::api::time();
+    let (quiz, key) = REVIEW_QUIZZES.with(|quizzes| quizzes.borrow().get(&quiz_id).cloned())
+        .filter(|(q, _)| q.user_id == caller && q.expires_at > now)
+        .ok_or("Review quiz not found or expired")?;
+    if answers.len() != key.len() {
+        return Err(format!("Expected {} answers", key.len()));
+    }
+    
+    let correct = answers.iter().zip(&key).filter(|(a, k)| a == k).count() as u32;
+    let total = key.len() as u32;
+    let score_before = SKILL_PROFICIENCY.with(|skills| skills.borrow().get(&skill_key(caller, &quiz.skill)))
+        .map(|p| decayed_score(&p, now))
+        .unwrap_or(0.0);
+    let proficiency = record_skill_assessment(caller, &quiz.skill, correct as f64 * 100.0 / total as f64, true, now);
+    REVIEW_QUIZZES.with(|quizzes| quizzes.borrow_mut().remove(&quiz_id));
+    
+    Ok(ReviewResult { skill: quiz.skill, correct, total, score_before, proficiency })
+}
+
+// --- Exams ---
+//
+// Sections run one after another, each on a server-side timer that starts when the section
+// is entered. Answers after a section's deadline are still recorded but flagged.
+
+const MAX_EXAM_SECTIONS: usize = 5;
+const MAX_SECTION_QUESTIONS: u32 = 20;
+const MAX_SECTION_MINUTES: u32 = 180;
+// Answers quicker than this are flagged as a proctoring signal
+const RAPID_ANSWER_NS: u64 = 3 * 1_000_000_000;
+const EXAM_CLIENT_EVENTS: [&str; 5] = ["tab_hidden", "window_blur", "copy", "paste", "fullscreen_exit"];
+
+fn exam_question(question: String, options: Vec<String>, correct_option: u32, source: &str) -> ExamQuestion {
+    ExamQuestion {
+        question,
+        options,
+        correct_option,
+        source: source.to_string(),
+        answer: None,
+        answered_at: None,
+        time_spent_ns: 0,
+        late: false,
+    }
+}
+
+// Quiz questions from earlier placement tests on the same topic
+fn question_bank(topic: &str) -> Vec<ExamQuestion> {
+    let topic = topic.trim().to_lowercase();
+    let mut bank: Vec<ExamQuestion> = Vec::new();
+    PLACEMENT_TESTS.with(|tests| {
+        for (_, test) in tests.borrow().iter().filter(|(_, t)| t.topic.to_lowercase() == topic) {
+            for item in test.items.into_iter().filter(|i| i.kind == "quiz") {
+                if !bank.iter().any(|q| q.question == item.question) {
+                    bank.push(exam_question(item.question, item.options, item.correct_option, "question_bank"));
+                }
+            }
+        }
+    });
+    bank
+}
+
+async fn generate_exam_questions(tutor_data: &Tutor, topic: &str, count: usize, acting: Principal) -> Vec<ExamQuestion> {
+    let prompt = format!(
+        "Create exam questions on '{}' for a tutor with expertise in: {}. Mix easy, medium and hard questions.
+        
+        Return ONLY a JSON array of {} multiple-choice questions:
+        [{{\"question\":\"Question\",\"options\":[\"a\",\"b\",\"c\",\"d\"],\"correct_option\":0}}]",
+        topic,
+        tutor_data.expertise.join(", "),
+        count
+    );
+    let response = match call_groq_ai(&prompt, "placement", acting).await {
+        Ok(response) => process_ai_response(response, &response_processing_for(acting, "json")),
+        Err(e) => {
+            ic_cdk::println!("Exam question generation failed: {}", e);
+            return Vec::new();
+        }
+    };
+    
+    serde_json::from_str::<Vec<AiReviewQuestion>>(&response)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|q| q.options.len() >= 2 && (q.correct_option as usize) < q.options.len())
+        .take(count)
+        .map(|q| exam_question(q.question, q.options, q.correct_option, "ai"))
+        .collect()
+}
+
+fn get_owned_exam(exam_id: u64, caller: Principal) -> Result<Exam, String> {
+    EXAMS.with(|exams| exams.borrow().get(&exam_id))
+        .filter(|e| e.user_id == caller)
+        .ok_or_else(|| "Exam not found".to_string())
+}
+
+fn save_exam(exam: &Exam) {
+    EXAMS.with(|exams| exams.borrow_mut().insert(exam.id, exam.clone()));
+}
+
+fn start_exam_section(section: &mut ExamSection, now: u64) {
+    section.started_at = Some(now);
+    section.deadline = Some(now + section.duration_minutes as u64 * 60 * 1_000_000_000);
+}
+
+fn exam_view(exam: &Exam) -> ExamView {
+    let section = &exam.sections[exam.current_section as usize];
+    ExamView {
+        exam_id: exam.id,
+        topic: exam.topic.clone(),
+        status: exam.status.clone(),
+        current_section: exam.current_section,
+        section_name: section.name.clone(),
+        section_deadline: section.deadline,
+        questions: section.questions.iter().enumerate().map(|(i, q)| ExamQuestionView {
+            question_index: i as u32,
+            question: q.question.clone(),
+            options: q.options.clone(),
+            answer: q.answer,
+        }).collect(),
+        sections_total: exam.sections.len() as u32,
+    }
+}
+
+fn exam_section_scores(exam: &Exam) -> Vec<ExamSectionScore> {
+    exam.sections.iter().map(|section| ExamSectionScore {
+        name: section.name.clone(),
+        correct: section.questions.iter().filter(|q| q.answer == Some(q.correct_option)).count() as u32,
+        total: section.questions.len() as u32,
+        time_spent_ns: section.questions.iter().map(|q| q.time_spent_ns).sum(),
+        late_answers: section.questions.iter().filter(|q| q.late).count() as u32,
+    }).collect()
+}
+
+fn exam_result(exam: &Exam) -> ExamResult {
+    ExamResult {
+        exam_id: exam.id,
+        topic: exam.topic.clone(),
+        score_percent: exam.score_percent.unwrap_or(0.0),
+        sections: exam_section_scores(exam),
+        flags: exam.flags.clone(),
+        analysis: exam.analysis.clone().unwrap_or_default(),
+    }
+}
+
+#[ic_cdk::update]
+async fn start_exam(tutor_id: String, topic: String, sections: Vec<ExamSectionSpec>) -> Result<ExamView, String> {
+    let caller = ic_cdk::caller();
+    rate_limit::check(caller, "generation")?;
+    if topic.trim().is_empty() {
+        return Err("Topic is required".to_string());
+    }
+    if sections.is_empty() || sections.len() > MAX_EXAM_SECTIONS {
+        return Err(format!("An exam needs between 1 and {} sections", MAX_EXAM_SECTIONS));
+    }
+    if let Some(spec) = sections.iter().find(|s| {
+        !(1..=MAX_SECTION_QUESTIONS).contains(&s.question_count) || !(1..=MAX_SECTION_MINUTES).contains(&s.duration_minutes)
+    }) {
+        return Err(format!(
+            "Section '{}' needs 1-{} questions and 1-{} minutes",
+            spec.name, MAX_SECTION_QUESTIONS, MAX_SECTION_MINUTES
+        ));
+    }
+    
+    let (_, tutor) = owned_tutor(&tutor_id, caller)?;
+    let tutor_id = tutor.public_id.clone();
+    
+    // Start from a random point in the bank so repeated exams differ
+    let needed: usize = sections.iter().map(|s| s.question_count as usize).sum();
+    let mut questions = question_bank(&topic);
+    if !questions.is_empty() {
+        let offset = random_bytes().await?[0] as usize % questions.len();
+        questions.rotate_left(offset);
+    }
+    questions.truncate(needed);
+    if questions.len() < needed {
+        let generated = generate_exam_questions(&tutor, topic.trim(), needed - questions.len(), caller).await;
+        questions.extend(generated);
+    }
+    if questions.len() < needed {
+        return Err("Not enough questions are available for this exam, please try again".to_string());
+    }
+    
+    let now = ic_cdk::api::time();
+    let mut questions = questions.into_iter();
+    let mut exam = Exam {
+        id: next_id("exam"),
+        user_id: caller,
+        tutor_id,
+        topic: topic.trim().to_string(),
+        sections: sections.into_iter().map(|spec| ExamSection {
+            name: spec.name,
+            duration_minutes: spec.duration_minutes,
+            questions: questions.by_ref().take(spec.question_count as usize).collect(),
+            started_at: None,
+            deadline: None,
+            finished_at: None,
+        }).collect(),
+        current_section: 0,
+        status: "in_progress".to_string(),
+        flags: Vec::new(),
+        score_percent: None,
+        analysis: None,
+        created_at: now,
+        completed_at: None,
+    };
+    start_exam_section(&mut exam.sections[0], now);
+    save_exam(&exam);
+    Ok(exam_view(&exam))
+}
+
+#[ic_cdk::query]
+fn get_exam(exam_id: u64) -> Result<ExamView, String> {
+    Ok(exam_view(&get_owned_exam(exam_id, ic_cdk::caller())?))
+}
+
+// Time on a question is counted from the previous answer in the section, or the section start
+#[ic_cdk::update]
+fn submit_exam_answer(exam_id: u64, question_index: u32, answer: u32) -> Result<ExamView, String> {
+    let caller = ic_cdk::caller();
+    let mut exam = get_owned_exam(exam_id, caller)?;
+    if exam.status != "in_progress" {
+        return Err("This exam is already completed".to_string());
+    }
+    
+    let now = ic_cdk::api::time();
+    let section_index = exam.current_section;
+    let section = &mut exam.sections[section_index as usize];
+    let last_activity = section.questions.iter()
+        .filter_map(|q| q.answered_at)
+        .chain(section.started_at)
+        .max()
+        .unwrap_or(now);
+    let late = section.deadline.is_some_and(|deadline| now > deadline);
+    
+    let question = section.questions.get_mut(question_index as usize).ok_or("Exam question not found")?;
+    if answer as usize >= question.options.len() {
+        return Err("Invalid answer option".to_string());
+    }
+    let elapsed = now.saturating_sub(last_activity);
+    question.answer = Some(answer);
+    question.answered_at = Some(now);
+    question.time_spent_ns += elapsed;
+    question.late |= late;
+    
+    let flag = |kind: &str| ExamFlag {
+        kind: kind.to_string(),
+        section_index,
+        question_index: Some(question_index),
+        timestamp: now,
+    };
+    if late {
+        exam.flags.push(flag("late_answer"));
+    }
+    if elapsed < RAPID_ANSWER_NS {
+        exam.flags.push(flag("rapid_answer"));
+    }
+    save_exam(&exam);
+    Ok(exam_view(&exam))
+}
+
+#[ic_cdk::update]
+fn next_exam_section(exam_id: u64) -> Result<ExamView, String> {
+    let caller = ic_cdk::caller();
+    let mut exam = get_owned_exam(exam_id, caller)?;
+    if exam.status != "in_progress" {
+        return Err("This exam is already completed".to_string());
+    }
+    if exam.current_section as usize + 1 >= exam.sections.len() {
+        return Err("This is the last section, finish the exam to see your results".to_string());
+    }
+    
+    let now = ic_cdk::api::time();
+    exam.sections[exam.current_section as usize].finished_at = Some(now);
+    exam.current_section += 1;
+    start_exam_section(&mut exam.sections[exam.current_section as usize], now);
+    save_exam(&exam);
+    Ok(exam_view(&exam))
+}
+
+// Proctoring signals reported by the client, e.g. the exam tab being hidden
+#[ic_cdk::update]
+fn record_exam_event(exam_id: u64, kind: String) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    let mut exam = get_owned_exam(exam_id, caller)?;
+    if exam.status != "in_progress" {
+        return Err("This exam is already completed".to_string());
+    }
+    if !EXAM_CLIENT_EVENTS.contains(&kind.as_str()) {
+        return Err(format!("Unknown exam event. Expected one of: {}", EXAM_CLIENT_EVENTS.join(", ")));
+    }
+    
+    exam.flags.push(ExamFlag {
+        kind,
+        section_index: exam.current_section,
+        question_index: None,
+        timestamp: ic_cdk::api::time(),
+    });
+    save_exam(&exam);
+    Ok(())
+}
+
+async fn exam_analysis(exam: &Exam, scores: &[ExamSectionScore], score_percent: f64) -> String {
+    let section_lines: Vec<String> = scores.iter().map(|s| {
+        let average_secs = s.time_spent_ns.checked_div(s.total as u64).unwrap_or(0) / 1_000_000_000;
+        format!("{}: {}/{} correct, {}s per question, {} late answers", s.name, s.correct, s.total, average_secs, s.late_answers)
+    }).collect();
+    let missed: Vec<String> = exam.sections.iter()
+        .flat_map(|s| s.questions.iter())
+        .filter(|q| q.answer != Some(q.correct_option))
+        .take(10)
+        .map(|q| q.question.clone())
+        .collect();
+    let mut flag_counts: Vec<(String, usize)> = Vec::new();
+    for flag in &exam.flags {
+        match flag_counts.iter_mut().find(|(kind, _)| *kind == flag.kind) {
+            Some((_, count)) => *count += 1,
+            None => flag_counts.push((flag.kind.clone(), 1)),
+        }
+    }
+    let flags: Vec<String> = flag_counts.iter().map(|(kind, count)| format!("{} x{}", kind, count)).collect();
+    
+    let prompt = format!(
+        "Write a short performance analysis (under 200 words) for a student who just took a timed exam on '{}'. \
+        Cover strengths, weak areas, time management and what to study next.
+        
+        Overall score: {:.0}%
+        Sections:
+        {}
+        Missed questions:
+        {}
+        Proctoring signals: {}",
+        exam.topic,
+        score_percent,
+        section_lines.join("\n"),
+        if missed.is_empty() { "none".to_string() } else { missed.join("\n") },
+        if flags.is_empty() { "none".to_string() } else { flags.join(", ") }
+    );
+    match call_groq_ai(&prompt, "default", exam.user_id).await {
+        Ok(analysis) => process_ai_response(analysis, &response_processing_for(exam.user_id, "plain")),
+        Err(e) => {
+            ic_cdk::println!("Exam analysis failed: {}, using summary", e);
+            format!("You scored {:.0}% on {}. {}.", score_percent, exam.topic, section_lines.join("; "))
+        }
+    }
+}
+
+#[ic_cdk::update]
+async fn finish_exam(exam_id: u64) -> Result<ExamResult, String> {
+    let caller = ic_cdk::caller();
+    rate_limit::check(caller, "generation")?;
+    let mut exam = get_owned_exam(exam_id, caller)?;
+    if exam.status != "in_progress" {
+        return Ok(exam_result(&exam));
+    }
+    
+    let now = ic_cdk::api::time();
+    exam.sections[exam.current_section as usize].finished_at = Some(now);
+    let scores = exam_section_scores(&exam);
+    let correct: u32 = scores.iter().map(|s| s.correct).sum();
+    let total: u32 = scores.iter().map(|s| s.total).sum();
+    let score_percent = correct as f64 * 100.0 / total.max(1) as f64;
+    
+    // Completed before the analysis call so answers cannot change while it is in flight
+    exam.status = "completed".to_string();
+    exam.score_percent = Some(score_percent);
+    exam.completed_at = Some(now);
+    save_exam(&exam);
+    record_skill_assessment(caller, &exam.topic, score_percent, false, now);
+    if let Some(statement) = exam_statement(&get_config(), &exam) {
+        queue_xapi_statement(caller, statement);
+    }
+    
+    exam.analysis = Some(exam_analysis(&exam, &scores, score_percent).await);
+    save_exam(&exam);
+    Ok(exam_result(&exam))
+}
+
+#[ic_cdk::query]
+fn get_exam_result(exam_id: u64) -> Result<ExamResult, String> {
+    let exam = get_owned_exam(exam_id, ic_cdk::caller())?;
+    if exam.status != "completed" {
+        return Err("Exam is not completed yet".to_string());
+    }
+    Ok(exam_result(&exam))
+}
+
+// --- Cohorts ---
+
+fn can_manage_cohort(cohort: &Cohort, caller: Principal) -> bool {
+    cohort.created_by == caller || has_permission(caller, Permission::ModerateContent)
+}
+
+fn get_cohort(cohort_id: u64) -> Result<Cohort, String> {
+    COHORTS.with(|cohorts| cohorts.borrow().get(&cohort_id)).ok_or_else(|| "Cohort not found".to_string())
+}
+
+fn cohort_enrollment(cohort_id: u64, user_id: Principal) -> Option<CohortEnrollment> {
+    COHORT_ENROLLMENTS.with(|enrollments| {
+        enrollments.borrow()
+            .iter()
+            .map(|(_, e)| e)
+            .find(|e| e.cohort_id == cohort_id && e.user_id == user_id)
+    })
+}
+
+// Enrolled learners and the cohort's managers can see its modules, discussions and stats
+fn check_cohort_access(cohort: &Cohort, caller: Principal) -> Result<(), String> {
+    if can_manage_cohort(cohort, caller) || cohort_enrollment(cohort.id, caller).is_some() {
+        Ok(())
+    } else {
+        Err("You are not enrolled in this cohort".to_string())
+    }
+}
+
+fn unlocked_cohort_module(cohort: &Cohort, module_order: u32, now: u64) -> Result<&CohortModule, String> {
+    let module = cohort.modules.iter().find(|m| m.order == module_order).ok_or("Module not found")?;
+    if module.unlocks_at > now {
+        return Err("This module has not unlocked yet".to_string());
+    }
+    Ok(module)
+}
+
+// Cohort listing without module content, which is only served once a module unlocks
+fn cohort_summary(mut cohort: Cohort) -> Cohort {
+    for module in cohort.modules.iter_mut() {
+        module.content = None;
+    }
+    cohort
+}
+
+#[ic_cdk::update]
+fn create_cohort(
+    tutor_id: String,
+    title: String,
+    outline: CourseOutline,
+    starts_at: u64,
+    unlock_interval_days: u32,
+    max_learners: u32,
+) -> Result<Cohort, String> {
+    let tutor_id = canonical_public_id("tutor", &tutor_id);
+    let caller = ic_cdk::caller();
+    require(caller, Permission::CreateCohorts)?;
+    if !has_permission(caller, Permission::ModerateContent) {
+        owned_tutor(&tutor_id, caller)?;
+    }
+    if title.trim().is_empty() {
+        return Err("Title is required".to_string());
+    }
+    if outline.modules.is_empty() {
+        return Err("The course outline has no modules".to_string());
+    }
+    if !(1..=30).contains(&unlock_interval_days) {
+        return Err("Modules must unlock every 1 to 30 days".to_string());
+    }
+    if max_learners == 0 {
+        return Err("A cohort needs room for at least one learner".to_string());
+    }
+    
+    let mut modules = outline.modules;
+    modules.sort_by_key(|m| m.order);
+    let interval_ns = unlock_interval_days as u64 * 24 * 60 * 60 * 1_000_000_000;
+    let cohort = Cohort {
+        id: next_id("cohort"),
+        title: title.trim().to_string(),
+        description: outline.description,
+        tutor_id,
+        created_by: caller,
+        modules: modules.into_iter().enumerate().map(|(week, m)| CohortModule {
+            order: m.order,
+            title: m.title,
+            description: m.description,
+            content: m.content,
+            unlocks_at: starts_at + week as u64 * interval_ns,
+        }).collect(),
+        starts_at,
+        unlock_interval_days,
+        max_learners,
+        enrollment_open: true,
+        created_at: ic_cdk::api::time(),
+    };
+    COHORTS.with(|cohorts| cohorts.borrow_mut().insert(cohort.id, cohort.clone()));
+    queue_tagging("cohort", &cohort.id.to_string());
+    Ok(cohort)
+}
+
+#[ic_cdk::update]
+fn set_cohort_enrollment_open(cohort_id: u64, open: bool) -> Result<Cohort, String> {
+    let mut cohort = get_cohort(cohort_id)?;
+    if !can_manage_cohort(&cohort, ic_cdk::caller()) {
+        return Err("Only the cohort's creator or an admin can change enrollment".to_string());
+    }
+    cohort.enrollment_open = open;
+    COHORTS.with(|cohorts| cohorts.borrow_mut().insert(cohort_id, cohort.clone()));
+    Ok(cohort)
+}
+
+#[ic_cdk::query]
+fn get_cohorts() -> Vec<Cohort> {
+    COHORTS.with(|cohorts| cohorts.borrow().iter().map(|(_, c)| cohort_summary(c)).collect())
+}
+
+#[ic_cdk::update]
+fn enroll_in_cohort(cohort_id: u64) -> Result<CohortEnrollment, String> {
+    let caller = ic_cdk::caller();
+    let cohort = get_cohort(cohort_id)?;
+    if !cohort.enrollment_open {
+        return Err("Enrollment for this cohort is closed".to_string());
+    }
+    if cohort_enrollment(cohort_id, caller).is_some() {
+        return Err("You are already enrolled in this cohort".to_string());
+    }
+    add_cohort_enrollment(&cohort, caller)
+}
+
+fn add_cohort_enrollment(cohort: &Cohort, user_id: Principal) -> Result<CohortEnrollment, String> {
+    let enrolled = COHORT_ENROLLMENTS.with(|enrollments| {
+        enrollments.borrow().iter().filter(|(_, e)| e.cohort_id == cohort.id).count()
+    });
+    if enrolled >= cohort.max_learners as usize {
+        return Err("This cohort is full".to_string());
+    }
+    
+    let enrollment = CohortEnrollment {
+        id: next_id("cohort_enrollment"),
+        cohort_id: cohort.id,
+        user_id,
+        completed_modules: Vec::new(),
+        enrolled_at: ic_cdk::api::time(),
+        completed_at: None,
+    };
+    COHORT_ENROLLMENTS.with(|enrollments| enrollments.borrow_mut().insert(enrollment.id, enrollment.clone()));
+    Ok(enrollment)
+}
+
+#[ic_cdk::query]
+fn get_cohort_modules(cohort_id: u64) -> Result<Vec<CohortModuleView>, String> {
+    let caller = ic_cdk::caller();
+    let cohort = get_cohort(cohort_id)?;
+    check_cohort_access(&cohort, caller)?;
+    
+    let now = ic_cdk::api::time();
+    let completed = cohort_enrollment(cohort_id, caller).map(|e| e.completed_modules).unwrap_or_default();
+    Ok(cohort.modules.into_iter().map(|m| {
+        let unlocked = m.unlocks_at <= now;
+        CohortModuleView {
+            order: m.order,
+            title: m.title,
+            description: m.description,
+            content: if unlocked { m.content } else { None },
+            unlocks_at: m.unlocks_at,
+            unlocked,
+            completed: completed.contains(&m.order),
+        }
+    }).collect())
+}
+
+#[ic_cdk::update]
+fn complete_cohort_module(cohort_id: u64, module_order: u32) -> Result<CohortEnrollment, String> {
+    let caller = ic_cdk::caller();
+    let cohort = get_cohort(cohort_id)?;
+    let mut enrollment = cohort_enrollment(cohort_id, caller).ok_or("You are not enrolled in this cohort")?;
+    let now = ic_cdk::api::time();
+    unlocked_cohort_module(&cohort, module_order, now)?;
+    
+    if !enrollment.completed_modules.contains(&module_order) {
+        enrollment.completed_modules.push(module_order);
+    }
+    if enrollment.completed_at.is_none() && cohort.modules.iter().all(|m| enrollment.completed_modules.contains(&m.order)) {
+        enrollment.completed_at = Some(now);
+    }
+    COHORT_ENROLLMENTS.with(|enrollments| enrollments.borrow_mut().insert(enrollment.id, enrollment.clone()));
+    Ok(enrollment)
+}
+
+#[ic_cdk::query]
+fn get_cohort_stats(cohort_id: u64) -> Result<CohortStats, String> {
+    let cohort = get_cohort(cohort_id)?;
+    check_cohort_access(&cohort, ic_cdk::caller())?;
+    
+    let enrollments: Vec<CohortEnrollment> = COHORT_ENROLLMENTS.with(|enrollments| {
+        enrollments.borrow().iter().map(|(_, e)| e).filter(|e| e.cohort_id == cohort_id).collect()
+    });
+    let post_counts: Vec<u32> = DISCUSSION_POSTS.with(|posts| {
+        let posts = posts.borrow();
+        cohort.modules.iter().map(|m| {
+            posts.iter().filter(|(_, p)| p.cohort_id == cohort_id && p.module_order == m.order).count() as u32
+        }).collect()
+    });
+    
+    let now = ic_cdk::api::time();
+    let enrolled = enrollments.len() as u32;
+    let percent = |count: usize, of: usize| if of == 0 { 0.0 } else { count as f64 * 100.0 / of as f64 };
+    let completions: usize = enrollments.iter().map(|e| e.completed_modules.len()).sum();
+    Ok(CohortStats {
+        cohort_id,
+        enrolled,
+        completed_course: enrollments.iter().filter(|e| e.completed_at.is_some()).count() as u32,
+        average_progress: percent(completions, enrollments.len() * cohort.modules.len()),
+        modules: cohort.modules.iter().zip(post_counts).map(|(m, discussion_posts)| {
+            let completed_count = enrollments.iter().filter(|e| e.completed_modules.contains(&m.order)).count();
+            CohortModuleStats {
+                order: m.order,
+                title: m.title.clone(),
+                unlocked: m.unlocks_at <= now,
+                completed_count: completed_count as u32,
+                completion_rate: percent(completed_count, enrollments.len()),
+                discussion_posts,
+            }
+        }).collect(),
+    })
+}
+
+// --- Module Discussions ---
+//
+// Threads are one level deep: an opening post and its replies. The cohort's creator owns the
+// module and can accept a reply as the answer and moderate posts.
+
+const MAX_COHORT_POST_CHARS: usize = 4_000;
+const MAX_DISCUSSION_PAGE_SIZE: u32 = 50;
+// Reports from this many learners hide a post until a moderator reviews it
+const DISCUSSION_REPORT_HIDE_THRESHOLD: usize = 3;
+const DISCUSSION_MODERATION_ACTIONS: [&str; 3] = ["hide", "restore", "delete"];
+
+fn get_discussion_post(post_id: u64) -> Result<(DiscussionPost, Cohort), String> {
+    let post = DISCUSSION_POSTS.with(|posts| posts.borrow().get(&post_id)).ok_or("Post not found")?;
+    let cohort = get_cohort(post.cohort_id)?;
+    Ok((post, cohort))
+}
+
+fn save_discussion_post(post: &DiscussionPost) {
+    DISCUSSION_POSTS.with(|posts| posts.borrow_mut().insert(post.id, post.clone()));
+}
+
+// Moderation hook run on every new post before it is stored
+fn moderate_discussion_content(content: &str) -> String {
+    let config = get_config().response_processing;
+    if config.mask_profanity {
+        mask_profanity(content, &config.profanity_words)
+    } else {
+        content.to_string()
+    }
+}
+
+#[ic_cdk::update]
+fn post_cohort_discussion(cohort_id: u64, module_order: u32, content: String, parent_id: Option<u64>) -> Result<DiscussionPost, String> {
+    let caller = ic_cdk::caller();
+    let cohort = get_cohort(cohort_id)?;
+    check_cohort_access(&cohort, caller)?;
+    unlocked_cohort_module(&cohort, module_order, ic_cdk::api::time())?;
+    if content.trim().is_empty() {
+        return Err("Post content is required".to_string());
+    }
+    if content.chars().count() > MAX_COHORT_POST_CHARS {
+        return Err(format!("Posts are limited to {} characters", MAX_COHORT_POST_CHARS));
+    }
+    let parent = match parent_id {
+        Some(id) => {
+            let (parent, _) = get_discussion_post(id)?;
+            if parent.cohort_id != cohort_id || parent.module_order != module_order {
+                return Err("Replies must be posted in the same module discussion".to_string());
+            }
+            if parent.parent_id.is_some() {
+                return Err("Replies can only be added to a thread's opening post".to_string());
+            }
+            Some(parent)
+        }
+        None => None,
+    };
+    
+    let post = DiscussionPost {
+        id: next_id("discussion_post"),
+        cohort_id,
+        module_order,
+        user_id: caller,
+        content: moderate_discussion_content(content.trim()),
+        created_at: ic_cdk::api::time(),
+        parent_id,
+        voters: Vec::new(),
+        vote_count: 0,
+        is_accepted: false,
+        status: "visible".to_string(),
+        reported_by: Vec::new(),
+    };
+    save_discussion_post(&post);
+    
+    if let Some(parent) = parent.filter(|p| p.user_id != caller) {
+        notify_user(
+            parent.user_id,
+            "discussion_reply",
+            "cohort",
+            format!("New reply in the {} discussion", cohort.title),
+            Some(post.id),
+        );
+    }
+    Ok(post)
+}
+
+// Hidden posts are only shown to their author and the cohort's moderators
+#[ic_cdk::query]
+fn get_cohort_discussion(cohort_id: u64, module_order: u32, offset: u32, limit: u32, sort_by: String) -> Result<DiscussionPage, String> {
+    let caller = ic_cdk::caller();
+    let cohort = get_cohort(cohort_id)?;
+    check_cohort_access(&cohort, caller)?;
+    let moderator = can_manage_cohort(&cohort, caller);
+    
+    let posts: Vec<DiscussionPost> = DISCUSSION_POSTS.with(|posts| {
+        posts.borrow()
+            .iter()
+            .map(|(_, p)| p)
+            .filter(|p| p.cohort_id == cohort_id && p.module_order == module_order)
+            .filter(|p| p.status == "visible" || moderator || p.user_id == caller)
+            .collect()
+    });
+    let (mut openers, replies): (Vec<DiscussionPost>, Vec<DiscussionPost>) = posts.into_iter().partition(|p| p.parent_id.is_none());
+    if sort_by == "top" {
+        openers.sort_by_key(|p| std::cmp::Reverse((p.vote_count, p.created_at)));
+    } else {
+        openers.sort_by_key(|p| std::cmp::Reverse(p.created_at));
+    }
+    
+    let total_threads = openers.len() as u32;
+    let limit = limit.clamp(1, MAX_DISCUSSION_PAGE_SIZE);
+    let threads: Vec<DiscussionThread> = openers
+        .into_iter()
+        .skip(offset as usize)
+        .take(limit as usize)
+        .map(|post| {
+            let mut thread_replies: Vec<DiscussionPost> = replies.iter().filter(|r| r.parent_id == Some(post.id)).cloned().collect();
+            thread_replies.sort_by_key(|r| (std::cmp::Reverse((r.is_accepted, r.vote_count)), r.created_at));
+            DiscussionThread { post, replies: thread_replies }
+        })
+        .collect();
+    
+    Ok(DiscussionPage {
+        has_more: offset.saturating_add(limit) < total_threads,
+        threads,
+        total_threads,
+    })
+}
+
+fn set_discussion_vote(post_id: u64, voted: bool) -> Result<DiscussionPost, String> {
+    let caller = ic_cdk::caller();
+    let (mut post, cohort) = get_discussion_post(post_id)?;
+    check_cohort_access(&cohort, caller)?;
+    if post.user_id == caller {
+        return Err("You can't vote on your own post".to_string());
+    }
+    
+    let has_voted = post.voters.contains(&caller);
+    if voted && !has_voted {
+        post.voters.push(caller);
+    } else if !voted && has_voted {
+        post.voters.retain(|v| *v != caller);
+    }
+    post.vote_count = post.voters.len() as u64;
+    save_discussion_post(&post);
+    Ok(post)
+}
+
+#[ic_cdk::update]
+fn upvote_discussion_post(post_id: u64) -> Result<DiscussionPost, String> {
+    set_discussion_vote(post_id, true)
+}
+
+#[ic_cdk::update]
+fn remove_discussion_vote(post_id: u64) -> Result<DiscussionPost, String> {
+    set_discussion_vote(post_id, false)
+}
+
+// Accepting a reply replaces any previously accepted answer in the same thread
+#[ic_cdk::update]
+fn accept_discussion_answer(post_id: u64) -> Result<DiscussionPost, String> {
+    let caller = ic_cdk::caller();
+    let (mut post, cohort) = get_discussion_post(post_id)?;
+    if !can_manage_cohort(&cohort, caller) {
+        return Err("Only the module owner can accept an answer".to_string());
+    }
+    let thread_id = post.parent_id.ok_or("Only replies can be accepted as an answer")?;
+    
+    let previous: Vec<DiscussionPost> = DISCUSSION_POSTS.with(|posts| {
+        posts.borrow()
+            .iter()
+            .map(|(_, p)| p)
+            .filter(|p| p.parent_id == Some(thread_id) && p.is_accepted && p.id != post_id)
+            .collect()
+    });
+    for mut other in previous {
+        other.is_accepted = false;
+        save_discussion_post(&other);
+    }
+    
+    post.is_accepted = true;
+    save_discussion_post(&post);
+    notify_user(
+        post.user_id,
+        "discussion_answer_accepted",
+        "cohort",
+        format!("Your reply in the {} discussion was accepted as the answer", cohort.title),
+        Some(post.id),
+    );
+    Ok(post)
+}
+
+#[ic_cdk::update]
+fn report_discussion_post(post_id: u64) -> Result<DiscussionPost, String> {
+    let caller = ic_cdk::caller();
+    let (mut post, cohort) = get_discussion_post(post_id)?;
+    check_cohort_access(&cohort, caller)?;
+    if post.reported_by.contains(&caller) {
+        return Err("You have already reported this post".to_string());
+    }
+    
+    post.reported_by.push(caller);
+    if post.status == "visible" && post.reported_by.len() >= DISCUSSION_REPORT_HIDE_THRESHOLD {
+        post.status = "hidden".to_string();
+        notify_user(
+            cohort.created_by,
+            "discussion_report",
+            "cohort",
+            format!("A post in the {} discussion was hidden after {} reports", cohort.title, post.reported_by.len()),
+            Some(post.id),
+        );
+    }
+    save_discussion_post(&post);
+    Ok(post)
+}
+
+#[ic_cdk::update]
+fn moderate_discussion_post(post_id: u64, action: String) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    let (mut post, cohort) = get_discussion_post(post_id)?;
+    if !can_manage_cohort(&cohort, caller) {
+        return Err("Only the cohort's creator or an admin can moderate its discussions".to_string());
+    }
+    if !DISCUSSION_MODERATION_ACTIONS.contains(&action.as_str()) {
+        return Err(format!("Action must be one of: {}", DISCUSSION_MODERATION_ACTIONS.join(", ")));
+    }
+    
+    match action.as_str() {
+        "delete" => {
+            // Replies go with the thread they belong to
+            let ids: Vec<u64> = DISCUSSION_POSTS.with(|posts| {
+                posts.borrow()
+                    .iter()
+                    .filter(|(id, p)| *id == post_id || p.parent_id == Some(post_id))
+                    .map(|(id, _)| id)
+                    .collect()
+            });
+            DISCUSSION_POSTS.with(|posts| {
+                let mut posts = posts.borrow_mut();
+                for id in ids {
+                    posts.remove(&id);
+                }
+            });
+        }
+        "hide" => {
+            post.status = "hidden".to_string();
+            save_discussion_post(&post);
+        }
+        _ => {
+            post.status = "visible".to_string();
+            post.reported_by.clear();
+            save_discussion_post(&post);
+        }
+    }
+    record_audit(caller, &format!("discussion_{}", action), Some(post.user_id), format!("post {} in cohort {}", post_id, cohort.id));
+    Ok(())
+}
+
+// --- Auto Tagging ---
+//
+// Creating or updating a tutor, cohort or study group queues it, and the heartbeat tags a
+// small batch at a time. AI tags below the confidence threshold wait for an admin.
+
+const TAGGING_JOB_INTERVAL_NS: u64 = 60 * 1_000_000_000;
+const TAGGING_BATCH_SIZE: usize = 5;
+// Entities being tagged are leased for this long; a failed attempt is retried once it passes
+const TAGGING_RETRY_DELAY_NS: u64 = 10 * 60 * 1_000_000_000;
+const MAX_TAGS_PER_ENTITY: usize = 5;
+const TAG_CACHE_CAPACITY: usize = 200;
+const TAGGABLE_ENTITIES: [&str; 3] = ["tutor", "cohort", "study_group"];
+
+thread_local! {
+    // Content hash -> AI suggestions, reused by entities whose text has not changed
+    static TAG_CACHE: RefCell<HashMap<String, Vec<(String, f64)>>> = RefCell::new(HashMap::new());
+}
+
+fn entity_key(entity_type: &str, entity_id: &str) -> String {
+    format!("{}:{}", entity_type, entity_id)
+}
+
+fn queue_tagging(entity_type: &str, entity_id: &str) {
+    if get_config().tagging.enabled {
+        TAGGING_QUEUE.with(|queue| queue.borrow_mut().insert(entity_key(entity_type, entity_id), ic_cdk::api::time()));
+    }
+}
+
+fn remove_entity_tags(entity_type: &str, entity_id: &str) {
+    let key = entity_key(entity_type, entity_id);
+    ENTITY_TAGS.with(|tags| tags.borrow_mut().remove(&key));
+    TAGGING_QUEUE.with(|queue| queue.borrow_mut().remove(&key));
+}
+
+// Text the tags are derived from, or None once the entity no longer exists
+fn taggable_text(entity_type: &str, entity_id: &str) -> Option<String> {
+    match entity_type {
+        "tutor" => TUTORS.with(|tutors| {
+            tutors.borrow().iter().find(|(_, t)| t.public_id == entity_id).map(|(_, t)| {
+                format!("{}\n{}\nExpertise: {}", t.name, t.description, t.expertise.join(", "))
+            })
+        }),
+        "cohort" => entity_id.parse::<u64>().ok()
+            .and_then(|id| COHORTS.with(|cohorts| cohorts.borrow().get(&id)))
+            .map(|c| {
+                let modules: Vec<String> = c.modules.iter().map(|m| m.title.clone()).collect();
+                format!("{}\n{}\nModules: {}", c.title, c.description, modules.join(", "))
+            }),
+        "study_group" => entity_id.parse::<u64>().ok()
+            .and_then(|id| STUDY_GROUPS.with(|groups| groups.borrow().get(&id)))
+            .map(|g| {
+                format!(
+                    "{}\n{}\nGoals: {}\nLevel: {}",
+                    g.name,
+                    g.description.unwrap_or_default(),
+                    g.goals.unwrap_or_default(),
+                    g.learning_level
+                )
+            }),
+        _ => None,
+    }
+}
+
+fn content_hash(text: &str) -> String {
+    use sha2::{Digest, Sha256};
+    hex_encode(&Sha256::digest(text.as_bytes()))
+}
+
+#[derive(serde::Deserialize)]
+struct AiTag {
+    tag: String,
+    confidence: f64,
+}
+
+async fn suggest_tags(text: &str, config: &TaggingConfig) -> Result<Vec<(String, f64)>, String> {
+    let prompt = format!(
+        "Tag this learning content using ONLY tags from this list: {}.
+        
+        Return ONLY a JSON array of at most {} tags with your confidence from 0 to 1:
+        [{{\"tag\":\"tag\",\"confidence\":0.9}}]
+        
+        Content:
+        {}",
+        config.taxonomy.join(", "),
+        MAX_TAGS_PER_ENTITY,
+        text
+    );
+    let response = process_ai_response(call_groq_ai(&prompt, "tagging", ic_cdk::id()).await?, &response_processing_for(ic_cdk::id(), "json"));
+    let parsed: Vec<AiTag> = serde_json::from_str(&response).map_err(|e| format!("Failed to parse tags: {}", e))?;
+    
+    let mut tags: Vec<(String, f64)> = Vec::new();
+    for ai_tag in parsed {
+        let known = config.taxonomy.iter().find(|t| t.eq_ignore_ascii_case(ai_tag.tag.trim()));
+        if let Some(tag) = known.filter(|t| !tags.iter().any(|(existing, _)| existing == *t)) {
+            tags.push((tag.clone(), ai_tag.confidence.clamp(0.0, 1.0)));
+        }
+    }
+    tags.truncate(MAX_TAGS_PER_ENTITY);
+    Ok(tags)
+}
+
+async fn tag_entity(key: String, lease: u64) {
+    let Some((entity_type, entity_id)) = key.split_once(':') else {
+        TAGGING_QUEUE.with(|queue| queue.borrow_mut().remove(&key));
+        return;
+    };
+    let Some(text) = taggable_text(entity_type, entity_id) else {
+        remove_entity_tags(entity_type, entity_id);
+        return;
+    };
+    let hash = content_hash(&text);
+    let existing = ENTITY_TAGS.with(|tags| tags.borrow().get(&key));
+    
+    if existing.as_ref().is_none_or(|e| e.content_hash != hash) {
+        let config = get_config().tagging;
+        let cached = TAG_CACHE.with(|cache| cache.borrow().get(&hash).cloned());
+        let suggested = match cached {
+            Some(suggested) => suggested,
+            None => match suggest_tags(&text, &config).await {
+                Ok(suggested) => {
+                    TAG_CACHE.with(|cache| {
+                        let mut cache = cache.borrow_mut();
+                        if cache.len() >= TAG_CACHE_CAPACITY {
+                            cache.clear();
+                        }
+                        cache.insert(hash.clone(), suggested.clone());
+                    });
+                    suggested
+                }
+                Err(e) => {
+                    // Stays queued under its lease and is retried once the lease expires
+                    ic_cdk::println!("Tagging {} failed: {}", key, e);
+                    return;
+                }
+            },
+        };
+        
+        // Admin decisions survive re-tagging as long as the tag is still in the taxonomy
+        let mut tags: Vec<EntityTag> = existing
+            .map(|e| e.tags)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|t| t.reviewed && config.taxonomy.contains(&t.tag))
+            .collect();
+        for (tag, confidence) in suggested {
+            if !tags.iter().any(|t| t.tag == tag) {
+                let status = if confidence >= config.min_confidence { "applied" } else { "pending_review" };
+                tags.push(EntityTag { tag, confidence, status: status.to_string(), reviewed: false });
+            }
+        }
+        ENTITY_TAGS.with(|all| all.borrow_mut().insert(key.clone(), EntityTags {
+            entity_type: entity_type.to_string(),
+            entity_id: entity_id.to_string(),
+            tags,
+            content_hash: hash,
+            tagged_at: ic_cdk::api::time(),
+        }));
+    }
+    
+    // An update while the AI call was in flight re-queued the entity with a new due time
+    TAGGING_QUEUE.with(|queue| {
+        let mut queue = queue.borrow_mut();
+        if queue.get(&key) == Some(lease) {
+            queue.remove(&key);
+        }
+    });
+}
+
+fn run_tagging_batch(now: u64) {
+    let mut due: Vec<(String, u64)> = TAGGING_QUEUE.with(|queue| {
+        queue.borrow().iter().filter(|(_, due_at)| *due_at <= now).collect()
+    });
+    due.sort_by_key(|(_, due_at)| *due_at);
+    
+    for (key, _) in due.into_iter().take(TAGGING_BATCH_SIZE) {
+        let lease = now + TAGGING_RETRY_DELAY_NS;
+        TAGGING_QUEUE.with(|queue| queue.borrow_mut().insert(key.clone(), lease));
+        ic_cdk::spawn(tag_entity(key, lease));
+    }
+}
+
+fn applied_tags(tags: EntityTags) -> EntityTags {
+    EntityTags {
+        tags: tags.tags.into_iter().filter(|t| t.status == "applied").collect(),
+        ..tags
+    }
+}
+
+#[ic_cdk::query]
+fn get_entity_tags(entity_type: String, entity_id: String) -> Vec<String> {
+    ENTITY_TAGS.with(|tags| tags.borrow().get(&entity_key(&entity_type, &entity_id)))
+        .map(|t| applied_tags(t).tags.into_iter().map(|t| t.tag).collect())
+        .unwrap_or_default()
+}
+
+// Tutors are only returned to those who can view them and private groups only to their members
+#[ic_cdk::query]
+fn search_by_tag(tag: String, entity_type: Option<String>) -> Vec<EntityTags> {
+    let caller = ic_cdk::caller();
+    let visible = |t: &EntityTags| match t.entity_type.as_str() {
+        "tutor" => TUTORS.with(|tutors| {
+            tutors.borrow().iter().any(|(_, tutor)| tutor.public_id == t.entity_id && can_view(&tutor, caller))
+        }),
+        "study_group" => t.entity_id.parse::<u64>().ok()
+            .and_then(|id| STUDY_GROUPS.with(|groups| groups.borrow().get(&id)))
+            .is_some_and(|g| can_view_group(&g, caller)),
+        _ => true,
+    };
+    
+    ENTITY_TAGS.with(|tags| {
+        tags.borrow()
+            .iter()
+            .map(|(_, t)| applied_tags(t))
+            .filter(|t| entity_type.as_ref().is_none_or(|kind| &t.entity_type == kind))
+            .filter(|t| t.tags.iter().any(|entity_tag| entity_tag.tag.eq_ignore_ascii_case(tag.trim())))
+            .filter(visible)
+            .collect()
+    })
+}
+
+#[ic_cdk::query]
+fn get_tag_review_queue_admin() -> Result<Vec<EntityTags>, String> {
+    require(ic_cdk::caller(), Permission::ModerateContent)?;
+    Ok(ENTITY_TAGS.with(|tags| {
+        tags.borrow()
+            .iter()
+            .map(|(_, t)| t)
+            .filter(|t| t.tags.iter().any(|entity_tag| entity_tag.status == "pending_review"))
+            .collect()
+    }))
+}
+
+#[ic_cdk::update]
+fn review_tag_admin(entity_type: String, entity_id: String, tag: String, approve: bool) -> Result<EntityTags, String> {
+    let caller = ic_cdk::caller();
+    require(caller, Permission::ModerateContent)?;
+    let key = entity_key(&entity_type, &entity_id);
+    let mut tags = ENTITY_TAGS.with(|tags| tags.borrow().get(&key)).ok_or("No tags for this entity")?;
+    let entity_tag = tags.tags.iter_mut().find(|t| t.tag == tag).ok_or("Tag not found on this entity")?;
+    
+    entity_tag.status = if approve { "applied" } else { "rejected" }.to_string();
+    entity_tag.reviewed = true;
+    ENTITY_TAGS.with(|all| all.borrow_mut().insert(key.clone(), tags.clone()));
+    record_audit(caller, "tag_review", None, format!("{} {} on {}", if approve { "approved" } else { "rejected" }, tag, key));
+    Ok(tags)
+}
+
+// Re-tags an entity even if its text is unchanged, e.g. after the taxonomy changes
+#[ic_cdk::update]
+fn retag_entity_admin(entity_type: String, entity_id: String) -> Result<(), String> {
+    require(ic_cdk::caller(), Permission::ModerateContent)?;
+    if !TAGGABLE_ENTITIES.contains(&entity_type.as_str()) {
+        return Err(format!("Entity type must be one of: {}", TAGGABLE_ENTITIES.join(", ")));
+    }
+    if taggable_text(&entity_type, &entity_id).is_none() {
+        return Err("Entity not found".to_string());
+    }
+    
+    let key = entity_key(&entity_type, &entity_id);
+    ENTITY_TAGS.with(|tags| {
+        let mut tags = tags.borrow_mut();
+        if let Some(mut existing) = tags.get(&key) {
+            existing.content_hash.clear();
+            tags.insert(key.clone(), existing);
+        }
+    });
+    TAGGING_QUEUE.with(|queue| queue.borrow_mut().insert(key, ic_cdk::api::time()));
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn set_tagging_config_admin(tagging: TaggingConfig) -> Result<CanisterConfig, String> {
+    require(ic_cdk::caller(), Permission::ManageSystem)?;
+    if !(0.0..=1.0).contains(&tagging.min_confidence) {
+        return Err("Minimum confidence must be between 0 and 1".to_string());
+    }
+    let mut taxonomy: Vec<String> = Vec::new();
+    for tag in tagging.taxonomy.iter().map(|t| t.trim().to_lowercase()).filter(|t| !t.is_empty()) {
+        if !taxonomy.contains(&tag) {
+            taxonomy.push(tag);
+        }
+    }
+    if taxonomy.is_empty() {
+        return Err("The taxonomy needs at least one tag".to_string());
+    }
+    
+    update_config(|config| {
+        config.tagging = TaggingConfig { taxonomy, ..tagging };
+        Ok(())
+    })
+}
+
+// --- Public IDs and Slugs ---
+
+const SLUG_MIN_CHARS: usize = 3;
+const SLUG_MAX_CHARS: usize = 60;
+const MAX_ID_MIGRATION_BATCH: u32 = 100;
+
+// Random public ids, so entities can't be enumerated by counting up
+async fn random_public_id(prefix: &str) -> Result<String, String> {
+    Ok(format!("{}_{}", prefix, hex_encode(&random_bytes().await?[..12])))
+}
+
+fn is_random_public_id(id: &str) -> bool {
+    id.rsplit_once('_').is_some_and(|(_, hex)| hex.len() == 24 && hex.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+fn alias_key(entity_type: &str, alias: &str) -> String {
+    format!("{}:{}", entity_type, alias)
+}
+
+// Maps a slug or legacy id to the entity's current public id; anything else is returned as is
+fn canonical_public_id(entity_type: &str, id: &str) -> String {
+    ID_ALIASES.with(|aliases| aliases.borrow().get(&alias_key(entity_type, id)))
+        .map(|alias| alias.public_id)
+        .unwrap_or_else(|| id.to_string())
+}
+
+fn add_alias(entity_type: &str, alias: &str, public_id: &str, kind: &str) {
+    ID_ALIASES.with(|aliases| aliases.borrow_mut().insert(alias_key(entity_type, alias), IdAlias {
+        entity_type: entity_type.to_string(),
+        alias: alias.to_string(),
+        public_id: public_id.to_string(),
+        kind: kind.to_string(),
+        created_at: ic_cdk::api::time(),
+        retired_at: None,
+    }));
+}
+
+// Slugs can't contain underscores or be all digits, so they never look like a public id
+fn normalize_slug(slug: &str) -> Result<String, String> {
+    let slug = slug.trim().to_lowercase();
+    let valid = (SLUG_MIN_CHARS..=SLUG_MAX_CHARS).contains(&slug.len())
+        && slug.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        && slug.chars().any(|c| c.is_ascii_lowercase())
+        && !slug.starts_with('-')
+        && !slug.ends_with('-')
+        && !slug.contains("--");
+    if valid {
+        Ok(slug)
+    } else {
+        Err(format!(
+            "Slugs must be {}-{} lowercase letters, digits and single hyphens, with at least one letter",
+            SLUG_MIN_CHARS, SLUG_MAX_CHARS
+        ))
+    }
+}
+
+// Retires the current slug and reserves the new one; returns the slug to store on the entity
+fn assign_slug(entity_type: &str, public_id: &str, current: Option<&str>, requested: Option<String>) -> Result<Option<String>, String> {
+    let requested = requested
+        .filter(|s| !s.trim().is_empty())
+        .map(|s| normalize_slug(&s))
+        .transpose()?;
+    if requested.as_deref() == current {
+        return Ok(requested);
+    }
+    if let Some(slug) = &requested {
+        let owner = ID_ALIASES.with(|aliases| aliases.borrow().get(&alias_key(entity_type, slug)));
+        if owner.is_some_and(|a| a.public_id != public_id) {
+            return Err("This slug is already taken".to_string());
+        }
+    }
+    
+    if let Some(old) = current {
+        ID_ALIASES.with(|aliases| {
+            let mut aliases = aliases.borrow_mut();
+            if let Some(mut alias) = aliases.get(&alias_key(entity_type, old)) {
+                alias.retired_at = Some(ic_cdk::api::time());
+                aliases.insert(alias_key(entity_type, old), alias);
+            }
+        });
+    }
+    if let Some(slug) = &requested {
+        add_alias(entity_type, slug, public_id, "slug");
+    }
+    Ok(requested)
+}
+
+#[ic_cdk::query]
+fn resolve_public_id(entity_type: String, id_or_slug: String) -> String {
+    canonical_public_id(&entity_type, &id_or_slug)
+}
+
+#[ic_cdk::query]
+fn get_slug_history(entity_type: String, public_id: String) -> Vec<IdAlias> {
+    let public_id = canonical_public_id(&entity_type, &public_id);
+    let mut history: Vec<IdAlias> = ID_ALIASES.with(|aliases| {
+        aliases.borrow()
+            .iter()
+            .map(|(_, a)| a)
+            .filter(|a| a.entity_type == entity_type && a.public_id == public_id && a.kind == "slug")
+            .collect()
+    });
+    history.sort_by_key(|a| a.created_at);
+    history
+}
+
+#[ic_cdk::update]
+fn set_tutor_slug(public_id: String, slug: Option<String>) -> Result<Tutor, String> {
+    let (id, mut tutor) = owned_tutor(&public_id, ic_cdk::caller())?;
+    tutor.slug = assign_slug("tutor", &tutor.public_id, tutor.slug.as_deref(), slug)?;
+    tutor.updated_at = ic_cdk::api::time();
+    cache::store_tutor(id, tutor.clone());
+    Ok(tutor)
+}
+
+#[ic_cdk::update]
+fn set_study_group_slug(group_id: u64, slug: Option<String>) -> Result<StudyGroup, String> {
+    let caller = ic_cdk::caller();
+    let mut group = visible_group(group_id, caller)?;
+    if !can_manage_group(&group, caller) {
+        return Err("Only the group's admins can change its slug".to_string());
+    }
+    group.slug = assign_slug("study_group", &group.public_id, group.slug.as_deref(), slug)?;
+    group.updated_at = ic_cdk::api::time();
+    STUDY_GROUPS.with(|groups| groups.borrow_mut().insert(group_id, group.clone()));
+    Ok(group)
+}
+
+#[ic_cdk::update]
+fn set_task_slug(task_id: u64, slug: Option<String>) -> Result<Task, String> {
+    let caller = ic_cdk::caller();
+    let mut task = TASKS.with(|tasks| tasks.borrow().get(&task_id)).ok_or("Task not found.")?;
+    if task.created_by != caller && !has_permission(caller, Permission::ModerateContent) {
+        return Err("Only the task's creator can change its slug".to_string());
+    }
+    task.slug = assign_slug("task", &task.public_id, task.slug.as_deref(), slug)?;
+    TASKS.with(|tasks| tasks.borrow_mut().insert(task_id, task.clone()));
+    Ok(task)
+}
+
+#[ic_cdk::query]
+fn get_study_group_by_public_id(public_id: String) -> Option<StudyGroup> {
+    let public_id = canonical_public_id("study_group", &public_id);
+    let caller = ic_cdk::caller();
+    STUDY_GROUPS.with(|groups| groups.borrow().iter().map(|(_, g)| g).find(|g| g.public_id == public_id))
+        .filter(|g| can_view_group(g, caller))
+}
+
+#[ic_cdk::query]
+fn get_task_by_public_id(public_id: String) -> Option<Task> {
+    let public_id = canonical_public_id("task", &public_id);
+    TASKS.with(|tasks| tasks.borrow().iter().map(|(_, t)| t).find(|t| t.public_id == public_id))
+}
+
+// Points everything that stores a tutor's public id at its new one
+fn replace_tutor_references(old: &str, new: &str) {
+    CHAT_SESSIONS.with(|sessions| {
+        let mut sessions = sessions.borrow_mut();
+        let affected: Vec<ChatSession> = sessions.iter().map(|(_, s)| s).filter(|s| s.tutor_id == old).collect();
+        for mut session in affected {
+            session.tutor_id = new.to_string();
+            sessions.insert(session.id.clone(), session);
+        }
+    });
+    PLACEMENT_TESTS.with(|tests| {
+        let mut tests = tests.borrow_mut();
+        let affected: Vec<PlacementTest> = tests.iter().map(|(_, t)| t).filter(|t| t.tutor_id == old).collect();
+        for mut test in affected {
+            test.tutor_id = new.to_string();
+            tests.insert(test.id, test);
+        }
+    });
+    EXAMS.with(|exams| {
+        let mut exams = exams.borrow_mut();
+        let affected: Vec<Exam> = exams.iter().map(|(_, e)| e).filter(|e| e.tutor_id == old).collect();
+        for mut exam in affected {
+            exam.tutor_id = new.to_string();
+            exams.insert(exam.id, exam);
+        }
+    });
+    COHORTS.with(|cohorts| {
+        let mut cohorts = cohorts.borrow_mut();
+        let affected: Vec<Cohort> = cohorts.iter().map(|(_, c)| c).filter(|c| c.tutor_id == old).collect();
+        for mut cohort in affected {
+            cohort.tutor_id = new.to_string();
+            cohorts.insert(cohort.id, cohort);
+        }
+    });
+    
+    let (old_key, new_key) = (entity_key("tutor", old), entity_key("tutor", new));
+    ENTITY_TAGS.with(|tags| {
+        let mut tags = tags.borrow_mut();
+        if let Some(mut entity_tags) = tags.remove(&old_key) {
+            entity_tags.entity_id = new.to_string();
+            tags.insert(new_key.clone(), entity_tags);
+        }
+    });
+    TAGGING_QUEUE.with(|queue| {
+        let mut queue = queue.borrow_mut();
+        if let Some(due_at) = queue.remove(&old_key) {
+            queue.insert(new_key, due_at);
+        }
+    });
+}
+
+// Gives entities created before random ids a new public id; the old one keeps resolving
+#[ic_cdk::update]
+async fn migrate_public_ids_admin(limit: u32) -> Result<u64, String> {
+    let caller = ic_cdk::caller();
+    require(caller, Permission::ManageSystem)?;
+    
+    let limit = limit.clamp(1, MAX_ID_MIGRATION_BATCH) as usize;
+    let mut pending: Vec<(&str, u64, String)> = TUTORS.with(|tutors| {
+        tutors.borrow().iter().filter(|(_, t)| !is_random_public_id(&t.public_id)).map(|(id, t)| ("tutor", id, t.public_id)).collect()
+    });
+    pending.extend(STUDY_GROUPS.with(|groups| {
+        groups.borrow().iter().filter(|(_, g)| !is_random_public_id(&g.public_id)).map(|(id, g)| ("study_group", id, g.public_id)).collect::<Vec<_>>()
+    }));
+    pending.extend(TASKS.with(|tasks| {
+        tasks.borrow().iter().filter(|(_, t)| !is_random_public_id(&t.public_id)).map(|(id, t)| ("task", id, t.public_id)).collect::<Vec<_>>()
+    }));
+    pending.truncate(limit);
+    
+    let mut migrated = 0;
+    for (entity_type, id, old) in pending {
+        let prefix = match entity_type {
+            "tutor" => "tutor",
+            "study_group" => "group",
+            _ => "task",
+        };
+        let new = random_public_id(prefix).await?;
+        
+        // Skipped if the entity was deleted or already migrated while waiting for randomness
+        let updated = match entity_type {
+            "tutor" => TUTORS.with(|tutors| tutors.borrow().get(&id))
+                .filter(|t| t.public_id == old)
+                .map(|mut t| {
+                    t.public_id = new.clone();
+                    cache::store_tutor(id, t);
+                }),
+            "study_group" => STUDY_GROUPS.with(|groups| {
+                let mut groups = groups.borrow_mut();
+                groups.get(&id).filter(|g| g.public_id == old).map(|mut g| {
+                    g.public_id = new.clone();
+                    groups.insert(id, g);
+                })
+            }),
+            _ => TASKS.with(|tasks| {
+                let mut tasks = tasks.borrow_mut();
+                tasks.get(&id).filter(|t| t.public_id == old).map(|mut t| {
+                    t.public_id = new.clone();
+                    tasks.insert(id, t);
+                })
+            }),
+        };
+        if updated.is_none() {
+            continue;
+        }
+        
+        if entity_type == "tutor" {
+            replace_tutor_references(&old, &new);
+        }
+        ID_ALIASES.with(|aliases| {
+            let mut aliases = aliases.borrow_mut();
+            let slugs: Vec<(String, IdAlias)> = aliases.iter()
+                .filter(|(_, a)| a.entity_type == entity_type && a.public_id == old)
+                .collect();
+            for (key, mut alias) in slugs {
+                alias.public_id = new.clone();
+                aliases.insert(key, alias);
+            }
+        });
+        add_alias(entity_type, &old, &new, "legacy_id");
+        migrated += 1;
+    }
+    
+    record_audit(caller, "public_id_migration", None, format!("migrated {} public ids", migrated));
+    Ok(migrated)
+}
+
+// --- Visibility ---
+
+#[ic_cdk::update]
+fn set_tutor_visibility(public_id: String, visibility: Visibility) -> Result<Tutor, String> {
+    let caller = ic_cdk::caller();
+    let (id, mut tutor) = owned_tutor(&public_id, caller)?;
+    let publishing = visibility == Visibility::Public && tutor.visibility != Visibility::Public;
+    if publishing {
+        ensure_public_tutor_allowed(caller)?;
+    }
+    tutor.visibility = visibility;
+    tutor.updated_at = ic_cdk::api::time();
+    cache::store_tutor(id, tutor.clone());
+    if publishing {
+        publish_activity(caller, "tutor_published", format!("published a tutor: {}", tutor.name), Some(tutor.public_id.clone()), None, None);
+    }
+    Ok(tutor)
+}
+
+#[ic_cdk::update]
+fn set_session_visibility(session_id: String, visibility: Visibility) -> Result<ChatSession, String> {
+    let caller = ic_cdk::caller();
+    let mut session = owned_session(&session_id, caller)?;
+    session.visibility = visibility;
+    session.updated_at = ic_cdk::api::time();
+    CHAT_SESSIONS.with(|sessions| sessions.borrow_mut().insert(session_id, session.clone()));
+    Ok(session)
+}
+
+// Another user's tutors and sessions that the caller is allowed to see
+#[ic_cdk::query]
+fn get_visible_tutors(owner: Principal) -> Vec<Tutor> {
+    let caller = ic_cdk::caller();
+    TUTORS.with(|tutors| {
+        tutors.borrow()
+            .iter()
+            .map(|(_, t)| t)
+            .filter(|t| t.user_id == owner && can_view(t, caller))
+            .collect()
+    })
+}
+
+#[ic_cdk::query]
+fn get_visible_sessions(owner: Principal) -> Vec<ChatSession> {
+    let caller = ic_cdk::caller();
+    let mut sessions: Vec<ChatSession> = CHAT_SESSIONS.with(|sessions| {
+        sessions.borrow()
+            .iter()
+            .map(|(_, s)| s)
+            .filter(|s| s.user_id == owner && can_view(s, caller))
+            .collect()
+    });
+    sessions.sort_by_key(|s| std::cmp::Reverse(s.updated_at));
+    sessions
+}
+
+// --- Time Zones and Daily Goals ---
+
+#[ic_cdk::update]
+fn set_timezone(timezone: String) -> Result<User, String> {
+    let caller = ic_cdk::caller();
+    parse_utc_offset(&timezone)?;
+    
+    let mut user = cache::user(caller).ok_or("User not found")?;
+    user.settings.timezone = timezone.trim().to_string();
+    user.updated_at = ic_cdk::api::time();
+    cache::store_user(user.clone());
+    Ok(user)
+}
+
+// Minutes studied per local day, from raw metrics and the aggregates of pruned ones
+fn minutes_by_local_day(user_id: Principal, offset: i64) -> HashMap<u64, u64> {
+    let mut minutes: HashMap<u64, u64> = HashMap::new();
+    METRICS_AGGREGATES.with(|aggregates| {
+        for (_, aggregate) in aggregates.borrow().iter().filter(|(_, a)| a.user_id == user_id) {
+            *minutes.entry(aggregate.day).or_default() += aggregate.time_spent_minutes;
+        }
+    });
+    LEARNING_METRICS.with(|metrics| {
+        for (_, m) in metrics.borrow().iter().filter(|(_, m)| m.user_id == user_id) {
+            *minutes.entry(local_day(m.created_at, offset)).or_default() += m.time_spent_minutes as u64;
+        }
+    });
+    minutes
+}
+
+// Length of the current run of days meeting the goal, and the day it started
+fn goal_streak(met: impl Fn(u64) -> bool, today: u64) -> (u32, u64) {
+    // An unfinished today doesn't break the streak until local midnight
+    let mut day = if met(today) { today } else { today.saturating_sub(1) };
+    let mut streak_days = 0;
+    while day > 0 && met(day) {
+        streak_days += 1;
+        day -= 1;
+    }
+    (streak_days, day + 1)
+}
+
+#[ic_cdk::query]
+fn get_daily_goal_progress() -> Result<DailyGoalProgress, String> {
+    let caller = ic_cdk::caller();
+    let user = get_self().ok_or("User not found")?;
+    let offset = user_offset_ns(caller);
+    let now = ic_cdk::api::time();
+    let today = local_day(now, offset);
+    
+    let goal_minutes = user.settings.daily_goal_hours as u64 * 60;
+    let minutes = minutes_by_local_day(caller, offset);
+    let spent = |day: u64| minutes.get(&day).copied().unwrap_or(0);
+    let met = |day: u64| goal_minutes > 0 && spent(day) >= goal_minutes;
+    
+    let (streak_days, _) = goal_streak(met, today);
+    
+    Ok(DailyGoalProgress {
+        timezone: user.settings.timezone,
+        day_start: local_day_start(today, offset),
+        resets_at: next_local_midnight(now, offset),
+        goal_minutes,
+        minutes_spent: spent(today),
+        goal_met: met(today),
+        streak_days,
+    })
+}
+
+// --- Curriculum Import ---
+//
+// Teachers can bring an existing curriculum into a tutor as a course. Common Cartridge
+// packages are zip files; the client unzips them and sends imsmanifest.xml, whose
+// organization tree becomes the module list.
+
+const CURRICULUM_FORMATS: [&str; 3] = ["common_cartridge", "csv", "markdown"];
+const MAX_CURRICULUM_BYTES: usize = 512 * 1024;
+const MAX_IMPORTED_MODULES: usize = 100;
+const MAX_MODULE_TITLE_CHARS: usize = 200;
+
+struct ParsedModule {
+    line: u32,
+    title: String,
+    description: String,
+    content: Vec<String>,
+}
+
+#[derive(Default)]
+struct ParsedCurriculum {
+    title: Option<String>,
+    description: String,
+    modules: Vec<ParsedModule>,
+    issues: Vec<ImportIssue>,
+}
+
+fn import_issue(line: u32, severity: &str, message: impl Into<String>) -> ImportIssue {
+    ImportIssue { line, severity: severity.to_string(), message: message.into() }
+}
+
+// "# Course", then a "## Module" heading per module. The first paragraph under a module
+// heading is its description and everything after it is content.
+fn parse_markdown_curriculum(source: &str) -> ParsedCurriculum {
+    let mut parsed = ParsedCurriculum::default();
+    let mut in_description = true;
+    
+    for (index, line) in source.lines().enumerate() {
+        let line_no = index as u32 + 1;
+        let trimmed = line.trim();
+        let heading = |marker: &str| trimmed.strip_prefix(marker).filter(|rest| rest.is_empty() || rest.starts_with(' '));
+        if let Some(title) = heading("##") {
+            parsed.modules.push(ParsedModule { line: line_no, title: title.trim().to_string(), description: String::new(), content: Vec::new() });
+            in_description = true;
+            continue;
+        }
+        if let Some(title) = heading("#") {
+            if parsed.title.is_none() && parsed.modules.is_empty() {
+                parsed.title = Some(title.trim().to_string());
+            } else {
+                parsed.issues.push(import_issue(line_no, "warning", "Only the first '# ' heading is used as the course title; this one was kept as text"));
+                if let Some(module) = parsed.modules.last_mut() {
+                    module.content.push(trimmed.to_string());
+                }
+            }
+            continue;
+        }
+        
+        match parsed.modules.last_mut() {
+            None => {
+                if !trimmed.is_empty() {
+                    parsed.description = format!("{} {}", parsed.description, trimmed).trim().to_string();
+                }
+            }
+            Some(module) if in_description => {
+                if trimmed.is_empty() {
+                    in_description = module.description.is_empty();
+                } else if trimmed.starts_with(['-', '*', '#', '|']) || trimmed.starts_with("```") {
+                    in_description = false;
+                    module.content.push(line.to_string());
+                } else {
+                    module.description = format!("{} {}", module.description, trimmed).trim().to_string();
+                }
+            }
+            Some(module) => module.content.push(line.to_string()),
+        }
+    }
+    
+    if parsed.modules.is_empty() {
+        parsed.issues.push(import_issue(0, "error", "No modules found; start each module with a '## ' heading"));
+    }
+    parsed
+}
+
+// Splits CSV into records of (line, fields), honouring quoted fields with commas, doubled
+// quotes and line breaks
+fn csv_records(source: &str) -> Vec<(u32, Vec<String>)> {
+    let mut records = Vec::new();
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut line = 1;
+    let mut record_line = 1;
+    let mut chars = source.chars().peekable();
+    
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if in_quotes && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => in_quotes = !in_quotes,
+            ',' if !in_quotes => fields.push(std::mem::take(&mut field)),
+            '\r' if !in_quotes => {}
+            '\n' if !in_quotes => {
+                fields.push(std::mem::take(&mut field));
+                records.push((record_line, std::mem::take(&mut fields)));
+                line += 1;
+                record_line = line;
+            }
+            _ => {
+                if c == '\n' {
+                    line += 1;
+                }
+                field.push(c);
+            }
+        }
+    }
+    if !field.is_empty() || !fields.is_empty() {
+        fields.push(field);
+        records.push((record_line, fields));
+    }
+    records.retain(|(_, fields)| fields.iter().any(|f| !f.trim().is_empty()));
+    records
+}
+
+// One module per row. A header row may name the columns (title, description, content,
+// order); without one the columns are read as title, description, content.
+fn parse_csv_curriculum(source: &str) -> ParsedCurriculum {
+    let mut parsed = ParsedCurriculum::default();
+    let mut records = csv_records(source);
+    if records.is_empty() {
+        parsed.issues.push(import_issue(0, "error", "The CSV file has no rows"));
+        return parsed;
+    }
+    
+    let header: Vec<String> = records[0].1.iter().map(|h| h.trim().to_lowercase()).collect();
+    let has_header = header.iter().any(|h| h == "title" || h == "module");
+    let column = |names: &[&str], fallback: usize| -> Option<usize> {
+        if has_header {
+            header.iter().position(|h| names.contains(&h.as_str()))
+        } else {
+            Some(fallback)
+        }
+    };
+    let title_col = column(&["title", "module"], 0);
+    let description_col = column(&["description", "summary"], 1);
+    let content_col = column(&["content", "topics"], 2);
+    let order_col = if has_header { column(&["order", "week"], 3) } else { None };
+    if has_header {
+        records.remove(0);
+    }
+    
+    let mut ordered: Vec<(u32, ParsedModule)> = Vec::new();
+    for (line, fields) in records {
+        let get = |col: Option<usize>| col.and_then(|c| fields.get(c)).map(|f| f.trim().to_string()).unwrap_or_default();
+        let title = get(title_col);
+        if title.is_empty() {
+            parsed.issues.push(import_issue(line, "error", "Row has no module title"));
+            continue;
+        }
+        let order = match get(order_col) {
+            raw if raw.is_empty() => ordered.len() as u32 + 1,
+            raw => raw.parse().unwrap_or_else(|_| {
+                parsed.issues.push(import_issue(line, "warning", format!("Order '{}' is not a number; keeping the row's position", raw)));
+                ordered.len() as u32 + 1
+            }),
+        };
+        let content = get(content_col);
+        ordered.push((order, ParsedModule {
+            line,
+            title,
+            description: get(description_col),
+            content: if content.is_empty() { Vec::new() } else { vec![content] },
+        }));
+    }
+    
+    ordered.sort_by_key(|(order, _)| *order);
+    parsed.modules = ordered.into_iter().map(|(_, module)| module).collect();
+    parsed
+}
+
+fn unescape_xml(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+// Reads the course title from the manifest metadata and the organization's item tree:
+// items one level below the root item are modules, anything deeper becomes content.
+fn parse_common_cartridge(manifest: &str) -> ParsedCurriculum {
+    let mut parsed = ParsedCurriculum::default();
+    if !manifest.contains("<manifest") {
+        parsed.issues.push(import_issue(0, "error", "Expected the imsmanifest.xml of a Common Cartridge package"));
+        return parsed;
+    }
+    
+    let line_at = |pos: usize| manifest[..pos].matches('\n').count() as u32 + 1;
+    let mut item_depth = 0usize;
+    let mut in_organization = false;
+    let mut in_metadata_title = false;
+    let mut pos = 0;
+    
+    while let Some(start) = manifest[pos..].find('<').map(|i| pos + i) {
+        let Some(end) = manifest[start..].find('>').map(|i| start + i) else {
+            parsed.issues.push(import_issue(line_at(start), "error", "Unterminated tag"));
+            break;
+        };
+        let tag = &manifest[start + 1..end];
+        let name = tag.trim_start_matches('/').split(|c: char| c.is_whitespace() || c == '/').next().unwrap_or("");
+        let local_name = name.rsplit(':').next().unwrap_or(name);
+        let closing = tag.starts_with('/');
+        let self_closing = tag.ends_with('/');
+        pos = end + 1;
+        
+        match (local_name, closing) {
+            ("organization", false) => in_organization = !self_closing,
+            ("organization", true) => in_organization = false,
+            ("item", false) if in_organization && !self_closing => item_depth += 1,
+            ("item", true) if in_organization => item_depth = item_depth.saturating_sub(1),
+            ("title", false) if !in_organization && name.contains(':') => in_metadata_title = true,
+            ("title", true) => in_metadata_title = false,
+            ("string", false) if in_metadata_title && parsed.title.is_none() => {
+                if let Some(close) = manifest[pos..].find('<') {
+                    parsed.title = Some(unescape_xml(manifest[pos..pos + close].trim())).filter(|t| !t.is_empty());
+                }
+            }
+            ("title", false) if in_organization && item_depth >= 2 => {
+                let Some(close) = manifest[pos..].find('<') else { continue };
+                let title = unescape_xml(manifest[pos..pos + close].trim());
+                if item_depth == 2 {
+                    parsed.modules.push(ParsedModule { line: line_at(start), title, description: String::new(), content: Vec::new() });
+                } else if let Some(module) = parsed.modules.last_mut() {
+                    let indent = "  ".repeat(item_depth - 3);
+                    module.content.push(format!("{}- {}", indent, title));
+                }
+            }
+            _ => {}
+        }
+    }
+    
+    if parsed.modules.is_empty() {
+        parsed.issues.push(import_issue(0, "error", "The manifest's organization has no module items"));
+    }
+    parsed
+}
+
+// Applies the shared validation rules and returns the modules that can be imported
+fn validate_curriculum(parsed: &mut ParsedCurriculum) -> Vec<CourseModule> {
+    let mut modules = Vec::new();
+    let mut seen_titles = std::collections::HashSet::new();
+    
+    for (index, module) in parsed.modules.iter().enumerate() {
+        if index >= MAX_IMPORTED_MODULES {
+            parsed.issues.push(import_issue(module.line, "error", format!("Courses are limited to {} modules; the rest were skipped", MAX_IMPORTED_MODULES)));
+            break;
+        }
+        let mut title = module.title.clone();
+        if title.is_empty() {
+            parsed.issues.push(import_issue(module.line, "error", "Module has no title"));
+            continue;
+        }
+        if title.chars().count() > MAX_MODULE_TITLE_CHARS {
+            title = title.chars().take(MAX_MODULE_TITLE_CHARS).collect();
+            parsed.issues.push(import_issue(module.line, "warning", format!("Title shortened to {} characters", MAX_MODULE_TITLE_CHARS)));
+        }
+        if !seen_titles.insert(title.to_lowercase()) {
+            parsed.issues.push(import_issue(module.line, "warning", format!("Duplicate module title '{}'", title)));
+        }
+        let content = module.content.join("\n").trim_matches('\n').to_string();
+        if module.description.is_empty() && content.is_empty() {
+            parsed.issues.push(import_issue(module.line, "warning", format!("Module '{}' has no description or content", title)));
+        }
+        
+        modules.push(CourseModule {
+            id: modules.len() as u64 + 1,
+            title,
+            description: module.description.clone(),
+            order: modules.len() as u32 + 1,
+            content: if content.is_empty() { None } else { Some(content) },
+            status: "pending".to_string(),
+        });
+    }
+    modules
+}
+
+fn tutor_course_bytes(course: &TutorCourse) -> u64 {
+    let modules: usize = course.modules.iter()
+        .map(|m| m.title.len() + m.description.len() + m.content.as_ref().map_or(0, |c| c.len()))
+        .sum();
+    (course.topic.len() + course.outline.len() + modules + 64) as u64
+}
+
+#[ic_cdk::update]
+fn import_curriculum(
+    tutor_id: String,
+    format: String,
+    source: String,
+    attribution: Option<CourseAttribution>,
+    allow_partial: bool,
+    dry_run: bool,
+) -> Result<CurriculumImportReport, String> {
+    let caller = ic_cdk::caller();
+    let (tutor_key, tutor) = owned_tutor(&tutor_id, caller)?;
+    if !CURRICULUM_FORMATS.contains(&format.as_str()) {
+        return Err(format!("Format must be one of: {}", CURRICULUM_FORMATS.join(", ")));
+    }
+    if source.len() > MAX_CURRICULUM_BYTES {
+        return Err(format!("Curricula are limited to {} KB", MAX_CURRICULUM_BYTES / 1024));
+    }
+    
+    let mut parsed = match format.as_str() {
+        "common_cartridge" => parse_common_cartridge(&source),
+        "csv" => parse_csv_curriculum(&source),
+        _ => parse_markdown_curriculum(&source),
+    };
+    let modules_found = parsed.modules.len() as u32;
+    let modules = validate_curriculum(&mut parsed);
+    let has_errors = parsed.issues.iter().any(|i| i.severity == "error");
+    let mut report = CurriculumImportReport {
+        course: None,
+        modules_found,
+        modules_imported: 0,
+        issues: parsed.issues.clone(),
+    };
+    if dry_run || modules.is_empty() || (has_errors && !allow_partial) {
+        return Ok(report);
+    }
+    
+    let now = ic_cdk::api::time();
+    let mut attribution = attribution.unwrap_or_default();
+    attribution.source_format = format;
+    attribution.imported_by = Some(caller);
+    attribution.imported_at = now;
+    let title = parsed.title
+        .or_else(|| attribution.source_title.clone())
+        .or_else(|| tutor.default_topic.clone())
+        .unwrap_or_else(|| format!("{} course", tutor.name));
+    let outline = CourseOutline {
+        title: title.clone(),
+        description: parsed.description,
+        learning_objectives: Vec::new(),
+        estimated_duration: String::new(),
+        difficulty_level: "intermediate".to_string(),
+        modules: modules.clone(),
+        degraded: false,
+    };
+    let course = TutorCourse {
+        id: next_id("tutor_course"),
+        tutor_id: tutor_key,
+        session_id: 0,
+        topic: title,
+        outline: serde_json::to_string(&outline).unwrap_or_default(),
+        difficulty_level: outline.difficulty_level.clone(),
+        estimated_duration: String::new(),
+        created_at: now,
+        modules,
+        attribution: Some(attribution),
+        license: None,
+        derived_from: Vec::new(),
+    };
+    
+    let bytes = tutor_course_bytes(&course);
+    check_storage_quota(caller, bytes)?;
+    TUTOR_COURSES.with(|courses| courses.borrow_mut().insert(course.id, course.clone()));
+    record_storage_change(caller, "knowledge_base", bytes as i64);
+    collect_glossary_terms(&course);
+    
+    report.modules_imported = course.modules.len() as u32;
+    report.course = Some(course);
+    Ok(report)
+}
+
+#[ic_cdk::query]
+fn get_tutor_courses(tutor_id: String) -> Result<Vec<TutorCourse>, String> {
+    let (tutor_key, _) = owned_tutor(&tutor_id, ic_cdk::caller())?;
+    Ok(TUTOR_COURSES.with(|courses| {
+        courses.borrow().iter().map(|(_, c)| c).filter(|c| c.tutor_id == tutor_key).collect()
+    }))
+}
+
+#[ic_cdk::update]
+fn delete_tutor_course(course_id: u64) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    let course = owned_course(course_id, caller)?;
+    TUTOR_COURSES.with(|courses| courses.borrow_mut().remove(&course_id));
+    remove_course_glossary(course_id);
+    record_storage_change(caller, "knowledge_base", -(tutor_course_bytes(&course) as i64));
+    Ok(())
+}
+
+// --- xAPI Learning Records ---
+//
+// Module completions, placement tests, exams and tutoring activity are expressed as xAPI
+// statements. They can be downloaded on demand, and when an LRS is configured each new
+// statement is also queued and forwarded in batches by the heartbeat.
+
+const XAPI_VERSION: &str = "1.0.3";
+const XAPI_JOB_INTERVAL_NS: u64 = 60 * 1_000_000_000;
+const XAPI_RETRY_BASE_NS: u64 = 60 * 1_000_000_000;
+const MAX_XAPI_EXPORT: usize = 1000;
+
+thread_local! {
+    static XAPI_DELIVERY_IN_FLIGHT: RefCell<bool> = const { RefCell::new(false) };
+}
+
+fn xapi_base_iri(config: &CanisterConfig) -> String {
+    config.lrs.activity_base_iri.clone()
+        .map(|iri| iri.trim_end_matches('/').to_string())
+        .unwrap_or_else(|| format!("https://{}.icp0.io/xapi", ic_cdk::id()))
+}
+
+// Deterministic UUID so re-sending a statement never creates a duplicate in the LRS
+fn xapi_statement_id(key: &str) -> String {
+    use sha2::{Digest, Sha256};
+    let mut bytes = Sha256::digest(key.as_bytes())[..16].to_vec();
+    bytes[6] = (bytes[6] & 0x0f) | 0x50;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex = hex_encode(&bytes);
+    format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
+
+struct XapiActivity<'a> {
+    path: String,
+    name: String,
+    activity_type: &'a str,
+}
+
+fn xapi_statement(
+    config: &CanisterConfig,
+    key: &str,
+    user_id: Principal,
+    verb: &str,
+    activity: XapiActivity,
+    result: Option<serde_json::Value>,
+    timestamp: u64,
+) -> serde_json::Value {
+    let base = xapi_base_iri(config);
+    let mut statement = json!({
+        "id": xapi_statement_id(key),
+        "actor": {
+            "objectType": "Agent",
+            "account": { "homePage": base, "name": user_id.to_text() },
+        },
+        "verb": {
+            "id": format!("http://adlnet.gov/expapi/verbs/{}", verb),
+            "display": { "en-US": verb },
+        },
+        "object": {
+            "objectType": "Activity",
+            "id": format!("{}/{}", base, activity.path),
+            "definition": {
+                "name": { "en-US": activity.name },
+                "type": format!("http://adlnet.gov/expapi/activities/{}", activity.activity_type),
+            },
+        },
+        "timestamp": iso8601(timestamp),
+        "version": XAPI_VERSION,
+    });
+    if let Some(result) = result {
+        statement["result"] = result;
+    }
+    statement
+}
+
+fn module_completion_statement(config: &CanisterConfig, completion: &ModuleCompletion) -> serde_json::Value {
+    xapi_statement(
+        config,
+        &format!("module_completion:{}", completion.id),
+        completion.user_id,
+        "completed",
+        XapiActivity { path: format!("modules/{}", completion.module_id), name: format!("Module {}", completion.module_id), activity_type: "module" },
+        Some(json!({ "completion": true })),
+        completion.completion_date.unwrap_or(completion.created_at),
+    )
+}
+
+fn placement_statement(config: &CanisterConfig, test: &PlacementTest) -> Option<serde_json::Value> {
+    let completed_at = test.completed_at?;
+    Some(xapi_statement(
+        config,
+        &format!("placement_test:{}", test.id),
+        test.user_id,
+        "completed",
+        XapiActivity { path: format!("placement-tests/{}", test.id), name: format!("Placement test: {}", test.topic), activity_type: "assessment" },
+        Some(json!({
+            "completion": true,
+            "score": { "raw": test.levels_passed, "min": 0, "max": PLACEMENT_LEVELS, "scaled": test.levels_passed as f64 / PLACEMENT_LEVELS as f64 },
+        })),
+        completed_at,
+    ))
+}
+
+fn exam_statement(config: &CanisterConfig, exam: &Exam) -> Option<serde_json::Value> {
+    let completed_at = exam.completed_at?;
+    let score = exam.score_percent.unwrap_or(0.0);
+    Some(xapi_statement(
+        config,
+        &format!("exam:{}", exam.id),
+        exam.user_id,
+        "completed",
+        XapiActivity { path: format!("exams/{}", exam.id), name: format!("Exam: {}", exam.topic), activity_type: "assessment" },
+        Some(json!({
+            "completion": true,
+            "score": { "raw": score, "min": 0, "max": 100, "scaled": score / 100.0 },
+            "duration": format!("PT{}S", completed_at.saturating_sub(exam.created_at) / 1_000_000_000),
+        })),
+        completed_at,
+    ))
+}
+
+fn session_activity_statement(config: &CanisterConfig, metrics: &LearningMetrics) -> serde_json::Value {
+    xapi_statement(
+        config,
+        &format!("learning_metrics:{}", metrics.id),
+        metrics.user_id,
+        "experienced",
+        XapiActivity { path: format!("sessions/{}", metrics.session_id), name: "Tutoring session".to_string(), activity_type: "interaction" },
+        Some(json!({ "duration": format!("PT{}M", metrics.time_spent_minutes) })),
+        metrics.created_at,
+    )
+}
+
+// All statements for a user (or every user), oldest first
+fn collect_xapi_statements(user_id: Option<Principal>, since: u64) -> Vec<serde_json::Value> {
+    let config = get_config();
+    let matches = |owner: &Principal, at: u64| user_id.is_none_or(|u| &u == owner) && at >= since;
+    let mut statements: Vec<(u64, serde_json::Value)> = Vec::new();
+    
+    MODULE_COMPLETIONS.with(|completions| {
+        for (_, c) in completions.borrow().iter() {
+            let at = c.completion_date.unwrap_or(c.created_at);
+            if c.completed && matches(&c.user_id, at) {
+                statements.push((at, module_completion_statement(&config, &c)));
+            }
+        }
+    });
+    PLACEMENT_TESTS.with(|tests| {
+        for (_, test) in tests.borrow().iter() {
+            if let Some(at) = test.completed_at.filter(|at| matches(&test.user_id, *at)) {
+                statements.extend(placement_statement(&config, &test).map(|s| (at, s)));
+            }
+        }
+    });
+    EXAMS.with(|exams| {
+        for (_, exam) in exams.borrow().iter() {
+            if let Some(at) = exam.completed_at.filter(|at| matches(&exam.user_id, *at)) {
+                statements.extend(exam_statement(&config, &exam).map(|s| (at, s)));
+            }
+        }
+    });
+    LEARNING_METRICS.with(|metrics| {
+        for (_, m) in metrics.borrow().iter() {
+            if matches(&m.user_id, m.created_at) {
+                statements.push((m.created_at, session_activity_statement(&config, &m)));
+            }
+        }
+    });
+    
+    statements.sort_by_key(|(at, _)| *at);
+    statements.into_iter().map(|(_, s)| s).collect()
+}
+
+fn queue_xapi_statement(user_id: Principal, statement: serde_json::Value) {
+    if !get_config().lrs.enabled {
+        return;
+    }
+    let now = ic_cdk::api::time();
+    let statement_id = statement["id"].as_str().unwrap_or_default().to_string();
+    XAPI_OUTBOX.with(|outbox| {
+        outbox.borrow_mut().insert(statement_id.clone(), XapiOutboxEntry {
+            statement_id,
+            user_id,
+            statement: statement.to_string(),
+            attempts: 0,
+            last_error: None,
+            next_attempt_at: now,
+            created_at: now,
+        });
+    });
+}
+
+async fn post_xapi_statements(config: &CanisterConfig, statements: &[String]) -> Result<(), String> {
+    let request = CanisterHttpRequestArgument {
+        url: format!("{}/statements", config.lrs.endpoint_url.trim_end_matches('/')),
+        max_response_bytes: Some(16 * 1024),
+        method: HttpMethod::POST,
+        headers: vec![
+            HttpHeader { name: "Content-Type".to_string(), value: "application/json".to_string() },
+            HttpHeader { name: "Authorization".to_string(), value: config.lrs.authorization.clone() },
+            HttpHeader { name: "X-Experience-API-Version".to_string(), value: XAPI_VERSION.to_string() },
+        ],
+        body: Some(format!("[{}]", statements.join(",")).into_bytes()),
+        transform: Some(TransformContext::from_name("transform_ai_response".to_string(), vec![])),
+    };
+    let budget = outcall_budget(config, "lrs");
+    let (response,) = ic_cdk::api::management_canister::http_request::http_request(request, budget.cycles as u128)
+        .await
+        .map_err(|(code, msg)| format!("HTTP outcall failed: {:?} - {}", code, msg))?;
+    
+    let status: u32 = response.status.0.try_into().unwrap_or(0);
+    if !(200..300).contains(&status) {
+        return Err(format!("LRS returned status {}", status));
+    }
+    Ok(())
+}
+
+// Sends one batch of due statements; failures back off exponentially and keep retrying
+async fn deliver_xapi_batch(now: u64) {
+    let config = get_config();
+    let batch: Vec<XapiOutboxEntry> = XAPI_OUTBOX.with(|outbox| {
+        outbox.borrow()
+            .iter()
+            .map(|(_, e)| e)
+            .filter(|e| e.next_attempt_at <= now)
+            .take(config.lrs.batch_size.max(1) as usize)
+            .collect()
+    });
+    if batch.is_empty() {
+        return;
+    }
+    
+    let statements: Vec<String> = batch.iter().map(|e| e.statement.clone()).collect();
+    let result = post_xapi_statements(&config, &statements).await;
+    XAPI_OUTBOX.with(|outbox| {
+        let mut outbox = outbox.borrow_mut();
+        for mut entry in batch {
+            match &result {
+                Ok(()) => {
+                    outbox.remove(&entry.statement_id);
+                }
+                Err(e) => {
+                    entry.attempts += 1;
+                    entry.last_error = Some(e.clone());
+                    entry.next_attempt_at = now + XAPI_RETRY_BASE_NS * (1u64 << entry.attempts.min(10));
+                    outbox.insert(entry.statement_id.clone(), entry);
+                }
+            }
+        }
+    });
+}
+
+fn run_xapi_delivery(now: u64) {
+    if !get_config().lrs.enabled || XAPI_DELIVERY_IN_FLIGHT.with(|f| f.replace(true)) {
+        return;
+    }
+    ic_cdk::spawn(async move {
+        deliver_xapi_batch(now).await;
+        XAPI_DELIVERY_IN_FLIGHT.with(|f| *f.borrow_mut() = false);
+    });
+}
+
+// Statements are returned as a JSON array, ready to POST to any LRS
+#[ic_cdk::query]
+fn export_my_xapi_statements(since: Option<u64>) -> String {
+    let statements = collect_xapi_statements(Some(ic_cdk::caller()), since.unwrap_or(0));
+    serde_json::Value::Array(statements.into_iter().take(MAX_XAPI_EXPORT).collect()).to_string()
+}
+
+#[ic_cdk::query]
+fn export_xapi_statements_admin(user_id: Option<Principal>, since: Option<u64>, limit: u32) -> Result<String, String> {
+    require(ic_cdk::caller(), Permission::ManageSystem)?;
+    let limit = (limit as usize).clamp(1, MAX_XAPI_EXPORT);
+    let statements: Vec<serde_json::Value> = collect_xapi_statements(user_id, since.unwrap_or(0)).into_iter().take(limit).collect();
+    let count = statements.len();
+    let export = serde_json::Value::Array(statements).to_string();
+    ensure_bytes_fit(export.len(), count, "Lower the limit or pass a later since timestamp.")?;
+    Ok(export)
+}
+
+#[ic_cdk::update]
+fn set_lrs_config_admin(lrs: LrsConfig) -> Result<CanisterConfig, String> {
+    require(ic_cdk::caller(), Permission::ManageSystem)?;
+    if lrs.enabled && !lrs.endpoint_url.starts_with("https://") {
+        return Err("The LRS must use an https endpoint".to_string());
+    }
+    if lrs.batch_size == 0 || lrs.batch_size > 500 {
+        return Err("Batch size must be between 1 and 500".to_string());
+    }
+    
+    update_config(|config| {
+        // Credentials come back redacted from get_config_admin, so an empty value keeps the stored one
+        let mut lrs = lrs;
+        if lrs.authorization.is_empty() {
+            lrs.authorization = config.lrs.authorization.clone();
+        }
+        config.lrs = lrs;
+        Ok(())
+    })
+}
+
+// Queues every stored statement for the LRS, e.g. after connecting one for the first time
+#[ic_cdk::update]
+fn backfill_lrs_admin(since: Option<u64>) -> Result<u64, String> {
+    let caller = ic_cdk::caller();
+    require(caller, Permission::ManageSystem)?;
+    if !get_config().lrs.enabled {
+        return Err("The LRS is not enabled".to_string());
+    }
+    
+    let statements = collect_xapi_statements(None, since.unwrap_or(0));
+    let count = statements.len() as u64;
+    for statement in statements {
+        let user_id = statement["actor"]["account"]["name"].as_str()
+            .and_then(|name| Principal::from_text(name).ok())
+            .unwrap_or_else(Principal::anonymous);
+        queue_xapi_statement(user_id, statement);
+    }
+    record_audit(caller, "lrs_backfill", None, format!("{} statements queued", count));
+    Ok(count)
+}
+
+#[ic_cdk::query]
+fn get_lrs_outbox_status_admin() -> Result<LrsOutboxStatus, String> {
+    require(ic_cdk::caller(), Permission::ManageSystem)?;
+    Ok(XAPI_OUTBOX.with(|outbox| {
+        let outbox = outbox.borrow();
+        let failing: Vec<XapiOutboxEntry> = outbox.iter().map(|(_, e)| e).filter(|e| e.attempts > 0).collect();
+        LrsOutboxStatus {
+            queued: outbox.len(),
+            failing: failing.len() as u64,
+            oldest_queued_at: outbox.iter().map(|(_, e)| e.created_at).min(),
+            last_error: failing.iter().max_by_key(|e| e.next_attempt_at).and_then(|e| e.last_error.clone()),
+        }
+    }))
+}
+
+// --- Guest Sessions ---
+//
+// Visitors without an account can try one short chat with a built-in demo tutor. Guest
+// sessions live in their own map under a random token, expire after a short TTL and have a
+// small fixed AI allowance. After signing up, convert_guest_session copies the conversation
+// into a regular session with the caller's own copy of the demo tutor.
+
+const DEMO_TUTOR_NAME: &str = "Cogni";
+const DEMO_TUTOR_STYLE: &str = "Socratic";
+const DEMO_TUTOR_PERSONALITY: &str = "patient and encouraging";
+
+fn demo_tutor(id: u64, public_id: String, user_id: Principal) -> Tutor {
+    let now = ic_cdk::api::time();
+    Tutor {
+        id,
+        public_id,
+        user_id,
+        name: DEMO_TUTOR_NAME.to_string(),
+        description: "A general-purpose tutor to get you started".to_string(),
+        teaching_style: DEMO_TUTOR_STYLE.to_string(),
+        personality: DEMO_TUTOR_PERSONALITY.to_string(),
+        expertise: vec!["General knowledge".to_string()],
+        knowledge_base: Vec::new(),
+        is_pinned: false,
+        avatar_url: None,
+        voice_id: None,
+        voice_settings: HashMap::new(),
+        created_at: now,
+        updated_at: now,
+        default_topic: None,
+        intake_questions: Vec::new(),
+        welcome_mode: "template".to_string(),
+        welcome_template: None,
+        slug: None,
+        visibility: Visibility::Private,
+        kb_version_pins: Vec::new(),
+        high_stakes_domain: None,
+        fact_check: false,
+        model_params: None,
+        license: None,
+        derived_from: Vec::new(),
+    }
+}
+
+fn guest_message(token: &str, sender: &str, content: String) -> ChatMessage {
+    let reading_grade = (sender == "tutor").then(|| estimate_reading_grade(&content));
+    ChatMessage {
+        id: format!("guest_{}", ic_cdk::api::time()),
+        session_id: token.to_string(),
+        sender: sender.to_string(),
+        content,
+        timestamp: ic_cdk::api::time(),
+        has_audio: Some(false),
+        delivery_status: "delivered".to_string(),
+        reading_grade,
+        author: None,
+        citations: Vec::new(),
+        confidence: None,
+        rating: None,
+        bookmarked: false,
+    }
+}
+
+// Expired sessions are reported as missing, the same as unknown tokens
+fn live_guest_session(token: &str) -> Result<GuestSession, String> {
+    GUEST_SESSIONS.with(|sessions| sessions.borrow().get(&token.to_string()))
+        .filter(|session| session.expires_at > ic_cdk::api::time())
+        .ok_or_else(|| "Guest session not found or expired".to_string())
+}
+
+fn prune_guest_sessions(now: u64) {
+    let expired: Vec<String> = GUEST_SESSIONS.with(|sessions| {
+        sessions.borrow().iter()
+            .filter(|(_, session)| session.expires_at <= now)
+            .map(|(token, _)| token)
+            .take(RETENTION_BATCH_SIZE)
+            .collect()
+    });
+    GUEST_SESSIONS.with(|sessions| {
+        let mut sessions = sessions.borrow_mut();
+        for token in expired {
+            sessions.remove(&token);
+        }
+    });
+}
+
+#[ic_cdk::update]
+async fn start_guest_session(topic: String) -> Result<GuestSession, String> {
+    let caller = ic_cdk::caller();
+    let guest = get_config().guest;
+    if !guest.enabled {
+        return Err("Guest sessions are not available right now".to_string());
+    }
+    if USERS.with(|users| users.borrow().contains_key(&caller)) {
+        return Err("You already have an account; start a regular session instead".to_string());
+    }
+    let topic = topic.trim().to_string();
+    if topic.is_empty() || topic.chars().count() > 200 {
+        return Err("Topic must be between 1 and 200 characters".to_string());
+    }
+    
+    let token = random_public_id("guest").await?;
+    let now = ic_cdk::api::time();
+    let (active, own_active) = GUEST_SESSIONS.with(|sessions| {
+        let sessions = sessions.borrow();
+        let live: Vec<GuestSession> = sessions.iter().map(|(_, s)| s).filter(|s| s.expires_at > now && s.embed_token.is_none()).collect();
+        let own = live.iter().any(|s| s.started_by == caller);
+        (live.len(), own)
+    });
+    // All anonymous callers share a principal, so only authenticated ones are held to one session
+    if own_active && caller != Principal::anonymous() {
+        return Err("You already have a guest session in progress".to_string());
+    }
+    if active >= guest.max_active_sessions as usize {
+        return Err("Too many guest sessions are active; please try again later or sign up".to_string());
+    }
+    
+    let tutor = demo_tutor(0, String::new(), Principal::anonymous());
+    let welcome = render_welcome_template(DEFAULT_WELCOME_TEMPLATE, &tutor, &topic, "there");
+    let session = GuestSession {
+        token: token.clone(),
+        started_by: caller,
+        topic,
+        messages: vec![guest_message(&token, "tutor", welcome)],
+        ai_calls_remaining: guest.max_ai_calls,
+        created_at: now,
+        expires_at: now + guest.session_ttl_minutes as u64 * 60 * 1_000_000_000,
+        embed_token: None,
+        tutor_id: None,
+    };
+    GUEST_SESSIONS.with(|sessions| sessions.borrow_mut().insert(token, session.clone()));
+    Ok(session)
+}
+
+#[ic_cdk::query]
+fn get_guest_session(token: String) -> Result<GuestSession, String> {
+    live_guest_session(&token)
+}
+
+#[ic_cdk::update]
+async fn send_guest_message(token: String, message: String) -> Result<ChatMessage, String> {
+    rate_limit::check(ic_cdk::caller(), "chat")?;
+    let guest = get_config().guest;
+    let mut session = live_guest_session(&token)?;
+    if session.embed_token.is_some() {
+        return Err("Use send_embed_message for embedded tutor sessions".to_string());
+    }
+    let message = message.trim().to_string();
+    if message.is_empty() {
+        return Err("Message cannot be empty".to_string());
+    }
+    if message.chars().count() > guest.max_message_chars as usize {
+        return Err(format!("Guest messages are limited to {} characters", guest.max_message_chars));
+    }
+    if session.ai_calls_remaining == 0 {
+        return Err("This demo has used all of its tutor replies; sign up to keep learning".to_string());
+    }
+    
+    // Charge the call before awaiting so concurrent messages can't overspend the allowance
+    session.ai_calls_remaining -= 1;
+    session.messages.push(guest_message(&token, "user", message.clone()));
+    GUEST_SESSIONS.with(|sessions| sessions.borrow_mut().insert(token.clone(), session.clone()));
+    
+    let earlier = &session.messages[..session.messages.len() - 1];
+    let mut context = String::new();
+    for msg in &earlier[earlier.len().saturating_sub(3)..] {
+        context.push_str(&format!("{}: {}\n", msg.sender, msg.content));
+    }
+    let prompt = format!(
+        "You are {} an AI tutor. Teaching style: {}. Personality: {}. Topic: {}.
+        
+        Context: {}
+        Student: {}
+        
+        Respond briefly and helpfully. Use emojis! Keep under 200 chars.",
+        DEMO_TUTOR_NAME,
+        DEMO_TUTOR_STYLE,
+        DEMO_TUTOR_PERSONALITY,
+        session.topic,
+        context,
+        message
+    );
+    let reply = process_ai_response(call_groq_ai(&prompt, "chat", ic_cdk::caller()).await?, &response_processing_for(Principal::anonymous(), "chat"));
+    let reply = guest_message(&token, "tutor", reply);
+    
+    // The session may have expired or been converted while the reply was generated
+    GUEST_SESSIONS.with(|sessions| {
+        let mut sessions = sessions.borrow_mut();
+        if let Some(mut current) = sessions.get(&token) {
+            current.messages.push(reply.clone());
+            sessions.insert(token.clone(), current);
+        }
+    });
+    Ok(reply)
+}
+
+// Moves the demo conversation into a regular session owned by the caller, who must have signed up
+#[ic_cdk::update]
+async fn convert_guest_session(token: String) -> Result<String, String> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return Err("Sign in before converting a guest session".to_string());
+    }
+    get_self().ok_or("Create an account before converting a guest session")?;
+    let session = live_guest_session(&token)?;
+    if session.embed_token.is_some() {
+        return Err("Embedded tutor sessions can't be converted".to_string());
+    }
+    let bytes: u64 = session.messages.iter().map(chat_message_bytes).sum();
+    check_storage_quota(caller, bytes)?;
+    enforce_quota(caller, "tutors", 1)?;
+    
+    let public_id = random_public_id("tutor").await?;
+    // Claim the session after the await so it can only be converted once
+    let session = GUEST_SESSIONS.with(|sessions| sessions.borrow_mut().remove(&token))
+        .filter(|session| session.expires_at > ic_cdk::api::time())
+        .ok_or("Guest session not found or expired")?;
+    
+    let tutor_id = next_id("tutor");
+    let tutor = demo_tutor(tutor_id, public_id, caller);
+    cache::store_tutor(tutor_id, tutor.clone());
+    queue_tagging("tutor", &tutor.public_id);
+    
+    let now = ic_cdk::api::time();
+    let session_id = format!("session_{}", now);
+    CHAT_SESSIONS.with(|sessions| sessions.borrow_mut().insert(session_id.clone(), ChatSession {
+        id: session_id.clone(),
+        tutor_id: tutor.public_id,
+        user_id: caller,
+        topic: session.topic,
+        status: "active".to_string(),
+        created_at: session.created_at,
+        updated_at: now,
+        summary: None,
+        intake_answers: Vec::new(),
+        visibility: Visibility::Private,
+        archived_at: None,
+        co_learners: Vec::new(),
+        owner_left_at: None,
+        imported_from: None,
+        unread_count: 0,
+        topic_drift: None,
+    }));
+    for (index, message) in session.messages.into_iter().enumerate() {
+        append_chat_message(caller, ChatMessage {
+            id: format!("{}_{}", message.timestamp, index),
+            session_id: session_id.clone(),
+            ..message
+        });
+    }
+    
+    Ok(session_id)
+}
+
+#[ic_cdk::update]
+fn set_guest_config_admin(guest: GuestConfig) -> Result<CanisterConfig, String> {
+    require(ic_cdk::caller(), Permission::ManageSystem)?;
+    if guest.session_ttl_minutes == 0 || guest.session_ttl_minutes > 24 * 60 {
+        return Err("Guest sessions must last between 1 minute and 24 hours".to_string());
+    }
+    if guest.max_message_chars == 0 {
+        return Err("Guest messages must allow at least one character".to_string());
+    }
+    update_config(|config| {
+        config.guest = guest;
+        Ok(())
+    })
+}
+
+// --- Invites and Waitlist ---
+//
+// In invite-only mode register_user needs a code from an admin-created batch. Visitors can
+// join the waitlist instead, and admins issue single-use codes to the oldest entries; the
+// codes are returned so they can be emailed from outside the canister.
+
+const MAX_INVITE_BATCH: u32 = 500;
+
+fn normalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+fn normalize_invite_code(code: &str) -> String {
+    code.trim().to_uppercase()
+}
+
+fn valid_invite_code(code: &str, email: &str) -> Result<InviteCode, String> {
+    let invite = INVITE_CODES.with(|codes| codes.borrow().get(&normalize_invite_code(code)))
+        .filter(|invite| !invite.revoked)
+        .ok_or("A valid invite code is required to register")?;
+    if invite.expires_at.is_some_and(|expires_at| expires_at <= ic_cdk::api::time()) {
+        return Err("This invite code has expired".to_string());
+    }
+    if invite.uses >= invite.max_uses {
+        return Err("This invite code has already been used".to_string());
+    }
+    if invite.issued_to.as_ref().is_some_and(|issued_to| *issued_to != normalize_email(email)) {
+        return Err("This invite code was issued to a different email address".to_string());
+    }
+    Ok(invite)
+}
+
+fn redeem_invite_code(mut invite: InviteCode, email: &str) {
+    invite.uses += 1;
+    INVITE_CODES.with(|codes| codes.borrow_mut().insert(invite.code.clone(), invite));
+    let email = normalize_email(email);
+    WAITLIST.with(|waitlist| {
+        let mut waitlist = waitlist.borrow_mut();
+        if let Some(mut entry) = waitlist.get(&email) {
+            entry.registered_at = Some(ic_cdk::api::time());
+            waitlist.insert(email, entry);
+        }
+    });
+}
+
+// Codes look like "3F9A-C21B-77E0"; derived from one raw_rand seed per call
+async fn new_invite_codes(count: u32) -> Result<Vec<String>, String> {
+    use sha2::{Digest, Sha256};
+    let seed = random_bytes().await?;
+    let mut codes = Vec::new();
+    let mut counter: u64 = 0;
+    while codes.len() < count as usize {
+        let mut hasher = Sha256::new();
+        hasher.update(&seed);
+        hasher.update(counter.to_be_bytes());
+        counter += 1;
+        let hex = hex_encode(&hasher.finalize()[..6]).to_uppercase();
+        let code = format!("{}-{}-{}", &hex[..4], &hex[4..8], &hex[8..]);
+        if !codes.contains(&code) && !INVITE_CODES.with(|c| c.borrow().contains_key(&code)) {
+            codes.push(code);
+        }
+    }
+    Ok(codes)
+}
+
+fn waitlist_pending(entry: &WaitlistEntry) -> bool {
+    entry.invite_code.is_none() && entry.registered_at.is_none()
+}
+
+// Oldest first
+fn sorted_waitlist(pending_only: bool) -> Vec<WaitlistEntry> {
+    let mut entries: Vec<WaitlistEntry> = WAITLIST.with(|waitlist| {
+        waitlist.borrow().iter().map(|(_, e)| e).filter(|e| !pending_only || waitlist_pending(e)).collect()
+    });
+    entries.sort_by_key(|e| e.joined_at);
+    entries
+}
+
+// Returns the caller's place in line; joining again with the same email is harmless
+#[ic_cdk::update]
+fn join_waitlist(email: String) -> Result<u64, String> {
+    if !get_config().registration.waitlist_open {
+        return Err("The waitlist is closed".to_string());
+    }
+    let email = normalize_email(&email);
+    if email.len() > 254 || !email.split_once('@').is_some_and(|(name, domain)| !name.is_empty() && domain.contains('.')) {
+        return Err("Please enter a valid email address".to_string());
+    }
+    if USERS.with(|users| users.borrow().values().any(|user| normalize_email(&user.email) == email)) {
+        return Err("An account with this email already exists".to_string());
+    }
+    
+    let entry = WAITLIST.with(|waitlist| waitlist.borrow().get(&email)).unwrap_or_else(|| WaitlistEntry {
+        email: email.clone(),
+        joined_at: ic_cdk::api::time(),
+        invite_code: None,
+        invited_at: None,
+        registered_at: None,
+    });
+    WAITLIST.with(|waitlist| waitlist.borrow_mut().insert(email, entry.clone()));
+    if !waitlist_pending(&entry) {
+        return Ok(0);
+    }
+    let ahead = WAITLIST.with(|waitlist| {
+        waitlist.borrow().iter().filter(|(_, e)| waitlist_pending(e) && e.joined_at < entry.joined_at).count()
+    });
+    Ok(ahead as u64 + 1)
+}
+
+// Lets the sign-up form check a code before submitting
+#[ic_cdk::query]
+fn check_invite_code(code: String, email: String) -> Result<(), String> {
+    valid_invite_code(&code, &email).map(|_| ())
+}
+
+#[ic_cdk::update]
+async fn create_invite_codes_admin(batch: String, count: u32, max_uses: u32, expires_at: Option<u64>) -> Result<Vec<InviteCode>, String> {
+    let caller = ic_cdk::caller();
+    require(caller, Permission::ManageInvites)?;
+    let batch = batch.trim().to_string();
+    if batch.is_empty() {
+        return Err("Batch name is required".to_string());
+    }
+    if count == 0 || count > MAX_INVITE_BATCH {
+        return Err(format!("Count must be between 1 and {}", MAX_INVITE_BATCH));
+    }
+    if max_uses == 0 {
+        return Err("Each code must allow at least one use".to_string());
+    }
+    
+    let now = ic_cdk::api::time();
+    let invites: Vec<InviteCode> = new_invite_codes(count).await?.into_iter().map(|code| InviteCode {
+        code,
+        batch: batch.clone(),
+        max_uses,
+        uses: 0,
+        expires_at,
+        issued_to: None,
+        revoked: false,
+        created_by: caller,
+        created_at: now,
+    }).collect();
+    INVITE_CODES.with(|codes| {
+        let mut codes = codes.borrow_mut();
+        for invite in &invites {
+            codes.insert(invite.code.clone(), invite.clone());
+        }
+    });
+    record_audit(caller, "invite_codes_created", None, format!("{} codes in batch '{}'", count, batch));
+    Ok(invites)
+}
+
+#[ic_cdk::query]
+fn list_invite_codes_admin(batch: Option<String>) -> Result<Vec<InviteCode>, String> {
+    require(ic_cdk::caller(), Permission::ManageInvites)?;
+    let invites: Vec<InviteCode> = INVITE_CODES.with(|codes| {
+        codes.borrow().iter()
+            .map(|(_, invite)| invite)
+            .filter(|invite| batch.as_ref().is_none_or(|batch| invite.batch == *batch))
+            .collect()
+    });
+    ensure_fits(&invites, "Filter by batch.")?;
+    Ok(invites)
+}
+
+#[ic_cdk::update]
+fn revoke_invite_batch_admin(batch: String) -> Result<u64, String> {
+    let caller = ic_cdk::caller();
+    require(caller, Permission::ManageInvites)?;
+    let revoked = INVITE_CODES.with(|codes| {
+        let mut codes = codes.borrow_mut();
+        let batch_codes: Vec<InviteCode> = codes.iter().map(|(_, c)| c).filter(|c| c.batch == batch && !c.revoked).collect();
+        for mut invite in batch_codes.iter().cloned() {
+            invite.revoked = true;
+            codes.insert(invite.code.clone(), invite);
+        }
+        batch_codes.len() as u64
+    });
+    record_audit(caller, "invite_batch_revoked", None, format!("{} codes in batch '{}'", revoked, batch));
+    Ok(revoked)
+}
+
+#[ic_cdk::query]
+fn get_waitlist_admin(pending_only: bool) -> Result<Vec<WaitlistEntry>, String> {
+    require(ic_cdk::caller(), Permission::ManageInvites)?;
+    let entries = sorted_waitlist(pending_only);
+    ensure_fits(&entries, "Set pending_only to list only people still waiting.")?;
+    Ok(entries)
+}
+
+// Issues a single-use code to each of the `count` longest-waiting entries and returns them
+#[ic_cdk::update]
+async fn invite_from_waitlist_admin(batch: String, count: u32, expires_at: Option<u64>) -> Result<Vec<WaitlistEntry>, String> {
+    let caller = ic_cdk::caller();
+    require(caller, Permission::ManageInvites)?;
+    if count == 0 || count > MAX_INVITE_BATCH {
+        return Err(format!("Count must be between 1 and {}", MAX_INVITE_BATCH));
+    }
+    let batch = batch.trim().to_string();
+    if batch.is_empty() {
+        return Err("Batch name is required".to_string());
+    }
+    
+    let codes = new_invite_codes(count).await?;
+    let mut pending = sorted_waitlist(true);
+    pending.truncate(count as usize);
+    
+    let now = ic_cdk::api::time();
+    let invited: Vec<WaitlistEntry> = pending.into_iter().zip(codes).map(|(mut entry, code)| {
+        INVITE_CODES.with(|codes| codes.borrow_mut().insert(code.clone(), InviteCode {
+            code: code.clone(),
+            batch: batch.clone(),
+            max_uses: 1,
+            uses: 0,
+            expires_at,
+            issued_to: Some(entry.email.clone()),
+            revoked: false,
+            created_by: caller,
+            created_at: now,
+        }));
+        entry.invite_code = Some(code);
+        entry.invited_at = Some(now);
+        WAITLIST.with(|waitlist| waitlist.borrow_mut().insert(entry.email.clone(), entry.clone()));
+        entry
+    }).collect();
+    record_audit(caller, "waitlist_invited", None, format!("{} entries invited in batch '{}'", invited.len(), batch));
+    Ok(invited)
+}
+
+#[ic_cdk::update]
+fn set_registration_config_admin(registration: RegistrationConfig) -> Result<CanisterConfig, String> {
+    let caller = ic_cdk::caller();
+    require(caller, Permission::ManageSystem)?;
+    record_audit(caller, "registration_config_updated", None, format!("invite_only={}, waitlist_open={}", registration.invite_only, registration.waitlist_open));
+    update_config(|config| {
+        config.registration = registration;
+        Ok(())
+    })
+}
+
+// --- AI Concurrency ---
+//
+// Each plan caps how many AI requests a user can have running at once. Slots live on the heap
+// and are released when the guard drops; entries older than AI_SLOT_STALE_NS are ignored in
+// case a trapped call never released its slot. Tutor replies that hit the cap are queued and
+// delivered when a slot frees up; any other AI operation is rejected.
+
+const AI_BUSY_ERROR: &str = "You already have the maximum number of AI requests running";
+const REPLY_QUEUED_MESSAGE: &str = "The reply is queued and will be delivered when one of your other AI requests finishes";
+const AI_SLOT_STALE_NS: u64 = 10 * 60 * 1_000_000_000;
+
+struct InFlightAi {
+    id: u64,
+    operation: String,
+    started_at: u64,
+}
+
+thread_local! {
+    static AI_IN_FLIGHT: RefCell<HashMap<Principal, Vec<InFlightAi>>> = RefCell::new(HashMap::new());
+    static NEXT_AI_SLOT_ID: RefCell<u64> = const { RefCell::new(0) };
+}
+
+struct AiSlot {
+    user_id: Principal,
+    id: u64,
+}
+
+impl Drop for AiSlot {
+    fn drop(&mut self) {
+        AI_IN_FLIGHT.with(|in_flight| {
+            let mut in_flight = in_flight.borrow_mut();
+            if let Some(slots) = in_flight.get_mut(&self.user_id) {
+                slots.retain(|slot| slot.id != self.id);
+                if slots.is_empty() {
+                    in_flight.remove(&self.user_id);
+                }
+            }
+        });
+    }
+}
+
+fn max_concurrent_ai(user_id: Principal) -> Option<u32> {
+    plan_limits(&user_plan(user_id)).and_then(|l| l.max_concurrent_ai)
+}
+
+// Drops stale entries and returns the operations still running for the user
+fn ai_operations_in_flight(user_id: Principal) -> Vec<String> {
+    let cutoff = ic_cdk::api::time().saturating_sub(AI_SLOT_STALE_NS);
+    AI_IN_FLIGHT.with(|in_flight| {
+        let mut in_flight = in_flight.borrow_mut();
+        let slots = in_flight.entry(user_id).or_default();
+        slots.retain(|slot| slot.started_at > cutoff);
+        let operations = slots.iter().map(|slot| slot.operation.clone()).collect();
+        if slots.is_empty() {
+            in_flight.remove(&user_id);
+        }
+        operations
+    })
+}
+
+fn ai_slot_free(user_id: Principal) -> bool {
+    max_concurrent_ai(user_id).is_none_or(|limit| ai_operations_in_flight(user_id).len() < limit as usize)
+}
+
+fn is_ai_busy_error(error: &str) -> bool {
+    error.starts_with(AI_BUSY_ERROR)
+}
+
+// Only signed-up users are limited; internal jobs get no slot
+fn acquire_ai_slot(user_id: Principal, operation: &str) -> Result<Option<AiSlot>, String> {
+    if !USERS.with(|users| users.borrow().contains_key(&user_id)) {
+        return Ok(None);
+    }
+    if let Some(limit) = max_concurrent_ai(user_id) {
+        if ai_operations_in_flight(user_id).len() >= limit as usize {
+            return Err(format!(
+                "{} ({} at a time on the {} plan); wait for one to finish",
+                AI_BUSY_ERROR,
+                limit,
+                user_plan(user_id)
+            ));
+        }
+    }
+    
+    let id = NEXT_AI_SLOT_ID.with(|next| {
+        let mut next = next.borrow_mut();
+        *next += 1;
+        *next
+    });
+    AI_IN_FLIGHT.with(|in_flight| {
+        in_flight.borrow_mut().entry(user_id).or_default().push(InFlightAi {
+            id,
+            operation: operation.to_string(),
+            started_at: ic_cdk::api::time(),
+        });
+    });
+    Ok(Some(AiSlot { user_id, id }))
+}
+
+fn queued_deliveries(user_id: Option<Principal>) -> Vec<PendingDelivery> {
+    let mut queued: Vec<PendingDelivery> = PENDING_DELIVERIES.with(|deliveries| {
+        deliveries.borrow().iter()
+            .map(|(_, d)| d)
+            .filter(|d| d.status == "queued" && user_id.is_none_or(|user_id| d.requester() == user_id))
+            .collect()
+    });
+    queued.sort_by_key(|d| d.created_at);
+    queued
+}
+
+// Starts the user's oldest queued reply if they have a free slot
+fn dispatch_queued_delivery(user_id: Principal) {
+    if !ai_slot_free(user_id) {
+        return;
+    }
+    if let Some(delivery) = queued_deliveries(Some(user_id)).into_iter().next() {
+        ic_cdk::spawn(async move {
+            if let Err(e) = deliver_tutor_reply(&delivery.message_id, ic_cdk::id()).await {
+                ic_cdk::println!("Queued reply {} failed: {}", delivery.message_id, e);
+            }
+        });
+    }
+}
+
+#[ic_cdk::query]
+fn get_ai_concurrency_status() -> AiConcurrencyStatus {
+    let caller = ic_cdk::caller();
+    let limit = max_concurrent_ai(caller);
+    let operations = ai_operations_in_flight(caller);
+    AiConcurrencyStatus {
+        plan: user_plan(caller),
+        in_flight: operations.len() as u32,
+        can_send: limit.is_none_or(|limit| operations.len() < limit as usize),
+        limit,
+        operations,
+        queued_replies: queued_deliveries(Some(caller)).len() as u32,
+    }
+}
+
+// --- Session Archival ---
+//
+// Active sessions with no activity for the configured number of days are archived by the
+// heartbeat. Each one gets a closing summary of its recent messages and the owner is
+// notified. Archived sessions keep their messages and can be reopened.
+
+const SESSION_ARCHIVAL_JOB_INTERVAL_NS: u64 = 60 * 60 * 1_000_000_000;
+// Sessions archived per run, each of which costs one summary call
+const SESSION_ARCHIVAL_BATCH_SIZE: usize = 10;
+const CLOSING_SUMMARY_MESSAGES: usize = 30;
+
+fn session_last_activity(session: &ChatSession) -> u64 {
+    let last_message = CHAT_MESSAGES.with(|messages| {
+        messages.borrow().get(&session.id).and_then(|list| list.0.last().map(|m| m.timestamp))
+    });
+    session.updated_at.max(last_message.unwrap_or(0))
+}
+
+fn archive_idle_sessions(now: u64) {
+    let archival = get_config().session_archival;
+    if !archival.enabled {
+        return;
+    }
+    let cutoff = now.saturating_sub(archival.idle_days as u64 * NANOS_PER_DAY);
+    let idle: Vec<ChatSession> = CHAT_SESSIONS.with(|sessions| {
+        sessions.borrow().iter()
+            .map(|(_, session)| session)
+            .filter(|session| session.status == "active" && session.updated_at < cutoff && session_last_activity(session) < cutoff)
+            .take(SESSION_ARCHIVAL_BATCH_SIZE)
+            .collect()
+    });
+    
+    for mut session in idle {
+        session.status = "archived".to_string();
+        session.archived_at = Some(now);
+        CHAT_SESSIONS.with(|sessions| sessions.borrow_mut().insert(session.id.clone(), session.clone()));
+        ic_cdk::spawn(close_archived_session(session, archival.idle_days));
+    }
+}
+
+async fn close_archived_session(session: ChatSession, idle_days: u32) {
+    let mut recent: Vec<ChatMessage> = CHAT_MESSAGES.with(|messages| {
+        messages.borrow().get(&session.id).map(|list| list.0).unwrap_or_default()
+    });
+    recent.retain(|m| m.delivery_status == "delivered");
+    recent.drain(..recent.len().saturating_sub(CLOSING_SUMMARY_MESSAGES));
+    
+    if !recent.is_empty() {
+        let summary = summarize_pruned_messages(session.user_id, session.summary.clone(), &recent).await;
+        // Leave it alone if the student reopened or deleted the session in the meantime
+        CHAT_SESSIONS.with(|sessions| {
+            let mut sessions = sessions.borrow_mut();
+            if let Some(mut current) = sessions.get(&session.id).filter(|s| s.status == "archived") {
+                current.summary = Some(summary);
+                sessions.insert(current.id.clone(), current);
+            }
+        });
+    }
+    
+    notify_user(
+        session.user_id,
+        "session_archived",
+        "system",
+        format!("Your session \"{}\" was archived after {} days without activity. You can reopen it from your sessions.", session.topic, idle_days),
+        None,
+    );
+}
+
+#[ic_cdk::update]
+fn reopen_chat_session(session_id: String) -> Result<ChatSession, String> {
+    let mut session = owned_session(&session_id, ic_cdk::caller())?;
+    if session.status != "archived" {
+        return Err("Only archived sessions can be reopened".to_string());
+    }
+    ensure_session_writable(&session)?;
+    session.status = "active".to_string();
+    session.archived_at = None;
+    session.updated_at = ic_cdk::api::time();
+    CHAT_SESSIONS.with(|sessions| sessions.borrow_mut().insert(session_id, session.clone()));
+    Ok(session)
+}
+
+#[ic_cdk::update]
+fn set_session_archival_config_admin(session_archival: SessionArchivalConfig) -> Result<CanisterConfig, String> {
+    require(ic_cdk::caller(), Permission::ManageSystem)?;
+    if session_archival.idle_days == 0 {
+        return Err("Sessions must be idle for at least one day before archiving".to_string());
+    }
+    update_config(|config| {
+        config.session_archival = session_archival;
+        Ok(())
+    })
+}
+
+// --- Undo ---
+//
+// Deleting a tutor or chat session, leaving a group and removing a connection take effect
+// right away, but what was removed is kept in UNDO_QUEUE for UNDO_WINDOW_NS so the user can
+// reverse it. The heartbeat finalizes expired actions, running any cleanup that was held back.
+
+const UNDO_WINDOW_NS: u64 = 5 * 60 * 1_000_000_000;
+const UNDO_JOB_INTERVAL_NS: u64 = 60 * 1_000_000_000;
+
+fn stage_undo(user_id: Principal, action: &str, target_id: &str, description: String, snapshot: UndoSnapshot) -> UndoAction {
+    let id = next_id("undo_action");
+    let now = ic_cdk::api::time();
+    let action = UndoAction {
+        id,
+        user_id,
+        action: action.to_string(),
+        target_id: target_id.to_string(),
+        description,
+        created_at: now,
+        expires_at: now + UNDO_WINDOW_NS,
+    };
+    UNDO_QUEUE.with(|queue| queue.borrow_mut().insert(id, StagedAction { action: action.clone(), snapshot }));
+    action
+}
+
+// Cleanup that can't be reversed, deferred until the action can no longer be undone
+fn finalize_staged_action(staged: StagedAction) {
+    if let UndoSnapshot::Tutor(tutor) = staged.snapshot {
+        remove_entity_tags("tutor", &tutor.public_id);
+        remove_knowledge_base_links(tutor.id);
+        remove_persona_evaluations(tutor.id);
+    }
+}
+
+fn finalize_expired_undo_actions(now: u64) {
+    let expired: Vec<u64> = UNDO_QUEUE.with(|queue| {
+        queue.borrow().iter()
+            .filter(|(_, staged)| staged.action.expires_at <= now)
+            .map(|(id, _)| id)
+            .take(RETENTION_BATCH_SIZE)
+            .collect()
+    });
+    for id in expired {
+        if let Some(staged) = UNDO_QUEUE.with(|queue| queue.borrow_mut().remove(&id)) {
+            finalize_staged_action(staged);
+        }
+    }
+}
+
+fn restore_snapshot(user_id: Principal, snapshot: UndoSnapshot) -> Result<(), String> {
+    match snapshot {
+        UndoSnapshot::Tutor(tutor) => {
+            cache::store_tutor(tutor.id, *tutor);
+        }
+        UndoSnapshot::ChatSession { session, messages, deliveries } => {
+            let bytes: u64 = messages.iter().map(chat_message_bytes).sum();
+            record_storage_change(user_id, "messages", bytes as i64);
+            if !messages.is_empty() {
+                certify::store_messages(user_id, &session.id, ChatMessageList(messages));
+            }
+            PENDING_DELIVERIES.with(|stored| {
+                let mut stored = stored.borrow_mut();
+                for delivery in deliveries {
+                    stored.insert(delivery.message_id.clone(), delivery);
+                }
+            });
+            CHAT_SESSIONS.with(|sessions| sessions.borrow_mut().insert(session.id.clone(), *session));
+        }
+        UndoSnapshot::GroupMembership(membership) => {
+            STUDY_GROUPS.with(|groups| groups.borrow().get(&membership.group_id)).ok_or("Study group not found.")?;
+            if active_group_membership(membership.group_id, user_id).is_some() {
+                return Err("You have already rejoined this group".to_string());
+            }
+            GROUP_MEMBERSHIPS.with(|memberships| memberships.borrow_mut().insert(membership.id, membership));
+        }
+        UndoSnapshot::Connection(connection) => {
+            if are_connected(connection.user1_id, connection.user2_id) {
+                return Err("You are already connected again".to_string());
+            }
+            CONNECTIONS.with(|connections| connections.borrow_mut().insert(connection.id, connection));
+        }
+    }
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn undo(action_id: u64) -> Result<UndoAction, String> {
+    let caller = ic_cdk::caller();
+    let staged = UNDO_QUEUE.with(|queue| queue.borrow().get(&action_id))
+        .filter(|staged| staged.action.user_id == caller)
+        .ok_or("Nothing to undo for this action")?;
+    if staged.action.expires_at <= ic_cdk::api::time() {
+        return Err("The undo window for this action has closed".to_string());
+    }
+    
+    restore_snapshot(caller, staged.snapshot)?;
+    UNDO_QUEUE.with(|queue| queue.borrow_mut().remove(&action_id));
+    Ok(staged.action)
+}
+
+// Most recent first
+#[ic_cdk::query]
+fn get_undoable_actions() -> Vec<UndoAction> {
+    let caller = ic_cdk::caller();
+    let now = ic_cdk::api::time();
+    let mut actions: Vec<UndoAction> = UNDO_QUEUE.with(|queue| {
+        queue.borrow().iter()
+            .map(|(_, staged)| staged.action)
+            .filter(|action| action.user_id == caller && action.expires_at > now)
+            .collect()
+    });
+    actions.reverse();
+    actions
+}
+
+#[ic_cdk::update]
+fn leave_group(group_id: u64) -> Result<UndoAction, String> {
+    let caller = ic_cdk::caller();
+    let group = STUDY_GROUPS.with(|groups| groups.borrow().get(&group_id)).ok_or("Study group not found.")?;
+    let membership = ensure_group_member(group_id, caller)?;
+    if group.creator_id == caller {
+        return Err("Group creators can't leave their own group".to_string());
+    }
+    
+    GROUP_MEMBERSHIPS.with(|memberships| {
+        memberships.borrow_mut().insert(membership.id, GroupMembership { status: "inactive".to_string(), ..membership.clone() })
+    });
+    Ok(stage_undo(caller, "leave_group", &group_id.to_string(), format!("Left {}", group.name), UndoSnapshot::GroupMembership(membership)))
+}
+
+#[ic_cdk::update]
+fn remove_connection(connection_id: u64) -> Result<UndoAction, String> {
+    let caller = ic_cdk::caller();
+    let connection = CONNECTIONS.with(|connections| connections.borrow().get(&connection_id))
+        .filter(|c| c.user1_id == caller || c.user2_id == caller)
+        .ok_or("Connection not found.")?;
+    let other = if connection.user1_id == caller { connection.user2_id } else { connection.user1_id };
+    let name = cache::user(other).map(|u| u.username).unwrap_or_else(|| other.to_text());
+    
+    CONNECTIONS.with(|connections| connections.borrow_mut().remove(&connection_id));
+    Ok(stage_undo(caller, "remove_connection", &connection_id.to_string(), format!("Removed connection with {}", name), UndoSnapshot::Connection(connection)))
+}
+
+// --- Caches ---
+
+// Counters cover update calls since the last upgrade; reads inside queries don't persist
+#[ic_cdk::query]
+fn get_cache_stats_admin() -> Result<Vec<CacheStats>, String> {
+    require(ic_cdk::caller(), Permission::ManageSystem)?;
+    Ok(cache::stats())
+}
+
+#[ic_cdk::update]
+fn clear_caches_admin() -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    require(caller, Permission::ManageSystem)?;
+    cache::clear();
+    record_audit(caller, "clear_caches", None, "Cleared heap caches".to_string());
+    Ok(())
+}
+
+// --- Record Encoding ---
+
+const REENCODE_JOB_INTERVAL_NS: u64 = 60 * 1_000_000_000;
+const REENCODE_MAPS: [&str; 4] = ["users", "tutors", "chat_sessions", "chat_messages"];
+const MAX_ENCODING_BENCHMARK_SAMPLE: u32 = 200;
+
+fn encoding_progress(map_name: &str, now: u64) -> EncodingProgress {
+    REENCODE_PROGRESS.with(|progress| progress.borrow().get(&map_name.to_string()))
+        .filter(|p| p.version == codec::CURRENT_VERSION)
+        .unwrap_or(EncodingProgress {
+            map_name: map_name.to_string(),
+            version: codec::CURRENT_VERSION,
+            cursor: None,
+            rewritten: 0,
+            started_at: now,
+            completed_at: None,
+        })
+}
+
+// Reads the next batch after the stored cursor and writes it back, which stores it in the
+// current format. Returns the number of records rewritten.
+fn reencode_batch<K, V, M>(
+    map_name: &str,
+    map: &'static std::thread::LocalKey<RefCell<StableBTreeMap<K, V, M>>>,
+    limit: usize,
+    now: u64,
+) -> usize
+where
+    K: ic_stable_structures::Storable + Ord + Clone,
+    V: ic_stable_structures::Storable,
+    M: ic_stable_structures::Memory,
+{
+    let mut progress = encoding_progress(map_name, now);
+    if progress.completed_at.is_some() || limit == 0 {
+        return 0;
+    }
+    
+    let after = progress.cursor.as_ref().map(|cursor| K::from_bytes(std::borrow::Cow::Borrowed(cursor)));
+    let batch: Vec<(K, V)> = map.with(|map| {
+        let map = map.borrow();
+        match after {
+            Some(key) => map.range((std::ops::Bound::Excluded(key), std::ops::Bound::Unbounded)).take(limit).collect(),
+            None => map.iter().take(limit).collect(),
+        }
+    });
+    let rewritten = batch.len();
+    if let Some((last, _)) = batch.last() {
+        progress.cursor = Some(last.to_bytes().into_owned());
+    }
+    map.with(|map| {
+        let mut map = map.borrow_mut();
+        for (key, value) in batch {
+            map.insert(key, value);
+        }
+    });
+    
+    progress.rewritten += rewritten as u64;
+    if rewritten < limit {
+        progress.completed_at = Some(now);
+    }
+    REENCODE_PROGRESS.with(|stored| stored.borrow_mut().insert(map_name.to_string(), progress));
+    rewritten
+}
+
+// Cached copies are unaffected: the records are rewritten unchanged
+fn run_reencoding(now: u64) -> bool {
+    let mut budget = RETENTION_BATCH_SIZE;
+    budget -= reencode_batch("users", &USERS, budget, now);
+    budget -= reencode_batch("tutors", &TUTORS, budget, now);
+    budget -= reencode_batch("chat_sessions", &CHAT_SESSIONS, budget, now);
+    // Message lists hold whole conversations, so they go a few at a time
+    budget -= reencode_batch("chat_messages", &CHAT_MESSAGES, budget / 50, now);
+    budget < RETENTION_BATCH_SIZE
+}
+
+#[ic_cdk::query]
+fn get_encoding_status_admin() -> Result<EncodingStatus, String> {
+    require(ic_cdk::caller(), Permission::ManageSystem)?;
+    let now = ic_cdk::api::time();
+    Ok(EncodingStatus {
+        current_version: codec::CURRENT_VERSION,
+        legacy_reads: codec::legacy_reads(),
+        maps: REENCODE_MAPS.iter().map(|name| encoding_progress(name, now)).collect(),
+    })
+}
+
+fn instructions_for<R>(run: impl FnOnce() -> R) -> u64 {
+    let start = ic_cdk::api::performance_counter(0);
+    std::hint::black_box(run());
+    ic_cdk::api::performance_counter(0) - start
+}
+
+fn benchmark_encoding<T: serde::Serialize + serde::de::DeserializeOwned>(model: &str, records: &[T]) -> EncodingBenchmark {
+    let legacy: Vec<Vec<u8>> = records.iter().map(codec::legacy_encode).collect();
+    let compact: Vec<Vec<u8>> = records.iter().map(codec::encode).collect();
+    EncodingBenchmark {
+        model: model.to_string(),
+        records: records.len() as u64,
+        legacy_bytes: legacy.iter().map(|b| b.len() as u64).sum(),
+        compact_bytes: compact.iter().map(|b| b.len() as u64).sum(),
+        legacy_encode_instructions: instructions_for(|| records.iter().map(codec::legacy_encode).collect::<Vec<_>>()),
+        compact_encode_instructions: instructions_for(|| records.iter().map(codec::encode).collect::<Vec<_>>()),
+        legacy_decode_instructions: instructions_for(|| legacy.iter().map(|b| serde_cbor::from_slice::<T>(b).unwrap()).collect::<Vec<_>>()),
+        compact_decode_instructions: instructions_for(|| compact.iter().map(|b| codec::decode::<T>(b)).collect::<Vec<_>>()),
+    }
+}
+
+// Compares both formats on the first records of each hot map
+#[ic_cdk::query]
+fn benchmark_encoding_admin(sample_size: u32) -> Result<Vec<EncodingBenchmark>, String> {
+    require(ic_cdk::caller(), Permission::ManageSystem)?;
+    let sample = sample_size.clamp(1, MAX_ENCODING_BENCHMARK_SAMPLE) as usize;
+    let users: Vec<User> = USERS.with(|users| users.borrow().iter().take(sample).map(|(_, u)| u).collect());
+    let tutors: Vec<Tutor> = TUTORS.with(|tutors| tutors.borrow().iter().take(sample).map(|(_, t)| t).collect());
+    let sessions: Vec<ChatSession> = CHAT_SESSIONS.with(|sessions| sessions.borrow().iter().take(sample).map(|(_, s)| s).collect());
+    let messages: Vec<ChatMessageList> = CHAT_MESSAGES.with(|messages| messages.borrow().iter().take(sample).map(|(_, m)| m).collect());
+    Ok(vec![
+        benchmark_encoding("User", &users),
+        benchmark_encoding("Tutor", &tutors),
+        benchmark_encoding("ChatSession", &sessions),
+        benchmark_encoding("ChatMessageList", &messages),
+    ])
+}
+
+// --- Memory Layout ---
+
+#[ic_cdk::query]
+fn get_memory_layout_admin() -> Result<MemoryLayout, String> {
+    require(ic_cdk::caller(), Permission::ManageSystem)?;
+    Ok(state::memory_layout())
+}
+
+// --- Compaction ---
+
+const COMPACTION_JOB_INTERVAL_NS: u64 = 60 * 60 * 1_000_000_000;
+const MAX_COMPACTION_REPORTS: usize = 24;
+
+thread_local! {
+    // Next message list to look at; lists are walked in key order and the walk wraps around
+    static COMPACTION_CURSOR: RefCell<Option<String>> = const { RefCell::new(None) };
+    static COMPACTION_REPORTS: RefCell<Vec<CompactionRunReport>> = const { RefCell::new(Vec::new()) };
+}
+
+fn stored_bytes<T: ic_stable_structures::Storable>(value: &T) -> u64 {
+    value.to_bytes().len() as u64
+}
+
+// Failed replies that won't be retried, plus deliveries left behind by a deleted session
+fn compact_deliveries(now: u64, report: &mut CompactionRunReport) {
+    let config = get_config();
+    let cutoff = retention_cutoff(&config, "failed_replies", now);
+    let expired: Vec<PendingDelivery> = PENDING_DELIVERIES.with(|deliveries| {
+        deliveries.borrow().iter()
+            .map(|(_, d)| d)
+            .filter(|d| {
+                let orphaned = !CHAT_SESSIONS.with(|sessions| sessions.borrow().contains_key(&d.session_id));
+                let abandoned = d.status == "failed"
+                    && d.next_retry_at.is_none()
+                    && cutoff.is_some_and(|cutoff| d.created_at < cutoff)
+                    && !config.retention_holds.iter().any(|h| h.user_id == d.user_id);
+                orphaned || abandoned
+            })
+            .take(RETENTION_BATCH_SIZE)
+            .collect()
+    });
+    
+    for delivery in expired {
+        if let Some(removed) = PENDING_DELIVERIES.with(|deliveries| deliveries.borrow_mut().remove(&delivery.message_id)) {
+            report.reclaimed_bytes += stored_bytes(&removed);
+            report.deliveries_removed += 1;
+        }
+        let placeholder = CHAT_MESSAGES.with(|messages| messages.borrow().get(&delivery.session_id)).and_then(|mut list| {
+            let old_bytes = stored_bytes(&list);
+            let index = list.0.iter().position(|m| m.id == delivery.message_id && m.delivery_status == "failed")?;
+            let placeholder = list.0.remove(index);
+            report.reclaimed_bytes += old_bytes.saturating_sub(stored_bytes(&list));
+            certify::store_messages(delivery.user_id, &delivery.session_id, list);
+            Some(placeholder)
+        });
+        if let Some(placeholder) = placeholder {
+            record_storage_change(delivery.user_id, "messages", -(chat_message_bytes(&placeholder) as i64));
+            report.failed_replies_removed += 1;
+        }
+    }
+}
+
+// Message lists whose session is gone, or that pruning left empty
+fn compact_message_lists(report: &mut CompactionRunReport) {
+    let after = COMPACTION_CURSOR.with(|cursor| cursor.borrow().clone());
+    let keys: Vec<String> = CHAT_MESSAGES.with(|messages| {
+        let messages = messages.borrow();
+        match after {
+            Some(key) => messages.keys_range((std::ops::Bound::Excluded(key), std::ops::Bound::Unbounded)).take(RETENTION_BATCH_SIZE).collect(),
+            None => messages.keys().take(RETENTION_BATCH_SIZE).collect(),
+        }
+    });
+    let wrapped = keys.len() < RETENTION_BATCH_SIZE;
+    COMPACTION_CURSOR.with(|cursor| *cursor.borrow_mut() = if wrapped { None } else { keys.last().cloned() });
+    
+    for session_id in keys {
+        report.lists_scanned += 1;
+        let owner = CHAT_SESSIONS.with(|sessions| sessions.borrow().get(&session_id)).map(|s| s.user_id);
+        let orphaned = owner.is_none();
+        let removable = orphaned || CHAT_MESSAGES.with(|messages| messages.borrow().get(&session_id)).is_some_and(|list| list.0.is_empty());
+        if !removable {
+            continue;
+        }
+        // An orphaned list has no owner left to rehash; its session can no longer be read anyway
+        let removed = match owner {
+            Some(owner) => certify::remove_messages(owner, &session_id),
+            None => CHAT_MESSAGES.with(|messages| messages.borrow_mut().remove(&session_id)),
+        };
+        if let Some(list) = removed {
+            report.reclaimed_bytes += stored_bytes(&list);
+            if orphaned {
+                report.orphaned_lists_removed += 1;
+            } else {
+                report.empty_lists_removed += 1;
+            }
+        }
+    }
+}
+
+fn run_compaction(now: u64) -> CompactionRunReport {
+    let mut report = CompactionRunReport { started_at: now, ..Default::default() };
+    compact_deliveries(now, &mut report);
+    compact_message_lists(&mut report);
+    
+    COMPACTION_REPORTS.with(|reports| {
+        let mut reports = reports.borrow_mut();
+        reports.push(report.clone());
+        if reports.len() > MAX_COMPACTION_REPORTS {
+            reports.remove(0);
+        }
+    });
+    report
+}
+
+#[ic_cdk::update]
+fn run_compaction_now_admin() -> Result<CompactionRunReport, String> {
+    require(ic_cdk::caller(), Permission::ManageSystem)?;
+    Ok(run_compaction(ic_cdk::api::time()))
+}
+
+// Most recent first; runs since the last upgrade
+#[ic_cdk::query]
+fn get_compaction_reports_admin() -> Result<Vec<CompactionRunReport>, String> {
+    require(ic_cdk::caller(), Permission::ManageSystem)?;
+    Ok(COMPACTION_REPORTS.with(|reports| reports.borrow().iter().rev().cloned().collect()))
+}
+
+// --- Certified Reads ---
+
+const MAX_REHASH_BATCH: u32 = 200;
+
+#[ic_cdk::init]
+fn init(args: Option<InitArgs>) {
+    bootstrap_admins(args);
+}
+
+#[ic_cdk::post_upgrade]
+fn post_upgrade(args: Option<InitArgs>) {
+    certify::restore_certified_data();
+    bootstrap_admins(args);
+    index_email_exchanges();
+}
+
+// The full message list with a proof linking it to the certified root; see certify.rs for
+// how the client recomputes each hash
+#[ic_cdk::query]
+fn get_certified_session_messages(session_id: String) -> Result<CertifiedSessionMessages, String> {
+    let session = owned_session(&session_id, ic_cdk::caller())?;
+    let messages = CHAT_MESSAGES.with(|messages| messages.borrow().get(&session_id)).map(|list| list.0).unwrap_or_default();
+    ensure_fits(&messages, "Certified reads return the whole session; use get_session_messages to page through it uncertified.")?;
+    Ok(CertifiedSessionMessages {
+        messages,
+        proof: certify::user_proof(session.user_id),
+    })
+}
+
+#[ic_cdk::query]
+fn get_my_data_proof() -> UserDataProof {
+    certify::user_proof(ic_cdk::caller())
+}
+
+// Hashes sessions whose messages were stored before certification; returns how many it did
+#[ic_cdk::update]
+fn rehash_user_data_admin(limit: u32) -> Result<u64, String> {
+    let caller = ic_cdk::caller();
+    require(caller, Permission::ManageSystem)?;
+    
+    let limit = limit.clamp(1, MAX_REHASH_BATCH) as usize;
+    let pending: Vec<(Principal, String)> = CHAT_SESSIONS.with(|sessions| {
+        sessions.borrow().iter()
+            .filter(|(id, s)| {
+                CHAT_MESSAGES.with(|messages| messages.borrow().contains_key(id))
+                    && !certify::user_proof_has_session(s.user_id, id)
+            })
+            .map(|(id, s)| (s.user_id, id))
+            .take(limit)
+            .collect()
+    });
+    for (user_id, session_id) in &pending {
+        certify::rehash_session(*user_id, session_id);
+    }
+    
+    record_audit(caller, "rehash_user_data", None, format!("rehashed {} sessions", pending.len()));
+    Ok(pending.len() as u64)
+}
+
+// --- Co-learning ---
+
+// Tells the model there are two students and which one it is answering
+fn co_learning_note(session: &ChatSession, requester: Principal) -> Option<String> {
+    let co_learner = session.active_co_learner()?;
+    let owner = learner_name(session.user_id).unwrap_or_else(|| "the first student".to_string());
+    let guest = learner_name(co_learner.user_id).unwrap_or_else(|| "the second student".to_string());
+    let speaker = if requester == co_learner.user_id { &guest } else { &owner };
+    Some(format!(
+        "This is a shared session with two students, {} and {}. The latest message is from {}; answer them by name and keep the other student included.",
+        owner, guest, speaker
+    ))
+}
+
+fn store_session(mut session: ChatSession) -> ChatSession {
+    session.updated_at = ic_cdk::api::time();
+    CHAT_SESSIONS.with(|sessions| sessions.borrow_mut().insert(session.id.clone(), session.clone()));
+    session
+}
+
+#[ic_cdk::update]
+fn invite_co_learner(session_id: String, user_id: Principal) -> Result<ChatSession, String> {
+    let caller = ic_cdk::caller();
+    let mut session = owned_session(&session_id, caller)?;
+    if session.status != "active" {
+        return Err("Only active sessions can be shared".to_string());
+    }
+    if user_id == caller {
+        return Err("You are already in this session".to_string());
+    }
+    if !are_connected(caller, user_id) {
+        return Err("You can only invite your connections".to_string());
+    }
+    if session.co_learners.iter().any(|c| c.status == "invited" || c.status == "active") {
+        return Err("This session already has a co-learner".to_string());
+    }
+    
+    session.co_learners.retain(|c| c.user_id != user_id);
+    session.co_learners.push(CoLearner {
+        user_id,
+        status: "invited".to_string(),
+        invited_at: ic_cdk::api::time(),
+        joined_at: None,
+        left_at: None,
+    });
+    let session = store_session(session);
+    
+    let inviter = learner_name(caller).unwrap_or_else(|| "A connection".to_string());
+    notify_user(
+        user_id,
+        "co_learning_invite",
+        "tutor",
+        format!("{} invited you to learn \"{}\" together", inviter, session.topic),
+        None,
+    );
+    Ok(session)
+}
+
+#[ic_cdk::update]
+fn respond_to_co_learning_invite(session_id: String, accept: bool) -> Result<ChatSession, String> {
+    let caller = ic_cdk::caller();
+    let mut session = CHAT_SESSIONS.with(|sessions| sessions.borrow().get(&session_id))
+        .ok_or("Session not found")?;
+    if accept && session.status != "active" {
+        return Err("This session is no longer active".to_string());
+    }
+    let now = ic_cdk::api::time();
+    let invite = session.co_learners.iter_mut()
+        .find(|c| c.user_id == caller && c.status == "invited")
+        .ok_or("Invitation not found")?;
+    
+    if accept {
+        invite.status = "active".to_string();
+        invite.joined_at = Some(now);
+    } else {
+        invite.status = "declined".to_string();
+    }
+    let session = store_session(session);
+    
+    if accept {
+        let progress_id = next_id("learning_progress");
+        LEARNING_PROGRESS.with(|progress| {
+            progress.borrow_mut().insert(progress_id, LearningProgress {
+                id: progress_id,
+                user_id: caller,
+                session_id: session_id.parse::<u64>().unwrap_or(0),
+                course_id: 1, // Placeholder
+                progress_percentage: 0.0,
+                current_module_id: None,
+                current_subtopic: None,
+                last_activity: now,
+                created_at: now,
+                updated_at: now,
+            });
+        });
+    }
+    
+    let guest = learner_name(caller).unwrap_or_else(|| "Your connection".to_string());
+    notify_user(
+        session.user_id,
+        "info",
+        "tutor",
+        format!("{} {} your invitation to \"{}\"", guest, if accept { "joined" } else { "declined" }, session.topic),
+        None,
+    );
+    Ok(session)
+}
+
+// Either participant can step out; the session itself stays open
+#[ic_cdk::update]
+fn leave_co_learning_session(session_id: String) -> Result<ChatSession, String> {
+    let caller = ic_cdk::caller();
+    let mut session = participant_session(&session_id, caller)?;
+    let now = ic_cdk::api::time();
+    
+    let other = if session.user_id == caller {
+        let co_learner = session.active_co_learner().ok_or("Only shared sessions can be left")?.user_id;
+        session.owner_left_at = Some(now);
+        co_learner
+    } else {
+        if let Some(c) = session.co_learners.iter_mut().find(|c| c.user_id == caller && c.status == "active") {
+            c.status = "left".to_string();
+            c.left_at = Some(now);
+        }
+        // Nobody would be left to chat, so hand the session back to its owner
+        session.owner_left_at = None;
+        session.user_id
+    };
+    let session = store_session(session);
+    
+    let name = learner_name(caller).unwrap_or_else(|| "Your co-learner".to_string());
+    notify_user(other, "info", "tutor", format!("{} left \"{}\"", name, session.topic), None);
+    Ok(session)
+}
+
+#[ic_cdk::update]
+fn rejoin_co_learning_session(session_id: String) -> Result<ChatSession, String> {
+    let mut session = owned_session(&session_id, ic_cdk::caller())?;
+    if session.owner_left_at.is_none() {
+        return Err("You haven't left this session".to_string());
+    }
+    session.owner_left_at = None;
+    Ok(store_session(session))
+}
+
+// Sessions the caller joined or was invited to by someone else
+#[ic_cdk::query]
+fn get_co_learning_sessions() -> Result<Vec<ChatSession>, String> {
+    let caller = ic_cdk::caller();
+    Ok(CHAT_SESSIONS.with(|sessions| {
+        sessions.borrow().values()
+            .filter(|s| s.co_learners.iter().any(|c| c.user_id == caller && (c.status == "invited" || c.status == "active")))
+            .collect()
+    }))
+}
+
+// --- Shared Knowledge Bases ---
+//
+// A tutor can use another of the owner's tutors' knowledge bases by reference. Files stay
+// with their source tutor and count once against storage. Deleting a file that links still
+// include only hides it from the source; it is removed once no link references it.
+
+fn kb_ref_count(file: &KnowledgeBaseFile) -> usize {
+    KNOWLEDGE_BASE_LINKS.with(|links| links.borrow().values().filter(|l| l.includes(file)).count())
+}
+
+// Removes files deleted by their owner that no link references any more
+fn release_unreferenced_files(source_tutor_id: u64) {
+    let released: Vec<KnowledgeBaseFile> = KNOWLEDGE_BASE_FILES.with(|files| {
+        files.borrow().values()
+            .filter(|f| f.tutor_id == source_tutor_id && f.deleted_at.is_some())
+            .collect()
+    });
+    for file in released.into_iter().filter(|f| kb_ref_count(f) == 0) {
+        KNOWLEDGE_BASE_FILES.with(|files| files.borrow_mut().remove(&file.id));
+        remove_kb_file_versions(file.id);
+        record_storage_change(file.user_id, "knowledge_base", -(file.file_size as i64));
+    }
+}
+
+// Links that belong to a tutor whose deletion can no longer be undone
+fn remove_knowledge_base_links(tutor_id: u64) {
+    let removed: Vec<KnowledgeBaseLink> = KNOWLEDGE_BASE_LINKS.with(|links| {
+        links.borrow().values().filter(|l| l.tutor_id == tutor_id).collect()
+    });
+    for link in removed {
+        KNOWLEDGE_BASE_LINKS.with(|links| links.borrow_mut().remove(&link.id));
+        release_unreferenced_files(link.source_tutor_id);
+    }
+}
+
+fn tutor_uses_kb_file(tutor_key: u64, file: &KnowledgeBaseFile) -> bool {
+    if file.tutor_id == tutor_key {
+        return file.deleted_at.is_none();
+    }
+    KNOWLEDGE_BASE_LINKS.with(|links| links.borrow().values().any(|l| l.tutor_id == tutor_key && l.includes(file)))
+}
+
+fn owned_kb_link(link_id: u64, caller: Principal) -> Result<KnowledgeBaseLink, String> {
+    KNOWLEDGE_BASE_LINKS.with(|links| links.borrow().get(&link_id))
+        .filter(|link| link.user_id == caller)
+        .ok_or_else(|| "Knowledge base link not found".to_string())
+}
+
+#[ic_cdk::update]
+fn link_knowledge_base(tutor_id: String, source_tutor_id: String) -> Result<KnowledgeBaseLink, String> {
+    let caller = ic_cdk::caller();
+    let (tutor_key, _) = owned_tutor(&tutor_id, caller)?;
+    let (source_key, _) = owned_tutor(&source_tutor_id, caller)?;
+    if tutor_key == source_key {
+        return Err("A tutor already uses its own knowledge base".to_string());
+    }
+    let exists = KNOWLEDGE_BASE_LINKS.with(|links| {
+        links.borrow().values().any(|l| l.tutor_id == tutor_key && l.source_tutor_id == source_key)
+    });
+    if exists {
+        return Err("This knowledge base is already linked".to_string());
+    }
+    
+    let now = ic_cdk::api::time();
+    let link = KnowledgeBaseLink {
+        id: next_id("knowledge_base_link"),
+        tutor_id: tutor_key,
+        source_tutor_id: source_key,
+        user_id: caller,
+        excluded_file_ids: Vec::new(),
+        created_at: now,
+        updated_at: now,
+    };
+    KNOWLEDGE_BASE_LINKS.with(|links| links.borrow_mut().insert(link.id, link.clone()));
+    Ok(link)
+}
+
+#[ic_cdk::update]
+fn unlink_knowledge_base(link_id: u64) -> Result<(), String> {
+    let link = owned_kb_link(link_id, ic_cdk::caller())?;
+    KNOWLEDGE_BASE_LINKS.with(|links| links.borrow_mut().remove(&link_id));
+    release_unreferenced_files(link.source_tutor_id);
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn set_linked_file_included(link_id: u64, file_id: u64, included: bool) -> Result<KnowledgeBaseLink, String> {
+    let mut link = owned_kb_link(link_id, ic_cdk::caller())?;
+    let file = KNOWLEDGE_BASE_FILES.with(|files| files.borrow().get(&file_id))
+        .filter(|f| f.tutor_id == link.source_tutor_id)
+        .ok_or("Knowledge base file not found")?;
+    
+    link.excluded_file_ids.retain(|id| *id != file.id);
+    if !included {
+        link.excluded_file_ids.push(file.id);
+    }
+    link.updated_at = ic_cdk::api::time();
+    KNOWLEDGE_BASE_LINKS.with(|links| links.borrow_mut().insert(link_id, link.clone()));
+    if !included {
+        release_unreferenced_files(link.source_tutor_id);
+    }
+    Ok(link)
+}
+
+#[ic_cdk::query]
+fn get_knowledge_base_links(tutor_id: String) -> Result<Vec<KnowledgeBaseLink>, String> {
+    let (tutor_key, _) = owned_tutor(&tutor_id, ic_cdk::caller())?;
+    Ok(KNOWLEDGE_BASE_LINKS.with(|links| {
+        links.borrow().values().filter(|l| l.tutor_id == tutor_key).collect()
+    }))
+}
+
+// Everything the tutor answers from: its own files plus the included files of linked tutors
+#[ic_cdk::query]
+fn get_tutor_knowledge_base(tutor_id: String) -> Result<Vec<TutorKnowledgeBaseEntry>, String> {
+    let (tutor_key, tutor) = owned_tutor(&tutor_id, ic_cdk::caller())?;
+    let pinned = |file: &KnowledgeBaseFile| {
+        tutor.kb_version_pins.iter().find(|p| p.file_id == file.id).map_or(file.version, |p| p.version)
+    };
+    let links: Vec<KnowledgeBaseLink> = KNOWLEDGE_BASE_LINKS.with(|links| {
+        links.borrow().values().filter(|l| l.tutor_id == tutor_key).collect()
+    });
+    let source_ids: HashMap<u64, String> = TUTORS.with(|tutors| {
+        let tutors = tutors.borrow();
+        links.iter().filter_map(|l| tutors.get(&l.source_tutor_id).map(|t| (l.source_tutor_id, t.public_id))).collect()
+    });
+    
+    Ok(KNOWLEDGE_BASE_FILES.with(|files| {
+        files.borrow().values()
+            .filter_map(|file| {
+                if file.tutor_id == tutor_key {
+                    return file.deleted_at.is_none().then(|| TutorKnowledgeBaseEntry { version: pinned(&file), file, link_id: None, source_tutor_id: None });
+                }
+                let link = links.iter().find(|l| l.includes(&file))?;
+                Some(TutorKnowledgeBaseEntry {
+                    version: pinned(&file),
+                    link_id: Some(link.id),
+                    source_tutor_id: source_ids.get(&file.tutor_id).cloned(),
+                    file,
+                })
+            })
+            .collect()
+    }))
+}
+
+// --- Knowledge Base Versions ---
+//
+// Re-uploading a file records a new version with its chunk hashes. The indexer keeps the
+// chunks of each file's current version plus any version a tutor is pinned to; uploads and
+// pin changes return a plan listing only the chunks to embed and the ones to drop.
+
+const MAX_KB_CHUNKS: usize = 20_000;
+const MAX_KB_CHUNK_HASH_LEN: usize = 128;
+// Older versions are pruned unless a tutor is pinned to them
+const MAX_KB_FILE_VERSIONS: usize = 20;
+
+fn kb_version_key(file_id: u64, version: u32) -> String {
+    format!("{:020}:{:010}", file_id, version)
+}
+
+fn kb_file_versions(file_id: u64) -> Vec<KnowledgeBaseFileVersion> {
+    KNOWLEDGE_BASE_FILE_VERSIONS.with(|versions| {
+        versions.borrow().range(kb_version_key(file_id, 0)..=kb_version_key(file_id, u32::MAX)).map(|(_, v)| v).collect()
+    })
+}
+
+fn kb_file_version(file_id: u64, version: u32) -> Option<KnowledgeBaseFileVersion> {
+    KNOWLEDGE_BASE_FILE_VERSIONS.with(|versions| versions.borrow().get(&kb_version_key(file_id, version)))
+}
+
+fn remove_kb_file_versions(file_id: u64) {
+    for version in kb_file_versions(file_id) {
+        KNOWLEDGE_BASE_FILE_VERSIONS.with(|versions| versions.borrow_mut().remove(&kb_version_key(file_id, version.version)));
+    }
+}
+
+fn validate_chunk_hashes(hashes: &[String]) -> Result<(), String> {
+    if hashes.is_empty() {
+        return Err("At least one chunk hash is required".to_string());
+    }
+    if hashes.len() > MAX_KB_CHUNKS {
+        return Err(format!("A file can have at most {} chunks", MAX_KB_CHUNKS));
+    }
+    if hashes.iter().any(|h| h.is_empty() || h.len() > MAX_KB_CHUNK_HASH_LEN) {
+        return Err(format!("Chunk hashes must be 1-{} characters", MAX_KB_CHUNK_HASH_LEN));
+    }
+    Ok(())
+}
+
+// Versions of a file that tutors are pinned to
+fn pinned_kb_versions(file_id: u64) -> Vec<u32> {
+    TUTORS.with(|tutors| {
+        tutors.borrow().values()
+            .flat_map(|t| t.kb_version_pins.into_iter().filter(|p| p.file_id == file_id).map(|p| p.version))
+            .collect()
+    })
+}
+
+// Chunk hashes the indexer should hold for a file: its current version and every pinned one
+fn indexed_chunk_hashes(file: &KnowledgeBaseFile) -> std::collections::HashSet<String> {
+    let mut versions = pinned_kb_versions(file.id);
+    versions.push(file.version);
+    versions.sort_unstable();
+    versions.dedup();
+    versions.into_iter()
+        .filter_map(|v| kb_file_version(file.id, v))
+        .flat_map(|v| v.chunk_hashes)
+        .collect()
+}
+
+fn reindex_plan(file_id: u64, target: &KnowledgeBaseFileVersion, before: &std::collections::HashSet<String>, after: &std::collections::HashSet<String>) -> KnowledgeBaseReindexPlan {
+    let embed_chunk_indexes: Vec<u32> = target.chunk_hashes.iter().enumerate()
+        .filter(|(_, hash)| !before.contains(*hash))
+        .map(|(index, _)| index as u32)
+        .collect();
+    let mut drop_chunk_hashes: Vec<String> = before.difference(after).cloned().collect();
+    drop_chunk_hashes.sort();
+    KnowledgeBaseReindexPlan {
+        file_id,
+        version: target.version,
+        unchanged_chunks: (target.chunk_hashes.len() - embed_chunk_indexes.len()) as u32,
+        embed_chunk_indexes,
+        drop_chunk_hashes,
+    }
+}
+
+// Records the next version of a file and prunes old unpinned ones; returns the updated file
+fn store_kb_file_version(mut file: KnowledgeBaseFile, file_size: u64, chunk_hashes: Vec<String>) -> KnowledgeBaseFile {
+    let now = ic_cdk::api::time();
+    file.version += 1;
+    file.file_size = file_size;
+    file.chunks_processed = chunk_hashes.len() as u32;
+    file.updated_at = now;
+    let version = KnowledgeBaseFileVersion {
+        file_id: file.id,
+        version: file.version,
+        file_size,
+        chunk_hashes,
+        created_at: now,
+    };
+    KNOWLEDGE_BASE_FILE_VERSIONS.with(|versions| versions.borrow_mut().insert(kb_version_key(file.id, file.version), version));
+    
+    let pinned = pinned_kb_versions(file.id);
+    let versions = kb_file_versions(file.id);
+    let excess = versions.len().saturating_sub(MAX_KB_FILE_VERSIONS);
+    for old in versions.into_iter().filter(|v| v.version != file.version && !pinned.contains(&v.version)).take(excess) {
+        KNOWLEDGE_BASE_FILE_VERSIONS.with(|versions| versions.borrow_mut().remove(&kb_version_key(file.id, old.version)));
+    }
+    file
+}
+
+// Re-upload of an existing file. Files added before versioning have no recorded chunks, so
+// their first versioned upload embeds everything.
+#[ic_cdk::update]
+fn upload_knowledge_base_file_version(file_id: u64, file_size: u64, chunk_hashes: Vec<String>, processing_time: f64) -> Result<KnowledgeBaseReindexPlan, String> {
+    let caller = ic_cdk::caller();
+    let file = owned_kb_file(file_id, caller)?;
+    if file.deleted_at.is_some() {
+        return Err("Knowledge base file not found".to_string());
+    }
+    validate_chunk_hashes(&chunk_hashes)?;
+    check_storage_quota(caller, file_size.saturating_sub(file.file_size))?;
+    
+    let before = indexed_chunk_hashes(&file);
+    let previous_size = file.file_size;
+    let mut file = store_kb_file_version(file, file_size, chunk_hashes);
+    file.processing_time = processing_time;
+    file.status = "completed".to_string();
+    file.error_message = None;
+    KNOWLEDGE_BASE_FILES.with(|files| files.borrow_mut().insert(file_id, file.clone()));
+    record_storage_change(caller, "knowledge_base", file_size as i64 - previous_size as i64);
+    
+    let after = indexed_chunk_hashes(&file);
+    let current = kb_file_version(file_id, file.version).ok_or("Version not found")?;
+    Ok(reindex_plan(file_id, &current, &before, &after))
+}
+
+#[ic_cdk::query]
+fn get_knowledge_base_file_versions(file_id: u64) -> Result<Vec<KnowledgeBaseFileVersion>, String> {
+    owned_kb_file(file_id, ic_cdk::caller())?;
+    let versions = kb_file_versions(file_id);
+    ensure_fits(&versions, "Old versions are pruned automatically; unpin versions you no longer need.")?;
+    Ok(versions)
+}
+
+// The tutor must own the file or include it through a link. Pass no version to follow the
+// file's current version again.
+#[ic_cdk::update]
+fn pin_knowledge_base_file_version(tutor_id: String, file_id: u64, version: Option<u32>) -> Result<KnowledgeBaseReindexPlan, String> {
+    let caller = ic_cdk::caller();
+    let (tutor_key, mut tutor) = owned_tutor(&tutor_id, caller)?;
+    let file = KNOWLEDGE_BASE_FILES.with(|files| files.borrow().get(&file_id))
+        .filter(|f| f.user_id == caller)
+        .ok_or("Knowledge base file not found")?;
+    if !tutor_uses_kb_file(tutor_key, &file) {
+        return Err("This tutor doesn't use that file".to_string());
+    }
+    let target = kb_file_version(file_id, version.unwrap_or(file.version)).ok_or("Version not found")?;
+    
+    let before = indexed_chunk_hashes(&file);
+    tutor.kb_version_pins.retain(|p| p.file_id != file_id);
+    if version.is_some() {
+        tutor.kb_version_pins.push(KnowledgeBaseVersionPin { file_id, version: target.version });
+    }
+    tutor.updated_at = ic_cdk::api::time();
+    cache::store_tutor(tutor_key, tutor);
+    let after = indexed_chunk_hashes(&file);
+    
+    Ok(reindex_plan(file_id, &target, &before, &after))
+}
+
+// --- Knowledge Base Citations ---
+//
+// Retrieval runs on the client, which sends the passages it found along with the student's
+// message. They are numbered in the prompt, and the reply keeps a citation for each [n] the
+// model used, so the frontend can link back to the document and chunk range.
+
+const MAX_REPLY_SOURCES: usize = 8;
+const MAX_REPLY_SOURCE_CHARS: usize = 2_000;
+
+// Checks each passage against the session tutor's knowledge base and records the document
+// version it came from
+fn resolve_reply_sources(session: &ChatSession, excerpts: Vec<KnowledgeBaseExcerpt>) -> Result<Vec<ReplySource>, String> {
+    if excerpts.is_empty() {
+        return Ok(Vec::new());
+    }
+    if excerpts.len() > MAX_REPLY_SOURCES {
+        return Err(format!("At most {} knowledge base passages can be sent with a message", MAX_REPLY_SOURCES));
+    }
+    let (tutor_key, tutor) = cache::tutor_by_public_id(&session.tutor_id).ok_or("Tutor not found")?;
+    
+    excerpts.into_iter().enumerate().map(|(index, excerpt)| {
+        let file = KNOWLEDGE_BASE_FILES.with(|files| files.borrow().get(&excerpt.file_id))
+            .filter(|file| tutor_uses_kb_file(tutor_key, file))
+            .ok_or_else(|| format!("Knowledge base file {} is not available to this tutor", excerpt.file_id))?;
+        if excerpt.chunk_start > excerpt.chunk_end {
+            return Err("A passage's chunk range must not end before it starts".to_string());
+        }
+        let version = tutor.kb_version_pins.iter().find(|p| p.file_id == file.id).map_or(file.version, |p| p.version);
+        Ok(ReplySource {
+            citation: MessageCitation {
+                marker: index as u32 + 1,
+                file_id: file.id,
+                file_name: file.file_name,
+                chunk_start: excerpt.chunk_start,
+                chunk_end: excerpt.chunk_end,
+                version,
+            },
+            text: trim_to_length(excerpt.text.trim(), MAX_REPLY_SOURCE_CHARS),
+        })
+    }).collect()
+}
+
+fn set_delivery_sources(message_id: &str, sources: Vec<ReplySource>) {
+    if sources.is_empty() {
+        return;
+    }
+    PENDING_DELIVERIES.with(|deliveries| {
+        let mut deliveries = deliveries.borrow_mut();
+        if let Some(mut delivery) = deliveries.get(&message_id.to_string()) {
+            delivery.sources = sources;
+            deliveries.insert(message_id.to_string(), delivery);
+        }
+    });
+}
+
+fn reply_sources_prompt(sources: &[ReplySource]) -> Option<String> {
+    if sources.is_empty() {
+        return None;
+    }
+    let passages: Vec<String> = sources.iter()
+        .map(|s| format!("[{}] {}: {}", s.citation.marker, s.citation.file_name, s.text))
+        .collect();
+    Some(format!(
+        "Passages from the student's study material:\n{}\nWhen you use a passage, cite its number in brackets, like [1].",
+        passages.join("\n")
+    ))
+}
+
+// The passages the reply cites; when it cites none, every passage it was given
+fn cited_sources(sources: &[ReplySource], reply: &str) -> Vec<MessageCitation> {
+    let cited: Vec<MessageCitation> = sources.iter()
+        .filter(|s| reply.contains(&format!("[{}]", s.citation.marker)))
+        .map(|s| s.citation.clone())
+        .collect();
+    if cited.is_empty() {
+        sources.iter().map(|s| s.citation.clone()).collect()
+    } else {
+        cited
+    }
+}
+
+#[ic_cdk::query]
+fn get_message_citations(session_id: String, message_id: String) -> Result<Vec<MessageCitation>, String> {
+    visible_session(&session_id, ic_cdk::caller())?;
+    CHAT_MESSAGES.with(|messages| messages.borrow().get(&session_id))
+        .and_then(|list| list.0.into_iter().find(|m| m.id == message_id))
+        .map(|m| m.citations)
+        .ok_or_else(|| "Message not found".to_string())
+}
+
+// --- Reply Confidence ---
+//
+// Each tutor reply gets a confidence estimate. The default is a cheap heuristic over hedging
+// language and reply length; with self_assessment on, the model also rates its own answer
+// and the lower score wins. Low scores are flagged for admin review, and when no knowledge
+// base passage backed the reply the tutor says it is unsure and offers to dig deeper.
+
+const HEDGING_PHRASES: [&str; 10] = [
+    "i'm not sure", "i am not sure", "not certain", "i don't know", "i do not know",
+    "i think", "i believe", "might be", "possibly", "it's unclear",
+];
+const UNSURE_NOTE: &str = "I'm not fully confident in this answer. If you'd like, ask me to research it and I'll work through it step by step, or add a source to my knowledge base so I can check.";
+const LOW_CONFIDENCE_TEXT_CHARS: usize = 1_000;
+
+fn estimate_reply_confidence(reply: &str, has_sources: bool) -> f32 {
+    let lower = reply.to_lowercase();
+    let hedges = HEDGING_PHRASES.iter().filter(|p| lower.contains(*p)).count() as f32;
+    let mut score = 0.8 - 0.15 * hedges;
+    if reply.trim().chars().count() < 40 {
+        score -= 0.2;
+    }
+    if has_sources {
+        score += 0.1;
+    }
+    score.clamp(0.0, 1.0)
+}
+
+async fn ai_reply_confidence(question: &str, reply: &str) -> Option<f32> {
+    let prompt = format!(
+        "A tutor answered a student's question. Rate from 0 to 1 how likely the answer is to be factually correct and complete. Reply with a single number only.\n\nQuestion:\n{}\n\nAnswer:\n{}",
+        question, reply
+    );
+    let response = call_groq_ai(&prompt, "confidence", ic_cdk::id()).await.ok()?;
+    response.trim().trim_end_matches('.').parse::<f32>().ok().filter(|score| (0.0..=1.0).contains(score))
+}
+
+// Scores a finished reply and adds the unsure note when it needs one
+async fn assess_reply(delivery: &PendingDelivery, reply: String) -> (String, Option<f32>) {
+    let config = get_config().confidence;
+    if !config.enabled {
+        return (reply, None);
+    }
+    let has_sources = !delivery.sources.is_empty();
+    let mut confidence = estimate_reply_confidence(&reply, has_sources);
+    if config.self_assessment {
+        if let Some(assessed) = ai_reply_confidence(&delivery.user_content, &reply).await {
+            confidence = confidence.min(assessed);
+        }
+    }
+    
+    if confidence < config.threshold && !has_sources {
+        return (format!("{}\n\n{}", reply, UNSURE_NOTE), Some(confidence));
+    }
+    (reply, Some(confidence))
+}
+
+fn flag_if_low_confidence(delivery: &PendingDelivery, message: &ChatMessage) {
+    let Some(confidence) = message.confidence else {
+        return;
+    };
+    if confidence >= get_config().confidence.threshold {
+        return;
+    }
+    let tutor_id = CHAT_SESSIONS.with(|sessions| sessions.borrow().get(&delivery.session_id))
+        .map(|s| s.tutor_id)
+        .unwrap_or_default();
+    let id = next_id("low_confidence_reply");
+    LOW_CONFIDENCE_REPLIES.with(|replies| {
+        replies.borrow_mut().insert(id, LowConfidenceReply {
+            id,
+            message_id: message.id.clone(),
+            session_id: delivery.session_id.clone(),
+            tutor_id,
+            user_id: delivery.requester(),
+            question: trim_to_length(&delivery.user_content, LOW_CONFIDENCE_TEXT_CHARS),
+            reply: trim_to_length(&message.content, LOW_CONFIDENCE_TEXT_CHARS),
+            confidence,
+            had_sources: !delivery.sources.is_empty(),
+            created_at: ic_cdk::api::time(),
+            reviewed_by: None,
+            reviewed_at: None,
+            review_note: None,
+        });
+    });
+}
+
+#[ic_cdk::query]
+fn get_low_confidence_replies_admin(include_reviewed: bool, limit: u32) -> Result<Vec<LowConfidenceReply>, String> {
+    require(ic_cdk::caller(), Permission::ModerateContent)?;
+    
+    let hint = "Lower the limit or review flagged replies to shorten the queue.";
+    let mut matching = Vec::new();
+    LOW_CONFIDENCE_REPLIES.with(|replies| {
+        for (scanned, (_, reply)) in replies.borrow().iter().rev().enumerate() {
+            scan_checkpoint(scanned, hint)?;
+            if matching.len() >= limit as usize {
+                break;
+            }
+            if include_reviewed || reply.reviewed_at.is_none() {
+                matching.push(reply);
+            }
+        }
+        Ok::<(), String>(())
+    })?;
+    ensure_fits(&matching, hint)?;
+    Ok(matching)
+}
+
+#[ic_cdk::update]
+fn review_low_confidence_reply_admin(id: u64, note: Option<String>) -> Result<LowConfidenceReply, String> {
+    let caller = ic_cdk::caller();
+    require(caller, Permission::ModerateContent)?;
+    let mut reply = LOW_CONFIDENCE_REPLIES.with(|replies| replies.borrow().get(&id))
+        .ok_or("Flagged reply not found")?;
+    reply.reviewed_by = Some(caller);
+    reply.reviewed_at = Some(ic_cdk::api::time());
+    reply.review_note = note.map(|n| n.trim().to_string()).filter(|n| !n.is_empty());
+    LOW_CONFIDENCE_REPLIES.with(|replies| replies.borrow_mut().insert(id, reply.clone()));
+    record_audit(caller, "low_confidence_reply_reviewed", Some(reply.user_id), format!("reply {} ({:.2})", reply.message_id, reply.confidence));
+    Ok(reply)
+}
+
+#[ic_cdk::update]
+fn set_confidence_config_admin(confidence: ConfidenceConfig) -> Result<CanisterConfig, String> {
+    require(ic_cdk::caller(), Permission::ManageSystem)?;
+    if !(0.0..=1.0).contains(&confidence.threshold) {
+        return Err("Threshold must be between 0 and 1".to_string());
+    }
+    update_config(|config| {
+        config.confidence = confidence;
+        Ok(())
+    })
+}
+
+// --- High-Stakes Replies ---
+//
+// Tutors covering medicine, law or finance append a standard disclaimer to every reply.
+// With fact_check on, each draft first goes through a second AI call that lists factual
+// errors and returns a corrected answer; both versions are kept.
+
+const HIGH_STAKES_DOMAINS: [&str; 3] = ["medicine", "law", "finance"];
+
+fn high_stakes_disclaimer(domain: &str) -> &'static str {
+    match domain {
+        "medicine" => "This is general information for learning, not medical advice. Talk to a qualified health professional about your own situation.",
+        "law" => "This is general information for learning, not legal advice. Consult a qualified lawyer about your own situation.",
+        _ => "This is general information for learning, not financial advice. Speak to a qualified financial adviser before making decisions.",
+    }
+}
+
+async fn fact_check_reply(tutor: &Tutor, domain: &str, delivery: &PendingDelivery, draft: String) -> String {
+    let prompt = format!(
+        "You are reviewing a tutor's draft answer in {}. Check it carefully for factual errors, outdated information and dangerous or misleading claims. \
+        Return JSON only: {{\"issues\": [\"...\"], \"answer\": \"...\"}} where issues lists each error found (empty if none) and answer is the draft with every error corrected, otherwise unchanged in tone and length.\n\nStudent question:\n{}\n\nDraft answer:\n{}",
+        domain, delivery.user_content, draft
+    );
+    let verdict = match call_groq_ai(&prompt, "fact_check", ic_cdk::id()).await {
+        Ok(response) => serde_json::from_str::<FactCheckVerdict>(&process_ai_response(response, &response_processing_for(delivery.requester(), "json")))
+            .map_err(|e| format!("Unreadable verification: {}", e)),
+        Err(e) => Err(e),
+    };
+    
+    let (issues, final_text, status, error) = match verdict {
+        Ok(verdict) if verdict.issues.is_empty() || verdict.answer.trim().is_empty() => (verdict.issues, draft.clone(), "verified", None),
+        Ok(verdict) => (verdict.issues, verdict.answer.trim().to_string(), "corrected", None),
+        // The draft still goes out; the failed check is on record for review
+        Err(e) => (Vec::new(), draft.clone(), "failed", Some(e)),
+    };
+    FACT_CHECKS.with(|checks| {
+        checks.borrow_mut().insert(delivery.message_id.clone(), FactCheckRecord {
+            message_id: delivery.message_id.clone(),
+            session_id: delivery.session_id.clone(),
+            tutor_id: tutor.public_id.clone(),
+            domain: domain.to_string(),
+            draft,
+            issues,
+            final_text: final_text.clone(),
+            status: status.to_string(),
+            error,
+            created_at: ic_cdk::api::time(),
+        });
+    });
+    final_text
+}
+
+// Verification, confidence scoring and the disclaimer, in that order, for a generated reply
+async fn finish_reply(tutor: &Tutor, delivery: &PendingDelivery, reply: String) -> (String, Option<f32>) {
+    let Some(domain) = tutor.high_stakes_domain.clone() else {
+        return assess_reply(delivery, reply).await;
+    };
+    let reply = if tutor.fact_check { fact_check_reply(tutor, &domain, delivery, reply).await } else { reply };
+    let (reply, confidence) = assess_reply(delivery, reply).await;
+    (format!("{}\n\n{}", reply, high_stakes_disclaimer(&domain)), confidence)
+}
+
+#[ic_cdk::update]
+fn set_tutor_high_stakes_domain(tutor_id: String, domain: Option<String>, fact_check: bool) -> Result<Tutor, String> {
+    let (key, mut tutor) = owned_tutor(&tutor_id, ic_cdk::caller())?;
+    let domain = domain.map(|d| d.trim().to_lowercase()).filter(|d| !d.is_empty());
+    if domain.as_deref().is_some_and(|d| !HIGH_STAKES_DOMAINS.contains(&d)) {
+        return Err(format!("Domain must be one of: {}", HIGH_STAKES_DOMAINS.join(", ")));
+    }
+    if fact_check && domain.is_none() {
+        return Err("Fact-checking is only available for high-stakes tutors".to_string());
+    }
+    
+    tutor.high_stakes_domain = domain;
+    tutor.fact_check = fact_check;
+    tutor.updated_at = ic_cdk::api::time();
+    cache::store_tutor(key, tutor.clone());
+    Ok(tutor)
+}
+
+#[ic_cdk::query]
+fn get_fact_check(session_id: String, message_id: String) -> Result<FactCheckRecord, String> {
+    visible_session(&session_id, ic_cdk::caller())?;
+    FACT_CHECKS.with(|checks| checks.borrow().get(&message_id))
+        .filter(|check| check.session_id == session_id)
+        .ok_or_else(|| "No fact check for this message".to_string())
+}
+
+// --- Gift Subscriptions ---
+//
+// Gifts are recorded against a pending payment transaction and become redeemable once the
+// payment is confirmed. Redeeming turns a gift into a plan grant. Grants stack as follows:
+// - the same plan or a lower one starts when the recipient's grants of that plan or higher end
+// - a higher plan starts now, and lower grants it overlaps are extended by the overlap
+// - a plan ranked below the recipient's own subscription can't be redeemed
+// Plans rank by their order in the configured plan limits.
+
+const MAX_GIFT_MONTHS: u32 = 24;
+const MAX_COMP_DAYS: u32 = 366;
+const GIFT_MONTH_NS: u64 = 30 * NANOS_PER_DAY;
+
+fn plan_rank(plan: &str) -> Option<usize> {
+    get_config().plan_limits.iter().position(|l| l.plan == plan)
+}
+
+fn plan_grant_key(user_id: Principal, grant_id: u64) -> String {
+    format!("{}:{:020}", user_id, grant_id)
+}
+
+fn plan_grants_for(user_id: Principal) -> Vec<PlanGrant> {
+    let start = format!("{}:", user_id);
+    let end = format!("{};", user_id); // ';' sorts right after ':'
+    PLAN_GRANTS.with(|grants| grants.borrow().range(start..end).map(|(_, g)| g).collect())
+}
+
+fn store_plan_grant(grant: PlanGrant) {
+    PLAN_GRANTS.with(|grants| grants.borrow_mut().insert(plan_grant_key(grant.user_id, grant.id), grant));
+}
+
+// The highest-ranked plan among the user's running grants
+fn granted_plan(user_id: Principal, now: u64) -> Option<String> {
+    plan_grants_for(user_id)
+        .into_iter()
+        .filter(|g| g.revoked_at.is_none() && g.starts_at <= now && now < g.ends_at)
+        .max_by_key(|g| plan_rank(&g.plan))
+        .map(|g| g.plan)
+}
+
+// Schedules a grant under the stacking rules and returns it
+fn add_plan_grant(user_id: Principal, plan: &str, duration_ns: u64, source: &str, gift_id: Option<u64>, granted_by: Principal, reason: Option<String>) -> Result<PlanGrant, String> {
+    let rank = plan_rank(plan).ok_or_else(|| format!("Unknown plan '{}'", plan))?;
+    let own_plan = cache::user(user_id).map(|u| u.subscription).unwrap_or_else(|| "free".to_string());
+    if plan_rank(&own_plan).is_some_and(|own| own > rank) {
+        return Err(format!("The recipient's {} plan already includes everything in {}", own_plan, plan));
+    }
+    
+    let now = ic_cdk::api::time();
+    let existing: Vec<PlanGrant> = plan_grants_for(user_id).into_iter().filter(|g| g.revoked_at.is_none() && g.ends_at > now).collect();
+    let starts_at = existing.iter()
+        .filter(|g| plan_rank(&g.plan).is_some_and(|r| r >= rank))
+        .map(|g| g.ends_at)
+        .max()
+        .unwrap_or(now)
+        .max(now);
+    let ends_at = starts_at + duration_ns;
+    
+    for mut lower in existing.into_iter().filter(|g| plan_rank(&g.plan).is_some_and(|r| r < rank)) {
+        let overlap = lower.ends_at.min(ends_at).saturating_sub(lower.starts_at.max(starts_at));
+        if overlap > 0 {
+            lower.ends_at += overlap;
+            store_plan_grant(lower);
+        }
+    }
+    
+    let grant = PlanGrant {
+        id: next_id("plan_grant"),
+        user_id,
+        plan: plan.to_string(),
+        starts_at,
+        ends_at,
+        source: source.to_string(),
+        gift_id,
+        granted_by,
+        reason,
+        revoked_at: None,
+        created_at: now,
+        expiry_reminded_at: None,
+        end_notified_at: None,
+    };
+    store_plan_grant(grant.clone());
+    Ok(grant)
+}
+
+fn gift_recipient_matches(gift: &GiftSubscription, user: &User) -> bool {
+    gift.recipient_principal == Some(user.id)
+        || gift.recipient_email.as_ref().is_some_and(|email| email.eq_ignore_ascii_case(user.email.trim()))
+}
+
+fn store_gift(gift: &GiftSubscription) {
+    GIFT_SUBSCRIPTIONS.with(|gifts| gifts.borrow_mut().insert(gift.id, gift.clone()));
+}
+
+// Priced in the purchaser's region, with that region's tax
+fn gift_price(plan: &str, months: u32, purchaser: Principal) -> PlanPrice {
+    let region = billing_region(purchaser);
+    let monthly = SUBSCRIPTION_PLANS.with(|plans| {
+        plans.borrow().values().find(|p| p.is_active && p.name.eq_ignore_ascii_case(plan) && p.billing_cycle == "monthly")
+    });
+    let (currency, amount) = monthly.as_ref()
+        .map(|p| regional_price(p, &region))
+        .unwrap_or_else(|| ("NGN".to_string(), 0));
+    let (total, tax) = apply_tax(amount * months as u64, &region);
+    PlanPrice {
+        plan_id: monthly.map(|p| p.id).unwrap_or(0),
+        name: plan.to_string(),
+        billing_cycle: format!("{} months", months),
+        region,
+        currency,
+        total,
+        tax,
+    }
+}
+
+#[ic_cdk::update]
+fn gift_subscription(recipient_email_or_principal: String, plan: String, months: u32, message: Option<String>) -> Result<GiftSubscription, String> {
+    let caller = ic_cdk::caller();
+    get_self().ok_or("User not found")?;
+    let recipient = recipient_email_or_principal.trim();
+    let (recipient_principal, recipient_email) = match Principal::from_text(recipient) {
+        Ok(principal) => (Some(principal), None),
+        Err(_) if recipient.contains('@') => (None, Some(recipient.to_lowercase())),
+        Err(_) => return Err("Recipient must be a principal or an email address".to_string()),
+    };
+    if recipient_principal == Some(caller) {
+        return Err("You can't gift a plan to yourself".to_string());
+    }
+    if plan_rank(&plan).is_none() || plan == "free" {
+        return Err(format!("'{}' is not a plan that can be gifted", plan));
+    }
+    if !(1..=MAX_GIFT_MONTHS).contains(&months) {
+        return Err(format!("Gifts last between 1 and {} months", MAX_GIFT_MONTHS));
+    }
+    
+    let now = ic_cdk::api::time();
+    let gift_id = next_id("gift_subscription");
+    let transaction_id = next_id("payment_transaction");
+    let price = gift_price(&plan, months, caller);
+    PAYMENT_TRANSACTIONS.with(|transactions| {
+        transactions.borrow_mut().insert(transaction_id, PaymentTransaction {
+            id: transaction_id,
+            user_id: caller,
+            subscription_id: None,
+            paystack_reference: format!("gift_{}", gift_id),
+            paystack_access_code: None,
+            paystack_transaction_id: None,
+            amount_naira: price.total,
+            currency: price.currency,
+            status: "pending".to_string(),
+            payment_method: None,
+            description: Some(format!("Gift: {} months of {}", months, plan)),
+            payment_metadata: None,
+            created_at: now,
+            paid_at: None,
+            region: Some(price.region),
+            tax: Some(price.tax),
+        });
+    });
+    
+    let gift = GiftSubscription {
+        id: gift_id,
+        purchaser_id: caller,
+        recipient_principal,
+        recipient_email,
+        plan,
+        months,
+        message: message.map(|m| m.trim().to_string()).filter(|m| !m.is_empty()),
+        transaction_id,
+        status: "pending_payment".to_string(),
+        grant_id: None,
+        created_at: now,
+        paid_at: None,
+        redeemed_at: None,
+    };
+    store_gift(&gift);
+    Ok(gift)
+}
+
+// Called once the gift's payment transaction has settled
+#[ic_cdk::update]
+fn confirm_gift_payment_admin(gift_id: u64, paystack_transaction_id: String) -> Result<GiftSubscription, String> {
+    let caller = ic_cdk::caller();
+    require(caller, Permission::ManageBilling)?;
+    let mut gift = GIFT_SUBSCRIPTIONS.with(|gifts| gifts.borrow().get(&gift_id)).ok_or("Gift not found")?;
+    if gift.status != "pending_payment" {
+        return Err(format!("Gift is already {}", gift.status.replace('_', " ")));
+    }
+    
+    let now = ic_cdk::api::time();
+    PAYMENT_TRANSACTIONS.with(|transactions| {
+        let mut transactions = transactions.borrow_mut();
+        if let Some(mut transaction) = transactions.get(&gift.transaction_id) {
+            transaction.status = "success".to_string();
+            transaction.paystack_transaction_id = Some(paystack_transaction_id.clone());
+            transaction.paid_at = Some(now);
+            transactions.insert(transaction.id, transaction);
+        }
+    });
+    gift.status = "paid".to_string();
+    gift.paid_at = Some(now);
+    store_gift(&gift);
+    record_audit(caller, "gift_payment_confirmed", Some(gift.purchaser_id), format!("gift {} ({})", gift_id, paystack_transaction_id));
+    
+    if let Some(recipient) = gift.recipient_principal {
+        notify_user(recipient, "gift", "billing", format!("You've been gifted {} months of {}. Redeem it from your plan page.", gift.months, gift.plan), Some(gift.id));
+    }
+    Ok(gift)
+}
+
+// Email gifts need a verified email so an unverified account can't claim someone else's gift
+#[ic_cdk::update]
+fn redeem_gift_subscription(gift_id: u64) -> Result<PlanGrant, String> {
+    let caller = ic_cdk::caller();
+    let user = get_self().ok_or("User not found")?;
+    let mut gift = GIFT_SUBSCRIPTIONS.with(|gifts| gifts.borrow().get(&gift_id))
+        .filter(|gift| gift_recipient_matches(gift, &user))
+        .ok_or("Gift not found")?;
+    if gift.recipient_principal != Some(caller) && !user.is_verified {
+        return Err("Verify your email address to redeem this gift".to_string());
+    }
+    match gift.status.as_str() {
+        "paid" => {}
+        "pending_payment" => return Err("This gift hasn't been paid for yet".to_string()),
+        status => return Err(format!("This gift is already {}", status)),
+    }
+    
+    let grant = add_plan_grant(caller, &gift.plan, gift.months as u64 * GIFT_MONTH_NS, "gift", Some(gift.id), gift.purchaser_id, gift.message.clone())?;
+    gift.status = "redeemed".to_string();
+    gift.grant_id = Some(grant.id);
+    gift.redeemed_at = Some(grant.created_at);
+    store_gift(&gift);
+    
+    let name = learner_name(caller).unwrap_or_else(|| "Your recipient".to_string());
+    notify_user(gift.purchaser_id, "info", "billing", format!("{} redeemed your {} gift", name, gift.plan), Some(gift.id));
+    Ok(grant)
+}
+
+// The recipient, or the purchaser while the gift is unclaimed, can pass it to someone else
+#[ic_cdk::update]
+fn transfer_gift_subscription(gift_id: u64, new_recipient: Principal) -> Result<GiftSubscription, String> {
+    let caller = ic_cdk::caller();
+    let user = get_self().ok_or("User not found")?;
+    let mut gift = GIFT_SUBSCRIPTIONS.with(|gifts| gifts.borrow().get(&gift_id))
+        .filter(|gift| gift.purchaser_id == caller || gift_recipient_matches(gift, &user))
+        .ok_or("Gift not found")?;
+    if gift.status == "redeemed" || gift.status == "cancelled" {
+        return Err(format!("This gift is already {}", gift.status));
+    }
+    if new_recipient == caller {
+        return Err("Redeem the gift instead of transferring it to yourself".to_string());
+    }
+    
+    gift.recipient_principal = Some(new_recipient);
+    gift.recipient_email = None;
+    store_gift(&gift);
+    if gift.status == "paid" {
+        notify_user(new_recipient, "gift", "billing", format!("You've been gifted {} months of {}. Redeem it from your plan page.", gift.months, gift.plan), Some(gift.id));
+    }
+    Ok(gift)
+}
+
+#[ic_cdk::query]
+fn get_my_gift_subscriptions() -> Vec<GiftSubscription> {
+    let caller = ic_cdk::caller();
+    let user = cache::user(caller);
+    GIFT_SUBSCRIPTIONS.with(|gifts| {
+        gifts.borrow().values()
+            .filter(|g| g.purchaser_id == caller || user.as_ref().is_some_and(|u| gift_recipient_matches(g, u)))
+            .collect()
+    })
+}
+
+#[ic_cdk::query]
+fn get_my_plan_grants() -> Vec<PlanGrant> {
+    plan_grants_for(ic_cdk::caller())
+}
+
+// Promotional comps follow the same stacking rules as gifts
+#[ic_cdk::update]
+fn grant_plan_admin(user_id: Principal, plan: String, days: u32, reason: String) -> Result<PlanGrant, String> {
+    let caller = ic_cdk::caller();
+    require(caller, Permission::ManageBilling)?;
+    if !(1..=MAX_COMP_DAYS).contains(&days) {
+        return Err(format!("Comps last between 1 and {} days", MAX_COMP_DAYS));
+    }
+    if reason.trim().is_empty() {
+        return Err("A reason is required".to_string());
+    }
+    cache::user(user_id).ok_or("User not found")?;
+    
+    let grant = add_plan_grant(user_id, &plan, days as u64 * NANOS_PER_DAY, "comp", None, caller, Some(reason.trim().to_string()))?;
+    record_audit(caller, "plan_comp_granted", Some(user_id), format!("{} for {} days: {}", plan, days, reason.trim()));
+    notify_user(user_id, "success", "billing", format!("You've been given {} days of {}", days, plan), Some(grant.id));
+    Ok(grant)
+}
+
+#[ic_cdk::update]
+fn revoke_plan_grant_admin(user_id: Principal, grant_id: u64, reason: String) -> Result<PlanGrant, String> {
+    let caller = ic_cdk::caller();
+    require(caller, Permission::ManageBilling)?;
+    let mut grant = PLAN_GRANTS.with(|grants| grants.borrow().get(&plan_grant_key(user_id, grant_id)))
+        .ok_or("Plan grant not found")?;
+    if grant.revoked_at.is_some() {
+        return Err("Plan grant is already revoked".to_string());
+    }
+    grant.revoked_at = Some(ic_cdk::api::time());
+    store_plan_grant(grant.clone());
+    record_audit(caller, "plan_grant_revoked", Some(user_id), format!("grant {} ({}): {}", grant_id, grant.plan, reason.trim()));
+    Ok(grant)
+}
+
+#[ic_cdk::query]
+fn get_plan_grants_admin(user_id: Principal) -> Result<Vec<PlanGrant>, String> {
+    require(ic_cdk::caller(), Permission::ManageBilling)?;
+    Ok(plan_grants_for(user_id))
+}
+
+// --- Educator Verification ---
+//
+// Educators submit credentials for admin review. Approval sets the verified badge on their
+// profile, which search shows on their public tutors. Verified educators get the higher
+// revenue share, can publish any number of tutors and are listed first in search.
+
+const MAX_CREDENTIALS: usize = 10;
+const MAX_SEARCH_RESULTS: u64 = 100;
+
+fn is_verified_educator(user_id: Principal) -> bool {
+    cache::user(user_id).is_some_and(|u| u.verified_educator)
+}
+
+fn revenue_share_percent(user_id: Principal) -> u8 {
+    let educators = get_config().educators;
+    if is_verified_educator(user_id) {
+        educators.verified_revenue_share_percent
+    } else {
+        educators.revenue_share_percent
+    }
+}
+
+fn public_tutor_count(user_id: Principal) -> u32 {
+    TUTORS.with(|tutors| {
+        tutors.borrow().iter().filter(|(_, t)| t.user_id == user_id && t.visibility == Visibility::Public).count() as u32
+    })
+}
+
+fn ensure_public_tutor_allowed(user_id: Principal) -> Result<(), String> {
+    let Some(limit) = get_config().educators.unverified_public_tutor_limit else {
+        return Ok(());
+    };
+    if !is_verified_educator(user_id) && public_tutor_count(user_id) >= limit {
+        return Err(format!("Unverified educators can publish up to {} tutors. Apply for educator verification to publish more.", limit));
+    }
+    Ok(())
+}
+
+fn set_verified_educator(user_id: Principal, verified: bool) {
+    if let Some(mut user) = cache::user(user_id) {
+        user.verified_educator = verified;
+        user.updated_at = ic_cdk::api::time();
+        cache::store_user(user);
+    }
+}
+
+#[ic_cdk::update]
+fn submit_educator_verification(
+    full_name: String,
+    institution: Option<String>,
+    subject_areas: Vec<String>,
+    credentials: Vec<String>,
+    statement: Option<String>,
+) -> Result<EducatorVerification, String> {
+    let caller = ic_cdk::caller();
+    get_self().ok_or("User not found")?;
+    if full_name.trim().is_empty() {
+        return Err("Full name is required".to_string());
+    }
+    let credentials: Vec<String> = credentials.into_iter().map(|c| c.trim().to_string()).filter(|c| !c.is_empty()).collect();
+    if credentials.is_empty() || credentials.len() > MAX_CREDENTIALS {
+        return Err(format!("Provide between 1 and {} credentials", MAX_CREDENTIALS));
+    }
+    if let Some(existing) = EDUCATOR_VERIFICATIONS.with(|v| v.borrow().get(&caller)) {
+        if existing.status == "approved" {
+            return Err("You are already a verified educator".to_string());
+        }
+    }
+    
+    let verification = EducatorVerification {
+        user_id: caller,
+        full_name: full_name.trim().to_string(),
+        institution: institution.map(|i| i.trim().to_string()).filter(|i| !i.is_empty()),
+        subject_areas: subject_areas.into_iter().map(|s| s.trim().to_string()).filter(|s| !s.is_empty()).collect(),
+        credentials,
+        statement: statement.map(|s| s.trim().to_string()).filter(|s| !s.is_empty()),
+        status: "pending".to_string(),
+        submitted_at: ic_cdk::api::time(),
+        reviewed_by: None,
+        reviewed_at: None,
+        review_note: None,
+    };
+    EDUCATOR_VERIFICATIONS.with(|v| v.borrow_mut().insert(caller, verification.clone()));
+    Ok(verification)
+}
+
+#[ic_cdk::query]
+fn get_my_educator_status() -> EducatorStatus {
+    let caller = ic_cdk::caller();
+    let verified = is_verified_educator(caller);
+    EducatorStatus {
+        verified,
+        verification: EDUCATOR_VERIFICATIONS.with(|v| v.borrow().get(&caller)),
+        revenue_share_percent: revenue_share_percent(caller),
+        public_tutor_limit: if verified { None } else { get_config().educators.unverified_public_tutor_limit },
+        public_tutors: public_tutor_count(caller),
+    }
+}
+
+#[ic_cdk::query]
+fn get_educator_verifications_admin(status: Option<String>) -> Result<Vec<EducatorVerification>, String> {
+    require(ic_cdk::caller(), Permission::ReviewEducators)?;
+    let mut verifications: Vec<EducatorVerification> = EDUCATOR_VERIFICATIONS.with(|v| {
+        v.borrow().values().filter(|v| status.as_ref().is_none_or(|s| &v.status == s)).collect()
+    });
+    verifications.sort_by_key(|v| v.submitted_at);
+    Ok(verifications)
+}
+
+#[ic_cdk::update]
+fn review_educator_verification_admin(user_id: Principal, approve: bool, note: Option<String>) -> Result<EducatorVerification, String> {
+    let caller = ic_cdk::caller();
+    require(caller, Permission::ReviewEducators)?;
+    let mut verification = EDUCATOR_VERIFICATIONS.with(|v| v.borrow().get(&user_id)).ok_or("Verification request not found")?;
+    if verification.status != "pending" {
+        return Err(format!("Verification request is already {}", verification.status));
+    }
+    
+    verification.status = if approve { "approved" } else { "rejected" }.to_string();
+    verification.reviewed_by = Some(caller);
+    verification.reviewed_at = Some(ic_cdk::api::time());
+    verification.review_note = note.map(|n| n.trim().to_string()).filter(|n| !n.is_empty());
+    EDUCATOR_VERIFICATIONS.with(|v| v.borrow_mut().insert(user_id, verification.clone()));
+    set_verified_educator(user_id, approve);
+    record_audit(caller, if approve { "educator_verified" } else { "educator_verification_rejected" }, Some(user_id), verification.review_note.clone().unwrap_or_default());
+    
+    let content = if approve {
+        "Your educator verification was approved. Your profile and public tutors now show the verified badge.".to_string()
+    } else {
+        format!("Your educator verification was not approved{}", verification.review_note.as_ref().map(|n| format!(": {}", n)).unwrap_or_default())
+    };
+    notify_user(user_id, if approve { "success" } else { "info" }, "system", content, None);
+    Ok(verification)
+}
+
+// Tutors already public stay public; the limit applies to the next one published
+#[ic_cdk::update]
+fn revoke_educator_verification_admin(user_id: Principal, reason: String) -> Result<EducatorVerification, String> {
+    let caller = ic_cdk::caller();
+    require(caller, Permission::ReviewEducators)?;
+    if reason.trim().is_empty() {
+        return Err("A reason is required".to_string());
+    }
+    let mut verification = EDUCATOR_VERIFICATIONS.with(|v| v.borrow().get(&user_id))
+        .filter(|v| v.status == "approved")
+        .ok_or("User is not a verified educator")?;
+    
+    verification.status = "revoked".to_string();
+    verification.reviewed_by = Some(caller);
+    verification.reviewed_at = Some(ic_cdk::api::time());
+    verification.review_note = Some(reason.trim().to_string());
+    EDUCATOR_VERIFICATIONS.with(|v| v.borrow_mut().insert(user_id, verification.clone()));
+    set_verified_educator(user_id, false);
+    record_audit(caller, "educator_verification_revoked", Some(user_id), reason.trim().to_string());
+    notify_user(user_id, "warning", "system", format!("Your educator verification was revoked: {}", reason.trim()), None);
+    Ok(verification)
+}
+
+// Public tutors matching name, description or expertise, in marketplace order; course_count is
+// left at zero for fill_course_counts
+fn public_tutor_listings(query: Option<String>, verified_only: bool, hint: &str) -> Result<Vec<TutorListing>, String> {
+    let query = query.map(|q| q.trim().to_lowercase()).filter(|q| !q.is_empty());
+    let matches = |t: &Tutor| query.as_ref().is_none_or(|q| {
+        t.name.to_lowercase().contains(q)
+            || t.description.to_lowercase().contains(q)
+            || t.expertise.iter().any(|e| e.to_lowercase().contains(q))
+    });
+    
+    let mut listings = Vec::new();
+    TUTORS.with(|tutors| -> Result<(), String> {
+        for (scanned, (_, tutor)) in tutors.borrow().iter().enumerate() {
+            scan_checkpoint(scanned, hint)?;
+            if tutor.visibility != Visibility::Public || !matches(&tutor) {
+                continue;
+            }
+            let owner = cache::user(tutor.user_id);
+            let verified_educator = owner.as_ref().is_some_and(|u| u.verified_educator);
+            if verified_only && !verified_educator {
+                continue;
+            }
+            listings.push(TutorListing {
+                owner_name: owner.map(|u| u.username).unwrap_or_default(),
+                verified_educator,
+                course_count: 0,
+                tutor,
+            });
+        }
+        Ok(())
+    })?;
+    
+    if get_config().educators.verified_first_in_search {
+        listings.sort_by_key(|l| (std::cmp::Reverse(l.verified_educator), std::cmp::Reverse(l.tutor.updated_at)));
+    } else {
+        listings.sort_by_key(|l| std::cmp::Reverse(l.tutor.updated_at));
+    }
+    Ok(listings)
+}
+
+fn fill_course_counts(page: &mut [TutorListing]) {
+    TUTOR_COURSES.with(|courses| {
+        for course in courses.borrow().values() {
+            if let Some(listing) = page.iter_mut().find(|l| l.tutor.id == course.tutor_id) {
+                listing.course_count += 1;
+            }
+        }
+    });
+}
+
+// Marketplace search over public tutors, matching name, description and expertise
+#[ic_cdk::query]
+fn search_public_tutors(query: Option<String>, verified_only: bool, offset: u64, limit: u64) -> Result<Vec<TutorListing>, String> {
+    let limit = limit.min(MAX_SEARCH_RESULTS);
+    let listings = public_tutor_listings(query, verified_only, "Narrow the search with a query or verified_only.")?;
+    let mut page: Vec<TutorListing> = listings.into_iter().skip(offset as usize).take(limit as usize).collect();
+    fill_course_counts(&mut page);
+    ensure_fits(&page, "Request a smaller page.")?;
+    Ok(page)
+}
+
+#[ic_cdk::update]
+fn set_educator_config_admin(educators: EducatorConfig) -> Result<CanisterConfig, String> {
+    require(ic_cdk::caller(), Permission::ManageSystem)?;
+    if educators.revenue_share_percent > 100 || educators.verified_revenue_share_percent > 100 {
+        return Err("Revenue shares must be between 0 and 100 percent".to_string());
+    }
+    update_config(|config| {
+        config.educators = educators;
+        Ok(())
+    })
+}
+
+// --- Creator Revenue ---
+//
+// Replies from a public tutor to a learner on a paid plan earn its creator credits, tracked
+// per tutor and month. Once a month closes, each creator's unpaid credits become a pending
+// payout at their revenue share. Approving a payout transfers tokens through the configured
+// ICRC-1 ledger; fiat-only payouts are marked paid by an admin once the transfer is made.
+
+const CREATOR_PAYOUT_JOB_INTERVAL_NS: u64 = 24 * 60 * 60 * 1_000_000_000;
+const CREATOR_USAGE_MONTHS: usize = 12;
+
+fn usage_month(timestamp: u64) -> String {
+    iso8601(timestamp)[..7].to_string()
+}
+
+fn accrue_creator_usage(delivery: &PendingDelivery, now: u64) {
+    let settings = get_config().creator_revenue;
+    if !settings.enabled || settings.credits_per_reply == 0 {
+        return;
+    }
+    let Some(session) = CHAT_SESSIONS.with(|sessions| sessions.borrow().get(&delivery.session_id)) else {
+        return;
+    };
+    let Some((_, tutor)) = cache::tutor_by_public_id(&session.tutor_id) else {
+        return;
+    };
+    let learner = delivery.requester();
+    if tutor.user_id == learner || tutor.visibility != Visibility::Public || user_plan(learner) == "free" {
+        return;
+    }
+    if is_non_commercial_copy(&tutor.derived_from) {
+        return;
+    }
+    
+    let month = usage_month(now);
+    let key = format!("{}:{}", month, tutor.public_id);
+    CREATOR_USAGE.with(|usage| {
+        let mut usage = usage.borrow_mut();
+        let mut entry = usage.get(&key).unwrap_or(CreatorUsage {
+            month,
+            tutor_id: tutor.public_id.clone(),
+            creator_id: tutor.user_id,
+            premium_replies: 0,
+            learners: Vec::new(),
+            credits: 0,
+            payout_id: None,
+            updated_at: now,
+        });
+        entry.premium_replies += 1;
+        entry.credits += settings.credits_per_reply;
+        if !entry.learners.contains(&learner) {
+            entry.learners.push(learner);
+        }
+        entry.updated_at = now;
+        usage.insert(key, entry);
+    });
+}
+
+// Collects unpaid credits from closed months into one payout per creator
+fn compute_creator_payouts(now: u64) -> Vec<CreatorPayout> {
+    let settings = get_config().creator_revenue;
+    let current_month = usage_month(now);
+    let mut unpaid: HashMap<Principal, Vec<(String, CreatorUsage)>> = HashMap::new();
+    CREATOR_USAGE.with(|usage| {
+        for (key, entry) in usage.borrow().range(..current_month) {
+            if entry.payout_id.is_none() {
+                unpaid.entry(entry.creator_id).or_default().push((key, entry));
+            }
+        }
+    });
+    
+    let mut payouts = Vec::new();
+    for (creator_id, entries) in unpaid {
+        let credits: u64 = entries.iter().map(|(_, e)| e.credits).sum();
+        if credits < settings.minimum_payout_credits {
+            continue;
+        }
+        let share = revenue_share_percent(creator_id);
+        let mut months: Vec<String> = entries.iter().map(|(_, e)| e.month.clone()).collect();
+        months.sort();
+        months.dedup();
+        let payout = CreatorPayout {
+            id: next_id("creator_payout"),
+            creator_id,
+            months,
+            credits,
+            revenue_share_percent: share,
+            amount_kobo: credits * settings.kobo_per_credit * share as u64 / 100,
+            token_amount: credits * settings.tokens_per_credit * share as u64 / 100,
+            status: "pending".to_string(),
+            created_at: now,
+            reviewed_by: None,
+            reviewed_at: None,
+            ledger_block: None,
+            payment_reference: None,
+            error: None,
+            paid_at: None,
+        };
+        CREATOR_USAGE.with(|usage| {
+            let mut usage = usage.borrow_mut();
+            for (key, mut entry) in entries {
+                entry.payout_id = Some(payout.id);
+                usage.insert(key, entry);
+            }
+        });
+        CREATOR_PAYOUTS.with(|p| p.borrow_mut().insert(payout.id, payout.clone()));
+        notify_user(creator_id, "info", "billing", format!("Your earnings for {} are ready for payout review", payout.months.join(", ")), Some(payout.id));
+        payouts.push(payout);
+    }
+    payouts
+}
+
+fn store_creator_payout(payout: &CreatorPayout) {
+    CREATOR_PAYOUTS.with(|p| p.borrow_mut().insert(payout.id, payout.clone()));
+}
+
+async fn transfer_payout_tokens(ledger: Principal, payout: &CreatorPayout) -> Result<u64, String> {
+    let arg = TransferArg {
+        from_subaccount: None,
+        to: Account { owner: payout.creator_id, subaccount: None },
+        amount: Nat::from(payout.token_amount),
+        fee: None,
+        memo: Some(payout.id.to_be_bytes().to_vec()),
+        created_at_time: Some(ic_cdk::api::time()),
+    };
+    let (result,): (Result<Nat, TransferError>,) = ic_cdk::call(ledger, "icrc1_transfer", (arg,))
+        .await
+        .map_err(|(code, msg)| format!("Ledger call failed: {:?} {}", code, msg))?;
+    let block = result.map_err(|e| format!("Ledger rejected the transfer: {:?}", e))?;
+    u64::try_from(&block.0).map_err(|_| "Ledger returned an invalid block index".to_string())
+}
+
+#[ic_cdk::query]
+fn get_my_creator_earnings() -> CreatorEarnings {
+    let caller = ic_cdk::caller();
+    let mut usage: Vec<CreatorUsage> = CREATOR_USAGE.with(|usage| {
+        usage.borrow().values().filter(|u| u.creator_id == caller).collect()
+    });
+    usage.sort_by(|a, b| b.month.cmp(&a.month).then_with(|| b.credits.cmp(&a.credits)));
+    let unpaid_credits = usage.iter().filter(|u| u.payout_id.is_none()).map(|u| u.credits).sum();
+    let recent_months: Vec<String> = {
+        let mut months: Vec<String> = usage.iter().map(|u| u.month.clone()).collect();
+        months.dedup();
+        months.into_iter().take(CREATOR_USAGE_MONTHS).collect()
+    };
+    usage.retain(|u| recent_months.contains(&u.month));
+    
+    let mut payouts: Vec<CreatorPayout> = CREATOR_PAYOUTS.with(|p| {
+        p.borrow().values().filter(|p| p.creator_id == caller).collect()
+    });
+    payouts.sort_by_key(|p| std::cmp::Reverse(p.created_at));
+    let paid = payouts.iter().filter(|p| p.status == "paid");
+    CreatorEarnings {
+        revenue_share_percent: revenue_share_percent(caller),
+        current_month: usage_month(ic_cdk::api::time()),
+        usage,
+        unpaid_credits,
+        paid_amount_kobo: paid.clone().map(|p| p.amount_kobo).sum(),
+        paid_tokens: paid.map(|p| p.token_amount).sum(),
+        payouts,
+    }
+}
+
+#[ic_cdk::query]
+fn get_creator_payouts_admin(status: Option<String>) -> Result<Vec<CreatorPayout>, String> {
+    require(ic_cdk::caller(), Permission::ManageBilling)?;
+    let payouts: Vec<CreatorPayout> = CREATOR_PAYOUTS.with(|p| {
+        p.borrow().values().filter(|p| status.as_ref().is_none_or(|s| &p.status == s)).collect()
+    });
+    ensure_fits(&payouts, "Filter by status.")?;
+    Ok(payouts)
+}
+
+#[ic_cdk::update]
+fn compute_creator_payouts_admin() -> Result<Vec<CreatorPayout>, String> {
+    let caller = ic_cdk::caller();
+    require(caller, Permission::ManageBilling)?;
+    let payouts = compute_creator_payouts(ic_cdk::api::time());
+    record_audit(caller, "creator_payouts_computed", None, format!("{} payouts", payouts.len()));
+    Ok(payouts)
+}
+
+// Failed transfers can be approved again; the ledger deduplicates on memo and time
+#[ic_cdk::update]
+async fn approve_creator_payout_admin(payout_id: u64) -> Result<CreatorPayout, String> {
+    let caller = ic_cdk::caller();
+    require(caller, Permission::ManageBilling)?;
+    let mut payout = CREATOR_PAYOUTS.with(|p| p.borrow().get(&payout_id)).ok_or("Payout not found")?;
+    if payout.status != "pending" && payout.status != "failed" {
+        return Err(format!("Payout is already {}", payout.status));
+    }
+    
+    let now = ic_cdk::api::time();
+    payout.reviewed_by = Some(caller);
+    payout.reviewed_at = Some(now);
+    record_audit(caller, "creator_payout_approved", Some(payout.creator_id), format!("payout {}: {} kobo, {} tokens", payout.id, payout.amount_kobo, payout.token_amount));
+    
+    let ledger = get_config().creator_revenue.token_ledger.filter(|_| payout.token_amount > 0);
+    let Some(ledger) = ledger else {
+        payout.status = "approved".to_string();
+        store_creator_payout(&payout);
+        return Ok(payout);
+    };
+    
+    // Held in "processing" across the ledger call so it can't be approved twice
+    payout.status = "processing".to_string();
+    store_creator_payout(&payout);
+    match transfer_payout_tokens(ledger, &payout).await {
+        Ok(block) => {
+            payout.status = "paid".to_string();
+            payout.ledger_block = Some(block);
+            payout.error = None;
+            payout.paid_at = Some(ic_cdk::api::time());
+            notify_user(payout.creator_id, "success", "billing", format!("Your creator payout of {} tokens has been sent", payout.token_amount), Some(payout.id));
+        }
+        Err(e) => {
+            payout.status = "failed".to_string();
+            payout.error = Some(e);
+        }
+    }
+    store_creator_payout(&payout);
+    Ok(payout)
+}
+
+// Records a fiat payout made outside the canister
+#[ic_cdk::update]
+fn mark_creator_payout_paid_admin(payout_id: u64, payment_reference: String) -> Result<CreatorPayout, String> {
+    let caller = ic_cdk::caller();
+    require(caller, Permission::ManageBilling)?;
+    let mut payout = CREATOR_PAYOUTS.with(|p| p.borrow().get(&payout_id)).ok_or("Payout not found")?;
+    if payout.status != "approved" {
+        return Err("Only approved payouts can be marked paid".to_string());
+    }
+    payout.status = "paid".to_string();
+    payout.payment_reference = Some(payment_reference.trim().to_string());
+    payout.paid_at = Some(ic_cdk::api::time());
+    store_creator_payout(&payout);
+    record_audit(caller, "creator_payout_paid", Some(payout.creator_id), format!("payout {} ({})", payout.id, payment_reference.trim()));
+    notify_user(payout.creator_id, "success", "billing", format!("Your creator payout of ₦{:.2} has been paid", payout.amount_kobo as f64 / 100.0), Some(payout.id));
+    Ok(payout)
+}
+
+// The payout's credits stay attached to it and are not paid again
+#[ic_cdk::update]
+fn reject_creator_payout_admin(payout_id: u64, reason: String) -> Result<CreatorPayout, String> {
+    let caller = ic_cdk::caller();
+    require(caller, Permission::ManageBilling)?;
+    if reason.trim().is_empty() {
+        return Err("A reason is required".to_string());
+    }
+    let mut payout = CREATOR_PAYOUTS.with(|p| p.borrow().get(&payout_id)).ok_or("Payout not found")?;
+    if payout.status != "pending" && payout.status != "failed" {
+        return Err(format!("Payout is already {}", payout.status));
+    }
+    payout.status = "rejected".to_string();
+    payout.reviewed_by = Some(caller);
+    payout.reviewed_at = Some(ic_cdk::api::time());
+    payout.error = Some(reason.trim().to_string());
+    store_creator_payout(&payout);
+    record_audit(caller, "creator_payout_rejected", Some(payout.creator_id), format!("payout {}: {}", payout.id, reason.trim()));
+    notify_user(payout.creator_id, "warning", "billing", format!("Your creator payout was rejected: {}", reason.trim()), Some(payout.id));
+    Ok(payout)
+}
+
+#[ic_cdk::update]
+fn set_creator_revenue_config_admin(creator_revenue: CreatorRevenueConfig) -> Result<CanisterConfig, String> {
+    require(ic_cdk::caller(), Permission::ManageBilling)?;
+    update_config(|config| {
+        config.creator_revenue = creator_revenue;
+        Ok(())
+    })
+}
+
+// --- Trials ---
+//
+// New accounts get a trial plan grant, so it stacks and expires like any other grant. Each
+// email and principal can start one trial. The plan expiry job reminds users before a grant
+// ends and tells them which plan they are on once it has.
+
+const PLAN_EXPIRY_JOB_INTERVAL_NS: u64 = 60 * 60 * 1_000_000_000;
+const MAX_TRIAL_EXTENSION_DAYS: u32 = 90;
+
+// Folds case, "+tag" suffixes and Gmail's ignored dots so variants of one inbox match
+fn normalized_trial_email(email: &str) -> Option<String> {
+    let email = email.trim().to_lowercase();
+    let (local, domain) = email.split_once('@')?;
+    let local = local.split('+').next().unwrap_or(local);
+    let local = if domain == "gmail.com" || domain == "googlemail.com" { local.replace('.', "") } else { local.to_string() };
+    (!local.is_empty()).then(|| format!("email:{}@{}", local, domain))
+}
+
+fn trial_keys(user: &User) -> Vec<String> {
+    let mut keys = vec![format!("principal:{}", user.id)];
+    keys.extend(normalized_trial_email(&user.email));
+    keys
+}
+
+fn had_trial(user: &User) -> bool {
+    TRIAL_HISTORY.with(|history| {
+        let history = history.borrow();
+        trial_keys(user).iter().any(|key| history.contains_key(key))
+    })
+}
+
+// Called when an account is created; failures leave the user on their own plan
+fn start_trial(user: &User) {
+    let trial = get_config().trial;
+    if !trial.enabled || trial.days == 0 || had_trial(user) {
+        return;
+    }
+    let Ok(grant) = add_plan_grant(user.id, &trial.plan, trial.days as u64 * NANOS_PER_DAY, "trial", None, ic_cdk::api::id(), None) else {
+        return;
+    };
+    TRIAL_HISTORY.with(|history| {
+        let mut history = history.borrow_mut();
+        for key in trial_keys(user) {
+            history.insert(key, TrialRecord { user_id: user.id, grant_id: grant.id, started_at: grant.created_at });
+        }
+    });
+    notify_user(user.id, "success", "billing", format!("Your {}-day {} trial has started", trial.days, trial.plan), Some(grant.id));
+}
+
+fn grant_label(grant: &PlanGrant) -> String {
+    match grant.source.as_str() {
+        "trial" => format!("{} trial", grant.plan),
+        "gift" => format!("gifted {} plan", grant.plan),
+        _ => format!("{} plan", grant.plan),
+    }
+}
+
+fn notify_plan_expiry(now: u64) {
+    let reminder_ns = get_config().trial.reminder_days as u64 * NANOS_PER_DAY;
+    let due: Vec<PlanGrant> = PLAN_GRANTS.with(|grants| {
+        grants.borrow().values()
+            .filter(|g| g.revoked_at.is_none() && g.end_notified_at.is_none())
+            .filter(|g| g.ends_at <= now || (g.expiry_reminded_at.is_none() && g.ends_at <= now + reminder_ns && g.starts_at <= now))
+            .collect()
+    });
+    
+    for mut grant in due {
+        if grant.ends_at <= now {
+            grant.end_notified_at = Some(now);
+            notify_user(grant.user_id, "info", "billing", format!("Your {} has ended. You're now on the {} plan.", grant_label(&grant), user_plan(grant.user_id)), Some(grant.id));
+        } else {
+            grant.expiry_reminded_at = Some(now);
+            let days_left = (grant.ends_at - now).div_ceil(NANOS_PER_DAY);
+            notify_user(grant.user_id, "warning", "billing", format!("Your {} ends in {} day{}", grant_label(&grant), days_left, if days_left == 1 { "" } else { "s" }), Some(grant.id));
+        }
+        store_plan_grant(grant);
+    }
+}
+
+#[ic_cdk::query]
+fn get_my_trial() -> Option<PlanGrant> {
+    plan_grants_for(ic_cdk::caller()).into_iter().filter(|g| g.source == "trial").max_by_key(|g| g.created_at)
+}
+
+// Extends the user's latest trial, restarting it from now if it has already ended
+#[ic_cdk::update]
+fn extend_trial_admin(user_id: Principal, days: u32, reason: String) -> Result<PlanGrant, String> {
+    let caller = ic_cdk::caller();
+    require(caller, Permission::ManageBilling)?;
+    if !(1..=MAX_TRIAL_EXTENSION_DAYS).contains(&days) {
+        return Err(format!("Trials can be extended by 1 to {} days", MAX_TRIAL_EXTENSION_DAYS));
+    }
+    let mut grant = plan_grants_for(user_id).into_iter()
+        .filter(|g| g.source == "trial" && g.revoked_at.is_none())
+        .max_by_key(|g| g.created_at)
+        .ok_or("User has no trial")?;
+    
+    let now = ic_cdk::api::time();
+    grant.ends_at = grant.ends_at.max(now) + days as u64 * NANOS_PER_DAY;
+    grant.expiry_reminded_at = None;
+    grant.end_notified_at = None;
+    store_plan_grant(grant.clone());
+    record_audit(caller, "trial_extended", Some(user_id), format!("{} days: {}", days, reason.trim()));
+    notify_user(user_id, "success", "billing", format!("Your {} trial has been extended by {} days", grant.plan, days), Some(grant.id));
+    Ok(grant)
+}
+
+#[ic_cdk::update]
+fn set_trial_config_admin(trial: TrialConfig) -> Result<CanisterConfig, String> {
+    require(ic_cdk::caller(), Permission::ManageBilling)?;
+    if trial.enabled && plan_rank(&trial.plan).is_none() {
+        return Err(format!("Unknown plan '{}'", trial.plan));
+    }
+    update_config(|config| {
+        config.trial = trial;
+        Ok(())
+    })
+}
+
+// --- Experiments ---
+//
+// Admins A/B test tutor prompts or models. A learner is assigned a variant the first time
+// a tutor replies to them while an experiment runs, and keeps it. Outcomes are summed on
+// the assignment: reply ratings, comprehension scores, and whether the learner came back
+// retention_days later. Only one experiment of each kind runs at a time.
+
+const EXPERIMENT_KINDS: [&str; 2] = ["prompt", "model"];
+const MAX_EXPERIMENT_VARIANTS: usize = 4;
+
+fn experiment_assignment_key(experiment_id: u64, user_id: Principal) -> String {
+    format!("{:020}:{}", experiment_id, user_id)
+}
+
+fn running_experiments() -> Vec<Experiment> {
+    EXPERIMENTS.with(|experiments| experiments.borrow().values().filter(|e| e.status == "running").collect())
+}
+
+// Weighted and deterministic per learner, so it is stable even before it is stored
+fn pick_variant(experiment: &Experiment, user_id: Principal) -> ExperimentVariant {
+    use sha2::{Digest, Sha256};
+    let mut hasher = Sha256::new();
+    hasher.update(experiment.id.to_be_bytes());
+    hasher.update(user_id.as_slice());
+    let digest = hasher.finalize();
+    let total: u64 = experiment.variants.iter().map(|v| v.weight as u64).sum();
+    let mut point = u64::from_be_bytes(digest[..8].try_into().unwrap()) % total.max(1);
+    for variant in &experiment.variants {
+        if point < variant.weight as u64 {
+            return variant.clone();
+        }
+        point -= variant.weight as u64;
+    }
+    experiment.variants[0].clone()
+}
+
+// Assigns the learner to every running experiment and marks them active in each
+fn enrolled_variants(user_id: Principal, now: u64) -> Vec<(Experiment, ExperimentVariant)> {
+    if user_id == Principal::anonymous() {
+        return Vec::new();
+    }
+    running_experiments()
+        .into_iter()
+        .map(|experiment| {
+            let key = experiment_assignment_key(experiment.id, user_id);
+            let mut assignment = EXPERIMENT_ASSIGNMENTS.with(|a| a.borrow().get(&key)).unwrap_or_else(|| ExperimentAssignment {
+                experiment_id: experiment.id,
+                user_id,
+                variant: pick_variant(&experiment, user_id).key,
+                assigned_at: now,
+                last_active_at: now,
+                rating_sum: 0,
+                rating_count: 0,
+                comprehension_sum: 0.0,
+                comprehension_count: 0,
+            });
+            assignment.last_active_at = now;
+            let variant = experiment.variants.iter().find(|v| v.key == assignment.variant).cloned()
+                .unwrap_or_else(|| experiment.variants[0].clone());
+            EXPERIMENT_ASSIGNMENTS.with(|a| a.borrow_mut().insert(key, assignment));
+            (experiment, variant)
+        })
+        .collect()
+}
+
+// Tutor replies go through this so running experiments can change the prompt or model
+async fn call_tutor_ai(prompt: &str, operation: &str, tutor: &Tutor, learner: Principal, message_id: Option<&str>, acting: Principal) -> Result<String, String> {
+    let mut prompt = prompt.to_string();
+    let mut params = tutor.model_params.clone();
+    for (experiment, variant) in enrolled_variants(learner, ic_cdk::api::time()) {
+        if experiment.kind == "prompt" {
+            if let Some(suffix) = variant.prompt_suffix {
+                prompt = format!("{}\n\n{}", prompt, suffix);
+            }
+        } else if variant.model_params.is_some() {
+            params = variant.model_params;
+        }
+    }
+    call_ai_with_params(&prompt, operation, params.as_ref(), message_id, acting).await
+}
+
+// Applies an outcome to the learner's assignments made at or before `since`
+fn record_experiment_outcome<F: Fn(&mut ExperimentAssignment)>(user_id: Principal, since: u64, record: F) {
+    for experiment in running_experiments() {
+        let key = experiment_assignment_key(experiment.id, user_id);
+        EXPERIMENT_ASSIGNMENTS.with(|assignments| {
+            let mut assignments = assignments.borrow_mut();
+            if let Some(mut assignment) = assignments.get(&key).filter(|a| a.assigned_at <= since) {
+                record(&mut assignment);
+                assignments.insert(key, assignment);
+            }
+        });
+    }
+}
+
+#[ic_cdk::update]
+fn rate_tutor_message(session_id: String, message_id: String, rating: u8) -> Result<ChatMessage, String> {
+    let caller = ic_cdk::caller();
+    if !(1..=5).contains(&rating) {
+        return Err("Rating must be between 1 and 5".to_string());
+    }
+    let session = participant_session(&session_id, caller)?;
+    let message = CHAT_MESSAGES.with(|messages| messages.borrow().get(&session_id))
+        .and_then(|list| list.0.into_iter().find(|m| m.id == message_id))
+        .filter(|m| m.sender == "tutor" && m.delivery_status == "delivered")
+        .ok_or("Tutor reply not found")?;
+    
+    let previous = message.rating;
+    let message = update_chat_message(session.user_id, &session_id, &message_id, |m| m.rating = Some(rating))
+        .ok_or("Tutor reply not found")?;
+    record_experiment_outcome(caller, message.timestamp, |a| {
+        match previous {
+            Some(old) if a.rating_count > 0 => a.rating_sum = a.rating_sum.saturating_sub(old as u64) + rating as u64,
+            _ => {
+                a.rating_sum += rating as u64;
+                a.rating_count += 1;
+            }
+        }
+    });
+    Ok(message)
+}
+
+fn validate_experiment_variants(kind: &str, variants: &[ExperimentVariant]) -> Result<(), String> {
+    if variants.len() < 2 || variants.len() > MAX_EXPERIMENT_VARIANTS {
+        return Err(format!("Experiments need between 2 and {} variants", MAX_EXPERIMENT_VARIANTS));
+    }
+    let mut keys = std::collections::HashSet::new();
+    for variant in variants {
+        if variant.key.trim().is_empty() || !keys.insert(variant.key.trim()) {
+            return Err("Each variant needs a unique key".to_string());
+        }
+        if variant.weight == 0 {
+            return Err(format!("Variant {} needs a traffic weight above 0", variant.key));
+        }
+        match kind {
+            "prompt" if variant.model_params.is_some() => return Err("Prompt experiments can't change model params".to_string()),
+            "model" if variant.prompt_suffix.is_some() => return Err("Model experiments can't change the prompt".to_string()),
+            _ => {}
+        }
+        if let Some(params) = &variant.model_params {
+            validate_model_params(params, &get_config().model_defaults)?;
+        }
+    }
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn create_experiment_admin(name: String, description: Option<String>, kind: String, variants: Vec<ExperimentVariant>, retention_days: u32) -> Result<Experiment, String> {
+    let caller = ic_cdk::caller();
+    require(caller, Permission::ManageExperiments)?;
+    if name.trim().is_empty() {
+        return Err("Name is required".to_string());
+    }
+    if !EXPERIMENT_KINDS.contains(&kind.as_str()) {
+        return Err(format!("Kind must be one of: {}", EXPERIMENT_KINDS.join(", ")));
+    }
+    if retention_days == 0 {
+        return Err("Retention must be measured over at least 1 day".to_string());
+    }
+    validate_experiment_variants(&kind, &variants)?;
+    
+    let experiment = Experiment {
+        id: next_id("experiment"),
+        name: name.trim().to_string(),
+        description,
+        kind,
+        variants: variants.into_iter().map(|v| ExperimentVariant { key: v.key.trim().to_string(), ..v }).collect(),
+        retention_days,
+        status: "draft".to_string(),
+        created_by: caller,
+        created_at: ic_cdk::api::time(),
+        started_at: None,
+        stopped_at: None,
+    };
+    EXPERIMENTS.with(|experiments| experiments.borrow_mut().insert(experiment.id, experiment.clone()));
+    record_audit(caller, "experiment_created", None, format!("{} ({})", experiment.name, experiment.id));
+    Ok(experiment)
+}
+
+#[ic_cdk::update]
+fn start_experiment_admin(experiment_id: u64) -> Result<Experiment, String> {
+    let caller = ic_cdk::caller();
+    require(caller, Permission::ManageExperiments)?;
+    let mut experiment = EXPERIMENTS.with(|e| e.borrow().get(&experiment_id)).ok_or("Experiment not found")?;
+    if experiment.status != "draft" {
+        return Err(format!("Experiment is already {}", experiment.status));
+    }
+    if let Some(running) = running_experiments().into_iter().find(|e| e.kind == experiment.kind) {
+        return Err(format!("Stop '{}' first; only one {} experiment can run at a time", running.name, experiment.kind));
+    }
+    experiment.status = "running".to_string();
+    experiment.started_at = Some(ic_cdk::api::time());
+    EXPERIMENTS.with(|e| e.borrow_mut().insert(experiment_id, experiment.clone()));
+    record_audit(caller, "experiment_started", None, format!("{} ({})", experiment.name, experiment.id));
+    Ok(experiment)
+}
+
+// Stopped experiments keep their assignments so results stay available
+#[ic_cdk::update]
+fn stop_experiment_admin(experiment_id: u64) -> Result<Experiment, String> {
+    let caller = ic_cdk::caller();
+    require(caller, Permission::ManageExperiments)?;
+    let mut experiment = EXPERIMENTS.with(|e| e.borrow().get(&experiment_id)).ok_or("Experiment not found")?;
+    if experiment.status != "running" {
+        return Err("Experiment is not running".to_string());
+    }
+    experiment.status = "stopped".to_string();
+    experiment.stopped_at = Some(ic_cdk::api::time());
+    EXPERIMENTS.with(|e| e.borrow_mut().insert(experiment_id, experiment.clone()));
+    record_audit(caller, "experiment_stopped", None, format!("{} ({})", experiment.name, experiment.id));
+    Ok(experiment)
+}
+
+#[ic_cdk::query]
+fn get_experiments_admin() -> Result<Vec<Experiment>, String> {
+    require(ic_cdk::caller(), Permission::ManageExperiments)?;
+    Ok(EXPERIMENTS.with(|e| e.borrow().values().collect()))
+}
+
+fn lift(value: Option<f64>, control: Option<f64>) -> Option<f64> {
+    match (value, control) {
+        (Some(value), Some(control)) if control != 0.0 => Some((value - control) / control),
+        _ => None,
+    }
+}
+
+#[ic_cdk::query]
+fn get_experiment_results_admin(experiment_id: u64) -> Result<ExperimentResults, String> {
+    require(ic_cdk::caller(), Permission::ManageExperiments)?;
+    let experiment = EXPERIMENTS.with(|e| e.borrow().get(&experiment_id)).ok_or("Experiment not found")?;
+    let now = ic_cdk::api::time();
+    let retention_ns = experiment.retention_days as u64 * NANOS_PER_DAY;
+    let mut results: Vec<VariantResult> = experiment.variants.iter().map(|v| VariantResult {
+        variant: v.key.clone(),
+        learners: 0,
+        ratings: 0,
+        mean_rating: None,
+        rating_lift: None,
+        comprehension_samples: 0,
+        mean_comprehension: None,
+        comprehension_lift: None,
+        retention_eligible: 0,
+        retained: 0,
+        retention_rate: None,
+        retention_lift: None,
+    }).collect();
+    let mut rating_sums = vec![0u64; results.len()];
+    let mut comprehension_sums = vec![0f64; results.len()];
+    
+    let start = format!("{:020}:", experiment_id);
+    let end = format!("{:020};", experiment_id); // ';' sorts right after ':'
+    EXPERIMENT_ASSIGNMENTS.with(|assignments| -> Result<(), String> {
+        for (scanned, (_, a)) in assignments.borrow().range(start..end).enumerate() {
+            scan_checkpoint(scanned, "Results for very large experiments need to be computed offline.")?;
+            let Some(index) = results.iter().position(|r| r.variant == a.variant) else {
+                continue;
+            };
+            let result = &mut results[index];
+            result.learners += 1;
+            result.ratings += a.rating_count;
+            rating_sums[index] += a.rating_sum;
+            result.comprehension_samples += a.comprehension_count;
+            comprehension_sums[index] += a.comprehension_sum;
+            if a.assigned_at + retention_ns <= now {
+                result.retention_eligible += 1;
+                if a.last_active_at >= a.assigned_at + retention_ns {
+                    result.retained += 1;
+                }
+            }
+        }
+        Ok(())
+    })?;
+    
+    for (index, result) in results.iter_mut().enumerate() {
+        result.mean_rating = (result.ratings > 0).then(|| rating_sums[index] as f64 / result.ratings as f64);
+        result.mean_comprehension = (result.comprehension_samples > 0).then(|| comprehension_sums[index] / result.comprehension_samples as f64);
+        result.retention_rate = (result.retention_eligible > 0).then(|| result.retained as f64 / result.retention_eligible as f64);
+    }
+    let control = results[0].clone();
+    for result in results.iter_mut().skip(1) {
+        result.rating_lift = lift(result.mean_rating, control.mean_rating);
+        result.comprehension_lift = lift(result.mean_comprehension, control.mean_comprehension);
+        result.retention_lift = lift(result.retention_rate, control.retention_rate);
+    }
+    
+    Ok(ExperimentResults { experiment, variants: results, computed_at: now })
+}
+
+// --- External History Import ---
+//
+// Learners can bring conversations exported from ChatGPT (conversations.json), Claude
+// (conversations.json) or a generic [{title, messages: [{role, content, timestamp}]}] list.
+// Each conversation becomes an archived, read-only session under one of the learner's
+// tutors, and its summary is generated afterwards so the history informs later sessions.
+// Re-importing the same export skips conversations already imported.
+
+const MAX_IMPORTED_CONVERSATIONS: usize = 100;
+const MAX_IMPORTED_MESSAGES: usize = 1000;
+const MAX_IMPORTED_MESSAGE_CHARS: usize = 20_000;
+
+struct ExternalConversation {
+    id: String,
+    title: String,
+    created_at: Option<u64>,
+    messages: Vec<(String, String, Option<u64>)>, // sender ("user" or "tutor"), content, timestamp
+}
+
+fn ensure_session_writable(session: &ChatSession) -> Result<(), String> {
+    match &session.imported_from {
+        Some(source) => Err(format!("This session was imported from {} and is read-only", source)),
+        None => ensure_not_migrating(session.user_id),
+    }
+}
+
+fn detect_history_format(conversations: &[serde_json::Value]) -> Option<&'static str> {
+    let first = conversations.first()?;
+    if first.get("mapping").is_some() {
+        Some("chatgpt")
+    } else if first.get("chat_messages").is_some() {
+        Some("claude")
+    } else if first.get("messages").is_some() {
+        Some("generic")
+    } else {
+        None
+    }
+}
+
+fn imported_sender(role: &str) -> Option<&'static str> {
+    match role {
+        "user" | "human" => Some("user"),
+        "assistant" | "tutor" => Some("tutor"),
+        _ => None, // system prompts and tool calls
+    }
+}
+
+// Numbers are Unix seconds (ChatGPT); strings are ISO 8601 (Claude)
+fn imported_timestamp(value: &serde_json::Value) -> Option<u64> {
+    match value {
+        serde_json::Value::Number(n) => n.as_f64().filter(|s| *s > 0.0).map(|s| (s * 1_000_000_000.0) as u64),
+        serde_json::Value::String(s) => parse_iso8601(s),
+        _ => None,
+    }
+}
+
+// ChatGPT stores a tree of edits and regenerations; the shown branch runs from current_node up
+fn parse_chatgpt_conversation(value: &serde_json::Value) -> ExternalConversation {
+    let mapping = &value["mapping"];
+    let mut messages = Vec::new();
+    let mut node_id = value["current_node"].as_str().map(|s| s.to_string());
+    while let Some(id) = node_id {
+        let node = &mapping[id.as_str()];
+        let message = &node["message"];
+        let content: Vec<&str> = message["content"]["parts"].as_array()
+            .map(|parts| parts.iter().filter_map(|p| p.as_str()).collect())
+            .unwrap_or_default();
+        if let Some(sender) = message["author"]["role"].as_str().and_then(imported_sender) {
+            messages.push((sender.to_string(), content.join("\n"), imported_timestamp(&message["create_time"])));
+        }
+        node_id = node["parent"].as_str().map(|s| s.to_string());
+        if messages.len() > MAX_IMPORTED_MESSAGES * 2 {
+            break;
+        }
+    }
+    messages.reverse();
+    ExternalConversation {
+        id: value["id"].as_str().or_else(|| value["conversation_id"].as_str()).unwrap_or_default().to_string(),
+        title: value["title"].as_str().unwrap_or_default().to_string(),
+        created_at: imported_timestamp(&value["create_time"]),
+        messages,
+    }
+}
+
+fn parse_claude_conversation(value: &serde_json::Value) -> ExternalConversation {
+    let messages = value["chat_messages"].as_array().map(|list| {
+        list.iter().filter_map(|m| {
+            let sender = imported_sender(m["sender"].as_str()?)?;
+            let text = match m["text"].as_str().filter(|t| !t.is_empty()) {
+                Some(text) => text.to_string(),
+                None => m["content"].as_array()
+                    .map(|blocks| blocks.iter().filter_map(|b| b["text"].as_str()).collect::<Vec<_>>().join("\n"))
+                    .unwrap_or_default(),
+            };
+            Some((sender.to_string(), text, imported_timestamp(&m["created_at"])))
+        }).collect()
+    }).unwrap_or_default();
+    ExternalConversation {
+        id: value["uuid"].as_str().unwrap_or_default().to_string(),
+        title: value["name"].as_str().unwrap_or_default().to_string(),
+        created_at: imported_timestamp(&value["created_at"]),
+        messages,
+    }
+}
+
+fn parse_generic_conversation(value: &serde_json::Value, index: usize) -> ExternalConversation {
+    let messages = value["messages"].as_array().map(|list| {
+        list.iter().filter_map(|m| {
+            let sender = imported_sender(m["role"].as_str()?)?;
+            Some((sender.to_string(), m["content"].as_str()?.to_string(), imported_timestamp(&m["timestamp"])))
+        }).collect()
+    }).unwrap_or_default();
+    ExternalConversation {
+        id: value["id"].as_str().map(|s| s.to_string()).unwrap_or_else(|| index.to_string()),
+        title: value["title"].as_str().unwrap_or_default().to_string(),
+        created_at: imported_timestamp(&value["created_at"]),
+        messages,
+    }
+}
+
+// Stable per learner and conversation, so a second import of the same export is detected
+fn imported_session_id(user_id: Principal, format: &str, conversation_id: &str) -> String {
+    use sha2::{Digest, Sha256};
+    let digest = Sha256::digest(format!("{}:{}:{}", user_id, format, conversation_id).as_bytes());
+    let hex: String = digest[..8].iter().map(|b| format!("{:02x}", b)).collect();
+    format!("import_{}", hex)
+}
+
+#[ic_cdk::update]
+fn import_external_history(tutor_id: String, export_json: String, format: String) -> Result<HistoryImportReport, String> {
+    let caller = ic_cdk::caller();
+    get_self().ok_or("User not found")?;
+    let (_, tutor) = owned_tutor(&tutor_id, caller)?;
+    
+    let parsed: serde_json::Value = serde_json::from_str(&export_json).map_err(|e| format!("Invalid JSON: {}", e))?;
+    let conversations = match parsed {
+        serde_json::Value::Array(list) => list,
+        single @ serde_json::Value::Object(_) => vec![single],
+        _ => return Err("Expected a list of conversations".to_string()),
+    };
+    let format = match format.as_str() {
+        "auto" => detect_history_format(&conversations).ok_or("Unrecognized export format; choose chatgpt, claude or generic")?,
+        "chatgpt" => "chatgpt",
+        "claude" => "claude",
+        "generic" => "generic",
+        _ => return Err("Format must be auto, chatgpt, claude or generic".to_string()),
+    };
+    if conversations.len() > MAX_IMPORTED_CONVERSATIONS {
+        return Err(format!("Exports are imported {} conversations at a time; split the file and import it in parts", MAX_IMPORTED_CONVERSATIONS));
+    }
+    
+    let now = ic_cdk::api::time();
+    let mut skipped = Vec::new();
+    let mut pending: Vec<(ChatSession, Vec<ChatMessage>)> = Vec::new();
+    for (index, value) in conversations.iter().enumerate() {
+        let conversation = match format {
+            "chatgpt" => parse_chatgpt_conversation(value),
+            "claude" => parse_claude_conversation(value),
+            _ => parse_generic_conversation(value, index),
+        };
+        let label = if conversation.title.trim().is_empty() { format!("Conversation {}", index + 1) } else { conversation.title.trim().to_string() };
+        let session_id = imported_session_id(caller, format, &conversation.id);
+        if conversation.id.is_empty() {
+            skipped.push(format!("{}: missing conversation id", label));
+            continue;
+        }
+        if CHAT_SESSIONS.with(|sessions| sessions.borrow().contains_key(&session_id)) {
+            skipped.push(format!("{}: already imported", label));
+            continue;
+        }
+        
+        let fallback_time = conversation.created_at.unwrap_or(now);
+        let messages: Vec<ChatMessage> = conversation.messages.into_iter()
+            .filter(|(_, content, _)| !content.trim().is_empty())
+            .take(MAX_IMPORTED_MESSAGES)
+            .enumerate()
+            .map(|(i, (sender, content, timestamp))| {
+                let timestamp = timestamp.unwrap_or(fallback_time).min(now);
+                ChatMessage {
+                    id: format!("{}_{}", timestamp, i),
+                    session_id: session_id.clone(),
+                    author: (sender == "user").then_some(caller),
+                    sender,
+                    content: trim_to_length(&content, MAX_IMPORTED_MESSAGE_CHARS),
+                    timestamp,
+                    has_audio: Some(false),
+                    delivery_status: "delivered".to_string(),
+                    reading_grade: None,
+                    citations: Vec::new(),
+                    confidence: None,
+                    rating: None,
+                    bookmarked: false,
+                }
+            })
+            .collect();
+        if messages.is_empty() {
+            skipped.push(format!("{}: no messages", label));
+            continue;
+        }
+        
+        let session = ChatSession {
+            id: session_id,
+            tutor_id: tutor.public_id.clone(),
+            user_id: caller,
+            topic: trim_to_length(&label, 200),
+            status: "archived".to_string(),
+            created_at: messages.first().map(|m| m.timestamp).unwrap_or(fallback_time),
+            updated_at: messages.last().map(|m| m.timestamp).unwrap_or(fallback_time),
+            summary: None,
+            intake_answers: Vec::new(),
+            visibility: Visibility::Private,
+            archived_at: Some(now),
+            co_learners: Vec::new(),
+            owner_left_at: None,
+            imported_from: Some(format.to_string()),
+            unread_count: 0,
+            topic_drift: None,
+        };
+        pending.push((session, messages));
+    }
+    
+    let bytes: u64 = pending.iter().flat_map(|(_, messages)| messages.iter()).map(chat_message_bytes).sum();
+    check_storage_quota(caller, bytes)?;
+    
+    let mut messages_imported = 0;
+    let mut sessions = Vec::new();
+    for (session, messages) in pending {
+        messages_imported += messages.len() as u32;
+        CHAT_SESSIONS.with(|s| s.borrow_mut().insert(session.id.clone(), session.clone()));
+        certify::store_messages(caller, &session.id, ChatMessageList(messages));
+        sessions.push(session);
+    }
+    record_storage_change(caller, "messages", bytes as i64);
+    
+    let to_summarize: Vec<String> = sessions.iter().map(|s| s.id.clone()).collect();
+    ic_cdk::spawn(summarize_imported_sessions(caller, to_summarize));
+    
+    Ok(HistoryImportReport {
+        format: format.to_string(),
+        conversations_found: conversations.len() as u32,
+        sessions,
+        messages_imported,
+        skipped,
+    })
+}
+
+// One at a time so the learner's AI slot isn't contended
+async fn summarize_imported_sessions(user_id: Principal, session_ids: Vec<String>) {
+    for session_id in session_ids {
+        let mut recent: Vec<ChatMessage> = CHAT_MESSAGES.with(|messages| {
+            messages.borrow().get(&session_id).map(|list| list.0).unwrap_or_default()
+        });
+        recent.drain(..recent.len().saturating_sub(CLOSING_SUMMARY_MESSAGES));
+        let summary = summarize_pruned_messages(user_id, None, &recent).await;
+        CHAT_SESSIONS.with(|sessions| {
+            let mut sessions = sessions.borrow_mut();
+            if let Some(mut session) = sessions.get(&session_id) {
+                session.summary = Some(summary);
+                sessions.insert(session_id, session);
+            }
+        });
+    }
+}
+
+// --- Email Tutoring ---
+//
+// A learner can get a private address for each tutor they use. A trusted relay parses mail
+// sent to it and pushes it to receive_inbound_email, where it becomes a message in the
+// address's session. Once the tutor's reply is delivered it is queued and emailed back by
+// the heartbeat through the provider's HTTP API, with the same backoff as the xAPI outbox.
+
+const EMAIL_JOB_INTERVAL_NS: u64 = 60 * 1_000_000_000;
+const EMAIL_RETRY_BASE_NS: u64 = 60 * 1_000_000_000;
+const MAX_EMAIL_ATTEMPTS: u32 = 8;
+// Exchanges are kept this long after the mail arrived, whatever their status, so a relay
+// redelivering the same mail is recognized
+const EMAIL_EXCHANGE_RETENTION_NS: u64 = 7 * NANOS_PER_DAY;
+const EMAIL_EXPIRY_BATCH: usize = 100;
+
+fn email_index_key(at: u64, inbound_id: &str) -> String {
+    format!("{:020}:{}", at, inbound_id)
+}
+
+fn queue_email(exchange: &EmailExchange) {
+    EMAIL_QUEUE.with(|queue| {
+        queue.borrow_mut().insert(email_index_key(exchange.next_attempt_at, &exchange.inbound_message_id), exchange.inbound_message_id.clone())
+    });
+}
+
+// Indexes exchanges stored before the queue and expiry indexes existed
+fn index_email_exchanges() {
+    if EMAIL_EXPIRY.with(|expiry| !expiry.borrow().is_empty()) {
+        return;
+    }
+    for (id, exchange) in EMAIL_EXCHANGES.with(|exchanges| exchanges.borrow().iter().collect::<Vec<_>>()) {
+        EMAIL_EXPIRY.with(|expiry| expiry.borrow_mut().insert(email_index_key(exchange.received_at, &id), id.clone()));
+        if exchange.status == "queued" {
+            queue_email(&exchange);
+        }
+    }
+}
+
+thread_local! {
+    static EMAIL_DELIVERY_IN_FLIGHT: RefCell<bool> = const { RefCell::new(false) };
+}
+
+// Accepts "Name <learner@example.com>" as well as a bare address
+fn bare_email_address(value: &str) -> String {
+    let value = match (value.rfind('<'), value.rfind('>')) {
+        (Some(start), Some(end)) if start < end => &value[start + 1..end],
+        _ => value,
+    };
+    normalize_email(value)
+}
+
+// Drops the quoted conversation most mail clients append to a reply
+fn strip_quoted_reply(text: &str) -> String {
+    let mut lines = Vec::new();
+    for line in text.lines() {
+        let trimmed = line.trim();
+        if (trimmed.starts_with("On ") && trimmed.ends_with("wrote:")) || trimmed == "-----Original Message-----" {
+            break;
+        }
+        if !trimmed.starts_with('>') {
+            lines.push(line);
+        }
+    }
+    lines.join("\n").trim().to_string()
+}
+
+fn email_topic(subject: &str) -> String {
+    let mut topic = subject.trim();
+    while let Some(rest) = topic.strip_prefix("Re:").or_else(|| topic.strip_prefix("RE:")).or_else(|| topic.strip_prefix("Fwd:")) {
+        topic = rest.trim();
+    }
+    if topic.is_empty() { "Email questions".to_string() } else { trim_to_length(topic, 200) }
+}
+
+fn email_enabled(config: &CanisterConfig) -> Result<(), String> {
+    if !config.email.enabled || config.email.inbound_domain.is_empty() {
+        return Err("Email tutoring is not enabled".to_string());
+    }
+    Ok(())
+}
+
+#[ic_cdk::update]
+async fn get_tutor_email_address(tutor_id: String) -> Result<TutorEmailAddress, String> {
+    let caller = ic_cdk::caller();
+    let config = get_config();
+    email_enabled(&config)?;
+    get_self().ok_or("User not found")?;
+    let (_, tutor) = visible_tutor(&tutor_id, caller)
+        .ok()
+        .filter(|(_, t)| t.user_id == caller || t.visibility == Visibility::Public)
+        .ok_or("Tutor not found")?;
+    
+    let existing = || TUTOR_EMAIL_ADDRESSES.with(|addresses| {
+        addresses.borrow().iter().map(|(_, a)| a).find(|a| a.user_id == caller && a.tutor_id == tutor.public_id && !a.disabled)
+    });
+    if let Some(address) = existing() {
+        return Ok(address);
+    }
+    let token = hex_encode(&random_bytes().await?[..8]);
+    // Another call may have created one while we were waiting on raw_rand
+    if let Some(address) = existing() {
+        return Ok(address);
+    }
+    
+    let address = TutorEmailAddress {
+        address: format!("{}@{}", token, config.email.inbound_domain),
+        token: token.clone(),
+        user_id: caller,
+        tutor_id: tutor.public_id.clone(),
+        session_id: None,
+        created_at: ic_cdk::api::time(),
+        disabled: false,
+    };
+    TUTOR_EMAIL_ADDRESSES.with(|addresses| addresses.borrow_mut().insert(token, address.clone()));
+    Ok(address)
+}
+
+#[ic_cdk::query]
+fn get_my_tutor_email_addresses() -> Vec<TutorEmailAddress> {
+    let caller = ic_cdk::caller();
+    TUTOR_EMAIL_ADDRESSES.with(|addresses| {
+        addresses.borrow().iter().map(|(_, a)| a).filter(|a| a.user_id == caller).collect()
+    })
+}
+
+// A leaked address can be turned off; asking again issues a new one
+#[ic_cdk::update]
+fn disable_tutor_email_address(token: String) -> Result<TutorEmailAddress, String> {
+    let caller = ic_cdk::caller();
+    let mut address = TUTOR_EMAIL_ADDRESSES.with(|addresses| addresses.borrow().get(&token))
+        .filter(|a| a.user_id == caller)
+        .ok_or("Email address not found")?;
+    address.disabled = true;
+    TUTOR_EMAIL_ADDRESSES.with(|addresses| addresses.borrow_mut().insert(token, address.clone()));
+    Ok(address)
+}
+
+// The session mail continues in, or a new one when the last was closed or deleted
+fn email_session(address: &mut TutorEmailAddress, subject: &str, now: u64) -> ChatSession {
+    let current = address.session_id.as_ref()
+        .and_then(|id| CHAT_SESSIONS.with(|sessions| sessions.borrow().get(id)))
+        .filter(|s| s.status == "active");
+    if let Some(session) = current {
+        return session;
+    }
+    let session = ChatSession {
+        id: format!("session_{}_{}", now, address.token),
+        tutor_id: address.tutor_id.clone(),
+        user_id: address.user_id,
+        topic: email_topic(subject),
+        status: "active".to_string(),
+        created_at: now,
+        updated_at: now,
+        summary: None,
+        intake_answers: Vec::new(),
+        visibility: Visibility::Private,
+        archived_at: None,
+        co_learners: Vec::new(),
+        owner_left_at: None,
+        imported_from: None,
+        unread_count: 0,
+        topic_drift: None,
+    };
+    CHAT_SESSIONS.with(|sessions| sessions.borrow_mut().insert(session.id.clone(), session.clone()));
+    address.session_id = Some(session.id.clone());
+    session
+}
+
+// Called by the relay. Returns the id of the stored message; redelivering the same
+// Message-ID returns the original id without storing anything.
+#[ic_cdk::update]
+fn receive_inbound_email(email: InboundEmail) -> Result<String, String> {
+    let config = get_config();
+    if !config.email.relay_principals.contains(&ic_cdk::caller()) {
+        return Err("Only the email relay can deliver inbound mail".to_string());
+    }
+    email_enabled(&config)?;
+    if email.message_id.trim().is_empty() {
+        return Err("Message-ID is required".to_string());
+    }
+    if let Some(exchange) = EMAIL_EXCHANGES.with(|exchanges| exchanges.borrow().get(&email.message_id)) {
+        return Ok(exchange.user_message_id);
+    }
+    
+    let to = bare_email_address(&email.to);
+    let (token, domain) = to.split_once('@').ok_or("Invalid recipient address")?;
+    if domain != config.email.inbound_domain.to_lowercase() {
+        return Err("Recipient is not a tutor address".to_string());
+    }
+    let mut address = TUTOR_EMAIL_ADDRESSES.with(|addresses| addresses.borrow().get(&token.to_string()))
+        .filter(|a| !a.disabled)
+        .ok_or("This tutor address is not active")?;
+    let owner = address.user_id;
+    let user = cache::user(owner).ok_or("User not found")?;
+    if !user.is_verified || bare_email_address(&email.from) != normalize_email(&user.email) {
+        return Err("Sender does not match the owner of this tutor address".to_string());
+    }
+    cache::tutor_by_public_id(&address.tutor_id)
+        .filter(|(_, t)| t.user_id == owner || t.visibility == Visibility::Public)
+        .ok_or("Tutor not found")?;
+    let content = trim_to_length(&strip_quoted_reply(&email.text), config.email.max_body_chars as usize);
+    if content.is_empty() {
+        return Err("The email has no message text".to_string());
+    }
+    // The relay delivers for many users, so the owner's own window applies
+    rate_limit::check(owner, "chat")?;
+    
+    let now = ic_cdk::api::time();
+    let session = email_session(&mut address, &email.subject, now);
+    TUTOR_EMAIL_ADDRESSES.with(|addresses| addresses.borrow_mut().insert(address.token.clone(), address.clone()));
+    let (user_message, reply_message_id) = relay_user_message(&session, owner, &content, Some(email.message_id.clone()))?;
+    
+    EMAIL_EXCHANGES.with(|exchanges| {
+        exchanges.borrow_mut().insert(email.message_id.clone(), EmailExchange {
+            inbound_message_id: email.message_id.clone(),
+            token: address.token.clone(),
+            user_id: owner,
+            session_id: session.id.clone(),
+            user_message_id: user_message.id.clone(),
+            reply_message_id: reply_message_id.clone(),
+            reply_to: user.email.clone(),
+            subject: email.subject.clone(),
+            status: "awaiting_reply".to_string(),
+            attempts: 0,
+            last_error: None,
+            next_attempt_at: now,
+            received_at: now,
+            sent_at: None,
+        });
+    });
+    EMAIL_EXPIRY.with(|expiry| expiry.borrow_mut().insert(email_index_key(now, &email.message_id), email.message_id.clone()));
+    
+    // The relay shouldn't wait on the AI call; failed replies retry like any other
+    ic_cdk::spawn(async move {
+        if let Err(e) = deliver_tutor_reply(&reply_message_id, owner).await {
+            ic_cdk::println!("Email reply {} not delivered yet: {}", reply_message_id, e);
+        }
+    });
+    Ok(user_message.id)
+}
+
+// Called once a reply is delivered, or has failed for good
+fn settle_email_exchange(delivery: &PendingDelivery, error: Option<&str>, now: u64) {
+    let Some(inbound_id) = &delivery.email_reply_to else {
+        return;
+    };
+    EMAIL_EXCHANGES.with(|exchanges| {
+        let mut exchanges = exchanges.borrow_mut();
+        if let Some(mut exchange) = exchanges.get(inbound_id) {
+            match error {
+                None => {
+                    exchange.status = "queued".to_string();
+                    exchange.next_attempt_at = now;
+                    queue_email(&exchange);
+                }
+                Some(e) => {
+                    exchange.status = "failed".to_string();
+                    exchange.last_error = Some(format!("No tutor reply: {}", e));
+                }
+            }
+            exchanges.insert(inbound_id.clone(), exchange);
+        }
+    });
+}
+
+async fn post_email(config: &CanisterConfig, exchange: &EmailExchange, tutor_name: &str, body: &str) -> Result<(), String> {
+    let reply_address = format!("{}@{}", exchange.token, config.email.inbound_domain);
+    let payload = json!({
+        "from": format!("{} <{}>", tutor_name, config.email.from_address),
+        "reply_to": reply_address,
+        "to": [exchange.reply_to],
+        "subject": format!("Re: {}", email_topic(&exchange.subject)),
+        "text": body,
+        "headers": { "In-Reply-To": exchange.inbound_message_id, "References": exchange.inbound_message_id },
+    });
+    post_to_email_provider(config, payload, &exchange.reply_message_id).await
+}
+
+async fn post_to_email_provider(config: &CanisterConfig, payload: serde_json::Value, idempotency_key: &str) -> Result<(), String> {
+    let request = CanisterHttpRequestArgument {
+        url: config.email.provider_url.clone(),
+        max_response_bytes: Some(16 * 1024),
+        method: HttpMethod::POST,
+        headers: vec![
+            HttpHeader { name: "Content-Type".to_string(), value: "application/json".to_string() },
+            HttpHeader { name: "Authorization".to_string(), value: format!("Bearer {}", config.email.api_key) },
+            // Every replica makes the outcall, so the provider must collapse them into one email
+            HttpHeader { name: "Idempotency-Key".to_string(), value: idempotency_key.to_string() },
+        ],
+        body: Some(payload.to_string().into_bytes()),
+        transform: Some(TransformContext::from_name("transform_ai_response".to_string(), vec![])),
+    };
+    let budget = outcall_budget(config, "email");
+    let (response,) = ic_cdk::api::management_canister::http_request::http_request(request, budget.cycles as u128)
+        .await
+        .map_err(|(code, msg)| format!("HTTP outcall failed: {:?} - {}", code, msg))?;
+    
+    let status: u32 = response.status.0.try_into().unwrap_or(0);
+    if !(200..300).contains(&status) {
+        return Err(format!("Email provider returned status {}", status));
+    }
+    Ok(())
+}
+
+// Drops a bounded batch of exchanges past retention, along with any queued send
+fn expire_email_exchanges(now: u64) {
+    let cutoff = email_index_key(now.saturating_sub(EMAIL_EXCHANGE_RETENTION_NS), "");
+    let expired: Vec<(String, String)> = EMAIL_EXPIRY.with(|expiry| {
+        expiry.borrow().range(..cutoff).take(EMAIL_EXPIRY_BATCH).collect()
+    });
+    for (key, id) in expired {
+        EMAIL_EXPIRY.with(|expiry| expiry.borrow_mut().remove(&key));
+        if let Some(exchange) = EMAIL_EXCHANGES.with(|exchanges| exchanges.borrow_mut().remove(&id)) {
+            EMAIL_QUEUE.with(|queue| queue.borrow_mut().remove(&email_index_key(exchange.next_attempt_at, &id)));
+        }
+    }
+}
+
+async fn send_email_batch(now: u64) {
+    let config = get_config();
+    expire_email_exchanges(now);
+    let due: Vec<(String, String)> = EMAIL_QUEUE.with(|queue| {
+        queue.borrow().range(..format!("{:020};", now)).take(config.email.batch_size.max(1) as usize).collect()
+    });
+    let mut batch = Vec::new();
+    for (key, id) in due {
+        EMAIL_QUEUE.with(|queue| queue.borrow_mut().remove(&key));
+        if let Some(exchange) = EMAIL_EXCHANGES.with(|exchanges| exchanges.borrow().get(&id)).filter(|e| e.status == "queued") {
+            batch.push(exchange);
+        }
+    }
+    
+    for mut exchange in batch {
+        let session = CHAT_SESSIONS.with(|sessions| sessions.borrow().get(&exchange.session_id));
+        let reply = CHAT_MESSAGES.with(|messages| messages.borrow().get(&exchange.session_id))
+            .and_then(|list| list.0.into_iter().find(|m| m.id == exchange.reply_message_id));
+        let tutor_name = session
+            .and_then(|s| cache::tutor_by_public_id(&s.tutor_id))
+            .map(|(_, t)| t.name)
+            .unwrap_or_else(|| "Your tutor".to_string());
+        let result = match reply {
+            Some(reply) => post_email(&config, &exchange, &tutor_name, &reply.content).await,
+            None => Err("The reply was deleted before it was sent".to_string()),
+        };
+        
+        exchange.attempts += 1;
+        match result {
+            Ok(()) => {
+                exchange.status = "sent".to_string();
+                exchange.sent_at = Some(ic_cdk::api::time());
+                exchange.last_error = None;
+            }
+            Err(e) => {
+                if exchange.attempts >= MAX_EMAIL_ATTEMPTS || e.starts_with("The reply was deleted") {
+                    exchange.status = "failed".to_string();
+                }
+                exchange.next_attempt_at = now + EMAIL_RETRY_BASE_NS * (1u64 << exchange.attempts.min(10));
+                exchange.last_error = Some(e);
+                if exchange.status == "queued" {
+                    queue_email(&exchange);
+                }
+            }
+        }
+        EMAIL_EXCHANGES.with(|exchanges| exchanges.borrow_mut().insert(exchange.inbound_message_id.clone(), exchange));
+    }
+}
+
+fn run_email_delivery(now: u64) {
+    if !get_config().email.enabled || EMAIL_DELIVERY_IN_FLIGHT.with(|f| f.replace(true)) {
+        return;
+    }
+    ic_cdk::spawn(async move {
+        send_email_batch(now).await;
+        EMAIL_DELIVERY_IN_FLIGHT.with(|f| *f.borrow_mut() = false);
+    });
+}
+
+#[ic_cdk::query]
+fn get_email_exchanges_admin(status: Option<String>, limit: u32) -> Result<Vec<EmailExchange>, String> {
+    require(ic_cdk::caller(), Permission::ManageSystem)?;
+    let exchanges: Vec<EmailExchange> = EMAIL_EXCHANGES.with(|exchanges| {
+        exchanges.borrow()
+            .iter()
+            .map(|(_, e)| e)
+            .filter(|e| status.as_ref().is_none_or(|s| &e.status == s))
+            .take(limit.clamp(1, 500) as usize)
+            .collect()
+    });
+    ensure_fits(&exchanges, "Lower the limit or filter by status.")?;
+    Ok(exchanges)
+}
+
+#[ic_cdk::update]
+fn set_email_config_admin(email: EmailConfig) -> Result<CanisterConfig, String> {
+    let caller = ic_cdk::caller();
+    require(caller, Permission::ManageSystem)?;
+    if email.enabled {
+        if email.inbound_domain.trim().is_empty() || email.from_address.trim().is_empty() {
+            return Err("An inbound domain and a from address are required".to_string());
+        }
+        if !email.provider_url.starts_with("https://") {
+            return Err("The email provider must use an https endpoint".to_string());
+        }
+        if email.relay_principals.is_empty() {
+            return Err("At least one relay principal is required to receive mail".to_string());
+        }
+    }
+    if email.batch_size == 0 || email.batch_size > 100 {
+        return Err("Batch size must be between 1 and 100".to_string());
+    }
+    if email.max_body_chars == 0 {
+        return Err("The body limit must be above zero".to_string());
+    }
+    
+    let updated = update_config(|config| {
+        // Credentials come back redacted from get_config_admin, so an empty value keeps the stored one
+        let mut email = email;
+        if email.api_key.is_empty() {
+            email.api_key = config.email.api_key.clone();
+        }
+        email.inbound_domain = email.inbound_domain.trim().to_lowercase();
+        config.email = email;
+        Ok(())
+    })?;
+    record_audit(caller, "set_email_config", None, format!("enabled: {}", updated.email.enabled));
+    Ok(updated)
+}
+
+// --- Chat Bot Bridges ---
+//
+// Lets a Telegram or Discord bot relay a platform thread to a session. An admin creates a
+// bridge and hands its service key to the bot, which passes it on every call. Learners link
+// their platform account once with a code from the app; after that the bot can open
+// threads for them and relay messages, which go through the same quota checks and reply
+// pipeline as the web app. Tutor replies come back from bridge_send_message, and replies
+// delivered later (queued or retried) are picked up with bridge_pull_updates.
+
+const BOT_PLATFORMS: [&str; 2] = ["telegram", "discord"];
+const BOT_LINK_CODE_TTL_NS: u64 = 15 * 60 * 1_000_000_000;
+const BOT_LINK_CODE_ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+const MAX_BRIDGE_MESSAGE_CHARS: usize = 4_000;
+const MAX_BRIDGE_UPDATES: u32 = 200;
+
+fn sha256_hex(text: &str) -> String {
+    use sha2::{Digest, Sha256};
+    hex_encode(&Sha256::digest(text.as_bytes()))
+}
+
+fn authenticated_bridge(service_key: &str) -> Result<BotBridge, String> {
+    let hash = sha256_hex(service_key);
+    BOT_BRIDGES.with(|bridges| bridges.borrow().iter().map(|(_, b)| b).find(|b| b.key_hash == hash))
+        .filter(|b| b.enabled)
+        .ok_or_else(|| "Invalid service key".to_string())
+}
+
+fn bot_key(platform: &str, external_id: &str) -> String {
+    format!("{}:{}", platform, external_id)
+}
+
+fn linked_user(bridge: &BotBridge, external_user_id: &str) -> Result<BotLink, String> {
+    BOT_LINKS.with(|links| links.borrow().get(&bot_key(&bridge.platform, external_user_id)))
+        .ok_or_else(|| "This account is not linked yet; send the link code from the app's settings".to_string())
+}
+
+fn bot_thread(bridge: &BotBridge, external_user_id: &str, thread_id: &str) -> Result<BotThread, String> {
+    BOT_THREADS.with(|threads| threads.borrow().get(&bot_key(&bridge.platform, thread_id)))
+        .filter(|t| t.external_user_id == external_user_id)
+        .ok_or_else(|| "No session is open in this thread".to_string())
+}
+
+// Delivered tutor messages the bot hasn't been given yet, oldest first; advances the cursor
+fn take_thread_updates(thread: &mut BotThread, limit: usize) -> Vec<ChatMessage> {
+    let messages: Vec<ChatMessage> = CHAT_MESSAGES.with(|messages| messages.borrow().get(&thread.session_id))
+        .map(|list| list.0)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|m| m.sender == "tutor" && m.delivery_status == "delivered" && m.timestamp > thread.relayed_through)
+        .take(limit)
+        .collect();
+    if let Some(newest) = messages.iter().map(|m| m.timestamp).max() {
+        thread.relayed_through = newest;
+        BOT_THREADS.with(|threads| threads.borrow_mut().insert(bot_key(&thread.platform, &thread.thread_id), thread.clone()));
+    }
+    messages
+}
+
+#[ic_cdk::update]
+async fn create_bot_bridge_admin(name: String, platform: String) -> Result<String, String> {
+    let caller = ic_cdk::caller();
+    require(caller, Permission::ManageSystem)?;
+    let name = name.trim().to_string();
+    if name.is_empty() {
+        return Err("Bridge name is required".to_string());
+    }
+    if !BOT_PLATFORMS.contains(&platform.as_str()) {
+        return Err(format!("Platform must be one of: {}", BOT_PLATFORMS.join(", ")));
+    }
+    
+    let service_key = format!("cbk_{}", hex_encode(&random_bytes().await?));
+    if BOT_BRIDGES.with(|bridges| bridges.borrow().contains_key(&name)) {
+        return Err("A bridge with this name already exists".to_string());
+    }
+    BOT_BRIDGES.with(|bridges| {
+        bridges.borrow_mut().insert(name.clone(), BotBridge {
+            name: name.clone(),
+            platform: platform.clone(),
+            key_hash: sha256_hex(&service_key),
+            enabled: true,
+            created_at: ic_cdk::api::time(),
+        });
+    });
+    record_audit(caller, "create_bot_bridge", None, format!("{} ({})", name, platform));
+    Ok(service_key)
+}
+
+// Disabling a bridge is how a leaked service key is revoked
+#[ic_cdk::update]
+fn set_bot_bridge_enabled_admin(name: String, enabled: bool) -> Result<BotBridge, String> {
+    let caller = ic_cdk::caller();
+    require(caller, Permission::ManageSystem)?;
+    let mut bridge = BOT_BRIDGES.with(|bridges| bridges.borrow().get(&name)).ok_or("Bridge not found")?;
+    bridge.enabled = enabled;
+    BOT_BRIDGES.with(|bridges| bridges.borrow_mut().insert(name.clone(), bridge.clone()));
+    record_audit(caller, "set_bot_bridge_enabled", None, format!("{}: {}", name, enabled));
+    Ok(bridge)
+}
+
+#[ic_cdk::query]
+fn get_bot_bridges_admin() -> Result<Vec<BotBridge>, String> {
+    require(ic_cdk::caller(), Permission::ManageSystem)?;
+    Ok(BOT_BRIDGES.with(|bridges| bridges.borrow().iter().map(|(_, b)| b).collect()))
+}
+
+#[ic_cdk::update]
+async fn create_bot_link_code(platform: String) -> Result<BotLinkCode, String> {
+    let caller = ic_cdk::caller();
+    get_self().ok_or("User not found")?;
+    if !BOT_PLATFORMS.contains(&platform.as_str()) {
+        return Err(format!("Platform must be one of: {}", BOT_PLATFORMS.join(", ")));
+    }
+    
+    let bytes = random_bytes().await?;
+    let code: String = bytes.iter().take(8).map(|b| BOT_LINK_CODE_ALPHABET[*b as usize % BOT_LINK_CODE_ALPHABET.len()] as char).collect();
+    let now = ic_cdk::api::time();
+    let link_code = BotLinkCode { code: code.clone(), user_id: caller, platform, expires_at: now + BOT_LINK_CODE_TTL_NS };
+    BOT_LINK_CODES.with(|codes| {
+        let mut codes = codes.borrow_mut();
+        // One live code per learner and platform; expired codes are dropped along the way
+        let stale: Vec<String> = codes.iter()
+            .filter(|(_, c)| c.expires_at <= now || (c.user_id == caller && c.platform == link_code.platform))
+            .map(|(code, _)| code)
+            .collect();
+        for code in stale {
+            codes.remove(&code);
+        }
+        codes.insert(code, link_code.clone());
+    });
+    Ok(link_code)
+}
+
+#[ic_cdk::query]
+fn get_my_bot_links() -> Vec<BotLink> {
+    let caller = ic_cdk::caller();
+    BOT_LINKS.with(|links| links.borrow().iter().map(|(_, l)| l).filter(|l| l.user_id == caller).collect())
+}
+
+fn remove_bot_link(platform: &str, external_user_id: &str) {
+    BOT_LINKS.with(|links| links.borrow_mut().remove(&bot_key(platform, external_user_id)));
+    BOT_THREADS.with(|threads| {
+        let mut threads = threads.borrow_mut();
+        let start = format!("{}:", platform);
+        let end = format!("{};", platform);
+        let linked: Vec<String> = threads.range(start..end)
+            .filter(|(_, t)| t.external_user_id == external_user_id)
+            .map(|(key, _)| key)
+            .collect();
+        for key in linked {
+            threads.remove(&key);
+        }
+    });
+}
+
+#[ic_cdk::update]
+fn unlink_bot_account(platform: String, external_user_id: String) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    BOT_LINKS.with(|links| links.borrow().get(&bot_key(&platform, &external_user_id)))
+        .filter(|l| l.user_id == caller)
+        .ok_or("Linked account not found")?;
+    remove_bot_link(&platform, &external_user_id);
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn bridge_link_account(service_key: String, external_user_id: String, code: String) -> Result<BotLink, String> {
+    let bridge = authenticated_bridge(&service_key)?;
+    let now = ic_cdk::api::time();
+    let code = code.trim().to_uppercase();
+    let link_code = BOT_LINK_CODES.with(|codes| codes.borrow().get(&code))
+        .filter(|c| c.platform == bridge.platform && c.expires_at > now)
+        .ok_or("This link code is invalid or has expired")?;
+    if external_user_id.trim().is_empty() {
+        return Err("External user id is required".to_string());
+    }
+    
+    BOT_LINK_CODES.with(|codes| codes.borrow_mut().remove(&code));
+    // Relinking to a different learner drops the threads opened for the previous one
+    let key = bot_key(&bridge.platform, &external_user_id);
+    if BOT_LINKS.with(|links| links.borrow().get(&key)).is_some_and(|l| l.user_id != link_code.user_id) {
+        remove_bot_link(&bridge.platform, &external_user_id);
+    }
+    let link = BotLink { platform: bridge.platform.clone(), external_user_id, user_id: link_code.user_id, linked_at: now };
+    BOT_LINKS.with(|links| links.borrow_mut().insert(key, link.clone()));
+    notify_user(
+        link.user_id,
+        "account_linked",
+        "bot_bridge",
+        format!("Your {} account is now linked and can chat with your tutors.", bridge.platform),
+        None,
+    );
+    Ok(link)
+}
+
+#[ic_cdk::update]
+fn bridge_unlink_account(service_key: String, external_user_id: String) -> Result<(), String> {
+    let bridge = authenticated_bridge(&service_key)?;
+    linked_user(&bridge, &external_user_id)?;
+    remove_bot_link(&bridge.platform, &external_user_id);
+    Ok(())
+}
+
+// Binds a platform thread to a new session with the tutor, or to one of the learner's
+// existing sessions when session_id is given
+#[ic_cdk::update]
+fn bridge_open_thread(
+    service_key: String,
+    external_user_id: String,
+    thread_id: String,
+    tutor_id: String,
+    topic: String,
+    session_id: Option<String>,
+) -> Result<ChatSession, String> {
+    let bridge = authenticated_bridge(&service_key)?;
+    let link = linked_user(&bridge, &external_user_id)?;
+    let now = ic_cdk::api::time();
+    
+    let session = match session_id {
+        Some(session_id) => {
+            let session = participant_session(&session_id, link.user_id)?;
+            ensure_session_writable(&session)?;
+            session
+        }
+        None => {
+            let (_, tutor) = visible_tutor(&tutor_id, link.user_id)
+                .ok()
+                .filter(|(_, t)| t.user_id == link.user_id || t.visibility == Visibility::Public)
+                .ok_or("Tutor not found")?;
+            let session = ChatSession {
+                id: format!("session_{}_{}", now, &sha256_hex(&bot_key(&bridge.platform, &thread_id))[..12]),
+                tutor_id: tutor.public_id.clone(),
+                user_id: link.user_id,
+                topic: if topic.trim().is_empty() { format!("Chat with {}", tutor.name) } else { trim_to_length(topic.trim(), 200) },
+                status: "active".to_string(),
+                created_at: now,
+                updated_at: now,
+                summary: None,
+                intake_answers: Vec::new(),
+                visibility: Visibility::Private,
+                archived_at: None,
+                co_learners: Vec::new(),
+                owner_left_at: None,
+                imported_from: None,
+                unread_count: 0,
+                topic_drift: None,
+            };
+            CHAT_SESSIONS.with(|sessions| sessions.borrow_mut().insert(session.id.clone(), session.clone()));
+            session
+        }
+    };
+    
+    // Earlier messages stay in the app; the thread only receives what arrives from now on
+    BOT_THREADS.with(|threads| {
+        threads.borrow_mut().insert(bot_key(&bridge.platform, &thread_id), BotThread {
+            platform: bridge.platform.clone(),
+            thread_id,
+            external_user_id,
+            user_id: link.user_id,
+            session_id: session.id.clone(),
+            relayed_through: now,
+            created_at: now,
+        });
+    });
+    Ok(session)
+}
+
+// Returns the tutor messages to post in the thread, normally just the reply. When the reply
+// is queued the list may be empty and the reply arrives through bridge_pull_updates.
+#[ic_cdk::update]
+async fn bridge_send_message(service_key: String, external_user_id: String, thread_id: String, content: String) -> Result<Vec<ChatMessage>, String> {
+    let bridge = authenticated_bridge(&service_key)?;
+    let link = linked_user(&bridge, &external_user_id)?;
+    // The bridge relays for many users, so each linked user gets their own window
+    rate_limit::check(link.user_id, "chat")?;
+    let thread = bot_thread(&bridge, &external_user_id, &thread_id)?;
+    let session = participant_session(&thread.session_id, link.user_id)?;
+    let content = content.trim();
+    if content.is_empty() {
+        return Err("Message content is required".to_string());
+    }
+    if content.chars().count() > MAX_BRIDGE_MESSAGE_CHARS {
+        return Err(format!("Messages are limited to {} characters", MAX_BRIDGE_MESSAGE_CHARS));
+    }
+    
+    let (_, reply_message_id) = relay_user_message(&session, link.user_id, content, None)?;
+    // A slow reply reaches the bot through bridge_pull_updates instead
+    if reply_in_background(&reply_message_id) {
+        ic_cdk::spawn(async move {
+            if let Err(e) = deliver_tutor_reply(&reply_message_id, link.user_id).await {
+                ic_cdk::println!("Background reply {} not delivered yet: {}", reply_message_id, e);
+            }
+        });
+    } else {
+        match deliver_tutor_reply(&reply_message_id, link.user_id).await {
+            Err(e) if e != REPLY_QUEUED_MESSAGE && e != REPLY_DEFERRED_MESSAGE => return Err(e),
+            _ => {}
+        }
+    }
+    // Re-read: the thread's cursor may have moved while the reply was generated
+    let mut thread = bot_thread(&bridge, &external_user_id, &thread_id)?;
+    Ok(take_thread_updates(&mut thread, MAX_BRIDGE_UPDATES as usize))
+}
+
+#[ic_cdk::update]
+fn bridge_pull_updates(service_key: String, limit: u32) -> Result<Vec<BridgeUpdate>, String> {
+    let bridge = authenticated_bridge(&service_key)?;
+    let limit = limit.clamp(1, MAX_BRIDGE_UPDATES) as usize;
+    let start = format!("{}:", bridge.platform);
+    let end = format!("{};", bridge.platform);
+    let threads: Vec<BotThread> = BOT_THREADS.with(|threads| threads.borrow().range(start..end).map(|(_, t)| t).collect());
+    
+    let mut updates = Vec::new();
+    for mut thread in threads {
+        if updates.len() >= limit {
+            break;
+        }
+        for message in take_thread_updates(&mut thread, limit - updates.len()) {
+            updates.push(BridgeUpdate {
+                thread_id: thread.thread_id.clone(),
+                external_user_id: thread.external_user_id.clone(),
+                message,
+            });
+        }
+    }
+    Ok(updates)
+}
+
+// --- Public Catalog API ---
+//
+// Partner sites embed the catalog of public tutors and their courses from
+// /api/v1/catalog/... on the HTTP gateway. Requests carry a read-only token as a Bearer
+// Authorization header or an api_key query parameter. The query path only answers CORS
+// preflights and asks the gateway to upgrade, so tokens are checked, rate limited and counted
+// in http_request_update, where state changes persist.
+
+const API_RATE_WINDOW_NS: u64 = 60 * 1_000_000_000;
+const DEFAULT_CATALOG_PAGE: usize = 20;
+const MAX_CATALOG_PAGE: usize = 100;
+
+thread_local! {
+    // Token id -> (window start, requests in it). Kept on the heap: losing it on upgrade only
+    // resets the current minute.
+    static API_RATE_WINDOWS: RefCell<HashMap<u64, (u64, u32)>> = RefCell::new(HashMap::new());
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                match std::str::from_utf8(&bytes[i + 1..i + 3]).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                    Some(byte) => {
+                        decoded.push(byte);
+                        i += 2;
+                    }
+                    None => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn query_param(query: &str, name: &str) -> Option<String> {
+    query.split('&')
+        .filter_map(|param| param.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| percent_decode(value))
+        .filter(|value| !value.is_empty())
+}
+
+fn request_header<'a>(req: &'a HttpRequest, name: &str) -> Option<&'a str> {
+    req.headers.iter().find(|(key, _)| key.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str())
+}
+
+fn is_catalog_path(segments: &[&str]) -> bool {
+    matches!(segments, ["api", "v1", "catalog", ..])
+}
+
+fn authenticated_api_token(req: &HttpRequest, query: &str) -> Option<ApiToken> {
+    let presented = request_header(req, "authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token.trim().to_string())
+        .or_else(|| query_param(query, "api_key"))?;
+    let hash = sha256_hex(&presented);
+    API_TOKENS.with(|tokens| tokens.borrow().iter().map(|(_, t)| t).find(|t| t.key_hash == hash))
+        .filter(|t| t.revoked_at.is_none())
+}
+
+fn with_cors(mut response: HttpResponse, req: &HttpRequest, token: &ApiToken) -> HttpResponse {
+    if let Some(origin) = request_header(req, "origin") {
+        if token.allowed_origins.iter().any(|allowed| allowed == "*" || allowed == origin) {
+            response.headers.push(("Access-Control-Allow-Origin".to_string(), origin.to_string()));
+            response.headers.push(("Vary".to_string(), "Origin".to_string()));
+        }
+    }
+    response
+}
+
+// Counts the request against the token's minute window; false once the limit is reached
+fn take_api_rate_slot(token: &ApiToken, now: u64) -> bool {
+    API_RATE_WINDOWS.with(|windows| {
+        let mut windows = windows.borrow_mut();
+        let window = windows.entry(token.id).or_insert((now, 0));
+        if now.saturating_sub(window.0) >= API_RATE_WINDOW_NS {
+            *window = (now, 0);
+        }
+        if window.1 >= token.rate_limit_per_minute {
+            return false;
+        }
+        window.1 += 1;
+        true
+    })
+}
+
+fn record_api_usage(token: &ApiToken, endpoint: &str, rate_limited: bool, now: u64) {
+    let day = now / NANOS_PER_DAY;
+    let key = format!("{:020}:{}", token.id, day);
+    API_USAGE.with(|usage| {
+        let mut usage = usage.borrow_mut();
+        let mut entry = usage.get(&key).unwrap_or(ApiUsageDay { token_id: token.id, day, requests: 0, rate_limited: 0, endpoints: Vec::new() });
+        if rate_limited {
+            entry.rate_limited += 1;
+        } else {
+            entry.requests += 1;
+            match entry.endpoints.iter_mut().find(|(name, _)| name == endpoint) {
+                Some((_, count)) => *count += 1,
+                None => entry.endpoints.push((endpoint.to_string(), 1)),
+            }
+        }
+        usage.insert(key, entry);
+    });
+    if !rate_limited {
+        API_TOKENS.with(|tokens| {
+            let mut tokens = tokens.borrow_mut();
+            if let Some(mut stored) = tokens.get(&token.id) {
+                stored.last_used_at = Some(now);
+                tokens.insert(token.id, stored);
+            }
+        });
+    }
+}
+
+// Gate for the external user endpoints. Allowlisted principals skip the key; everyone else
+// needs an unrevoked key with the scope. Usage and rate windows only stick on update calls.
+fn authorize_external(caller: Principal, api_key: Option<String>, scope: &str, endpoint: &str) -> Result<(), String> {
+    if get_config().external_api.allowed_principals.contains(&caller) {
+        return Ok(());
+    }
+    let presented = api_key.map(|key| key.trim().to_string()).filter(|key| !key.is_empty()).ok_or("An API key is required")?;
+    let hash = sha256_hex(&presented);
+    let token = API_TOKENS.with(|tokens| tokens.borrow().iter().map(|(_, t)| t).find(|t| t.key_hash == hash))
+        .filter(|t| t.revoked_at.is_none())
+        .ok_or("Invalid or revoked API key")?;
+    if !token.scopes.iter().any(|s| s == scope) {
+        return Err(format!("This API key does not have the {} scope", scope));
+    }
+    let now = ic_cdk::api::time();
+    if !take_api_rate_slot(&token, now) {
+        record_api_usage(&token, endpoint, true, now);
+        return Err("Rate limit exceeded for this API key; try again in a minute".to_string());
+    }
+    record_api_usage(&token, endpoint, false, now);
+    Ok(())
+}
+
+fn catalog_page(query: &str) -> (usize, usize) {
+    let offset = query_param(query, "offset").and_then(|v| v.parse().ok()).unwrap_or(0);
+    let limit = query_param(query, "limit").and_then(|v| v.parse().ok()).unwrap_or(DEFAULT_CATALOG_PAGE).clamp(1, MAX_CATALOG_PAGE);
+    (offset, limit)
+}
+
+fn catalog_tutor_json(listing: &TutorListing) -> serde_json::Value {
+    let tutor = &listing.tutor;
+    json!({
+        "id": tutor.public_id,
+        "slug": tutor.slug,
+        "name": tutor.name,
+        "description": tutor.description,
+        "expertise": tutor.expertise,
+        "teaching_style": tutor.teaching_style,
+        "avatar_url": tutor.avatar_url,
+        "creator": listing.owner_name,
+        "verified_educator": listing.verified_educator,
+        "course_count": listing.course_count,
+        "license": effective_license(&tutor.license),
+        "attribution": attribution_line(&tutor.derived_from),
+        "created_at": iso8601(tutor.created_at),
+        "updated_at": iso8601(tutor.updated_at),
+    })
+}
+
+fn catalog_course_json(course: &TutorCourse, tutor: &Tutor) -> serde_json::Value {
+    let mut modules: Vec<&CourseModule> = course.modules.iter().collect();
+    modules.sort_by_key(|m| m.order);
+    json!({
+        "id": course.id,
+        "tutor_id": tutor.public_id,
+        "tutor_name": tutor.name,
+        "topic": course.topic,
+        "difficulty_level": course.difficulty_level,
+        "estimated_duration": course.estimated_duration,
+        "modules": modules.iter().map(|m| json!({ "title": m.title, "description": m.description })).collect::<Vec<_>>(),
+        "license": course_license(course, tutor),
+        "attribution": attribution_line(&course.derived_from),
+        "created_at": iso8601(course.created_at),
+    })
+}
+
+fn catalog_list(items: Vec<serde_json::Value>, total: usize, offset: usize, limit: usize) -> serde_json::Value {
+    json!({ "items": items, "total": total, "offset": offset, "limit": limit })
+}
+
+// Filters: q (name, description, expertise), verified=true
+fn catalog_tutors(query: &str) -> Result<serde_json::Value, String> {
+    let (offset, limit) = catalog_page(query);
+    let verified_only = query_param(query, "verified").is_some_and(|v| v == "true");
+    let listings = public_tutor_listings(query_param(query, "q"), verified_only, "Narrow the search with q or verified=true.")?;
+    let total = listings.len();
+    let mut page: Vec<TutorListing> = listings.into_iter().skip(offset).take(limit).collect();
+    fill_course_counts(&mut page);
+    Ok(catalog_list(page.iter().map(catalog_tutor_json).collect(), total, offset, limit))
+}
+
+fn catalog_tutor(public_id: &str) -> Option<serde_json::Value> {
+    let public_id = canonical_public_id("tutor", public_id);
+    let (_, tutor) = cache::tutor_by_public_id(&public_id).filter(|(_, t)| t.visibility == Visibility::Public)?;
+    let owner = cache::user(tutor.user_id);
+    let mut listing = [TutorListing {
+        owner_name: owner.as_ref().map(|u| u.username.clone()).unwrap_or_default(),
+        verified_educator: owner.is_some_and(|u| u.verified_educator),
+        course_count: 0,
+        tutor,
+    }];
+    fill_course_counts(&mut listing);
+    Some(catalog_tutor_json(&listing[0]))
+}
+
+// Filters: tutor (public id), difficulty, q (topic)
+fn catalog_courses(query: &str) -> Result<serde_json::Value, String> {
+    let (offset, limit) = catalog_page(query);
+    let tutor_filter = query_param(query, "tutor").map(|id| canonical_public_id("tutor", &id));
+    let difficulty = query_param(query, "difficulty").map(|d| d.to_lowercase());
+    let text = query_param(query, "q").map(|q| q.to_lowercase());
+    
+    let mut public_tutors: HashMap<u64, Option<Tutor>> = HashMap::new();
+    let mut matches = Vec::new();
+    TUTOR_COURSES.with(|courses| -> Result<(), String> {
+        for (scanned, (_, course)) in courses.borrow().iter().enumerate() {
+            scan_checkpoint(scanned, "Filter by tutor to narrow the scan.")?;
+            let tutor = public_tutors.entry(course.tutor_id).or_insert_with(|| {
+                TUTORS.with(|tutors| tutors.borrow().get(&course.tutor_id)).filter(|t| t.visibility == Visibility::Public)
+            });
+            let Some(tutor) = tutor else {
+                continue;
+            };
+            if tutor_filter.as_ref().is_some_and(|id| &tutor.public_id != id)
+                || difficulty.as_ref().is_some_and(|d| &course.difficulty_level.to_lowercase() != d)
+                || text.as_ref().is_some_and(|q| !course.topic.to_lowercase().contains(q))
+            {
+                continue;
+            }
+            matches.push((course.created_at, catalog_course_json(&course, tutor)));
+        }
+        Ok(())
+    })?;
+    
+    matches.sort_by_key(|(created_at, _)| std::cmp::Reverse(*created_at));
+    let total = matches.len();
+    let items = matches.into_iter().skip(offset).take(limit).map(|(_, item)| item).collect();
+    Ok(catalog_list(items, total, offset, limit))
+}
+
+fn catalog_response(req: &HttpRequest, segments: &[&str], query: &str) -> HttpResponse {
+    if req.method != "GET" {
+        return http_json(405, &json!({ "error": "The catalog API is read-only" }));
+    }
+    let Some(token) = authenticated_api_token(req, query) else {
+        return http_json(401, &json!({ "error": "A valid API token is required" }));
+    };
+    let (endpoint, scope) = match segments {
+        ["api", "v1", "catalog", "tutors"] => ("tutors", "catalog:tutors"),
+        ["api", "v1", "catalog", "tutors", _] => ("tutor", "catalog:tutors"),
+        ["api", "v1", "catalog", "courses"] => ("courses", "catalog:courses"),
+        _ => return with_cors(http_not_found(), req, &token),
+    };
+    if !token.scopes.iter().any(|s| s == scope) {
+        return with_cors(http_json(403, &json!({ "error": format!("This token lacks the {} scope", scope) })), req, &token);
+    }
+    
+    let now = ic_cdk::api::time();
+    if !take_api_rate_slot(&token, now) {
+        record_api_usage(&token, endpoint, true, now);
+        let mut response = http_json(429, &json!({ "error": format!("Rate limit of {} requests a minute exceeded", token.rate_limit_per_minute) }));
+        response.headers.push(("Retry-After".to_string(), "60".to_string()));
+        return with_cors(response, req, &token);
+    }
+    record_api_usage(&token, endpoint, false, now);
+    
+    let body = match segments {
+        ["api", "v1", "catalog", "tutors", public_id] => catalog_tutor(public_id).ok_or_else(|| "Not found".to_string()),
+        ["api", "v1", "catalog", "tutors"] => catalog_tutors(query),
+        _ => catalog_courses(query),
+    };
+    let response = match body {
+        Ok(body) => http_json(200, &body),
+        Err(e) if e == "Not found" => http_not_found(),
+        Err(e) => http_json(503, &json!({ "error": e })),
+    };
+    with_cors(response, req, &token)
+}
+
+// Preflights carry no token, so any origin may ask; the real request is checked per token
+fn catalog_preflight() -> HttpResponse {
+    let mut response = http_response(204, "text/plain", Vec::new());
+    response.headers.extend([
+        ("Access-Control-Allow-Origin".to_string(), "*".to_string()),
+        ("Access-Control-Allow-Methods".to_string(), "GET, OPTIONS".to_string()),
+        ("Access-Control-Allow-Headers".to_string(), "Authorization".to_string()),
+        ("Access-Control-Max-Age".to_string(), "86400".to_string()),
+    ]);
+    response
+}
+
+#[ic_cdk::update]
+fn http_request_update(req: HttpRequest) -> HttpResponse {
+    let (path, query) = req.url.split_once('?').unwrap_or((req.url.as_str(), ""));
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    if is_catalog_path(&segments) {
+        catalog_response(&req, &segments, query)
+    } else {
+        http_not_found()
+    }
+}
+
+#[ic_cdk::update]
+async fn create_api_token_admin(name: String, scopes: Vec<String>, rate_limit_per_minute: u32, allowed_origins: Vec<String>) -> Result<String, String> {
+    let caller = ic_cdk::caller();
+    require(caller, Permission::ManageSystem)?;
+    if name.trim().is_empty() {
+        return Err("Token name is required".to_string());
+    }
+    if scopes.is_empty() || scopes.iter().any(|s| !API_SCOPES.contains(&s.as_str())) {
+        return Err(format!("Scopes must be one or more of: {}", API_SCOPES.join(", ")));
+    }
+    if rate_limit_per_minute == 0 || rate_limit_per_minute > 6000 {
+        return Err("Rate limit must be between 1 and 6000 requests a minute".to_string());
+    }
+    if allowed_origins.iter().any(|o| o != "*" && !o.starts_with("https://")) {
+        return Err("Allowed origins must be https origins or \"*\"".to_string());
+    }
+    
+    let secret = format!("cat_{}", hex_encode(&random_bytes().await?));
+    let token = ApiToken {
+        id: next_id("api_token"),
+        name: name.trim().to_string(),
+        key_hash: sha256_hex(&secret),
+        scopes,
+        rate_limit_per_minute,
+        allowed_origins: allowed_origins.into_iter().map(|o| o.trim_end_matches('/').to_string()).collect(),
+        created_by: caller,
+        created_at: ic_cdk::api::time(),
+        revoked_at: None,
+        last_used_at: None,
+    };
+    API_TOKENS.with(|tokens| tokens.borrow_mut().insert(token.id, token.clone()));
+    record_audit(caller, "create_api_token", None, format!("{} ({})", token.name, token.scopes.join(", ")));
+    Ok(secret)
+}
+
+#[ic_cdk::update]
+fn revoke_api_token_admin(token_id: u64) -> Result<ApiToken, String> {
+    let caller = ic_cdk::caller();
+    require(caller, Permission::ManageSystem)?;
+    let mut token = API_TOKENS.with(|tokens| tokens.borrow().get(&token_id)).ok_or("API token not found")?;
+    if token.revoked_at.is_some() {
+        return Err("This token is already revoked".to_string());
+    }
+    token.revoked_at = Some(ic_cdk::api::time());
+    API_TOKENS.with(|tokens| tokens.borrow_mut().insert(token_id, token.clone()));
+    record_audit(caller, "revoke_api_token", None, token.name.clone());
+    Ok(token)
+}
+
+#[ic_cdk::query]
+fn get_api_tokens_admin() -> Result<Vec<ApiToken>, String> {
+    require(ic_cdk::caller(), Permission::ManageSystem)?;
+    Ok(API_TOKENS.with(|tokens| tokens.borrow().iter().map(|(_, t)| t).collect()))
+}
+
+#[ic_cdk::update]
+fn set_external_api_config_admin(external_api: ExternalApiConfig) -> Result<CanisterConfig, String> {
+    let caller = ic_cdk::caller();
+    require(caller, Permission::ManageSystem)?;
+    if external_api.allowed_principals.contains(&Principal::anonymous()) {
+        return Err("The anonymous principal can't be allowlisted".to_string());
+    }
+    
+    let details = format!(
+        "allowed_principals={}",
+        external_api.allowed_principals.iter().map(|p| p.to_text()).collect::<Vec<_>>().join(",")
+    );
+    let config = update_config(|config| {
+        config.external_api = external_api;
+        Ok(())
+    })?;
+    record_audit(caller, "set_external_api_config", None, details);
+    Ok(config)
+}
+
+// Daily usage for the last `days` days, for one token or all of them
+#[ic_cdk::query]
+fn get_api_usage_admin(token_id: Option<u64>, days: u32) -> Result<Vec<ApiUsageDay>, String> {
+    require(ic_cdk::caller(), Permission::ManageSystem)?;
+    let today = ic_cdk::api::time() / NANOS_PER_DAY;
+    let since = today.saturating_sub(days.clamp(1, 366) as u64 - 1);
+    let usage: Vec<ApiUsageDay> = API_USAGE.with(|usage| {
+        let usage = usage.borrow();
+        match token_id {
+            Some(id) => usage.range(format!("{:020}:", id)..format!("{:020};", id)).map(|(_, u)| u).filter(|u| u.day >= since).collect(),
+            None => usage.iter().map(|(_, u)| u).filter(|u| u.day >= since).collect(),
+        }
+    });
+    ensure_fits(&usage, "Pass a token id or fewer days.")?;
+    Ok(usage)
+}
+
+// --- Embedded Tutors ---
+//
+// An owner can put one of their public tutors on an external site with an embed token.
+// Visitors chat anonymously: their sessions are guest sessions tagged with the token, so
+// they expire and are pruned the same way, but use the embedded tutor instead of the demo
+// tutor. Each token has its own daily message budget and per-session limits instead of the
+// owner's plan quota, and usage is counted per day for the owner's analytics.
+
+const MAX_EMBED_TOKENS_PER_TUTOR: usize = 10;
+
+fn embed_usage_key(token: &str, day: u64) -> String {
+    format!("{}:{}", token, day)
+}
+
+fn update_embed_usage<F: FnOnce(&mut EmbedUsageDay)>(token: &str, now: u64, update: F) {
+    let day = now / NANOS_PER_DAY;
+    let key = embed_usage_key(token, day);
+    EMBED_USAGE.with(|usage| {
+        let mut usage = usage.borrow_mut();
+        let mut entry = usage.get(&key).unwrap_or(EmbedUsageDay { token: token.to_string(), day, ..Default::default() });
+        update(&mut entry);
+        usage.insert(key, entry);
+    });
+}
+
+// The token must be live and its tutor still public; either going away closes the embed
+fn active_embed(token: &str) -> Result<(EmbedToken, Tutor), String> {
+    let embed = EMBED_TOKENS.with(|tokens| tokens.borrow().get(&token.to_string()))
+        .filter(|e| e.revoked_at.is_none())
+        .ok_or("This tutor is not available")?;
+    let (_, tutor) = cache::tutor_by_public_id(&embed.tutor_id)
+        .filter(|(_, t)| t.visibility == Visibility::Public && t.user_id == embed.owner)
+        .ok_or("This tutor is not available")?;
+    Ok((embed, tutor))
+}
+
+fn validate_embed_limits(daily_message_limit: u32, max_messages_per_session: u32, max_active_sessions: u32, session_ttl_minutes: u32) -> Result<(), String> {
+    if daily_message_limit == 0 || max_messages_per_session == 0 || max_active_sessions == 0 {
+        return Err("Limits must be above zero".to_string());
+    }
+    if !(5..=24 * 60).contains(&session_ttl_minutes) {
+        return Err("Sessions must last between 5 minutes and a day".to_string());
+    }
+    Ok(())
+}
+
+#[ic_cdk::update]
+async fn create_embed_token(
+    tutor_id: String,
+    label: String,
+    daily_message_limit: u32,
+    max_messages_per_session: u32,
+    max_active_sessions: u32,
+    session_ttl_minutes: u32,
+) -> Result<EmbedToken, String> {
+    let caller = ic_cdk::caller();
+    let (_, tutor) = owned_tutor(&tutor_id, caller)?;
+    if tutor.visibility != Visibility::Public {
+        return Err("Only public tutors can be embedded; publish the tutor first".to_string());
+    }
+    validate_embed_limits(daily_message_limit, max_messages_per_session, max_active_sessions, session_ttl_minutes)?;
+    let existing = EMBED_TOKENS.with(|tokens| {
+        tokens.borrow().iter().filter(|(_, e)| e.tutor_id == tutor.public_id && e.revoked_at.is_none()).count()
+    });
+    if existing >= MAX_EMBED_TOKENS_PER_TUTOR {
+        return Err(format!("A tutor can have at most {} active embed tokens", MAX_EMBED_TOKENS_PER_TUTOR));
+    }
+    
+    let embed = EmbedToken {
+        token: random_public_id("embed").await?,
+        tutor_id: tutor.public_id.clone(),
+        owner: caller,
+        label: trim_to_length(label.trim(), 100),
+        daily_message_limit,
+        max_messages_per_session,
+        max_active_sessions,
+        session_ttl_minutes,
+        created_at: ic_cdk::api::time(),
+        revoked_at: None,
+    };
+    EMBED_TOKENS.with(|tokens| tokens.borrow_mut().insert(embed.token.clone(), embed.clone()));
+    Ok(embed)
+}
+
+#[ic_cdk::update]
+fn update_embed_token(
+    token: String,
+    label: String,
+    daily_message_limit: u32,
+    max_messages_per_session: u32,
+    max_active_sessions: u32,
+    session_ttl_minutes: u32,
+) -> Result<EmbedToken, String> {
+    let caller = ic_cdk::caller();
+    let mut embed = EMBED_TOKENS.with(|tokens| tokens.borrow().get(&token))
+        .filter(|e| e.owner == caller)
+        .ok_or("Embed token not found")?;
+    validate_embed_limits(daily_message_limit, max_messages_per_session, max_active_sessions, session_ttl_minutes)?;
+    embed.label = trim_to_length(label.trim(), 100);
+    embed.daily_message_limit = daily_message_limit;
+    embed.max_messages_per_session = max_messages_per_session;
+    embed.max_active_sessions = max_active_sessions;
+    embed.session_ttl_minutes = session_ttl_minutes;
+    EMBED_TOKENS.with(|tokens| tokens.borrow_mut().insert(token, embed.clone()));
+    Ok(embed)
+}
+
+// Revoking also ends the visitor sessions started from the token
+#[ic_cdk::update]
+fn revoke_embed_token(token: String) -> Result<EmbedToken, String> {
+    let caller = ic_cdk::caller();
+    let mut embed = EMBED_TOKENS.with(|tokens| tokens.borrow().get(&token))
+        .filter(|e| e.owner == caller && e.revoked_at.is_none())
+        .ok_or("Embed token not found")?;
+    embed.revoked_at = Some(ic_cdk::api::time());
+    EMBED_TOKENS.with(|tokens| tokens.borrow_mut().insert(token.clone(), embed.clone()));
+    GUEST_SESSIONS.with(|sessions| {
+        let mut sessions = sessions.borrow_mut();
+        let started: Vec<String> = sessions.iter()
+            .filter(|(_, s)| s.embed_token.as_deref() == Some(token.as_str()))
+            .map(|(key, _)| key)
+            .collect();
+        for key in started {
+            sessions.remove(&key);
+        }
+    });
+    Ok(embed)
+}
+
+#[ic_cdk::query]
+fn get_my_embed_tokens(tutor_id: Option<String>) -> Vec<EmbedToken> {
+    let caller = ic_cdk::caller();
+    let tutor_id = tutor_id.map(|id| canonical_public_id("tutor", &id));
+    EMBED_TOKENS.with(|tokens| {
+        tokens.borrow()
+            .iter()
+            .map(|(_, e)| e)
+            .filter(|e| e.owner == caller && tutor_id.as_ref().is_none_or(|id| &e.tutor_id == id))
+            .collect()
+    })
+}
+
+// Daily usage for the last `days` days, oldest first
+#[ic_cdk::query]
+fn get_embed_usage(token: String, days: u32) -> Result<Vec<EmbedUsageDay>, String> {
+    let caller = ic_cdk::caller();
+    EMBED_TOKENS.with(|tokens| tokens.borrow().get(&token))
+        .filter(|e| e.owner == caller)
+        .ok_or("Embed token not found")?;
+    let today = ic_cdk::api::time() / NANOS_PER_DAY;
+    let since = today.saturating_sub(days.clamp(1, 366) as u64 - 1);
+    let mut usage: Vec<EmbedUsageDay> = EMBED_USAGE.with(|usage| {
+        usage.borrow()
+            .range(format!("{}:", token)..format!("{};", token))
+            .map(|(_, u)| u)
+            .filter(|u| u.day >= since)
+            .collect()
+    });
+    usage.sort_by_key(|u| u.day);
+    Ok(usage)
+}
+
+#[ic_cdk::update]
+async fn start_embed_session(embed_token: String, topic: Option<String>) -> Result<GuestSession, String> {
+    let caller = ic_cdk::caller();
+    let (embed, tutor) = active_embed(&embed_token)?;
+    let topic = topic.map(|t| t.trim().to_string()).filter(|t| !t.is_empty())
+        .or_else(|| tutor.default_topic.clone())
+        .unwrap_or_else(|| tutor.expertise.first().cloned().unwrap_or_else(|| tutor.name.clone()));
+    if topic.chars().count() > 200 {
+        return Err("Topic must be at most 200 characters".to_string());
+    }
+    
+    let session_token = random_public_id("guest").await?;
+    let now = ic_cdk::api::time();
+    let active = GUEST_SESSIONS.with(|sessions| {
+        sessions.borrow().iter().filter(|(_, s)| s.embed_token.as_deref() == Some(embed_token.as_str()) && s.expires_at > now).count()
+    });
+    if active >= embed.max_active_sessions as usize {
+        update_embed_usage(&embed.token, now, |u| u.rejected += 1);
+        return Err("This tutor is busy right now; please try again in a few minutes".to_string());
+    }
+    
+    let template = tutor.welcome_template.clone().unwrap_or_else(|| DEFAULT_WELCOME_TEMPLATE.to_string());
+    let welcome = render_welcome_template(&template, &tutor, &topic, "there");
+    let session = GuestSession {
+        token: session_token.clone(),
+        started_by: caller,
+        topic,
+        messages: vec![guest_message(&session_token, "tutor", welcome)],
+        ai_calls_remaining: embed.max_messages_per_session,
+        created_at: now,
+        expires_at: now + embed.session_ttl_minutes as u64 * 60 * 1_000_000_000,
+        embed_token: Some(embed.token.clone()),
+        tutor_id: Some(tutor.public_id.clone()),
+    };
+    GUEST_SESSIONS.with(|sessions| sessions.borrow_mut().insert(session_token, session.clone()));
+    update_embed_usage(&embed.token, now, |u| u.sessions_started += 1);
+    Ok(session)
+}
+
+#[ic_cdk::update]
+async fn send_embed_message(session_token: String, message: String) -> Result<ChatMessage, String> {
+    rate_limit::check(ic_cdk::caller(), "chat")?;
+    let mut session = live_guest_session(&session_token)?;
+    let embed_token = session.embed_token.clone().ok_or("Guest session not found or expired")?;
+    let (embed, tutor) = active_embed(&embed_token)?;
+    let message = message.trim().to_string();
+    if message.is_empty() {
+        return Err("Message cannot be empty".to_string());
+    }
+    let max_chars = get_config().guest.max_message_chars as usize;
+    if message.chars().count() > max_chars {
+        return Err(format!("Messages are limited to {} characters", max_chars));
+    }
+    let now = ic_cdk::api::time();
+    if session.ai_calls_remaining == 0 {
+        update_embed_usage(&embed.token, now, |u| u.rejected += 1);
+        return Err("This conversation has reached its message limit".to_string());
+    }
+    let sent_today = EMBED_USAGE.with(|usage| usage.borrow().get(&embed_usage_key(&embed.token, now / NANOS_PER_DAY)))
+        .map_or(0, |u| u.messages);
+    if sent_today >= embed.daily_message_limit as u64 {
+        update_embed_usage(&embed.token, now, |u| u.rejected += 1);
+        return Err("This tutor has answered all the questions it can today; please come back tomorrow".to_string());
+    }
+    
+    // Charge before awaiting so concurrent messages can't overspend either limit
+    session.ai_calls_remaining -= 1;
+    session.messages.push(guest_message(&session_token, "user", message.clone()));
+    GUEST_SESSIONS.with(|sessions| sessions.borrow_mut().insert(session_token.clone(), session.clone()));
+    update_embed_usage(&embed.token, now, |u| u.messages += 1);
+    
+    let earlier = &session.messages[..session.messages.len() - 1];
+    let context: Vec<String> = earlier[earlier.len().saturating_sub(6)..].iter()
+        .map(|m| format!("{}: {}", m.sender, m.content))
+        .collect();
+    let background = format!("Topic: {}\nConversation so far:\n{}", session.topic, context.join("\n"));
+    let prompt = tutor_reply_prompt(&tutor, &message, Some(background), "");
+    // Visitors are anonymous, so there is no learner to enroll in experiments
+    let reply = match call_ai_with_params(&prompt, "chat", tutor.model_params.as_ref(), None, ic_cdk::caller()).await {
+        Ok(reply) => reply,
+        Err(e) => {
+            update_embed_usage(&embed.token, ic_cdk::api::time(), |u| u.failed_replies += 1);
+            return Err(e);
+        }
+    };
+    let reply = guest_message(&session_token, "tutor", process_ai_response(reply, &response_processing_for(Principal::anonymous(), "chat")));
+    
+    // The session may have expired or the token been revoked while the reply was generated
+    GUEST_SESSIONS.with(|sessions| {
+        let mut sessions = sessions.borrow_mut();
+        if let Some(mut current) = sessions.get(&session_token) {
+            current.messages.push(reply.clone());
+            sessions.insert(session_token.clone(), current);
+        }
+    });
+    Ok(reply)
+}
+
+// --- Content Licensing ---
+//
+// Creators choose the license public tutors and courses are published under. It decides
+// whether others may copy them (copy_public_tutor, copy_public_course) or download them
+// (export_course). Copies record where they came from, so the chain of creators and
+// licenses is shown wherever the copy appears. Share-alike licenses carry over to copies,
+// and copies of non-commercial content don't earn creator revenue.
+
+fn effective_license(license: &Option<String>) -> String {
+    license.clone().unwrap_or_else(|| "all_rights_reserved".to_string())
+}
+
+fn course_license(course: &TutorCourse, tutor: &Tutor) -> String {
+    effective_license(&course.license.clone().or_else(|| tutor.license.clone()))
+}
+
+fn validate_license(license: &str) -> Result<(), String> {
+    if !LICENSES.contains(&license) {
+        return Err(format!("License must be one of: {}", LICENSES.join(", ")));
+    }
+    Ok(())
+}
+
+// A copy of share-alike content must keep that license
+fn ensure_license_compatible(license: &str, derived_from: &[AttributionEntry]) -> Result<(), String> {
+    if let Some(required) = derived_from.iter().rev().find(|e| license_terms(&e.license).share_alike) {
+        if required.license != license {
+            return Err(format!(
+                "This is based on \"{}\", shared under {}, so it must keep that license",
+                required.title,
+                license_name(&required.license)
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn is_non_commercial_copy(derived_from: &[AttributionEntry]) -> bool {
+    derived_from.iter().any(|e| license_terms(&e.license).non_commercial)
+}
+
+// "Based on "Algebra Coach" by ana (CC BY 4.0), which is based on ..." or None for originals
+fn attribution_line(derived_from: &[AttributionEntry]) -> Option<String> {
+    let credits: Vec<String> = derived_from.iter().rev()
+        .map(|e| format!("\"{}\" by {} ({})", e.title, e.creator_name, license_name(&e.license)))
+        .collect();
+    (!credits.is_empty()).then(|| format!("Based on {}", credits.join(", which is based on ")))
+}
+
+fn creator_name(user_id: Principal) -> String {
+    cache::user(user_id).map(|u| u.username).unwrap_or_else(|| user_id.to_text())
+}
+
+fn attribution_entry(source_type: &str, source_id: String, title: &str, creator: Principal, license: String, now: u64) -> AttributionEntry {
+    AttributionEntry {
+        source_type: source_type.to_string(),
+        source_id,
+        title: title.to_string(),
+        creator,
+        creator_name: creator_name(creator),
+        license,
+        copied_at: now,
+    }
+}
+
+// Whether the caller may copy or export: always for the owner, otherwise only public content
+// under a license that allows it
+fn licensed_for(tutor: &Tutor, license: &str, caller: Principal, export: bool) -> Result<(), String> {
+    if tutor.user_id == caller {
+        return Ok(());
+    }
+    if tutor.visibility != Visibility::Public {
+        return Err("Tutor not found".to_string());
+    }
+    let terms = license_terms(license);
+    if (export && !terms.allows_export) || (!export && !terms.allows_copies) {
+        return Err(format!(
+            "The creator published this under {}, which doesn't allow {}",
+            license_name(license),
+            if export { "downloads" } else { "copies" }
+        ));
+    }
+    Ok(())
+}
+
+fn copied_course(course: &TutorCourse, source_tutor: &Tutor, target_tutor_key: u64, license: String, now: u64) -> TutorCourse {
+    let mut derived_from = course.derived_from.clone();
+    derived_from.push(attribution_entry("course", course.id.to_string(), &course.topic, source_tutor.user_id, license.clone(), now));
+    TutorCourse {
+        id: next_id("tutor_course"),
+        tutor_id: target_tutor_key,
+        session_id: 0,
+        created_at: now,
+        modules: course.modules.iter().cloned().map(|m| CourseModule { status: "pending".to_string(), ..m }).collect(),
+        license: license_terms(&license).share_alike.then_some(license),
+        derived_from,
+        ..course.clone()
+    }
+}
+
+#[ic_cdk::update]
+fn set_tutor_license(tutor_id: String, license: String) -> Result<Tutor, String> {
+    let caller = ic_cdk::caller();
+    let (key, mut tutor) = owned_tutor(&tutor_id, caller)?;
+    validate_license(&license)?;
+    ensure_license_compatible(&license, &tutor.derived_from)?;
+    tutor.license = Some(license);
+    tutor.updated_at = ic_cdk::api::time();
+    cache::store_tutor(key, tutor.clone());
+    Ok(tutor)
+}
+
+// None makes the course follow its tutor's license
+#[ic_cdk::update]
+fn set_course_license(course_id: u64, license: Option<String>) -> Result<TutorCourse, String> {
+    let caller = ic_cdk::caller();
+    let mut course = owned_course(course_id, caller)?;
+    let tutor = TUTORS.with(|tutors| tutors.borrow().get(&course.tutor_id)).ok_or("Course not found")?;
+    if let Some(license) = &license {
+        validate_license(license)?;
+    }
+    let effective = effective_license(&license.clone().or_else(|| tutor.license.clone()));
+    ensure_license_compatible(&effective, &course.derived_from)?;
+    course.license = license;
+    TUTOR_COURSES.with(|courses| courses.borrow_mut().insert(course_id, course.clone()));
+    Ok(course)
+}
+
+// Copies a tutor into the caller's account along with the courses whose licenses allow it.
+// Knowledge base files stay with the original creator.
+#[ic_cdk::update]
+async fn copy_public_tutor(tutor_id: String) -> Result<Tutor, String> {
+    let caller = ic_cdk::caller();
+    get_self().ok_or("User not found")?;
+    let (source_key, source) = visible_tutor(&tutor_id, caller)?;
+    let source_license = effective_license(&source.license);
+    licensed_for(&source, &source_license, caller, false)?;
+    enforce_quota(caller, "tutors", 1)?;
+    
+    let now = ic_cdk::api::time();
+    let courses: Vec<TutorCourse> = TUTOR_COURSES.with(|courses| {
+        courses.borrow().iter().map(|(_, c)| c).filter(|c| c.tutor_id == source_key).collect()
+    });
+    let courses: Vec<(TutorCourse, String)> = courses.into_iter()
+        .map(|c| {
+            let license = course_license(&c, &source);
+            (c, license)
+        })
+        .filter(|(_, license)| licensed_for(&source, license, caller, false).is_ok())
+        .collect();
+    let bytes: u64 = courses.iter().map(|(c, _)| tutor_course_bytes(c)).sum();
+    check_storage_quota(caller, bytes)?;
+    
+    let public_id = random_public_id("tutor").await?;
+    let tutor_key = next_id("tutor");
+    let mut derived_from = source.derived_from.clone();
+    derived_from.push(attribution_entry("tutor", source.public_id.clone(), &source.name, source.user_id, source_license.clone(), now));
+    let tutor = Tutor {
+        id: tutor_key,
+        public_id,
+        user_id: caller,
+        knowledge_base: Vec::new(),
+        is_pinned: false,
+        created_at: now,
+        updated_at: now,
+        slug: None,
+        visibility: Visibility::Private,
+        kb_version_pins: Vec::new(),
+        license: license_terms(&source_license).share_alike.then_some(source_license),
+        derived_from,
+        ..source.clone()
+    };
+    cache::store_tutor(tutor_key, tutor.clone());
+    queue_tagging("tutor", &tutor.public_id);
+    
+    for (course, license) in courses {
+        let copy = copied_course(&course, &source, tutor_key, license, now);
+        copy_course_glossary(course.id, copy.id);
+        TUTOR_COURSES.with(|c| c.borrow_mut().insert(copy.id, copy));
+    }
+    record_storage_change(caller, "knowledge_base", bytes as i64);
+    Ok(tutor)
+}
+
+#[ic_cdk::update]
+fn copy_public_course(course_id: u64, target_tutor_id: String) -> Result<TutorCourse, String> {
+    let caller = ic_cdk::caller();
+    let (target_key, _) = owned_tutor(&target_tutor_id, caller)?;
+    let course = TUTOR_COURSES.with(|courses| courses.borrow().get(&course_id)).ok_or("Course not found")?;
+    let source = TUTORS.with(|tutors| tutors.borrow().get(&course.tutor_id))
+        .filter(|t| t.user_id == caller || t.visibility == Visibility::Public)
+        .ok_or("Course not found")?;
+    let license = course_license(&course, &source);
+    licensed_for(&source, &license, caller, false)?;
+    
+    let copy = copied_course(&course, &source, target_key, license, ic_cdk::api::time());
+    let bytes = tutor_course_bytes(&copy);
+    check_storage_quota(caller, bytes)?;
+    TUTOR_COURSES.with(|courses| courses.borrow_mut().insert(copy.id, copy.clone()));
+    copy_course_glossary(course_id, copy.id);
+    record_storage_change(caller, "knowledge_base", bytes as i64);
+    Ok(copy)
+}
+
+// Markdown in the layout import_curriculum reads, with the license and credits at the end
+#[ic_cdk::query]
+fn export_course(course_id: u64) -> Result<String, String> {
+    let caller = ic_cdk::caller();
+    let course = TUTOR_COURSES.with(|courses| courses.borrow().get(&course_id)).ok_or("Course not found")?;
+    let tutor = TUTORS.with(|tutors| tutors.borrow().get(&course.tutor_id))
+        .filter(|t| t.user_id == caller || t.visibility == Visibility::Public)
+        .ok_or("Course not found")?;
+    let license = course_license(&course, &tutor);
+    licensed_for(&tutor, &license, caller, true)?;
+    
+    let mut modules: Vec<&CourseModule> = course.modules.iter().collect();
+    modules.sort_by_key(|m| m.order);
+    let mut markdown = format!("# {}\n", course.topic);
+    for module in modules {
+        markdown.push_str(&format!("\n## {}\n", module.title));
+        if !module.description.is_empty() {
+            markdown.push_str(&format!("{}\n", module.description));
+        }
+        if let Some(content) = &module.content {
+            markdown.push_str(&format!("\n{}\n", content));
+        }
+    }
+    markdown.push_str(&format!("\n---\n\n- Created by {} with {}\n- License: {}\n", creator_name(tutor.user_id), tutor.name, license_name(&license)));
+    if let Some(line) = attribution_line(&course.derived_from) {
+        markdown.push_str(&format!("- {}\n", line));
+    }
+    if let Some(source) = &course.attribution {
+        let title = source.source_title.clone().unwrap_or_else(|| format!("a {} curriculum", source.source_format));
+        let author = source.author.as_ref().map(|a| format!(" by {}", a)).unwrap_or_default();
+        let license = source.license.as_ref().map(|l| format!(" ({})", l)).unwrap_or_default();
+        markdown.push_str(&format!("- Adapted from {}{}{}\n", title, author, license));
+    }
+    ensure_bytes_fit(markdown.len(), 1, "The course is too large to export in one reply.")?;
+    Ok(markdown)
+}
+
+// --- Refunds ---
+//
+// Learners can ask for a payment to be refunded within the configured window after it
+// settled. Admins approve or deny each request with a reason. Approving sends the money back
+// (a Paystack refund for card payments, a ledger transfer for token payments) and, once that
+// succeeds, takes back what the payment bought: gifts it paid for are cancelled or their
+// grants revoked, and the subscription it paid for ends.
+
+fn store_refund(refund: &RefundRequest) {
+    REFUND_REQUESTS.with(|refunds| refunds.borrow_mut().insert(refund.id, refund.clone()));
+}
+
+fn refund_method(transaction: &PaymentTransaction) -> &'static str {
+    if transaction.payment_method.as_deref() == Some("token") { "token" } else { "paystack" }
+}
+
+// Every replica sends the refund. Paystack accepts the first and rejects the rest because the
+// transaction is already reversed, so both are reported as success for replicas to agree.
+#[ic_cdk::query]
+fn transform_refund_response(args: TransformArgs) -> ic_cdk::api::management_canister::http_request::HttpResponse {
+    let status: u32 = args.response.status.0.clone().try_into().unwrap_or(0);
+    let reversed = String::from_utf8_lossy(&args.response.body).to_lowercase().contains("reversed");
+    let ok = (200..300).contains(&status) || reversed;
+    ic_cdk::api::management_canister::http_request::HttpResponse {
+        status: Nat::from(if ok { 200u32 } else { status }),
+        headers: Vec::new(),
+        body: Vec::new(),
+    }
+}
+
+async fn send_paystack_refund(config: &CanisterConfig, transaction: &PaymentTransaction, amount: u64) -> Result<(), String> {
+    if config.refunds.paystack_secret_key.is_empty() {
+        return Err("Paystack refunds aren't configured".to_string());
+    }
+    let payload = json!({
+        "transaction": transaction.paystack_transaction_id.clone().unwrap_or_else(|| transaction.paystack_reference.clone()),
+        "amount": amount,
+    });
+    let request = CanisterHttpRequestArgument {
+        url: config.refunds.paystack_url.clone(),
+        max_response_bytes: Some(16 * 1024),
+        method: HttpMethod::POST,
+        headers: vec![
+            HttpHeader { name: "Content-Type".to_string(), value: "application/json".to_string() },
+            HttpHeader { name: "Authorization".to_string(), value: format!("Bearer {}", config.refunds.paystack_secret_key) },
+        ],
+        body: Some(payload.to_string().into_bytes()),
+        transform: Some(TransformContext::from_name("transform_refund_response".to_string(), vec![])),
+    };
+    let budget = outcall_budget(config, "refund");
+    let (response,) = ic_cdk::api::management_canister::http_request::http_request(request, budget.cycles as u128)
+        .await
+        .map_err(|(code, msg)| format!("HTTP outcall failed: {:?} - {}", code, msg))?;
+    let status: u32 = response.status.0.try_into().unwrap_or(0);
+    if status != 200 {
+        return Err(format!("Paystack returned status {}", status));
+    }
+    Ok(())
+}
+
+async fn reverse_token_payment(ledger: Principal, refund: &RefundRequest) -> Result<u64, String> {
+    let arg = TransferArg {
+        from_subaccount: None,
+        to: Account { owner: refund.user_id, subaccount: None },
+        amount: Nat::from(refund.amount),
+        fee: None,
+        memo: Some(format!("refund:{}", refund.id).into_bytes()),
+        created_at_time: Some(ic_cdk::api::time()),
+    };
+    let (result,): (Result<Nat, TransferError>,) = ic_cdk::call(ledger, "icrc1_transfer", (arg,))
+        .await
+        .map_err(|(code, msg)| format!("Ledger call failed: {:?} {}", code, msg))?;
+    let block = result.map_err(|e| format!("Ledger rejected the transfer: {:?}", e))?;
+    u64::try_from(&block.0).map_err(|_| "Ledger returned an invalid block index".to_string())
+}
+
+// Takes back what a refunded payment bought and describes each change for the audit trail
+fn revoke_refunded_entitlements(transaction: &PaymentTransaction, now: u64) -> Vec<String> {
+    let mut changes = Vec::new();
+    let gifts: Vec<GiftSubscription> = GIFT_SUBSCRIPTIONS.with(|gifts| {
+        gifts.borrow().values().filter(|g| g.transaction_id == transaction.id).collect()
+    });
+    for mut gift in gifts {
+        if let Some(grant_id) = gift.grant_id {
+            let grant = PLAN_GRANTS.with(|grants| grants.borrow().values().find(|g| g.id == grant_id));
+            if let Some(mut grant) = grant.filter(|g| g.revoked_at.is_none()) {
+                grant.revoked_at = Some(now);
+                notify_user(grant.user_id, "warning", "billing", format!("Your {} gift was refunded to the purchaser and has ended", grant.plan), Some(gift.id));
+                changes.push(format!("revoked {} grant {} of {}", grant.plan, grant.id, grant.user_id));
+                store_plan_grant(grant);
+            }
+        }
+        if gift.status != "cancelled" {
+            changes.push(format!("cancelled gift {} ({})", gift.id, gift.status.replace('_', " ")));
+            gift.status = "cancelled".to_string();
+            store_gift(&gift);
+        }
+    }
+    
+    if let Some(subscription_id) = transaction.subscription_id {
+        let subscription = USER_SUBSCRIPTIONS.with(|subscriptions| subscriptions.borrow().get(&subscription_id));
+        if let Some(mut subscription) = subscription.filter(|s| s.status == "active") {
+            subscription.status = "cancelled".to_string();
+            subscription.auto_renew = false;
+            subscription.end_date = Some(now);
+            subscription.next_payment_date = None;
+            subscription.cancelled_at = Some(now);
+            subscription.updated_at = now;
+            USER_SUBSCRIPTIONS.with(|subscriptions| subscriptions.borrow_mut().insert(subscription_id, subscription.clone()));
+            changes.push(format!("ended subscription {}", subscription_id));
+            
+            let still_subscribed = USER_SUBSCRIPTIONS.with(|subscriptions| {
+                subscriptions.borrow().values().any(|s| s.user_id == subscription.user_id && s.status == "active")
+            });
+            if let Some(mut user) = cache::user(subscription.user_id).filter(|u| !still_subscribed && u.subscription != "free") {
+                changes.push(format!("moved {} from {} to free", user.id, user.subscription));
+                user.subscription = "free".to_string();
+                user.updated_at = now;
+                cache::store_user(user);
+            }
+        }
+    }
+    changes
+}
+
+#[ic_cdk::query]
+fn get_my_payments() -> Vec<PaymentTransaction> {
+    let caller = ic_cdk::caller();
+    let mut payments: Vec<PaymentTransaction> = PAYMENT_TRANSACTIONS.with(|transactions| {
+        transactions.borrow().values().filter(|t| t.user_id == caller).collect()
+    });
+    payments.sort_by_key(|t| std::cmp::Reverse(t.created_at));
+    payments
+}
+
+#[ic_cdk::update]
+fn request_refund(transaction_id: u64, reason: String) -> Result<RefundRequest, String> {
+    let caller = ic_cdk::caller();
+    get_self().ok_or("User not found")?;
+    let config = get_config().refunds;
+    if !config.enabled {
+        return Err("Refunds aren't available right now. Contact support.".to_string());
+    }
+    if reason.trim().is_empty() {
+        return Err("Tell us why you're asking for a refund".to_string());
+    }
+    let transaction = PAYMENT_TRANSACTIONS.with(|transactions| transactions.borrow().get(&transaction_id))
+        .filter(|t| t.user_id == caller)
+        .ok_or("Payment not found")?;
+    // Tutoring payments sit in escrow and are refunded by cancelling or disputing the booking
+    if transaction.paystack_reference.starts_with("peer_") {
+        return Err("Cancel the booking or raise a dispute to get a tutoring session refunded".to_string());
+    }
+    match transaction.status.as_str() {
+        "success" => {}
+        "refunded" => return Err("This payment has already been refunded".to_string()),
+        _ => return Err("Only completed payments can be refunded".to_string()),
+    }
+    
+    let now = ic_cdk::api::time();
+    let paid_at = transaction.paid_at.unwrap_or(transaction.created_at);
+    if now.saturating_sub(paid_at) > config.window_days as u64 * NANOS_PER_DAY {
+        return Err(format!("Refunds can be requested up to {} days after payment", config.window_days));
+    }
+    let open = REFUND_REQUESTS.with(|refunds| {
+        refunds.borrow().values().any(|r| r.transaction_id == transaction_id && r.status != "denied")
+    });
+    if open {
+        return Err("A refund has already been requested for this payment".to_string());
+    }
+    
+    let refund = RefundRequest {
+        id: next_id("refund_request"),
+        user_id: caller,
+        transaction_id,
+        amount: transaction.amount_naira,
+        currency: transaction.currency.clone(),
+        method: refund_method(&transaction).to_string(),
+        reason: trim_to_length(reason.trim(), 2000),
+        status: "requested".to_string(),
+        requested_at: now,
+        reviewed_by: None,
+        reviewed_at: None,
+        review_note: None,
+        ledger_block: None,
+        error: None,
+        entitlement_changes: Vec::new(),
+        refunded_at: None,
+    };
+    store_refund(&refund);
+    record_audit(caller, "refund_requested", Some(caller), format!("refund {} for payment {}", refund.id, transaction_id));
+    Ok(refund)
+}
+
+#[ic_cdk::query]
+fn get_my_refund_requests() -> Vec<RefundRequest> {
+    let caller = ic_cdk::caller();
+    REFUND_REQUESTS.with(|refunds| refunds.borrow().values().filter(|r| r.user_id == caller).collect())
+}
+
+#[ic_cdk::query]
+fn get_refund_requests_admin(status: Option<String>) -> Result<Vec<RefundRequest>, String> {
+    require(ic_cdk::caller(), Permission::ManageBilling)?;
+    let refunds: Vec<RefundRequest> = REFUND_REQUESTS.with(|refunds| {
+        refunds.borrow().values().filter(|r| status.as_ref().is_none_or(|s| &r.status == s)).collect()
+    });
+    ensure_fits(&refunds, "Filter by status.")?;
+    Ok(refunds)
+}
+
+// Failed refunds can be approved again
+#[ic_cdk::update]
+async fn approve_refund_admin(refund_id: u64, note: Option<String>) -> Result<RefundRequest, String> {
+    let caller = ic_cdk::caller();
+    require(caller, Permission::ManageBilling)?;
+    let mut refund = REFUND_REQUESTS.with(|refunds| refunds.borrow().get(&refund_id)).ok_or("Refund request not found")?;
+    if refund.status != "requested" && refund.status != "failed" {
+        return Err(format!("Refund request is already {}", refund.status));
+    }
+    let transaction = PAYMENT_TRANSACTIONS.with(|transactions| transactions.borrow().get(&refund.transaction_id))
+        .ok_or("Payment not found")?;
+    let config = get_config();
+    
+    refund.reviewed_by = Some(caller);
+    refund.reviewed_at = Some(ic_cdk::api::time());
+    refund.review_note = note.map(|n| n.trim().to_string()).filter(|n| !n.is_empty());
+    record_audit(caller, "refund_approved", Some(refund.user_id), format!("refund {}: {} {} via {}", refund.id, refund.amount, refund.currency, refund.method));
+    
+    // Held in "processing" across the outcall so it can't be approved twice
+    refund.status = "processing".to_string();
+    store_refund(&refund);
+    let result = match refund.method.as_str() {
+        "token" => match config.refunds.token_ledger {
+            Some(ledger) => reverse_token_payment(ledger, &refund).await.map(Some),
+            None => Err("No token ledger is configured for refunds".to_string()),
+        },
+        _ => send_paystack_refund(&config, &transaction, refund.amount).await.map(|_| None),
+    };
+    
+    let now = ic_cdk::api::time();
+    match result {
+        Ok(block) => {
+            refund.status = "refunded".to_string();
+            refund.ledger_block = block;
+            refund.error = None;
+            refund.refunded_at = Some(now);
+            PAYMENT_TRANSACTIONS.with(|transactions| {
+                let mut transactions = transactions.borrow_mut();
+                if let Some(mut transaction) = transactions.get(&refund.transaction_id) {
+                    transaction.status = "refunded".to_string();
+                    transactions.insert(transaction.id, transaction);
+                }
+            });
+            refund.entitlement_changes = revoke_refunded_entitlements(&transaction, now);
+            record_audit(caller, "refund_completed", Some(refund.user_id), format!("refund {}: {}", refund.id, if refund.entitlement_changes.is_empty() { "no entitlements changed".to_string() } else { refund.entitlement_changes.join("; ") }));
+            notify_user(refund.user_id, "success", "billing", "Your refund has been approved and is on its way".to_string(), Some(refund.id));
+        }
+        Err(e) => {
+            record_audit(caller, "refund_failed", Some(refund.user_id), format!("refund {}: {}", refund.id, e));
+            refund.status = "failed".to_string();
+            refund.error = Some(e);
+        }
+    }
+    store_refund(&refund);
+    Ok(refund)
+}
+
+#[ic_cdk::update]
+fn deny_refund_admin(refund_id: u64, reason: String) -> Result<RefundRequest, String> {
+    let caller = ic_cdk::caller();
+    require(caller, Permission::ManageBilling)?;
+    if reason.trim().is_empty() {
+        return Err("A reason is required".to_string());
+    }
+    let mut refund = REFUND_REQUESTS.with(|refunds| refunds.borrow().get(&refund_id)).ok_or("Refund request not found")?;
+    if refund.status != "requested" && refund.status != "failed" {
+        return Err(format!("Refund request is already {}", refund.status));
+    }
+    refund.status = "denied".to_string();
+    refund.reviewed_by = Some(caller);
+    refund.reviewed_at = Some(ic_cdk::api::time());
+    refund.review_note = Some(reason.trim().to_string());
+    store_refund(&refund);
+    record_audit(caller, "refund_denied", Some(refund.user_id), format!("refund {}: {}", refund.id, reason.trim()));
+    notify_user(refund.user_id, "warning", "billing", format!("Your refund request was declined: {}", reason.trim()), Some(refund.id));
+    Ok(refund)
+}
+
+#[ic_cdk::update]
+fn set_refund_config_admin(refunds: RefundConfig) -> Result<CanisterConfig, String> {
+    let caller = ic_cdk::caller();
+    require(caller, Permission::ManageBilling)?;
+    if !refunds.paystack_url.starts_with("https://") {
+        return Err("Paystack refunds must use an https endpoint".to_string());
+    }
+    
+    let updated = update_config(|config| {
+        // Credentials come back redacted from get_config_admin, so an empty value keeps the stored one
+        let mut refunds = refunds;
+        if refunds.paystack_secret_key.is_empty() {
+            refunds.paystack_secret_key = config.refunds.paystack_secret_key.clone();
+        }
+        config.refunds = refunds;
+        Ok(())
+    })?;
+    record_audit(caller, "set_refund_config", None, format!("enabled: {}, window: {} days", updated.refunds.enabled, updated.refunds.window_days));
+    Ok(updated)
+}
+
+// --- Regional Pricing ---
+//
+// Users declare a billing region (an ISO 3166 country code). Plans can set a price per region,
+// with "*" covering regions without their own; price_naira applies when neither matches.
+// Payments record the region and the VAT or GST charged, so revenue can be reported per region.
+
+fn normalize_region(region: &str, allow_wildcard: bool) -> Result<String, String> {
+    let region = region.trim().to_uppercase();
+    if (allow_wildcard && region == "*") || (region.len() == 2 && region.chars().all(|c| c.is_ascii_uppercase())) {
+        return Ok(region);
+    }
+    Err(format!("'{}' is not a two-letter country code", region))
+}
+
+fn billing_region(user_id: Principal) -> String {
+    cache::user(user_id)
+        .and_then(|u| u.billing_region)
+        .unwrap_or_else(|| get_config().billing.default_region)
+}
+
+fn regional_price(plan: &SubscriptionPlan, region: &str) -> (String, u64) {
+    plan.regional_prices.iter().find(|p| p.region == region)
+        .or_else(|| plan.regional_prices.iter().find(|p| p.region == "*"))
+        .map(|p| (p.currency.clone(), p.amount))
+        .unwrap_or_else(|| ("NGN".to_string(), plan.price_naira))
+}
+
+// Returns the total to charge and the tax breakdown to store with the payment
+fn apply_tax(amount: u64, region: &str) -> (u64, InvoiceTax) {
+    let tax_region = get_config().billing.tax_regions.into_iter().find(|t| t.region == region);
+    let Some(tax_region) = tax_region else {
+        return (amount, InvoiceTax {
+            region: region.to_string(),
+            tax_name: None,
+            rate_bps: 0,
+            net_amount: amount,
+            tax_amount: 0,
+            prices_include_tax: false,
+            registration_number: None,
+        });
+    };
+    let rate = tax_region.rate_bps as u64;
+    let (total, tax_amount) = if tax_region.prices_include_tax {
+        (amount, amount * rate / (10_000 + rate))
+    } else {
+        let tax_amount = amount * rate / 10_000;
+        (amount + tax_amount, tax_amount)
+    };
+    (total, InvoiceTax {
+        region: region.to_string(),
+        tax_name: Some(tax_region.tax_name),
+        rate_bps: tax_region.rate_bps,
+        net_amount: total - tax_amount,
+        tax_amount,
+        prices_include_tax: tax_region.prices_include_tax,
+        registration_number: tax_region.registration_number,
+    })
+}
+
+fn validated_regional_prices(prices: Vec<RegionalPrice>) -> Result<Vec<RegionalPrice>, String> {
+    let mut seen = std::collections::HashSet::new();
+    let mut validated = Vec::new();
+    for price in prices {
+        let region = normalize_region(&price.region, true)?;
+        if !seen.insert(region.clone()) {
+            return Err(format!("{} has more than one price", region));
+        }
+        let currency = price.currency.trim().to_uppercase();
+        if currency.len() != 3 || !currency.chars().all(|c| c.is_ascii_uppercase()) {
+            return Err(format!("'{}' is not a three-letter currency code", price.currency.trim()));
+        }
+        validated.push(RegionalPrice { region, currency, amount: price.amount });
+    }
+    Ok(validated)
+}
+
+// None clears the declared region, so the default applies
+#[ic_cdk::update]
+fn set_billing_region(region: Option<String>) -> Result<User, String> {
+    let caller = ic_cdk::caller();
+    let region = region.map(|r| normalize_region(&r, false)).transpose()?;
+    let mut user = cache::user(caller).ok_or("User not found")?;
+    user.billing_region = region;
+    user.updated_at = ic_cdk::api::time();
+    cache::store_user(user.clone());
+    Ok(user)
+}
+
+// Active plans priced for the caller's billing region
+#[ic_cdk::query]
+fn get_plan_prices() -> Vec<PlanPrice> {
+    let region = billing_region(ic_cdk::caller());
+    SUBSCRIPTION_PLANS.with(|plans| {
+        plans.borrow().values()
+            .filter(|p| p.is_active)
+            .map(|plan| {
+                let (currency, amount) = regional_price(&plan, &region);
+                let (total, tax) = apply_tax(amount, &region);
+                PlanPrice {
+                    plan_id: plan.id,
+                    name: plan.name,
+                    billing_cycle: plan.billing_cycle,
+                    region: region.clone(),
+                    currency,
+                    total,
+                    tax,
+                }
+            })
+            .collect()
+    })
+}
+
+#[ic_cdk::update]
+fn set_plan_prices_admin(plan_id: u64, regional_prices: Vec<RegionalPrice>) -> Result<SubscriptionPlan, String> {
+    let caller = ic_cdk::caller();
+    require(caller, Permission::ManageBilling)?;
+    let mut plan = SUBSCRIPTION_PLANS.with(|plans| plans.borrow().get(&plan_id)).ok_or("Plan not found")?;
+    plan.regional_prices = validated_regional_prices(regional_prices)?;
+    SUBSCRIPTION_PLANS.with(|plans| plans.borrow_mut().insert(plan_id, plan.clone()));
+    let regions: Vec<String> = plan.regional_prices.iter().map(|p| format!("{} {} {}", p.region, p.amount, p.currency)).collect();
+    record_audit(caller, "plan_prices_updated", None, format!("{}: {}", plan.name, regions.join(", ")));
+    Ok(plan)
+}
+
+#[ic_cdk::update]
+fn set_billing_config_admin(billing: BillingConfig) -> Result<CanisterConfig, String> {
+    let caller = ic_cdk::caller();
+    require(caller, Permission::ManageBilling)?;
+    let mut billing = billing;
+    billing.default_region = normalize_region(&billing.default_region, false)?;
+    let mut seen = std::collections::HashSet::new();
+    for tax_region in &mut billing.tax_regions {
+        tax_region.region = normalize_region(&tax_region.region, false)?;
+        if !seen.insert(tax_region.region.clone()) {
+            return Err(format!("{} has more than one tax rate", tax_region.region));
+        }
+        if tax_region.rate_bps > 5_000 {
+            return Err(format!("The tax rate for {} is over 50%", tax_region.region));
+        }
+    }
+    
+    let updated = update_config(|config| {
+        config.billing = billing;
+        Ok(())
+    })?;
+    record_audit(caller, "set_billing_config", None, format!("{} tax regions", updated.billing.tax_regions.len()));
+    Ok(updated)
+}
+
+// Settled payments, optionally limited to those paid within [from, to)
+#[ic_cdk::query]
+fn get_revenue_by_region_admin(from: Option<u64>, to: Option<u64>) -> Result<Vec<RegionRevenue>, String> {
+    require(ic_cdk::caller(), Permission::ManageBilling)?;
+    let mut revenue: std::collections::BTreeMap<(String, String), RegionRevenue> = std::collections::BTreeMap::new();
+    PAYMENT_TRANSACTIONS.with(|transactions| {
+        for (scanned, (_, t)) in transactions.borrow().iter().enumerate() {
+            scan_checkpoint(scanned, "Narrow the date range.")?;
+            let Some(paid_at) = t.paid_at.filter(|_| t.status == "success" || t.status == "refunded") else {
+                continue;
+            };
+            if from.is_some_and(|f| paid_at < f) || to.is_some_and(|t| paid_at >= t) {
+                continue;
+            }
+            let region = t.region.clone().unwrap_or_else(|| "unknown".to_string());
+            let entry = revenue.entry((region.clone(), t.currency.clone())).or_insert_with(|| RegionRevenue {
+                region,
+                currency: t.currency.clone(),
+                payments: 0,
+                gross: 0,
+                tax: 0,
+                net: 0,
+                refunded: 0,
+            });
+            let tax = t.tax.as_ref().map(|tax| tax.tax_amount).unwrap_or(0);
+            entry.payments += 1;
+            entry.gross += t.amount_naira;
+            entry.tax += tax;
+            entry.net += t.amount_naira - tax;
+            if t.status == "refunded" {
+                entry.refunded += t.amount_naira;
+            }
+        }
+        Ok::<(), String>(())
+    })?;
+    Ok(revenue.into_values().collect())
+}
+
+// --- Study Packs ---
+//
+// A study pack is a print-ready HTML document built from one session: the summary, the tutor
+// replies the learner bookmarked or rated highly, AI-generated flashcards, and results of
+// placement tests and exams on the same tutor and topic. Each learner keeps one pack per
+// session; generating again replaces it. Packs are served at /study-pack/{id}, where the
+// random id is the only credential, so they can be opened and printed from a browser.
+
+const STUDY_PACK_CHUNK_BYTES: usize = 256 * 1024;
+const STUDY_PACK_FLASHCARDS: usize = 12;
+
+#[derive(serde::Deserialize)]
+struct AiStudyPack {
+    summary: String,
+    flashcards: Vec<Flashcard>,
+}
+
+fn study_pack_chunk_key(pack_id: &str, index: u32) -> String {
+    format!("{}:{:04}", pack_id, index)
+}
+
+fn remove_study_pack(pack: &StudyPack) {
+    STUDY_PACKS.with(|packs| packs.borrow_mut().remove(&pack.id));
+    STUDY_PACK_CHUNKS.with(|chunks| {
+        let mut chunks = chunks.borrow_mut();
+        for index in 0..pack.chunk_count {
+            chunks.remove(&study_pack_chunk_key(&pack.id, index));
+        }
+    });
+    record_storage_change(pack.user_id, "messages", -(pack.total_bytes as i64));
+}
+
+fn study_pack_html(pack: &StudyPack, tutor_name: &str, topic: &str, summary: &str, explanations: &[ChatMessage], quiz_lines: &[String]) -> String {
+    let mut html = format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>{title}</title>\
+        <style>body{{font-family:Georgia,serif;max-width:46em;margin:2em auto;line-height:1.5}}\
+        .reply{{white-space:pre-wrap}}.card{{border:1px solid #999;padding:.6em 1em;margin:.6em 0;break-inside:avoid}}\
+        @media print{{h2{{break-before:page}}h2:first-of-type{{break-before:auto}}}}</style></head>\
+        <body><h1>{title}</h1><p>{tutor} &middot; {topic} &middot; {date}</p>",
+        title = escape_html(&pack.title),
+        tutor = escape_html(tutor_name),
+        topic = escape_html(topic),
+        date = &iso8601(pack.created_at)[..10],
+    );
+    html.push_str(&format!("<h2>Summary</h2><div class=\"reply\">{}</div>", escape_html(summary)));
+    
+    html.push_str("<h2>Key explanations</h2>");
+    if explanations.is_empty() {
+        html.push_str("<p>Bookmark tutor replies during the session to include them here.</p>");
+    }
+    for message in explanations {
+        html.push_str(&format!("<div class=\"card reply\">{}</div>", escape_html(strip_markdown_fences(message.content.trim()).trim())));
+    }
+    
+    html.push_str("<h2>Flashcards</h2>");
+    for card in &pack.flashcards {
+        html.push_str(&format!("<div class=\"card\"><strong>{}</strong><p>{}</p></div>", escape_html(&card.front), escape_html(&card.back)));
+    }
+    
+    html.push_str("<h2>Quiz results</h2>");
+    if quiz_lines.is_empty() {
+        html.push_str("<p>No placement tests or exams on this topic yet.</p>");
+    } else {
+        html.push_str("<ul>");
+        for line in quiz_lines {
+            html.push_str(&format!("<li>{}</li>", escape_html(line)));
+        }
+        html.push_str("</ul>");
+    }
+    html.push_str("</body></html>");
+    html
+}
+
+// Completed placement tests and exams the learner took with this tutor on the session's topic
+fn study_pack_quiz_lines(user_id: Principal, tutor_id: &str, topic: &str) -> Vec<String> {
+    let matches = |t: &str, tp: &str| t == tutor_id && tp.trim().eq_ignore_ascii_case(topic.trim());
+    let mut results: Vec<(u64, String)> = PLACEMENT_TESTS.with(|tests| {
+        tests.borrow().values()
+            .filter(|t| t.user_id == user_id && t.status == "completed" && matches(&t.tutor_id, &t.topic))
+            .map(|t| {
+                let answered = t.items.iter().filter(|i| i.answer.is_some()).count();
+                let correct = t.items.iter().filter(|i| i.is_correct == Some(true)).count();
+                let level = t.recommended_difficulty.clone().unwrap_or_else(|| "unrated".to_string());
+                (t.completed_at.unwrap_or(t.updated_at), format!("Placement test: {} of {} correct, placed at {}", correct, answered, level))
+            })
+            .collect()
+    });
+    EXAMS.with(|exams| {
+        for exam in exams.borrow().values().filter(|e| e.user_id == user_id && e.status == "completed" && matches(&e.tutor_id, &e.topic)) {
+            let line = format!("Exam: {:.0}%{}", exam.score_percent.unwrap_or(0.0), exam.analysis.as_ref().map(|a| format!(". {}", a.trim())).unwrap_or_default());
+            results.push((exam.completed_at.unwrap_or(exam.created_at), line));
+        }
+    });
+    results.sort_by_key(|(at, _)| *at);
+    results.into_iter().map(|(at, line)| format!("{} - {}", &iso8601(at)[..10], line)).collect()
+}
+
+#[ic_cdk::update]
+fn bookmark_tutor_message(session_id: String, message_id: String, bookmarked: bool) -> Result<ChatMessage, String> {
+    let caller = ic_cdk::caller();
+    let session = participant_session(&session_id, caller)?;
+    CHAT_MESSAGES.with(|messages| messages.borrow().get(&session_id))
+        .and_then(|list| list.0.into_iter().find(|m| m.id == message_id))
+        .filter(|m| m.sender == "tutor" && m.delivery_status == "delivered")
+        .ok_or("Tutor reply not found")?;
+    update_chat_message(session.user_id, &session_id, &message_id, |m| m.bookmarked = bookmarked)
+        .ok_or_else(|| "Tutor reply not found".to_string())
+}
+
+#[ic_cdk::update]
+async fn generate_study_pack(session_id: String) -> Result<StudyPack, String> {
+    let caller = ic_cdk::caller();
+    rate_limit::check(caller, "generation")?;
+    let session = participant_session(&session_id, caller)?;
+    let messages = CHAT_MESSAGES.with(|messages| {
+        messages.borrow().get(&session_id).map(|list| list.0).unwrap_or_default()
+    });
+    let tutor_name = cache::tutor_by_public_id(&session.tutor_id).map(|(_, t)| t.name).unwrap_or_else(|| "Tutor".to_string());
+    let transcript = clean_transcript(&messages, &tutor_name);
+    if transcript.is_empty() {
+        return Err("Session has no messages to build a study pack from".to_string());
+    }
+    
+    let prompt = format!(
+        "Turn this tutoring session on '{}' into revision material for the student.
+        
+        Return JSON:
+        {{\"summary\":\"The key concepts and how they fit together, under 300 words\",\
+        \"flashcards\":[{{\"front\":\"A question or term\",\"back\":\"A short answer\"}}]}}
+        
+        Write up to {} flashcards covering what the student worked on.
+        
+        Earlier summary: {}
+        
+        Transcript:
+        {}",
+        session.topic,
+        STUDY_PACK_FLASHCARDS,
+        session.summary.clone().unwrap_or_else(|| "none".to_string()),
+        transcript
+    );
+    let generated = call_groq_ai(&prompt, "summary", caller).await
+        .map(|r| process_ai_response(r, &response_processing_for(caller, "json"))).ok()
+        .and_then(|r| serde_json::from_str::<AiStudyPack>(&r).ok());
+    let (summary, flashcards) = match generated {
+        Some(pack) if !pack.summary.trim().is_empty() => {
+            let cards = pack.flashcards.into_iter()
+                .filter(|c| !c.front.trim().is_empty() && !c.back.trim().is_empty())
+                .take(STUDY_PACK_FLASHCARDS)
+                .collect();
+            (pack.summary.trim().to_string(), cards)
+        }
+        _ => (session.summary.clone().unwrap_or_else(|| format!("Tutoring session on {}.", session.topic)), Vec::new()),
+    };
+    
+    // Bookmarked replies, and replies the learner rated 4 or 5 as a stand-in when none are bookmarked
+    let bookmarked: Vec<ChatMessage> = messages.iter().filter(|m| m.sender == "tutor" && m.bookmarked).cloned().collect();
+    let explanations = if bookmarked.is_empty() {
+        messages.into_iter().filter(|m| m.sender == "tutor" && m.rating.is_some_and(|r| r >= 4)).collect()
+    } else {
+        bookmarked
+    };
+    let quiz_lines = study_pack_quiz_lines(caller, &session.tutor_id, &session.topic);
+    
+    // Re-check after the AI call; the session may have been deleted meanwhile
+    participant_session(&session_id, caller)?;
+    let id = format!("pack_{}", hex_encode(&random_bytes().await?[..16]));
+    let mut pack = StudyPack {
+        url_path: format!("/study-pack/{}", id),
+        id,
+        session_id: session_id.clone(),
+        user_id: caller,
+        title: format!("Study pack: {}", session.topic),
+        flashcards,
+        explanations: explanations.len() as u32,
+        quiz_results: quiz_lines.len() as u32,
+        chunk_count: 0,
+        total_bytes: 0,
+        created_at: ic_cdk::api::time(),
+    };
+    let html = study_pack_html(&pack, &tutor_name, &session.topic, &summary, &explanations, &quiz_lines);
+    // The HTTP route returns the whole document in one reply
+    ensure_bytes_fit(html.len(), 1, "Bookmark fewer replies to shorten the study pack.")?;
+    
+    let previous = STUDY_PACKS.with(|packs| {
+        packs.borrow().values().find(|p| p.user_id == caller && p.session_id == session_id)
+    });
+    let freed = previous.as_ref().map(|p| p.total_bytes).unwrap_or(0);
+    check_storage_quota(caller, (html.len() as u64).saturating_sub(freed))?;
+    if let Some(previous) = previous {
+        remove_study_pack(&previous);
+    }
+    
+    let mut chunks = Vec::new();
+    let mut rest = html.as_str();
+    while !rest.is_empty() {
+        let mut end = rest.len().min(STUDY_PACK_CHUNK_BYTES);
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        chunks.push(rest[..end].to_string());
+        rest = &rest[end..];
+    }
+    pack.chunk_count = chunks.len() as u32;
+    pack.total_bytes = html.len() as u64;
+    STUDY_PACK_CHUNKS.with(|stored| {
+        let mut stored = stored.borrow_mut();
+        for (index, chunk) in chunks.into_iter().enumerate() {
+            stored.insert(study_pack_chunk_key(&pack.id, index as u32), chunk);
+        }
+    });
+    STUDY_PACKS.with(|packs| packs.borrow_mut().insert(pack.id.clone(), pack.clone()));
+    record_storage_change(caller, "messages", pack.total_bytes as i64);
+    Ok(pack)
+}
+
+#[ic_cdk::query]
+fn get_my_study_packs() -> Vec<StudyPack> {
+    let caller = ic_cdk::caller();
+    STUDY_PACKS.with(|packs| packs.borrow().values().filter(|p| p.user_id == caller).collect())
+}
+
+// For clients that fetch the document over candid rather than the HTTP route
+#[ic_cdk::query]
+fn get_study_pack_chunk(pack_id: String, index: u32) -> Result<String, String> {
+    let caller = ic_cdk::caller();
+    STUDY_PACKS.with(|packs| packs.borrow().get(&pack_id))
+        .filter(|p| p.user_id == caller)
+        .ok_or("Study pack not found")?;
+    STUDY_PACK_CHUNKS.with(|chunks| chunks.borrow().get(&study_pack_chunk_key(&pack_id, index)))
+        .ok_or_else(|| "Chunk not found".to_string())
+}
+
+#[ic_cdk::update]
+fn delete_study_pack(pack_id: String) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    let pack = STUDY_PACKS.with(|packs| packs.borrow().get(&pack_id))
+        .filter(|p| p.user_id == caller)
+        .ok_or("Study pack not found")?;
+    remove_study_pack(&pack);
+    Ok(())
+}
+
+fn study_pack_response(pack_id: &str) -> HttpResponse {
+    let Some(pack) = STUDY_PACKS.with(|packs| packs.borrow().get(&pack_id.to_string())) else {
+        return http_not_found();
+    };
+    let mut body = Vec::with_capacity(pack.total_bytes as usize);
+    STUDY_PACK_CHUNKS.with(|chunks| {
+        let chunks = chunks.borrow();
+        for index in 0..pack.chunk_count {
+            if let Some(chunk) = chunks.get(&study_pack_chunk_key(&pack.id, index)) {
+                body.extend_from_slice(chunk.as_bytes());
+            }
+        }
+    });
+    let mut response = http_response(200, "text/html; charset=utf-8", body);
+    response.headers.push(("Cache-Control".to_string(), "private, no-store".to_string()));
+    response.headers.push(("X-Robots-Tag".to_string(), "noindex".to_string()));
+    response
+}
+
+// --- Concept Maps ---
+//
+// Asks the AI for a graph of the concepts in a course or session and keeps it per learner.
+// Replies are validated before they are stored: labels are trimmed and deduplicated, edges
+// must join two known nodes, and graphs are capped in size. Completing a course module marks
+// its concepts done and asks for any concepts the module adds that the map is missing.
+
+const CONCEPT_MAP_MAX_NODES: usize = 60;
+const CONCEPT_MAP_MAX_EDGES: usize = 150;
+
+#[derive(serde::Deserialize)]
+struct AiConceptNode {
+    id: String,
+    label: String,
+    #[serde(default)]
+    description: String,
+    #[serde(default)]
+    module: Option<usize>, // 1-based position of the module in the outline given
+}
+
+#[derive(serde::Deserialize)]
+struct AiConceptEdge {
+    from: String,
+    to: String,
+    #[serde(default)]
+    relation: String,
+}
+
+#[derive(serde::Deserialize)]
+struct AiConceptGraph {
+    nodes: Vec<AiConceptNode>,
+    #[serde(default)]
+    edges: Vec<AiConceptEdge>,
+}
+
+fn concept_map_key(user_id: Principal, source_type: &str, source_id: &str) -> String {
+    format!("{}:{}:{}", user_id, source_type, source_id)
+}
+
+fn concept_node_id(label: &str) -> String {
+    let slug: String = label.to_lowercase().chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect();
+    slug.split('_').filter(|part| !part.is_empty()).collect::<Vec<_>>().join("_")
+}
+
+// Merges an AI graph into the map. AI node ids are only used to resolve its edges; stored ids
+// come from labels, so a concept the map already has is matched rather than duplicated.
+fn merge_concept_graph(map: &mut ConceptMap, graph: AiConceptGraph, modules: &[CourseModule]) {
+    let mut ids: HashMap<String, String> = HashMap::new();
+    for node in graph.nodes {
+        let label = trim_to_length(node.label.trim(), 80);
+        let id = concept_node_id(&label);
+        if id.is_empty() {
+            continue;
+        }
+        ids.insert(node.id.trim().to_string(), id.clone());
+        if map.nodes.iter().any(|n| n.id == id) || map.nodes.len() >= CONCEPT_MAP_MAX_NODES {
+            continue;
+        }
+        let module_id = node.module.and_then(|m| m.checked_sub(1)).and_then(|m| modules.get(m)).map(|m| m.id);
+        map.nodes.push(ConceptNode {
+            id,
+            label,
+            description: trim_to_length(node.description.trim(), 300),
+            module_id,
+            completed: module_id.is_some_and(|m| map.completed_modules.contains(&m)),
+        });
+    }
+    for edge in graph.edges {
+        let (Some(from), Some(to)) = (ids.get(edge.from.trim()), ids.get(edge.to.trim())) else {
+            continue;
+        };
+        let known = |id: &String| map.nodes.iter().any(|n| &n.id == id);
+        if from == to || !known(from) || !known(to) || map.edges.len() >= CONCEPT_MAP_MAX_EDGES {
+            continue;
+        }
+        if map.edges.iter().any(|e| &e.from == from && &e.to == to) {
+            continue;
+        }
+        let relation = trim_to_length(edge.relation.trim(), 40);
+        map.edges.push(ConceptEdge {
+            from: from.clone(),
+            to: to.clone(),
+            relation: if relation.is_empty() { "relates to".to_string() } else { relation },
+        });
+    }
+}
+
+async fn request_concept_graph(prompt: &str, caller: Principal) -> Result<AiConceptGraph, String> {
+    let response = call_groq_ai(prompt, "concept_map", ic_cdk::caller()).await?;
+    let response = process_ai_response(response, &response_processing_for(caller, "json"));
+    serde_json::from_str::<AiConceptGraph>(&response)
+        .map_err(|_| "The AI didn't return a usable concept map. Try again.".to_string())
+}
+
+const CONCEPT_GRAPH_FORMAT: &str = "Return ONLY JSON:
+        {\"nodes\":[{\"id\":\"n1\",\"label\":\"Concept\",\"description\":\"One sentence\",\"module\":1}],\
+        \"edges\":[{\"from\":\"n1\",\"to\":\"n2\",\"relation\":\"is a prerequisite of\"}]}";
+
+fn numbered_modules(modules: &[CourseModule]) -> String {
+    modules.iter().enumerate()
+        .map(|(i, m)| format!("{}. {} - {}", i + 1, m.title, m.description))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn sorted_modules(course: &TutorCourse) -> Vec<CourseModule> {
+    let mut modules = course.modules.clone();
+    modules.sort_by_key(|m| m.order);
+    modules
+}
+
+// Courses the caller can see through their tutor
+fn readable_course(course_id: u64, caller: Principal) -> Result<TutorCourse, String> {
+    let course = TUTOR_COURSES.with(|courses| courses.borrow().get(&course_id)).ok_or("Course not found")?;
+    TUTORS.with(|tutors| tutors.borrow().get(&course.tutor_id))
+        .filter(|tutor| can_view(tutor, caller))
+        .ok_or("Course not found")?;
+    Ok(course)
+}
+
+// Exactly one of course_id and session_id identifies what to map
+#[ic_cdk::update]
+async fn generate_concept_map(course_id: Option<u64>, session_id: Option<String>) -> Result<ConceptMap, String> {
+    let caller = ic_cdk::caller();
+    rate_limit::check(caller, "generation")?;
+    let (source_type, source_id, title, prompt, modules) = match (course_id, session_id) {
+        (Some(course_id), None) => {
+            let course = readable_course(course_id, caller)?;
+            let modules = sorted_modules(&course);
+            let prompt = format!(
+                "Map the concepts taught in the course '{}' and how they relate.
+                
+                Modules:
+                {}
+                
+                Use up to {} concepts. Set \"module\" to the number of the module that teaches each concept.
+                {}",
+                course.topic,
+                numbered_modules(&modules),
+                CONCEPT_MAP_MAX_NODES / 2,
+                CONCEPT_GRAPH_FORMAT
+            );
+            ("course", course_id.to_string(), course.topic, prompt, modules)
+        }
+        (None, Some(session_id)) => {
+            let session = participant_session(&session_id, caller)?;
+            let messages = CHAT_MESSAGES.with(|messages| {
+                messages.borrow().get(&session_id).map(|list| list.0).unwrap_or_default()
+            });
+            let tutor_name = cache::tutor_by_public_id(&session.tutor_id).map(|(_, t)| t.name).unwrap_or_else(|| "Tutor".to_string());
+            let transcript = clean_transcript(&messages, &tutor_name);
+            if transcript.is_empty() {
+                return Err("Session has no messages to map".to_string());
+            }
+            let prompt = format!(
+                "Map the concepts discussed in this tutoring session on '{}' and how they relate. Leave out \
+                small talk. Omit \"module\".
+                
+                Earlier summary: {}
+                
+                Transcript:
+                {}
+                
+                Use up to {} concepts.
+                {}",
+                session.topic,
+                session.summary.clone().unwrap_or_else(|| "none".to_string()),
+                transcript,
+                CONCEPT_MAP_MAX_NODES / 2,
+                CONCEPT_GRAPH_FORMAT
+            );
+            ("session", session_id, session.topic, prompt, Vec::new())
+        }
+        _ => return Err("Pass either a course id or a session id".to_string()),
+    };
+    let graph = request_concept_graph(&prompt, caller).await?;
+    
+    let now = ic_cdk::api::time();
+    let key = concept_map_key(caller, source_type, &source_id);
+    let previous = CONCEPT_MAPS.with(|maps| maps.borrow().get(&key));
+    let completed_modules: Vec<u64> = if source_type == "course" {
+        let module_ids: Vec<u64> = modules.iter().map(|m| m.id).collect();
+        MODULE_COMPLETIONS.with(|completions| {
+            completions.borrow().values()
+                .filter(|c| c.user_id == caller && c.completed && module_ids.contains(&c.module_id))
+                .map(|c| c.module_id)
+                .collect()
+        })
+    } else {
+        Vec::new()
+    };
+    let mut map = ConceptMap {
+        user_id: caller,
+        source_type: source_type.to_string(),
+        source_id,
+        title,
+        nodes: Vec::new(),
+        edges: Vec::new(),
+        completed_modules,
+        version: previous.map(|p| p.version + 1).unwrap_or(1),
+        generated_at: now,
+        updated_at: now,
+    };
+    merge_concept_graph(&mut map, graph, &modules);
+    if map.nodes.is_empty() {
+        return Err("The AI didn't return any concepts. Try again.".to_string());
+    }
+    CONCEPT_MAPS.with(|maps| maps.borrow_mut().insert(key, map.clone()));
+    Ok(map)
+}
+
+// Called when a learner completes a module. Marks the module's concepts done in every course
+// map the learner has that contains it, and asks for concepts the module adds that are missing.
+async fn update_concept_maps_for_module(user_id: Principal, module_id: u64) {
+    let start = format!("{}:course:", user_id);
+    let end = format!("{}:course;", user_id);
+    let maps: Vec<(String, ConceptMap)> = CONCEPT_MAPS.with(|maps| maps.borrow().range(start..end).collect());
+    for (key, mut map) in maps {
+        let Some(course) = map.source_id.parse::<u64>().ok().and_then(|id| TUTOR_COURSES.with(|c| c.borrow().get(&id))) else {
+            continue;
+        };
+        let modules = sorted_modules(&course);
+        let Some(position) = modules.iter().position(|m| m.id == module_id) else {
+            continue;
+        };
+        if map.completed_modules.contains(&module_id) {
+            continue;
+        }
+        map.completed_modules.push(module_id);
+        for node in map.nodes.iter_mut().filter(|n| n.module_id == Some(module_id)) {
+            node.completed = true;
+        }
+        
+        // Extending the map is a bonus; without AI quota the completion is still recorded
+        if map.nodes.len() < CONCEPT_MAP_MAX_NODES {
+            let existing: Vec<String> = map.nodes.iter().map(|n| format!("{} ({})", n.label, n.id)).collect();
+            let prompt = format!(
+                "A learner just completed module {} of the course '{}'. List concepts that module teaches \
+                which are missing from their concept map, and how they connect to the existing ones. \
+                Refer to existing concepts by the id in brackets. Return empty lists if nothing is missing.
+                
+                Modules:
+                {}
+                
+                Existing concepts: {}
+                {}",
+                position + 1,
+                course.topic,
+                numbered_modules(&modules),
+                existing.join(", "),
+                CONCEPT_GRAPH_FORMAT
+            );
+            match request_concept_graph(&prompt, user_id).await {
+                Ok(mut graph) => {
+                    // Existing concepts resolve to themselves when edges name them
+                    for node in &map.nodes {
+                        graph.nodes.push(AiConceptNode { id: node.id.clone(), label: node.label.clone(), description: String::new(), module: None });
+                    }
+                    merge_concept_graph(&mut map, graph, &modules);
+                }
+                Err(e) => ic_cdk::println!("Concept map update failed for {}: {}", key, e),
+            }
+        }
+        
+        // Re-read so a map regenerated during the AI call isn't overwritten
+        let Some(mut current) = CONCEPT_MAPS.with(|maps| maps.borrow().get(&key)) else {
+            continue;
+        };
+        if current.generated_at != map.generated_at {
+            if !current.completed_modules.contains(&module_id) {
+                current.completed_modules.push(module_id);
+            }
+            for node in current.nodes.iter_mut().filter(|n| n.module_id == Some(module_id)) {
+                node.completed = true;
+            }
+            map = current;
+        }
+        map.version += 1;
+        map.updated_at = ic_cdk::api::time();
+        CONCEPT_MAPS.with(|maps| maps.borrow_mut().insert(key, map));
+    }
+}
+
+#[ic_cdk::query]
+fn get_concept_map(course_id: Option<u64>, session_id: Option<String>) -> Result<ConceptMap, String> {
+    let caller = ic_cdk::caller();
+    let key = match (course_id, session_id) {
+        (Some(course_id), None) => concept_map_key(caller, "course", &course_id.to_string()),
+        (None, Some(session_id)) => concept_map_key(caller, "session", &session_id),
+        _ => return Err("Pass either a course id or a session id".to_string()),
+    };
+    CONCEPT_MAPS.with(|maps| maps.borrow().get(&key)).ok_or_else(|| "Concept map not found".to_string())
+}
+
+#[ic_cdk::query]
+fn get_my_concept_maps() -> Vec<ConceptMap> {
+    let caller = ic_cdk::caller();
+    let start = format!("{}:", caller);
+    let end = format!("{};", caller);
+    CONCEPT_MAPS.with(|maps| maps.borrow().range(start..end).map(|(_, m)| m).collect())
+}
+
+#[ic_cdk::update]
+fn delete_concept_map(course_id: Option<u64>, session_id: Option<String>) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    let key = match (course_id, session_id) {
+        (Some(course_id), None) => concept_map_key(caller, "course", &course_id.to_string()),
+        (None, Some(session_id)) => concept_map_key(caller, "session", &session_id),
+        _ => return Err("Pass either a course id or a session id".to_string()),
+    };
+    CONCEPT_MAPS.with(|maps| maps.borrow_mut().remove(&key)).map(|_| ()).ok_or_else(|| "Concept map not found".to_string())
+}
+
+// --- Course Glossaries ---
+//
+// Each course keeps a glossary of key terms. Terms are picked up from lesson text whenever a
+// course is stored (bold "**Term**: definition" lines and markdown definition lists), and the
+// owner can ask the AI to extract more. Terms are deduplicated case-insensitively, and owner
+// edits always win over extraction. Review quizzes on a course topic draw definition questions
+// from its glossary instead of calling the AI.
+
+const GLOSSARY_MAX_TERMS: usize = 300;
+const GLOSSARY_QUIZ_MIN_TERMS: usize = 4;
+
+#[derive(serde::Deserialize)]
+struct AiGlossaryTerm {
+    term: String,
+    definition: String,
+    #[serde(default)]
+    module: Option<usize>, // 1-based position in the module list given
+}
+
+fn normalized_term(term: &str) -> String {
+    term.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase()
+}
+
+fn glossary_key(course_id: u64, term: &str) -> String {
+    format!("{:020}:{}", course_id, normalized_term(term))
+}
+
+fn glossary_entries(course_id: u64) -> Vec<GlossaryEntry> {
+    let start = format!("{:020}:", course_id);
+    let end = format!("{:020};", course_id);
+    COURSE_GLOSSARY.with(|glossary| glossary.borrow().range(start..end).map(|(_, e)| e).collect())
+}
+
+fn valid_glossary_pair(term: &str, definition: &str) -> bool {
+    let words = term.split_whitespace().count();
+    (1..=6).contains(&words) && term.chars().count() <= 60 && definition.chars().count() >= 10
+}
+
+// "**Term**: definition", "**Term:** definition", "**Term** - definition", and "Term" followed
+// by a ": definition" line
+fn defined_terms(text: &str) -> Vec<(String, String)> {
+    let lines: Vec<&str> = text.lines().map(str::trim).collect();
+    let mut pairs = Vec::new();
+    for (index, line) in lines.iter().enumerate() {
+        let line = line.strip_prefix("- ").or_else(|| line.strip_prefix("* ")).unwrap_or(line);
+        if let Some((term, rest)) = line.strip_prefix("**").and_then(|l| l.split_once("**")) {
+            let separated = term.trim_end().ends_with(':') || rest.trim_start().starts_with([':', '-', '\u{2013}', '\u{2014}']);
+            let term = term.trim().trim_end_matches(':').trim();
+            let definition = rest.trim_start().trim_start_matches([':', '-', '\u{2013}', '\u{2014}']).trim();
+            if separated && valid_glossary_pair(term, definition) {
+                pairs.push((term.to_string(), definition.to_string()));
+            }
+        } else if let Some(definition) = lines.get(index + 1).and_then(|next| next.strip_prefix(": ")) {
+            let term = line.trim_start_matches('#').trim();
+            if !line.is_empty() && valid_glossary_pair(term, definition.trim()) {
+                pairs.push((term.to_string(), definition.trim().to_string()));
+            }
+        }
+    }
+    pairs
+}
+
+// Adds a term unless the course already has it; returns whether it was added
+fn add_glossary_term(course_id: u64, term: &str, definition: &str, module_id: Option<u64>, source: &str, now: u64) -> bool {
+    let key = glossary_key(course_id, term);
+    COURSE_GLOSSARY.with(|glossary| {
+        let mut glossary = glossary.borrow_mut();
+        if glossary.contains_key(&key) {
+            return false;
+        }
+        glossary.insert(key, GlossaryEntry {
+            course_id,
+            term: term.split_whitespace().collect::<Vec<_>>().join(" "),
+            definition: trim_to_length(definition.trim(), 400),
+            module_id,
+            source: source.to_string(),
+            removed: false,
+            edited_by: None,
+            updated_at: now,
+        });
+        true
+    })
+}
+
+// Run whenever a course's lessons are stored
+fn collect_glossary_terms(course: &TutorCourse) -> u32 {
+    let now = ic_cdk::api::time();
+    let mut room = GLOSSARY_MAX_TERMS.saturating_sub(glossary_entries(course.id).len());
+    let mut added = 0;
+    for module in &course.modules {
+        let text = format!("{}\n{}", module.description, module.content.clone().unwrap_or_default());
+        for (term, definition) in defined_terms(&text) {
+            if room == 0 {
+                return added;
+            }
+            if add_glossary_term(course.id, &term, &definition, Some(module.id), "extracted", now) {
+                added += 1;
+                room -= 1;
+            }
+        }
+    }
+    added
+}
+
+fn copy_course_glossary(from_course_id: u64, to_course_id: u64) {
+    for mut entry in glossary_entries(from_course_id).into_iter().filter(|e| !e.removed) {
+        entry.course_id = to_course_id;
+        entry.edited_by = None;
+        COURSE_GLOSSARY.with(|glossary| glossary.borrow_mut().insert(glossary_key(to_course_id, &entry.term), entry));
+    }
+}
+
+fn remove_course_glossary(course_id: u64) {
+    let keys: Vec<String> = glossary_entries(course_id).iter().map(|e| glossary_key(course_id, &e.term)).collect();
+    COURSE_GLOSSARY.with(|glossary| {
+        let mut glossary = glossary.borrow_mut();
+        for key in keys {
+            glossary.remove(&key);
+        }
+    });
+}
+
+// Glossary terms from the courses the caller can see whose topic is this skill
+fn glossary_for_skill(skill: &str, caller: Principal) -> Vec<GlossaryEntry> {
+    let courses: Vec<u64> = TUTOR_COURSES.with(|courses| {
+        courses.borrow().values()
+            .filter(|c| c.topic.trim().eq_ignore_ascii_case(skill.trim()))
+            .filter(|c| TUTORS.with(|tutors| tutors.borrow().get(&c.tutor_id)).is_some_and(|t| can_view(&t, caller)))
+            .map(|c| c.id)
+            .collect()
+    });
+    let mut seen = std::collections::HashSet::new();
+    courses.into_iter()
+        .flat_map(glossary_entries)
+        .filter(|e| !e.removed && seen.insert(normalized_term(&e.term)))
+        .collect()
+}
+
+// "Which term matches this definition?" with other glossary terms as the wrong options
+fn glossary_questions(entries: &[GlossaryEntry], seed: &[u8], count: usize) -> Vec<AiReviewQuestion> {
+    let mut state = seed.iter().take(8).fold(0x9e37_79b9_7f4a_7c15u64, |acc, b| (acc << 8) ^ *b as u64) | 1;
+    let mut next = |bound: usize| {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        (state % bound as u64) as usize
+    };
+    let mut order: Vec<usize> = (0..entries.len()).collect();
+    for i in (1..order.len()).rev() {
+        order.swap(i, next(i + 1));
+    }
+    order.iter().take(count).map(|&answer| {
+        let mut options: Vec<usize> = vec![answer];
+        while options.len() < GLOSSARY_QUIZ_MIN_TERMS {
+            let candidate = next(entries.len());
+            if !options.contains(&candidate) {
+                options.push(candidate);
+            }
+        }
+        let correct = next(options.len());
+        options.swap(0, correct);
+        AiReviewQuestion {
+            question: format!("Which term matches this definition: {}", entries[answer].definition),
+            options: options.iter().map(|&i| entries[i].term.clone()).collect(),
+            correct_option: correct as u32,
+        }
+    }).collect()
+}
+
+#[ic_cdk::query]
+fn get_course_glossary(course_id: u64) -> Result<Vec<GlossaryEntry>, String> {
+    readable_course(course_id, ic_cdk::caller())?;
+    Ok(glossary_entries(course_id).into_iter().filter(|e| !e.removed).collect())
+}
+
+// Asks the AI for terms the lesson text defines without the markup extraction looks for
+#[ic_cdk::update]
+async fn extract_course_glossary(course_id: u64) -> Result<Vec<GlossaryEntry>, String> {
+    let caller = ic_cdk::caller();
+    rate_limit::check(caller, "generation")?;
+    let course = owned_course(course_id, caller)?;
+    let modules = sorted_modules(&course);
+    let lessons: Vec<String> = modules.iter().enumerate()
+        .map(|(i, m)| format!("Module {}: {}\n{}\n{}", i + 1, m.title, m.description, m.content.clone().unwrap_or_default()))
+        .collect();
+    
+    let prompt = format!(
+        "List the key terms a learner must know from the course '{}', each with a one-sentence \
+        definition based on the lessons. Skip terms already in the glossary.
+        
+        Return ONLY a JSON array:
+        [{{\"term\":\"Term\",\"definition\":\"Definition\",\"module\":1}}]
+        
+        Glossary: {}
+        
+        Lessons:
+        {}",
+        course.topic,
+        glossary_entries(course_id).iter().map(|e| e.term.clone()).collect::<Vec<_>>().join(", "),
+        trim_to_length(&lessons.join("\n\n"), 12_000)
+    );
+    let response = process_ai_response(call_groq_ai(&prompt, "glossary", caller).await?, &response_processing_for(caller, "json"));
+    let terms: Vec<AiGlossaryTerm> = serde_json::from_str(&response)
+        .map_err(|_| "The AI didn't return a usable term list. Try again.".to_string())?;
+    
+    let now = ic_cdk::api::time();
+    let mut room = GLOSSARY_MAX_TERMS.saturating_sub(glossary_entries(course_id).len());
+    for term in terms {
+        let (name, definition) = (term.term.trim(), term.definition.trim());
+        if room == 0 || !valid_glossary_pair(name, definition) {
+            continue;
+        }
+        let module_id = term.module.and_then(|m| m.checked_sub(1)).and_then(|m| modules.get(m)).map(|m| m.id);
+        if add_glossary_term(course_id, name, definition, module_id, "ai", now) {
+            room -= 1;
+        }
+    }
+    Ok(glossary_entries(course_id).into_iter().filter(|e| !e.removed).collect())
+}
+
+// Adds a term or replaces its definition; later extraction leaves it alone
+#[ic_cdk::update]
+fn set_glossary_entry(course_id: u64, term: String, definition: String) -> Result<GlossaryEntry, String> {
+    let caller = ic_cdk::caller();
+    owned_course(course_id, caller)?;
+    let term = term.split_whitespace().collect::<Vec<_>>().join(" ");
+    if term.is_empty() || term.chars().count() > 60 {
+        return Err("Terms must be between 1 and 60 characters".to_string());
+    }
+    if definition.trim().is_empty() {
+        return Err("A definition is required".to_string());
+    }
+    let key = glossary_key(course_id, &term);
+    let existing = COURSE_GLOSSARY.with(|glossary| glossary.borrow().get(&key));
+    if existing.as_ref().is_none_or(|e| e.removed) && glossary_entries(course_id).iter().filter(|e| !e.removed).count() >= GLOSSARY_MAX_TERMS {
+        return Err(format!("Glossaries are limited to {} terms", GLOSSARY_MAX_TERMS));
+    }
+    
+    let entry = GlossaryEntry {
+        course_id,
+        term,
+        definition: trim_to_length(definition.trim(), 400),
+        module_id: existing.and_then(|e| e.module_id),
+        source: "user".to_string(),
+        removed: false,
+        edited_by: Some(caller),
+        updated_at: ic_cdk::api::time(),
+    };
+    COURSE_GLOSSARY.with(|glossary| glossary.borrow_mut().insert(key, entry.clone()));
+    Ok(entry)
+}
+
+// Kept as a removed entry so the term isn't extracted again
+#[ic_cdk::update]
+fn remove_glossary_entry(course_id: u64, term: String) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    owned_course(course_id, caller)?;
+    let key = glossary_key(course_id, &term);
+    let mut entry = COURSE_GLOSSARY.with(|glossary| glossary.borrow().get(&key))
+        .filter(|e| !e.removed)
+        .ok_or("Term not found")?;
+    entry.removed = true;
+    entry.source = "user".to_string();
+    entry.edited_by = Some(caller);
+    entry.updated_at = ic_cdk::api::time();
+    COURSE_GLOSSARY.with(|glossary| glossary.borrow_mut().insert(key, entry));
+    Ok(())
+}
+
+// --- AI Call Diagnostics ---
+//
+// call_ai_with_params records which provider and model answered each call, how many attempts
+// it took, the total latency and the response size. Records are pruned by the
+// "ai_diagnostics" retention policy and capped in number, so the table stays bounded even
+// when retention is turned off.
+
+const AI_DIAGNOSTICS_MAX_RECORDS: u64 = 50_000;
+const AI_DIAGNOSTICS_PAGE_LIMIT: usize = 500;
+
+struct AiCallTrace<'a> {
+    operation: &'a str,
+    message_id: Option<&'a str>,
+    started: u64,
+    provider: Option<String>,
+    model: Option<String>,
+    attempts: u32,
+}
+
+impl AiCallTrace<'_> {
+    fn record(&self, outcome: &str, response_bytes: usize) {
+        let now = ic_cdk::api::time();
+        let diagnostic = AiCallDiagnostic {
+            id: next_id("ai_diagnostic"),
+            operation: self.operation.to_string(),
+            caller: ic_cdk::caller(),
+            message_id: self.message_id.map(str::to_string),
+            provider: self.provider.clone(),
+            model: self.model.clone(),
+            attempts: self.attempts,
+            latency_ms: now.saturating_sub(self.started) / 1_000_000,
+            response_bytes: response_bytes as u64,
+            outcome: outcome.to_string(),
+            recorded_at: now,
+        };
+        AI_DIAGNOSTICS.with(|diagnostics| {
+            let mut diagnostics = diagnostics.borrow_mut();
+            diagnostics.insert(diagnostic.id, diagnostic);
+            if diagnostics.len() > AI_DIAGNOSTICS_MAX_RECORDS {
+                if let Some((oldest, _)) = diagnostics.first_key_value() {
+                    diagnostics.remove(&oldest);
+                }
+            }
+        });
+    }
+}
+
+// Ids increase with time, so expired records are always at the front
+fn prune_ai_diagnostics(cutoff: u64) -> u64 {
+    let expired: Vec<u64> = AI_DIAGNOSTICS.with(|diagnostics| {
+        diagnostics.borrow().iter()
+            .take_while(|(_, d)| d.recorded_at < cutoff)
+            .map(|(id, _)| id)
+            .take(RETENTION_BATCH_SIZE)
+            .collect()
+    });
+    AI_DIAGNOSTICS.with(|diagnostics| {
+        let mut diagnostics = diagnostics.borrow_mut();
+        for id in &expired {
+            diagnostics.remove(id);
+        }
+    });
+    expired.len() as u64
+}
+
+#[derive(Default)]
+struct LatencyGroup {
+    latencies: Vec<u64>, // successful calls only
+    calls: u64,
+    failed_calls: u64,
+    attempts: u64,
+    response_bytes: u64,
+}
+
+fn percentile(sorted: &[u64], percent: usize) -> u64 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    sorted[((sorted.len() * percent).div_ceil(100)).saturating_sub(1)]
+}
+
+// Newest first. Pass before_id from the last record to page further back.
+#[ic_cdk::query]
+fn get_ai_diagnostics_admin(message_id: Option<String>, before_id: Option<u64>, limit: u32) -> Result<Vec<AiCallDiagnostic>, String> {
+    require(ic_cdk::caller(), Permission::ManageSystem)?;
+    
+    let limit = (limit as usize).clamp(1, AI_DIAGNOSTICS_PAGE_LIMIT);
+    let hint = "Filter by message id or page with before_id.";
+    let mut records = Vec::new();
+    AI_DIAGNOSTICS.with(|diagnostics| {
+        let diagnostics = diagnostics.borrow();
+        for (scanned, (_, diagnostic)) in diagnostics.range(..before_id.unwrap_or(u64::MAX)).rev().enumerate() {
+            scan_checkpoint(scanned, hint)?;
+            if message_id.as_ref().is_none_or(|id| diagnostic.message_id.as_ref() == Some(id)) {
+                records.push(diagnostic);
+                if records.len() == limit {
+                    break;
+                }
+            }
+        }
+        Ok::<(), String>(())
+    })?;
+    ensure_fits(&records, hint)?;
+    Ok(records)
+}
+
+// Latency percentiles per provider and model over calls recorded since `since` (default: last 24 hours)
+#[ic_cdk::query]
+fn get_ai_latency_stats_admin(since: Option<u64>) -> Result<Vec<AiLatencyStats>, String> {
+    require(ic_cdk::caller(), Permission::ManageSystem)?;
+    
+    let since = since.unwrap_or_else(|| ic_cdk::api::time().saturating_sub(NANOS_PER_DAY));
+    let mut groups: std::collections::BTreeMap<(String, String), LatencyGroup> = std::collections::BTreeMap::new();
+    AI_DIAGNOSTICS.with(|diagnostics| {
+        for (scanned, (_, diagnostic)) in diagnostics.borrow().iter().rev().take_while(|(_, d)| d.recorded_at >= since).enumerate() {
+            scan_checkpoint(scanned, "Pass a later `since` to cover fewer calls.")?;
+            let (Some(provider), Some(model)) = (diagnostic.provider, diagnostic.model) else {
+                continue;
+            };
+            let group = groups.entry((provider, model)).or_default();
+            if diagnostic.outcome == "ok" {
+                group.latencies.push(diagnostic.latency_ms);
+                group.response_bytes += diagnostic.response_bytes;
+            } else {
+                group.failed_calls += 1;
+            }
+            group.calls += 1;
+            group.attempts += diagnostic.attempts as u64;
+        }
+        Ok::<(), String>(())
+    })?;
+    
+    Ok(groups.into_iter().map(|((provider, model), mut group)| {
+        group.latencies.sort_unstable();
+        AiLatencyStats {
+            provider,
+            model,
+            calls: group.calls,
+            failed_calls: group.failed_calls,
+            p50_latency_ms: percentile(&group.latencies, 50),
+            p95_latency_ms: percentile(&group.latencies, 95),
+            average_attempts: group.attempts as f64 / group.calls as f64,
+            average_response_bytes: group.response_bytes.checked_div(group.latencies.len() as u64).unwrap_or(0),
+        }
+    }).collect())
+}
+
+// --- AI Degradation Mode ---
+//
+// When HTTPS outcalls are unavailable, AI calls fail fast with AI_DEGRADED_ERROR instead of
+// spending cycles on outcalls that can't succeed. Outlines, topic checks and suggestions fall
+// back to templates marked `degraded`; tutor replies are kept as "deferred" messages and
+// generated by the delivery job once AI is back. Availability is tracked on the heap, so
+// auto-detection starts over after an upgrade.
+
+const AI_DEGRADED_ERROR: &str = "AI features are temporarily unavailable";
+const REPLY_DEFERRED_MESSAGE: &str = "AI is temporarily unavailable; the reply will be delivered once it is back";
+const REPLY_DEFERRED_CONTENT: &str = "I can't answer right now because AI features are temporarily unavailable. I'll reply to this message as soon as they're back.";
+const DEGRADATION_MODES: [&str; 3] = ["auto", "on", "off"];
+
+#[derive(Default)]
+struct AiAvailability {
+    consecutive_failures: u32,
+    degraded_since: Option<u64>,
+    last_probe_at: u64,
+    last_error: Option<String>,
+}
+
+thread_local! {
+    static AI_AVAILABILITY: RefCell<AiAvailability> = RefCell::new(AiAvailability::default());
+}
+
+fn is_ai_degraded_error(error: &str) -> bool {
+    error.starts_with(AI_DEGRADED_ERROR)
+}
+
+// Why AI calls are currently skipped, or None when they are attempted
+fn ai_degraded_reason(settings: &DegradationSettings) -> Option<String> {
+    match settings.mode.as_str() {
+        "on" => Some("Degraded mode was turned on by an admin".to_string()),
+        "off" => None,
+        _ => AI_AVAILABILITY.with(|availability| {
+            let availability = availability.borrow();
+            availability.degraded_since?;
+            Some(availability.last_error.clone().unwrap_or_else(|| "No AI provider is reachable".to_string()))
+        }),
+    }
+}
+
+fn ai_probe_due(settings: &DegradationSettings, now: u64) -> bool {
+    settings.mode == "auto" && AI_AVAILABILITY.with(|availability| {
+        now.saturating_sub(availability.borrow().last_probe_at) >= settings.probe_interval_seconds * 1_000_000_000
+    })
+}
+
+// While auto-degraded, one call per probe interval still goes out to detect recovery
+fn ensure_ai_available(settings: &DegradationSettings, now: u64) -> Result<(), String> {
+    let Some(reason) = ai_degraded_reason(settings) else {
+        return Ok(());
+    };
+    if ai_probe_due(settings, now) {
+        AI_AVAILABILITY.with(|availability| availability.borrow_mut().last_probe_at = now);
+        return Ok(());
+    }
+    Err(format!("{} ({}). Try again later.", AI_DEGRADED_ERROR, reason))
+}
+
+// Called once per AI call with the error when no provider answered
+fn record_ai_availability(settings: &DegradationSettings, error: Option<String>) {
+    let now = ic_cdk::api::time();
+    AI_AVAILABILITY.with(|availability| {
+        let mut availability = availability.borrow_mut();
+        match error {
+            None => {
+                if availability.degraded_since.take().is_some() {
+                    ic_cdk::println!("AI providers reachable again; leaving degraded mode");
+                }
+                availability.consecutive_failures = 0;
+                availability.last_error = None;
+            }
+            Some(error) => {
+                availability.consecutive_failures += 1;
+                availability.last_error = Some(error);
+                if availability.degraded_since.is_none() && availability.consecutive_failures >= settings.failure_threshold.max(1) {
+                    ic_cdk::println!("No AI provider answered {} calls in a row; entering degraded mode", availability.consecutive_failures);
+                    availability.degraded_since = Some(now);
+                    availability.last_probe_at = now;
+                }
+            }
+        }
+    });
+}
+
+// Ok(None) when AI is unavailable, so the caller can answer from a template
+fn ai_unless_degraded(result: Result<String, String>) -> Result<Option<String>, String> {
+    match result {
+        Ok(response) => Ok(Some(response)),
+        Err(e) if is_ai_degraded_error(&e) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+// Returned by send_ai_tutor_message when the reply isn't available yet
+fn placeholder_analysis(pending_message_id: Option<String>, degraded: bool) -> ComprehensionAnalysis {
+    ComprehensionAnalysis {
+        comprehension_score: 0.5,
+        difficulty_adjustment: "maintain".to_string(),
+        timestamp: ic_cdk::api::time().to_string(),
+        quota_warning: None,
+        degraded,
+        pending_message_id,
+    }
+}
+
+// Oldest first. While still degraded only one is sent, which doubles as the recovery probe.
+fn dispatch_deferred_deliveries(now: u64) {
+    let settings = get_config().ai_degradation;
+    let batch = match ai_degraded_reason(&settings) {
+        None => DELIVERY_RETRY_BATCH_SIZE,
+        Some(_) if ai_probe_due(&settings, now) => 1,
+        Some(_) => return,
+    };
+    let mut deferred: Vec<PendingDelivery> = PENDING_DELIVERIES.with(|deliveries| {
+        deliveries.borrow().iter().map(|(_, d)| d).filter(|d| d.status == "deferred").collect()
+    });
+    deferred.sort_by_key(|d| d.created_at);
+    for delivery in deferred.into_iter().take(batch) {
+        ic_cdk::spawn(async move {
+            if let Err(e) = deliver_tutor_reply(&delivery.message_id, ic_cdk::id()).await {
+                ic_cdk::println!("Deferred reply {} not delivered yet: {}", delivery.message_id, e);
+            }
+        });
+    }
+}
+
+#[ic_cdk::query]
+fn get_service_status() -> ServiceStatus {
+    let settings = get_config().ai_degradation;
+    let reason = ai_degraded_reason(&settings);
+    ServiceStatus {
+        ai_degraded: reason.is_some(),
+        degraded_since: if settings.mode == "auto" { AI_AVAILABILITY.with(|availability| availability.borrow().degraded_since) } else { None },
+        mode: settings.mode,
+        reason,
+    }
+}
+
+#[ic_cdk::update]
+fn set_ai_degradation_admin(settings: DegradationSettings) -> Result<CanisterConfig, String> {
+    let caller = ic_cdk::caller();
+    require(caller, Permission::ManageSystem)?;
+    if !DEGRADATION_MODES.contains(&settings.mode.as_str()) {
+        return Err(format!("Mode must be one of: {}", DEGRADATION_MODES.join(", ")));
+    }
+    if settings.failure_threshold == 0 || settings.probe_interval_seconds == 0 {
+        return Err("The failure threshold and probe interval must be at least 1".to_string());
+    }
+    
+    let mode = settings.mode.clone();
+    let config = update_config(|config| {
+        config.ai_degradation = settings;
+        Ok(())
+    })?;
+    // Turning auto mode back on starts detection from scratch
+    if mode != "on" {
+        AI_AVAILABILITY.with(|availability| *availability.borrow_mut() = AiAvailability::default());
+    }
+    record_audit(caller, "set_ai_degradation", None, format!("mode={}", mode));
+    Ok(config)
+}
+
+// --- Background Replies ---
+//
+// A reply expected to miss the async_replies deadline is generated in the background: the
+// chat call returns the pending message id straight away, the delivery job retries it like
+// any other failed reply, and the learner gets a notification once it lands or gives up.
+
+const REPLY_PENDING_CONTENT: &str = "Your tutor is still working on this reply. You'll get a notification when it's ready.";
+const REPLY_LATENCY_SAMPLES: usize = 100;
+// Diagnostics for other operations are skipped over; stop after this many
+const REPLY_LATENCY_SCAN_LIMIT: usize = 2_000;
+
+// p95 latency of recent tutor replies, or None until there are enough of them
+fn expected_reply_ms(config: &AsyncReplyConfig) -> Option<u64> {
+    let mut latencies: Vec<u64> = AI_DIAGNOSTICS.with(|diagnostics| {
+        diagnostics.borrow().iter().rev()
+            .take(REPLY_LATENCY_SCAN_LIMIT)
+            .filter(|(_, d)| d.operation == "chat" && d.message_id.is_some())
+            .take(REPLY_LATENCY_SAMPLES)
+            .map(|(_, d)| d.latency_ms)
+            .collect()
+    });
+    if latencies.len() < (config.min_samples as usize).max(1) {
+        return None;
+    }
+    latencies.sort_unstable();
+    Some(percentile(&latencies, 95))
+}
+
+// Marks the delivery for a notification when its reply is likely to be slow
+fn reply_in_background(message_id: &str) -> bool {
+    let config = get_config().async_replies;
+    if !config.enabled || expected_reply_ms(&config).is_none_or(|ms| ms <= config.deadline_ms) {
+        return false;
+    }
+    PENDING_DELIVERIES.with(|deliveries| {
+        let mut deliveries = deliveries.borrow_mut();
+        match deliveries.get(&message_id.to_string()) {
+            Some(mut delivery) => {
+                delivery.notify_when_ready = true;
+                deliveries.insert(message_id.to_string(), delivery);
+                true
+            }
+            None => false,
+        }
+    })
+}
+
+// Called once a background reply is delivered, or has failed for good
+fn notify_reply_settled(delivery: &PendingDelivery, error: Option<&str>) {
+    if !delivery.notify_when_ready {
+        return;
+    }
+    let topic = CHAT_SESSIONS.with(|sessions| sessions.borrow().get(&delivery.session_id))
+        .map(|session| session.topic)
+        .unwrap_or_default();
+    let content = match error {
+        None => format!("Your tutor's reply about \"{}\" is ready.", topic),
+        Some(_) => format!("Your tutor couldn't reply to your message about \"{}\". Open the session to try again.", topic),
+    };
+    notify_user(delivery.requester(), "reply_ready", "tutor", content, None);
+}
+
+#[ic_cdk::update]
+fn set_async_reply_config_admin(async_replies: AsyncReplyConfig) -> Result<CanisterConfig, String> {
+    let caller = ic_cdk::caller();
+    require(caller, Permission::ManageSystem)?;
+    if async_replies.deadline_ms == 0 {
+        return Err("The deadline must be at least 1 ms".to_string());
+    }
+    
+    let details = format!("enabled={} deadline_ms={}", async_replies.enabled, async_replies.deadline_ms);
+    let config = update_config(|config| {
+        config.async_replies = async_replies;
+        Ok(())
+    })?;
+    record_audit(caller, "set_async_reply_config", None, details);
+    Ok(config)
+}
+
+// --- Read Positions ---
+//
+// Each device reports where the user stopped reading a session, so switching devices resumes
+// at the furthest position read on any of them. Unread counts start from that position and
+// cover delivered messages the user didn't write.
+
+const MAX_READ_DEVICES: usize = 10;
+const DEFAULT_READ_DEVICE: &str = "default";
+
+fn read_cursor_key(session_id: &str, user_id: Principal, device_id: &str) -> String {
+    format!("{}:{}:{}", session_id, user_id, device_id)
+}
+
+fn read_cursors(session_id: &str, user_id: Principal) -> Vec<ReadCursor> {
+    let start = format!("{}:{}:", session_id, user_id);
+    let end = format!("{}:{};", session_id, user_id);
+    READ_CURSORS.with(|cursors| cursors.borrow().range(start..end).map(|(_, c)| c).collect())
+}
+
+fn remove_session_read_cursors(session_id: &str) {
+    READ_CURSORS.with(|cursors| {
+        let mut cursors = cursors.borrow_mut();
+        let keys: Vec<String> = cursors.range(format!("{}:", session_id)..format!("{};", session_id)).map(|(key, _)| key).collect();
+        for key in keys {
+            cursors.remove(&key);
+        }
+    });
+}
+
+// Number of messages up to and including the cursor's message
+fn read_upto(messages: &[ChatMessage], cursor: &ReadCursor) -> usize {
+    messages.iter().position(|m| m.id == cursor.message_id).map(|index| index + 1)
+        .unwrap_or_else(|| messages.iter().filter(|m| m.timestamp <= cursor.message_timestamp).count())
+}
+
+fn unread_count(messages: &[ChatMessage], cursors: &[ReadCursor], user_id: Principal) -> u32 {
+    let read = cursors.iter().map(|c| read_upto(messages, c)).max().unwrap_or(0);
+    messages[read.min(messages.len())..].iter()
+        .filter(|m| m.delivery_status == "delivered")
+        .filter(|m| m.sender != "user" || m.author.is_some_and(|author| author != user_id))
+        .count() as u32
+}
+
+fn session_unread_count(session_id: &str, user_id: Principal) -> u32 {
+    let messages = CHAT_MESSAGES.with(|messages| messages.borrow().get(&session_id.to_string()).map(|list| list.0).unwrap_or_default());
+    unread_count(&messages, &read_cursors(session_id, user_id), user_id)
+}
+
+fn read_positions(session_id: &str, user_id: Principal) -> ReadPositions {
+    let messages = CHAT_MESSAGES.with(|messages| messages.borrow().get(&session_id.to_string()).map(|list| list.0).unwrap_or_default());
+    let mut cursors = read_cursors(session_id, user_id);
+    cursors.sort_by_key(|c| std::cmp::Reverse(c.updated_at));
+    ReadPositions {
+        resume_at: cursors.iter().max_by_key(|c| (read_upto(&messages, c), c.updated_at)).cloned(),
+        unread_count: unread_count(&messages, &cursors, user_id),
+        cursors,
+    }
+}
+
+// device_id can be left out by single-device clients
+#[ic_cdk::update]
+fn set_read_position(session_id: String, message_id: String, device_id: Option<String>) -> Result<ReadPositions, String> {
+    let caller = ic_cdk::caller();
+    cache::user(caller).ok_or("User not found")?;
+    visible_session(&session_id, caller)?;
+    let device_id = device_id.map(|d| d.trim().to_string()).filter(|d| !d.is_empty()).unwrap_or_else(|| DEFAULT_READ_DEVICE.to_string());
+    if device_id.chars().count() > 64 {
+        return Err("Device ids are limited to 64 characters".to_string());
+    }
+    let message = CHAT_MESSAGES.with(|messages| messages.borrow().get(&session_id))
+        .and_then(|list| list.0.into_iter().find(|m| m.id == message_id))
+        .ok_or("Message not found")?;
+    
+    let now = ic_cdk::api::time();
+    let mut cursors = read_cursors(&session_id, caller);
+    if !cursors.iter().any(|c| c.device_id == device_id) && cursors.len() >= MAX_READ_DEVICES {
+        // Forget the device that has gone longest without reading
+        cursors.sort_by_key(|c| c.updated_at);
+        let stale = read_cursor_key(&session_id, caller, &cursors[0].device_id);
+        READ_CURSORS.with(|c| c.borrow_mut().remove(&stale));
+    }
+    READ_CURSORS.with(|c| c.borrow_mut().insert(read_cursor_key(&session_id, caller, &device_id), ReadCursor {
+        session_id: session_id.clone(),
+        user_id: caller,
+        device_id,
+        message_id,
+        message_timestamp: message.timestamp,
+        updated_at: now,
+    }));
+    Ok(read_positions(&session_id, caller))
+}
+
+#[ic_cdk::query]
+fn get_read_positions(session_id: String) -> Result<ReadPositions, String> {
+    let caller = ic_cdk::caller();
+    visible_session(&session_id, caller)?;
+    Ok(read_positions(&session_id, caller))
+}
+
+// --- Session Tokens ---
+
+const AUTH_SESSION_TTL_NS: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
+const MAX_AUTH_SESSIONS_PER_USER: usize = 10;
+
+// Unknown, expired and other callers' tokens are all reported the same way
+fn validate_session(token: &str) -> Result<Principal, String> {
+    let caller = ic_cdk::caller();
+    let session = SESSIONS.with(|sessions| sessions.borrow().get(&token.to_string()))
+        .filter(|s| s.expires_at > ic_cdk::api::time() && s.issued_to == caller)
+        .ok_or("Session expired or invalid; please log in again")?;
+    Ok(session.user_id)
+}
+
+// For queries that take an optional session token: without one the caller acts as itself
+fn session_reader(session_token: Option<String>) -> Result<Principal, String> {
+    match session_token {
+        Some(token) => validate_session(&token),
+        None => Ok(ic_cdk::caller()),
+    }
+}
+
+// The same for updates, which are refused while the user is moving to another shard
+fn session_caller(session_token: Option<String>) -> Result<Principal, String> {
+    let caller = session_reader(session_token)?;
+    ensure_not_migrating(caller)?;
+    Ok(caller)
+}
+
+fn remove_auth_sessions(tokens: Vec<String>) {
+    SESSIONS.with(|sessions| {
+        let mut sessions = sessions.borrow_mut();
+        for token in tokens {
+            sessions.remove(&token);
+        }
+    });
+}
+
+fn remove_user_auth_sessions(user_id: Principal) {
+    let tokens = SESSIONS.with(|sessions| {
+        sessions.borrow().iter().filter(|(_, s)| s.user_id == user_id).map(|(token, _)| token).collect()
+    });
+    remove_auth_sessions(tokens);
+}
+
+fn prune_auth_sessions(now: u64) {
+    let expired = SESSIONS.with(|sessions| {
+        sessions.borrow().iter()
+            .filter(|(_, s)| s.expires_at <= now)
+            .map(|(token, _)| token)
+            .take(RETENTION_BATCH_SIZE)
+            .collect()
+    });
+    remove_auth_sessions(expired);
+}
+
+// Password accounts can't sign calls themselves, so the frontend logs in from its own session
+// key and passes the token to endpoints that act for the user
+#[ic_cdk::update]
+async fn login_user_session(email: String, password: String) -> Result<SessionLogin, String> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return Err("Log in from a session key; anonymous callers can't hold a session".to_string());
+    }
+    let user = password_login(email, password).await?;
+    issue_auth_session(user, caller).await
+}
+
+async fn issue_auth_session(user: User, caller: Principal) -> Result<SessionLogin, String> {
+    let token = format!("sess_{}", hex_encode(&random_bytes().await?));
+    let now = ic_cdk::api::time();
+    
+    let mut existing: Vec<AuthSession> = SESSIONS.with(|sessions| {
+        sessions.borrow().iter().map(|(_, s)| s).filter(|s| s.user_id == user.id).collect()
+    });
+    existing.sort_by_key(|s| s.created_at);
+    let excess = (existing.len() + 1).saturating_sub(MAX_AUTH_SESSIONS_PER_USER);
+    remove_auth_sessions(existing.into_iter().take(excess).map(|s| s.token).collect());
+    
+    let session = AuthSession {
+        token: token.clone(),
+        user_id: user.id,
+        issued_to: caller,
+        created_at: now,
+        expires_at: now + AUTH_SESSION_TTL_NS,
+    };
+    SESSIONS.with(|sessions| sessions.borrow_mut().insert(token.clone(), session.clone()));
+    Ok(SessionLogin { token, expires_at: session.expires_at, user })
+}
+
+#[ic_cdk::update]
+fn logout_session(token: String) -> Result<(), String> {
+    validate_session(&token)?;
+    remove_auth_sessions(vec![token]);
+    Ok(())
+}
+
+// --- Chat Signals ---
+
+// Clients resend while the user keeps typing, so a stopped client drops out on its own
+const COMPOSING_TTL_NS: u64 = 8 * 1_000_000_000;
+// Only reached if a delivery traps before clearing its own signal
+const GENERATING_TTL_NS: u64 = 5 * 60 * 1_000_000_000;
+
+#[derive(Default)]
+struct SessionSignals {
+    composing: HashMap<Principal, u64>, // participant -> expires at
+    generating: HashMap<String, u64>, // reply message id -> started at
+}
+
+thread_local! {
+    static CHAT_SIGNALS: RefCell<HashMap<String, SessionSignals>> = RefCell::new(HashMap::new());
+}
+
+fn update_session_signals(session_id: &str, change: impl FnOnce(&mut SessionSignals)) {
+    let now = ic_cdk::api::time();
+    CHAT_SIGNALS.with(|signals| {
+        let mut signals = signals.borrow_mut();
+        let entry = signals.entry(session_id.to_string()).or_default();
+        change(entry);
+        entry.composing.retain(|_, expires_at| *expires_at > now);
+        entry.generating.retain(|_, started| now.saturating_sub(*started) < GENERATING_TTL_NS);
+        if entry.composing.is_empty() && entry.generating.is_empty() {
+            signals.remove(session_id);
+        }
+    });
+}
+
+fn mark_generation_started(delivery: &PendingDelivery) {
+    let now = ic_cdk::api::time();
+    update_session_signals(&delivery.session_id, |s| {
+        s.generating.insert(delivery.message_id.clone(), now);
+    });
+}
+
+fn clear_generation(delivery: &PendingDelivery) {
+    update_session_signals(&delivery.session_id, |s| {
+        s.generating.remove(&delivery.message_id);
+    });
+}
+
+#[ic_cdk::update]
+fn signal_composing(session_id: String, composing: bool, session_token: Option<String>) -> Result<(), String> {
+    let caller = session_caller(session_token)?;
+    participant_session(&session_id, caller)?;
+    let expires_at = ic_cdk::api::time() + COMPOSING_TTL_NS;
+    update_session_signals(&session_id, |s| {
+        if composing {
+            s.composing.insert(caller, expires_at);
+        } else {
+            s.composing.remove(&caller);
+        }
+    });
+    Ok(())
+}
+
+// Reply states come from the session's pending deliveries, so an indicator only shows while
+// a reply is actually outstanding
+#[ic_cdk::query]
+fn get_chat_signals(session_id: String, session_token: Option<String>) -> Result<ChatSignals, String> {
+    let caller = session_reader(session_token)?;
+    visible_session(&session_id, caller)?;
+    let now = ic_cdk::api::time();
+    let (composing, generating) = CHAT_SIGNALS.with(|signals| {
+        signals.borrow().get(&session_id).map(|s| {
+            let composing: Vec<Principal> = s.composing.iter()
+                .filter(|(user_id, expires_at)| **user_id != caller && **expires_at > now)
+                .map(|(user_id, _)| *user_id)
+                .collect();
+            let generating: HashMap<String, u64> = s.generating.iter()
+                .filter(|(_, started)| now.saturating_sub(**started) < GENERATING_TTL_NS)
+                .map(|(id, started)| (id.clone(), *started))
+                .collect();
+            (composing, generating)
+        }).unwrap_or_default()
+    });
+    
+    let mut replies: Vec<ReplySignal> = PENDING_DELIVERIES.with(|deliveries| {
+        deliveries.borrow().iter().map(|(_, d)| d).filter(|d| d.session_id == session_id).filter_map(|d| {
+            let (status, since) = match (d.status.as_str(), generating.get(&d.message_id)) {
+                ("in_flight", Some(started)) => ("generating", *started),
+                ("queued", _) => ("queued", d.created_at),
+                ("deferred", _) => ("deferred", d.created_at),
+                ("failed", _) if d.next_retry_at.is_some() => ("retrying", d.created_at),
+                _ => return None,
+            };
+            Some(ReplySignal { message_id: d.message_id, status: status.to_string(), since })
+        }).collect()
+    });
+    replies.sort_by_key(|r| r.since);
+    
+    Ok(ChatSignals { session_id, composing, replies })
+}
+
+// --- Group Flashcard Decks ---
+
+const DECK_NAME_MAX_CHARS: usize = 100;
+const CARD_SIDE_MAX_CHARS: usize = 500;
+const MAX_DECK_CARDS: usize = 2_000;
+const DECK_STATS_HARDEST: usize = 10;
+const DAY_NS: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+fn schedule_key(deck_id: u64, user_id: Principal, card_id: u64) -> String {
+    format!("{:020}:{}:{:020}", deck_id, user_id, card_id)
+}
+
+fn deck_schedules(deck_id: u64) -> Vec<CardSchedule> {
+    CARD_SCHEDULES.with(|schedules| {
+        schedules.borrow().range(format!("{:020}:", deck_id)..format!("{:020};", deck_id)).map(|(_, s)| s).collect()
+    })
+}
+
+fn learner_schedules(deck_id: u64, user_id: Principal) -> HashMap<u64, CardSchedule> {
+    CARD_SCHEDULES.with(|schedules| {
+        schedules.borrow()
+            .range(format!("{:020}:{}:", deck_id, user_id)..format!("{:020}:{};", deck_id, user_id))
+            .map(|(_, s)| (s.card_id, s))
+            .collect()
+    })
+}
+
+fn deck_cards(deck_id: u64) -> Vec<DeckCard> {
+    DECK_CARDS.with(|cards| cards.borrow().iter().map(|(_, c)| c).filter(|c| c.deck_id == deck_id).collect())
+}
+
+fn group_deck(deck_id: u64, caller: Principal) -> Result<(FlashcardDeck, StudyGroup), String> {
+    let deck = FLASHCARD_DECKS.with(|decks| decks.borrow().get(&deck_id)).ok_or("Deck not found")?;
+    let group = visible_group(deck.group_id, caller).map_err(|_| "Deck not found".to_string())?;
+    Ok((deck, group))
+}
+
+fn card_text(text: &str, side: &str) -> Result<String, String> {
+    let text = text.trim();
+    if text.is_empty() || text.chars().count() > CARD_SIDE_MAX_CHARS {
+        return Err(format!("Card {} must be between 1 and {} characters", side, CARD_SIDE_MAX_CHARS));
+    }
+    Ok(text.to_string())
+}
+
+// Approved contributions count toward the member's standing in the group
+fn credit_group_contribution(group_id: u64, user_id: Principal, now: u64) {
+    GROUP_MEMBERSHIPS.with(|memberships| {
+        let mut memberships = memberships.borrow_mut();
+        let own = memberships.iter().find(|(_, m)| m.group_id == group_id && m.user_id == user_id && m.status == "active");
+        if let Some((id, mut membership)) = own {
+            membership.contributions += 1;
+            membership.last_active_at = Some(now);
+            memberships.insert(id, membership);
+        }
+    });
+}
+
+// SM-2: grades of 3 and up count as recalled and stretch the interval by the card's ease;
+// lower grades start the card over
+fn apply_review(schedule: &mut CardSchedule, grade: u8, now: u64) {
+    let miss = 5 - grade as i64;
+    let ease = schedule.ease as i64 + 100 - miss * (80 + miss * 20);
+    schedule.ease = ease.max(1_300) as u32;
+    if grade < 3 {
+        schedule.repetitions = 0;
+        schedule.interval_days = 1;
+        schedule.lapses += 1;
+    } else {
+        schedule.repetitions += 1;
+        schedule.interval_days = match schedule.repetitions {
+            1 => 1,
+            2 => 6,
+            _ => ((schedule.interval_days as u64 * schedule.ease as u64).div_ceil(1_000)) as u32,
+        };
+    }
+    schedule.reviews += 1;
+    schedule.last_grade = grade;
+    schedule.last_reviewed_at = now;
+    schedule.due_at = now + schedule.interval_days as u64 * DAY_NS;
+}
+
+#[ic_cdk::update]
+fn create_group_deck(group_id: u64, name: String, description: Option<String>) -> Result<FlashcardDeck, String> {
+    let caller = ic_cdk::caller();
+    let group = visible_group(group_id, caller)?;
+    if !can_manage_group(&group, caller) {
+        return Err("Only group admins and moderators can create decks".to_string());
+    }
+    let name = name.trim().to_string();
+    if name.is_empty() || name.chars().count() > DECK_NAME_MAX_CHARS {
+        return Err(format!("Deck name must be between 1 and {} characters", DECK_NAME_MAX_CHARS));
+    }
+    
+    let now = ic_cdk::api::time();
+    let deck = FlashcardDeck {
+        id: next_id("flashcard_deck"),
+        group_id,
+        name,
+        description: description.map(|d| d.trim().to_string()).filter(|d| !d.is_empty()),
+        created_by: caller,
+        created_at: now,
+        updated_at: now,
+    };
+    FLASHCARD_DECKS.with(|decks| decks.borrow_mut().insert(deck.id, deck.clone()));
+    Ok(deck)
+}
+
+#[ic_cdk::query]
+fn get_group_decks(group_id: u64) -> Result<Vec<FlashcardDeck>, String> {
+    visible_group(group_id, ic_cdk::caller())?;
+    let mut decks: Vec<FlashcardDeck> = FLASHCARD_DECKS.with(|decks| {
+        decks.borrow().iter().map(|(_, d)| d).filter(|d| d.group_id == group_id).collect()
+    });
+    decks.sort_by_key(|d| d.created_at);
+    Ok(decks)
+}
+
+// Cards from admins and moderators are approved straight away; other members' cards wait
+// for one of them to curate
+#[ic_cdk::update]
+fn add_deck_card(deck_id: u64, front: String, back: String) -> Result<DeckCard, String> {
+    let caller = ic_cdk::caller();
+    let (deck, group) = group_deck(deck_id, caller)?;
+    ensure_group_member(deck.group_id, caller)?;
+    let front = card_text(&front, "front")?;
+    let back = card_text(&back, "back")?;
+    let cards = deck_cards(deck_id);
+    if cards.iter().filter(|c| c.status != "removed" && c.status != "rejected").count() >= MAX_DECK_CARDS {
+        return Err(format!("A deck can hold up to {} cards", MAX_DECK_CARDS));
+    }
+    if cards.iter().any(|c| c.status == "approved" && c.front.eq_ignore_ascii_case(&front)) {
+        return Err("The deck already has a card with this front".to_string());
+    }
+    
+    let now = ic_cdk::api::time();
+    let curator = can_manage_group(&group, caller);
+    let card = DeckCard {
+        id: next_id("deck_card"),
+        deck_id,
+        group_id: deck.group_id,
+        front,
+        back,
+        contributed_by: caller,
+        status: if curator { "approved" } else { "proposed" }.to_string(),
+        curated_by: curator.then_some(caller),
+        created_at: now,
+        updated_at: now,
+    };
+    DECK_CARDS.with(|cards| cards.borrow_mut().insert(card.id, card.clone()));
+    if curator {
+        credit_group_contribution(deck.group_id, caller, now);
+    }
+    Ok(card)
+}
+
+// Group admins and moderators approve, reject or remove cards and may edit them while doing so
+#[ic_cdk::update]
+fn curate_deck_card(card_id: u64, status: String, front: Option<String>, back: Option<String>) -> Result<DeckCard, String> {
+    let caller = ic_cdk::caller();
+    let mut card = DECK_CARDS.with(|cards| cards.borrow().get(&card_id)).ok_or("Card not found")?;
+    let (_, group) = group_deck(card.deck_id, caller).map_err(|_| "Card not found".to_string())?;
+    if !can_manage_group(&group, caller) {
+        return Err("Only group admins and moderators can curate cards".to_string());
+    }
+    if !matches!(status.as_str(), "approved" | "rejected" | "removed") {
+        return Err("Status must be approved, rejected or removed".to_string());
+    }
+    
+    let now = ic_cdk::api::time();
+    let newly_approved = status == "approved" && card.status != "approved";
+    if let Some(front) = front {
+        card.front = card_text(&front, "front")?;
+    }
+    if let Some(back) = back {
+        card.back = card_text(&back, "back")?;
+    }
+    card.status = status;
+    card.curated_by = Some(caller);
+    card.updated_at = now;
+    DECK_CARDS.with(|cards| cards.borrow_mut().insert(card_id, card.clone()));
+    if newly_approved {
+        credit_group_contribution(card.group_id, card.contributed_by, now);
+    }
+    Ok(card)
+}
+
+// Members see approved cards and their own proposals; curators see everything but removed cards
+#[ic_cdk::query]
+fn get_deck_cards(deck_id: u64) -> Result<Vec<DeckCard>, String> {
+    let caller = ic_cdk::caller();
+    let (_, group) = group_deck(deck_id, caller)?;
+    let curator = can_manage_group(&group, caller);
+    let mut cards: Vec<DeckCard> = deck_cards(deck_id).into_iter()
+        .filter(|c| c.status == "approved" || (c.status != "removed" && (curator || c.contributed_by == caller)))
+        .collect();
+    cards.sort_by_key(|c| c.created_at);
+    Ok(cards)
+}
+
+// Overdue cards first, most overdue first, then cards the learner hasn't seen yet
+#[ic_cdk::query]
+fn get_due_deck_cards(deck_id: u64, limit: u32) -> Result<Vec<DueCard>, String> {
+    let caller = ic_cdk::caller();
+    let (deck, _) = group_deck(deck_id, caller)?;
+    ensure_group_member(deck.group_id, caller)?;
+    let now = ic_cdk::api::time();
+    let mut schedules = learner_schedules(deck_id, caller);
+    
+    let mut due: Vec<DueCard> = deck_cards(deck_id).into_iter()
+        .filter(|c| c.status == "approved")
+        .filter_map(|card| match schedules.remove(&card.id) {
+            Some(schedule) if schedule.due_at > now => None,
+            schedule => Some(DueCard { card, schedule }),
+        })
+        .collect();
+    due.sort_by_key(|d| (d.schedule.is_none(), d.schedule.as_ref().map(|s| s.due_at).unwrap_or(d.card.created_at)));
+    due.truncate(limit.clamp(1, 100) as usize);
+    Ok(due)
+}
+
+#[ic_cdk::update]
+fn grade_deck_card(card_id: u64, grade: u8) -> Result<CardSchedule, String> {
+    let caller = ic_cdk::caller();
+    if grade > 5 {
+        return Err("Grade must be between 0 and 5".to_string());
+    }
+    let card = DECK_CARDS.with(|cards| cards.borrow().get(&card_id))
+        .filter(|c| c.status == "approved")
+        .ok_or("Card not found")?;
+    let (deck, _) = group_deck(card.deck_id, caller).map_err(|_| "Card not found".to_string())?;
+    ensure_group_member(deck.group_id, caller)?;
+    
+    let now = ic_cdk::api::time();
+    let key = schedule_key(card.deck_id, caller, card_id);
+    let mut schedule = CARD_SCHEDULES.with(|schedules| schedules.borrow().get(&key)).unwrap_or(CardSchedule {
+        deck_id: card.deck_id,
+        card_id,
+        user_id: caller,
+        ease: 2_500,
+        interval_days: 0,
+        repetitions: 0,
+        reviews: 0,
+        lapses: 0,
+        last_grade: 0,
+        last_reviewed_at: 0,
+        due_at: now,
+    });
+    apply_review(&mut schedule, grade, now);
+    CARD_SCHEDULES.with(|schedules| schedules.borrow_mut().insert(key, schedule.clone()));
+    Ok(schedule)
+}
+
+fn deck_stats(deck: FlashcardDeck, active_members: &std::collections::HashSet<Principal>, now: u64) -> DeckStats {
+    let cards = deck_cards(deck.id);
+    let approved: HashMap<u64, &DeckCard> = cards.iter().filter(|c| c.status == "approved").map(|c| (c.id, c)).collect();
+    let schedules: Vec<CardSchedule> = deck_schedules(deck.id).into_iter()
+        .filter(|s| approved.contains_key(&s.card_id) && active_members.contains(&s.user_id))
+        .collect();
+    let learners: std::collections::HashSet<Principal> = schedules.iter().map(|s| s.user_id).collect();
+    let pairs = active_members.len() * approved.len();
+    
+    let mut per_card: HashMap<u64, Vec<&CardSchedule>> = HashMap::new();
+    for schedule in &schedules {
+        per_card.entry(schedule.card_id).or_default().push(schedule);
+    }
+    let mut hardest: Vec<HardCard> = per_card.into_iter().map(|(card_id, schedules)| {
+        let reviews: u32 = schedules.iter().map(|s| s.reviews).sum();
+        let lapses: u32 = schedules.iter().map(|s| s.lapses).sum();
+        HardCard {
+            card_id,
+            front: approved[&card_id].front.clone(),
+            learners: schedules.len() as u32,
+            reviews,
+            lapse_rate: if reviews == 0 { 0.0 } else { lapses as f32 / reviews as f32 },
+            average_ease: schedules.iter().map(|s| s.ease).sum::<u32>() / schedules.len() as u32,
+        }
+    }).collect();
+    hardest.sort_by(|a, b| b.lapse_rate.total_cmp(&a.lapse_rate).then(a.average_ease.cmp(&b.average_ease)));
+    hardest.truncate(DECK_STATS_HARDEST);
+    
+    DeckStats {
+        approved_cards: approved.len() as u32,
+        proposed_cards: cards.iter().filter(|c| c.status == "proposed").count() as u32,
+        learners: learners.len() as u32,
+        coverage: if pairs == 0 { 0.0 } else { schedules.len() as f32 / pairs as f32 },
+        due_now: schedules.iter().filter(|s| s.due_at <= now).count() as u32,
+        hardest,
+        deck,
+    }
+}
+
+// Deck panel of the group dashboard
+#[ic_cdk::query]
+fn get_group_deck_stats(group_id: u64) -> Result<Vec<DeckStats>, String> {
+    let caller = ic_cdk::caller();
+    visible_group(group_id, caller)?;
+    ensure_group_member(group_id, caller)?;
+    let active_members: std::collections::HashSet<Principal> = GROUP_MEMBERSHIPS.with(|memberships| {
+        memberships.borrow().iter().map(|(_, m)| m).filter(|m| m.group_id == group_id && m.status == "active").map(|m| m.user_id).collect()
+    });
+    let now = ic_cdk::api::time();
+    Ok(get_group_decks(group_id)?.into_iter().map(|deck| deck_stats(deck, &active_members, now)).collect())
+}
+
+// --- Seasonal Events ---
+
+const EVENT_JOB_INTERVAL_NS: u64 = 60 * 1_000_000_000;
+const MAX_EVENT_DURATION_NS: u64 = 90 * 24 * 60 * 60 * 1_000_000_000;
+const MAX_EVENT_MULTIPLIER_PERCENT: u32 = 1_000;
+
+fn participation_key(event_id: u64, user_id: Principal) -> String {
+    format!("{:020}:{}", event_id, user_id)
+}
+
+fn set_tasks_active(task_ids: &[u64], active: bool) {
+    TASKS.with(|tasks| {
+        let mut tasks = tasks.borrow_mut();
+        for id in task_ids {
+            if let Some(mut task) = tasks.get(id) {
+                task.is_active = active;
+                tasks.insert(*id, task);
+            }
+        }
+    });
+}
+
+fn save_event(event: &SeasonalEvent) {
+    SEASONAL_EVENTS.with(|events| events.borrow_mut().insert(event.id, event.clone()));
+}
+
+// Runs from the scheduled job, and right after an event is created so one starting now opens at once
+fn run_event_transitions(now: u64) {
+    let due: Vec<SeasonalEvent> = SEASONAL_EVENTS.with(|events| {
+        events.borrow().iter().map(|(_, e)| e)
+            .filter(|e| (e.status == "scheduled" && e.starts_at <= now) || (e.status == "active" && e.ends_at <= now))
+            .collect()
+    });
+    for mut event in due {
+        if event.ends_at <= now {
+            event.status = "ended".to_string();
+            set_tasks_active(&event.task_ids, false);
+        } else {
+            event.status = "active".to_string();
+            set_tasks_active(&event.task_ids, true);
+        }
+        save_event(&event);
+    }
+}
+
+fn active_events(now: u64) -> Vec<SeasonalEvent> {
+    SEASONAL_EVENTS.with(|events| {
+        events.borrow().iter().map(|(_, e)| e).filter(|e| e.status == "active" && e.starts_at <= now && now < e.ends_at).collect()
+    })
+}
+
+fn award_event_badge(event: &SeasonalEvent, achievement_id: u64, user_id: Principal, now: u64) {
+    let achievement = USER_ACHIEVEMENTS.with(|achievements| {
+        let mut achievements = achievements.borrow_mut();
+        if achievements.iter().any(|(_, a)| a.user_id == user_id && a.achievement_id == achievement_id) {
+            return None;
+        }
+        let achievement = UserAchievement {
+            id: next_id("user_achievement"),
+            user_id,
+            achievement_id,
+            progress: 100.0,
+            is_completed: true,
+            completed_at: Some(now),
+            tokens_earned: 0,
+            points_earned: 0,
+            created_at: now,
+            updated_at: now,
+        };
+        achievements.insert(achievement.id, achievement.clone());
+        Some(achievement)
+    });
+    if achievement.is_some() {
+        notify_user(user_id, "achievement", "event", format!("You earned the {} badge", event.name), Some(event.id));
+        publish_activity(user_id, "badge_earned", format!("earned the {} badge", event.name), Some(achievement_id.to_string()), None, None);
+    }
+}
+
+// Task points earned while events are running get the largest active multiplier and count
+// toward every running event's leaderboard. Returns the points to credit.
+fn score_event_points(user_id: Principal, base_points: u32, now: u64) -> u32 {
+    let events = active_events(now);
+    let multiplier = events.iter().map(|e| e.points_multiplier_percent).max().unwrap_or(100);
+    let points = (base_points as u64 * multiplier as u64 / 100).min(u32::MAX as u64) as u32;
+    
+    for event in events {
+        let key = participation_key(event.id, user_id);
+        let mut participation = EVENT_PARTICIPATION.with(|p| p.borrow().get(&key)).unwrap_or(EventParticipation {
+            event_id: event.id,
+            user_id,
+            points: 0,
+            tasks_completed: 0,
+            badge_earned_at: None,
+            last_scored_at: now,
+        });
+        participation.points = participation.points.saturating_add(points);
+        participation.tasks_completed += 1;
+        participation.last_scored_at = now;
+        if let Some(achievement_id) = event.badge_achievement_id {
+            if participation.badge_earned_at.is_none() && participation.points >= event.badge_min_points {
+                participation.badge_earned_at = Some(now);
+                award_event_badge(&event, achievement_id, user_id, now);
+            }
+        }
+        EVENT_PARTICIPATION.with(|p| p.borrow_mut().insert(key, participation));
+    }
+    points
+}
+
+#[ic_cdk::update]
+async fn create_seasonal_event_admin(spec: SeasonalEventSpec) -> Result<SeasonalEvent, String> {
+    let caller = ic_cdk::caller();
+    require(caller, Permission::ManageAnnouncements)?;
+    let SeasonalEventSpec { name, description, theme, starts_at, ends_at, points_multiplier_percent, task_ids, badge } = spec;
+    let name = name.trim().to_string();
+    if name.is_empty() || name.chars().count() > 100 {
+        return Err("Event name must be between 1 and 100 characters".to_string());
+    }
+    let now = ic_cdk::api::time();
+    if ends_at <= starts_at || ends_at <= now {
+        return Err("An event must end after it starts and in the future".to_string());
+    }
+    if ends_at - starts_at > MAX_EVENT_DURATION_NS {
+        return Err("Events can run for at most 90 days".to_string());
+    }
+    if !(100..=MAX_EVENT_MULTIPLIER_PERCENT).contains(&points_multiplier_percent) {
+        return Err(format!("Points multiplier must be between 100 and {} percent", MAX_EVENT_MULTIPLIER_PERCENT));
+    }
+    let mut unique = std::collections::HashSet::new();
+    for task_id in &task_ids {
+        if !unique.insert(*task_id) || !TASKS.with(|tasks| tasks.borrow().contains_key(task_id)) {
+            return Err(format!("Task {} not found or listed twice", task_id));
+        }
+    }
+    let taken = SEASONAL_EVENTS.with(|events| {
+        events.borrow().iter().map(|(_, e)| e)
+            .filter(|e| e.status == "scheduled" || e.status == "active")
+            .find_map(|e| e.task_ids.iter().find(|id| unique.contains(id)).map(|id| (*id, e.name)))
+    });
+    if let Some((task_id, other)) = taken {
+        return Err(format!("Task {} already belongs to the event \"{}\"", task_id, other));
+    }
+    
+    // The badge is a regular achievement so it shows with the user's other achievements
+    let badge_min_points = badge.as_ref().map(|b| b.min_points).unwrap_or(0);
+    let badge_achievement_id = match badge {
+        Some(badge) => {
+            let achievement = Achievement {
+                id: next_id("achievement"),
+                public_id: random_public_id("achievement").await?,
+                title: badge.title,
+                description: badge.description,
+                category: "event".to_string(),
+                icon: badge.icon,
+                requirements: format!("Earn {} points during {}", badge.min_points, name),
+                reward_tokens: 0,
+                reward_points: 0,
+                is_active: true,
+                created_at: now,
+                created_by: caller,
+            };
+            ACHIEVEMENTS.with(|achievements| achievements.borrow_mut().insert(achievement.id, achievement.clone()));
+            Some(achievement.id)
+        }
+        None => None,
+    };
+    
+    // Event tasks stay closed until the event starts
+    set_tasks_active(&task_ids, false);
+    TASKS.with(|tasks| {
+        let mut tasks = tasks.borrow_mut();
+        for id in &task_ids {
+            if let Some(mut task) = tasks.get(id) {
+                task.expires_at = Some(ends_at);
+                tasks.insert(*id, task);
+            }
+        }
+    });
+    let event = SeasonalEvent {
+        id: next_id("seasonal_event"),
+        name,
+        description,
+        theme,
+        starts_at,
+        ends_at,
+        points_multiplier_percent,
+        task_ids,
+        badge_achievement_id,
+        badge_min_points,
+        status: "scheduled".to_string(),
+        created_by: caller,
+        created_at: now,
+    };
+    save_event(&event);
+    run_event_transitions(now);
+    record_audit(caller, "seasonal_event_created", None, format!("event {} \"{}\"", event.id, event.name));
+    Ok(SEASONAL_EVENTS.with(|events| events.borrow().get(&event.id)).unwrap_or(event))
+}
+
+// Points and badges already earned are kept
+#[ic_cdk::update]
+fn cancel_seasonal_event_admin(event_id: u64) -> Result<SeasonalEvent, String> {
+    let caller = ic_cdk::caller();
+    require(caller, Permission::ManageAnnouncements)?;
+    let mut event = SEASONAL_EVENTS.with(|events| events.borrow().get(&event_id)).ok_or("Event not found")?;
+    if event.status == "ended" || event.status == "cancelled" {
+        return Err(format!("Event is already {}", event.status));
+    }
+    event.status = "cancelled".to_string();
+    set_tasks_active(&event.task_ids, false);
+    save_event(&event);
+    record_audit(caller, "seasonal_event_cancelled", None, format!("event {} \"{}\"", event.id, event.name));
+    Ok(event)
+}
+
+#[ic_cdk::query]
+fn get_seasonal_events(include_ended: bool) -> Vec<SeasonalEvent> {
+    let mut events: Vec<SeasonalEvent> = SEASONAL_EVENTS.with(|events| {
+        events.borrow().iter().map(|(_, e)| e)
+            .filter(|e| e.status == "scheduled" || e.status == "active" || (include_ended && e.status == "ended"))
+            .collect()
+    });
+    events.sort_by_key(|e| e.starts_at);
+    events
+}
+
+fn leaderboard_entry(rank: u32, participation: &EventParticipation) -> EventLeaderboardEntry {
+    let user = cache::user(participation.user_id);
+    let public = user.as_ref().is_some_and(|u| u.settings.profile_visibility != "private");
+    EventLeaderboardEntry {
+        rank,
+        user_id: public.then_some(participation.user_id),
+        username: user.filter(|_| public).map(|u| u.username).unwrap_or_else(|| "Anonymous learner".to_string()),
+        points: participation.points,
+        tasks_completed: participation.tasks_completed,
+        badge_earned: participation.badge_earned_at.is_some(),
+    }
+}
+
+// Ties go to whoever reached the score first
+#[ic_cdk::query]
+fn get_event_leaderboard(event_id: u64, limit: u32) -> Result<EventLeaderboard, String> {
+    let caller = ic_cdk::caller();
+    let event = SEASONAL_EVENTS.with(|events| events.borrow().get(&event_id))
+        .filter(|e| e.status != "cancelled")
+        .ok_or("Event not found")?;
+    let mut standings: Vec<EventParticipation> = EVENT_PARTICIPATION.with(|p| {
+        p.borrow().range(format!("{:020}:", event_id)..format!("{:020};", event_id)).map(|(_, p)| p).collect()
+    });
+    standings.sort_by_key(|p| (std::cmp::Reverse(p.points), p.last_scored_at));
+    
+    let own = standings.iter().position(|p| p.user_id == caller).map(|i| leaderboard_entry(i as u32 + 1, &standings[i]));
+    let entries = standings.iter().take(limit.clamp(1, 100) as usize).enumerate()
+        .map(|(i, p)| leaderboard_entry(i as u32 + 1, p))
+        .collect();
+    Ok(EventLeaderboard { event, entries, own })
+}
+
+// --- Review Reminders ---
+//
+// Turns skills flagged by the decay job into reminder notifications, within each user's quiet
+// hours and the configured frequency cap. A reminder's deep link opens a review quiz on the skill.
+
+const REVIEW_REMINDER_JOB_INTERVAL_NS: u64 = 60 * 60 * 1_000_000_000;
+const REVIEW_REMINDER_TTL_NS: u64 = 30 * NANOS_PER_DAY;
+const REVIEW_REMINDER_BATCH: usize = 500;
+const NANOS_PER_HOUR: u64 = 60 * 60 * 1_000_000_000;
+
+fn reminder_key(user_id: Principal, id: u64) -> String {
+    format!("{}:{:020}", user_id, id)
+}
+
+fn user_reminders(user_id: Principal) -> Vec<ReviewReminder> {
+    REVIEW_REMINDERS.with(|reminders| {
+        reminders.borrow().range(format!("{}:", user_id)..format!("{};", user_id)).map(|(_, r)| r).collect()
+    })
+}
+
+fn in_quiet_hours(user: &User, now: u64) -> bool {
+    let Some(quiet) = user.settings.quiet_hours else {
+        return false;
+    };
+    let local = now.saturating_add_signed(user_offset_ns(user.id));
+    quiet.contains((local % NANOS_PER_DAY / NANOS_PER_HOUR) as u8)
+}
+
+// The weakest due skill that hasn't had a reminder since it was last assessed, if the user
+// can be reminded now
+fn reminder_candidate(user: &User, skills: &[SkillProficiency], config: &ReviewReminderConfig, now: u64) -> Option<SkillProficiency> {
+    if user.settings.review_reminders_muted || in_quiet_hours(user, now) || focus_ends_at(user.id, now).is_some() {
+        return None;
+    }
+    let sent = user_reminders(user.id);
+    let this_week = sent.iter().filter(|r| now.saturating_sub(r.created_at) < 7 * NANOS_PER_DAY).count();
+    let last_sent = sent.iter().map(|r| r.created_at).max().unwrap_or(0);
+    if this_week >= config.max_per_week as usize || now.saturating_sub(last_sent) < config.min_gap_hours as u64 * NANOS_PER_HOUR {
+        return None;
+    }
+    skills.iter()
+        .filter(|p| !sent.iter().any(|r| r.skill.eq_ignore_ascii_case(&p.skill) && r.created_at >= p.last_assessed_at))
+        .min_by(|a, b| a.score.total_cmp(&b.score))
+        .cloned()
+}
+
+fn send_review_reminders(now: u64) {
+    prune_review_reminders(now);
+    let config = get_config().review_reminders;
+    if !config.enabled {
+        return;
+    }
+    let mut due: HashMap<Principal, Vec<SkillProficiency>> = HashMap::new();
+    SKILL_PROFICIENCY.with(|skills| {
+        for (_, proficiency) in skills.borrow().iter().filter(|(_, p)| p.due_for_review) {
+            due.entry(proficiency.user_id).or_default().push(proficiency);
+        }
+    });
+    
+    for (user_id, skills) in due.into_iter().take(REVIEW_REMINDER_BATCH) {
+        let Some(user) = cache::user(user_id) else {
+            continue;
+        };
+        let Some(skill) = reminder_candidate(&user, &skills, &config, now) else {
+            continue;
+        };
+        let id = next_id("review_reminder");
+        let days = (now.saturating_sub(skill.last_assessed_at) / NANOS_PER_DAY) as u32;
+        let reminder = ReviewReminder {
+            id,
+            user_id,
+            skill: skill.skill.clone(),
+            score: decayed_score(&skill, now),
+            days_since_assessed: days,
+            deep_link: format!("/review/{}", id),
+            created_at: now,
+            opened_at: None,
+        };
+        REVIEW_REMINDERS.with(|reminders| reminders.borrow_mut().insert(reminder_key(user_id, id), reminder));
+        notify_user(
+            user_id,
+            "review_due",
+            "mastery",
+            format!(
+                "You learned {} {} day{} ago — {}-minute refresher?",
+                skill.skill,
+                days,
+                if days == 1 { "" } else { "s" },
+                config.refresher_minutes
+            ),
+            Some(id),
+        );
+    }
+}
+
+fn prune_review_reminders(now: u64) {
+    let expired: Vec<String> = REVIEW_REMINDERS.with(|reminders| {
+        reminders.borrow().iter()
+            .filter(|(_, r)| now.saturating_sub(r.created_at) > REVIEW_REMINDER_TTL_NS)
+            .map(|(key, _)| key)
+            .take(RETENTION_BATCH_SIZE)
+            .collect()
+    });
+    REVIEW_REMINDERS.with(|reminders| {
+        let mut reminders = reminders.borrow_mut();
+        for key in expired {
+            reminders.remove(&key);
+        }
+    });
+}
+
+// Target of a reminder's deep link
+#[ic_cdk::update]
+async fn open_review_reminder(reminder_id: u64) -> Result<ReviewQuiz, String> {
+    let caller = ic_cdk::caller();
+    rate_limit::check(caller, "generation")?;
+    let key = reminder_key(caller, reminder_id);
+    let mut reminder = REVIEW_REMINDERS.with(|reminders| reminders.borrow().get(&key)).ok_or("Reminder not found")?;
+    if reminder.opened_at.is_none() {
+        reminder.opened_at = Some(ic_cdk::api::time());
+        REVIEW_REMINDERS.with(|reminders| reminders.borrow_mut().insert(key, reminder.clone()));
+    }
+    build_review_quiz(caller, &reminder.skill).await
+}
+
+#[ic_cdk::query]
+fn get_my_review_reminders() -> Vec<ReviewReminder> {
+    let mut reminders = user_reminders(ic_cdk::caller());
+    reminders.sort_by_key(|r| std::cmp::Reverse(r.created_at));
+    reminders
+}
+
+#[ic_cdk::update]
+fn set_review_reminder_preferences(quiet_hours: Option<QuietHours>, muted: bool) -> Result<User, String> {
+    let caller = ic_cdk::caller();
+    if quiet_hours.is_some_and(|q| q.start_hour > 23 || q.end_hour > 23) {
+        return Err("Quiet hours must be between 0 and 23".to_string());
+    }
+    let mut user = cache::user(caller).ok_or("User not found")?;
+    user.settings.quiet_hours = quiet_hours;
+    user.settings.review_reminders_muted = muted;
+    user.updated_at = ic_cdk::api::time();
+    cache::store_user(user.clone());
+    Ok(user)
+}
+
+#[ic_cdk::update]
+fn set_review_reminder_config_admin(review_reminders: ReviewReminderConfig) -> Result<CanisterConfig, String> {
+    let caller = ic_cdk::caller();
+    require(caller, Permission::ManageSystem)?;
+    if review_reminders.max_per_week == 0 || review_reminders.refresher_minutes == 0 {
+        return Err("Reminders per week and refresher minutes must be at least 1; disable reminders instead".to_string());
+    }
+    
+    let details = format!(
+        "enabled={} max_per_week={} min_gap_hours={}",
+        review_reminders.enabled, review_reminders.max_per_week, review_reminders.min_gap_hours
+    );
+    let config = update_config(|config| {
+        config.review_reminders = review_reminders;
+        Ok(())
+    })?;
+    record_audit(caller, "set_review_reminder_config", None, details);
+    Ok(config)
+}
+
+// --- OAuth Accounts ---
+//
+// A verified provider identity is bound to one user through OAUTH_IDENTITIES and mirrored in
+// User.oauth_provider/oauth_id. Every replica makes the verification outcall, so the token is
+// seen by each node; the transform reduces responses to the claims checked here.
+
+const OAUTH_PROVIDERS: [&str; 2] = ["google", "github"];
+const GOOGLE_ISSUERS: [&str; 2] = ["accounts.google.com", "https://accounts.google.com"];
+
+struct VerifiedIdentity {
+    provider: String,
+    subject: String,
+    email: Option<String>,
+}
+
+fn oauth_identity_key(provider: &str, subject: &str) -> String {
+    format!("{}:{}", provider, subject)
+}
+
+fn base64_encode(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::new();
+    for chunk in bytes.chunks(3) {
+        let n = (chunk[0] as u32) << 16 | (*chunk.get(1).unwrap_or(&0) as u32) << 8 | *chunk.get(2).unwrap_or(&0) as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+// Keeps only the claims the canister checks, in one shape for both providers, so replicas
+// agree regardless of headers, field order or fields that vary between requests
+#[ic_cdk::query]
+fn transform_oauth_response(args: TransformArgs) -> ic_cdk::api::management_canister::http_request::HttpResponse {
+    let parsed: serde_json::Value = serde_json::from_slice(&args.response.body).unwrap_or_default();
+    let claims = match args.context.as_slice() {
+        b"github" => json!({
+            "sub": parsed["user"]["id"].as_u64().map(|id| id.to_string()),
+            "aud": parsed["app"]["client_id"],
+            "email": parsed["user"]["email"],
+        }),
+        _ => json!({
+            "sub": parsed["sub"],
+            "aud": parsed["aud"],
+            "iss": parsed["iss"],
+            "exp": parsed["exp"],
+            "email": parsed["email"],
+            "email_verified": parsed["email_verified"],
+        }),
+    };
+    ic_cdk::api::management_canister::http_request::HttpResponse {
+        status: args.response.status,
+        headers: Vec::new(),
+        body: claims.to_string().into_bytes(),
+    }
+}
+
+async fn verify_oauth_token(provider: &str, token: &str) -> Result<VerifiedIdentity, String> {
+    let config = get_config();
+    let oauth = &config.oauth;
+    if token.is_empty() || token.len() > 4096 || !token.chars().all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c)) {
+        return Err("Invalid token".to_string());
+    }
+    let (client_id, request) = match provider {
+        "google" => (&oauth.google_client_id, CanisterHttpRequestArgument {
+            url: format!("{}?id_token={}", oauth.google_tokeninfo_url, token),
+            max_response_bytes: Some(8 * 1024),
+            method: HttpMethod::GET,
+            headers: Vec::new(),
+            body: None,
+            transform: Some(TransformContext::from_name("transform_oauth_response".to_string(), b"google".to_vec())),
+        }),
+        "github" => (&oauth.github_client_id, CanisterHttpRequestArgument {
+            url: format!("{}/applications/{}/token", oauth.github_api_url, oauth.github_client_id),
+            max_response_bytes: Some(16 * 1024),
+            method: HttpMethod::POST,
+            headers: vec![
+                HttpHeader { name: "Accept".to_string(), value: "application/vnd.github+json".to_string() },
+                HttpHeader { name: "User-Agent".to_string(), value: "cogni-icp-backend".to_string() },
+                HttpHeader {
+                    name: "Authorization".to_string(),
+                    value: format!("Basic {}", base64_encode(format!("{}:{}", oauth.github_client_id, oauth.github_client_secret).as_bytes())),
+                },
+            ],
+            body: Some(json!({ "access_token": token }).to_string().into_bytes()),
+            transform: Some(TransformContext::from_name("transform_oauth_response".to_string(), b"github".to_vec())),
+        }),
+        _ => return Err(format!("Provider must be one of: {}", OAUTH_PROVIDERS.join(", "))),
+    };
+    if client_id.is_empty() {
+        return Err(format!("{} sign-in isn't configured", provider));
+    }
+    
+    let budget = outcall_budget(&config, "oauth");
+    let (response,) = ic_cdk::api::management_canister::http_request::http_request(request, budget.cycles as u128)
+        .await
+        .map_err(|(code, msg)| format!("HTTP outcall failed: {:?} - {}", code, msg))?;
+    let status: u32 = response.status.0.try_into().unwrap_or(0);
+    if !(200..300).contains(&status) {
+        return Err("The provider rejected the token".to_string());
+    }
+    let claims: serde_json::Value = serde_json::from_slice(&response.body).map_err(|_| "Invalid provider response".to_string())?;
+    
+    if claims["aud"].as_str() != Some(client_id.as_str()) {
+        return Err("The token was issued to a different application".to_string());
+    }
+    if provider == "google" {
+        if !GOOGLE_ISSUERS.contains(&claims["iss"].as_str().unwrap_or_default()) {
+            return Err("The token has an unexpected issuer".to_string());
+        }
+        let expires = claims["exp"].as_str().and_then(|e| e.parse::<u64>().ok()).unwrap_or(0);
+        if expires <= ic_cdk::api::time() / 1_000_000_000 {
+            return Err("The token has expired".to_string());
+        }
+    }
+    let subject = claims["sub"].as_str().filter(|s| !s.is_empty()).ok_or("The provider response had no account id")?;
+    // GitHub doesn't say whether the address is verified, so only Google's is used
+    let email_verified = claims["email_verified"].as_str() == Some("true") || claims["email_verified"].as_bool() == Some(true);
+    Ok(VerifiedIdentity {
+        provider: provider.to_string(),
+        subject: subject.to_string(),
+        email: claims["email"].as_str().filter(|_| email_verified).map(|e| e.to_string()),
+    })
+}
+
+fn oauth_user(provider: &str, subject: &str) -> Option<Principal> {
+    OAUTH_IDENTITIES.with(|identities| identities.borrow().get(&oauth_identity_key(provider, subject)))
+}
+
+fn remove_user_oauth_identity(user: &User) {
+    if let (Some(provider), Some(subject)) = (&user.oauth_provider, &user.oauth_id) {
+        let key = oauth_identity_key(provider, subject);
+        OAUTH_IDENTITIES.with(|identities| {
+            let mut identities = identities.borrow_mut();
+            if identities.get(&key) == Some(user.id) {
+                identities.remove(&key);
+            }
+        });
+    }
+}
+
+fn index_user_oauth_identity(user: &User) {
+    if let (Some(provider), Some(subject)) = (&user.oauth_provider, &user.oauth_id) {
+        if OAUTH_PROVIDERS.contains(&provider.as_str()) {
+            OAUTH_IDENTITIES.with(|identities| identities.borrow_mut().insert(oauth_identity_key(provider, subject), user.id));
+        }
+    }
+}
+
+// Password accounts pass their session token. A user holds one linked identity at a time;
+// linking another replaces it.
+#[ic_cdk::update]
+async fn link_oauth_account(provider: String, id_token: String, session_token: Option<String>) -> Result<User, String> {
+    let caller = session_caller(session_token)?;
+    cache::user(caller).ok_or("User not found")?;
+    let provider = provider.trim().to_lowercase();
+    let identity = verify_oauth_token(&provider, id_token.trim()).await?;
+    
+    // Re-read after the outcall
+    let mut user = cache::user(caller).ok_or("User not found")?;
+    match oauth_user(&identity.provider, &identity.subject) {
+        Some(owner) if owner != caller => return Err("This account is already linked to another user".to_string()),
+        _ => {}
+    }
+    remove_user_oauth_identity(&user);
+    user.oauth_provider = Some(identity.provider);
+    user.oauth_id = Some(identity.subject);
+    if !user.is_verified && identity.email.as_deref().is_some_and(|e| e.eq_ignore_ascii_case(&user.email)) {
+        user.is_verified = true;
+    }
+    user.updated_at = ic_cdk::api::time();
+    index_user_oauth_identity(&user);
+    cache::store_user(user.clone());
+    record_audit(caller, "oauth_linked", Some(caller), user.oauth_provider.clone().unwrap_or_default());
+    Ok(user)
+}
+
+#[ic_cdk::update]
+fn unlink_oauth_account(session_token: Option<String>) -> Result<User, String> {
+    let caller = session_caller(session_token)?;
+    let mut user = cache::user(caller).ok_or("User not found")?;
+    if user.oauth_id.is_none() {
+        return Err("No account is linked".to_string());
+    }
+    if user.password_hash.is_none() {
+        return Err("Set a password before unlinking your only sign-in method".to_string());
+    }
+    remove_user_oauth_identity(&user);
+    let provider = user.oauth_provider.take().unwrap_or_default();
+    user.oauth_id = None;
+    user.updated_at = ic_cdk::api::time();
+    cache::store_user(user.clone());
+    record_audit(caller, "oauth_unlinked", Some(caller), provider);
+    Ok(user)
+}
+
+// Signs in an account that has linked this provider identity; returns a session token like
+// login_user_session
+#[ic_cdk::update]
+async fn login_with_oauth(provider: String, id_token: String) -> Result<SessionLogin, String> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return Err("Log in from a session key; anonymous callers can't hold a session".to_string());
+    }
+    let provider = provider.trim().to_lowercase();
+    let identity = verify_oauth_token(&provider, id_token.trim()).await?;
+    let user_id = oauth_user(&identity.provider, &identity.subject).ok_or("No account is linked to this sign-in; link it from your account first")?;
+    let mut user = cache::user(user_id).filter(|u| u.is_active).ok_or("User not found")?;
+    user.last_login = Some(ic_cdk::api::time());
+    user.last_active = ic_cdk::api::time();
+    cache::store_user(user.clone());
+    issue_auth_session(user, caller).await
+}
+
+#[ic_cdk::update]
+fn set_oauth_config_admin(oauth: OAuthConfig) -> Result<CanisterConfig, String> {
+    let caller = ic_cdk::caller();
+    require(caller, Permission::ManageSystem)?;
+    if !oauth.google_tokeninfo_url.starts_with("https://") || !oauth.github_api_url.starts_with("https://") {
+        return Err("OAuth endpoints must use https".to_string());
+    }
+    
+    let updated = update_config(|config| {
+        // Credentials come back redacted from get_config_admin, so an empty value keeps the stored one
+        let mut oauth = oauth;
+        if oauth.github_client_secret.is_empty() {
+            oauth.github_client_secret = config.oauth.github_client_secret.clone();
+        }
+        config.oauth = oauth;
+        Ok(())
+    })?;
+    record_audit(
+        caller,
+        "set_oauth_config",
+        None,
+        format!("google: {}, github: {}", !updated.oauth.google_client_id.is_empty(), !updated.oauth.github_client_id.is_empty()),
+    );
+    Ok(updated)
+}
+
+// --- Notification Limits ---
+//
+// notify_user asks admit_notification whether a notification goes out now. Each user gets at
+// most their daily limit per source (local day); anything over it, or everything for
+// digest-only users, is held and summarized once a day at the configured local hour. Pushes
+// are queued for the relay, which pulls them with claim_push_notifications; during quiet
+// hours they wait until the quiet hours end. Exempt sources skip all of this.
+
+const NOTIFICATION_DIGEST_JOB_INTERVAL_NS: u64 = 60 * 60 * 1_000_000_000;
+const MAX_DIGEST_ITEMS: usize = 50;
+const DIGEST_PREVIEW_ITEMS: usize = 5;
+const NOTIFICATION_DIGEST_BATCH: usize = 500;
+// A push the relay hasn't claimed by then is no longer timely
+const PUSH_TTL_NS: u64 = NANOS_PER_DAY;
+const MAX_CATEGORY_LIMITS: usize = 30;
+
+fn notification_ledger(user_id: Principal, day: u64) -> NotificationLedger {
+    let mut ledger = NOTIFICATION_LEDGERS.with(|ledgers| ledgers.borrow().get(&user_id)).unwrap_or(NotificationLedger {
+        user_id,
+        day,
+        delivered: Vec::new(),
+        pending: Vec::new(),
+        overflow: 0,
+        last_digest_day: 0,
+    });
+    if ledger.day != day {
+        ledger.day = day;
+        ledger.delivered.clear();
+    }
+    ledger
+}
+
+// Returns false when the notification was held for the user's digest instead
+fn admit_notification(user_id: Principal, item: DigestItem) -> bool {
+    let config = get_config().notifications;
+    if config.exempt_sources.contains(&item.source) {
+        return true;
+    }
+    let Some(user) = cache::user(user_id) else {
+        return true;
+    };
+    let preferences = &user.settings.notification_preferences;
+    let mut ledger = notification_ledger(user_id, local_day(item.timestamp, user_offset_ns(user_id)));
+    // The default of 0 means no limit, but a user's own 0 holds the whole source
+    let limit = preferences.limit_for(&item.source).or(Some(config.default_daily_limit).filter(|l| *l > 0));
+    let delivered = ledger.delivered.iter().find(|c| c.source == item.source).map(|c| c.count).unwrap_or(0);
+    
+    let admitted = !preferences.digest_only && limit.is_none_or(|limit| delivered < limit);
+    if admitted {
+        match ledger.delivered.iter_mut().find(|c| c.source == item.source) {
+            Some(count) => count.count += 1,
+            None => ledger.delivered.push(SourceCount { source: item.source.clone(), count: 1 }),
+        }
+    } else if ledger.pending.len() < MAX_DIGEST_ITEMS {
+        ledger.pending.push(item);
+    } else {
+        ledger.overflow += 1;
+    }
+    NOTIFICATION_LEDGERS.with(|ledgers| ledgers.borrow_mut().insert(user_id, ledger));
+    admitted
+}
+
+fn push_key(due: u64, notification_id: u64) -> String {
+    format!("{:020}:{:020}", due, notification_id)
+}
+
+// When quiet hours end, if the user is in them
+fn quiet_hours_end(user: &User, now: u64) -> Option<u64> {
+    if !in_quiet_hours(user, now) {
+        return None;
+    }
+    let quiet = user.settings.quiet_hours?;
+    let local = now.saturating_add_signed(user_offset_ns(user.id));
+    let hour = local % NANOS_PER_DAY / NANOS_PER_HOUR;
+    let hours_left = (quiet.end_hour as u64 + 24 - hour) % 24;
+    Some(now - local % NANOS_PER_HOUR + hours_left * NANOS_PER_HOUR)
+}
+
+fn queue_push(user_id: Principal, notification_id: u64, now: u64) {
+    if !get_config().notifications.push_enabled {
+        return;
+    }
+    let quiet_until = cache::user(user_id).and_then(|user| quiet_hours_end(&user, now));
+    let due = quiet_until.into_iter().chain(focus_ends_at(user_id, now)).max().unwrap_or(now);
+    PUSH_QUEUE.with(|queue| queue.borrow_mut().insert(push_key(due, notification_id), user_id));
+}
+
+fn store_notification(notification: Notification) {
+    let (user_id, id, timestamp) = (notification.user_id, notification.id, notification.timestamp);
+    NOTIFICATIONS.with(|notifications| {
+        notifications.borrow_mut().insert(id, notification);
+    });
+    queue_push(user_id, id, timestamp);
+}
+
+fn digest_content(ledger: &NotificationLedger) -> String {
+    let mut counts: Vec<SourceCount> = Vec::new();
+    for item in &ledger.pending {
+        match counts.iter_mut().find(|c| c.source == item.source) {
+            Some(count) => count.count += 1,
+            None => counts.push(SourceCount { source: item.source.clone(), count: 1 }),
+        }
+    }
+    let total = ledger.pending.len() as u32 + ledger.overflow;
+    let mut content = format!(
+        "Your daily digest: {} update{} ({})",
+        total,
+        if total == 1 { "" } else { "s" },
+        counts.iter().map(|c| format!("{} {}", c.count, c.source.replace('_', " "))).collect::<Vec<_>>().join(", ")
+    );
+    for item in ledger.pending.iter().rev().take(DIGEST_PREVIEW_ITEMS) {
+        content.push_str("\n- ");
+        content.push_str(&trim_to_length(&item.content, 140));
+    }
+    if ledger.overflow > 0 {
+        content.push_str(&format!("\n…and {} more", ledger.overflow));
+    }
+    content
+}
+
+fn send_notification_digests(now: u64) {
+    let config = get_config().notifications;
+    let ready: Vec<NotificationLedger> = NOTIFICATION_LEDGERS.with(|ledgers| {
+        ledgers.borrow().iter().map(|(_, l)| l).filter(|l| !l.pending.is_empty()).take(NOTIFICATION_DIGEST_BATCH).collect()
+    });
+    for mut ledger in ready {
+        let Some(user) = cache::user(ledger.user_id) else {
+            NOTIFICATION_LEDGERS.with(|ledgers| ledgers.borrow_mut().remove(&ledger.user_id));
+            continue;
+        };
+        let offset = user_offset_ns(user.id);
+        let today = local_day(now, offset);
+        let hour = (now.saturating_add_signed(offset) % NANOS_PER_DAY / NANOS_PER_HOUR) as u8;
+        if ledger.last_digest_day >= today || hour < config.digest_hour || in_quiet_hours(&user, now) {
+            continue;
+        }
+        store_notification(Notification {
+            id: next_id("notification"),
+            user_id: user.id,
+            notification_type: "digest".to_string(),
+            content: digest_content(&ledger),
+            is_read: false,
+            source: "digest".to_string(),
+            related_id: None,
+            timestamp: now,
+        });
+        ledger.pending.clear();
+        ledger.overflow = 0;
+        ledger.last_digest_day = today;
+        NOTIFICATION_LEDGERS.with(|ledgers| ledgers.borrow_mut().insert(ledger.user_id, ledger));
+    }
+}
+
+// Called by the push relay. Claimed pushes leave the queue, so each is handed out once;
+// ones that landed in newly set quiet hours are put back for later.
+#[ic_cdk::update]
+fn claim_push_notifications() -> Result<Vec<Notification>, String> {
+    let config = get_config().notifications;
+    if !config.push_relay_principals.contains(&ic_cdk::caller()) {
+        return Err("Only the push relay can claim notifications".to_string());
+    }
+    let now = ic_cdk::api::time();
+    let due: Vec<(String, Principal)> = PUSH_QUEUE.with(|queue| {
+        queue.borrow().range(..format!("{:020};", now)).take(config.push_batch_size.max(1) as usize).collect()
+    });
+    
+    let mut claimed = Vec::new();
+    for (key, user_id) in due {
+        PUSH_QUEUE.with(|queue| queue.borrow_mut().remove(&key));
+        let Some(notification_id) = key.split(':').nth(1).and_then(|id| id.parse::<u64>().ok()) else {
+            continue;
+        };
+        let Some(notification) = NOTIFICATIONS.with(|notifications| notifications.borrow().get(&notification_id)) else {
+            continue;
+        };
+        if notification.is_read || now.saturating_sub(notification.timestamp) > PUSH_TTL_NS {
+            continue;
+        }
+        if let Some(later) = cache::user(user_id).and_then(|user| quiet_hours_end(&user, now)) {
+            PUSH_QUEUE.with(|queue| queue.borrow_mut().insert(push_key(later, notification_id), user_id));
+            continue;
+        }
+        claimed.push(notification);
+    }
+    Ok(claimed)
+}
+
+#[ic_cdk::update]
+fn set_notification_preferences(quiet_hours: Option<QuietHours>, preferences: NotificationPreferences) -> Result<User, String> {
+    let caller = ic_cdk::caller();
+    if quiet_hours.is_some_and(|q| q.start_hour > 23 || q.end_hour > 23) {
+        return Err("Quiet hours must be between 0 and 23".to_string());
+    }
+    if preferences.category_limits.len() > MAX_CATEGORY_LIMITS {
+        return Err(format!("At most {} category limits can be set", MAX_CATEGORY_LIMITS));
+    }
+    let mut preferences = preferences;
+    let mut seen = std::collections::HashSet::new();
+    for limit in preferences.category_limits.iter_mut() {
+        limit.category = limit.category.trim().to_lowercase();
+        if limit.category.is_empty() || !seen.insert(limit.category.clone()) {
+            return Err("Each category limit needs a distinct category".to_string());
+        }
+    }
+    
+    let mut user = cache::user(caller).ok_or("User not found")?;
+    user.settings.quiet_hours = quiet_hours;
+    user.settings.notification_preferences = preferences;
+    user.updated_at = ic_cdk::api::time();
+    cache::store_user(user.clone());
+    Ok(user)
+}
+
+// What the next digest will contain
+#[ic_cdk::query]
+fn get_pending_notification_digest() -> Vec<DigestItem> {
+    let caller = ic_cdk::caller();
+    NOTIFICATION_LEDGERS.with(|ledgers| ledgers.borrow().get(&caller)).map(|l| l.pending).unwrap_or_default()
+}
+
+#[ic_cdk::update]
+fn set_notification_config_admin(notifications: NotificationConfig) -> Result<CanisterConfig, String> {
+    let caller = ic_cdk::caller();
+    require(caller, Permission::ManageSystem)?;
+    if notifications.digest_hour > 23 {
+        return Err("Digest hour must be between 0 and 23".to_string());
+    }
+    if notifications.push_enabled && notifications.push_relay_principals.is_empty() {
+        return Err("At least one relay principal is required to send pushes".to_string());
+    }
+    
+    let details = format!(
+        "default_daily_limit={} digest_hour={} push_enabled={}",
+        notifications.default_daily_limit, notifications.digest_hour, notifications.push_enabled
+    );
+    let config = update_config(|config| {
+        config.notifications = notifications;
+        Ok(())
+    })?;
+    record_audit(caller, "set_notification_config", None, details);
+    Ok(config)
+}
+
+// --- Principal Linking ---
+//
+// Accounts from register_user are keyed by a synthetic principal their owner can't sign in
+// as. link_principal proves ownership with the password and moves the account onto the
+// caller's principal. The user record moves at once and session tokens issued to the old account
+// are revoked; every other record that names the old principal, as owner, participant or actor,
+// follows in the rekey job, one stable map per step, so some may show up a few seconds late.
+// The audit log and AI diagnostics keep the principal as it was.
+
+const REKEY_JOB_INTERVAL_NS: u64 = 60 * 1_000_000_000;
+// Leaves most of the heartbeat's instruction limit to the other jobs
+const REKEY_JOB_INSTRUCTIONS: u64 = 4_000_000_000;
+
+// Re-points records in a map whose keys don't contain the owner
+macro_rules! reassign_owner {
+    ($map:ident, $old:expr, $new:expr) => {
+        $map.with(|map| {
+            let mut map = map.borrow_mut();
+            let owned: Vec<_> = map.iter().filter(|(_, record)| record.user_id == $old).collect();
+            for (key, mut record) in owned {
+                record.user_id = $new;
+                map.insert(key, record);
+            }
+        })
+    };
+}
+
+// For maps keyed "{user_id}:..."
+macro_rules! reassign_prefixed {
+    ($map:ident, $old:expr, $new:expr) => {
+        $map.with(|map| {
+            let mut map = map.borrow_mut();
+            let owned: Vec<_> = map.range(format!("{}:", $old)..format!("{};", $old)).collect();
+            for (key, mut record) in owned {
+                map.remove(&key);
+                record.user_id = $new;
+                map.insert(format!("{}{}", $new, &key[$old.to_text().len()..]), record);
+            }
+        })
+    };
+}
+
+// Rewrites records in place; `$update` says whether it changed the record
+macro_rules! reassign_where {
+    ($map:ident, $update:expr) => {
+        $map.with(|map| {
+            let mut map = map.borrow_mut();
+            let update = $update;
+            let changed: Vec<_> = map.iter().filter_map(|(key, mut record)| update(&mut record).then_some((key, record))).collect();
+            for (key, record) in changed {
+                map.insert(key, record);
+            }
+        })
+    };
+}
+
+fn swap_principal(field: &mut Principal, old: Principal, new: Principal) -> bool {
+    let matched = *field == old;
+    if matched {
+        *field = new;
+    }
+    matched
+}
+
+fn swap_principals(list: &mut [Principal], old: Principal, new: Principal) -> bool {
+    list.iter_mut().fold(false, |changed, p| swap_principal(p, old, new) | changed)
+}
+
+fn swap_optional(field: &mut Option<Principal>, old: Principal, new: Principal) -> bool {
+    field.as_mut().is_some_and(|p| swap_principal(p, old, new))
+}
+
+// Moves the account itself; the records that name the old principal follow in the rekey job
+fn rekey_user(old: Principal, new: Principal) {
+    let Some(mut user) = cache::remove_user(old) else {
+        return;
+    };
+    let now = ic_cdk::api::time();
+    remove_user_oauth_identity(&user);
+    remove_user_auth_sessions(old);
+    user.id = new;
+    user.updated_at = now;
+    index_user_oauth_identity(&user);
+    cache::store_user(user);
+
+    // A rekey still moving records onto `old` now takes them straight to `new`
+    PRINCIPAL_REKEYS.with(|rekeys| {
+        let mut rekeys = rekeys.borrow_mut();
+        let chained: Vec<PrincipalRekey> = rekeys.values().filter(|r| r.new == old).collect();
+        for mut rekey in chained {
+            rekey.new = new;
+            rekeys.insert(rekey.old, rekey);
+        }
+        rekeys.insert(old, PrincipalRekey { old, new, next_memory: 0, started_at: now });
+    });
+    reschedule_job("principal_rekey");
+}
+
+// Works through pending rekeys one stable map at a time until the instruction budget is spent;
+// returns whether any are left
+fn run_principal_rekeys() -> bool {
+    let start = ic_cdk::api::performance_counter(0);
+    let pending: Vec<PrincipalRekey> = PRINCIPAL_REKEYS.with(|rekeys| rekeys.borrow().values().collect());
+    for mut rekey in pending {
+        let next = |from: u16| StableMemory::ALL.iter().copied().filter(|m| *m as u16 >= from).min_by_key(|m| *m as u8);
+        while let Some(memory) = next(rekey.next_memory) {
+            if ic_cdk::api::performance_counter(0).saturating_sub(start) > REKEY_JOB_INSTRUCTIONS {
+                PRINCIPAL_REKEYS.with(|rekeys| rekeys.borrow_mut().insert(rekey.old, rekey));
+                return true;
+            }
+            rekey_memory(memory, rekey.old, rekey.new);
+            rekey.next_memory = memory as u16 + 1;
+        }
+        PRINCIPAL_REKEYS.with(|rekeys| rekeys.borrow_mut().remove(&rekey.old));
+    }
+    false
+}
+
+// Every stable map is listed, so a map added to the registry doesn't build until it says how
+// a linked principal moves through it
+fn rekey_memory(memory: StableMemory, old: Principal, new: Principal) {
+    match memory {
+        // Moved when the link is made
+        StableMemory::Users | StableMemory::AuthSessions | StableMemory::OAuthIdentities => {}
+        // History keeps the principal as it was
+        StableMemory::AuditLog | StableMemory::AiDiagnostics => {}
+        // Keyed by email, token, tutor or entity, or holding no principals
+        StableMemory::LearningPaths | StableMemory::SubscriptionPlans | StableMemory::CertificateSigningKey | StableMemory::IdCounters
+        | StableMemory::EntityTags | StableMemory::TaggingQueue | StableMemory::IdAliases | StableMemory::Waitlist
+        | StableMemory::ReencodeProgress | StableMemory::KnowledgeBaseFileVersions | StableMemory::FactChecks
+        | StableMemory::StudyPackChunks | StableMemory::PersonaEvaluations | StableMemory::LoginAttempts
+        | StableMemory::DailyQuestions | StableMemory::ApiUsage | StableMemory::EmbedUsage | StableMemory::BotBridges
+        | StableMemory::EmailQueue | StableMemory::EmailExpiry | StableMemory::PrincipalRekeys => {}
+        // Maintained together with the chat sessions
+        StableMemory::ChatMessages | StableMemory::UserDataHashes | StableMemory::CertifiedBuckets => {}
+        StableMemory::RetiredMessages | StableMemory::RetiredSessions
+        | StableMemory::RetiredTutorEmailAddresses | StableMemory::RetiredEmailExchanges | StableMemory::RetiredBotBridges | StableMemory::RetiredBotLinkCodes | StableMemory::RetiredBotLinks | StableMemory::RetiredBotThreads
+        | StableMemory::RetiredApiUsage
+        | StableMemory::RetiredEmbedUsage
+        | StableMemory::RetiredAiDiagnostics => {}
+
+        StableMemory::Tutors => {
+            let tutors: Vec<(u64, Tutor)> = TUTORS.with(|tutors| {
+                tutors.borrow().iter().filter(|(_, t)| t.user_id == old || t.derived_from.iter().any(|a| a.creator == old)).collect()
+            });
+            for (id, mut tutor) in tutors {
+                swap_principal(&mut tutor.user_id, old, new);
+                for entry in &mut tutor.derived_from {
+                    swap_principal(&mut entry.creator, old, new);
+                }
+                cache::store_tutor(id, tutor);
+            }
+        }
+        StableMemory::TutorCourses => reassign_where!(TUTOR_COURSES, |course: &mut TutorCourse| {
+            let imported = course.attribution.as_mut().is_some_and(|a| swap_optional(&mut a.imported_by, old, new));
+            course.derived_from.iter_mut().fold(imported, |changed, a| swap_principal(&mut a.creator, old, new) | changed)
+        }),
+        // Sessions the user owns or joined; message hashes are certified per owner
+        StableMemory::ChatSessions => {
+            let sessions: Vec<ChatSession> = CHAT_SESSIONS.with(|sessions| {
+                sessions.borrow().values().filter(|s| s.user_id == old || s.co_learners.iter().any(|c| c.user_id == old)).collect()
+            });
+            for mut session in sessions {
+                let owned = swap_principal(&mut session.user_id, old, new);
+                for co_learner in &mut session.co_learners {
+                    swap_principal(&mut co_learner.user_id, old, new);
+                }
+                CHAT_SESSIONS.with(|s| s.borrow_mut().insert(session.id.clone(), session.clone()));
+                let list = if owned {
+                    certify::remove_messages(old, &session.id)
+                } else {
+                    CHAT_MESSAGES.with(|messages| messages.borrow().get(&session.id))
+                };
+                if let Some(mut list) = list {
+                    for message in &mut list.0 {
+                        if let Some(author) = message.author.as_mut() {
+                            swap_principal(author, old, new);
+                        }
+                    }
+                    certify::store_messages(session.user_id, &session.id, list);
+                }
+            }
+        }
+
+        StableMemory::KnowledgeBaseFiles => reassign_owner!(KNOWLEDGE_BASE_FILES, old, new),
+        StableMemory::KnowledgeBaseLinks => reassign_owner!(KNOWLEDGE_BASE_LINKS, old, new),
+        StableMemory::UserSubscriptions => reassign_owner!(USER_SUBSCRIPTIONS, old, new),
+        StableMemory::PaymentTransactions => reassign_owner!(PAYMENT_TRANSACTIONS, old, new),
+        StableMemory::UserAchievements => reassign_owner!(USER_ACHIEVEMENTS, old, new),
+        StableMemory::Milestones => reassign_owner!(MILESTONES, old, new),
+        StableMemory::ActivityPosts => {
+            reassign_owner!(ACTIVITY_POSTS, old, new);
+            reassign_where!(ACTIVITY_POSTS, |post: &mut ActivityPost| {
+                post.reactions.iter_mut().fold(false, |changed, r| swap_principal(&mut r.user_id, old, new) | changed)
+            });
+        }
+        StableMemory::UserTaskCompletions => reassign_owner!(USER_TASK_COMPLETIONS, old, new),
+        StableMemory::LearningProgress => reassign_owner!(LEARNING_PROGRESS, old, new),
+        StableMemory::LearningMetrics => reassign_owner!(LEARNING_METRICS, old, new),
+        StableMemory::ModuleCompletions => reassign_owner!(MODULE_COMPLETIONS, old, new),
+        StableMemory::PlacementTests => reassign_owner!(PLACEMENT_TESTS, old, new),
+        StableMemory::Certificates => {
+            reassign_owner!(CERTIFICATES, old, new);
+            reassign_where!(CERTIFICATES, |c: &mut Certificate| swap_optional(&mut c.revoked_by, old, new));
+        }
+        StableMemory::AnnouncementDismissals => reassign_owner!(ANNOUNCEMENT_DISMISSALS, old, new),
+        StableMemory::SupportTickets => {
+            reassign_owner!(SUPPORT_TICKETS, old, new);
+            reassign_where!(SUPPORT_TICKETS, |ticket: &mut SupportTicket| {
+                ticket.messages.iter_mut().fold(false, |changed, m| swap_principal(&mut m.author_id, old, new) | changed)
+            });
+        }
+        StableMemory::Notifications => reassign_owner!(NOTIFICATIONS, old, new),
+        StableMemory::FeedbackItems => {
+            reassign_owner!(FEEDBACK_ITEMS, old, new);
+            reassign_where!(FEEDBACK_ITEMS, |item: &mut FeedbackItem| swap_principals(&mut item.voters, old, new));
+        }
+        StableMemory::Exams => reassign_owner!(EXAMS, old, new),
+        StableMemory::CohortEnrollments => reassign_owner!(COHORT_ENROLLMENTS, old, new),
+        StableMemory::GroupMemberships => reassign_owner!(GROUP_MEMBERSHIPS, old, new),
+        StableMemory::RefundRequests => {
+            reassign_owner!(REFUND_REQUESTS, old, new);
+            reassign_where!(REFUND_REQUESTS, |request: &mut RefundRequest| swap_optional(&mut request.reviewed_by, old, new));
+        }
+        StableMemory::SkillProficiency => reassign_prefixed!(SKILL_PROFICIENCY, old, new),
+        StableMemory::PlanGrants => {
+            reassign_prefixed!(PLAN_GRANTS, old, new);
+            reassign_where!(PLAN_GRANTS, |grant: &mut PlanGrant| swap_principal(&mut grant.granted_by, old, new));
+        }
+        StableMemory::ConceptMaps => reassign_prefixed!(CONCEPT_MAPS, old, new),
+        StableMemory::FocusSessions => reassign_prefixed!(FOCUS_SESSIONS, old, new),
+        StableMemory::FocusWeeks => reassign_prefixed!(FOCUS_WEEKS, old, new),
+        StableMemory::DailyAnswers => reassign_prefixed!(DAILY_ANSWERS, old, new),
+        StableMemory::ReviewReminders => reassign_prefixed!(REVIEW_REMINDERS, old, new),
+        StableMemory::MetricsAggregates => reassign_prefixed!(METRICS_AGGREGATES, old, new),
+
+        StableMemory::CardSchedules => CARD_SCHEDULES.with(|schedules| {
+            let mut schedules = schedules.borrow_mut();
+            let owned: Vec<(String, CardSchedule)> = schedules.iter().filter(|(_, s)| s.user_id == old).collect();
+            for (key, mut schedule) in owned {
+                schedules.remove(&key);
+                schedule.user_id = new;
+                schedules.insert(schedule_key(schedule.deck_id, new, schedule.card_id), schedule);
+            }
+        }),
+        StableMemory::EventParticipations => EVENT_PARTICIPATION.with(|participation| {
+            let mut participation = participation.borrow_mut();
+            let owned: Vec<(String, EventParticipation)> = participation.iter().filter(|(_, p)| p.user_id == old).collect();
+            for (key, mut entry) in owned {
+                participation.remove(&key);
+                entry.user_id = new;
+                participation.insert(participation_key(entry.event_id, new), entry);
+            }
+        }),
+        StableMemory::StorageUsage => {
+            if let Some(usage) = STORAGE_USAGE.with(|usage| usage.borrow_mut().remove(&old)) {
+                STORAGE_USAGE.with(|u| u.borrow_mut().insert(new, usage));
+            }
+        }
+        StableMemory::EducatorVerifications => {
+            if let Some(mut verification) = EDUCATOR_VERIFICATIONS.with(|v| v.borrow_mut().remove(&old)) {
+                verification.user_id = new;
+                EDUCATOR_VERIFICATIONS.with(|v| v.borrow_mut().insert(new, verification));
+            }
+            reassign_where!(EDUCATOR_VERIFICATIONS, |v: &mut EducatorVerification| swap_optional(&mut v.reviewed_by, old, new));
+        }
+        StableMemory::NotificationLedgers => {
+            if let Some(mut ledger) = NOTIFICATION_LEDGERS.with(|ledgers| ledgers.borrow_mut().remove(&old)) {
+                ledger.user_id = new;
+                NOTIFICATION_LEDGERS.with(|ledgers| ledgers.borrow_mut().insert(new, ledger));
+            }
+        }
+        StableMemory::DailyQuestionStats => {
+            if let Some(mut stats) = DAILY_QUESTION_STATS.with(|stats| stats.borrow_mut().remove(&old)) {
+                stats.user_id = new;
+                DAILY_QUESTION_STATS.with(|map| map.borrow_mut().insert(new, stats));
+            }
+        }
+        StableMemory::PeerTutors => {
+            if let Some(mut profile) = PEER_TUTORS.with(|tutors| tutors.borrow_mut().remove(&old)) {
+                profile.user_id = new;
+                PEER_TUTORS.with(|tutors| tutors.borrow_mut().insert(new, profile));
+            }
+        }
+        StableMemory::PeerSlots => PEER_SLOTS.with(|slots| {
+            let mut slots = slots.borrow_mut();
+            let owned: Vec<(u64, PeerSlot)> = slots.iter().filter(|(_, s)| s.tutor_id == old).collect();
+            for (id, mut slot) in owned {
+                slot.tutor_id = new;
+                slots.insert(id, slot);
+            }
+        }),
+        StableMemory::PeerBookings => reassign_where!(PEER_BOOKINGS, |booking: &mut PeerBooking| {
+            let parties = swap_principal(&mut booking.tutor_id, old, new) | swap_principal(&mut booking.learner_id, old, new);
+            let cancelled = swap_optional(&mut booking.cancelled_by, old, new);
+            let dispute = booking.dispute.as_mut().is_some_and(|d| swap_principal(&mut d.raised_by, old, new) | swap_optional(&mut d.resolved_by, old, new));
+            parties | cancelled | dispute
+        }),
+        StableMemory::OrgMembers => reassign_owner!(ORG_MEMBERS, old, new),
+        StableMemory::LearnerRiskFlags => reassign_owner!(LEARNER_RISK_FLAGS, old, new),
+        StableMemory::Organizations => reassign_where!(ORGANIZATIONS, |org: &mut Organization| {
+            swap_principals(&mut org.admins, old, new) | swap_principal(&mut org.created_by, old, new)
+        }),
+        StableMemory::OrgImports => reassign_where!(ORG_IMPORTS, |import: &mut OrgImport| {
+            let rows = import.rows.iter_mut().fold(false, |changed, row| swap_optional(&mut row.user_id, old, new) | changed);
+            swap_principal(&mut import.created_by, old, new) | rows
+        }),
+
+        // Connections, groups and cohorts
+        StableMemory::Connections => reassign_where!(CONNECTIONS, |c: &mut UserConnection| swap_principal(&mut c.user1_id, old, new) | swap_principal(&mut c.user2_id, old, new)),
+        StableMemory::ConnectionRequests => reassign_where!(CONNECTION_REQUESTS, |r: &mut ConnectionRequest| swap_principal(&mut r.sender_id, old, new) | swap_principal(&mut r.receiver_id, old, new)),
+        StableMemory::StudyGroups => reassign_where!(STUDY_GROUPS, |group: &mut StudyGroup| swap_principal(&mut group.creator_id, old, new)),
+        StableMemory::StudyResources => reassign_owner!(STUDY_RESOURCES, old, new),
+        StableMemory::FlashcardDecks => reassign_where!(FLASHCARD_DECKS, |deck: &mut FlashcardDeck| swap_principal(&mut deck.created_by, old, new)),
+        StableMemory::DeckCards => reassign_where!(DECK_CARDS, |card: &mut DeckCard| swap_principal(&mut card.contributed_by, old, new) | swap_optional(&mut card.curated_by, old, new)),
+        StableMemory::Cohorts => reassign_where!(COHORTS, |cohort: &mut Cohort| swap_principal(&mut cohort.created_by, old, new)),
+        StableMemory::DiscussionPosts => reassign_where!(DISCUSSION_POSTS, |post: &mut DiscussionPost| {
+            swap_principal(&mut post.user_id, old, new) | swap_principals(&mut post.voters, old, new) | swap_principals(&mut post.reported_by, old, new)
+        }),
+        StableMemory::CourseGlossary => reassign_where!(COURSE_GLOSSARY, |entry: &mut GlossaryEntry| swap_optional(&mut entry.edited_by, old, new)),
+
+        // Tutoring activity
+        StableMemory::TutorSessions => reassign_owner!(TUTOR_SESSIONS, old, new),
+        StableMemory::XapiOutbox => reassign_owner!(XAPI_OUTBOX, old, new),
+        StableMemory::PendingDeliveries => reassign_where!(PENDING_DELIVERIES, |d: &mut PendingDelivery| swap_principal(&mut d.user_id, old, new) | swap_optional(&mut d.requested_by, old, new)),
+        StableMemory::LowConfidenceReplies => reassign_where!(LOW_CONFIDENCE_REPLIES, |r: &mut LowConfidenceReply| swap_principal(&mut r.user_id, old, new) | swap_optional(&mut r.reviewed_by, old, new)),
+        StableMemory::UndoQueue => reassign_where!(UNDO_QUEUE, |staged: &mut StagedAction| swap_principal(&mut staged.action.user_id, old, new)),
+        StableMemory::StudyPacks => reassign_owner!(STUDY_PACKS, old, new),
+        StableMemory::ReadCursors => READ_CURSORS.with(|cursors| {
+            let mut cursors = cursors.borrow_mut();
+            let owned: Vec<(String, ReadCursor)> = cursors.iter().filter(|(_, c)| c.user_id == old).collect();
+            for (key, mut cursor) in owned {
+                cursors.remove(&key);
+                cursor.user_id = new;
+                cursors.insert(read_cursor_key(&cursor.session_id, new, &cursor.device_id), cursor);
+            }
+        }),
+        StableMemory::PlanFramings => {
+            if let Some(mut framing) = PLAN_FRAMINGS.with(|framings| framings.borrow_mut().remove(&old)) {
+                framing.user_id = new;
+                PLAN_FRAMINGS.with(|framings| framings.borrow_mut().insert(new, framing));
+            }
+        }
+
+        // Billing and creator earnings
+        StableMemory::GiftSubscriptions => reassign_where!(GIFT_SUBSCRIPTIONS, |gift: &mut GiftSubscription| {
+            swap_principal(&mut gift.purchaser_id, old, new) | swap_optional(&mut gift.recipient_principal, old, new)
+        }),
+        StableMemory::CreatorUsage => reassign_where!(CREATOR_USAGE, |usage: &mut CreatorUsage| swap_principal(&mut usage.creator_id, old, new) | swap_principals(&mut usage.learners, old, new)),
+        StableMemory::CreatorPayouts => reassign_where!(CREATOR_PAYOUTS, |payout: &mut CreatorPayout| swap_principal(&mut payout.creator_id, old, new) | swap_optional(&mut payout.reviewed_by, old, new)),
+        StableMemory::TrialHistory => {
+            reassign_owner!(TRIAL_HISTORY, old, new);
+            if let Some(record) = TRIAL_HISTORY.with(|history| history.borrow_mut().remove(&format!("principal:{}", old))) {
+                TRIAL_HISTORY.with(|history| history.borrow_mut().insert(format!("principal:{}", new), record));
+            }
+        }
+        StableMemory::ExperimentAssignments => EXPERIMENT_ASSIGNMENTS.with(|assignments| {
+            let mut assignments = assignments.borrow_mut();
+            let owned: Vec<(String, ExperimentAssignment)> = assignments.iter().filter(|(_, a)| a.user_id == old).collect();
+            for (key, mut assignment) in owned {
+                assignments.remove(&key);
+                assignment.user_id = new;
+                assignments.insert(experiment_assignment_key(assignment.experiment_id, new), assignment);
+            }
+        }),
+
+        // Inbound email and chat bridges route replies by user_id
+        StableMemory::TutorEmailAddresses => reassign_owner!(TUTOR_EMAIL_ADDRESSES, old, new),
+        StableMemory::EmailExchanges => reassign_owner!(EMAIL_EXCHANGES, old, new),
+        StableMemory::BotLinkCodes => reassign_owner!(BOT_LINK_CODES, old, new),
+        StableMemory::BotLinks => reassign_owner!(BOT_LINKS, old, new),
+        StableMemory::BotThreads => reassign_owner!(BOT_THREADS, old, new),
+        StableMemory::EmbedTokens => reassign_where!(EMBED_TOKENS, |token: &mut EmbedToken| swap_principal(&mut token.owner, old, new)),
+
+        // Records of staff actions
+        StableMemory::ImpersonationSessions => reassign_where!(IMPERSONATION_SESSIONS, |s: &mut ImpersonationSession| swap_principal(&mut s.admin_id, old, new) | swap_principal(&mut s.user_id, old, new)),
+        StableMemory::Achievements => reassign_where!(ACHIEVEMENTS, |a: &mut Achievement| swap_principal(&mut a.created_by, old, new)),
+        StableMemory::Tasks => reassign_where!(TASKS, |t: &mut Task| swap_principal(&mut t.created_by, old, new)),
+        StableMemory::Announcements => reassign_where!(ANNOUNCEMENTS, |a: &mut Announcement| swap_principal(&mut a.created_by, old, new)),
+        StableMemory::Experiments => reassign_where!(EXPERIMENTS, |e: &mut Experiment| swap_principal(&mut e.created_by, old, new)),
+        StableMemory::SeasonalEvents => reassign_where!(SEASONAL_EVENTS, |e: &mut SeasonalEvent| swap_principal(&mut e.created_by, old, new)),
+        StableMemory::ApiTokens => reassign_where!(API_TOKENS, |t: &mut ApiToken| swap_principal(&mut t.created_by, old, new)),
+        StableMemory::InviteCodes => reassign_where!(INVITE_CODES, |code: &mut InviteCode| swap_principal(&mut code.created_by, old, new)),
+        StableMemory::GuestSessions => reassign_where!(GUEST_SESSIONS, |guest: &mut GuestSession| swap_principal(&mut guest.started_by, old, new)),
+        StableMemory::Config => {
+            let _ = update_config(|config| {
+                for hold in &mut config.retention_holds {
+                    swap_principal(&mut hold.user_id, old, new);
+                    swap_principal(&mut hold.set_by, old, new);
+                }
+                Ok(())
+            });
+        }
+
+        // Per-principal counters and routing
+        StableMemory::UserShards => {
+            if let Some(shard) = USER_SHARDS.with(|shards| shards.borrow_mut().remove(&old)) {
+                USER_SHARDS.with(|shards| shards.borrow_mut().insert(new, shard));
+            }
+        }
+        StableMemory::PushQueue => PUSH_QUEUE.with(|queue| {
+            let mut queue = queue.borrow_mut();
+            let queued: Vec<String> = queue.iter().filter(|(_, user_id)| *user_id == old).map(|(key, _)| key).collect();
+            for key in queued {
+                queue.insert(key, new);
+            }
+        }),
+        StableMemory::AiCallCounts => {
+            let suffix = format!(":{}", old);
+            AI_CALL_COUNTS.with(|counts| {
+                let mut counts = counts.borrow_mut();
+                let owned: Vec<(String, u64)> = counts.iter().filter(|(key, _)| key.ends_with(&suffix)).collect();
+                for (key, count) in owned {
+                    counts.remove(&key);
+                    if let Ok(day) = key[..key.len() - suffix.len()].parse() {
+                        counts.insert(ai_call_key(new, day), count);
+                    }
+                }
+            });
+        }
+        StableMemory::RateLimits => rate_limit::rekey(old, new),
+    }
+}
+
+// Called from the principal to link, usually an Internet Identity, with the password
+// account's credentials
+#[ic_cdk::update]
+async fn link_principal(email: String, password: String) -> Result<User, String> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return Err("Sign in with the identity you want to link first".to_string());
+    }
+    if cache::user(caller).is_some() {
+        return Err("This principal already has an account".to_string());
+    }
+    let user = password_login(email, password).await?;
+    
+    // Re-check after the await
+    if cache::user(caller).is_some() {
+        return Err("This principal already has an account".to_string());
+    }
+    rekey_user(user.id, caller);
+    record_audit(caller, "principal_linked", Some(caller), format!("moved from {}", user.id));
+    claim_pending_admin(caller);
+    cache::user(caller).ok_or("User not found".to_string())
+}
+
+// --- Topic Drift ---
+//
+// Delivered replies queue their session for a drift check once enough messages have passed
+// since the last one. The heartbeat rates the recent conversation against the declared topic
+// and, past the threshold, the tutor posts an offer to refocus, switch the session's topic
+// or start a new session on the emergent one. The learner's answer is recorded on the session.
+
+const DRIFT_JOB_INTERVAL_NS: u64 = 60 * 1_000_000_000;
+const DRIFT_BATCH_SIZE: usize = 5;
+const DRIFT_MESSAGE_CHARS: usize = 400;
+const MAX_EMERGENT_TOPIC_CHARS: usize = 120;
+const DRIFT_ACTIONS: [&str; 4] = ["refocus", "switch", "spawn", "dismiss"];
+
+thread_local! {
+    // Sessions waiting for a check; lost on upgrade, and the next reply queues them again
+    static DRIFT_CHECK_QUEUE: RefCell<std::collections::BTreeSet<String>> = const { RefCell::new(std::collections::BTreeSet::new()) };
+}
+
+#[derive(serde::Deserialize)]
+struct AiDriftCheck {
+    drift: f32,
+    emergent_topic: Option<String>,
+}
+
+fn session_message_count(session_id: &str) -> u32 {
+    CHAT_MESSAGES.with(|messages| messages.borrow().get(&session_id.to_string())).map(|list| list.0.len() as u32).unwrap_or(0)
+}
+
+fn queue_drift_check(session_id: &str) {
+    let config = get_config().topic_drift;
+    if !config.enabled {
+        return;
+    }
+    let Some(session) = CHAT_SESSIONS.with(|sessions| sessions.borrow().get(&session_id.to_string())) else {
+        return;
+    };
+    let checked = session.topic_drift.as_ref().map(|d| d.checked_message_count).unwrap_or(0);
+    if session.status == "active" && session_message_count(session_id) >= checked + config.check_every_messages.max(1) {
+        DRIFT_CHECK_QUEUE.with(|queue| queue.borrow_mut().insert(session_id.to_string()));
+    }
+}
+
+fn run_drift_checks() {
+    let batch: Vec<String> = DRIFT_CHECK_QUEUE.with(|queue| {
+        let mut queue = queue.borrow_mut();
+        let batch: Vec<String> = queue.iter().take(DRIFT_BATCH_SIZE).cloned().collect();
+        for session_id in &batch {
+            queue.remove(session_id);
+        }
+        batch
+    });
+    for session_id in batch {
+        ic_cdk::spawn(async move {
+            if let Err(e) = check_topic_drift(&session_id).await {
+                ic_cdk::println!("Drift check for {} failed: {}", session_id, e);
+            }
+        });
+    }
+}
+
+async fn check_topic_drift(session_id: &str) -> Result<(), String> {
+    let config = get_config().topic_drift;
+    let session = CHAT_SESSIONS.with(|sessions| sessions.borrow().get(&session_id.to_string())).ok_or("Session not found")?;
+    let messages = CHAT_MESSAGES.with(|messages| messages.borrow().get(&session_id.to_string())).map(|list| list.0).unwrap_or_default();
+    let count = messages.len() as u32;
+    let delivered: Vec<&ChatMessage> = messages.iter().filter(|m| m.delivery_status == "delivered").collect();
+    let window = delivered.len().saturating_sub(config.window_messages.max(1) as usize);
+    let transcript: Vec<String> = delivered[window..].iter()
+        .map(|m| format!("{}: {}", if m.sender == "user" { "Student" } else { "Tutor" }, trim_to_length(&m.content, DRIFT_MESSAGE_CHARS)))
+        .collect();
+    
+    let prompt = format!(
+        "A tutoring session was started on this topic: {}
+        
+        Rate how far the recent conversation below has drifted from that topic, from 0 (on topic) to 1 (about something else entirely). Closely related subtopics and prerequisites count as on topic.
+        
+        Return ONLY JSON: {{\"drift\":0.2,\"emergent_topic\":\"what they are discussing now, or null\"}}
+        
+        Conversation:
+        {}",
+        session.topic,
+        transcript.join("\n")
+    );
+    let response = process_ai_response(call_groq_ai(&prompt, "topic_drift", ic_cdk::id()).await?, &response_processing_for(ic_cdk::id(), "json"));
+    let parsed: AiDriftCheck = serde_json::from_str(&response).map_err(|e| format!("Failed to parse drift check: {}", e))?;
+    
+    // Re-read after the AI call
+    let mut session = CHAT_SESSIONS.with(|sessions| sessions.borrow().get(&session_id.to_string())).ok_or("Session not found")?;
+    let mut drift = session.topic_drift.take().unwrap_or_default();
+    drift.score = parsed.drift.clamp(0.0, 1.0);
+    drift.emergent_topic = parsed.emergent_topic
+        .map(|t| trim_to_length(t.trim(), MAX_EMERGENT_TOPIC_CHARS))
+        .filter(|t| !t.is_empty() && !t.eq_ignore_ascii_case(&session.topic));
+    drift.checked_at = ic_cdk::api::time();
+    drift.checked_message_count = count;
+    
+    let quiet = !drift.decisions.is_empty() && count < drift.decided_message_count + config.reoffer_after_messages;
+    if drift.score >= config.threshold && drift.offer_message_id.is_none() && !quiet {
+        if let Some(emergent) = &drift.emergent_topic {
+            let message = ChatMessage {
+                id: format!("drift_{}", ic_cdk::api::time()),
+                session_id: session.id.clone(),
+                sender: "tutor".to_string(),
+                content: format!(
+                    "We've wandered from {} into {}. Would you like to get back to {}, make {} the topic of this session, or start a new session on it?",
+                    session.topic, emergent, session.topic, emergent
+                ),
+                timestamp: ic_cdk::api::time(),
+                has_audio: Some(false),
+                delivery_status: "delivered".to_string(),
+                reading_grade: None,
+                author: None,
+                citations: Vec::new(),
+                confidence: None,
+                rating: None,
+                bookmarked: false,
+            };
+            drift.offer_message_id = Some(message.id.clone());
+            append_chat_message(session.user_id, message);
+        }
+    }
+    session.topic_drift = Some(drift);
+    CHAT_SESSIONS.with(|sessions| sessions.borrow_mut().insert(session.id.clone(), session));
+    Ok(())
+}
+
+// The learner's answer to a refocus offer. "switch" renames this session's topic and "spawn"
+// starts a new session with the same tutor on the emergent topic.
+#[ic_cdk::update]
+async fn resolve_topic_drift(session_id: String, action: String, session_token: Option<String>) -> Result<ChatSession, String> {
+    let caller = session_caller(session_token.clone())?;
+    let session = owned_session(&session_id, caller)?;
+    let drift = session.topic_drift.clone().filter(|d| d.offer_message_id.is_some()).ok_or("There is no open refocus offer for this session")?;
+    if !DRIFT_ACTIONS.contains(&action.as_str()) {
+        return Err(format!("Action must be one of: {}", DRIFT_ACTIONS.join(", ")));
+    }
+    let emergent = drift.emergent_topic.clone();
+    let new_session_id = if action == "spawn" {
+        let topic = emergent.clone().ok_or("There is no new topic to start a session on")?;
+        Some(create_chat_session(session.tutor_id.clone(), topic, None, session_token).await?)
+    } else {
+        None
+    };
+    
+    // Re-read after the await
+    let mut session = owned_session(&session_id, caller)?;
+    let mut drift = session.topic_drift.take().unwrap_or_default();
+    let now = ic_cdk::api::time();
+    drift.decisions.push(DriftDecision {
+        action: action.clone(),
+        from_topic: session.topic.clone(),
+        to_topic: if matches!(action.as_str(), "switch" | "spawn") { emergent.clone() } else { None },
+        new_session_id,
+        score: drift.score,
+        decided_at: now,
+    });
+    if action == "switch" {
+        session.topic = emergent.ok_or("There is no new topic to switch to")?;
+    }
+    drift.offer_message_id = None;
+    drift.decided_message_count = session_message_count(&session_id);
+    session.topic_drift = Some(drift);
+    session.updated_at = now;
+    CHAT_SESSIONS.with(|sessions| sessions.borrow_mut().insert(session_id, session.clone()));
+    Ok(session)
+}
+
+#[ic_cdk::update]
+fn set_topic_drift_config_admin(topic_drift: TopicDriftConfig) -> Result<CanisterConfig, String> {
+    let caller = ic_cdk::caller();
+    require(caller, Permission::ManageSystem)?;
+    if !(0.0..=1.0).contains(&topic_drift.threshold) || topic_drift.check_every_messages == 0 || topic_drift.window_messages == 0 {
+        return Err("Threshold must be between 0 and 1, and the check interval and window at least 1 message".to_string());
+    }
+    
+    let details = format!(
+        "enabled={} threshold={} check_every_messages={}",
+        topic_drift.enabled, topic_drift.threshold, topic_drift.check_every_messages
+    );
+    let config = update_config(|config| {
+        config.topic_drift = topic_drift;
+        Ok(())
+    })?;
+    record_audit(caller, "set_topic_drift_config", None, details);
+    Ok(config)
+}
+
+// --- Rate Limits ---
+
+#[ic_cdk::update]
+fn set_rate_limit_config_admin(rate_limits: RateLimitConfig) -> Result<CanisterConfig, String> {
+    let caller = ic_cdk::caller();
+    require(caller, Permission::ManageSystem)?;
+    let mut seen = std::collections::HashSet::new();
+    for rule in &rate_limits.rules {
+        if rule.class.trim().is_empty() || !seen.insert(rule.class.as_str()) {
+            return Err("Each rule needs a distinct method class".to_string());
+        }
+        if rule.window_secs == 0 {
+            return Err("Rate limit windows must be at least 1 second".to_string());
+        }
+    }
+    
+    let details = rate_limits.rules.iter()
+        .map(|r| format!("{}={}/{}s", r.class, r.max_calls, r.window_secs))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let config = update_config(|config| {
+        config.rate_limits = rate_limits;
+        Ok(())
+    })?;
+    record_audit(caller, "set_rate_limit_config", None, details);
+    Ok(config)
+}
+
+// --- Persona Consistency ---
+//
+// An AI judge periodically rates a sample of each tutor's recent replies against the tutor's
+// personality and teaching style, with findings and suggested edits to that text for the
+// owner. Scores are grouped by persona revision so owners can see whether an edit helped.
+// Replies from any learner's session are sampled, so the judge is told not to quote them.
+
+const PERSONA_EVAL_JOB_INTERVAL_NS: u64 = 60 * 60 * 1_000_000_000;
+const PERSONA_REPLY_CHARS: usize = 700;
+const PERSONA_SESSIONS_SAMPLED: usize = 10;
+const MAX_PERSONA_NOTES: usize = 3;
+
+#[derive(serde::Deserialize)]
+struct AiPersonaJudgement {
+    score: f32,
+    #[serde(default)]
+    findings: Vec<String>,
+    #[serde(default)]
+    suggestions: Vec<String>,
+}
+
+fn persona_revision(tutor: &Tutor) -> String {
+    content_hash(&format!("{}\n{}\n{}", tutor.personality, tutor.teaching_style, tutor.description))[..12].to_string()
+}
+
+fn persona_evaluations(tutor_id: u64) -> Vec<PersonaEvaluation> {
+    PERSONA_EVALUATIONS.with(|evaluations| {
+        evaluations.borrow().range(format!("{:020}:", tutor_id)..format!("{:020};", tutor_id)).map(|(_, e)| e).collect()
+    })
+}
+
+fn remove_persona_evaluations(tutor_id: u64) {
+    PERSONA_EVALUATIONS.with(|evaluations| {
+        let mut evaluations = evaluations.borrow_mut();
+        let keys: Vec<String> = evaluations.range(format!("{:020}:", tutor_id)..format!("{:020};", tutor_id)).map(|(key, _)| key).collect();
+        for key in keys {
+            evaluations.remove(&key);
+        }
+    });
+}
+
+// Sessions active since each tutor's last evaluation, most recent first, in one pass over
+// sessions. Keyed by tutor public id; the values are the times since which to sample.
+fn recent_tutor_sessions(since: &HashMap<String, u64>) -> HashMap<String, Vec<ChatSession>> {
+    let mut found: HashMap<String, Vec<ChatSession>> = HashMap::new();
+    CHAT_SESSIONS.with(|sessions| {
+        for (_, session) in sessions.borrow().iter() {
+            if since.get(&session.tutor_id).is_some_and(|at| session.updated_at > *at) {
+                found.entry(session.tutor_id.clone()).or_default().push(session);
+            }
+        }
+    });
+    for sessions in found.values_mut() {
+        sessions.sort_by_key(|s| std::cmp::Reverse(s.updated_at));
+        sessions.truncate(PERSONA_SESSIONS_SAMPLED);
+    }
+    found
+}
+
+// The latest delivered replies in those sessions, newer than `since`
+fn sample_tutor_replies(sessions: &[ChatSession], since: u64, sample_size: usize) -> Vec<ChatMessage> {
+    let mut replies: Vec<ChatMessage> = sessions.iter()
+        .flat_map(|s| CHAT_MESSAGES.with(|messages| messages.borrow().get(&s.id)).map(|list| list.0).unwrap_or_default())
+        .filter(|m| m.sender == "tutor" && m.delivery_status == "delivered" && m.timestamp > since)
+        .filter(|m| !m.id.starts_with("welcome_") && !m.id.starts_with("drift_"))
+        .collect();
+    replies.sort_by_key(|m| std::cmp::Reverse(m.timestamp));
+    replies.truncate(sample_size);
+    replies
+}
+
+async fn judge_tutor_persona(tutor_id: u64, replies: Vec<ChatMessage>) -> Result<PersonaEvaluation, String> {
+    let tutor = TUTORS.with(|tutors| tutors.borrow().get(&tutor_id)).ok_or("Tutor not found")?;
+    let revision = persona_revision(&tutor);
+    let samples: Vec<String> = replies.iter()
+        .enumerate()
+        .map(|(i, m)| format!("Reply {}:\n{}", i + 1, trim_to_length(&m.content, PERSONA_REPLY_CHARS)))
+        .collect();
+    let prompt = format!(
+        "You are reviewing an AI tutor for consistency with the persona its creator configured.
+        
+        Description: {}
+        Personality: {}
+        Teaching style: {}
+        
+        Rate from 0 to 1 how consistently the replies below reflect this personality and teaching style. Give at most {} findings describing inconsistencies in general terms, without quoting the replies or mentioning the students, and at most {} concrete edits to the personality or teaching style text that would make replies more consistent.
+        
+        Return ONLY JSON: {{\"score\":0.8,\"findings\":[\"...\"],\"suggestions\":[\"...\"]}}
+        
+        {}",
+        tutor.description,
+        tutor.personality,
+        tutor.teaching_style,
+        MAX_PERSONA_NOTES,
+        MAX_PERSONA_NOTES,
+        samples.join("\n\n")
+    );
+    let response = process_ai_response(call_groq_ai(&prompt, "persona_eval", ic_cdk::id()).await?, &response_processing_for(ic_cdk::id(), "json"));
+    let judgement: AiPersonaJudgement = serde_json::from_str(&response).map_err(|e| format!("Failed to parse persona evaluation: {}", e))?;
+    let notes = |items: Vec<String>| -> Vec<String> {
+        items.into_iter().map(|n| n.trim().to_string()).filter(|n| !n.is_empty()).take(MAX_PERSONA_NOTES).collect()
+    };
+    
+    let evaluation = PersonaEvaluation {
+        id: next_id("persona_evaluation"),
+        tutor_id,
+        revision,
+        score: judgement.score.clamp(0.0, 1.0),
+        sampled_replies: replies.len() as u32,
+        findings: notes(judgement.findings),
+        suggestions: notes(judgement.suggestions),
+        evaluated_at: ic_cdk::api::time(),
+    };
+    PERSONA_EVALUATIONS.with(|evaluations| {
+        evaluations.borrow_mut().insert(format!("{:020}:{:020}", tutor_id, evaluation.id), evaluation.clone())
+    });
+    Ok(evaluation)
+}
+
+fn run_persona_evaluations(now: u64) {
+    let config = get_config().persona_eval;
+    if !config.enabled {
+        return;
+    }
+    let interval = config.interval_hours.max(1) as u64 * NANOS_PER_HOUR;
+    let mut due: Vec<(u64, Tutor, u64)> = TUTORS.with(|tutors| {
+        tutors.borrow().iter()
+            .map(|(id, tutor)| (id, tutor, persona_evaluations(id).last().map(|e| e.evaluated_at).unwrap_or(0)))
+            .filter(|(_, _, last)| now.saturating_sub(*last) >= interval)
+            .collect()
+    });
+    due.sort_by_key(|(_, _, last)| *last);
+    let since: HashMap<String, u64> = due.iter().map(|(_, tutor, last)| (tutor.public_id.clone(), *last)).collect();
+    let mut sessions = recent_tutor_sessions(&since);
+    
+    let mut started = 0;
+    for (tutor_id, tutor, last) in due {
+        if started >= config.tutors_per_run {
+            break;
+        }
+        let tutor_sessions = sessions.remove(&tutor.public_id).unwrap_or_default();
+        let replies = sample_tutor_replies(&tutor_sessions, last, config.sample_size.max(1) as usize);
+        if replies.len() < config.min_replies as usize {
+            continue;
+        }
+        started += 1;
+        let alert_below = config.alert_below;
+        ic_cdk::spawn(async move {
+            match judge_tutor_persona(tutor_id, replies).await {
+                Ok(evaluation) if evaluation.score < alert_below => notify_user(
+                    tutor.user_id,
+                    "warning",
+                    "tutor",
+                    format!("{}'s recent replies scored {:.0}% for staying in character; see the suggestions on its consistency report", tutor.name, evaluation.score * 100.0),
+                    Some(evaluation.id),
+                ),
+                Ok(_) => {}
+                Err(e) => ic_cdk::println!("Persona evaluation of tutor {} failed: {}", tutor_id, e),
+            }
+        });
+    }
+}
+
+// Runs an evaluation now on the replies since the last one
+#[ic_cdk::update]
+async fn evaluate_tutor_persona(tutor_id: String) -> Result<PersonaEvaluation, String> {
+    let caller = ic_cdk::caller();
+    rate_limit::check(caller, "generation")?;
+    let (id, tutor) = owned_tutor(&tutor_id, caller)?;
+    let config = get_config().persona_eval;
+    let since = persona_evaluations(id).last().map(|e| e.evaluated_at).unwrap_or(0);
+    let sessions = recent_tutor_sessions(&HashMap::from([(tutor.public_id.clone(), since)])).remove(&tutor.public_id).unwrap_or_default();
+    let replies = sample_tutor_replies(&sessions, since, config.sample_size.max(1) as usize);
+    if replies.len() < config.min_replies.max(1) as usize {
+        return Err(format!("At least {} new replies are needed for an evaluation", config.min_replies.max(1)));
+    }
+    judge_tutor_persona(id, replies).await
+}
+
+#[ic_cdk::query]
+fn get_tutor_consistency_report(tutor_id: String) -> Result<PersonaConsistencyReport, String> {
+    let (id, tutor) = owned_tutor(&tutor_id, ic_cdk::caller())?;
+    let evaluations = persona_evaluations(id);
+    
+    let mut revisions: Vec<RevisionScore> = Vec::new();
+    for evaluation in &evaluations {
+        match revisions.iter_mut().find(|r| r.revision == evaluation.revision) {
+            Some(revision) => {
+                revision.average_score = (revision.average_score * revision.evaluations as f32 + evaluation.score) / (revision.evaluations + 1) as f32;
+                revision.evaluations += 1;
+                revision.last_evaluated_at = evaluation.evaluated_at;
+            }
+            None => revisions.push(RevisionScore {
+                revision: evaluation.revision.clone(),
+                evaluations: 1,
+                average_score: evaluation.score,
+                first_evaluated_at: evaluation.evaluated_at,
+                last_evaluated_at: evaluation.evaluated_at,
+            }),
+        }
+    }
+    let change_since_previous = match evaluations.as_slice() {
+        [.., previous, latest] => Some(latest.score - previous.score),
+        _ => None,
+    };
+    
+    Ok(PersonaConsistencyReport {
+        tutor_id: tutor.public_id.clone(),
+        current_revision: persona_revision(&tutor),
+        latest: evaluations.last().cloned(),
+        change_since_previous,
+        revisions,
+        evaluations: evaluations.into_iter().rev().collect(),
+    })
+}
+
+#[ic_cdk::update]
+fn set_persona_eval_config_admin(persona_eval: PersonaEvalConfig) -> Result<CanisterConfig, String> {
+    let caller = ic_cdk::caller();
+    require(caller, Permission::ManageSystem)?;
+    if !(0.0..=1.0).contains(&persona_eval.alert_below) || persona_eval.sample_size == 0 || persona_eval.interval_hours == 0 {
+        return Err("Alert threshold must be between 0 and 1, and the sample size and interval at least 1".to_string());
+    }
+    
+    let details = format!(
+        "enabled={} interval_hours={} sample_size={} alert_below={}",
+        persona_eval.enabled, persona_eval.interval_hours, persona_eval.sample_size, persona_eval.alert_below
+    );
+    let config = update_config(|config| {
+        config.persona_eval = persona_eval;
+        Ok(())
+    })?;
+    record_audit(caller, "set_persona_eval_config", None, details);
+    Ok(config)
+}
+
+// --- Daily Plan ---
+//
+// A short plan for returning learners built only from stored progress: the next module in
+// the course they were last working through, flashcards due in their groups' decks, and a
+// review quiz on their weakest skill due for review. polish_todays_plan adds a line of AI
+// encouragement, cached until the user's local day ends.
+
+const PLAN_MODULE_MINUTES: u32 = 20;
+const PLAN_QUIZ_MINUTES: u32 = 5;
+const PLAN_MAX_FLASHCARDS: u32 = 10;
+
+fn next_course_module(user_id: Principal) -> Option<(TutorCourse, CourseModule)> {
+    let tutor_ids: std::collections::HashSet<u64> = TUTORS.with(|tutors| {
+        tutors.borrow().iter().filter(|(_, t)| t.user_id == user_id).map(|(id, _)| id).collect()
+    });
+    let completed: HashMap<u64, u64> = MODULE_COMPLETIONS.with(|completions| {
+        completions.borrow().iter()
+            .map(|(_, c)| c)
+            .filter(|c| c.user_id == user_id && c.completed)
+            .map(|c| (c.module_id, c.completion_date.unwrap_or(c.updated_at)))
+            .collect()
+    });
+    
+    // The unfinished course with the latest completion, or else the newest unfinished one
+    TUTOR_COURSES.with(|courses| {
+        courses.borrow().iter()
+            .map(|(_, course)| course)
+            .filter(|course| tutor_ids.contains(&course.tutor_id))
+            .filter_map(|course| {
+                let next = sorted_modules(&course).into_iter().find(|m| !completed.contains_key(&m.id))?;
+                let last_active = course.modules.iter().filter_map(|m| completed.get(&m.id)).max().copied().unwrap_or(course.created_at);
+                Some((last_active, course, next))
+            })
+            .max_by_key(|(last_active, _, _)| *last_active)
+            .map(|(_, course, next)| (course, next))
+    })
+}
+
+// Due or unseen approved cards in decks of groups the user is active in, by deck
+fn due_flashcards(user_id: Principal, now: u64) -> Vec<(FlashcardDeck, u32)> {
+    let groups: std::collections::HashSet<u64> = GROUP_MEMBERSHIPS.with(|memberships| {
+        memberships.borrow().iter()
+            .map(|(_, m)| m)
+            .filter(|m| m.user_id == user_id && m.status == "active")
+            .map(|m| m.group_id)
+            .collect()
+    });
+    let decks: HashMap<u64, FlashcardDeck> = FLASHCARD_DECKS.with(|decks| {
+        decks.borrow().iter().filter(|(_, d)| groups.contains(&d.group_id)).collect()
+    });
+    let schedules: HashMap<u64, HashMap<u64, CardSchedule>> = decks.keys().map(|id| (*id, learner_schedules(*id, user_id))).collect();
+    
+    let mut due: HashMap<u64, u32> = HashMap::new();
+    DECK_CARDS.with(|cards| {
+        for (_, card) in cards.borrow().iter() {
+            let Some(deck_schedules) = schedules.get(&card.deck_id) else {
+                continue;
+            };
+            if card.status == "approved" && deck_schedules.get(&card.id).is_none_or(|s| s.due_at <= now) {
+                *due.entry(card.deck_id).or_default() += 1;
+            }
+        }
+    });
+    let mut due: Vec<(FlashcardDeck, u32)> = due.into_iter().filter_map(|(id, count)| decks.get(&id).map(|d| (d.clone(), count))).collect();
+    due.sort_by_key(|(deck, count)| (std::cmp::Reverse(*count), deck.id));
+    due
+}
+
+fn build_daily_plan(user_id: Principal, now: u64) -> DailyPlan {
+    let mut items = Vec::new();
+    if let Some((course, module)) = next_course_module(user_id) {
+        items.push(PlanItem {
+            kind: "resume_module".to_string(),
+            title: format!("Continue {}", course.topic),
+            detail: format!("Module {}: {}", module.order, module.title),
+            target_id: module.id.to_string(),
+            count: 1,
+            estimated_minutes: PLAN_MODULE_MINUTES,
+        });
+    }
+    
+    let decks = due_flashcards(user_id, now);
+    let total_due: u32 = decks.iter().map(|(_, count)| count).sum();
+    if let Some((deck, _)) = decks.first() {
+        let count = total_due.min(PLAN_MAX_FLASHCARDS);
+        items.push(PlanItem {
+            kind: "flashcards".to_string(),
+            title: format!("Review {} flashcard{}", count, if count == 1 { "" } else { "s" }),
+            detail: if decks.len() > 1 {
+                format!("{} due across {} decks, starting with {}", total_due, decks.len(), deck.name)
+            } else {
+                format!("{} due in {}", total_due, deck.name)
+            },
+            target_id: deck.id.to_string(),
+            count,
+            estimated_minutes: count.div_ceil(2),
+        });
+    }
+    
+    let weakest = SKILL_PROFICIENCY.with(|skills| {
+        skills.borrow()
+            .range(format!("{}:", user_id)..format!("{};", user_id))
+            .map(|(_, p)| p)
+            .filter(|p| p.due_for_review)
+            .min_by(|a, b| decayed_score(a, now).total_cmp(&decayed_score(b, now)))
+    });
+    if let Some(skill) = weakest {
+        items.push(PlanItem {
+            kind: "review_quiz".to_string(),
+            title: format!("Take a quick quiz on {}", skill.skill),
+            detail: format!("Last assessed {} days ago", now.saturating_sub(skill.last_assessed_at) / NANOS_PER_DAY),
+            target_id: skill.skill.clone(),
+            count: 1,
+            estimated_minutes: PLAN_QUIZ_MINUTES,
+        });
+    }
+    
+    let day = local_day(now, user_offset_ns(user_id));
+    DailyPlan {
+        day,
+        total_minutes: items.iter().map(|i| i.estimated_minutes).sum(),
+        items,
+        framing: PLAN_FRAMINGS.with(|framings| framings.borrow().get(&user_id)).filter(|f| f.day == day).map(|f| f.text),
+    }
+}
+
+#[ic_cdk::query]
+fn get_todays_plan() -> Result<DailyPlan, String> {
+    let caller = ic_cdk::caller();
+    cache::user(caller).ok_or("User not found")?;
+    Ok(build_daily_plan(caller, ic_cdk::api::time()))
+}
+
+// Adds the day's framing if it isn't cached yet; an empty plan gets none
+#[ic_cdk::update]
+async fn polish_todays_plan() -> Result<DailyPlan, String> {
+    let caller = ic_cdk::caller();
+    let user = cache::user(caller).ok_or("User not found")?;
+    let plan = build_daily_plan(caller, ic_cdk::api::time());
+    if plan.framing.is_some() || plan.items.is_empty() {
+        return Ok(plan);
+    }
+    rate_limit::check(caller, "generation")?;
+    
+    let items: Vec<String> = plan.items.iter().map(|i| format!("- {} ({})", i.title, i.detail)).collect();
+    let prompt = format!(
+        "Write one or two warm, encouraging sentences to greet {} and introduce today's study plan, which takes about {} minutes:
+        {}
+        
+        Don't list the items again and don't use a greeting like \"Dear\". Return only the sentences.",
+        user.first_name.clone().unwrap_or(user.username.clone()),
+        plan.total_minutes,
+        items.join("\n")
+    );
+    let text = process_ai_response(call_groq_ai(&prompt, "daily_plan", caller).await?, &response_processing_for(caller, "text"));
+    let text = trim_to_length(text.trim(), 400);
+    
+    PLAN_FRAMINGS.with(|framings| framings.borrow_mut().insert(caller, PlanFraming { user_id: caller, day: plan.day, text: text.clone() }));
+    Ok(DailyPlan { framing: Some(text), ..plan })
+}
+
+// --- Milestones ---
+//
+// Completing every module of a course and reaching a 30-day goal streak each earn a milestone
+// once: a short AI-written celebration, sent as a notification, and an achievement card the
+// client renders as an image. Cards are public at /achievement/<public_id> and can be posted
+// to the activity feed.
+
+const STREAK_MILESTONE_DAYS: u32 = 30;
+const STREAK_JOB_INTERVAL_NS: u64 = 10 * 60 * 1_000_000_000;
+const STREAK_BATCH_SIZE: usize = 10;
+
+thread_local! {
+    // Learners who studied since the last run; lost on upgrade, and the next reply queues them again
+    static STREAK_CHECK_QUEUE: RefCell<std::collections::BTreeSet<Principal>> = const { RefCell::new(std::collections::BTreeSet::new()) };
+}
+
+fn has_milestone(user_id: Principal, kind: &str, reference: u64) -> bool {
+    MILESTONES.with(|milestones| {
+        milestones.borrow().iter().any(|(_, m)| m.user_id == user_id && m.kind == kind && m.reference == reference)
+    })
+}
+
+fn user_display_name(user: &User) -> String {
+    user.first_name.clone().filter(|name| !name.trim().is_empty()).unwrap_or_else(|| user.username.clone())
+}
+
+async fn award_milestone(user_id: Principal, kind: &str, reference: u64, achievement: String, stat_label: &str, stat_value: String) -> Result<(), String> {
+    let user = cache::user(user_id).ok_or("User not found")?;
+    let display_name = user_display_name(&user);
+    let public_id = random_public_id("ms").await?;
+    
+    let prompt = format!(
+        "Write a short, upbeat message (at most two sentences) congratulating {} on this learning milestone: {}. Return only the message.",
+        display_name, achievement
+    );
+    let message = match call_groq_ai(&prompt, "milestone", ic_cdk::caller()).await {
+        Ok(response) => trim_to_length(process_ai_response(response, &response_processing_for(ic_cdk::id(), "text")).trim(), 300),
+        Err(e) => {
+            ic_cdk::println!("Milestone message for {} failed: {}", user_id, e);
+            String::new()
+        }
+    };
+    let message = if message.is_empty() { format!("Congratulations, {}! You {}.", display_name, achievement) } else { message };
+    
+    // Re-check after the awaits so overlapping calls don't award it twice
+    if has_milestone(user_id, kind, reference) {
+        return Ok(());
+    }
+    let now = ic_cdk::api::time();
+    let mut milestone = Milestone {
+        id: next_id("milestone"),
+        public_id,
+        user_id,
+        kind: kind.to_string(),
+        reference,
+        card: AchievementCard {
+            template: kind.to_string(),
+            display_name,
+            headline: achievement,
+            message: message.clone(),
+            stat_label: stat_label.to_string(),
+            stat_value,
+            earned_at: now,
+        },
+        post_id: None,
+        created_at: now,
+    };
+    milestone.post_id = publish_activity(user_id, kind, milestone.card.headline.clone(), None, Some(milestone.id), Some(milestone.card.clone()));
+    MILESTONES.with(|milestones| milestones.borrow_mut().insert(milestone.id, milestone.clone()));
+    notify_user(user_id, "milestone", "achievements", message, Some(milestone.id));
+    Ok(())
+}
+
+// Called after each module completion; awards the course once every module is done
+fn course_completed(user_id: Principal, course: &TutorCourse) -> bool {
+    let completed: std::collections::HashSet<u64> = MODULE_COMPLETIONS.with(|completions| {
+        completions.borrow().values().filter(|c| c.user_id == user_id && c.completed).map(|c| c.module_id).collect()
+    });
+    !course.modules.is_empty() && course.modules.iter().all(|m| completed.contains(&m.id))
+}
+
+async fn check_course_milestone(user_id: Principal, module_id: u64) {
+    let Some(course) = TUTOR_COURSES.with(|courses| courses.borrow().values().find(|c| c.modules.iter().any(|m| m.id == module_id))) else {
+        return;
+    };
+    if has_milestone(user_id, "course_completed", course.id) || !course_completed(user_id, &course) {
+        return;
+    }
+    let achievement = format!("completed the course \"{}\"", course.topic);
+    if let Err(e) = award_milestone(user_id, "course_completed", course.id, achievement, "Modules completed", course.modules.len().to_string()).await {
+        ic_cdk::println!("Course milestone for {} failed: {}", user_id, e);
+    }
+}
+
+fn queue_streak_check(user_id: Principal) {
+    STREAK_CHECK_QUEUE.with(|queue| queue.borrow_mut().insert(user_id));
+}
+
+fn run_streak_checks(now: u64) {
+    let batch: Vec<Principal> = STREAK_CHECK_QUEUE.with(|queue| {
+        let mut queue = queue.borrow_mut();
+        let batch: Vec<Principal> = queue.iter().take(STREAK_BATCH_SIZE).copied().collect();
+        for user_id in &batch {
+            queue.remove(user_id);
+        }
+        batch
+    });
+    for user_id in batch {
+        let Some(user) = cache::user(user_id) else {
+            continue;
+        };
+        let goal_minutes = user.settings.daily_goal_hours as u64 * 60;
+        if goal_minutes == 0 {
+            continue;
+        }
+        let offset = user_offset_ns(user_id);
+        let minutes = minutes_by_local_day(user_id, offset);
+        let (streak_days, started) = goal_streak(|day| minutes.get(&day).is_some_and(|m| *m >= goal_minutes), local_day(now, offset));
+        // Keyed by the start day, so a later streak can earn it again
+        if streak_days < STREAK_MILESTONE_DAYS || has_milestone(user_id, "streak_30", started) {
+            continue;
+        }
+        ic_cdk::spawn(async move {
+            let achievement = format!("met their daily study goal {} days in a row", STREAK_MILESTONE_DAYS);
+            if let Err(e) = award_milestone(user_id, "streak_30", started, achievement, "Day streak", STREAK_MILESTONE_DAYS.to_string()).await {
+                ic_cdk::println!("Streak milestone for {} failed: {}", user_id, e);
+            }
+        });
+    }
+}
+
+fn remove_user_milestones(user_id: Principal) {
+    MILESTONES.with(|milestones| {
+        let mut milestones = milestones.borrow_mut();
+        let ids: Vec<u64> = milestones.iter().filter(|(_, m)| m.user_id == user_id).map(|(id, _)| id).collect();
+        for id in ids {
+            milestones.remove(&id);
+        }
+    });
+}
+
+fn achievement_card_html(card: &AchievementCard) -> String {
+    format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>{headline}</title>\
+        <meta property=\"og:title\" content=\"{name} {headline}\"><meta property=\"og:description\" content=\"{message}\"></head>\
+        <body><h1>{name}</h1><p><strong>{headline}</strong></p><p>{message}</p>\
+        <p>{stat_label}: {stat_value}</p></body></html>",
+        name = escape_html(&card.display_name),
+        headline = escape_html(&card.headline),
+        message = escape_html(&card.message),
+        stat_label = escape_html(&card.stat_label),
+        stat_value = escape_html(&card.stat_value)
+    )
+}
+
+fn achievement_response(req: &HttpRequest, query: &str, public_id: &str) -> HttpResponse {
+    let Some(milestone) = MILESTONES.with(|milestones| milestones.borrow().values().find(|m| m.public_id == public_id)) else {
+        return http_not_found();
+    };
+    if wants_html(req, query) {
+        http_response(200, "text/html; charset=utf-8", achievement_card_html(&milestone.card).into_bytes())
+    } else {
+        http_json(200, &milestone.card)
+    }
+}
+
+#[ic_cdk::query]
+fn get_my_milestones() -> Vec<Milestone> {
+    let caller = ic_cdk::caller();
+    MILESTONES.with(|milestones| milestones.borrow().values().filter(|m| m.user_id == caller).collect())
+}
+
+#[ic_cdk::update]
+fn post_milestone_to_feed(milestone_id: u64, text: Option<String>) -> Result<ActivityPost, String> {
+    let caller = ic_cdk::caller();
+    let user = cache::user(caller).ok_or("User not found")?;
+    if user.settings.activity_sharing == "private" {
+        return Err("Activity sharing is turned off in your settings".to_string());
+    }
+    let mut milestone = MILESTONES.with(|milestones| milestones.borrow().get(&milestone_id))
+        .filter(|m| m.user_id == caller)
+        .ok_or("Milestone not found")?;
+    if milestone.post_id.is_some() {
+        return Err("This milestone is already in your feed".to_string());
+    }
+    let text = text.map(|t| t.trim().to_string()).filter(|t| !t.is_empty()).unwrap_or_else(|| milestone.card.headline.clone());
+    if text.chars().count() > MAX_POST_CHARS {
+        return Err(format!("Posts can be at most {} characters", MAX_POST_CHARS));
+    }
+    
+    let post = create_activity_post(caller, &milestone.kind, text, None, Some(milestone_id), Some(milestone.card.clone()));
+    milestone.post_id = Some(post.id);
+    MILESTONES.with(|milestones| milestones.borrow_mut().insert(milestone_id, milestone));
+    Ok(post)
+}
+
+// --- Roles ---
+//
+// A fresh install gets its first admins from the install argument; principals without an
+// account yet are kept in config.pending_admins and promoted when they create one. After that,
+// role changes go through these endpoints, which canister controllers may also call. The
+// last remaining admin can't be demoted.
+
+fn ensure_role_manager(caller: Principal) -> Result<(), String> {
+    if ic_cdk::api::is_controller(&caller) {
+        return Ok(());
+    }
+    require(caller, Permission::ManageRoles)
+}
+
+fn admin_count() -> usize {
+    USERS.with(|users| users.borrow().values().filter(|u| Role::parse(&u.role) == Some(Role::Admin)).count())
+}
+
+fn set_role(caller: Principal, user_id: Principal, role: Role) -> Result<User, String> {
+    let mut user = cache::user(user_id).ok_or("User not found")?;
+    let previous = role_of(user_id);
+    if previous == Role::Admin && role != Role::Admin && admin_count() <= 1 {
+        return Err("This is the last admin; promote someone else first".to_string());
+    }
+    user.role = role.as_str().to_string();
+    user.updated_at = ic_cdk::api::time();
+    cache::store_user(user.clone());
+    record_audit(caller, "assign_role", Some(user_id), format!("{} -> {}", previous.as_str(), role.as_str()));
+    Ok(user)
+}
+
+fn bootstrap_admins(args: Option<InitArgs>) {
+    let caller = ic_cdk::caller();
+    for admin in args.map(|a| a.admins).unwrap_or_default() {
+        if role_of(admin) == Role::Admin {
+            continue;
+        }
+        if cache::user(admin).is_some() {
+            let _ = set_role(caller, admin, Role::Admin);
+        } else {
+            let _ = update_config(|config| {
+                if !config.pending_admins.contains(&admin) {
+                    config.pending_admins.push(admin);
+                }
+                Ok(())
+            });
+        }
+    }
+}
+
+// Run when an account lands on a principal named in the install argument
+fn claim_pending_admin(user_id: Principal) {
+    if !get_config().pending_admins.contains(&user_id) {
+        return;
+    }
+    let _ = update_config(|config| {
+        config.pending_admins.retain(|p| *p != user_id);
+        Ok(())
+    });
+    let _ = set_role(user_id, user_id, Role::Admin);
+}
+
+#[ic_cdk::update]
+fn assign_role_admin(user_id: Principal, role: String) -> Result<User, String> {
+    let caller = ic_cdk::caller();
+    ensure_role_manager(caller)?;
+    let role = Role::parse(role.trim()).ok_or_else(|| format!("Role must be one of: {}", ROLE_NAMES.join(", ")))?;
+    set_role(caller, user_id, role)
+}
+
+#[ic_cdk::update]
+fn add_admin_admin(user_id: Principal) -> Result<User, String> {
+    let caller = ic_cdk::caller();
+    ensure_role_manager(caller)?;
+    set_role(caller, user_id, Role::Admin)
+}
+
+#[ic_cdk::update]
+fn remove_admin_admin(user_id: Principal) -> Result<User, String> {
+    let caller = ic_cdk::caller();
+    ensure_role_manager(caller)?;
+    if role_of(user_id) != Role::Admin {
+        return Err("This user is not an admin".to_string());
+    }
+    set_role(caller, user_id, Role::User)
+}
+
+#[ic_cdk::query]
+fn get_my_permissions() -> Vec<Permission> {
+    role_of(ic_cdk::caller()).permissions()
+}
+
+// --- Activity Feed ---
+//
+// Course completions, streaks, badges and newly public tutors are posted for users who turn
+// on publish_activity; milestones can also be posted by hand. A post reaches the author's
+// active connections unless the author's activity_sharing is "private" or the post itself
+// was made private. Feeds page newest first by post id.
+
+const ACTIVITY_VISIBILITIES: [&str; 2] = ["connections", "private"];
+const ACTIVITY_REACTIONS: [&str; 4] = ["like", "celebrate", "insightful", "support"];
+const MAX_POST_CHARS: usize = 500;
+const MAX_FEED_PAGE: usize = 50;
+
+fn create_activity_post(
+    user_id: Principal,
+    kind: &str,
+    text: String,
+    target_id: Option<String>,
+    milestone_id: Option<u64>,
+    card: Option<AchievementCard>,
+) -> ActivityPost {
+    let post = ActivityPost {
+        id: next_id("activity_post"),
+        user_id,
+        kind: kind.to_string(),
+        text,
+        target_id,
+        milestone_id,
+        card,
+        visibility: "connections".to_string(),
+        reactions: Vec::new(),
+        created_at: ic_cdk::api::time(),
+    };
+    ACTIVITY_POSTS.with(|posts| posts.borrow_mut().insert(post.id, post.clone()));
+    post
+}
+
+// Posts automatically only for users who opted in; returns the new post's id
+fn publish_activity(
+    user_id: Principal,
+    kind: &str,
+    text: String,
+    target_id: Option<String>,
+    milestone_id: Option<u64>,
+    card: Option<AchievementCard>,
+) -> Option<u64> {
+    let user = cache::user(user_id)?;
+    if !user.settings.publish_activity || user.settings.activity_sharing == "private" {
+        return None;
+    }
+    Some(create_activity_post(user_id, kind, text, target_id, milestone_id, card).id)
+}
+
+fn shares_activity(user_id: Principal) -> bool {
+    cache::user(user_id).is_some_and(|u| u.settings.activity_sharing != "private")
+}
+
+fn can_see_activity(post: &ActivityPost, viewer: Principal) -> bool {
+    post.user_id == viewer || (post.visibility == "connections" && are_connected(post.user_id, viewer) && shares_activity(post.user_id))
+}
+
+fn owned_activity_post(post_id: u64, caller: Principal) -> Result<ActivityPost, String> {
+    ACTIVITY_POSTS.with(|posts| posts.borrow().get(&post_id))
+        .filter(|p| p.user_id == caller)
+        .ok_or_else(|| "Post not found".to_string())
+}
+
+fn remove_user_activity(user_id: Principal) {
+    ACTIVITY_POSTS.with(|posts| {
+        let mut posts = posts.borrow_mut();
+        let ids: Vec<u64> = posts.iter().filter(|(_, p)| p.user_id == user_id).map(|(id, _)| id).collect();
+        for id in ids {
+            posts.remove(&id);
+        }
+        let reacted: Vec<ActivityPost> = posts.values().filter(|p| p.reactions.iter().any(|r| r.user_id == user_id)).collect();
+        for mut post in reacted {
+            post.reactions.retain(|r| r.user_id != user_id);
+            posts.insert(post.id, post);
+        }
+    });
+}
+
+#[ic_cdk::update]
+fn set_activity_sharing(activity_sharing: String, publish_activity: bool) -> Result<User, String> {
+    let caller = ic_cdk::caller();
+    if !ACTIVITY_VISIBILITIES.contains(&activity_sharing.as_str()) {
+        return Err(format!("Activity sharing must be one of: {}", ACTIVITY_VISIBILITIES.join(", ")));
+    }
+    
+    let mut user = cache::user(caller).ok_or("User not found")?;
+    user.settings.activity_sharing = activity_sharing;
+    user.settings.publish_activity = publish_activity;
+    user.updated_at = ic_cdk::api::time();
+    cache::store_user(user.clone());
+    Ok(user)
+}
+
+// The caller's own posts and those their connections share with them
+#[ic_cdk::query]
+fn get_my_feed(cursor: Option<u64>, limit: u32) -> Result<ActivityFeedPage, String> {
+    let caller = ic_cdk::caller();
+    let limit = (limit as usize).clamp(1, MAX_FEED_PAGE);
+    let mut authors: std::collections::HashSet<Principal> = CONNECTIONS.with(|connections| {
+        connections.borrow().values()
+            .filter(|c| c.status == "active" && (c.user1_id == caller || c.user2_id == caller))
+            .map(|c| if c.user1_id == caller { c.user2_id } else { c.user1_id })
+            .filter(|id| shares_activity(*id))
+            .collect()
+    });
+    authors.insert(caller);
+    
+    ACTIVITY_POSTS.with(|posts| {
+        let posts = posts.borrow();
+        let mut items: Vec<ActivityPost> = Vec::new();
+        for (scanned, (_, post)) in posts.range(..cursor.unwrap_or(u64::MAX)).rev().enumerate() {
+            guards::scan_checkpoint(scanned + 1, "Request a smaller page.")?;
+            if !authors.contains(&post.user_id) || (post.user_id != caller && post.visibility != "connections") {
+                continue;
+            }
+            if items.len() == limit {
+                return Ok(ActivityFeedPage { next_cursor: items.last().map(|p| p.id), items });
+            }
+            items.push(post);
+        }
+        Ok(ActivityFeedPage { items, next_cursor: None })
+    })
+}
+
+#[ic_cdk::update]
+fn set_activity_visibility(post_id: u64, visibility: String) -> Result<ActivityPost, String> {
+    let caller = ic_cdk::caller();
+    if !ACTIVITY_VISIBILITIES.contains(&visibility.as_str()) {
+        return Err(format!("Visibility must be one of: {}", ACTIVITY_VISIBILITIES.join(", ")));
+    }
+    let mut post = owned_activity_post(post_id, caller)?;
+    post.visibility = visibility;
+    ACTIVITY_POSTS.with(|posts| posts.borrow_mut().insert(post_id, post.clone()));
+    Ok(post)
+}
+
+#[ic_cdk::update]
+fn delete_activity_post(post_id: u64) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    let post = owned_activity_post(post_id, caller)?;
+    ACTIVITY_POSTS.with(|posts| posts.borrow_mut().remove(&post_id));
+    if let Some(mut milestone) = post.milestone_id.and_then(|id| MILESTONES.with(|milestones| milestones.borrow().get(&id))) {
+        milestone.post_id = None;
+        MILESTONES.with(|milestones| milestones.borrow_mut().insert(milestone.id, milestone));
+    }
+    Ok(())
+}
+
+// One reaction per user and post; None takes it back
+#[ic_cdk::update]
+fn react_to_activity(post_id: u64, reaction: Option<String>) -> Result<ActivityPost, String> {
+    let caller = ic_cdk::caller();
+    if let Some(reaction) = &reaction {
+        if !ACTIVITY_REACTIONS.contains(&reaction.as_str()) {
+            return Err(format!("Reaction must be one of: {}", ACTIVITY_REACTIONS.join(", ")));
+        }
+    }
+    let mut post = ACTIVITY_POSTS.with(|posts| posts.borrow().get(&post_id))
+        .filter(|p| can_see_activity(p, caller))
+        .ok_or("Post not found")?;
+    
+    let first_reaction = !post.reactions.iter().any(|r| r.user_id == caller);
+    post.reactions.retain(|r| r.user_id != caller);
+    if let Some(reaction) = reaction {
+        post.reactions.push(ActivityReaction { user_id: caller, reaction, reacted_at: ic_cdk::api::time() });
+        if first_reaction && post.user_id != caller {
+            let name = cache::user(caller).map(|u| user_display_name(&u)).unwrap_or_else(|| "Someone".to_string());
+            notify_user(post.user_id, "activity_reaction", "social", format!("{} reacted to your post", name), Some(post_id));
+        }
+    }
+    ACTIVITY_POSTS.with(|posts| posts.borrow_mut().insert(post_id, post.clone()));
+    Ok(post)
+}
+
+// --- Login Lockout ---
+//
+// Password logins are tracked by email. Too many wrong passwords in a row lock the account
+// for a window that doubles with each further failure; a locked account is refused before
+// the password is checked, so guesses made during the lockout cost nothing and reveal
+// nothing. A successful login clears the streak.
+
+const LOGIN_RECENT_ATTEMPTS: usize = 20;
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+fn login_key(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+fn login_attempts(email: &str) -> LoginAttempts {
+    LOGIN_ATTEMPTS.with(|attempts| attempts.borrow().get(&login_key(email))).unwrap_or_else(|| LoginAttempts {
+        email: login_key(email),
+        failed_count: 0,
+        locked_until: None,
+        last_failed_at: None,
+        last_success_at: None,
+        recent: Vec::new(),
+    })
+}
+
+fn save_login_attempt(mut attempts: LoginAttempts, outcome: &str, now: u64) {
+    attempts.recent.push(LoginAttempt { at: now, caller: ic_cdk::caller(), outcome: outcome.to_string() });
+    let excess = attempts.recent.len().saturating_sub(LOGIN_RECENT_ATTEMPTS);
+    attempts.recent.drain(..excess);
+    LOGIN_ATTEMPTS.with(|map| map.borrow_mut().insert(attempts.email.clone(), attempts));
+}
+
+fn ensure_not_locked(email: &str, now: u64) -> Result<(), String> {
+    if !get_config().login_lockout.enabled {
+        return Ok(());
+    }
+    let attempts = login_attempts(email);
+    let Some(locked_until) = attempts.locked_until.filter(|until| *until > now) else {
+        return Ok(());
+    };
+    save_login_attempt(attempts, "locked", now);
+    let minutes = (locked_until - now).div_ceil(60 * NANOS_PER_SEC);
+    Err(format!("Too many failed login attempts; try again in {} minute{}", minutes, if minutes == 1 { "" } else { "s" }))
+}
+
+fn record_login_failure(email: &str, now: u64) {
+    let config = get_config().login_lockout;
+    let mut attempts = login_attempts(email);
+    if attempts.last_failed_at.is_some_and(|at| now.saturating_sub(at) > config.reset_after_secs.saturating_mul(NANOS_PER_SEC)) {
+        attempts.failed_count = 0;
+    }
+    attempts.failed_count += 1;
+    attempts.last_failed_at = Some(now);
+    if config.enabled && config.max_failures > 0 && attempts.failed_count >= config.max_failures {
+        let doublings = (attempts.failed_count - config.max_failures).min(32);
+        let secs = config.base_lockout_secs.saturating_mul(1u64 << doublings).min(config.max_lockout_secs);
+        attempts.locked_until = Some(now.saturating_add(secs.saturating_mul(NANOS_PER_SEC)));
+    }
+    save_login_attempt(attempts, "invalid_password", now);
+}
+
+fn record_login_success(email: &str, now: u64) {
+    let mut attempts = login_attempts(email);
+    attempts.failed_count = 0;
+    attempts.locked_until = None;
+    attempts.last_success_at = Some(now);
+    save_login_attempt(attempts, "success", now);
+}
+
+// Entries with no lock left and no failures worth remembering
+fn prune_login_attempts(now: u64) {
+    let reset_after = get_config().login_lockout.reset_after_secs.saturating_mul(NANOS_PER_SEC);
+    LOGIN_ATTEMPTS.with(|attempts| {
+        let mut attempts = attempts.borrow_mut();
+        let stale: Vec<String> = attempts.iter()
+            .filter(|(_, a)| a.locked_until.is_none_or(|until| until <= now))
+            .filter(|(_, a)| a.recent.last().is_none_or(|last| now.saturating_sub(last.at) > reset_after))
+            .map(|(key, _)| key)
+            .take(RETENTION_BATCH_SIZE)
+            .collect();
+        for key in stale {
+            attempts.remove(&key);
+        }
+    });
+}
+
+#[ic_cdk::query]
+fn get_login_attempts_admin(email: String) -> Result<Option<LoginAttempts>, String> {
+    require(ic_cdk::caller(), Permission::ManageSupport)?;
+    Ok(LOGIN_ATTEMPTS.with(|attempts| attempts.borrow().get(&login_key(&email))))
+}
+
+#[ic_cdk::update]
+fn clear_login_lockout_admin(email: String) -> Result<LoginAttempts, String> {
+    let caller = ic_cdk::caller();
+    require(caller, Permission::ManageSupport)?;
+    let mut attempts = LOGIN_ATTEMPTS.with(|attempts| attempts.borrow().get(&login_key(&email))).ok_or("No login attempts recorded for this email")?;
+    attempts.failed_count = 0;
+    attempts.locked_until = None;
+    LOGIN_ATTEMPTS.with(|map| map.borrow_mut().insert(attempts.email.clone(), attempts.clone()));
+    let target = USERS.with(|users| users.borrow().values().find(|u| login_key(&u.email) == attempts.email).map(|u| u.id));
+    record_audit(caller, "clear_login_lockout", target, attempts.email.clone());
+    Ok(attempts)
+}
+
+#[ic_cdk::update]
+fn set_login_lockout_config_admin(login_lockout: LoginLockoutConfig) -> Result<CanisterConfig, String> {
+    let caller = ic_cdk::caller();
+    require(caller, Permission::ManageSystem)?;
+    if login_lockout.base_lockout_secs == 0 || login_lockout.max_lockout_secs < login_lockout.base_lockout_secs {
+        return Err("Lockouts need a base of at least 1 second and a maximum no shorter than the base".to_string());
+    }
+    
+    let details = format!(
+        "enabled={} max_failures={} base={}s max={}s reset_after={}s",
+        login_lockout.enabled, login_lockout.max_failures, login_lockout.base_lockout_secs, login_lockout.max_lockout_secs, login_lockout.reset_after_secs
+    );
+    let config = update_config(|config| {
+        config.login_lockout = login_lockout;
+        Ok(())
+    })?;
+    record_audit(caller, "set_login_lockout_config", None, details);
+    Ok(config)
+}
+
+// --- Focus Sessions ---
+//
+// A learner commits to one chat session or course module for a set time. While it runs,
+// pushes are held as in quiet hours and review reminders wait. Messages in the declared
+// session and completing the declared module count as activity; when time is up the session
+// is kept if activity never paused longer than max_idle_minutes. Kept sessions earn points,
+// with a bonus for a run of days with one, and every outcome is added to the week's totals.
+
+const FOCUS_JOB_INTERVAL_NS: u64 = 5 * 60 * 1_000_000_000;
+const MAX_FOCUS_GOAL_CHARS: usize = 200;
+const MAX_FOCUS_WEEKS: u32 = 52;
+
+fn focus_key(user_id: Principal, id: u64) -> String {
+    format!("{}:{:020}", user_id, id)
+}
+
+// Only the newest session can be active
+fn active_focus(user_id: Principal) -> Option<FocusSession> {
+    FOCUS_SESSIONS.with(|sessions| {
+        sessions.borrow().range(format!("{}:", user_id)..format!("{};", user_id)).next_back().map(|(_, f)| f)
+    }).filter(|f| f.status == "active")
+}
+
+fn focus_ends_at(user_id: Principal, now: u64) -> Option<u64> {
+    active_focus(user_id).map(|f| f.ends_at).filter(|ends_at| *ends_at > now)
+}
+
+fn save_focus(focus: &FocusSession) {
+    FOCUS_SESSIONS.with(|sessions| sessions.borrow_mut().insert(focus_key(focus.user_id, focus.id), focus.clone()));
+}
+
+fn record_focus_activity(user_id: Principal, chat_session_id: Option<&str>, module_id: Option<u64>, now: u64) {
+    let Some(mut focus) = active_focus(user_id).filter(|f| now < f.ends_at) else {
+        return;
+    };
+    let on_session = chat_session_id.is_some() && focus.chat_session_id.as_deref() == chat_session_id;
+    let on_module = module_id.is_some() && focus.module_id == module_id;
+    if on_session || on_module {
+        let since = focus.last_activity_at.unwrap_or(focus.started_at);
+        focus.longest_gap_ns = focus.longest_gap_ns.max(now.saturating_sub(since));
+        focus.activity_count += 1;
+        focus.last_activity_at = Some(now);
+    } else if chat_session_id.is_some() {
+        focus.off_target_count += 1;
+    } else {
+        return;
+    }
+    save_focus(&focus);
+}
+
+// Local days with a kept session
+fn kept_focus_days(user_id: Principal, offset: i64) -> std::collections::HashSet<u64> {
+    FOCUS_SESSIONS.with(|sessions| {
+        sessions.borrow().range(format!("{}:", user_id)..format!("{};", user_id))
+            .map(|(_, f)| f)
+            .filter(|f| f.status == "kept")
+            .map(|f| local_day(f.ends_at, offset))
+            .collect()
+    })
+}
+
+// Monday-based; local day 0 was a Thursday
+fn focus_week(day: u64) -> u64 {
+    (day + 3) / 7
+}
+
+fn record_focus_week(focus: &FocusSession) {
+    let week = focus_week(local_day(focus.started_at, user_offset_ns(focus.user_id)));
+    let key = format!("{}:{:010}", focus.user_id, week);
+    let mut totals = FOCUS_WEEKS.with(|weeks| weeks.borrow().get(&key)).unwrap_or(FocusWeek {
+        user_id: focus.user_id,
+        week,
+        week_start: (week * 7).saturating_sub(3),
+        sessions: 0,
+        kept: 0,
+        broken: 0,
+        abandoned: 0,
+        focus_minutes: 0,
+        points: 0,
+    });
+    totals.sessions += 1;
+    match focus.status.as_str() {
+        "kept" => {
+            totals.kept += 1;
+            totals.focus_minutes += focus.ends_at.saturating_sub(focus.started_at) / (60 * NANOS_PER_SEC);
+        }
+        "broken" => totals.broken += 1,
+        _ => totals.abandoned += 1,
+    }
+    totals.points = totals.points.saturating_add(focus.points_awarded);
+    FOCUS_WEEKS.with(|weeks| weeks.borrow_mut().insert(key, totals));
+}
+
+fn finish_focus(mut focus: FocusSession, now: u64) -> FocusSession {
+    let config = get_config().focus;
+    let final_gap = focus.ends_at.saturating_sub(focus.last_activity_at.unwrap_or(focus.started_at));
+    focus.longest_gap_ns = focus.longest_gap_ns.max(final_gap);
+    let kept = focus.activity_count > 0 && focus.longest_gap_ns <= config.max_idle_minutes as u64 * 60 * NANOS_PER_SEC;
+    focus.ended_at = Some(focus.ends_at);
+    
+    let message = if kept {
+        let offset = user_offset_ns(focus.user_id);
+        let kept_days = kept_focus_days(focus.user_id, offset);
+        let (streak_days, _) = goal_streak(|day| kept_days.contains(&day), local_day(focus.ends_at, offset));
+        let bonus = config.streak_bonus_points.saturating_mul(streak_days.min(config.max_streak_bonus_days));
+        focus.points_awarded = score_event_points(focus.user_id, config.points_per_session.saturating_add(bonus), now);
+        focus.status = "kept".to_string();
+        format!("You kept your focus session \"{}\" and earned {} points", focus.goal, focus.points_awarded)
+    } else {
+        focus.status = "broken".to_string();
+        format!("Your focus session \"{}\" has ended; there were long stretches without activity on it", focus.goal)
+    };
+    save_focus(&focus);
+    record_focus_week(&focus);
+    notify_user(focus.user_id, "focus", "focus", message, Some(focus.id));
+    focus
+}
+
+fn run_focus_checks(now: u64) {
+    let due: Vec<FocusSession> = FOCUS_SESSIONS.with(|sessions| {
+        sessions.borrow().values().filter(|f| f.status == "active" && f.ends_at <= now).take(RETENTION_BATCH_SIZE).collect()
+    });
+    for focus in due {
+        finish_focus(focus, now);
+    }
+}
+
+fn remove_user_focus(user_id: Principal) {
+    FOCUS_SESSIONS.with(|sessions| {
+        let mut sessions = sessions.borrow_mut();
+        let keys: Vec<String> = sessions.range(format!("{}:", user_id)..format!("{};", user_id)).map(|(key, _)| key).collect();
+        for key in keys {
+            sessions.remove(&key);
+        }
+    });
+    FOCUS_WEEKS.with(|weeks| {
+        let mut weeks = weeks.borrow_mut();
+        let keys: Vec<String> = weeks.range(format!("{}:", user_id)..format!("{};", user_id)).map(|(key, _)| key).collect();
+        for key in keys {
+            weeks.remove(&key);
+        }
+    });
+}
+
+#[ic_cdk::update]
+fn start_focus_session(duration_minutes: u32, goal: String, chat_session_id: Option<String>, module_id: Option<u64>) -> Result<FocusSession, String> {
+    let caller = ic_cdk::caller();
+    cache::user(caller).ok_or("User not found")?;
+    let config = get_config().focus;
+    if duration_minutes < config.min_minutes || duration_minutes > config.max_minutes {
+        return Err(format!("Focus sessions last between {} and {} minutes", config.min_minutes, config.max_minutes));
+    }
+    let goal = goal.trim().to_string();
+    if goal.is_empty() || goal.chars().count() > MAX_FOCUS_GOAL_CHARS {
+        return Err(format!("A goal of up to {} characters is required", MAX_FOCUS_GOAL_CHARS));
+    }
+    if chat_session_id.is_none() && module_id.is_none() {
+        return Err("Choose a chat session or course module to focus on".to_string());
+    }
+    if let Some(session_id) = &chat_session_id {
+        participant_session(session_id, caller)?;
+    }
+    if let Some(module_id) = module_id {
+        let exists = TUTOR_COURSES.with(|courses| courses.borrow().values().any(|c| c.modules.iter().any(|m| m.id == module_id)));
+        if !exists {
+            return Err("Module not found".to_string());
+        }
+    }
+    
+    let now = ic_cdk::api::time();
+    if let Some(focus) = active_focus(caller) {
+        if focus.ends_at > now {
+            return Err("A focus session is already running".to_string());
+        }
+        finish_focus(focus, now);
+    }
+    let focus = FocusSession {
+        id: next_id("focus_session"),
+        user_id: caller,
+        goal,
+        chat_session_id,
+        module_id,
+        started_at: now,
+        ends_at: now + duration_minutes as u64 * 60 * NANOS_PER_SEC,
+        status: "active".to_string(),
+        activity_count: 0,
+        off_target_count: 0,
+        last_activity_at: None,
+        longest_gap_ns: 0,
+        points_awarded: 0,
+        ended_at: None,
+    };
+    save_focus(&focus);
+    Ok(focus)
+}
+
+// Stopping early abandons the session; after the end time it is scored as usual
+#[ic_cdk::update]
+fn end_focus_session() -> Result<FocusSession, String> {
+    let caller = ic_cdk::caller();
+    let mut focus = active_focus(caller).ok_or("No focus session is running")?;
+    let now = ic_cdk::api::time();
+    if focus.ends_at <= now {
+        return Ok(finish_focus(focus, now));
+    }
+    focus.status = "abandoned".to_string();
+    focus.ended_at = Some(now);
+    save_focus(&focus);
+    record_focus_week(&focus);
+    Ok(focus)
+}
+
+#[ic_cdk::query]
+fn get_focus_stats(weeks: u32) -> Result<FocusStats, String> {
+    let caller = ic_cdk::caller();
+    cache::user(caller).ok_or("User not found")?;
+    let offset = user_offset_ns(caller);
+    let kept_days = kept_focus_days(caller, offset);
+    let (streak_days, _) = goal_streak(|day| kept_days.contains(&day), local_day(ic_cdk::api::time(), offset));
+    Ok(FocusStats {
+        active: active_focus(caller),
+        streak_days,
+        weeks: FOCUS_WEEKS.with(|totals| {
+            totals.borrow().range(format!("{}:", caller)..format!("{};", caller))
+                .rev()
+                .take(weeks.clamp(1, MAX_FOCUS_WEEKS) as usize)
+                .map(|(_, w)| w)
+                .collect()
+        }),
+    })
+}
+
+#[ic_cdk::update]
+fn set_focus_config_admin(focus: FocusConfig) -> Result<CanisterConfig, String> {
+    let caller = ic_cdk::caller();
+    require(caller, Permission::ManageSystem)?;
+    if focus.min_minutes == 0 || focus.max_minutes < focus.min_minutes {
+        return Err("Focus sessions need a minimum of at least 1 minute and a maximum no shorter than it".to_string());
+    }
+    
+    let details = format!(
+        "minutes={}-{} max_idle={} points={} streak_bonus={}x{}",
+        focus.min_minutes, focus.max_minutes, focus.max_idle_minutes, focus.points_per_session, focus.streak_bonus_points, focus.max_streak_bonus_days
+    );
+    let config = update_config(|config| {
+        config.focus = focus;
+        Ok(())
+    })?;
+    record_audit(caller, "set_focus_config", None, details);
+    Ok(config)
+}
+
+// --- Question of the Day ---
+//
+// Once a UTC day the job picks the skills the most learners have proficiency in and prepares
+// one multiple-choice question for each. It reuses an earlier day's question for the area once
+// that question is outside the repeat window, then tries the placement question bank (with an
+// AI-written explanation), and only generates a new question when neither has one. Stored
+// questions are the cache, so areas with some history rarely need an AI call. One answer per
+// question; the correct option, explanation and how others did are shown after answering.
+
+const DAILY_QUESTION_JOB_INTERVAL_NS: u64 = 60 * 60 * 1_000_000_000;
+const MAX_DAILY_QUESTION_AREAS: u32 = 20;
+const MAX_DAILY_EXPLANATION_CHARS: usize = 600;
+
+thread_local! {
+    // Questions whose AI call is still running, so the next job run doesn't start another
+    static DAILY_QUESTIONS_PREPARING: RefCell<std::collections::HashSet<String>> = RefCell::new(std::collections::HashSet::new());
+}
+
+#[derive(serde::Deserialize)]
+struct AiDailyQuestion {
+    question: String,
+    options: Vec<String>,
+    correct_option: u32,
+    explanation: String,
+}
+
+fn daily_question_key(day: u64, area: &str) -> String {
+    format!("{:010}:{}", day, area)
+}
+
+fn daily_answer_key(user_id: Principal, day: u64, area: &str) -> String {
+    format!("{}:{:010}:{}", user_id, day, area)
+}
+
+// Most learners first, ties by name
+fn popular_skill_areas(min_learners: u32) -> Vec<String> {
+    let mut counts: HashMap<String, u32> = HashMap::new();
+    SKILL_PROFICIENCY.with(|skills| {
+        for (_, proficiency) in skills.borrow().iter() {
+            *counts.entry(proficiency.skill.trim().to_lowercase()).or_default() += 1;
+        }
+    });
+    let mut areas: Vec<(String, u32)> = counts.into_iter()
+        .filter(|(area, count)| !area.is_empty() && *count >= min_learners.max(1))
+        .collect();
+    areas.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    areas.into_iter().map(|(area, _)| area).collect()
+}
+
+fn daily_questions_for(day: u64) -> Vec<DailyQuestion> {
+    DAILY_QUESTIONS.with(|questions| {
+        questions.borrow().range(format!("{:010}:", day)..format!("{:010};", day)).map(|(_, q)| q).collect()
+    })
+}
+
+fn run_daily_questions(now: u64) {
+    let config = get_config().daily_questions;
+    if !config.enabled {
+        return;
+    }
+    let day = now / NANOS_PER_DAY;
+    let prepared: Vec<String> = daily_questions_for(day).into_iter().map(|q| q.area).collect();
+    let missing = (config.areas_per_day as usize).saturating_sub(prepared.len());
+    let areas: Vec<String> = popular_skill_areas(config.min_learners).into_iter()
+        .filter(|area| !prepared.contains(area))
+        .take(missing)
+        .collect();
+
+    for area in areas {
+        let key = daily_question_key(day, &area);
+        if !DAILY_QUESTIONS_PREPARING.with(|preparing| preparing.borrow_mut().insert(key.clone())) {
+            continue;
+        }
+        let repeat_after_days = config.repeat_after_days;
+        ic_cdk::spawn(async move {
+            match prepare_daily_question(day, &area, repeat_after_days).await {
+                Ok(question) => DAILY_QUESTIONS.with(|questions| {
+                    let mut questions = questions.borrow_mut();
+                    if !questions.contains_key(&key) {
+                        questions.insert(key.clone(), question);
+                    }
+                }),
+                Err(e) => ic_cdk::println!("Question of the day for {} failed: {}", area, e),
+            }
+            DAILY_QUESTIONS_PREPARING.with(|preparing| preparing.borrow_mut().remove(&key));
+        });
+    }
+}
+
+async fn prepare_daily_question(day: u64, area: &str, repeat_after_days: u32) -> Result<DailyQuestion, String> {
+    let now = ic_cdk::api::time();
+    let cutoff = day.saturating_sub(repeat_after_days.max(1) as u64);
+    let mut recent: std::collections::HashSet<String> = std::collections::HashSet::new();
+    let mut earlier: Vec<DailyQuestion> = Vec::new();
+    DAILY_QUESTIONS.with(|questions| {
+        for (_, question) in questions.borrow().iter().filter(|(_, q)| q.area == area) {
+            if question.day >= cutoff {
+                recent.insert(question.question.clone());
+            } else {
+                earlier.push(question);
+            }
+        }
+    });
+
+    earlier.retain(|q| !recent.contains(&q.question));
+    if !earlier.is_empty() {
+        let question = earlier.swap_remove(day as usize % earlier.len());
+        return Ok(DailyQuestion {
+            day,
+            source: "reused".to_string(),
+            created_at: now,
+            answers: 0,
+            correct_answers: 0,
+            ..question
+        });
+    }
+
+    let bank: Vec<ExamQuestion> = question_bank(area).into_iter().filter(|q| !recent.contains(&q.question)).collect();
+    if !bank.is_empty() {
+        let question = &bank[day as usize % bank.len()];
+        let answer = question.options.get(question.correct_option as usize).cloned().unwrap_or_default();
+        let prompt = format!(
+            "Explain in two or three sentences why \"{}\" is the correct answer to this question on {}: {}
+
+            Return only the explanation.",
+            answer, area, question.question
+        );
+        let explanation = match call_groq_ai(&prompt, "daily_question", ic_cdk::id()).await {
+            Ok(response) => trim_to_length(process_ai_response(response, &response_processing_for(ic_cdk::id(), "text")).trim(), MAX_DAILY_EXPLANATION_CHARS),
+            Err(e) => {
+                ic_cdk::println!("Explanation for the {} question of the day failed: {}", area, e);
+                format!("The correct answer is: {}", answer)
+            }
+        };
+        return Ok(DailyQuestion {
+            day,
+            area: area.to_string(),
+            question: question.question.clone(),
+            options: question.options.clone(),
+            correct_option: question.correct_option,
+            explanation,
+            source: "question_bank".to_string(),
+            created_at: now,
+            answers: 0,
+            correct_answers: 0,
+        });
+    }
+
+    let prompt = format!(
+        "Write one multiple-choice question on '{}' that a learner can answer in under a minute.
+
+        Return ONLY a JSON object:
+        {{\"question\":\"Question\",\"options\":[\"a\",\"b\",\"c\",\"d\"],\"correct_option\":0,\"explanation\":\"Why the answer is correct, in two or three sentences\"}}",
+        area
+    );
+    let response = process_ai_response(call_groq_ai(&prompt, "daily_question", ic_cdk::id()).await?, &response_processing_for(ic_cdk::id(), "json"));
+    let generated: AiDailyQuestion = serde_json::from_str(&response).map_err(|e| format!("Failed to parse the generated question: {}", e))?;
+    if generated.question.trim().is_empty() || generated.options.len() < 2 || generated.correct_option as usize >= generated.options.len() {
+        return Err("The generated question was incomplete".to_string());
+    }
+    Ok(DailyQuestion {
+        day,
+        area: area.to_string(),
+        question: generated.question.trim().to_string(),
+        options: generated.options,
+        correct_option: generated.correct_option,
+        explanation: trim_to_length(generated.explanation.trim(), MAX_DAILY_EXPLANATION_CHARS),
+        source: "ai".to_string(),
+        created_at: now,
+        answers: 0,
+        correct_answers: 0,
+    })
+}
+
+fn daily_question_view(question: DailyQuestion, answer: Option<DailyAnswer>) -> DailyQuestionView {
+    let answered = answer.is_some();
+    DailyQuestionView {
+        day: question.day,
+        area: question.area,
+        question: question.question,
+        options: question.options,
+        answer,
+        correct_option: answered.then_some(question.correct_option),
+        explanation: answered.then_some(question.explanation),
+        answers: answered.then_some(question.answers),
+        correct_answers: answered.then_some(question.correct_answers),
+    }
+}
+
+fn record_daily_question_stats(answer: &DailyAnswer) {
+    let mut stats = DAILY_QUESTION_STATS.with(|stats| stats.borrow().get(&answer.user_id)).unwrap_or(DailyQuestionStats {
+        user_id: answer.user_id,
+        answered: 0,
+        correct: 0,
+        points: 0,
+        current_streak: 0,
+        best_streak: 0,
+        last_day: 0,
+    });
+    if stats.answered == 0 || answer.day > stats.last_day + 1 {
+        stats.current_streak = 1;
+    } else if answer.day == stats.last_day + 1 {
+        stats.current_streak += 1;
+    }
+    stats.best_streak = stats.best_streak.max(stats.current_streak);
+    stats.last_day = answer.day;
+    stats.answered += 1;
+    if answer.correct {
+        stats.correct += 1;
+    }
+    stats.points = stats.points.saturating_add(answer.points);
+    DAILY_QUESTION_STATS.with(|map| map.borrow_mut().insert(answer.user_id, stats));
+}
+
+// A streak is still alive today if yesterday had an answer
+fn daily_question_streak(stats: &DailyQuestionStats, today: u64) -> u32 {
+    if stats.last_day + 1 >= today { stats.current_streak } else { 0 }
+}
+
+fn remove_user_daily_answers(user_id: Principal) {
+    DAILY_ANSWERS.with(|answers| {
+        let mut answers = answers.borrow_mut();
+        let keys: Vec<String> = answers.range(format!("{}:", user_id)..format!("{};", user_id)).map(|(key, _)| key).collect();
+        for key in keys {
+            answers.remove(&key);
+        }
+    });
+    DAILY_QUESTION_STATS.with(|stats| stats.borrow_mut().remove(&user_id));
+}
+
+#[ic_cdk::query]
+fn get_daily_questions() -> Result<Vec<DailyQuestionView>, String> {
+    let caller = ic_cdk::caller();
+    cache::user(caller).ok_or("User not found")?;
+    let day = ic_cdk::api::time() / NANOS_PER_DAY;
+    Ok(daily_questions_for(day).into_iter().map(|question| {
+        let answer = DAILY_ANSWERS.with(|answers| answers.borrow().get(&daily_answer_key(caller, day, &question.area)));
+        daily_question_view(question, answer)
+    }).collect())
+}
+
+#[ic_cdk::update]
+fn answer_daily_question(area: String, selected_option: u32) -> Result<DailyQuestionView, String> {
+    let caller = ic_cdk::caller();
+    cache::user(caller).ok_or("User not found")?;
+    let now = ic_cdk::api::time();
+    let day = now / NANOS_PER_DAY;
+    let area = area.trim().to_lowercase();
+    let key = daily_question_key(day, &area);
+    let mut question = DAILY_QUESTIONS.with(|questions| questions.borrow().get(&key)).ok_or("There is no question of the day for this area")?;
+    if selected_option as usize >= question.options.len() {
+        return Err("Choose one of the question's options".to_string());
+    }
+    let answer_key = daily_answer_key(caller, day, &area);
+    if DAILY_ANSWERS.with(|answers| answers.borrow().contains_key(&answer_key)) {
+        return Err("You've already answered today's question for this area".to_string());
+    }
+
+    let config = get_config().daily_questions;
+    let correct = selected_option == question.correct_option;
+    let base_points = config.points_per_answer.saturating_add(if correct { config.points_per_correct } else { 0 });
+    let answer = DailyAnswer {
+        user_id: caller,
+        day,
+        area,
+        selected_option,
+        correct,
+        points: score_event_points(caller, base_points, now),
+        answered_at: now,
+    };
+    question.answers += 1;
+    if correct {
+        question.correct_answers += 1;
+    }
+    DAILY_QUESTIONS.with(|questions| questions.borrow_mut().insert(key, question.clone()));
+    DAILY_ANSWERS.with(|answers| answers.borrow_mut().insert(answer_key, answer.clone()));
+    record_daily_question_stats(&answer);
+    Ok(daily_question_view(question, Some(answer)))
+}
+
+fn daily_question_entry(rank: u32, stats: &DailyQuestionStats, today: u64) -> DailyQuestionLeaderboardEntry {
+    let user = cache::user(stats.user_id);
+    let public = user.as_ref().is_some_and(|u| u.settings.profile_visibility != "private");
+    DailyQuestionLeaderboardEntry {
+        rank,
+        user_id: public.then_some(stats.user_id),
+        username: user.filter(|_| public).map(|u| u.username).unwrap_or_else(|| "Anonymous learner".to_string()),
+        points: stats.points,
+        answered: stats.answered,
+        correct: stats.correct,
+        current_streak: daily_question_streak(stats, today),
+        best_streak: stats.best_streak,
+    }
+}
+
+// Ranked by points, then by correct answers
+#[ic_cdk::query]
+fn get_daily_question_leaderboard(limit: u32) -> DailyQuestionLeaderboard {
+    let caller = ic_cdk::caller();
+    let today = ic_cdk::api::time() / NANOS_PER_DAY;
+    let mut standings: Vec<DailyQuestionStats> = DAILY_QUESTION_STATS.with(|stats| stats.borrow().iter().map(|(_, s)| s).collect());
+    standings.sort_by_key(|s| (std::cmp::Reverse(s.points), std::cmp::Reverse(s.correct)));
+
+    let own = standings.iter().position(|s| s.user_id == caller).map(|i| daily_question_entry(i as u32 + 1, &standings[i], today));
+    let entries = standings.iter().take(limit.clamp(1, 100) as usize).enumerate()
+        .map(|(i, s)| daily_question_entry(i as u32 + 1, s, today))
+        .collect();
+    DailyQuestionLeaderboard { entries, own }
+}
+
+#[ic_cdk::update]
+fn set_daily_question_config_admin(daily_questions: DailyQuestionConfig) -> Result<CanisterConfig, String> {
+    let caller = ic_cdk::caller();
+    require(caller, Permission::ManageSystem)?;
+    if daily_questions.areas_per_day == 0 || daily_questions.areas_per_day > MAX_DAILY_QUESTION_AREAS {
+        return Err(format!("Areas per day must be between 1 and {}", MAX_DAILY_QUESTION_AREAS));
+    }
+    if daily_questions.repeat_after_days == 0 {
+        return Err("Questions need at least a day before they can repeat".to_string());
+    }
+
+    let details = format!(
+        "enabled={} areas={} min_learners={} repeat_after={}d points={}+{}",
+        daily_questions.enabled, daily_questions.areas_per_day, daily_questions.min_learners, daily_questions.repeat_after_days,
+        daily_questions.points_per_answer, daily_questions.points_per_correct
+    );
+    let config = update_config(|config| {
+        config.daily_questions = daily_questions;
+        Ok(())
+    })?;
+    record_audit(caller, "set_daily_question_config", None, details);
+    Ok(config)
+}
+
+// --- Account Deletion ---
+//
+// Removes an account and everything it owns in one call. It starts from the same removal
+// used when a user moves shards, then deletes the learning records, memberships and
+// connections that stay behind on a move. Study groups the user created pass to another
+// member, or go with their content when nobody else is left. Billing records, support
+// tickets, feedback, certificates and the audit log are kept, as is the user's principal on
+// records of staff actions they took. Accounts under a retention hold can't be deleted.
+
+// Deletes the user's records from a map whose values carry user_id; returns how many went
+macro_rules! remove_owned {
+    ($map:ident, $user:expr) => {
+        $map.with(|map| {
+            let mut map = map.borrow_mut();
+            let owned: Vec<_> = map.iter().filter(|(_, record)| record.user_id == $user).map(|(key, _)| key).collect();
+            for key in &owned {
+                map.remove(key);
+            }
+            owned.len() as u64
+        })
+    };
+}
+
+// Removes a group that has nobody left, with its members, resources and decks
+fn remove_study_group(group: &StudyGroup) {
+    let _ = assign_slug("study_group", &group.public_id, group.slug.as_deref(), None);
+    STUDY_GROUPS.with(|groups| groups.borrow_mut().remove(&group.id));
+    GROUP_MEMBERSHIPS.with(|memberships| {
+        let mut memberships = memberships.borrow_mut();
+        let ids: Vec<u64> = memberships.iter().filter(|(_, m)| m.group_id == group.id).map(|(id, _)| id).collect();
+        for id in ids {
+            memberships.remove(&id);
+        }
+    });
+    STUDY_RESOURCES.with(|resources| {
+        let mut resources = resources.borrow_mut();
+        let ids: Vec<u64> = resources.iter().filter(|(_, r)| r.group_id == group.id).map(|(id, _)| id).collect();
+        for id in ids {
+            resources.remove(&id);
+        }
+    });
+    let decks: Vec<u64> = FLASHCARD_DECKS.with(|decks| decks.borrow().iter().filter(|(_, d)| d.group_id == group.id).map(|(id, _)| id).collect());
+    for deck_id in decks {
+        FLASHCARD_DECKS.with(|d| d.borrow_mut().remove(&deck_id));
+        for card in deck_cards(deck_id) {
+            DECK_CARDS.with(|cards| cards.borrow_mut().remove(&card.id));
+        }
+        CARD_SCHEDULES.with(|schedules| {
+            let mut schedules = schedules.borrow_mut();
+            let keys: Vec<String> = schedules.range(format!("{:020}:", deck_id)..format!("{:020};", deck_id)).map(|(key, _)| key).collect();
+            for key in keys {
+                schedules.remove(&key);
+            }
+        });
+    }
+}
+
+// Groups pass to their admins first, then moderators, then whoever joined earliest
+fn hand_over_study_groups(user_id: Principal, now: u64) {
+    let created: Vec<StudyGroup> = STUDY_GROUPS.with(|groups| groups.borrow().values().filter(|g| g.creator_id == user_id).collect());
+    for mut group in created {
+        let successor = GROUP_MEMBERSHIPS.with(|memberships| {
+            memberships.borrow()
+                .values()
+                .filter(|m| m.group_id == group.id && m.user_id != user_id && m.status == "active")
+                .min_by_key(|m| (m.role != "admin", m.role != "moderator", m.joined_at))
+        });
+        let Some(mut membership) = successor else {
+            remove_study_group(&group);
+            continue;
+        };
+        group.creator_id = membership.user_id;
+        group.updated_at = now;
+        membership.role = "admin".to_string();
+        STUDY_GROUPS.with(|groups| groups.borrow_mut().insert(group.id, group.clone()));
+        GROUP_MEMBERSHIPS.with(|memberships| memberships.borrow_mut().insert(membership.id, membership.clone()));
+        notify_user(membership.user_id, "info", "groups", format!("You now run {}; its creator deleted their account", group.name), Some(group.id));
+    }
+}
+
+fn delete_user_account(user_id: Principal, deleted_by: Principal) -> Result<AccountDeletionSummary, String> {
+    let user = cache::user(user_id).ok_or("User not found")?;
+    if get_config().retention_holds.iter().any(|h| h.user_id == user_id) {
+        return Err("This account is under a retention hold and can't be deleted until the hold is lifted".to_string());
+    }
+    if role_of(user_id) == Role::Admin && admin_count() <= 1 {
+        return Err("This is the last admin; promote someone else first".to_string());
+    }
+    let unsettled = PEER_BOOKINGS.with(|bookings| {
+        bookings.borrow().values().any(|b| {
+            (b.tutor_id == user_id || b.learner_id == user_id)
+                && matches!(b.escrow.as_str(), "awaiting_payment" | "held" | "releasing" | "refunding")
+        })
+    });
+    if unsettled {
+        return Err("This account has tutoring bookings whose payments haven't been settled yet".to_string());
+    }
+
+    let bundle = export_user_data(user_id);
+    let tutor_ids: Vec<u64> = bundle.tutors.iter().map(|t| t.id).collect();
+    let mut summary = AccountDeletionSummary {
+        user_id,
+        tutors: bundle.tutors.len() as u64,
+        tutor_courses: 0,
+        chat_sessions: bundle.chat_sessions.len() as u64,
+        chat_messages: bundle.chat_messages.iter().map(|(_, list)| list.len() as u64).sum(),
+        knowledge_base_files: bundle.knowledge_base_files.len() as u64,
+        learning_progress: 0,
+        learning_metrics: 0,
+        module_completions: 0,
+        group_memberships: 0,
+        cohort_enrollments: 0,
+        connections: 0,
+        connection_requests: 0,
+        notifications: 0,
+        achievements: 0,
+        deleted_at: ic_cdk::api::time(),
+    };
+
+    // What deleting a tutor normally leaves for the end of its undo window
+    for tutor in &bundle.tutors {
+        remove_entity_tags("tutor", &tutor.public_id);
+        remove_knowledge_base_links(tutor.id);
+        remove_persona_evaluations(tutor.id);
+    }
+    let courses: Vec<u64> = TUTOR_COURSES.with(|courses| {
+        courses.borrow().iter().filter(|(_, c)| tutor_ids.contains(&c.tutor_id)).map(|(id, _)| id).collect()
+    });
+    for course_id in &courses {
+        TUTOR_COURSES.with(|c| c.borrow_mut().remove(course_id));
+        remove_course_glossary(*course_id);
+    }
+    summary.tutor_courses = courses.len() as u64;
+    let staged: Vec<u64> = UNDO_QUEUE.with(|queue| {
+        queue.borrow().iter().filter(|(_, s)| s.action.user_id == user_id).map(|(id, _)| id).collect()
+    });
+    for id in staged {
+        if let Some(staged) = UNDO_QUEUE.with(|queue| queue.borrow_mut().remove(&id)) {
+            finalize_staged_action(staged);
+        }
+    }
+    remove_owned!(PENDING_DELIVERIES, user_id);
+
+    remove_user_data(&bundle);
+    summary.learning_progress = remove_owned!(LEARNING_PROGRESS, user_id);
+    summary.learning_metrics = remove_owned!(LEARNING_METRICS, user_id) + remove_owned!(METRICS_AGGREGATES, user_id);
+    summary.module_completions = remove_owned!(MODULE_COMPLETIONS, user_id);
+    summary.group_memberships = remove_owned!(GROUP_MEMBERSHIPS, user_id);
+    summary.cohort_enrollments = remove_owned!(COHORT_ENROLLMENTS, user_id);
+    summary.notifications = remove_owned!(NOTIFICATIONS, user_id);
+    summary.achievements = remove_owned!(USER_ACHIEVEMENTS, user_id);
+    remove_owned!(USER_TASK_COMPLETIONS, user_id);
+    remove_owned!(PLACEMENT_TESTS, user_id);
+    remove_owned!(EXAMS, user_id);
+    remove_owned!(ANNOUNCEMENT_DISMISSALS, user_id);
+    remove_owned!(EVENT_PARTICIPATION, user_id);
+    remove_owned!(ORG_MEMBERS, user_id);
+    remove_owned!(LEARNER_RISK_FLAGS, user_id);
+    PEER_TUTORS.with(|tutors| tutors.borrow_mut().remove(&user_id));
+    PEER_SLOTS.with(|slots| {
+        let mut slots = slots.borrow_mut();
+        let owned: Vec<u64> = slots.iter().filter(|(_, s)| s.tutor_id == user_id).map(|(id, _)| id).collect();
+        for id in owned {
+            slots.remove(&id);
+        }
+    });
+    SKILL_PROFICIENCY.with(|skills| {
+        let mut skills = skills.borrow_mut();
+        let keys: Vec<String> = skills.range(format!("{}:", user_id)..format!("{};", user_id)).map(|(key, _)| key).collect();
+        for key in keys {
+            skills.remove(&key);
+        }
+    });
+    summary.connections = CONNECTIONS.with(|connections| {
+        let mut connections = connections.borrow_mut();
+        let owned: Vec<u64> = connections.iter().filter(|(_, c)| c.user1_id == user_id || c.user2_id == user_id).map(|(id, _)| id).collect();
+        for id in &owned {
+            connections.remove(id);
+        }
+        owned.len() as u64
+    });
+    summary.connection_requests = CONNECTION_REQUESTS.with(|requests| {
+        let mut requests = requests.borrow_mut();
+        let owned: Vec<u64> = requests.iter().filter(|(_, r)| r.sender_id == user_id || r.receiver_id == user_id).map(|(id, _)| id).collect();
+        for id in &owned {
+            requests.remove(id);
+        }
+        owned.len() as u64
+    });
+
+    // Groups and what the user contributed to them
+    hand_over_study_groups(user_id, summary.deleted_at);
+    remove_owned!(STUDY_RESOURCES, user_id);
+    let group_creator = |group_id: u64| STUDY_GROUPS.with(|groups| groups.borrow().get(&group_id)).map(|g| g.creator_id);
+    // Decks pass to the group's creator and contributed cards to the deck's
+    reassign_where!(FLASHCARD_DECKS, |deck: &mut FlashcardDeck| {
+        deck.created_by == user_id && group_creator(deck.group_id).is_some_and(|creator| swap_principal(&mut deck.created_by, user_id, creator))
+    });
+    reassign_where!(DECK_CARDS, |card: &mut DeckCard| {
+        card.contributed_by == user_id
+            && FLASHCARD_DECKS.with(|decks| decks.borrow().get(&card.deck_id)).is_some_and(|d| swap_principal(&mut card.contributed_by, user_id, d.created_by))
+    });
+    // The user's threads go with their replies, as when a moderator deletes them
+    DISCUSSION_POSTS.with(|posts| {
+        let mut posts = posts.borrow_mut();
+        let threads: Vec<u64> = posts.iter().filter(|(_, p)| p.user_id == user_id && p.parent_id.is_none()).map(|(id, _)| id).collect();
+        let removed: Vec<u64> = posts.iter()
+            .filter(|(_, p)| p.user_id == user_id || p.parent_id.is_some_and(|parent| threads.contains(&parent)))
+            .map(|(id, _)| id)
+            .collect();
+        for id in removed {
+            posts.remove(&id);
+        }
+    });
+    reassign_where!(DISCUSSION_POSTS, |post: &mut DiscussionPost| {
+        let before = post.voters.len() + post.reported_by.len();
+        post.voters.retain(|v| *v != user_id);
+        post.reported_by.retain(|r| *r != user_id);
+        post.vote_count = post.voters.len() as u64;
+        post.voters.len() + post.reported_by.len() != before
+    });
+    reassign_where!(FEEDBACK_ITEMS, |item: &mut FeedbackItem| {
+        let before = item.voters.len();
+        item.voters.retain(|v| *v != user_id);
+        item.voters.len() != before
+    });
+    reassign_where!(CHAT_SESSIONS, |session: &mut ChatSession| {
+        let before = session.co_learners.len();
+        session.co_learners.retain(|c| c.user_id != user_id);
+        session.co_learners.len() != before
+    });
+    reassign_where!(ORGANIZATIONS, |org: &mut Organization| {
+        let before = org.admins.len();
+        org.admins.retain(|admin| *admin != user_id);
+        org.admins.len() != before
+    });
+
+    // Embeds of the user's tutors, with the visitor sessions they started
+    let public_ids: Vec<&str> = bundle.tutors.iter().map(|t| t.public_id.as_str()).collect();
+    let embeds: Vec<String> = EMBED_TOKENS.with(|tokens| {
+        tokens.borrow().iter().filter(|(_, e)| e.owner == user_id || public_ids.contains(&e.tutor_id.as_str())).map(|(token, _)| token).collect()
+    });
+    for token in &embeds {
+        EMBED_TOKENS.with(|tokens| tokens.borrow_mut().remove(token));
+        EMBED_USAGE.with(|usage| {
+            let mut usage = usage.borrow_mut();
+            let keys: Vec<String> = usage.range(format!("{}:", token)..format!("{};", token)).map(|(key, _)| key).collect();
+            for key in keys {
+                usage.remove(&key);
+            }
+        });
+    }
+    GUEST_SESSIONS.with(|sessions| {
+        let mut sessions = sessions.borrow_mut();
+        let started: Vec<String> = sessions.iter()
+            .filter(|(_, s)| s.started_by == user_id || s.embed_token.as_ref().is_some_and(|t| embeds.contains(t)))
+            .map(|(key, _)| key)
+            .collect();
+        for key in started {
+            sessions.remove(&key);
+        }
+    });
+
+    // Tutoring activity and the bridges that route to the user
+    remove_owned!(TUTOR_SESSIONS, user_id);
+    remove_owned!(XAPI_OUTBOX, user_id);
+    remove_owned!(LOW_CONFIDENCE_REPLIES, user_id);
+    remove_owned!(EXPERIMENT_ASSIGNMENTS, user_id);
+    remove_owned!(BOT_LINK_CODES, user_id);
+    remove_owned!(BOT_THREADS, user_id);
+    let exchanges: Vec<EmailExchange> = EMAIL_EXCHANGES.with(|exchanges| exchanges.borrow().values().filter(|e| e.user_id == user_id).collect());
+    for exchange in exchanges {
+        let id = &exchange.inbound_message_id;
+        EMAIL_EXCHANGES.with(|e| e.borrow_mut().remove(id));
+        EMAIL_QUEUE.with(|queue| queue.borrow_mut().remove(&email_index_key(exchange.next_attempt_at, id)));
+        EMAIL_EXPIRY.with(|expiry| expiry.borrow_mut().remove(&email_index_key(exchange.received_at, id)));
+    }
+
+    // Per-principal counters, routing and sign-in state
+    USER_SHARDS.with(|shards| shards.borrow_mut().remove(&user_id));
+    PUSH_QUEUE.with(|queue| {
+        let mut queue = queue.borrow_mut();
+        let queued: Vec<String> = queue.iter().filter(|(_, p)| *p == user_id).map(|(key, _)| key).collect();
+        for key in queued {
+            queue.remove(&key);
+        }
+    });
+    let suffix = format!(":{}", user_id);
+    AI_CALL_COUNTS.with(|counts| {
+        let mut counts = counts.borrow_mut();
+        let owned: Vec<String> = counts.iter().filter(|(key, _)| key.ends_with(&suffix)).map(|(key, _)| key).collect();
+        for key in owned {
+            counts.remove(&key);
+        }
+    });
+    rate_limit::remove(user_id);
+    LOGIN_ATTEMPTS.with(|attempts| attempts.borrow_mut().remove(&login_key(&user.email)));
+    WAITLIST.with(|waitlist| waitlist.borrow_mut().remove(&normalize_email(&user.email)));
+
+    record_audit(
+        deleted_by,
+        "delete_account",
+        Some(user_id),
+        format!(
+            "{} tutors, {} sessions, {} messages, {} files",
+            summary.tutors, summary.chat_sessions, summary.chat_messages, summary.knowledge_base_files
+        ),
+    );
+    Ok(summary)
+}
+
+// The confirmation must be the account's email or username; password accounts also give the password
+#[ic_cdk::update]
+fn delete_my_account(confirmation: String, password: Option<String>, session_token: Option<String>) -> Result<AccountDeletionSummary, String> {
+    let caller = session_caller(session_token)?;
+    let user = cache::user(caller).ok_or("User not found")?;
+    let confirmation = confirmation.trim();
+    if confirmation.is_empty() || (!confirmation.eq_ignore_ascii_case(&user.email) && confirmation != user.username) {
+        return Err("Enter your email or username to confirm deleting your account".to_string());
+    }
+    if let Some(password_hash) = user.password_hash.as_deref() {
+        let now = ic_cdk::api::time();
+        ensure_not_locked(&user.email, now)?;
+        if !password.is_some_and(|p| password::verify(&p, password_hash, user.password_salt.as_deref())) {
+            record_login_failure(&user.email, now);
+            return Err("Invalid password".to_string());
+        }
+    }
+    delete_user_account(caller, caller)
+}
+
+#[ic_cdk::update]
+fn delete_user_admin(user_id: Principal) -> Result<AccountDeletionSummary, String> {
+    let caller = ic_cdk::caller();
+    require(caller, Permission::ManageUsers)?;
+    if user_id == caller {
+        return Err("Use delete_my_account to delete your own account".to_string());
+    }
+    delete_user_account(user_id, caller)
+}
+
+// --- Peer Tutoring ---
+//
+// Verified educators offer one-to-one sessions in slots they publish ahead of time. A learner
+// books a slot and pays either by card (confirmed by billing, like gift purchases) or in tokens
+// pulled with an ICRC-2 approval. The payment stays in escrow until the learner confirms the
+// session or the confirm window passes, and then goes to the tutor less the platform fee. Either
+// side can dispute a held payment, and billing staff decide whether it's released or refunded.
+// Card payments released to a tutor are paid out by billing; the canister only records them.
+
+const PEER_TUTORING_JOB_INTERVAL_NS: u64 = 10 * 60 * 1_000_000_000;
+const MAX_PEER_SLOTS_PER_CALL: usize = 50;
+const MAX_OPEN_PEER_SLOTS: usize = 200;
+const MAX_PEER_SUBJECTS: usize = 10;
+const MAX_PEER_HEADLINE_CHARS: usize = 120;
+const MAX_PEER_BIO_CHARS: usize = 2000;
+const MAX_PEER_TEXT_CHARS: usize = 1000; // topics, reviews, dispute reasons and notes
+const MAX_PEER_TUTOR_RESULTS: usize = 100;
+
+fn store_peer_slot(slot: &PeerSlot) {
+    PEER_SLOTS.with(|slots| slots.borrow_mut().insert(slot.id, slot.clone()));
+}
+
+fn store_peer_booking(booking: &PeerBooking) {
+    PEER_BOOKINGS.with(|bookings| bookings.borrow_mut().insert(booking.id, booking.clone()));
+}
+
+// Bookings are visible to their tutor and learner only
+fn peer_booking_for(booking_id: u64, caller: Principal) -> Result<PeerBooking, String> {
+    PEER_BOOKINGS.with(|bookings| bookings.borrow().get(&booking_id))
+        .filter(|b| b.tutor_id == caller || b.learner_id == caller)
+        .ok_or_else(|| "Booking not found".to_string())
+}
+
+fn set_peer_transaction_status(transaction_id: u64, status: &str, now: u64) {
+    PAYMENT_TRANSACTIONS.with(|transactions| {
+        let mut transactions = transactions.borrow_mut();
+        if let Some(mut transaction) = transactions.get(&transaction_id) {
+            transaction.status = status.to_string();
+            if status == "success" {
+                transaction.paid_at = Some(now);
+            }
+            transactions.insert(transaction.id, transaction);
+        }
+    });
+}
+
+fn reopen_peer_slot(slot_id: u64) {
+    if let Some(mut slot) = PEER_SLOTS.with(|slots| slots.borrow().get(&slot_id)) {
+        slot.status = "open".to_string();
+        slot.booking_id = None;
+        store_peer_slot(&slot);
+    }
+}
+
+fn peer_text(text: Option<String>) -> Option<String> {
+    text.map(|t| trim_to_length(t.trim(), MAX_PEER_TEXT_CHARS)).filter(|t| !t.is_empty())
+}
+
+fn notify_billing_staff(content: String, related_id: Option<u64>) {
+    let staff: Vec<Principal> = USERS.with(|users| {
+        users.borrow().values()
+            .filter(|u| Role::parse(&u.role).is_some_and(|r| r.permissions().contains(&Permission::ManageBilling)))
+            .map(|u| u.id)
+            .collect()
+    });
+    for user_id in staff {
+        notify_user(user_id, "warning", "billing", content.clone(), related_id);
+    }
+}
+
+async fn take_peer_token_payment(ledger: Principal, booking: &PeerBooking) -> Result<u64, String> {
+    let arg = TransferFromArgs {
+        spender_subaccount: None,
+        from: Account { owner: booking.learner_id, subaccount: None },
+        to: Account { owner: ic_cdk::id(), subaccount: None },
+        amount: Nat::from(booking.amount),
+        fee: None,
+        memo: Some(format!("peer:{}", booking.id).into_bytes()),
+        created_at_time: Some(ic_cdk::api::time()),
+    };
+    let (result,): (Result<Nat, TransferFromError>,) = ic_cdk::call(ledger, "icrc2_transfer_from", (arg,))
+        .await
+        .map_err(|(code, msg)| format!("Ledger call failed: {:?} {}", code, msg))?;
+    let block = result.map_err(|e| match e {
+        TransferFromError::InsufficientAllowance { .. } => "Approve this canister to spend the session price first".to_string(),
+        TransferFromError::InsufficientFunds { .. } => "You don't have enough tokens for this session".to_string(),
+        e => format!("Ledger rejected the transfer: {:?}", e),
+    })?;
+    u64::try_from(&block.0).map_err(|_| "Ledger returned an invalid block index".to_string())
+}
+
+async fn send_peer_tokens(ledger: Principal, to: Principal, amount: u64, memo: String) -> Result<u64, String> {
+    let arg = TransferArg {
+        from_subaccount: None,
+        to: Account { owner: to, subaccount: None },
+        amount: Nat::from(amount),
+        fee: None,
+        memo: Some(memo.into_bytes()),
+        created_at_time: Some(ic_cdk::api::time()),
+    };
+    let (result,): (Result<Nat, TransferError>,) = ic_cdk::call(ledger, "icrc1_transfer", (arg,))
+        .await
+        .map_err(|(code, msg)| format!("Ledger call failed: {:?} {}", code, msg))?;
+    let block = result.map_err(|e| format!("Ledger rejected the transfer: {:?}", e))?;
+    u64::try_from(&block.0).map_err(|_| "Ledger returned an invalid block index".to_string())
+}
+
+// Held in "releasing" or "refunding" across the payout so it can't be settled twice
+fn begin_peer_escrow(booking: &mut PeerBooking, release: bool) -> Result<(), String> {
+    if booking.escrow != "held" {
+        return Err(format!("The payment for this booking is {}", booking.escrow.replace('_', " ")));
+    }
+    booking.escrow = if release { "releasing" } else { "refunding" }.to_string();
+    booking.updated_at = ic_cdk::api::time();
+    store_peer_booking(booking);
+    Ok(())
+}
+
+async fn settle_peer_escrow(mut booking: PeerBooking, release: bool, actor: Principal) -> PeerBooking {
+    let config = get_config();
+    let result = match (booking.payment_method.as_str(), release) {
+        ("token", _) => match config.refunds.token_ledger {
+            Some(ledger) if release => send_peer_tokens(ledger, booking.tutor_id, booking.amount - booking.platform_fee, format!("peer:{}", booking.id)).await.map(|_| ()),
+            Some(ledger) => send_peer_tokens(ledger, booking.learner_id, booking.amount, format!("peer-refund:{}", booking.id)).await.map(|_| ()),
+            None => Err("No token ledger is configured".to_string()),
+        },
+        (_, true) => Ok(()),
+        (_, false) => match PAYMENT_TRANSACTIONS.with(|transactions| transactions.borrow().get(&booking.transaction_id)) {
+            Some(transaction) => send_paystack_refund(&config, &transaction, booking.amount).await,
+            None => Err("Payment not found".to_string()),
+        },
+    };
+
+    let now = ic_cdk::api::time();
+    booking.updated_at = now;
+    match result {
+        Ok(()) => {
+            booking.escrow_error = None;
+            if release {
+                booking.escrow = "released".to_string();
+                notify_user(booking.tutor_id, "success", "billing", format!("The payment for your session on {} has been released to you", iso8601(booking.starts_at)), Some(booking.id));
+                record_audit(actor, "peer_escrow_released", Some(booking.tutor_id), format!("booking {}: {} {} less {} fee", booking.id, booking.amount, booking.currency, booking.platform_fee));
+            } else {
+                booking.escrow = "refunded".to_string();
+                set_peer_transaction_status(booking.transaction_id, "refunded", now);
+                notify_user(booking.learner_id, "success", "billing", format!("Your payment for the session on {} has been refunded", iso8601(booking.starts_at)), Some(booking.id));
+                record_audit(actor, "peer_escrow_refunded", Some(booking.learner_id), format!("booking {}: {} {}", booking.id, booking.amount, booking.currency));
+            }
+        }
+        Err(e) => {
+            booking.escrow = "held".to_string();
+            record_audit(actor, "peer_escrow_failed", None, format!("booking {}: {}", booking.id, e));
+            notify_billing_staff(format!("Settling peer booking {} failed: {}", booking.id, e), Some(booking.id));
+            booking.escrow_error = Some(e);
+        }
+    }
+    store_peer_booking(&booking);
+    booking
+}
+
+#[ic_cdk::update]
+fn set_peer_tutor_profile(headline: String, bio: String, subjects: Vec<String>, hourly_rate: u64, currency: String, token_hourly_rate: Option<u64>, active: bool) -> Result<PeerTutorProfile, String> {
+    let caller = ic_cdk::caller();
+    let user = get_self().ok_or("User not found")?;
+    if !user.verified_educator {
+        return Err("Only verified educators can offer peer tutoring".to_string());
+    }
+    if !get_config().peer_tutoring.enabled {
+        return Err("Peer tutoring isn't available right now".to_string());
+    }
+    let headline = headline.trim().to_string();
+    if headline.is_empty() || headline.chars().count() > MAX_PEER_HEADLINE_CHARS {
+        return Err(format!("Headline must be between 1 and {} characters", MAX_PEER_HEADLINE_CHARS));
+    }
+    let bio = bio.trim().to_string();
+    if bio.chars().count() > MAX_PEER_BIO_CHARS {
+        return Err(format!("Bio can be at most {} characters", MAX_PEER_BIO_CHARS));
+    }
+    let mut subjects: Vec<String> = subjects.iter().map(|s| s.trim().to_lowercase()).filter(|s| !s.is_empty()).collect();
+    subjects.sort();
+    subjects.dedup();
+    if subjects.is_empty() || subjects.len() > MAX_PEER_SUBJECTS {
+        return Err(format!("List between 1 and {} subjects", MAX_PEER_SUBJECTS));
+    }
+    let currency = currency.trim().to_uppercase();
+    if currency.len() != 3 || !currency.chars().all(|c| c.is_ascii_alphabetic()) {
+        return Err("Currency must be a three-letter code".to_string());
+    }
+    if hourly_rate == 0 || token_hourly_rate == Some(0) {
+        return Err("Rates must be above zero".to_string());
+    }
+
+    let now = ic_cdk::api::time();
+    let existing = PEER_TUTORS.with(|tutors| tutors.borrow().get(&caller));
+    let profile = PeerTutorProfile {
+        user_id: caller,
+        headline,
+        bio,
+        subjects,
+        hourly_rate,
+        currency,
+        token_hourly_rate,
+        active,
+        rating_sum: existing.as_ref().map_or(0, |p| p.rating_sum),
+        rating_count: existing.as_ref().map_or(0, |p| p.rating_count),
+        sessions_completed: existing.as_ref().map_or(0, |p| p.sessions_completed),
+        created_at: existing.as_ref().map_or(now, |p| p.created_at),
+        updated_at: now,
+    };
+    PEER_TUTORS.with(|tutors| tutors.borrow_mut().insert(caller, profile.clone()));
+    Ok(profile)
+}
+
+#[ic_cdk::update]
+fn add_peer_slots(specs: Vec<PeerSlotSpec>) -> Result<Vec<PeerSlot>, String> {
+    let caller = ic_cdk::caller();
+    PEER_TUTORS.with(|tutors| tutors.borrow().get(&caller)).ok_or("Set up your tutoring profile first")?;
+    let config = get_config().peer_tutoring;
+    if !config.enabled {
+        return Err("Peer tutoring isn't available right now".to_string());
+    }
+    if specs.is_empty() || specs.len() > MAX_PEER_SLOTS_PER_CALL {
+        return Err(format!("Add between 1 and {} slots at a time", MAX_PEER_SLOTS_PER_CALL));
+    }
+
+    let now = ic_cdk::api::time();
+    let mut taken: Vec<(u64, u64)> = PEER_SLOTS.with(|slots| {
+        slots.borrow().values().filter(|s| s.tutor_id == caller && s.ends_at > now).map(|s| (s.starts_at, s.ends_at)).collect()
+    });
+    if taken.len() + specs.len() > MAX_OPEN_PEER_SLOTS {
+        return Err(format!("You can have at most {} upcoming slots", MAX_OPEN_PEER_SLOTS));
+    }
+    let latest = now + config.booking_window_days as u64 * NANOS_PER_DAY;
+    for spec in &specs {
+        if spec.starts_at <= now || spec.starts_at > latest {
+            return Err(format!("Slots must start in the next {} days", config.booking_window_days));
+        }
+        if spec.minutes < config.min_slot_minutes || spec.minutes > config.max_slot_minutes {
+            return Err(format!("Slots last between {} and {} minutes", config.min_slot_minutes, config.max_slot_minutes));
+        }
+        let ends_at = spec.starts_at + spec.minutes as u64 * 60 * 1_000_000_000;
+        if taken.iter().any(|&(start, end)| spec.starts_at < end && start < ends_at) {
+            return Err(format!("The slot at {} overlaps another of your slots", iso8601(spec.starts_at)));
+        }
+        taken.push((spec.starts_at, ends_at));
+    }
+
+    let slots: Vec<PeerSlot> = specs.iter().map(|spec| PeerSlot {
+        id: next_id("peer_slot"),
+        tutor_id: caller,
+        starts_at: spec.starts_at,
+        ends_at: spec.starts_at + spec.minutes as u64 * 60 * 1_000_000_000,
+        status: "open".to_string(),
+        booking_id: None,
+        created_at: now,
+    }).collect();
+    for slot in &slots {
+        store_peer_slot(slot);
+    }
+    Ok(slots)
+}
+
+#[ic_cdk::update]
+fn remove_peer_slot(slot_id: u64) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    let slot = PEER_SLOTS.with(|slots| slots.borrow().get(&slot_id))
+        .filter(|s| s.tutor_id == caller)
+        .ok_or("Slot not found")?;
+    if slot.status != "open" {
+        return Err("This slot is booked; cancel the booking instead".to_string());
+    }
+    PEER_SLOTS.with(|slots| slots.borrow_mut().remove(&slot_id));
+    Ok(())
+}
+
+// Active verified tutors, best rated first
+#[ic_cdk::query]
+fn get_peer_tutors(subject: Option<String>) -> Vec<PeerTutorProfile> {
+    let subject = subject.map(|s| s.trim().to_lowercase()).filter(|s| !s.is_empty());
+    let mut tutors: Vec<PeerTutorProfile> = PEER_TUTORS.with(|tutors| {
+        tutors.borrow().values()
+            .filter(|p| p.active && is_verified_educator(p.user_id))
+            .filter(|p| subject.as_ref().is_none_or(|s| p.subjects.iter().any(|subject| subject.contains(s.as_str()))))
+            .collect()
+    });
+    let average = |p: &PeerTutorProfile| if p.rating_count == 0 { 0 } else { p.rating_sum * 100 / p.rating_count as u64 };
+    tutors.sort_by(|a, b| average(b).cmp(&average(a)).then_with(|| b.sessions_completed.cmp(&a.sessions_completed)));
+    tutors.truncate(MAX_PEER_TUTOR_RESULTS);
+    tutors
+}
+
+// Open upcoming slots; tutors also see their own booked ones
+#[ic_cdk::query]
+fn get_peer_tutor_slots(tutor_id: Principal) -> Vec<PeerSlot> {
+    let caller = ic_cdk::caller();
+    let now = ic_cdk::api::time();
+    let mut slots: Vec<PeerSlot> = PEER_SLOTS.with(|slots| {
+        slots.borrow().values()
+            .filter(|s| s.tutor_id == tutor_id && s.starts_at > now && (s.status == "open" || caller == tutor_id))
+            .collect()
+    });
+    slots.sort_by_key(|s| s.starts_at);
+    slots
+}
+
+// Token payments are taken straight away and need an ICRC-2 approval for the amount; card
+// bookings hold the slot until billing confirms the payment or the hold runs out
+#[ic_cdk::update]
+async fn book_peer_slot(slot_id: u64, topic: String, pay_with_tokens: bool) -> Result<PeerBooking, String> {
+    let caller = ic_cdk::caller();
+    get_self().ok_or("User not found")?;
+    let config = get_config();
+    if !config.peer_tutoring.enabled {
+        return Err("Peer tutoring isn't available right now".to_string());
+    }
+    let now = ic_cdk::api::time();
+    let mut slot = PEER_SLOTS.with(|slots| slots.borrow().get(&slot_id)).ok_or("Slot not found")?;
+    if slot.status != "open" || slot.starts_at <= now {
+        return Err("This slot is no longer available".to_string());
+    }
+    if slot.tutor_id == caller {
+        return Err("You can't book your own slot".to_string());
+    }
+    let profile = PEER_TUTORS.with(|tutors| tutors.borrow().get(&slot.tutor_id))
+        .filter(|p| p.active && is_verified_educator(p.user_id))
+        .ok_or("This tutor isn't taking bookings")?;
+    let topic = peer_text(Some(topic)).ok_or("Tell the tutor what you'd like to cover")?;
+
+    let minutes = (slot.ends_at - slot.starts_at) / (60 * 1_000_000_000);
+    let fee_percent = config.peer_tutoring.platform_fee_percent.min(100) as u64;
+    let (amount, currency, platform_fee, region, tax, ledger) = if pay_with_tokens {
+        let rate = profile.token_hourly_rate.ok_or("This tutor doesn't take tokens")?;
+        let ledger = config.refunds.token_ledger.ok_or("Token payments aren't available right now")?;
+        let amount = (rate * minutes).div_ceil(60);
+        (amount, "TOKEN".to_string(), amount * fee_percent / 100, None, None, Some(ledger))
+    } else {
+        let base = (profile.hourly_rate * minutes).div_ceil(60);
+        let region = billing_region(caller);
+        let (total, tax) = apply_tax(base, &region);
+        (total, profile.currency.clone(), tax.net_amount * fee_percent / 100, Some(region), Some(tax), None)
+    };
+
+    let booking_id = next_id("peer_booking");
+    let transaction_id = next_id("payment_transaction");
+    PAYMENT_TRANSACTIONS.with(|transactions| {
+        transactions.borrow_mut().insert(transaction_id, PaymentTransaction {
+            id: transaction_id,
+            user_id: caller,
+            subscription_id: None,
+            paystack_reference: format!("peer_{}", booking_id),
+            paystack_access_code: None,
+            paystack_transaction_id: None,
+            amount_naira: amount,
+            currency: currency.clone(),
+            status: "pending".to_string(),
+            payment_method: Some(if pay_with_tokens { "token" } else { "paystack" }.to_string()),
+            description: Some(format!("Tutoring session on {}", iso8601(slot.starts_at))),
+            payment_metadata: None,
+            created_at: now,
+            paid_at: None,
+            region,
+            tax,
+        });
+    });
+    let mut booking = PeerBooking {
+        id: booking_id,
+        slot_id,
+        tutor_id: slot.tutor_id,
+        learner_id: caller,
+        starts_at: slot.starts_at,
+        ends_at: slot.ends_at,
+        topic,
+        payment_method: if pay_with_tokens { "token" } else { "paystack" }.to_string(),
+        amount,
+        currency,
+        platform_fee,
+        transaction_id,
+        status: "pending_payment".to_string(),
+        escrow: "awaiting_payment".to_string(),
+        escrow_error: None,
+        ledger_block: None,
+        learner_confirmed_at: None,
+        tutor_confirmed_at: None,
+        tutor_rating: None,
+        tutor_review: None,
+        learner_rating: None,
+        learner_review: None,
+        dispute: None,
+        cancelled_by: None,
+        cancel_reason: None,
+        created_at: now,
+        updated_at: now,
+    };
+    slot.status = "booked".to_string();
+    slot.booking_id = Some(booking_id);
+    store_peer_slot(&slot);
+    store_peer_booking(&booking);
+
+    let Some(ledger) = ledger else {
+        return Ok(booking);
+    };
+    let result = take_peer_token_payment(ledger, &booking).await;
+    let now = ic_cdk::api::time();
+    booking.updated_at = now;
+    match result {
+        Ok(block) => {
+            booking.status = "booked".to_string();
+            booking.escrow = "held".to_string();
+            booking.ledger_block = Some(block);
+            set_peer_transaction_status(transaction_id, "success", now);
+            store_peer_booking(&booking);
+            notify_user(booking.tutor_id, "info", "tutoring", format!("Your session on {} has been booked: {}", iso8601(booking.starts_at), booking.topic), Some(booking.id));
+            Ok(booking)
+        }
+        Err(e) => {
+            booking.status = "cancelled".to_string();
+            booking.escrow = "none".to_string();
+            booking.cancel_reason = Some(e.clone());
+            set_peer_transaction_status(transaction_id, "failed", now);
+            store_peer_booking(&booking);
+            reopen_peer_slot(slot_id);
+            Err(e)
+        }
+    }
+}
+
+// Called once a card booking's payment has settled
+#[ic_cdk::update]
+fn confirm_peer_booking_payment_admin(booking_id: u64, paystack_transaction_id: String) -> Result<PeerBooking, String> {
+    let caller = ic_cdk::caller();
+    require(caller, Permission::ManageBilling)?;
+    let mut booking = PEER_BOOKINGS.with(|bookings| bookings.borrow().get(&booking_id)).ok_or("Booking not found")?;
+    if booking.status != "pending_payment" || booking.payment_method != "paystack" {
+        return Err(format!("Booking is already {}", booking.status.replace('_', " ")));
+    }
+
+    let now = ic_cdk::api::time();
+    PAYMENT_TRANSACTIONS.with(|transactions| {
+        let mut transactions = transactions.borrow_mut();
+        if let Some(mut transaction) = transactions.get(&booking.transaction_id) {
+            transaction.status = "success".to_string();
+            transaction.paystack_transaction_id = Some(paystack_transaction_id.clone());
+            transaction.paid_at = Some(now);
+            transactions.insert(transaction.id, transaction);
+        }
+    });
+    booking.status = "booked".to_string();
+    booking.escrow = "held".to_string();
+    booking.updated_at = now;
+    store_peer_booking(&booking);
+    record_audit(caller, "peer_payment_confirmed", Some(booking.learner_id), format!("booking {} ({})", booking_id, paystack_transaction_id));
+    notify_user(booking.tutor_id, "info", "tutoring", format!("Your session on {} has been booked: {}", iso8601(booking.starts_at), booking.topic), Some(booking.id));
+    notify_user(booking.learner_id, "success", "tutoring", format!("Your session on {} is confirmed", iso8601(booking.starts_at)), Some(booking.id));
+    Ok(booking)
+}
+
+// Learners get a refund up to the notice period before the start; tutors can cancel until the
+// start, which always refunds the learner and withdraws the slot
+#[ic_cdk::update]
+async fn cancel_peer_booking(booking_id: u64, reason: Option<String>) -> Result<PeerBooking, String> {
+    let caller = ic_cdk::caller();
+    let mut booking = peer_booking_for(booking_id, caller)?;
+    if booking.status != "pending_payment" && booking.status != "booked" {
+        return Err(format!("Booking is already {}", booking.status.replace('_', " ")));
+    }
+    let now = ic_cdk::api::time();
+    if now >= booking.starts_at {
+        return Err("The session has already started; raise a dispute if something went wrong".to_string());
+    }
+    let notice_hours = get_config().peer_tutoring.cancellation_notice_hours;
+    if caller == booking.learner_id && booking.status == "booked" && booking.starts_at - now < notice_hours as u64 * NANOS_PER_HOUR {
+        return Err(format!("Bookings can only be cancelled up to {} hours before the start", notice_hours));
+    }
+    if booking.escrow == "awaiting_payment" && booking.payment_method == "token" {
+        return Err("The payment for this booking is still being taken".to_string());
+    }
+
+    booking.status = "cancelled".to_string();
+    booking.cancelled_by = Some(caller);
+    booking.cancel_reason = peer_text(reason);
+    booking.updated_at = now;
+    if caller == booking.tutor_id {
+        PEER_SLOTS.with(|slots| slots.borrow_mut().remove(&booking.slot_id));
+    } else {
+        reopen_peer_slot(booking.slot_id);
+    }
+    let other = if caller == booking.tutor_id { booking.learner_id } else { booking.tutor_id };
+    notify_user(other, "warning", "tutoring", format!("Your session on {} was cancelled", iso8601(booking.starts_at)), Some(booking.id));
+
+    if booking.escrow == "awaiting_payment" {
+        booking.escrow = "none".to_string();
+        set_peer_transaction_status(booking.transaction_id, "abandoned", now);
+        store_peer_booking(&booking);
+        return Ok(booking);
+    }
+    begin_peer_escrow(&mut booking, false)?;
+    Ok(settle_peer_escrow(booking, false, caller).await)
+}
+
+// The learner's confirmation completes the session and releases the payment; the tutor's only
+// records their rating of the learner
+#[ic_cdk::update]
+async fn confirm_peer_session(booking_id: u64, rating: u8, review: Option<String>) -> Result<PeerBooking, String> {
+    let caller = ic_cdk::caller();
+    let mut booking = peer_booking_for(booking_id, caller)?;
+    if booking.status != "booked" && booking.status != "completed" {
+        return Err(format!("Booking is {}", booking.status.replace('_', " ")));
+    }
+    let now = ic_cdk::api::time();
+    if now < booking.starts_at {
+        return Err("You can confirm the session once it has started".to_string());
+    }
+    if !(1..=5).contains(&rating) {
+        return Err("Rating must be between 1 and 5".to_string());
+    }
+    let review = peer_text(review);
+
+    booking.updated_at = now;
+    if caller == booking.learner_id {
+        if booking.learner_confirmed_at.is_some() {
+            return Err("You've already confirmed this session".to_string());
+        }
+        booking.learner_confirmed_at = Some(now);
+        booking.tutor_rating = Some(rating);
+        booking.tutor_review = review;
+        if let Some(mut profile) = PEER_TUTORS.with(|tutors| tutors.borrow().get(&booking.tutor_id)) {
+            profile.rating_sum += rating as u64;
+            profile.rating_count += 1;
+            if booking.status == "booked" {
+                profile.sessions_completed += 1;
+            }
+            PEER_TUTORS.with(|tutors| tutors.borrow_mut().insert(profile.user_id, profile));
+        }
+        booking.status = "completed".to_string();
+        notify_user(booking.tutor_id, "success", "tutoring", format!("Your learner rated the session on {} {}/5", iso8601(booking.starts_at), rating), Some(booking.id));
+        if booking.escrow == "held" && booking.escrow_error.is_none() {
+            begin_peer_escrow(&mut booking, true)?;
+            return Ok(settle_peer_escrow(booking, true, caller).await);
+        }
+    } else {
+        if booking.tutor_confirmed_at.is_some() {
+            return Err("You've already confirmed this session".to_string());
+        }
+        booking.tutor_confirmed_at = Some(now);
+        booking.learner_rating = Some(rating);
+        booking.learner_review = review;
+        if booking.learner_confirmed_at.is_none() {
+            notify_user(booking.learner_id, "info", "tutoring", format!("Your tutor confirmed the session on {}. Confirm it too to release their payment.", iso8601(booking.starts_at)), Some(booking.id));
+        }
+    }
+    store_peer_booking(&booking);
+    Ok(booking)
+}
+
+#[ic_cdk::update]
+fn raise_peer_dispute(booking_id: u64, reason: String) -> Result<PeerBooking, String> {
+    let caller = ic_cdk::caller();
+    let mut booking = peer_booking_for(booking_id, caller)?;
+    if booking.status != "booked" && booking.status != "completed" {
+        return Err(format!("Booking is {}", booking.status.replace('_', " ")));
+    }
+    let now = ic_cdk::api::time();
+    if now < booking.starts_at {
+        return Err("Cancel the booking instead; the session hasn't started".to_string());
+    }
+    if booking.escrow != "held" {
+        return Err("The payment for this booking has already been settled".to_string());
+    }
+    let reason = peer_text(Some(reason)).ok_or("Describe what went wrong")?;
+
+    booking.status = "disputed".to_string();
+    booking.dispute = Some(PeerDispute {
+        raised_by: caller,
+        reason,
+        raised_at: now,
+        resolution: None,
+        note: None,
+        resolved_by: None,
+        resolved_at: None,
+    });
+    booking.updated_at = now;
+    store_peer_booking(&booking);
+    let other = if caller == booking.tutor_id { booking.learner_id } else { booking.tutor_id };
+    notify_user(other, "warning", "tutoring", format!("The session on {} has been disputed; the payment is on hold until it's reviewed", iso8601(booking.starts_at)), Some(booking.id));
+    notify_billing_staff(format!("Peer booking {} has been disputed", booking.id), Some(booking.id));
+    Ok(booking)
+}
+
+#[ic_cdk::update]
+async fn resolve_peer_dispute_admin(booking_id: u64, refund: bool, note: Option<String>) -> Result<PeerBooking, String> {
+    let caller = ic_cdk::caller();
+    require(caller, Permission::ManageBilling)?;
+    let mut booking = PEER_BOOKINGS.with(|bookings| bookings.borrow().get(&booking_id)).ok_or("Booking not found")?;
+    let mut dispute = booking.dispute.clone().filter(|d| booking.status == "disputed" && d.resolution.is_none())
+        .ok_or("This booking has no open dispute")?;
+
+    let now = ic_cdk::api::time();
+    dispute.resolution = Some(if refund { "refunded" } else { "released" }.to_string());
+    dispute.note = peer_text(note);
+    dispute.resolved_by = Some(caller);
+    dispute.resolved_at = Some(now);
+    booking.dispute = Some(dispute);
+    if refund {
+        booking.status = "cancelled".to_string();
+        booking.cancelled_by = Some(caller);
+        booking.cancel_reason = Some("Refunded after a dispute".to_string());
+    } else {
+        booking.status = "completed".to_string();
+    }
+    begin_peer_escrow(&mut booking, !refund)?;
+    record_audit(caller, "peer_dispute_resolved", Some(booking.learner_id), format!("booking {}: {}", booking.id, if refund { "refund" } else { "release" }));
+    for party in [booking.tutor_id, booking.learner_id] {
+        notify_user(party, "info", "tutoring", format!("The dispute over the session on {} has been resolved", iso8601(booking.starts_at)), Some(booking.id));
+    }
+    Ok(settle_peer_escrow(booking, !refund, caller).await)
+}
+
+// Retries a release or refund that failed, in the direction the booking was settled
+#[ic_cdk::update]
+async fn retry_peer_escrow_admin(booking_id: u64) -> Result<PeerBooking, String> {
+    let caller = ic_cdk::caller();
+    require(caller, Permission::ManageBilling)?;
+    let mut booking = PEER_BOOKINGS.with(|bookings| bookings.borrow().get(&booking_id)).ok_or("Booking not found")?;
+    if booking.escrow_error.is_none() {
+        return Err("This booking has no failed payment to retry".to_string());
+    }
+    let release = match booking.status.as_str() {
+        "completed" => true,
+        "cancelled" => false,
+        status => return Err(format!("Booking is {}", status.replace('_', " "))),
+    };
+    begin_peer_escrow(&mut booking, release)?;
+    Ok(settle_peer_escrow(booking, release, caller).await)
+}
+
+#[ic_cdk::query]
+fn get_my_peer_bookings() -> Vec<PeerBooking> {
+    let caller = ic_cdk::caller();
+    let mut bookings: Vec<PeerBooking> = PEER_BOOKINGS.with(|bookings| {
+        bookings.borrow().values().filter(|b| b.tutor_id == caller || b.learner_id == caller).collect()
+    });
+    bookings.sort_by_key(|b| std::cmp::Reverse(b.starts_at));
+    bookings
+}
+
+#[ic_cdk::query]
+fn get_peer_bookings_admin(status: Option<String>) -> Result<Vec<PeerBooking>, String> {
+    require(ic_cdk::caller(), Permission::ManageBilling)?;
+    let mut bookings: Vec<PeerBooking> = PEER_BOOKINGS.with(|bookings| {
+        bookings.borrow().values().filter(|b| status.as_ref().is_none_or(|s| &b.status == s)).collect()
+    });
+    bookings.sort_by_key(|b| std::cmp::Reverse(b.updated_at));
+    ensure_fits(&bookings, "Filter by status to narrow the list.")?;
+    Ok(bookings)
+}
+
+#[ic_cdk::update]
+fn set_peer_tutoring_config_admin(peer_tutoring: PeerTutoringConfig) -> Result<CanisterConfig, String> {
+    let caller = ic_cdk::caller();
+    require(caller, Permission::ManageBilling)?;
+    if peer_tutoring.platform_fee_percent > 100 {
+        return Err("Platform fee can't be more than 100%".to_string());
+    }
+    if peer_tutoring.min_slot_minutes == 0 || peer_tutoring.min_slot_minutes > peer_tutoring.max_slot_minutes {
+        return Err("Slot lengths need a minimum above zero and no more than the maximum".to_string());
+    }
+    if peer_tutoring.booking_window_days == 0 || peer_tutoring.payment_hold_minutes == 0 {
+        return Err("Booking window and payment hold must be above zero".to_string());
+    }
+
+    let details = format!(
+        "enabled={} fee={}% slots={}-{}min window={}d hold={}min notice={}h confirm={}h",
+        peer_tutoring.enabled, peer_tutoring.platform_fee_percent, peer_tutoring.min_slot_minutes, peer_tutoring.max_slot_minutes,
+        peer_tutoring.booking_window_days, peer_tutoring.payment_hold_minutes, peer_tutoring.cancellation_notice_hours,
+        peer_tutoring.confirm_window_hours
+    );
+    let config = update_config(|config| {
+        config.peer_tutoring = peer_tutoring;
+        Ok(())
+    })?;
+    record_audit(caller, "set_peer_tutoring_config", None, details);
+    Ok(config)
+}
+
+// Expires unpaid card bookings, releases payments nobody disputed in time and clears out
+// open slots that have passed
+fn run_peer_tutoring_checks(now: u64) {
+    let config = get_config().peer_tutoring;
+    let hold = config.payment_hold_minutes as u64 * 60 * 1_000_000_000;
+    let confirm_window = config.confirm_window_hours as u64 * NANOS_PER_HOUR;
+    let bookings: Vec<PeerBooking> = PEER_BOOKINGS.with(|bookings| {
+        bookings.borrow().values()
+            .filter(|b| b.escrow == "awaiting_payment" || (b.escrow == "held" && b.escrow_error.is_none()))
+            .collect()
+    });
+    for mut booking in bookings {
+        if booking.status == "pending_payment" && booking.payment_method == "paystack" && now > booking.created_at + hold {
+            booking.status = "cancelled".to_string();
+            booking.escrow = "none".to_string();
+            booking.cancel_reason = Some("Payment wasn't completed in time".to_string());
+            booking.updated_at = now;
+            set_peer_transaction_status(booking.transaction_id, "abandoned", now);
+            store_peer_booking(&booking);
+            reopen_peer_slot(booking.slot_id);
+            continue;
+        }
+        let release = match booking.status.as_str() {
+            "booked" | "completed" if booking.escrow == "held" && now > booking.ends_at + confirm_window => true,
+            "cancelled" if booking.escrow == "held" => false,
+            _ => continue,
+        };
+        if release && booking.status == "booked" {
+            booking.status = "completed".to_string();
+            if let Some(mut profile) = PEER_TUTORS.with(|tutors| tutors.borrow().get(&booking.tutor_id)) {
+                profile.sessions_completed += 1;
+                PEER_TUTORS.with(|tutors| tutors.borrow_mut().insert(profile.user_id, profile));
+            }
+        }
+        if begin_peer_escrow(&mut booking, release).is_ok() {
+            ic_cdk::spawn(async move {
+                settle_peer_escrow(booking, release, ic_cdk::id()).await;
+            });
+        }
+    }
+
+    let cutoff = now.saturating_sub(NANOS_PER_DAY);
+    PEER_SLOTS.with(|slots| {
+        let mut slots = slots.borrow_mut();
+        let expired: Vec<u64> = slots.iter().filter(|(_, s)| s.status == "open" && s.ends_at < cutoff).map(|(id, _)| id).collect();
+        for id in expired {
+            slots.remove(&id);
+        }
+    });
+}
+
+// --- Data Export ---
+//
+// Everything a user has created, in one structured record they can take elsewhere. Message
+// histories can outgrow a single reply, so messages are packed session by session until the
+// reply is full and next_cursor says where to pick up; calling again with it returns the next
+// chunk. Callers that don't pass a cursor still get a complete export when it fits.
+
+fn user_data_export(user_id: Principal) -> DataExport {
+    let profile = cache::user(user_id);
+    let tutors: Vec<Tutor> = TUTORS.with(|tutors| tutors.borrow().values().filter(|t| t.user_id == user_id).collect());
+    let tutor_ids: Vec<u64> = tutors.iter().map(|t| t.id).collect();
+    let group_memberships: Vec<GroupMembership> = GROUP_MEMBERSHIPS.with(|memberships| {
+        memberships.borrow().values().filter(|m| m.user_id == user_id).collect()
+    });
+    let groups = STUDY_GROUPS.with(|groups| {
+        let groups = groups.borrow();
+        group_memberships.iter().filter_map(|m| groups.get(&m.group_id)).collect()
+    });
+
+    DataExport {
+        user_id,
+        exported_at: ic_cdk::api::time(),
+        profile,
+        tutor_courses: TUTOR_COURSES.with(|courses| courses.borrow().values().filter(|c| tutor_ids.contains(&c.tutor_id)).collect()),
+        tutors,
+        chat_sessions: CHAT_SESSIONS.with(|sessions| sessions.borrow().values().filter(|s| s.user_id == user_id).collect()),
+        learning_progress: LEARNING_PROGRESS.with(|progress| progress.borrow().values().filter(|p| p.user_id == user_id).collect()),
+        learning_metrics: LEARNING_METRICS.with(|metrics| metrics.borrow().values().filter(|m| m.user_id == user_id).collect()),
+        module_completions: MODULE_COMPLETIONS.with(|completions| completions.borrow().values().filter(|c| c.user_id == user_id).collect()),
+        groups,
+        group_memberships,
+        task_completions: USER_TASK_COMPLETIONS.with(|completions| completions.borrow().values().filter(|c| c.user_id == user_id).collect()),
+        messages: Vec::new(),
+        next_cursor: None,
+    }
+}
+
+// Adds messages from the cursor on until the reply is full; always adds at least one so every
+// call makes progress
+fn pack_export_messages(export: &mut DataExport, session_ids: &[String], cursor: Option<DataExportCursor>) {
+    let mut budget = MAX_RESPONSE_BYTES.saturating_sub(estimated_size(export));
+    let start = cursor.as_ref().map_or(0, |c| session_ids.partition_point(|id| id < &c.session_id));
+    let mut packed = 0;
+    for session_id in &session_ids[start..] {
+        let messages = CHAT_MESSAGES.with(|messages| messages.borrow().get(session_id)).map(|list| list.0).unwrap_or_default();
+        let offset = cursor.as_ref().filter(|c| &c.session_id == session_id).map_or(0, |c| c.message_offset as usize);
+        let mut chunk = ExportedMessages {
+            session_id: session_id.clone(),
+            offset: offset as u32,
+            total: messages.len() as u32,
+            messages: Vec::new(),
+        };
+        let mut next = offset;
+        for message in messages.iter().skip(offset) {
+            let size = estimated_size(message);
+            if size > budget && packed > 0 {
+                break;
+            }
+            budget = budget.saturating_sub(size);
+            chunk.messages.push(message.clone());
+            packed += 1;
+            next += 1;
+        }
+        let finished = next >= messages.len();
+        if !chunk.messages.is_empty() || messages.is_empty() {
+            export.messages.push(chunk);
+        }
+        if !finished {
+            export.next_cursor = Some(DataExportCursor { session_id: session_id.clone(), message_offset: next as u32 });
+            return;
+        }
+    }
+}
+
+#[ic_cdk::query]
+fn export_my_data(cursor: Option<DataExportCursor>, session_token: Option<String>) -> Result<DataExport, String> {
+    let caller = session_reader(session_token)?;
+    let mut session_ids: Vec<String> = CHAT_SESSIONS.with(|sessions| {
+        sessions.borrow().iter().filter(|(_, s)| s.user_id == caller).map(|(id, _)| id).collect()
+    });
+    session_ids.sort();
+    let mut export = match cursor {
+        None => {
+            let export = user_data_export(caller);
+            ensure_bytes_fit(estimated_size(&export), 1, "Contact support for an export of an account this large.")?;
+            export
+        }
+        Some(_) => DataExport {
+            user_id: caller,
+            exported_at: ic_cdk::api::time(),
+            profile: None,
+            tutors: Vec::new(),
+            tutor_courses: Vec::new(),
+            chat_sessions: Vec::new(),
+            learning_progress: Vec::new(),
+            learning_metrics: Vec::new(),
+            module_completions: Vec::new(),
+            groups: Vec::new(),
+            group_memberships: Vec::new(),
+            task_completions: Vec::new(),
+            messages: Vec::new(),
+            next_cursor: None,
+        },
+    };
+    pack_export_messages(&mut export, &session_ids, cursor);
+    Ok(export)
+}
+
+// --- Organization SSO ---
+//
+// Organizations that run their own identity provider get a service key. Their backend uses it
+// to provision members by the provider's subject id and to sign those members in for a
+// frontend session key. Provisioning mints an account for a new email. When an account already
+// has the email, the organization only asks to link it: the member stays "pending_link" until
+// the account's owner accepts while signed in, and the organization can never sign that member
+// in or change the account. Staff accounts can't be provisioned at all. Member emails must be
+// on one of the organization's domains, matched exactly. Deprovisioning deactivates accounts
+// the organization created and only unlinks accounts that existed before. The organization's
+// admins can list its members.
+
+const MAX_ORG_EXTERNAL_ID_CHARS: usize = 256;
+
+fn org_member_key(org_id: u64, external_id: &str) -> String {
+    format!("{:020}:{}", org_id, external_id)
+}
+
+fn store_org_member(member: &OrgMember) {
+    ORG_MEMBERS.with(|members| members.borrow_mut().insert(org_member_key(member.org_id, &member.external_id), member.clone()));
+}
+
+// Every wrong or inactive key gets the same answer
+fn org_for_key(org_key: &str) -> Result<Organization, String> {
+    let hash = sha256_hex(org_key.trim());
+    let mut org = ORGANIZATIONS.with(|orgs| orgs.borrow().values().find(|o| o.key_hash == hash))
+        .filter(|o| o.active)
+        .ok_or("Invalid organization key")?;
+    org.key_last_used_at = Some(ic_cdk::api::time());
+    ORGANIZATIONS.with(|orgs| orgs.borrow_mut().insert(org.id, org.clone()));
+    Ok(org)
+}
+
+fn org_email_allowed(org: &Organization, email: &str) -> bool {
+    let Some((_, domain)) = email.rsplit_once('@') else {
+        return false;
+    };
+    org.email_domains.iter().any(|d| domain.eq_ignore_ascii_case(d))
+}
+
+fn validated_org_domains(email_domains: Vec<String>, org_id: Option<u64>) -> Result<Vec<String>, String> {
+    let mut domains: Vec<String> = email_domains.iter().map(|d| d.trim().trim_start_matches('@').to_lowercase()).filter(|d| !d.is_empty()).collect();
+    domains.sort();
+    domains.dedup();
+    if domains.is_empty() || domains.len() > MAX_ORG_DOMAINS {
+        return Err(format!("List between 1 and {} email domains", MAX_ORG_DOMAINS));
+    }
+    if let Some(bad) = domains.iter().find(|d| !d.contains('.') || !d.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')) {
+        return Err(format!("'{}' is not a valid email domain", bad));
+    }
+    // A domain belongs to one organization, or it could take over another's members
+    let claimed = ORGANIZATIONS.with(|orgs| {
+        orgs.borrow().values().filter(|o| Some(o.id) != org_id).find_map(|o| domains.iter().find(|d| o.email_domains.contains(d)).cloned())
+    });
+    if let Some(domain) = claimed {
+        return Err(format!("{} already belongs to another organization", domain));
+    }
+    Ok(domains)
+}
+
+// The org's admins and platform user admins
+fn org_admin_access(org_id: u64, caller: Principal) -> Result<Organization, String> {
+    ORGANIZATIONS.with(|orgs| orgs.borrow().get(&org_id))
+        .filter(|o| o.admins.contains(&caller) || has_permission(caller, Permission::ManageUsers))
+        .ok_or_else(|| "Organization not found".to_string())
+}
+
+fn org_member_view(member: OrgMember) -> OrgMemberView {
+    let user = cache::user(member.user_id);
+    OrgMemberView {
+        username: user.as_ref().map(|u| u.username.clone()).unwrap_or_default(),
+        is_active: user.as_ref().is_some_and(|u| u.is_active),
+        last_active: user.map(|u| u.last_active),
+        member,
+    }
+}
+
+fn request_org_link(org: &Organization, member: &OrgMember) {
+    notify_user(
+        member.user_id,
+        "info",
+        "system",
+        format!("{} asked to link your account to its organization; accept or decline it in your account settings", org.name),
+        Some(org.id),
+    );
+}
+
+fn provision_org_member(org: &Organization, input: OrgMemberInput, actor: Principal) -> Result<OrgMember, String> {
+    let external_id = input.external_id.trim().to_string();
+    if external_id.is_empty() || external_id.chars().count() > MAX_ORG_EXTERNAL_ID_CHARS || external_id.chars().any(char::is_control) {
+        return Err(format!("External ids must be between 1 and {} printable characters", MAX_ORG_EXTERNAL_ID_CHARS));
+    }
+    let email = input.email.trim().to_lowercase();
+    if !org_email_allowed(org, &email) {
+        return Err(format!("{} isn't on one of {}'s email domains", email, org.name));
+    }
+    let now = ic_cdk::api::time();
+    let email_owner = USERS.with(|users| users.borrow().values().find(|u| u.email.eq_ignore_ascii_case(&email)));
+    let existing = ORG_MEMBERS.with(|members| members.borrow().get(&org_member_key(org.id, &external_id)))
+        .filter(|m| cache::user(m.user_id).is_some());
+
+    let member = match existing {
+        // The owner keeps control of an account they brought; a deprovisioned link is asked for again
+        Some(mut member) if member.linked_existing => {
+            let user = cache::user(member.user_id).ok_or("User not found")?;
+            if !user.email.eq_ignore_ascii_case(&email) {
+                return Err(format!("{} manages their own account; the organization can't change it", user.email));
+            }
+            match member.status.as_str() {
+                "declined" => return Err(format!("{} declined to link their account", user.email)),
+                "deprovisioned" => {
+                    member.status = "pending_link".to_string();
+                    member.deprovisioned_at = None;
+                    request_org_link(org, &member);
+                }
+                _ => {}
+            }
+            member.updated_at = now;
+            member
+        }
+        Some(mut member) => {
+            let mut user = cache::user(member.user_id).ok_or("User not found")?;
+            if email_owner.as_ref().is_some_and(|owner| owner.id != user.id) {
+                return Err(format!("{} belongs to another account", email));
+            }
+            if role_of(user.id) != Role::User {
+                return Err(format!("{} is a staff account, which organizations can't manage", user.email));
+            }
+            user.email = email.clone();
+            if let Some(username) = input.username.filter(|u| !u.trim().is_empty()) { user.username = username; }
+            if let Some(first_name) = input.first_name.filter(|f| !f.trim().is_empty()) { user.first_name = Some(first_name); }
+            if let Some(last_name) = input.last_name.filter(|l| !l.trim().is_empty()) { user.last_name = Some(last_name); }
+            user.is_verified = true;
+            if member.status != "active" && !member.linked_existing {
+                user.is_active = true;
+                user.status = "active".to_string();
+            }
+            user.updated_at = now;
+            cache::store_user(user);
+            member.email = email;
+            member.status = "active".to_string();
+            member.deprovisioned_at = None;
+            member.updated_at = now;
+            member
+        }
+        None => {
+            let taken = ORG_MEMBERS.with(|members| {
+                members.borrow().range(format!("{:020}:", org.id)..format!("{:020};", org.id))
+                    .any(|(_, m)| matches!(m.status.as_str(), "active" | "pending_link") && email_owner.as_ref().is_some_and(|owner| owner.id == m.user_id))
+            });
+            if taken {
+                return Err(format!("{} is already provisioned under another external id", email));
+            }
+            let (user_id, linked_existing) = match email_owner {
+                Some(owner) if role_of(owner.id) != Role::User => {
+                    return Err(format!("{} is a staff account, which organizations can't link", email));
+                }
+                Some(owner) => (owner.id, true),
+                None => (upsert_external(ExternalUserInput {
+                    email: email.clone(),
+                    username: input.username.filter(|u| !u.trim().is_empty()),
+                    first_name: input.first_name.filter(|f| !f.trim().is_empty()),
+                    last_name: input.last_name.filter(|l| !l.trim().is_empty()),
+                    avatar_url: None,
+                    is_verified: Some(true),
+                }).id, false),
+            };
+            let member = OrgMember {
+                org_id: org.id,
+                external_id,
+                user_id,
+                email,
+                status: if linked_existing { "pending_link" } else { "active" }.to_string(),
+                linked_existing,
+                provisioned_at: now,
+                updated_at: now,
+                deprovisioned_at: None,
+                invited_at: None,
+            };
+            if linked_existing {
+                request_org_link(org, &member);
+            }
+            member
+        }
+    };
+    store_org_member(&member);
+    record_audit(actor, "org_member_provisioned", Some(member.user_id), format!("org {}: {}", org.id, member.external_id));
+    Ok(member)
+}
+
+fn deprovision_org_member(org: &Organization, external_id: &str, actor: Principal) -> Result<OrgMember, String> {
+    let mut member = ORG_MEMBERS.with(|members| members.borrow().get(&org_member_key(org.id, external_id.trim())))
+        .filter(|m| m.status == "active" || m.status == "pending_link")
+        .ok_or("No active member has this external id")?;
+    let now = ic_cdk::api::time();
+    member.status = "deprovisioned".to_string();
+    member.deprovisioned_at = Some(now);
+    member.updated_at = now;
+    if !member.linked_existing {
+        if let Some(mut user) = cache::user(member.user_id) {
+            user.is_active = false;
+            user.status = "inactive".to_string();
+            user.updated_at = now;
+            cache::store_user(user);
+        }
+        remove_user_auth_sessions(member.user_id);
+    }
+    store_org_member(&member);
+    record_audit(actor, "org_member_deprovisioned", Some(member.user_id), format!("org {}: {}", org.id, member.external_id));
+    Ok(member)
+}
+
+fn org_member_result(external_id: String, result: Result<OrgMember, String>) -> OrgMemberResult {
+    match result {
+        Ok(member) => OrgMemberResult { external_id, member: Some(member), error: None },
+        Err(error) => OrgMemberResult { external_id, member: None, error: Some(error) },
+    }
+}
+
+#[ic_cdk::update]
+fn org_provision_member(org_key: String, input: OrgMemberInput) -> Result<OrgMember, String> {
+    let org = org_for_key(&org_key)?;
+    provision_org_member(&org, input, ic_cdk::caller())
+}
+
+#[ic_cdk::update]
+fn org_bulk_provision_members(org_key: String, inputs: Vec<OrgMemberInput>) -> Result<Vec<OrgMemberResult>, String> {
+    let org = org_for_key(&org_key)?;
+    if inputs.is_empty() || inputs.len() > MAX_ORG_MEMBER_BATCH {
+        return Err(format!("Send between 1 and {} members per batch", MAX_ORG_MEMBER_BATCH));
+    }
+    let caller = ic_cdk::caller();
+    let mut seen = std::collections::HashSet::new();
+    Ok(inputs.into_iter().map(|input| {
+        let external_id = input.external_id.trim().to_string();
+        let result = if seen.insert(external_id.clone()) {
+            provision_org_member(&org, input, caller)
+        } else {
+            Err("This external id appears more than once in the batch".to_string())
+        };
+        org_member_result(external_id, result)
+    }).collect())
+}
+
+#[ic_cdk::update]
+fn org_deprovision_members(org_key: String, external_ids: Vec<String>) -> Result<Vec<OrgMemberResult>, String> {
+    let org = org_for_key(&org_key)?;
+    if external_ids.is_empty() || external_ids.len() > MAX_ORG_MEMBER_BATCH {
+        return Err(format!("Send between 1 and {} members per batch", MAX_ORG_MEMBER_BATCH));
+    }
+    let caller = ic_cdk::caller();
+    Ok(external_ids.into_iter().map(|external_id| {
+        let result = deprovision_org_member(&org, &external_id, caller);
+        org_member_result(external_id, result)
+    }).collect())
+}
+
+// The organization's backend has authenticated the member; the token is issued to the
+// frontend's session key, which passes it like one from login_user_session
+#[ic_cdk::update]
+async fn org_sign_in_member(org_key: String, external_id: String, session_key: Principal) -> Result<SessionLogin, String> {
+    let org = org_for_key(&org_key)?;
+    if session_key == Principal::anonymous() {
+        return Err("Sign in for a session key; anonymous callers can't hold a session".to_string());
+    }
+    let member = ORG_MEMBERS.with(|members| members.borrow().get(&org_member_key(org.id, external_id.trim())))
+        .filter(|m| m.status == "active")
+        .ok_or("No active member has this external id")?;
+    if member.linked_existing {
+        return Err("This member linked their own account and signs in with it".to_string());
+    }
+    let mut user = cache::user(member.user_id).filter(|u| u.is_active).ok_or("User not found")?;
+    if role_of(user.id) != Role::User {
+        return Err("Staff accounts can't be signed in by an organization".to_string());
+    }
+    user.last_login = Some(ic_cdk::api::time());
+    user.last_active = ic_cdk::api::time();
+    cache::store_user(user.clone());
+    issue_auth_session(user, session_key).await
+}
+
+#[ic_cdk::query]
+fn get_my_org_link_requests(session_token: Option<String>) -> Result<Vec<OrgMember>, String> {
+    let caller = session_reader(session_token)?;
+    Ok(ORG_MEMBERS.with(|members| {
+        members.borrow().values().filter(|m| m.user_id == caller && m.status == "pending_link").collect()
+    }))
+}
+
+#[ic_cdk::update]
+fn respond_to_org_link(org_id: u64, accept: bool, session_token: Option<String>) -> Result<OrgMember, String> {
+    let caller = session_caller(session_token)?;
+    let user = cache::user(caller).ok_or("User not found")?;
+    let mut member = ORG_MEMBERS.with(|members| {
+        members.borrow().range(format!("{:020}:", org_id)..format!("{:020};", org_id))
+            .map(|(_, m)| m)
+            .find(|m| m.user_id == caller && m.status == "pending_link")
+    }).ok_or("Link request not found")?;
+    let org = ORGANIZATIONS.with(|orgs| orgs.borrow().get(&org_id)).filter(|o| o.active).ok_or("Organization not found")?;
+    if accept && !org_email_allowed(&org, &user.email) {
+        return Err(format!("Your email isn't on one of {}'s domains", org.name));
+    }
+
+    member.status = if accept { "active" } else { "declined" }.to_string();
+    member.updated_at = ic_cdk::api::time();
+    store_org_member(&member);
+    record_audit(caller, if accept { "org_link_accepted" } else { "org_link_declined" }, Some(caller), format!("org {}: {}", org.id, member.external_id));
+    Ok(member)
+}
+
+#[ic_cdk::query]
+fn get_org_members(org_id: u64, status: Option<String>) -> Result<Vec<OrgMemberView>, String> {
+    org_admin_access(org_id, ic_cdk::caller())?;
+    let members: Vec<OrgMemberView> = ORG_MEMBERS.with(|members| {
+        members.borrow().range(format!("{:020}:", org_id)..format!("{:020};", org_id))
+            .map(|(_, m)| m)
+            .filter(|m| status.as_ref().is_none_or(|s| &m.status == s))
+            .collect::<Vec<_>>()
+    }).into_iter().map(org_member_view).collect();
+    ensure_fits(&members, "Filter by status to narrow the list.")?;
+    Ok(members)
+}
+
+#[ic_cdk::query]
+fn get_org_member(org_id: u64, external_id: String) -> Result<OrgMemberView, String> {
+    org_admin_access(org_id, ic_cdk::caller())?;
+    ORG_MEMBERS.with(|members| members.borrow().get(&org_member_key(org_id, external_id.trim())))
+        .map(org_member_view)
+        .ok_or_else(|| "Member not found".to_string())
+}
+
+// Returns the organization and its service key, which isn't shown again
+#[ic_cdk::update]
+async fn create_organization_admin(name: String, email_domains: Vec<String>, admins: Vec<Principal>) -> Result<(Organization, String), String> {
+    let caller = ic_cdk::caller();
+    require(caller, Permission::ManageUsers)?;
+    let name = name.trim().to_string();
+    if name.is_empty() {
+        return Err("Organization name is required".to_string());
+    }
+    validated_org_domains(email_domains.clone(), None)?;
+
+    let secret = format!("org_{}", hex_encode(&random_bytes().await?));
+    // Checked again after the await in case another call claimed a domain meanwhile
+    let email_domains = validated_org_domains(email_domains, None)?;
+    let now = ic_cdk::api::time();
+    let org = Organization {
+        id: next_id("organization"),
+        name,
+        email_domains,
+        admins,
+        key_hash: sha256_hex(&secret),
+        key_issued_at: now,
+        key_last_used_at: None,
+        active: true,
+        created_by: caller,
+        created_at: now,
+        updated_at: now,
+    };
+    ORGANIZATIONS.with(|orgs| orgs.borrow_mut().insert(org.id, org.clone()));
+    record_audit(caller, "create_organization", None, format!("{} ({})", org.name, org.email_domains.join(", ")));
+    Ok((org, secret))
+}
+
+#[ic_cdk::update]
+fn update_organization_admin(org_id: u64, name: String, email_domains: Vec<String>, admins: Vec<Principal>, active: bool) -> Result<Organization, String> {
+    let caller = ic_cdk::caller();
+    require(caller, Permission::ManageUsers)?;
+    let mut org = ORGANIZATIONS.with(|orgs| orgs.borrow().get(&org_id)).ok_or("Organization not found")?;
+    let name = name.trim().to_string();
+    if name.is_empty() {
+        return Err("Organization name is required".to_string());
+    }
+    org.email_domains = validated_org_domains(email_domains, Some(org_id))?;
+    org.name = name;
+    org.admins = admins;
+    org.active = active;
+    org.updated_at = ic_cdk::api::time();
+    ORGANIZATIONS.with(|orgs| orgs.borrow_mut().insert(org_id, org.clone()));
+    record_audit(caller, "update_organization", None, format!("{}: {} active={}", org.name, org.email_domains.join(", "), org.active));
+    Ok(org)
+}
+
+// The old key stops working immediately
+#[ic_cdk::update]
+async fn rotate_org_service_key_admin(org_id: u64) -> Result<String, String> {
+    let caller = ic_cdk::caller();
+    require(caller, Permission::ManageUsers)?;
+    ORGANIZATIONS.with(|orgs| orgs.borrow().get(&org_id)).ok_or("Organization not found")?;
+    let secret = format!("org_{}", hex_encode(&random_bytes().await?));
+    let mut org = ORGANIZATIONS.with(|orgs| orgs.borrow().get(&org_id)).ok_or("Organization not found")?;
+    org.key_hash = sha256_hex(&secret);
+    org.key_issued_at = ic_cdk::api::time();
+    org.key_last_used_at = None;
+    ORGANIZATIONS.with(|orgs| orgs.borrow_mut().insert(org_id, org.clone()));
+    record_audit(caller, "rotate_org_service_key", None, org.name);
+    Ok(secret)
+}
+
+#[ic_cdk::query]
+fn get_organizations_admin() -> Result<Vec<Organization>, String> {
+    require(ic_cdk::caller(), Permission::ManageUsers)?;
+    Ok(ORGANIZATIONS.with(|orgs| orgs.borrow().values().collect()))
+}
+
+// --- Organization Imports ---
+//
+// Organization admins import members as CSV lines of name, email, role and cohort, sent in
+// numbered batches so a large file fits in several calls and a retried call isn't applied
+// twice. Lines are checked as their batch arrives; the import job then provisions the valid
+// ones a few at a time, enrolls them in their cohort and emails an invitation through the
+// email integration. Members who are already set up come out "unchanged" and anyone invited
+// before isn't emailed again, so running the same file twice is safe. Progress is read back
+// with get_org_import.
+
+const ORG_IMPORT_JOB_INTERVAL_NS: u64 = 30 * 1_000_000_000;
+const ORG_IMPORT_JOB_ROWS: usize = 100;
+const MAX_ORG_IMPORT_BATCH_ROWS: usize = 500;
+const MAX_ORG_IMPORT_ROWS: usize = 5000;
+const MAX_ORG_IMPORT_NAME_CHARS: usize = 200;
+const ORG_IMPORT_RETENTION_NS: u64 = 30 * NANOS_PER_DAY;
+
+thread_local! {
+    static ORG_INVITES_IN_FLIGHT: RefCell<bool> = const { RefCell::new(false) };
+}
+
+fn store_org_import(import: &OrgImport) {
+    ORG_IMPORTS.with(|imports| imports.borrow_mut().insert(import.id, import.clone()));
+}
+
+fn refresh_org_import(import: &mut OrgImport, now: u64) {
+    import.processed = import.rows.iter().filter(|r| r.status != "pending").count() as u32;
+    import.failed = import.rows.iter().filter(|r| r.status == "failed").count() as u32;
+    import.invitations_sent = import.rows.iter().filter(|r| r.invitation == "sent").count() as u32;
+    let outstanding = import.rows.iter().any(|r| r.status == "pending" || r.invitation == "pending");
+    import.status = match (import.closed, outstanding) {
+        (false, _) => "receiving",
+        (true, true) => "processing",
+        (true, false) => "completed",
+    }.to_string();
+    import.updated_at = now;
+    if import.status == "completed" && import.completed_at.is_none() {
+        import.completed_at = Some(now);
+        notify_user(
+            import.created_by,
+            "info",
+            "system",
+            format!("Your member import finished: {} of {} lines imported, {} invitations sent", import.processed - import.failed, import.rows.len(), import.invitations_sent),
+            Some(import.id),
+        );
+    }
+}
+
+// Checks one line against the organization, the caller's cohorts and the lines before it
+fn validate_org_import_line(org: &Organization, caller: Principal, fields: &[String], earlier: &[OrgImportRow]) -> Result<(String, String, String, Option<u64>), String> {
+    let field = |i: usize| fields.get(i).cloned().unwrap_or_default();
+    let name = field(0);
+    if name.is_empty() || name.chars().count() > MAX_ORG_IMPORT_NAME_CHARS {
+        return Err(format!("Name must be between 1 and {} characters", MAX_ORG_IMPORT_NAME_CHARS));
+    }
+    let email = field(1).to_lowercase();
+    if !email.contains('@') {
+        return Err("Email is missing or invalid".to_string());
+    }
+    if !org_email_allowed(org, &email) {
+        return Err(format!("{} isn't on one of {}'s email domains", email, org.name));
+    }
+    if let Some(duplicate) = earlier.iter().find(|r| r.email == email && r.status != "failed") {
+        return Err(format!("Same email as line {}", duplicate.line));
+    }
+    let role = Some(field(2).to_lowercase()).filter(|r| !r.is_empty()).unwrap_or_else(|| "member".to_string());
+    if !ORG_IMPORT_ROLES.contains(&role.as_str()) {
+        return Err(format!("Role must be one of: {}", ORG_IMPORT_ROLES.join(", ")));
+    }
+    let cohort = field(3);
+    let cohort_id = if cohort.is_empty() {
+        None
+    } else {
+        let found = COHORTS.with(|cohorts| {
+            cohorts.borrow().values().find(|c| c.id.to_string() == cohort || c.title.eq_ignore_ascii_case(&cohort))
+        });
+        let found = found.ok_or_else(|| format!("No cohort matches '{}'", cohort))?;
+        if !can_manage_cohort(&found, caller) {
+            return Err(format!("You can't enroll learners in '{}'", found.title));
+        }
+        Some(found.id)
+    };
+    Ok((name, email, role, cohort_id))
+}
+
+// Returns the import with only this batch's lines. A batch that was already received returns
+// the same lines again without adding them.
+#[ic_cdk::update]
+fn import_org_members(org_id: u64, import_id: Option<u64>, batch_index: u32, csv: String, last_batch: bool) -> Result<OrgImport, String> {
+    let caller = ic_cdk::caller();
+    let org = org_admin_access(org_id, caller)?;
+    if !org.active {
+        return Err("This organization is inactive".to_string());
+    }
+    let now = ic_cdk::api::time();
+    let mut import = match import_id {
+        Some(id) => ORG_IMPORTS.with(|imports| imports.borrow().get(&id))
+            .filter(|i| i.org_id == org_id)
+            .ok_or("Import not found")?,
+        None => OrgImport {
+            id: next_id("org_import"),
+            org_id,
+            created_by: caller,
+            batches_received: 0,
+            closed: false,
+            status: "receiving".to_string(),
+            rows: Vec::new(),
+            processed: 0,
+            failed: 0,
+            invitations_sent: 0,
+            created_at: now,
+            updated_at: now,
+            completed_at: None,
+        },
+    };
+    if batch_index < import.batches_received {
+        import.rows.retain(|r| r.batch == batch_index);
+        return Ok(import);
+    }
+    if batch_index > import.batches_received {
+        return Err(format!("Expected batch {} next", import.batches_received));
+    }
+    if import.closed {
+        return Err("This import has already received its last batch".to_string());
+    }
+
+    let lines: Vec<Vec<String>> = csv_records(&csv).into_iter()
+        .map(|(_, fields)| fields.iter().map(|f| f.trim().to_string()).collect::<Vec<_>>())
+        .filter(|fields| !fields.get(1).is_some_and(|f| f.eq_ignore_ascii_case("email")))
+        .collect();
+    if lines.len() > MAX_ORG_IMPORT_BATCH_ROWS {
+        return Err(format!("Send at most {} lines per batch", MAX_ORG_IMPORT_BATCH_ROWS));
+    }
+    if import.rows.len() + lines.len() > MAX_ORG_IMPORT_ROWS {
+        return Err(format!("An import can have at most {} lines; start another for the rest", MAX_ORG_IMPORT_ROWS));
+    }
+    for fields in lines {
+        let line = import.rows.len() as u32 + 1;
+        let row = match validate_org_import_line(&org, caller, &fields, &import.rows) {
+            Ok((name, email, role, cohort_id)) => OrgImportRow {
+                batch: batch_index,
+                line,
+                name,
+                email,
+                role,
+                cohort_id,
+                status: "pending".to_string(),
+                error: None,
+                user_id: None,
+                invitation: "none".to_string(),
+            },
+            Err(error) => OrgImportRow {
+                batch: batch_index,
+                line,
+                name: fields.first().cloned().unwrap_or_default(),
+                email: fields.get(1).cloned().unwrap_or_default(),
+                role: fields.get(2).cloned().unwrap_or_default(),
+                cohort_id: None,
+                status: "failed".to_string(),
+                error: Some(error),
+                user_id: None,
+                invitation: "none".to_string(),
+            },
+        };
+        import.rows.push(row);
+    }
+    import.batches_received += 1;
+    import.closed = last_batch;
+    refresh_org_import(&mut import, now);
+    store_org_import(&import);
+    if import_id.is_none() {
+        record_audit(caller, "org_import_started", None, format!("org {}: import {}", org_id, import.id));
+    }
+    import.rows.retain(|r| r.batch == batch_index);
+    Ok(import)
+}
+
+// Provisions one line and applies its role and cohort; returns the line's status and the member
+fn apply_org_import_row(org_id: u64, row: &OrgImportRow, actor: Principal) -> Result<(String, OrgMember), String> {
+    let mut org = ORGANIZATIONS.with(|orgs| orgs.borrow().get(&org_id)).filter(|o| o.active).ok_or("The organization is inactive")?;
+    let existing = ORG_MEMBERS.with(|members| {
+        members.borrow().range(format!("{:020}:", org_id)..format!("{:020};", org_id)).map(|(_, m)| m).find(|m| m.email == row.email)
+    }).filter(|m| cache::user(m.user_id).is_some());
+    let (mut status, member) = match existing {
+        Some(member) if member.status == "active" => ("unchanged", member),
+        existing => {
+            let (first_name, last_name) = match row.name.split_once(char::is_whitespace) {
+                Some((first, last)) => (first.to_string(), Some(last.trim().to_string())),
+                None => (row.name.clone(), None),
+            };
+            let member = provision_org_member(&org, OrgMemberInput {
+                external_id: existing.as_ref().map_or_else(|| row.email.clone(), |m| m.external_id.clone()),
+                email: row.email.clone(),
+                username: None,
+                first_name: Some(first_name),
+                last_name,
+            }, actor)?;
+            let status = match existing {
+                _ if member.status == "pending_link" => "link_requested",
+                Some(_) => "updated",
+                None => "created",
+            };
+            (status, member)
+        }
+    };
+    // Role and cohort wait until the owner accepts; importing the file again applies them
+    if member.status != "active" {
+        return Ok((status.to_string(), member));
+    }
+
+    let is_admin = org.admins.contains(&member.user_id);
+    if (row.role == "admin") != is_admin {
+        if is_admin {
+            org.admins.retain(|a| *a != member.user_id);
+        } else {
+            org.admins.push(member.user_id);
+        }
+        ORGANIZATIONS.with(|orgs| orgs.borrow_mut().insert(org.id, org.clone()));
+        if status == "unchanged" {
+            status = "updated";
+        }
+    }
+    if let Some(cohort_id) = row.cohort_id {
+        if cohort_enrollment(cohort_id, member.user_id).is_none() {
+            add_cohort_enrollment(&get_cohort(cohort_id)?, member.user_id)?;
+            if status == "unchanged" {
+                status = "updated";
+            }
+        }
+    }
+    Ok((status.to_string(), member))
+}
+
+fn run_org_imports(now: u64) {
+    let email_enabled = get_config().email.enabled;
+    let mut budget = ORG_IMPORT_JOB_ROWS;
+    let mut expired = Vec::new();
+    let open: Vec<OrgImport> = ORG_IMPORTS.with(|imports| {
+        imports.borrow().values().filter(|i| {
+            if i.completed_at.is_some_and(|at| now.saturating_sub(at) > ORG_IMPORT_RETENTION_NS) {
+                expired.push(i.id);
+            }
+            i.status != "completed"
+        }).collect()
+    });
+    ORG_IMPORTS.with(|imports| {
+        let mut imports = imports.borrow_mut();
+        for id in expired {
+            imports.remove(&id);
+        }
+    });
+
+    for mut import in open {
+        if budget == 0 {
+            break;
+        }
+        let mut changed = false;
+        for row in import.rows.iter_mut().filter(|r| r.status == "pending").take(budget) {
+            budget -= 1;
+            changed = true;
+            match apply_org_import_row(import.org_id, row, import.created_by) {
+                Ok((status, member)) => {
+                    row.status = status;
+                    row.user_id = Some(member.user_id);
+                    row.invitation = match (member.invited_at, email_enabled) {
+                        (Some(_), _) => "none",
+                        (None, true) => "pending",
+                        (None, false) => "skipped",
+                    }.to_string();
+                }
+                Err(e) => {
+                    row.status = "failed".to_string();
+                    row.error = Some(e);
+                }
+            }
+        }
+        if changed {
+            refresh_org_import(&mut import, now);
+            store_org_import(&import);
+        }
+    }
+
+    if email_enabled && !ORG_INVITES_IN_FLIGHT.with(|f| f.replace(true)) {
+        ic_cdk::spawn(async move {
+            send_org_invitations().await;
+            ORG_INVITES_IN_FLIGHT.with(|f| *f.borrow_mut() = false);
+        });
+    }
+}
+
+async fn send_org_invitations() {
+    let config = get_config();
+    let mut due: Vec<(u64, u64, OrgImportRow)> = Vec::new();
+    ORG_IMPORTS.with(|imports| {
+        for import in imports.borrow().values().filter(|i| i.status == "processing") {
+            for row in import.rows.iter().filter(|r| r.invitation == "pending") {
+                if due.len() < config.email.batch_size.max(1) as usize {
+                    due.push((import.id, import.org_id, row.clone()));
+                }
+            }
+        }
+    });
+
+    for (import_id, org_id, row) in due {
+        let Some(user_id) = row.user_id else {
+            continue;
+        };
+        let org_name = ORGANIZATIONS.with(|orgs| orgs.borrow().get(&org_id)).map(|o| o.name).unwrap_or_default();
+        let (subject, text) = if row.status == "link_requested" {
+            (
+                format!("{} wants to link your Cogni account", org_name),
+                format!("Hi {},\n\n{} asked to link your Cogni account to its organization. Sign in to Cogni to accept or decline.\n", row.name, org_name),
+            )
+        } else {
+            (
+                format!("You've been added to {} on Cogni", org_name),
+                format!("Hi {},\n\n{} has added you to Cogni. Sign in with your {} account to start learning.\n", row.name, org_name, org_name),
+            )
+        };
+        let payload = json!({
+            "from": config.email.from_address,
+            "to": [row.email],
+            "subject": subject,
+            "text": text,
+        });
+        // Keyed by member so the provider drops repeats across imports
+        let result = post_to_email_provider(&config, payload, &format!("org-invite:{}:{}", org_id, user_id)).await;
+
+        let now = ic_cdk::api::time();
+        if result.is_ok() {
+            let member = ORG_MEMBERS.with(|members| {
+                members.borrow().range(format!("{:020}:", org_id)..format!("{:020};", org_id)).map(|(_, m)| m).find(|m| m.user_id == user_id)
+            });
+            if let Some(mut member) = member {
+                member.invited_at = Some(now);
+                store_org_member(&member);
+            }
+        }
+        if let Some(mut import) = ORG_IMPORTS.with(|imports| imports.borrow().get(&import_id)) {
+            if let Some(stored) = import.rows.iter_mut().find(|r| r.line == row.line) {
+                match result {
+                    Ok(()) => stored.invitation = "sent".to_string(),
+                    Err(e) => {
+                        stored.invitation = "failed".to_string();
+                        stored.error = Some(format!("Invitation not sent: {}", e));
+                    }
+                }
+            }
+            refresh_org_import(&mut import, now);
+            store_org_import(&import);
+        }
+    }
+}
+
+// Without their lines; fetch one import for those
+#[ic_cdk::query]
+fn get_org_imports(org_id: u64) -> Result<Vec<OrgImport>, String> {
+    org_admin_access(org_id, ic_cdk::caller())?;
+    let mut imports: Vec<OrgImport> = ORG_IMPORTS.with(|imports| {
+        imports.borrow().values().filter(|i| i.org_id == org_id).map(|mut i| {
+            i.rows = Vec::new();
+            i
+        }).collect()
+    });
+    imports.sort_by_key(|i| std::cmp::Reverse(i.created_at));
+    Ok(imports)
+}
+
+#[ic_cdk::query]
+fn get_org_import(org_id: u64, import_id: u64, status: Option<String>) -> Result<OrgImport, String> {
+    org_admin_access(org_id, ic_cdk::caller())?;
+    let mut import = ORG_IMPORTS.with(|imports| imports.borrow().get(&import_id))
+        .filter(|i| i.org_id == org_id)
+        .ok_or("Import not found")?;
+    if let Some(status) = status {
+        import.rows.retain(|r| r.status == status);
+    }
+    ensure_fits(&import.rows, "Filter the lines by status.")?;
+    Ok(import)
+}
+
+// --- Organization Reports ---
+//
+// Progress and engagement of an organization's members in a cohort, or in every cohort of a
+// course (a tutor's cohorts). Only active members are counted, so admins never see learners
+// outside their organization, and only counts and scores are read: no chat messages, session
+// topics or exam answers. Time and comprehension are recorded per learner rather than per
+// course, so they cover everything a learner studied since enrolling.
+
+const ORG_AT_RISK_INACTIVE_DAYS: u64 = 7;
+const ORG_AT_RISK_COMPREHENSION: f64 = 0.5;
+const ORG_AT_RISK_EXAM_SCORE: f64 = 50.0;
+const ORG_AT_RISK_MIN_SAMPLES: u32 = 3; // comprehension scores needed before a low average flags a learner
+const ORG_AT_RISK_MIN_EXAMS: usize = 2;
+const ORG_REPORT_CSV_ROWS: usize = 1000;
+
+const ORG_REPORT_CSV_HEADER: &str = "cohort_id,user_id,username,email,enrolled_at,completed_modules,total_modules,progress_percent,completed_at,minutes_spent,average_comprehension,average_exam_score,last_active,at_risk,risk_reasons";
+
+fn org_report_cohorts(scope: &str, scope_id: &str) -> Result<Vec<Cohort>, String> {
+    match scope {
+        "cohort" => {
+            let cohort_id = scope_id.trim().parse::<u64>().map_err(|_| "Cohort not found".to_string())?;
+            Ok(vec![get_cohort(cohort_id)?])
+        }
+        "course" => {
+            let tutor_id = canonical_public_id("tutor", scope_id.trim());
+            let cohorts: Vec<Cohort> = COHORTS.with(|cohorts| {
+                cohorts.borrow().values().filter(|c| c.tutor_id == tutor_id).collect()
+            });
+            if cohorts.is_empty() {
+                return Err("This course has no cohorts".to_string());
+            }
+            Ok(cohorts)
+        }
+        _ => Err(format!("Scope must be one of: {}", ORG_REPORT_SCOPES.join(", "))),
+    }
+}
+
+// One entry per enrollment of an active member, ordered by cohort then enrollment time
+fn org_learner_reports(org_id: u64, cohorts: &[Cohort], now: u64) -> Result<Vec<OrgLearnerReport>, String> {
+    let hint = "Report on a single cohort instead.";
+    let members: HashMap<Principal, String> = ORG_MEMBERS.with(|members| {
+        members.borrow().range(format!("{:020}:", org_id)..format!("{:020};", org_id))
+            .map(|(_, m)| m)
+            .filter(|m| m.status == "active")
+            .map(|m| (m.user_id, m.email))
+            .collect()
+    });
+    let mut scanned = 0;
+    let mut enrollments: Vec<CohortEnrollment> = Vec::new();
+    COHORT_ENROLLMENTS.with(|all| -> Result<(), String> {
+        for (_, e) in all.borrow().iter() {
+            scanned += 1;
+            scan_checkpoint(scanned, hint)?;
+            if members.contains_key(&e.user_id) && cohorts.iter().any(|c| c.id == e.cohort_id) {
+                enrollments.push(e);
+            }
+        }
+        Ok(())
+    })?;
+    enrollments.sort_by_key(|e| (e.cohort_id, e.enrolled_at));
+    let learners: std::collections::HashSet<Principal> = enrollments.iter().map(|e| e.user_id).collect();
+
+    // (time, minutes, comprehension sum, comprehension samples); aggregates are timed by the
+    // start of their local day
+    let mut study: HashMap<Principal, Vec<(u64, u64, f64, u32)>> = HashMap::new();
+    METRICS_AGGREGATES.with(|aggregates| -> Result<(), String> {
+        for (_, a) in aggregates.borrow().iter() {
+            scanned += 1;
+            scan_checkpoint(scanned, hint)?;
+            if learners.contains(&a.user_id) {
+                let at = local_day_start(a.day, user_offset_ns(a.user_id));
+                study.entry(a.user_id).or_default().push((at, a.time_spent_minutes, a.comprehension_sum, a.comprehension_samples));
+            }
+        }
+        Ok(())
+    })?;
+    LEARNING_METRICS.with(|metrics| -> Result<(), String> {
+        for (_, m) in metrics.borrow().iter() {
+            scanned += 1;
+            scan_checkpoint(scanned, hint)?;
+            if learners.contains(&m.user_id) {
+                let sum = m.comprehension_scores.values().sum::<f64>();
+                study.entry(m.user_id).or_default().push((m.created_at, m.time_spent_minutes as u64, sum, m.comprehension_scores.len() as u32));
+            }
+        }
+        Ok(())
+    })?;
+    let mut exams: HashMap<Principal, Vec<(String, u64, f64)>> = HashMap::new();
+    EXAMS.with(|all| -> Result<(), String> {
+        for (_, exam) in all.borrow().iter() {
+            scanned += 1;
+            scan_checkpoint(scanned, hint)?;
+            if let (true, Some(score)) = (learners.contains(&exam.user_id), exam.score_percent) {
+                exams.entry(exam.user_id).or_default().push((exam.tutor_id, exam.created_at, score));
+            }
+        }
+        Ok(())
+    })?;
+
+    let flagged = open_risk_reasons(&learners);
+    let inactive_after = now.saturating_sub(ORG_AT_RISK_INACTIVE_DAYS * NANOS_PER_DAY);
+    Ok(enrollments.into_iter().filter_map(|e| {
+        let cohort = cohorts.iter().find(|c| c.id == e.cohort_id)?;
+        let user = cache::user(e.user_id)?;
+        let entries: Vec<&(u64, u64, f64, u32)> = study.get(&e.user_id).map(|s| s.iter().filter(|s| s.0 >= e.enrolled_at).collect()).unwrap_or_default();
+        let minutes_spent = entries.iter().map(|s| s.1).sum();
+        let samples: u32 = entries.iter().map(|s| s.3).sum();
+        let average_comprehension = (samples > 0).then(|| entries.iter().map(|s| s.2).sum::<f64>() / samples as f64);
+        let scores: Vec<f64> = exams.get(&e.user_id).map(|x| {
+            x.iter().filter(|(tutor_id, at, _)| *tutor_id == cohort.tutor_id && *at >= e.enrolled_at).map(|x| x.2).collect()
+        }).unwrap_or_default();
+        let average_exam_score = (!scores.is_empty()).then(|| scores.iter().sum::<f64>() / scores.len() as f64);
+        let last_active = entries.iter().map(|s| s.0).max().unwrap_or(0).max(user.last_active).max(e.enrolled_at);
+
+        let mut risk_reasons = Vec::new();
+        if e.completed_at.is_none() && last_active < inactive_after {
+            risk_reasons.push("inactive".to_string());
+        }
+        if samples >= ORG_AT_RISK_MIN_SAMPLES && average_comprehension.is_some_and(|c| c < ORG_AT_RISK_COMPREHENSION) {
+            risk_reasons.push("low_comprehension".to_string());
+        }
+        if scores.len() >= ORG_AT_RISK_MIN_EXAMS && average_exam_score.is_some_and(|s| s < ORG_AT_RISK_EXAM_SCORE) {
+            risk_reasons.push("low_exam_scores".to_string());
+        }
+        risk_reasons.extend(flagged.get(&e.user_id).cloned().unwrap_or_default());
+        let total_modules = cohort.modules.len() as u32;
+        let completed_modules = cohort.modules.iter().filter(|m| e.completed_modules.contains(&m.order)).count() as u32;
+        Some(OrgLearnerReport {
+            user_id: e.user_id,
+            username: user.username,
+            email: members.get(&e.user_id).cloned().unwrap_or_default(),
+            cohort_id: e.cohort_id,
+            enrolled_at: e.enrolled_at,
+            completed_modules,
+            total_modules,
+            progress_percent: if total_modules == 0 { 0.0 } else { completed_modules as f64 * 100.0 / total_modules as f64 },
+            completed_at: e.completed_at,
+            minutes_spent,
+            average_comprehension,
+            average_exam_score,
+            last_active,
+            risk_reasons,
+        })
+    }).collect())
+}
+
+fn average(values: impl Iterator<Item = f64>) -> Option<f64> {
+    let (sum, count) = values.fold((0.0, 0), |(sum, count), v| (sum + v, count + 1));
+    (count > 0).then(|| sum / count as f64)
+}
+
+// Quotes a CSV field when needed, and defuses values a spreadsheet would run as a formula
+fn csv_cell(value: &str) -> String {
+    let value = if value.starts_with(['=', '+', '-', '@']) { format!("'{}", value) } else { value.to_string() };
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value
+    }
+}
+
+fn org_report_csv_line(learner: &OrgLearnerReport) -> String {
+    let optional = |value: Option<f64>| value.map(|v| format!("{:.2}", v)).unwrap_or_default();
+    [
+        learner.cohort_id.to_string(),
+        learner.user_id.to_text(),
+        csv_cell(&learner.username),
+        csv_cell(&learner.email),
+        iso8601(learner.enrolled_at),
+        learner.completed_modules.to_string(),
+        learner.total_modules.to_string(),
+        format!("{:.1}", learner.progress_percent),
+        learner.completed_at.map(iso8601).unwrap_or_default(),
+        learner.minutes_spent.to_string(),
+        optional(learner.average_comprehension),
+        optional(learner.average_exam_score),
+        iso8601(learner.last_active),
+        (!learner.risk_reasons.is_empty()).to_string(),
+        learner.risk_reasons.join(";"),
+    ].join(",")
+}
+
+#[ic_cdk::query]
+fn get_org_progress_report(org_id: u64, scope: String, scope_id: String) -> Result<OrgProgressReport, String> {
+    org_admin_access(org_id, ic_cdk::caller())?;
+    let cohorts = org_report_cohorts(&scope, &scope_id)?;
+    let now = ic_cdk::api::time();
+    let learners = org_learner_reports(org_id, &cohorts, now)?;
+
+    let count = learners.len();
+    let percent = |part: usize| if count == 0 { 0.0 } else { part as f64 * 100.0 / count as f64 };
+    let completed = learners.iter().filter(|l| l.completed_at.is_some()).count();
+    let total_minutes: u64 = learners.iter().map(|l| l.minutes_spent).sum();
+    let modules = match cohorts.as_slice() {
+        [cohort] if scope == "cohort" => {
+            let reported: std::collections::HashSet<Principal> = learners.iter().map(|l| l.user_id).collect();
+            let completed_modules: Vec<Vec<u32>> = COHORT_ENROLLMENTS.with(|enrollments| {
+                enrollments.borrow().values()
+                    .filter(|e| e.cohort_id == cohort.id && reported.contains(&e.user_id))
+                    .map(|e| e.completed_modules)
+                    .collect()
+            });
+            cohort.modules.iter().map(|m| {
+                let completed_count = completed_modules.iter().filter(|done| done.contains(&m.order)).count();
+                OrgModuleProgress {
+                    order: m.order,
+                    title: m.title.clone(),
+                    unlocked: m.unlocks_at <= now,
+                    completed_count: completed_count as u32,
+                    completion_rate: percent(completed_count),
+                }
+            }).collect()
+        }
+        _ => Vec::new(),
+    };
+    let report = OrgProgressReport {
+        org_id,
+        scope,
+        scope_id: scope_id.trim().to_string(),
+        cohort_ids: cohorts.iter().map(|c| c.id).collect(),
+        learners: count as u32,
+        completed: completed as u32,
+        completion_rate: percent(completed),
+        average_progress: average(learners.iter().map(|l| l.progress_percent)).unwrap_or(0.0),
+        average_comprehension: average(learners.iter().filter_map(|l| l.average_comprehension)),
+        average_exam_score: average(learners.iter().filter_map(|l| l.average_exam_score)),
+        total_minutes,
+        average_minutes: if count == 0 { 0.0 } else { total_minutes as f64 / count as f64 },
+        modules,
+        at_risk: learners.into_iter().filter(|l| !l.risk_reasons.is_empty()).collect(),
+        generated_at: now,
+    };
+    ensure_fits(&report.at_risk, "Export the report as CSV for the full list of learners.")?;
+    Ok(report)
+}
+
+// One line per learner enrollment; call again with next_offset until it is None
+#[ic_cdk::query]
+fn export_org_progress_csv(org_id: u64, scope: String, scope_id: String, offset: u32) -> Result<OrgReportCsvChunk, String> {
+    org_admin_access(org_id, ic_cdk::caller())?;
+    let cohorts = org_report_cohorts(&scope, &scope_id)?;
+    let learners = org_learner_reports(org_id, &cohorts, ic_cdk::api::time())?;
+
+    let mut csv = if offset == 0 { format!("{}\n", ORG_REPORT_CSV_HEADER) } else { String::new() };
+    let mut rows = 0;
+    for learner in learners.iter().skip(offset as usize).take(ORG_REPORT_CSV_ROWS) {
+        let line = org_report_csv_line(learner);
+        if rows > 0 && csv.len() + line.len() + 1 > MAX_RESPONSE_BYTES {
+            break;
+        }
+        csv.push_str(&line);
+        csv.push('\n');
+        rows += 1;
+    }
+    let next = offset as usize + rows;
+    Ok(OrgReportCsvChunk {
+        csv,
+        offset,
+        rows: rows as u32,
+        total: learners.len() as u32,
+        next_offset: (next < learners.len()).then_some(next as u32),
+    })
+}
+
+// --- Learner Risk ---
+//
+// A periodic job compares each learner's recent study time and comprehension with their own
+// baseline from the weeks before and flags sharp drops. Flagged learners get a short
+// encouraging message written for them, unless nudges are off or they muted reminders, and
+// show up in their organizations' and study groups' dashboards. Once the outcome window
+// passes, the flag closes with whether the learner got back to studying, so re-engagement
+// with and without a nudge can be compared.
+
+const LEARNER_RISK_JOB_INTERVAL_NS: u64 = 6 * NANOS_PER_HOUR;
+const LEARNER_RISK_MIN_SAMPLES: u32 = 3; // comprehension scores needed in each window to compare them
+const LEARNER_RISK_RE_ENGAGED_SHARE: f64 = 0.5; // of the baseline's daily study time
+const LEARNER_NUDGE_BATCH: usize = 20;
+
+thread_local! {
+    static LEARNER_NUDGES_IN_FLIGHT: RefCell<bool> = const { RefCell::new(false) };
+}
+
+#[derive(Default, Clone, Copy)]
+struct StudyTotals {
+    minutes: u64,
+    comprehension_sum: f64,
+    samples: u32,
+}
+
+impl StudyTotals {
+    fn comprehension(&self) -> Option<f64> {
+        (self.samples > 0).then(|| self.comprehension_sum / self.samples as f64)
+    }
+}
+
+// Study per user in `windows` buckets; window_of picks the bucket for a user and time, if any.
+// Pruned metrics count from the start of their local day.
+fn study_totals(windows: usize, window_of: impl Fn(Principal, u64) -> Option<usize>) -> HashMap<Principal, Vec<StudyTotals>> {
+    let mut totals: HashMap<Principal, Vec<StudyTotals>> = HashMap::new();
+    let mut add = |user_id: Principal, at: u64, minutes: u64, comprehension_sum: f64, samples: u32| {
+        if let Some(window) = window_of(user_id, at) {
+            let entry = &mut totals.entry(user_id).or_insert_with(|| vec![StudyTotals::default(); windows])[window];
+            entry.minutes += minutes;
+            entry.comprehension_sum += comprehension_sum;
+            entry.samples += samples;
+        }
+    };
+    METRICS_AGGREGATES.with(|aggregates| {
+        for (_, a) in aggregates.borrow().iter() {
+            let at = local_day_start(a.day, user_offset_ns(a.user_id));
+            add(a.user_id, at, a.time_spent_minutes, a.comprehension_sum, a.comprehension_samples);
+        }
+    });
+    LEARNING_METRICS.with(|metrics| {
+        for (_, m) in metrics.borrow().iter() {
+            add(m.user_id, m.created_at, m.time_spent_minutes as u64, m.comprehension_scores.values().sum(), m.comprehension_scores.len() as u32);
+        }
+    });
+    totals
+}
+
+fn store_risk_flag(flag: &LearnerRiskFlag) {
+    LEARNER_RISK_FLAGS.with(|flags| flags.borrow_mut().insert(flag.id, flag.clone()));
+}
+
+fn run_learner_risk(now: u64) {
+    let config = get_config().learner_risk;
+    if !config.enabled {
+        return;
+    }
+    let flags: Vec<LearnerRiskFlag> = LEARNER_RISK_FLAGS.with(|flags| flags.borrow().values().collect());
+    close_risk_flags(&flags, &config, now);
+
+    let recent_from = now.saturating_sub(config.recent_days.max(1) as u64 * NANOS_PER_DAY);
+    let baseline_from = recent_from.saturating_sub(config.baseline_days.max(1) as u64 * NANOS_PER_DAY);
+    let cooldown = config.cooldown_days as u64 * NANOS_PER_DAY;
+    let skip: std::collections::HashSet<Principal> = flags.iter()
+        .filter(|f| f.status == "open" || f.closed_at.is_some_and(|at| now.saturating_sub(at) < cooldown))
+        .map(|f| f.user_id)
+        .collect();
+    let study = study_totals(2, |user_id, at| match at {
+        _ if skip.contains(&user_id) => None,
+        at if at >= recent_from && at < now => Some(1),
+        at if at >= baseline_from && at < recent_from => Some(0),
+        _ => None,
+    });
+
+    for (user_id, windows) in study {
+        let (baseline, recent) = (windows[0], windows[1]);
+        if baseline.minutes < config.min_baseline_minutes as u64 {
+            continue;
+        }
+        let Some(user) = cache::user(user_id).filter(|u| u.is_active) else {
+            continue;
+        };
+        let baseline_rate = baseline.minutes as f64 / config.baseline_days.max(1) as f64;
+        let recent_rate = recent.minutes as f64 / config.recent_days.max(1) as f64;
+        let mut reasons = Vec::new();
+        if recent_rate <= baseline_rate * (100 - config.activity_drop_percent.min(100)) as f64 / 100.0 {
+            reasons.push("activity_drop".to_string());
+        }
+        if let (Some(before), Some(after)) = (baseline.comprehension(), recent.comprehension()) {
+            if baseline.samples >= LEARNER_RISK_MIN_SAMPLES && recent.samples >= LEARNER_RISK_MIN_SAMPLES && before - after >= config.comprehension_drop {
+                reasons.push("comprehension_drop".to_string());
+            }
+        }
+        if reasons.is_empty() {
+            continue;
+        }
+        store_risk_flag(&LearnerRiskFlag {
+            id: next_id("learner_risk_flag"),
+            user_id,
+            reasons,
+            baseline_minutes_per_day: baseline_rate,
+            recent_minutes_per_day: recent_rate,
+            baseline_comprehension: baseline.comprehension(),
+            recent_comprehension: recent.comprehension(),
+            flagged_at: now,
+            status: "open".to_string(),
+            nudge: if config.nudges_enabled && !user.settings.review_reminders_muted { "pending" } else { "skipped" }.to_string(),
+            nudge_message: None,
+            nudged_at: None,
+            outcome: None,
+            minutes_per_day_after: None,
+            closed_at: None,
+        });
+    }
+
+    if !LEARNER_NUDGES_IN_FLIGHT.with(|f| f.replace(true)) {
+        ic_cdk::spawn(async {
+            send_learner_nudges().await;
+            LEARNER_NUDGES_IN_FLIGHT.with(|f| *f.borrow_mut() = false);
+        });
+    }
+}
+
+// Measures study in the outcome window of each open flag whose window has passed
+fn close_risk_flags(flags: &[LearnerRiskFlag], config: &LearnerRiskConfig, now: u64) {
+    let window = config.outcome_days.max(1) as u64 * NANOS_PER_DAY;
+    let due: HashMap<Principal, LearnerRiskFlag> = flags.iter()
+        .filter(|f| f.status == "open" && f.flagged_at + window <= now)
+        .map(|f| (f.user_id, f.clone()))
+        .collect();
+    if due.is_empty() {
+        return;
+    }
+    let study = study_totals(1, |user_id, at| {
+        due.get(&user_id).filter(|f| at >= f.flagged_at && at < f.flagged_at + window).map(|_| 0)
+    });
+    for (user_id, mut flag) in due {
+        let minutes = study.get(&user_id).map_or(0, |w| w[0].minutes);
+        let rate = minutes as f64 / config.outcome_days.max(1) as f64;
+        flag.minutes_per_day_after = Some(rate);
+        flag.outcome = Some(if rate >= flag.baseline_minutes_per_day * LEARNER_RISK_RE_ENGAGED_SHARE { "re_engaged" } else { "no_change" }.to_string());
+        if flag.nudge == "pending" {
+            flag.nudge = "skipped".to_string();
+        }
+        flag.status = "closed".to_string();
+        flag.closed_at = Some(now);
+        store_risk_flag(&flag);
+    }
+}
+
+async fn send_learner_nudges() {
+    let pending: Vec<LearnerRiskFlag> = LEARNER_RISK_FLAGS.with(|flags| {
+        flags.borrow().values().filter(|f| f.status == "open" && f.nudge == "pending").take(LEARNER_NUDGE_BATCH).collect()
+    });
+    for flag in pending {
+        let Some(user) = cache::user(flag.user_id) else {
+            continue;
+        };
+        let display_name = user_display_name(&user);
+        let topic = CHAT_SESSIONS.with(|sessions| {
+            sessions.borrow().values().filter(|s| s.user_id == flag.user_id).max_by_key(|s| s.updated_at).map(|s| s.topic)
+        });
+        let situation = if flag.reasons.iter().any(|r| r == "comprehension_drop") {
+            "has found their recent material harder than usual"
+        } else {
+            "has had less time for studying lately"
+        };
+        let prompt = format!(
+            "Write a short, warm message (at most two sentences) encouraging {} to keep going with their studies{}. They {}. \
+            Don't mention tracking, scores or statistics. Return only the message.",
+            display_name,
+            topic.map(|t| format!(" in {}", t)).unwrap_or_default(),
+            situation
+        );
+        let message = match call_groq_ai(&prompt, "nudge", ic_cdk::id()).await {
+            Ok(response) => trim_to_length(process_ai_response(response, &response_processing_for(ic_cdk::id(), "text")).trim(), 300),
+            Err(e) => {
+                ic_cdk::println!("Nudge for {} failed: {}", flag.user_id, e);
+                String::new()
+            }
+        };
+        let message = if message.is_empty() {
+            format!("Hi {}, every bit of progress counts. Pick up where you left off whenever you're ready.", display_name)
+        } else {
+            message
+        };
+
+        // The flag may have closed while the message was written
+        let Some(mut flag) = LEARNER_RISK_FLAGS.with(|flags| flags.borrow().get(&flag.id)).filter(|f| f.nudge == "pending") else {
+            continue;
+        };
+        let now = ic_cdk::api::time();
+        flag.nudge = "sent".to_string();
+        flag.nudge_message = Some(message.clone());
+        flag.nudged_at = Some(now);
+        store_risk_flag(&flag);
+        notify_user(flag.user_id, "encouragement", "tutor", message, Some(flag.id));
+    }
+}
+
+// For dashboards: the learner's message to them is left out
+fn learner_risk_views(learners: &std::collections::HashSet<Principal>, include_closed: bool) -> Vec<LearnerRiskView> {
+    let mut views: Vec<LearnerRiskView> = LEARNER_RISK_FLAGS.with(|flags| {
+        flags.borrow().values()
+            .filter(|f| learners.contains(&f.user_id) && (include_closed || f.status == "open"))
+            .collect::<Vec<_>>()
+    }).into_iter().map(|mut flag| {
+        flag.nudge_message = None;
+        LearnerRiskView {
+            username: cache::user(flag.user_id).map(|u| u.username).unwrap_or_default(),
+            flag,
+        }
+    }).collect();
+    views.sort_by_key(|v| std::cmp::Reverse(v.flag.flagged_at));
+    views
+}
+
+fn open_risk_reasons(learners: &std::collections::HashSet<Principal>) -> HashMap<Principal, Vec<String>> {
+    let mut reasons: HashMap<Principal, Vec<String>> = HashMap::new();
+    LEARNER_RISK_FLAGS.with(|flags| {
+        for (_, flag) in flags.borrow().iter().filter(|(_, f)| f.status == "open" && learners.contains(&f.user_id)) {
+            reasons.entry(flag.user_id).or_default().extend(flag.reasons);
+        }
+    });
+    reasons
+}
+
+#[ic_cdk::query]
+fn get_org_at_risk_learners(org_id: u64, include_closed: bool) -> Result<Vec<LearnerRiskView>, String> {
+    org_admin_access(org_id, ic_cdk::caller())?;
+    let members = ORG_MEMBERS.with(|members| {
+        members.borrow().range(format!("{:020}:", org_id)..format!("{:020};", org_id))
+            .map(|(_, m)| m)
+            .filter(|m| m.status == "active")
+            .map(|m| m.user_id)
+            .collect()
+    });
+    let views = learner_risk_views(&members, include_closed);
+    ensure_fits(&views, "Leave out closed flags.")?;
+    Ok(views)
+}
+
+#[ic_cdk::query]
+fn get_group_at_risk_learners(group_id: u64, include_closed: bool) -> Result<Vec<LearnerRiskView>, String> {
+    let group = get_study_group(group_id).ok_or("Study group not found.")?;
+    if !can_manage_group(&group, ic_cdk::caller()) {
+        return Err("Only the group's admins and moderators can see this".to_string());
+    }
+    let members = GROUP_MEMBERSHIPS.with(|memberships| {
+        memberships.borrow().values()
+            .filter(|m| m.group_id == group_id && m.status == "active")
+            .map(|m| m.user_id)
+            .collect()
+    });
+    let views = learner_risk_views(&members, include_closed);
+    ensure_fits(&views, "Leave out closed flags.")?;
+    Ok(views)
+}
+
+#[ic_cdk::query]
+fn get_learner_risk_stats_admin() -> Result<LearnerRiskStats, String> {
+    require(ic_cdk::caller(), Permission::ManageUsers)?;
+    let mut stats = LearnerRiskStats::default();
+    LEARNER_RISK_FLAGS.with(|flags| {
+        for (_, flag) in flags.borrow().iter() {
+            stats.flagged += 1;
+            let re_engaged = flag.outcome.as_deref() == Some("re_engaged");
+            match (flag.status.as_str(), flag.nudge.as_str()) {
+                ("open", _) => stats.open += 1,
+                (_, "sent") => {
+                    stats.measured_nudged += 1;
+                    stats.re_engaged_nudged += re_engaged as u32;
+                }
+                _ => {
+                    stats.measured_not_nudged += 1;
+                    stats.re_engaged_not_nudged += re_engaged as u32;
+                }
+            }
+            stats.nudges_sent += (flag.nudge == "sent") as u32;
+        }
+    });
+    let rate = |part: u32, of: u32| (of > 0).then(|| part as f64 * 100.0 / of as f64);
+    stats.re_engagement_rate_nudged = rate(stats.re_engaged_nudged, stats.measured_nudged);
+    stats.re_engagement_rate_not_nudged = rate(stats.re_engaged_not_nudged, stats.measured_not_nudged);
+    Ok(stats)
+}
+
+#[ic_cdk::update]
+fn set_learner_risk_config_admin(learner_risk: LearnerRiskConfig) -> Result<CanisterConfig, String> {
+    let caller = ic_cdk::caller();
+    require(caller, Permission::ManageSystem)?;
+    if learner_risk.recent_days == 0 || learner_risk.baseline_days == 0 || learner_risk.outcome_days == 0 {
+        return Err("Recent, baseline and outcome windows must be at least a day".to_string());
+    }
+    if !(1..=100).contains(&learner_risk.activity_drop_percent) {
+        return Err("Activity drop must be between 1% and 100%".to_string());
+    }
+    if !(learner_risk.comprehension_drop > 0.0 && learner_risk.comprehension_drop <= 1.0) {
+        return Err("Comprehension drop must be above 0 and at most 1".to_string());
+    }
+
+    let details = format!(
+        "enabled={} recent={}d baseline={}d drop={}% comprehension_drop={} nudges={} outcome={}d",
+        learner_risk.enabled, learner_risk.recent_days, learner_risk.baseline_days, learner_risk.activity_drop_percent,
+        learner_risk.comprehension_drop, learner_risk.nudges_enabled, learner_risk.outcome_days
+    );
+    let config = update_config(|config| {
+        config.learner_risk = learner_risk;
+        Ok(())
+    })?;
+    record_audit(caller, "set_learner_risk_config", None, details);
+    Ok(config)
+}
+
+// --- Email and Username Changes ---
+//
+// Both follow register_user's uniqueness rules. Changing the email needs the account password,
+// counts wrong passwords toward the login lockout like a failed login, and leaves the account
+// unverified until the new address is confirmed. Records keyed by email move with it.
+
+const MAX_EMAIL_CHARS: usize = 254;
+
+#[ic_cdk::update]
+fn change_email(new_email: String, password: String, session_token: Option<String>) -> Result<User, String> {
+    let caller = session_caller(session_token)?;
+    let mut user = cache::user(caller).ok_or("User not found")?;
+    let new_email = new_email.trim().to_string();
+    if new_email.chars().count() > MAX_EMAIL_CHARS || new_email.split_once('@').is_none_or(|(local, domain)| local.is_empty() || domain.is_empty()) {
+        return Err("Enter a valid email address".to_string());
+    }
+    if new_email == user.email {
+        return Err("That's already your email".to_string());
+    }
+    let password_hash = user.password_hash.as_deref().ok_or("Account not set up for password authentication")?;
+    let now = ic_cdk::api::time();
+    ensure_not_locked(&user.email, now)?;
+    if !password::verify(&password, password_hash, user.password_salt.as_deref()) {
+        record_login_failure(&user.email, now);
+        return Err("Invalid password".to_string());
+    }
+    ensure_email_available(&new_email, Some(caller))?;
+
+    // Organizations only manage addresses on their own domains
+    let memberships: Vec<OrgMember> = ORG_MEMBERS.with(|members| {
+        members.borrow().values().filter(|m| m.user_id == caller && m.status == "active").collect()
+    });
+    for member in &memberships {
+        if let Some(org) = ORGANIZATIONS.with(|orgs| orgs.borrow().get(&member.org_id)).filter(|o| !org_email_allowed(o, &new_email)) {
+            return Err(format!("{} manages this account; use an address on one of its email domains", org.name));
+        }
+    }
+
+    let old_email = std::mem::replace(&mut user.email, new_email.clone());
+    user.is_verified = false;
+    user.updated_at = now;
+    cache::store_user(user.clone());
+
+    for mut member in memberships {
+        member.email = new_email.to_lowercase();
+        member.updated_at = now;
+        store_org_member(&member);
+    }
+    if login_key(&old_email) != login_key(&new_email) {
+        LOGIN_ATTEMPTS.with(|attempts| {
+            let mut attempts = attempts.borrow_mut();
+            if let Some(mut moved) = attempts.remove(&login_key(&old_email)) {
+                moved.email = login_key(&new_email);
+                attempts.insert(moved.email.clone(), moved);
+            }
+        });
+    }
+    // The old address keeps its trial record; the new one gets a copy so it can't start another
+    if let (Some(old_key), Some(new_key)) = (normalized_trial_email(&old_email), normalized_trial_email(&new_email)) {
+        TRIAL_HISTORY.with(|history| {
+            let mut history = history.borrow_mut();
+            if let Some(record) = history.get(&old_key).filter(|_| !history.contains_key(&new_key)) {
+                history.insert(new_key, record);
+            }
+        });
+    }
+    record_audit(caller, "change_email", Some(caller), format!("{} -> {}", old_email, new_email));
+    Ok(user)
+}
+
+#[ic_cdk::update]
+fn change_username(new_username: String, session_token: Option<String>) -> Result<User, String> {
+    let caller = session_caller(session_token)?;
+    let mut user = cache::user(caller).ok_or("User not found")?;
+    let new_username = new_username.trim().to_string();
+    if new_username.is_empty() || new_username.chars().any(char::is_control) {
+        return Err("Enter a username".to_string());
+    }
+    if new_username == user.username {
+        return Err("That's already your username".to_string());
+    }
+    ensure_username_available(&new_username, Some(caller))?;
+
+    let old_username = std::mem::replace(&mut user.username, new_username);
+    user.updated_at = ic_cdk::api::time();
+    cache::store_user(user.clone());
+    record_audit(caller, "change_username", Some(caller), format!("{} -> {}", old_username, user.username));
+    Ok(user)
+}
+
 // --- Candid Generation ---
 ic_cdk::export_candid!();
@@ -0,0 +1,44 @@
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use ic_stable_structures::storable::{Storable, Bound};
+use std::borrow::Cow;
+use super::milestone::AchievementCard;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ActivityPost {
+    pub id: u64,
+    pub user_id: Principal,
+    pub kind: String, // "course_completed", "streak_30", "badge_earned", "tutor_published"
+    pub text: String,
+    pub target_id: Option<String>, // tutor public id or achievement id
+    pub milestone_id: Option<u64>,
+    pub card: Option<AchievementCard>,
+    pub visibility: String, // "connections" or "private"
+    pub reactions: Vec<ActivityReaction>,
+    pub created_at: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ActivityReaction {
+    pub user_id: Principal,
+    pub reaction: String,
+    pub reacted_at: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ActivityFeedPage {
+    pub items: Vec<ActivityPost>,
+    pub next_cursor: Option<u64>, // pass back to get the next, older page
+}
+
+impl Storable for ActivityPost {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
@@ -0,0 +1,153 @@
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use ic_stable_structures::storable::{Storable, Bound};
+use std::borrow::Cow;
+
+// An OpenAI-compatible chat completions endpoint. Providers are tried in list order.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct AiProviderConfig {
+    pub name: String,
+    pub vendor: String, // "groq", "openai", "together", ...
+    pub endpoint_url: String,
+    pub model: String,
+    pub api_key: String,
+    pub timeout_ms: u64,
+    pub max_response_bytes: u64,
+    pub enabled: bool,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct CircuitBreakerSettings {
+    pub failure_threshold: u32, // consecutive failures before the provider is skipped
+    pub cooldown_seconds: u64,
+}
+
+impl Default for CircuitBreakerSettings {
+    fn default() -> Self {
+        CircuitBreakerSettings { failure_threshold: 3, cooldown_seconds: 300 }
+    }
+}
+
+// HTTPS outcalls may be unavailable, e.g. on a local replica or during an incident. In
+// "auto" mode the canister degrades after failure_threshold AI calls in a row reach no
+// provider and lets one call through every probe_interval_seconds to notice recovery; "on"
+// forces degraded mode and "off" never degrades.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct DegradationSettings {
+    pub mode: String, // "auto", "on", "off"
+    pub failure_threshold: u32,
+    pub probe_interval_seconds: u64,
+}
+
+impl Default for DegradationSettings {
+    fn default() -> Self {
+        DegradationSettings { mode: "auto".to_string(), failure_threshold: 5, probe_interval_seconds: 300 }
+    }
+}
+
+// While degraded, AI endpoints answer from templates and tutor replies wait as "deferred"
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ServiceStatus {
+    pub ai_degraded: bool,
+    pub mode: String,
+    pub reason: Option<String>,
+    pub degraded_since: Option<u64>, // None when forced on by an admin
+}
+
+// Generation settings for one call. Unset fields fall back to ModelDefaults.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct ModelParams {
+    pub temperature: Option<f32>, // 0.0-2.0
+    pub top_p: Option<f32>, // above 0.0, up to 1.0
+    pub model: Option<String>, // must be in ModelDefaults.allowed_models
+}
+
+// Every replica makes the outcall and their responses must match, so sampled calls
+// (temperature above 0) also send a seed derived from the prompt. Providers without seed
+// support may then fail consensus; keep the temperature at 0 for those.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ModelDefaults {
+    pub temperature: f32,
+    pub top_p: Option<f32>,
+    pub allowed_models: Vec<String>, // models tutors may override to; empty disables overrides
+}
+
+impl Default for ModelDefaults {
+    fn default() -> Self {
+        ModelDefaults { temperature: 0.0, top_p: None, allowed_models: Vec::new() }
+    }
+}
+
+// Kept on the heap; health starts fresh after an upgrade
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct AiProviderHealth {
+    pub name: String,
+    pub total_calls: u64,
+    pub failed_calls: u64,
+    pub consecutive_failures: u32,
+    pub total_latency_ms: u64,
+    pub last_latency_ms: u64,
+    pub last_error: Option<String>,
+    pub last_success_at: Option<u64>,
+    pub circuit_open_until: Option<u64>,
+}
+
+impl AiProviderHealth {
+    pub fn error_rate(&self) -> f64 {
+        if self.total_calls == 0 { 0.0 } else { self.failed_calls as f64 / self.total_calls as f64 }
+    }
+
+    pub fn average_latency_ms(&self) -> u64 {
+        self.total_latency_ms.checked_div(self.total_calls).unwrap_or(0)
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct AiProviderStatus {
+    pub name: String,
+    pub vendor: String,
+    pub model: String,
+    pub enabled: bool,
+    pub circuit_open: bool,
+    pub error_rate: f64,
+    pub average_latency_ms: u64,
+    pub health: AiProviderHealth,
+}
+
+// One AI-backed call, kept for ops so provider settings can be tuned from real latencies.
+// message_id is set when the call produced a tutor reply.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct AiCallDiagnostic {
+    pub id: u64,
+    pub operation: String,
+    pub caller: Principal,
+    pub message_id: Option<String>,
+    pub provider: Option<String>, // the provider that answered, or the last one tried
+    pub model: Option<String>,
+    pub attempts: u32, // across all providers tried
+    pub latency_ms: u64, // whole call, including failed attempts
+    pub response_bytes: u64,
+    pub outcome: String, // "ok", "failed" (no provider answered), "budget_exceeded"
+    pub recorded_at: u64,
+}
+
+impl Storable for AiCallDiagnostic {
+    fn to_bytes(&self) -> Cow<[u8]> { Cow::Owned(serde_cbor::to_vec(&self).unwrap()) }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self { serde_cbor::from_slice(bytes.as_ref()).unwrap() }
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct AiLatencyStats {
+    pub provider: String,
+    pub model: String,
+    pub calls: u64,
+    pub failed_calls: u64,
+    pub p50_latency_ms: u64, // successful calls only
+    pub p95_latency_ms: u64,
+    pub average_attempts: f64,
+    pub average_response_bytes: u64,
+}
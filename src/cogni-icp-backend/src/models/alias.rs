@@ -0,0 +1,28 @@
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+use ic_stable_structures::storable::{Storable, Bound};
+use std::borrow::Cow;
+
+// Another name an entity can be looked up by, keyed by "{entity_type}:{alias}". Retired
+// slugs keep resolving and stay reserved so they can't be taken over by someone else.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct IdAlias {
+    pub entity_type: String, // "tutor", "study_group", "task"
+    pub alias: String,
+    pub public_id: String,
+    pub kind: String, // "slug", "legacy_id"
+    pub created_at: u64,
+    pub retired_at: Option<u64>, // set when a slug is renamed or cleared
+}
+
+impl Storable for IdAlias {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
@@ -0,0 +1,52 @@
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use ic_stable_structures::storable::{Storable, Bound};
+use std::borrow::Cow;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Announcement {
+    pub id: u64,
+    pub title: String,
+    pub body: String,
+    pub kind: String, // "maintenance", "feature", "promo"
+    pub audience_type: String, // "all", "plan", "role"
+    pub audience_values: Vec<String>, // plan tiers or roles when audience_type isn't "all"
+    pub starts_at: u64,
+    pub ends_at: u64,
+    pub is_active: bool,
+    pub created_by: Principal,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+impl Storable for Announcement {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct AnnouncementDismissal {
+    pub id: u64,
+    pub announcement_id: u64,
+    pub user_id: Principal,
+    pub dismissed_at: u64,
+}
+
+impl Storable for AnnouncementDismissal {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
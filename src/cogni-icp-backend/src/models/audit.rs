@@ -0,0 +1,53 @@
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use ic_stable_structures::storable::{Storable, Bound};
+use std::borrow::Cow;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct AuditEntry {
+    pub id: u64,
+    pub actor: Principal,
+    pub action: String, // e.g. "impersonation_started", "impersonation_access"
+    pub target_user: Option<Principal>,
+    pub details: String,
+    pub timestamp: u64,
+}
+
+impl Storable for AuditEntry {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ImpersonationSession {
+    pub id: u64,
+    pub admin_id: Principal,
+    pub user_id: Principal,
+    pub reason: String,
+    pub status: String, // "pending_approval", "active", "ended", "declined", "expired"
+    pub duration_minutes: u32,
+    pub consent: String, // "standing", "explicit"
+    pub created_at: u64,
+    pub started_at: Option<u64>,
+    pub expires_at: Option<u64>,
+    pub ended_at: Option<u64>,
+}
+
+impl Storable for ImpersonationSession {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
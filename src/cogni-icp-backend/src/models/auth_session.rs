@@ -0,0 +1,45 @@
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use ic_stable_structures::storable::{Storable, Bound};
+use std::borrow::Cow;
+use crate::models::user::User;
+
+// A password login, keyed by its token. Only usable from the identity that logged in, since
+// password accounts have a principal nobody holds the key for.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct AuthSession {
+    pub token: String,
+    pub user_id: Principal,
+    pub issued_to: Principal, // the frontend's session key
+    pub created_at: u64,
+    pub expires_at: u64,
+}
+
+impl Storable for AuthSession {
+    fn to_bytes(&self) -> Cow<[u8]> { Cow::Owned(serde_cbor::to_vec(&self).unwrap()) }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self { serde_cbor::from_slice(bytes.as_ref()).unwrap() }
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// An account moved onto a linked principal whose records are still being moved, keyed by the
+// old principal. next_memory is the lowest memory id not yet done.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct PrincipalRekey {
+    pub old: Principal,
+    pub new: Principal,
+    pub next_memory: u16,
+    pub started_at: u64,
+}
+
+impl Storable for PrincipalRekey {
+    fn to_bytes(&self) -> Cow<[u8]> { Cow::Owned(serde_cbor::to_vec(&self).unwrap()) }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self { serde_cbor::from_slice(bytes.as_ref()).unwrap() }
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct SessionLogin {
+    pub token: String,
+    pub expires_at: u64,
+    pub user: User,
+}
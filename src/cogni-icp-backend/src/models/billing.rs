@@ -15,6 +15,8 @@ pub struct SubscriptionPlan {
     pub paystack_plan_code: Option<String>,
     pub is_active: bool,
     pub created_at: u64,
+    #[serde(default)]
+    pub regional_prices: Vec<RegionalPrice>, // price_naira applies where none matches
 }
 
 impl Storable for SubscriptionPlan {
@@ -57,7 +59,7 @@ pub struct PaymentTransaction {
     pub paystack_reference: String,
     pub paystack_access_code: Option<String>,
     pub paystack_transaction_id: Option<String>,
-    pub amount_naira: u64,
+    pub amount_naira: u64, // total charged, in the minor unit of currency
     pub currency: String,
     pub status: String, // "pending", "success", "failed", "abandoned"
     pub payment_method: Option<String>,
@@ -65,10 +67,156 @@ pub struct PaymentTransaction {
     pub payment_metadata: Option<HashMap<String, String>>,
     pub created_at: u64,
     pub paid_at: Option<u64>,
+    #[serde(default)]
+    pub region: Option<String>, // the payer's billing region when the price was set
+    #[serde(default)]
+    pub tax: Option<InvoiceTax>,
 }
 
 impl Storable for PaymentTransaction {
     fn to_bytes(&self) -> Cow<[u8]> { Cow::Owned(serde_cbor::to_vec(&self).unwrap()) }
     fn from_bytes(bytes: Cow<[u8]>) -> Self { serde_cbor::from_slice(bytes.as_ref()).unwrap() }
     const BOUND: Bound = Bound::Unbounded;
-} 
\ No newline at end of file
+} 
+// A plan bought for someone else. The recipient is a principal or an email address matched
+// against the recipient's profile when they redeem.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct GiftSubscription {
+    pub id: u64,
+    pub purchaser_id: Principal,
+    pub recipient_principal: Option<Principal>,
+    pub recipient_email: Option<String>,
+    pub plan: String,
+    pub months: u32,
+    pub message: Option<String>,
+    pub transaction_id: u64,
+    pub status: String, // "pending_payment", "paid", "redeemed", "cancelled"
+    pub grant_id: Option<u64>,
+    pub created_at: u64,
+    pub paid_at: Option<u64>,
+    pub redeemed_at: Option<u64>,
+}
+
+impl Storable for GiftSubscription {
+    fn to_bytes(&self) -> Cow<[u8]> { Cow::Owned(serde_cbor::to_vec(&self).unwrap()) }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self { serde_cbor::from_slice(bytes.as_ref()).unwrap() }
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// Time on a plan from a gift or an admin comp. While any grant is running the user gets the
+// highest granted plan; otherwise their own subscription applies.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct PlanGrant {
+    pub id: u64,
+    pub user_id: Principal,
+    pub plan: String,
+    pub starts_at: u64,
+    pub ends_at: u64,
+    pub source: String, // "gift", "comp", "trial"
+    pub gift_id: Option<u64>,
+    pub granted_by: Principal,
+    pub reason: Option<String>,
+    pub revoked_at: Option<u64>,
+    pub created_at: u64,
+    #[serde(default)]
+    pub expiry_reminded_at: Option<u64>,
+    #[serde(default)]
+    pub end_notified_at: Option<u64>,
+}
+
+impl Storable for PlanGrant {
+    fn to_bytes(&self) -> Cow<[u8]> { Cow::Owned(serde_cbor::to_vec(&self).unwrap()) }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self { serde_cbor::from_slice(bytes.as_ref()).unwrap() }
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// Who has had a trial, keyed "email:{normalized email}" and "principal:{principal}" so neither
+// a new account with the same email nor the same principal gets a second one
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct TrialRecord {
+    pub user_id: Principal,
+    pub grant_id: u64,
+    pub started_at: u64,
+}
+
+impl Storable for TrialRecord {
+    fn to_bytes(&self) -> Cow<[u8]> { Cow::Owned(serde_cbor::to_vec(&self).unwrap()) }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self { serde_cbor::from_slice(bytes.as_ref()).unwrap() }
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// A plan's price in one region. Regions are ISO 3166 country codes, or "*" for every region
+// without its own price.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct RegionalPrice {
+    pub region: String,
+    pub currency: String,
+    pub amount: u64, // in the currency's minor unit
+}
+
+// VAT or GST charged on plans bought from a region
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct TaxRegion {
+    pub region: String,
+    pub tax_name: String, // "VAT", "GST"
+    pub rate_bps: u32, // 750 is 7.5%
+    pub prices_include_tax: bool,
+    pub registration_number: Option<String>, // printed on invoices
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct BillingConfig {
+    pub default_region: String, // for users who haven't declared one
+    pub tax_regions: Vec<TaxRegion>,
+}
+
+impl Default for BillingConfig {
+    fn default() -> Self {
+        BillingConfig {
+            default_region: "NG".to_string(),
+            tax_regions: vec![TaxRegion {
+                region: "NG".to_string(),
+                tax_name: "VAT".to_string(),
+                rate_bps: 750,
+                prices_include_tax: true,
+                registration_number: None,
+            }],
+        }
+    }
+}
+
+// Tax breakdown stored on a payment; the payment's amount is the total charged
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct InvoiceTax {
+    pub region: String,
+    pub tax_name: Option<String>, // None where the region charges no tax
+    pub rate_bps: u32,
+    pub net_amount: u64,
+    pub tax_amount: u64,
+    pub prices_include_tax: bool,
+    pub registration_number: Option<String>,
+}
+
+// A plan's price as the caller would pay it
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct PlanPrice {
+    pub plan_id: u64,
+    pub name: String,
+    pub billing_cycle: String,
+    pub region: String,
+    pub currency: String,
+    pub total: u64,
+    pub tax: InvoiceTax,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct RegionRevenue {
+    pub region: String, // "unknown" for payments made before regions were recorded
+    pub currency: String,
+    pub payments: u64,
+    pub gross: u64,
+    pub tax: u64,
+    pub net: u64,
+    pub refunded: u64, // gross of payments since refunded, included in the figures above
+}
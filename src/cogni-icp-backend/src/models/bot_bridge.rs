@@ -0,0 +1,102 @@
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use ic_stable_structures::storable::{Storable, Bound};
+use std::borrow::Cow;
+use crate::models::tutor::ChatMessage;
+
+// An external chat bot (Telegram, Discord) allowed to relay messages, keyed by name. The
+// service key itself is shown once when the bridge is created; only its hash is kept.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct BotBridge {
+    pub name: String,
+    pub platform: String, // "telegram" or "discord"
+    pub key_hash: String,
+    pub enabled: bool,
+    pub created_at: u64,
+}
+
+// Short-lived code a learner creates in the app and sends to the bot to link their account
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct BotLinkCode {
+    pub code: String,
+    pub user_id: Principal,
+    pub platform: String,
+    pub expires_at: u64,
+}
+
+// Keyed by "{platform}:{external_user_id}"
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct BotLink {
+    pub platform: String,
+    pub external_user_id: String,
+    pub user_id: Principal,
+    pub linked_at: u64,
+}
+
+// A platform thread relaying to one session, keyed by "{platform}:{thread_id}"
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct BotThread {
+    pub platform: String,
+    pub thread_id: String,
+    pub external_user_id: String,
+    pub user_id: Principal,
+    pub session_id: String,
+    pub relayed_through: u64, // timestamp of the newest tutor message handed to the bot
+    pub created_at: u64,
+}
+
+// A tutor message the bot should post to a thread
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct BridgeUpdate {
+    pub thread_id: String,
+    pub external_user_id: String,
+    pub message: ChatMessage,
+}
+
+impl Storable for BotBridge {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for BotLinkCode {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for BotLink {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for BotThread {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
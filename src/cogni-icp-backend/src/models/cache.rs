@@ -0,0 +1,14 @@
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+
+// Counters for one heap cache since the last upgrade
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct CacheStats {
+    pub name: String,
+    pub capacity: u64,
+    pub entries: u64,
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub invalidations: u64, // entries dropped because the record was written
+}
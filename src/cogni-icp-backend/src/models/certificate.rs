@@ -0,0 +1,50 @@
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use ic_stable_structures::storable::{Storable, Bound};
+use std::borrow::Cow;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Certificate {
+    pub id: u64,
+    pub public_id: String,
+    pub user_id: Principal,
+    pub display_name: String,
+    pub course_title: String,
+    pub session_id: Option<String>,
+    pub artifact_url: Option<String>,
+    pub artifact_hash: Option<String>,
+    pub signature: String, // hex HMAC-SHA256 over the certified fields
+    pub issued_at: u64,
+    pub revoked: bool,
+    pub revoked_at: Option<u64>,
+    pub revoked_by: Option<Principal>,
+    pub revocation_reason: Option<String>,
+    #[serde(default)]
+    pub course_id: Option<u64>, // the completed course; None on certificates issued before this was checked
+}
+
+impl Storable for Certificate {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// Public view of a certificate, safe to show to anyone holding the link
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct CertificateVerification {
+    pub certificate_id: String,
+    pub display_name: String,
+    pub course_title: String,
+    pub issued_at: u64,
+    pub artifact_url: Option<String>,
+    pub signature_valid: bool,
+    pub revoked: bool,
+    pub revocation_reason: Option<String>,
+    pub status: String, // "valid", "revoked", "invalid_signature"
+}
@@ -0,0 +1,53 @@
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use ic_stable_structures::storable::{Storable, Bound};
+use std::borrow::Cow;
+use super::tutor::ChatMessage;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct SessionDigest {
+    pub session_id: String,
+    pub digest: Vec<u8>,
+}
+
+// Sessions are kept sorted by id, matching the order they are hashed in
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct UserDataHash {
+    pub user_id: Principal,
+    pub sessions: Vec<SessionDigest>,
+    pub root: Vec<u8>,
+    pub updated_at: u64,
+}
+
+impl Storable for UserDataHash {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(crate::codec::encode(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        crate::codec::decode(bytes.as_ref())
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct BucketEntry {
+    pub user_id: Principal,
+    pub root: Vec<u8>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct UserDataProof {
+    pub user_hash: Option<UserDataHash>,
+    pub bucket: u8,
+    pub bucket_entries: Vec<BucketEntry>,
+    pub bucket_hashes: Vec<Vec<u8>>,
+    pub certificate: Option<Vec<u8>>, // None outside query calls
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct CertifiedSessionMessages {
+    pub messages: Vec<ChatMessage>,
+    pub proof: UserDataProof,
+}
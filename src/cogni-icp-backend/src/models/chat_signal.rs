@@ -0,0 +1,18 @@
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+
+// Live activity in a chat session for typing indicators. Built from heap state and pending
+// deliveries on each read; nothing here is stored.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ChatSignals {
+    pub session_id: String,
+    pub composing: Vec<Principal>, // other participants typing right now
+    pub replies: Vec<ReplySignal>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ReplySignal {
+    pub message_id: String,
+    pub status: String, // "generating" (an AI call is running), "queued", "deferred" or "retrying"
+    pub since: u64, // when generation started, or when the reply was requested
+}
@@ -0,0 +1,145 @@
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use ic_stable_structures::storable::{Storable, Bound};
+use std::borrow::Cow;
+
+// A scheduled run of a course: modules unlock for every enrolled learner at the same time
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Cohort {
+    pub id: u64,
+    pub title: String,
+    pub description: String,
+    pub tutor_id: String,
+    pub created_by: Principal,
+    pub modules: Vec<CohortModule>,
+    pub starts_at: u64,
+    pub unlock_interval_days: u32,
+    pub max_learners: u32,
+    pub enrollment_open: bool,
+    pub created_at: u64,
+}
+
+impl Storable for Cohort {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct CohortModule {
+    pub order: u32,
+    pub title: String,
+    pub description: String,
+    pub content: Option<String>,
+    pub unlocks_at: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct CohortEnrollment {
+    pub id: u64,
+    pub cohort_id: u64,
+    pub user_id: Principal,
+    pub completed_modules: Vec<u32>,
+    pub enrolled_at: u64,
+    pub completed_at: Option<u64>,
+}
+
+impl Storable for CohortEnrollment {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// Module as seen by a learner; content is withheld until the module unlocks
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct CohortModuleView {
+    pub order: u32,
+    pub title: String,
+    pub description: String,
+    pub content: Option<String>,
+    pub unlocks_at: u64,
+    pub unlocked: bool,
+    pub completed: bool,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct DiscussionPost {
+    pub id: u64,
+    pub cohort_id: u64,
+    pub module_order: u32,
+    pub user_id: Principal,
+    pub content: String,
+    pub created_at: u64,
+    #[serde(default)]
+    pub parent_id: Option<u64>, // None for a thread's opening post
+    #[serde(default)]
+    pub voters: Vec<Principal>,
+    #[serde(default)]
+    pub vote_count: u64,
+    #[serde(default)]
+    pub is_accepted: bool,
+    #[serde(default = "default_post_status")]
+    pub status: String, // "visible", "hidden"
+    #[serde(default)]
+    pub reported_by: Vec<Principal>,
+}
+
+fn default_post_status() -> String {
+    "visible".to_string()
+}
+
+impl Storable for DiscussionPost {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct DiscussionThread {
+    pub post: DiscussionPost,
+    pub replies: Vec<DiscussionPost>, // accepted answer first, then by votes
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct DiscussionPage {
+    pub threads: Vec<DiscussionThread>,
+    pub total_threads: u32,
+    pub has_more: bool,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct CohortModuleStats {
+    pub order: u32,
+    pub title: String,
+    pub unlocked: bool,
+    pub completed_count: u32,
+    pub completion_rate: f64, // share of enrolled learners, 0-100
+    pub discussion_posts: u32,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct CohortStats {
+    pub cohort_id: u64,
+    pub enrolled: u32,
+    pub completed_course: u32,
+    pub average_progress: f64, // 0-100
+    pub modules: Vec<CohortModuleStats>,
+}
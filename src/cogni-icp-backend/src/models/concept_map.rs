@@ -0,0 +1,42 @@
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use ic_stable_structures::storable::{Storable, Bound};
+use std::borrow::Cow;
+
+// A learner's graph of the concepts in a course or session, keyed
+// "{principal}:{source_type}:{source_id}". Course maps grow as the learner completes modules.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ConceptMap {
+    pub user_id: Principal,
+    pub source_type: String, // "course", "session"
+    pub source_id: String,
+    pub title: String,
+    pub nodes: Vec<ConceptNode>,
+    pub edges: Vec<ConceptEdge>,
+    pub completed_modules: Vec<u64>,
+    pub version: u32, // bumped on every change so clients can skip re-rendering
+    pub generated_at: u64,
+    pub updated_at: u64,
+}
+
+impl Storable for ConceptMap {
+    fn to_bytes(&self) -> Cow<[u8]> { Cow::Owned(serde_cbor::to_vec(&self).unwrap()) }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self { serde_cbor::from_slice(bytes.as_ref()).unwrap() }
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ConceptNode {
+    pub id: String,
+    pub label: String,
+    pub description: String,
+    pub module_id: Option<u64>, // the course module that teaches it
+    pub completed: bool, // its module has been completed
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ConceptEdge {
+    pub from: String,
+    pub to: String,
+    pub relation: String, // short verb phrase such as "is a prerequisite of"
+}
@@ -0,0 +1,34 @@
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use ic_stable_structures::storable::{Storable, Bound};
+use std::borrow::Cow;
+
+// A tutor reply that scored below the confidence threshold, kept for admin review
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct LowConfidenceReply {
+    pub id: u64,
+    pub message_id: String,
+    pub session_id: String,
+    pub tutor_id: String,
+    pub user_id: Principal,
+    pub question: String,
+    pub reply: String,
+    pub confidence: f32,
+    pub had_sources: bool,
+    pub created_at: u64,
+    pub reviewed_by: Option<Principal>,
+    pub reviewed_at: Option<u64>,
+    pub review_note: Option<String>,
+}
+
+impl Storable for LowConfidenceReply {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
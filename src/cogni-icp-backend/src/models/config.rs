@@ -0,0 +1,291 @@
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use ic_stable_structures::storable::{Storable, Bound};
+use std::borrow::Cow;
+use crate::models::sharding::ShardingConfig;
+use crate::models::ai_providers::{AiProviderConfig, CircuitBreakerSettings, ModelDefaults, DegradationSettings};
+use crate::models::tagging::TaggingConfig;
+use crate::models::xapi::LrsConfig;
+use crate::models::guest::GuestConfig;
+use crate::models::invite::RegistrationConfig;
+use crate::models::educator::EducatorConfig;
+use crate::models::creator::CreatorRevenueConfig;
+use crate::models::email::EmailConfig;
+use crate::models::refund::RefundConfig;
+use crate::models::billing::BillingConfig;
+use crate::models::delivery::AsyncReplyConfig;
+use crate::models::mastery::ReviewReminderConfig;
+use crate::models::oauth::OAuthConfig;
+use crate::models::notifications::NotificationConfig;
+use crate::models::topic_drift::TopicDriftConfig;
+use crate::models::rate_limit::RateLimitConfig;
+use crate::models::persona_eval::PersonaEvalConfig;
+use crate::models::login_attempt::LoginLockoutConfig;
+use crate::models::focus::FocusConfig;
+use crate::models::public_api::ExternalApiConfig;
+use crate::models::daily_question::DailyQuestionConfig;
+use crate::models::peer_tutoring::PeerTutoringConfig;
+use crate::models::learner_risk::LearnerRiskConfig;
+
+// Canister-wide settings editable by admins. New fields must have serde defaults so
+// configs written by older versions keep decoding after an upgrade.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct CanisterConfig {
+    pub retention_policies: Vec<RetentionPolicy>,
+    pub retention_holds: Vec<RetentionHold>,
+    pub plan_limits: Vec<PlanLimits>,
+    pub sharding: ShardingConfig,
+    pub ai_providers: Vec<AiProviderConfig>,
+    pub ai_circuit_breaker: CircuitBreakerSettings,
+    pub response_processing: ResponseProcessingConfig,
+    pub outcall_budgets: Vec<OutcallBudget>,
+    pub tagging: TaggingConfig,
+    pub lrs: LrsConfig,
+    pub guest: GuestConfig,
+    pub registration: RegistrationConfig,
+    pub session_archival: SessionArchivalConfig,
+    pub confidence: ConfidenceConfig,
+    pub educators: EducatorConfig,
+    pub creator_revenue: CreatorRevenueConfig,
+    pub trial: TrialConfig,
+    pub model_defaults: ModelDefaults,
+    pub email: EmailConfig,
+    pub refunds: RefundConfig,
+    pub billing: BillingConfig,
+    pub ai_degradation: DegradationSettings,
+    pub async_replies: AsyncReplyConfig,
+    pub review_reminders: ReviewReminderConfig,
+    pub oauth: OAuthConfig,
+    pub notifications: NotificationConfig,
+    pub topic_drift: TopicDriftConfig,
+    pub rate_limits: RateLimitConfig,
+    pub persona_eval: PersonaEvalConfig,
+    pub login_lockout: LoginLockoutConfig,
+    pub focus: FocusConfig,
+    pub external_api: ExternalApiConfig,
+    pub daily_questions: DailyQuestionConfig,
+    pub peer_tutoring: PeerTutoringConfig,
+    pub learner_risk: LearnerRiskConfig,
+    pub pending_admins: Vec<Principal>, // from install args; promoted once they have an account
+}
+
+impl CanisterConfig {
+    // Copy safe to hand back to callers; provider API keys, LRS credentials, the email API key, the Paystack key and the GitHub client secret never leave the canister
+    pub fn redacted(mut self) -> Self {
+        for provider in &mut self.ai_providers {
+            provider.api_key = String::new();
+        }
+        self.lrs.authorization = String::new();
+        self.email.api_key = String::new();
+        self.refunds.paystack_secret_key = String::new();
+        self.oauth.github_client_secret = String::new();
+        self
+    }
+}
+
+impl Default for CanisterConfig {
+    fn default() -> Self {
+        CanisterConfig {
+            retention_policies: vec![
+                RetentionPolicy { data_class: "raw_metrics".to_string(), retention_days: Some(90) },
+                RetentionPolicy { data_class: "metric_aggregates".to_string(), retention_days: None },
+                RetentionPolicy { data_class: "read_notifications".to_string(), retention_days: Some(14) },
+                RetentionPolicy { data_class: "failed_replies".to_string(), retention_days: Some(30) },
+                RetentionPolicy { data_class: "ai_diagnostics".to_string(), retention_days: Some(14) },
+            ],
+            retention_holds: Vec::new(),
+            plan_limits: vec![
+                PlanLimits { plan: "free".to_string(), storage_bytes: 5 * 1024 * 1024, ai_calls_per_day: Some(50), max_tutors: Some(3), max_concurrent_ai: Some(1), max_group_memberships: Some(5) },
+                PlanLimits { plan: "pro".to_string(), storage_bytes: 100 * 1024 * 1024, ai_calls_per_day: Some(1000), max_tutors: Some(25), max_concurrent_ai: Some(3), max_group_memberships: Some(50) },
+                PlanLimits { plan: "enterprise".to_string(), storage_bytes: 1024 * 1024 * 1024, ai_calls_per_day: None, max_tutors: None, max_concurrent_ai: None, max_group_memberships: None },
+            ],
+            sharding: ShardingConfig::default(),
+            ai_providers: Vec::new(),
+            ai_circuit_breaker: CircuitBreakerSettings::default(),
+            response_processing: ResponseProcessingConfig::default(),
+            outcall_budgets: default_outcall_budgets(),
+            tagging: TaggingConfig::default(),
+            lrs: LrsConfig::default(),
+            guest: GuestConfig::default(),
+            registration: RegistrationConfig::default(),
+            session_archival: SessionArchivalConfig::default(),
+            confidence: ConfidenceConfig::default(),
+            educators: EducatorConfig::default(),
+            creator_revenue: CreatorRevenueConfig::default(),
+            trial: TrialConfig::default(),
+            model_defaults: ModelDefaults::default(),
+            email: EmailConfig::default(),
+            refunds: RefundConfig::default(),
+            billing: BillingConfig::default(),
+            ai_degradation: DegradationSettings::default(),
+            async_replies: AsyncReplyConfig::default(),
+            review_reminders: ReviewReminderConfig::default(),
+            oauth: OAuthConfig::default(),
+            notifications: NotificationConfig::default(),
+            topic_drift: TopicDriftConfig::default(),
+            rate_limits: RateLimitConfig::default(),
+            persona_eval: PersonaEvalConfig::default(),
+            login_lockout: LoginLockoutConfig::default(),
+            focus: FocusConfig::default(),
+            external_api: ExternalApiConfig::default(),
+            daily_questions: DailyQuestionConfig::default(),
+            peer_tutoring: PeerTutoringConfig::default(),
+            learner_risk: LearnerRiskConfig::default(),
+            pending_admins: Vec::new(),
+        }
+    }
+}
+
+impl Storable for CanisterConfig {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct RetentionPolicy {
+    pub data_class: String, // "raw_metrics", "metric_aggregates", "read_notifications", "failed_replies", "ai_diagnostics"
+    pub retention_days: Option<u32>, // None keeps data forever
+}
+
+// Per-user override that exempts the user's data from pruning (e.g. legal hold)
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct RetentionHold {
+    pub user_id: Principal,
+    pub reason: String,
+    pub set_by: Principal,
+    pub set_at: u64,
+}
+
+// Quotas for a subscription tier, matched against User.subscription
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct PlanLimits {
+    pub plan: String,
+    pub storage_bytes: u64,
+    #[serde(default)]
+    pub ai_calls_per_day: Option<u64>, // None means unlimited
+    #[serde(default)]
+    pub max_tutors: Option<u64>,
+    #[serde(default)]
+    pub max_concurrent_ai: Option<u32>, // AI requests a user can have running at once
+    #[serde(default)]
+    pub max_group_memberships: Option<u64>, // active study group memberships
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ResponseProcessingConfig {
+    pub mask_profanity: bool,
+    pub profanity_words: Vec<String>,
+    pub max_chat_response_chars: Option<u32>,
+    pub strip_markdown_fences: bool, // only where plain text or JSON is expected
+    pub reading_level_margin: f32, // grades a reply may miss the user's target by before it is rewritten
+    pub rewrite_off_level_replies: bool,
+}
+
+impl Default for ResponseProcessingConfig {
+    fn default() -> Self {
+        ResponseProcessingConfig {
+            mask_profanity: true,
+            profanity_words: ["fuck", "shit", "bitch", "bastard", "asshole", "dick", "cunt", "damn"]
+                .iter()
+                .map(|w| w.to_string())
+                .collect(),
+            max_chat_response_chars: Some(4000),
+            strip_markdown_fences: true,
+            reading_level_margin: 2.0,
+            rewrite_off_level_replies: true,
+        }
+    }
+}
+
+// Active sessions without activity for idle_days are archived with a closing summary
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct SessionArchivalConfig {
+    pub enabled: bool,
+    pub idle_days: u32,
+}
+
+impl Default for SessionArchivalConfig {
+    fn default() -> Self {
+        SessionArchivalConfig {
+            enabled: true,
+            idle_days: 30,
+        }
+    }
+}
+
+// New accounts start on a trial of a paid plan, with a reminder before it ends
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct TrialConfig {
+    pub enabled: bool,
+    pub plan: String,
+    pub days: u32,
+    pub reminder_days: u32,
+}
+
+impl Default for TrialConfig {
+    fn default() -> Self {
+        TrialConfig {
+            enabled: true,
+            plan: "pro".to_string(),
+            days: 14,
+            reminder_days: 3,
+        }
+    }
+}
+
+// Tutor replies scored below threshold are flagged for review; without knowledge base
+// support the tutor also says it is unsure. self_assessment adds an AI call per reply.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ConfidenceConfig {
+    pub enabled: bool,
+    pub threshold: f32, // 0.0-1.0
+    pub self_assessment: bool,
+}
+
+impl Default for ConfidenceConfig {
+    fn default() -> Self {
+        ConfidenceConfig {
+            enabled: true,
+            threshold: 0.5,
+            self_assessment: false,
+        }
+    }
+}
+
+// Limits for one kind of AI outcall. The "default" entry covers operations not listed.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct OutcallBudget {
+    pub operation: String, // "chat", "welcome_message", "course_outline", "topic_suggestions", "topic_validation", "course_modules", "placement", "readability", "confidence", "fact_check", "default"
+    pub cycles: u64, // attached to each attempt
+    pub max_duration_ms: u64, // deadline for the whole call, across retries and failover
+    pub max_retries: u32, // extra attempts per provider before failing over
+}
+
+impl OutcallBudget {
+    pub fn new(operation: &str, cycles: u64, max_duration_ms: u64, max_retries: u32) -> Self {
+        OutcallBudget { operation: operation.to_string(), cycles, max_duration_ms, max_retries }
+    }
+}
+
+pub fn default_outcall_budgets() -> Vec<OutcallBudget> {
+    vec![
+        OutcallBudget::new("default", 30_000_000_000, 30_000, 1),
+        OutcallBudget::new("chat", 30_000_000_000, 20_000, 1),
+        OutcallBudget::new("welcome_message", 20_000_000_000, 20_000, 0),
+        OutcallBudget::new("course_outline", 50_000_000_000, 45_000, 1),
+        OutcallBudget::new("course_modules", 30_000_000_000, 30_000, 1),
+        OutcallBudget::new("placement", 30_000_000_000, 30_000, 0),
+    ]
+}
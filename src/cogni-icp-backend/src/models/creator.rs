@@ -0,0 +1,98 @@
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use ic_stable_structures::storable::{Storable, Bound};
+use std::borrow::Cow;
+
+// Premium usage of one published tutor in one calendar month (UTC), keyed "{month}:{tutor public id}"
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct CreatorUsage {
+    pub month: String, // "2024-05"
+    pub tutor_id: String,
+    pub creator_id: Principal,
+    pub premium_replies: u64,
+    pub learners: Vec<Principal>,
+    pub credits: u64,
+    pub payout_id: Option<u64>, // set once the credits are included in a payout
+    pub updated_at: u64,
+}
+
+impl Storable for CreatorUsage {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct CreatorPayout {
+    pub id: u64,
+    pub creator_id: Principal,
+    pub months: Vec<String>,
+    pub credits: u64,
+    pub revenue_share_percent: u8,
+    pub amount_kobo: u64, // fiat equivalent of the creator's share
+    pub token_amount: u64,
+    pub status: String, // "pending", "processing", "approved" (awaiting fiat payment), "paid", "failed", "rejected"
+    pub created_at: u64,
+    pub reviewed_by: Option<Principal>,
+    pub reviewed_at: Option<u64>,
+    pub ledger_block: Option<u64>,
+    pub payment_reference: Option<String>,
+    pub error: Option<String>,
+    pub paid_at: Option<u64>,
+}
+
+impl Storable for CreatorPayout {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// Credits accrue when a learner on a paid plan gets a reply from someone else's public tutor.
+// Payouts convert credits at these rates and then apply the creator's revenue share. Token
+// payouts go through token_ledger (ICRC-1) when it is set; otherwise payouts are fiat only.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct CreatorRevenueConfig {
+    pub enabled: bool,
+    pub credits_per_reply: u64,
+    pub kobo_per_credit: u64,
+    pub tokens_per_credit: u64, // in the ledger's smallest unit
+    pub token_ledger: Option<Principal>,
+    pub minimum_payout_credits: u64, // smaller balances carry over to the next month
+}
+
+impl Default for CreatorRevenueConfig {
+    fn default() -> Self {
+        CreatorRevenueConfig {
+            enabled: true,
+            credits_per_reply: 1,
+            kobo_per_credit: 100,
+            tokens_per_credit: 0,
+            token_ledger: None,
+            minimum_payout_credits: 100,
+        }
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct CreatorEarnings {
+    pub revenue_share_percent: u8,
+    pub current_month: String,
+    pub usage: Vec<CreatorUsage>, // most recent month first
+    pub unpaid_credits: u64,
+    pub payouts: Vec<CreatorPayout>,
+    pub paid_amount_kobo: u64,
+    pub paid_tokens: u64,
+}
@@ -0,0 +1,32 @@
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use crate::models::tutor::TutorCourse;
+
+// Where an imported curriculum came from, kept with the course so credit travels with it
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct CourseAttribution {
+    pub source_format: String, // "common_cartridge", "csv", "markdown"
+    pub source_title: Option<String>,
+    pub author: Option<String>,
+    pub license: Option<String>,
+    pub source_url: Option<String>,
+    #[serde(default)]
+    pub imported_by: Option<Principal>,
+    #[serde(default)]
+    pub imported_at: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ImportIssue {
+    pub line: u32, // 1-based line in the source; 0 when the issue is about the whole document
+    pub severity: String, // "error" skips the module, "warning" imports it as-is
+    pub message: String,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct CurriculumImportReport {
+    pub course: Option<TutorCourse>, // None on a dry run or when nothing was imported
+    pub modules_found: u32,
+    pub modules_imported: u32,
+    pub issues: Vec<ImportIssue>,
+}
@@ -0,0 +1,43 @@
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use ic_stable_structures::storable::{Storable, Bound};
+use std::borrow::Cow;
+
+// Built from stored progress on every call; only the framing comes from the AI
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct DailyPlan {
+    pub day: u64, // the user's local day number
+    pub items: Vec<PlanItem>,
+    pub total_minutes: u32,
+    pub framing: Option<String>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct PlanItem {
+    pub kind: String, // "resume_module", "flashcards" or "review_quiz"
+    pub title: String,
+    pub detail: String,
+    pub target_id: String, // module id, deck id or skill, depending on kind
+    pub count: u32,
+    pub estimated_minutes: u32,
+}
+
+// One per user, replaced the first time the plan is polished on a new day
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct PlanFraming {
+    pub user_id: Principal,
+    pub day: u64,
+    pub text: String,
+}
+
+impl Storable for PlanFraming {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
@@ -0,0 +1,134 @@
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use ic_stable_structures::storable::{Storable, Bound};
+use std::borrow::Cow;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct DailyQuestionConfig {
+    pub enabled: bool,
+    pub areas_per_day: u32,
+    pub min_learners: u32, // learners with proficiency in a skill before it counts as popular
+    pub repeat_after_days: u32, // an area's question can come back after this long
+    pub points_per_answer: u32,
+    pub points_per_correct: u32, // on top of points_per_answer
+}
+
+impl Default for DailyQuestionConfig {
+    fn default() -> Self {
+        DailyQuestionConfig {
+            enabled: true,
+            areas_per_day: 5,
+            min_learners: 3,
+            repeat_after_days: 60,
+            points_per_answer: 2,
+            points_per_correct: 8,
+        }
+    }
+}
+
+// Keyed "{day:010}:{area}", days counted in UTC
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct DailyQuestion {
+    pub day: u64,
+    pub area: String, // lowercased skill name
+    pub question: String,
+    pub options: Vec<String>,
+    pub correct_option: u32,
+    pub explanation: String,
+    pub source: String, // "question_bank", "ai", "reused"
+    pub created_at: u64,
+    pub answers: u32,
+    pub correct_answers: u32,
+}
+
+// Keyed "{user_id}:{day:010}:{area}"
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct DailyAnswer {
+    pub user_id: Principal,
+    pub day: u64,
+    pub area: String,
+    pub selected_option: u32,
+    pub correct: bool,
+    pub points: u32,
+    pub answered_at: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct DailyQuestionStats {
+    pub user_id: Principal,
+    pub answered: u32,
+    pub correct: u32,
+    pub points: u32,
+    pub current_streak: u32, // consecutive days with at least one answer
+    pub best_streak: u32,
+    pub last_day: u64,
+}
+
+// The answer, explanation and how others did stay hidden until the caller has answered
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct DailyQuestionView {
+    pub day: u64,
+    pub area: String,
+    pub question: String,
+    pub options: Vec<String>,
+    pub answer: Option<DailyAnswer>,
+    pub correct_option: Option<u32>,
+    pub explanation: Option<String>,
+    pub answers: Option<u32>,
+    pub correct_answers: Option<u32>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct DailyQuestionLeaderboardEntry {
+    pub rank: u32,
+    pub user_id: Option<Principal>, // None for users with a private profile
+    pub username: String,
+    pub points: u32,
+    pub answered: u32,
+    pub correct: u32,
+    pub current_streak: u32,
+    pub best_streak: u32,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct DailyQuestionLeaderboard {
+    pub entries: Vec<DailyQuestionLeaderboardEntry>,
+    pub own: Option<DailyQuestionLeaderboardEntry>,
+}
+
+impl Storable for DailyQuestion {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for DailyAnswer {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for DailyQuestionStats {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
@@ -0,0 +1,40 @@
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use crate::models::user::User;
+use crate::models::tutor::{Tutor, TutorCourse, ChatSession, ChatMessage, LearningProgress, LearningMetrics, ModuleCompletion};
+use crate::models::study_group::{StudyGroup, GroupMembership};
+use crate::models::gamification::UserTaskCompletion;
+
+// Where the next chunk of messages starts; sessions are exported in id order
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct DataExportCursor {
+    pub session_id: String,
+    pub message_offset: u32,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ExportedMessages {
+    pub session_id: String,
+    pub offset: u32, // index of the first message in this chunk
+    pub total: u32,
+    pub messages: Vec<ChatMessage>,
+}
+
+// The first chunk carries every record; later chunks only carry more messages
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct DataExport {
+    pub user_id: Principal,
+    pub exported_at: u64,
+    pub profile: Option<User>,
+    pub tutors: Vec<Tutor>,
+    pub tutor_courses: Vec<TutorCourse>,
+    pub chat_sessions: Vec<ChatSession>,
+    pub learning_progress: Vec<LearningProgress>,
+    pub learning_metrics: Vec<LearningMetrics>,
+    pub module_completions: Vec<ModuleCompletion>,
+    pub groups: Vec<StudyGroup>,
+    pub group_memberships: Vec<GroupMembership>,
+    pub task_completions: Vec<UserTaskCompletion>,
+    pub messages: Vec<ExportedMessages>,
+    pub next_cursor: Option<DataExportCursor>, // None once everything has been returned
+}
@@ -0,0 +1,70 @@
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use ic_stable_structures::storable::{Storable, Bound};
+use std::borrow::Cow;
+use crate::models::tutor::MessageCitation;
+
+// A tutor reply that has not been delivered yet, keyed by the placeholder message id
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct PendingDelivery {
+    pub message_id: String,
+    pub session_id: String,
+    pub user_id: Principal,
+    pub kind: String, // "quick" (send_tutor_message), "guided" (send_ai_tutor_message), "welcome"
+    pub user_content: String,
+    pub status: String, // "in_flight", "queued" (waiting for a free AI slot), "deferred" (waiting for AI to be available), "failed"
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    pub next_retry_at: Option<u64>, // None once a failure is not worth retrying automatically
+    pub created_at: u64,
+    #[serde(default)]
+    pub requested_by: Option<Principal>, // co-learner who asked; user_id stays the session owner
+    #[serde(default)]
+    pub sources: Vec<ReplySource>, // knowledge base passages sent with the question, in marker order
+    #[serde(default)]
+    pub email_reply_to: Option<String>, // inbound Message-ID when the question arrived by email
+    #[serde(default)]
+    pub notify_when_ready: bool, // generated in the background; the requester gets a notification
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ReplySource {
+    pub citation: MessageCitation,
+    pub text: String,
+}
+
+impl PendingDelivery {
+    // Whose AI slot and preferences the reply uses
+    pub fn requester(&self) -> Principal {
+        self.requested_by.unwrap_or(self.user_id)
+    }
+}
+
+impl Storable for PendingDelivery {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// Chat calls return right away with a pending message id when replies are expected to take
+// longer than deadline_ms, judged by the p95 latency of recent chat calls. The reply is then
+// generated in the background (retried by the delivery job) and the learner is notified.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct AsyncReplyConfig {
+    pub enabled: bool,
+    pub deadline_ms: u64,
+    pub min_samples: u32, // recent chat calls needed before latency is trusted
+}
+
+impl Default for AsyncReplyConfig {
+    fn default() -> Self {
+        AsyncReplyConfig { enabled: true, deadline_ms: 10_000, min_samples: 20 }
+    }
+}
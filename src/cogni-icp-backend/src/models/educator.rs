@@ -0,0 +1,72 @@
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use ic_stable_structures::storable::{Storable, Bound};
+use std::borrow::Cow;
+use crate::models::tutor::Tutor;
+
+// One per user; resubmitting replaces a pending, rejected or revoked application
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct EducatorVerification {
+    pub user_id: Principal,
+    pub full_name: String,
+    pub institution: Option<String>,
+    pub subject_areas: Vec<String>,
+    pub credentials: Vec<String>, // links to or descriptions of degrees, licences, teaching posts
+    pub statement: Option<String>,
+    pub status: String, // "pending", "approved", "rejected", "revoked"
+    pub submitted_at: u64,
+    pub reviewed_by: Option<Principal>,
+    pub reviewed_at: Option<u64>,
+    pub review_note: Option<String>,
+}
+
+impl Storable for EducatorVerification {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// Revenue shares are the creator's percentage of what their content earns
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct EducatorConfig {
+    pub revenue_share_percent: u8,
+    pub verified_revenue_share_percent: u8,
+    pub unverified_public_tutor_limit: Option<u32>, // None lets anyone publish any number of tutors
+    pub verified_first_in_search: bool,
+}
+
+impl Default for EducatorConfig {
+    fn default() -> Self {
+        EducatorConfig {
+            revenue_share_percent: 70,
+            verified_revenue_share_percent: 85,
+            unverified_public_tutor_limit: Some(3),
+            verified_first_in_search: true,
+        }
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct EducatorStatus {
+    pub verified: bool,
+    pub verification: Option<EducatorVerification>,
+    pub revenue_share_percent: u8,
+    pub public_tutor_limit: Option<u32>,
+    pub public_tutors: u32,
+}
+
+// A public tutor as shown in marketplace search
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct TutorListing {
+    pub tutor: Tutor,
+    pub owner_name: String,
+    pub verified_educator: bool,
+    pub course_count: u32,
+}
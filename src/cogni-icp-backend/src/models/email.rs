@@ -0,0 +1,97 @@
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use ic_stable_structures::storable::{Storable, Bound};
+use std::borrow::Cow;
+
+// Email-in tutoring. Inbound mail is parsed by a trusted relay (which also checks SPF/DKIM)
+// and pushed to receive_inbound_email; replies go out through the provider's HTTP API.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct EmailConfig {
+    pub enabled: bool,
+    pub inbound_domain: String, // tutor addresses are {token}@{inbound_domain}
+    pub relay_principals: Vec<Principal>, // the only callers allowed to deliver inbound mail
+    pub provider_url: String, // POST endpoint of the sending API
+    pub api_key: String,
+    pub from_address: String,
+    pub max_body_chars: u32,
+    pub batch_size: u32,
+}
+
+impl Default for EmailConfig {
+    fn default() -> Self {
+        EmailConfig {
+            enabled: false,
+            inbound_domain: String::new(),
+            relay_principals: Vec::new(),
+            provider_url: String::new(),
+            api_key: String::new(),
+            from_address: String::new(),
+            max_body_chars: 8000,
+            batch_size: 20,
+        }
+    }
+}
+
+// A learner's private address for one tutor, keyed by token
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct TutorEmailAddress {
+    pub token: String,
+    pub address: String,
+    pub user_id: Principal,
+    pub tutor_id: String, // public id
+    pub session_id: Option<String>, // session mail continues in; a new one starts when it is closed
+    pub created_at: u64,
+    pub disabled: bool,
+}
+
+// One inbound email and the reply sent for it, keyed by the inbound Message-ID
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct EmailExchange {
+    pub inbound_message_id: String,
+    pub token: String,
+    pub user_id: Principal,
+    pub session_id: String,
+    pub user_message_id: String,
+    pub reply_message_id: String,
+    pub reply_to: String,
+    pub subject: String,
+    pub status: String, // "awaiting_reply", "queued" (reply ready to send), "sent", "failed"
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    pub next_attempt_at: u64,
+    pub received_at: u64,
+    pub sent_at: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct InboundEmail {
+    pub message_id: String,
+    pub from: String,
+    pub to: String,
+    pub subject: String,
+    pub text: String, // plain-text body
+}
+
+impl Storable for TutorEmailAddress {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for EmailExchange {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
@@ -0,0 +1,55 @@
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use ic_stable_structures::storable::{Storable, Bound};
+use std::borrow::Cow;
+
+// Lets a tutor owner embed one public tutor on their own site, keyed by token. The token is
+// a publishable key that ships in the page, so its limits are what protect the owner.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct EmbedToken {
+    pub token: String,
+    pub tutor_id: String, // public id
+    pub owner: Principal,
+    pub label: String,
+    pub daily_message_limit: u32, // across all visitors, UTC day
+    pub max_messages_per_session: u32,
+    pub max_active_sessions: u32,
+    pub session_ttl_minutes: u32,
+    pub created_at: u64,
+    pub revoked_at: Option<u64>,
+}
+
+// Keyed by "{token}:{day}"
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct EmbedUsageDay {
+    pub token: String,
+    pub day: u64, // days since the epoch, UTC
+    pub sessions_started: u64,
+    pub messages: u64,
+    pub failed_replies: u64,
+    pub rejected: u64, // turned away by a limit
+}
+
+impl Storable for EmbedToken {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for EmbedUsageDay {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
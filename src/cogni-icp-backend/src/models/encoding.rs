@@ -0,0 +1,47 @@
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+use ic_stable_structures::storable::{Storable, Bound};
+use std::borrow::Cow;
+
+// Background re-encoding of one stable map into the current record format
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct EncodingProgress {
+    pub map_name: String,
+    pub version: u8,
+    pub cursor: Option<Vec<u8>>, // encoded key of the last record rewritten
+    pub rewritten: u64,
+    pub started_at: u64,
+    pub completed_at: Option<u64>,
+}
+
+impl Storable for EncodingProgress {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(crate::codec::encode(self))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        crate::codec::decode(bytes.as_ref())
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct EncodingStatus {
+    pub current_version: u8,
+    pub legacy_reads: u64, // since the last upgrade
+    pub maps: Vec<EncodingProgress>,
+}
+
+// Sizes and instruction counts for the same records in both formats
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct EncodingBenchmark {
+    pub model: String,
+    pub records: u64,
+    pub legacy_bytes: u64,
+    pub compact_bytes: u64,
+    pub legacy_encode_instructions: u64,
+    pub compact_encode_instructions: u64,
+    pub legacy_decode_instructions: u64,
+    pub compact_decode_instructions: u64,
+}
@@ -0,0 +1,83 @@
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use ic_stable_structures::storable::{Storable, Bound};
+use std::borrow::Cow;
+
+// A time-bound event such as "Math March". The scheduled job moves it from "scheduled" to
+// "active" at starts_at and to "ended" at ends_at, switching its tasks on and off with it.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct SeasonalEvent {
+    pub id: u64,
+    pub name: String,
+    pub description: String,
+    pub theme: Option<String>, // for the frontend, e.g. a color scheme or banner name
+    pub starts_at: u64,
+    pub ends_at: u64,
+    pub points_multiplier_percent: u32, // applied to task points earned while active; 100 is no bonus
+    pub task_ids: Vec<u64>, // event tasks, only open while the event is active
+    pub badge_achievement_id: Option<u64>,
+    pub badge_min_points: u32,
+    pub status: String, // "scheduled", "active", "ended", "cancelled"
+    pub created_by: Principal,
+    pub created_at: u64,
+}
+
+impl Storable for SeasonalEvent {
+    fn to_bytes(&self) -> Cow<[u8]> { Cow::Owned(serde_cbor::to_vec(&self).unwrap()) }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self { serde_cbor::from_slice(bytes.as_ref()).unwrap() }
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct SeasonalEventSpec {
+    pub name: String,
+    pub description: String,
+    pub theme: Option<String>,
+    pub starts_at: u64,
+    pub ends_at: u64,
+    pub points_multiplier_percent: u32,
+    pub task_ids: Vec<u64>,
+    pub badge: Option<EventBadgeSpec>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct EventBadgeSpec {
+    pub title: String,
+    pub description: String,
+    pub icon: Option<String>,
+    pub min_points: u32, // event points needed to earn it
+}
+
+// Points a user earned during one event, keyed "{event_id:020}:{principal}"
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct EventParticipation {
+    pub event_id: u64,
+    pub user_id: Principal,
+    pub points: u32,
+    pub tasks_completed: u32,
+    pub badge_earned_at: Option<u64>,
+    pub last_scored_at: u64,
+}
+
+impl Storable for EventParticipation {
+    fn to_bytes(&self) -> Cow<[u8]> { Cow::Owned(serde_cbor::to_vec(&self).unwrap()) }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self { serde_cbor::from_slice(bytes.as_ref()).unwrap() }
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct EventLeaderboardEntry {
+    pub rank: u32,
+    pub user_id: Option<Principal>, // None for users with a private profile
+    pub username: String,
+    pub points: u32,
+    pub tasks_completed: u32,
+    pub badge_earned: bool,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct EventLeaderboard {
+    pub event: SeasonalEvent,
+    pub entries: Vec<EventLeaderboardEntry>,
+    pub own: Option<EventLeaderboardEntry>, // the caller's standing, even outside the top entries
+}
@@ -0,0 +1,109 @@
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use ic_stable_structures::storable::{Storable, Bound};
+use std::borrow::Cow;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Exam {
+    pub id: u64,
+    pub user_id: Principal,
+    pub tutor_id: String,
+    pub topic: String,
+    pub sections: Vec<ExamSection>,
+    pub current_section: u32,
+    pub status: String, // "in_progress", "completed"
+    pub flags: Vec<ExamFlag>,
+    pub score_percent: Option<f64>,
+    pub analysis: Option<String>,
+    pub created_at: u64,
+    pub completed_at: Option<u64>,
+}
+
+impl Storable for Exam {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ExamSection {
+    pub name: String,
+    pub duration_minutes: u32,
+    pub questions: Vec<ExamQuestion>,
+    pub started_at: Option<u64>,
+    pub deadline: Option<u64>, // started_at + duration; later answers are flagged, not rejected
+    pub finished_at: Option<u64>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ExamQuestion {
+    pub question: String,
+    pub options: Vec<String>,
+    pub correct_option: u32,
+    pub source: String, // "question_bank", "ai"
+    pub answer: Option<u32>,
+    pub answered_at: Option<u64>,
+    pub time_spent_ns: u64, // accumulated across changes to the answer
+    pub late: bool,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ExamFlag {
+    pub kind: String, // "late_answer", "rapid_answer", or a client event such as "tab_hidden"
+    pub section_index: u32,
+    pub question_index: Option<u32>,
+    pub timestamp: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ExamSectionSpec {
+    pub name: String,
+    pub question_count: u32,
+    pub duration_minutes: u32,
+}
+
+// Exam as shown to the learner while it is running, without the answer key
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ExamView {
+    pub exam_id: u64,
+    pub topic: String,
+    pub status: String,
+    pub current_section: u32,
+    pub section_name: String,
+    pub section_deadline: Option<u64>,
+    pub questions: Vec<ExamQuestionView>,
+    pub sections_total: u32,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ExamQuestionView {
+    pub question_index: u32,
+    pub question: String,
+    pub options: Vec<String>,
+    pub answer: Option<u32>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ExamSectionScore {
+    pub name: String,
+    pub correct: u32,
+    pub total: u32,
+    pub time_spent_ns: u64,
+    pub late_answers: u32,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ExamResult {
+    pub exam_id: u64,
+    pub topic: String,
+    pub score_percent: f64,
+    pub sections: Vec<ExamSectionScore>,
+    pub flags: Vec<ExamFlag>,
+    pub analysis: String,
+}
@@ -0,0 +1,91 @@
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use ic_stable_structures::storable::{Storable, Bound};
+use std::borrow::Cow;
+use crate::models::ai_providers::ModelParams;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Experiment {
+    pub id: u64,
+    pub name: String,
+    pub description: Option<String>,
+    pub kind: String, // "prompt" or "model"
+    pub variants: Vec<ExperimentVariant>, // the first is the control that lift is measured against
+    pub retention_days: u32, // a learner is retained if they chat again this many days after assignment
+    pub status: String, // "draft", "running", "stopped"
+    pub created_by: Principal,
+    pub created_at: u64,
+    pub started_at: Option<u64>,
+    pub stopped_at: Option<u64>,
+}
+
+impl Storable for Experiment {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ExperimentVariant {
+    pub key: String, // "A", "B", ...
+    pub weight: u32, // share of traffic relative to the other variants
+    pub prompt_suffix: Option<String>, // prompt experiments: instructions appended to tutor prompts
+    pub model_params: Option<ModelParams>, // model experiments: replaces the tutor's params
+}
+
+// A learner's sticky variant and their outcomes, keyed "{experiment id:020}:{principal}"
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ExperimentAssignment {
+    pub experiment_id: u64,
+    pub user_id: Principal,
+    pub variant: String,
+    pub assigned_at: u64,
+    pub last_active_at: u64,
+    pub rating_sum: u64,
+    pub rating_count: u64,
+    pub comprehension_sum: f64,
+    pub comprehension_count: u64,
+}
+
+impl Storable for ExperimentAssignment {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// Lifts are relative to the control variant and are None for the control itself or when
+// either side has no samples
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct VariantResult {
+    pub variant: String,
+    pub learners: u64,
+    pub ratings: u64,
+    pub mean_rating: Option<f64>,
+    pub rating_lift: Option<f64>,
+    pub comprehension_samples: u64,
+    pub mean_comprehension: Option<f64>,
+    pub comprehension_lift: Option<f64>,
+    pub retention_eligible: u64, // learners assigned at least retention_days ago
+    pub retained: u64,
+    pub retention_rate: Option<f64>,
+    pub retention_lift: Option<f64>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ExperimentResults {
+    pub experiment: Experiment,
+    pub variants: Vec<VariantResult>,
+    pub computed_at: u64,
+}
@@ -0,0 +1,39 @@
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+use ic_stable_structures::storable::{Storable, Bound};
+use std::borrow::Cow;
+
+// The second-pass review of a high-stakes tutor reply, keyed by the reply's message id
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct FactCheckRecord {
+    pub message_id: String,
+    pub session_id: String,
+    pub tutor_id: String,
+    pub domain: String,
+    pub draft: String,
+    pub issues: Vec<String>,
+    pub final_text: String,
+    pub status: String, // "verified", "corrected", "failed"
+    pub error: Option<String>,
+    pub created_at: u64,
+}
+
+impl Storable for FactCheckRecord {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// What the verification call is asked to return
+#[derive(Deserialize)]
+pub struct FactCheckVerdict {
+    #[serde(default)]
+    pub issues: Vec<String>,
+    pub answer: String,
+}
@@ -0,0 +1,32 @@
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use ic_stable_structures::storable::{Storable, Bound};
+use std::borrow::Cow;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct FeedbackItem {
+    pub id: u64,
+    pub user_id: Principal,
+    pub kind: String, // "feature", "bug"
+    pub title: String,
+    pub description: String,
+    pub status: String, // "open", "planned", "shipped", "declined", "merged"
+    pub voters: Vec<Principal>,
+    pub vote_count: u64,
+    pub merged_into: Option<u64>,
+    pub admin_note: Option<String>,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+impl Storable for FeedbackItem {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
@@ -0,0 +1,92 @@
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use ic_stable_structures::storable::{Storable, Bound};
+use std::borrow::Cow;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct FocusConfig {
+    pub min_minutes: u32,
+    pub max_minutes: u32,
+    pub max_idle_minutes: u32, // a longer gap without activity on the target breaks the commitment
+    pub points_per_session: u32,
+    pub streak_bonus_points: u32, // per consecutive day before today with a kept session
+    pub max_streak_bonus_days: u32,
+}
+
+impl Default for FocusConfig {
+    fn default() -> Self {
+        FocusConfig {
+            min_minutes: 10,
+            max_minutes: 180,
+            max_idle_minutes: 15,
+            points_per_session: 10,
+            streak_bonus_points: 2,
+            max_streak_bonus_days: 10,
+        }
+    }
+}
+
+// Keyed "{user_id}:{id:020}"
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct FocusSession {
+    pub id: u64,
+    pub user_id: Principal,
+    pub goal: String,
+    pub chat_session_id: Option<String>,
+    pub module_id: Option<u64>,
+    pub started_at: u64,
+    pub ends_at: u64,
+    pub status: String, // "active", "kept", "broken", "abandoned"
+    pub activity_count: u32, // on the declared session or module
+    pub off_target_count: u32, // chats in other sessions during the focus
+    pub last_activity_at: Option<u64>,
+    pub longest_gap_ns: u64,
+    pub points_awarded: u32,
+    pub ended_at: Option<u64>,
+}
+
+// Keyed "{user_id}:{week:010}", weeks counted in the user's local time from Monday
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct FocusWeek {
+    pub user_id: Principal,
+    pub week: u64,
+    pub week_start: u64, // local day number of the Monday
+    pub sessions: u32,
+    pub kept: u32,
+    pub broken: u32,
+    pub abandoned: u32,
+    pub focus_minutes: u64, // time spent in kept sessions
+    pub points: u32,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct FocusStats {
+    pub active: Option<FocusSession>,
+    pub streak_days: u32,
+    pub weeks: Vec<FocusWeek>, // newest first
+}
+
+impl Storable for FocusSession {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for FocusWeek {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
@@ -64,6 +64,8 @@ pub struct Task {
     pub created_at: u64,
     pub expires_at: Option<u64>,
     pub metadata: Option<HashMap<String, String>>,
+    #[serde(default)]
+    pub slug: Option<String>,
 }
 
 impl Storable for Task {
@@ -0,0 +1,25 @@
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use ic_stable_structures::storable::{Storable, Bound};
+use std::borrow::Cow;
+
+// A key term in a course, keyed "{course_id:020}:{normalized term}" so each term appears once
+// per course. Entries the course owner edits or removes are kept as they left them; later
+// extraction doesn't overwrite or re-add them.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct GlossaryEntry {
+    pub course_id: u64,
+    pub term: String,
+    pub definition: String,
+    pub module_id: Option<u64>,
+    pub source: String, // "extracted" (from lesson text), "ai", "user"
+    pub removed: bool,
+    pub edited_by: Option<Principal>,
+    pub updated_at: u64,
+}
+
+impl Storable for GlossaryEntry {
+    fn to_bytes(&self) -> Cow<[u8]> { Cow::Owned(serde_cbor::to_vec(&self).unwrap()) }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self { serde_cbor::from_slice(bytes.as_ref()).unwrap() }
+    const BOUND: Bound = Bound::Unbounded;
+}
@@ -0,0 +1,56 @@
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use ic_stable_structures::storable::{Storable, Bound};
+use std::borrow::Cow;
+use crate::models::tutor::ChatMessage;
+
+// Limits for demo sessions started without an account
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct GuestConfig {
+    pub enabled: bool,
+    pub session_ttl_minutes: u32,
+    pub max_ai_calls: u32, // per session; guests have no daily plan quota
+    pub max_message_chars: u32,
+    pub max_active_sessions: u32, // across all guests, since anonymous callers share one principal
+}
+
+impl Default for GuestConfig {
+    fn default() -> Self {
+        GuestConfig {
+            enabled: true,
+            session_ttl_minutes: 30,
+            max_ai_calls: 10,
+            max_message_chars: 500,
+            max_active_sessions: 200,
+        }
+    }
+}
+
+// Demo chat with the built-in tutor, or a visitor's chat with an embedded tutor, kept apart from real sessions until it expires or is converted
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct GuestSession {
+    pub token: String, // bearer secret identifying the session
+    pub started_by: Principal,
+    pub topic: String,
+    pub messages: Vec<ChatMessage>,
+    pub ai_calls_remaining: u32,
+    pub created_at: u64,
+    pub expires_at: u64,
+    #[serde(default)]
+    pub embed_token: Option<String>, // set when started from an embedded tutor
+    #[serde(default)]
+    pub tutor_id: Option<String>, // the embedded tutor's public id; None for the demo tutor
+}
+
+impl Storable for GuestSession {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
@@ -0,0 +1,12 @@
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+use crate::models::tutor::ChatSession;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct HistoryImportReport {
+    pub format: String, // the detected format when "auto" was requested
+    pub conversations_found: u32,
+    pub sessions: Vec<ChatSession>,
+    pub messages_imported: u32,
+    pub skipped: Vec<String>, // one line per conversation left out, with the reason
+}
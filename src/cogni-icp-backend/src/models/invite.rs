@@ -0,0 +1,66 @@
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use ic_stable_structures::storable::{Storable, Bound};
+use std::borrow::Cow;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct RegistrationConfig {
+    pub invite_only: bool, // register_user requires a valid invite code
+    pub waitlist_open: bool,
+}
+
+impl Default for RegistrationConfig {
+    fn default() -> Self {
+        RegistrationConfig {
+            invite_only: false,
+            waitlist_open: true,
+        }
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct InviteCode {
+    pub code: String,
+    pub batch: String, // label shared by codes created together
+    pub max_uses: u32,
+    pub uses: u32,
+    pub expires_at: Option<u64>,
+    pub issued_to: Option<String>, // waitlisted email the code was sent to; only that email can redeem it
+    pub revoked: bool,
+    pub created_by: Principal,
+    pub created_at: u64,
+}
+
+impl Storable for InviteCode {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct WaitlistEntry {
+    pub email: String,
+    pub joined_at: u64,
+    pub invite_code: Option<String>,
+    pub invited_at: Option<u64>,
+    pub registered_at: Option<u64>,
+}
+
+impl Storable for WaitlistEntry {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
@@ -0,0 +1,87 @@
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use ic_stable_structures::storable::{Storable, Bound};
+use std::borrow::Cow;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct LearnerRiskConfig {
+    pub enabled: bool,
+    pub recent_days: u32, // compared against the baseline_days before them
+    pub baseline_days: u32,
+    pub min_baseline_minutes: u32, // learners who studied less than this in the baseline aren't assessed
+    pub activity_drop_percent: u32, // flag when daily study time falls this far below the baseline
+    pub comprehension_drop: f64, // flag when average comprehension (0-1) falls by this much
+    pub nudges_enabled: bool,
+    pub outcome_days: u32, // a flag's outcome is measured this long after it was raised
+    pub cooldown_days: u32, // a learner isn't flagged again this soon after a flag closes
+}
+
+impl Default for LearnerRiskConfig {
+    fn default() -> Self {
+        LearnerRiskConfig {
+            enabled: true,
+            recent_days: 7,
+            baseline_days: 28,
+            min_baseline_minutes: 60,
+            activity_drop_percent: 60,
+            comprehension_drop: 0.15,
+            nudges_enabled: true,
+            outcome_days: 7,
+            cooldown_days: 14,
+        }
+    }
+}
+
+// A sharp drop in a learner's activity or comprehension against their own baseline. The flag
+// stays open until its outcome is measured, which is what the effectiveness stats are built on.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct LearnerRiskFlag {
+    pub id: u64,
+    pub user_id: Principal,
+    pub reasons: Vec<String>, // "activity_drop", "comprehension_drop"
+    pub baseline_minutes_per_day: f64,
+    pub recent_minutes_per_day: f64,
+    pub baseline_comprehension: Option<f64>,
+    pub recent_comprehension: Option<f64>,
+    pub flagged_at: u64,
+    pub status: String, // "open", "closed"
+    pub nudge: String, // "pending", "sent", "skipped"
+    pub nudge_message: Option<String>, // only shown to the learner
+    pub nudged_at: Option<u64>,
+    pub outcome: Option<String>, // "re_engaged", "no_change"
+    pub minutes_per_day_after: Option<f64>,
+    pub closed_at: Option<u64>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct LearnerRiskView {
+    pub flag: LearnerRiskFlag,
+    pub username: String,
+}
+
+// Learners who got a nudge against those who didn't (nudges off, or muted by the learner)
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct LearnerRiskStats {
+    pub flagged: u32,
+    pub open: u32,
+    pub nudges_sent: u32,
+    pub measured_nudged: u32,
+    pub re_engaged_nudged: u32,
+    pub measured_not_nudged: u32,
+    pub re_engaged_not_nudged: u32,
+    pub re_engagement_rate_nudged: Option<f64>, // 0-100
+    pub re_engagement_rate_not_nudged: Option<f64>,
+}
+
+impl Storable for LearnerRiskFlag {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
@@ -0,0 +1,50 @@
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+
+// Licenses creators can publish tutors and courses under, stored as the code. A record with
+// no license is all rights reserved.
+pub const LICENSES: [&str; 6] = ["all_rights_reserved", "cc_by", "cc_by_sa", "cc_by_nc", "cc_by_nd", "cc0"];
+
+pub struct LicenseTerms {
+    pub allows_copies: bool, // others may copy and then edit their copy
+    pub allows_export: bool, // others may download it verbatim
+    pub share_alike: bool, // copies must keep the same license
+    pub non_commercial: bool, // copies may not earn creator revenue
+}
+
+pub fn license_terms(code: &str) -> LicenseTerms {
+    let (allows_copies, allows_export) = match code {
+        "cc_by" | "cc_by_sa" | "cc_by_nc" | "cc0" => (true, true),
+        "cc_by_nd" => (false, true),
+        _ => (false, false),
+    };
+    LicenseTerms {
+        allows_copies,
+        allows_export,
+        share_alike: code == "cc_by_sa",
+        non_commercial: code == "cc_by_nc",
+    }
+}
+
+pub fn license_name(code: &str) -> &'static str {
+    match code {
+        "cc_by" => "CC BY 4.0",
+        "cc_by_sa" => "CC BY-SA 4.0",
+        "cc_by_nc" => "CC BY-NC 4.0",
+        "cc_by_nd" => "CC BY-ND 4.0",
+        "cc0" => "CC0 1.0",
+        _ => "All rights reserved",
+    }
+}
+
+// One step in where a copied tutor or course came from; chains are kept oldest first
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct AttributionEntry {
+    pub source_type: String, // "tutor" or "course"
+    pub source_id: String, // public id of the tutor, or the course id
+    pub title: String,
+    pub creator: Principal,
+    pub creator_name: String,
+    pub license: String,
+    pub copied_at: u64,
+}
@@ -0,0 +1,59 @@
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use ic_stable_structures::storable::{Storable, Bound};
+use std::borrow::Cow;
+
+// After max_failures wrong passwords in a row the account locks for base_lockout_secs, and
+// each further failure doubles that, up to max_lockout_secs. A failure streak older than
+// reset_after_secs starts over.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct LoginLockoutConfig {
+    pub enabled: bool,
+    pub max_failures: u32,
+    pub base_lockout_secs: u64,
+    pub max_lockout_secs: u64,
+    pub reset_after_secs: u64,
+}
+
+impl Default for LoginLockoutConfig {
+    fn default() -> Self {
+        LoginLockoutConfig {
+            enabled: true,
+            max_failures: 5,
+            base_lockout_secs: 60,
+            max_lockout_secs: 24 * 60 * 60,
+            reset_after_secs: 24 * 60 * 60,
+        }
+    }
+}
+
+// Keyed by normalized email; only kept for emails that belong to an account
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct LoginAttempts {
+    pub email: String,
+    pub failed_count: u32, // consecutive failures since the last success
+    pub locked_until: Option<u64>,
+    pub last_failed_at: Option<u64>,
+    pub last_success_at: Option<u64>,
+    pub recent: Vec<LoginAttempt>, // newest last
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct LoginAttempt {
+    pub at: u64,
+    pub caller: Principal,
+    pub outcome: String, // "success", "invalid_password", "locked"
+}
+
+impl Storable for LoginAttempts {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
@@ -0,0 +1,91 @@
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use ic_stable_structures::storable::{Storable, Bound};
+use std::borrow::Cow;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct SkillProficiency {
+    pub user_id: Principal,
+    pub skill: String,
+    pub score: f64, // 0-100, decayed from assessed_score since the last assessment
+    pub assessed_score: f64,
+    pub half_life_days: f64, // grows with each passed review
+    pub review_count: u32,
+    pub last_assessed_at: u64,
+    pub due_for_review: bool,
+    pub updated_at: u64,
+}
+
+impl Storable for SkillProficiency {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// Review quizzes live on the heap only; the proficiency update is what gets persisted
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ReviewQuiz {
+    pub id: String,
+    pub user_id: Principal,
+    pub skill: String,
+    pub questions: Vec<ReviewQuestion>,
+    pub created_at: u64,
+    pub expires_at: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ReviewQuestion {
+    pub question: String,
+    pub options: Vec<String>,
+}
+
+// Forgetting-curve reminders: at most max_per_week per user, min_gap_hours apart, and one
+// per skill until it is reviewed again
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ReviewReminderConfig {
+    pub enabled: bool,
+    pub max_per_week: u32,
+    pub min_gap_hours: u32,
+    pub refresher_minutes: u32, // quoted in the reminder
+}
+
+impl Default for ReviewReminderConfig {
+    fn default() -> Self {
+        ReviewReminderConfig { enabled: true, max_per_week: 3, min_gap_hours: 48, refresher_minutes: 10 }
+    }
+}
+
+// A reminder sent for one skill, keyed "{principal}:{id:020}"; its id is the notification's related_id
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ReviewReminder {
+    pub id: u64,
+    pub user_id: Principal,
+    pub skill: String,
+    pub score: f64, // decayed score when the reminder was sent
+    pub days_since_assessed: u32,
+    pub deep_link: String,
+    pub created_at: u64,
+    pub opened_at: Option<u64>,
+}
+
+impl Storable for ReviewReminder {
+    fn to_bytes(&self) -> Cow<[u8]> { Cow::Owned(serde_cbor::to_vec(&self).unwrap()) }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self { serde_cbor::from_slice(bytes.as_ref()).unwrap() }
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ReviewResult {
+    pub skill: String,
+    pub correct: u32,
+    pub total: u32,
+    pub score_before: f64,
+    pub proficiency: SkillProficiency,
+}
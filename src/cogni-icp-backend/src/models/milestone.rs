@@ -0,0 +1,40 @@
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use ic_stable_structures::storable::{Storable, Bound};
+use std::borrow::Cow;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Milestone {
+    pub id: u64,
+    pub public_id: String, // share token for the card's HTTP route
+    pub user_id: Principal,
+    pub kind: String, // "course_completed", "streak_30"
+    pub reference: u64, // course id, or the local day the streak started
+    pub card: AchievementCard,
+    pub post_id: Option<u64>,
+    pub created_at: u64,
+}
+
+// Everything a client needs to render the shareable image
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct AchievementCard {
+    pub template: String, // same as the milestone kind
+    pub display_name: String,
+    pub headline: String,
+    pub message: String,
+    pub stat_label: String,
+    pub stat_value: String,
+    pub earned_at: u64,
+}
+
+impl Storable for Milestone {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
@@ -6,4 +6,67 @@ pub mod gamification;
 pub mod notifications;
 pub mod billing;
 pub mod learning_path;
-pub mod learning_progress; 
\ No newline at end of file
+pub mod learning_progress; 
+pub mod placement;
+pub mod certificate;
+pub mod http;
+pub mod announcements;
+pub mod support;
+pub mod feedback;
+pub mod config;
+pub mod retention;
+pub mod storage;
+pub mod sharding;
+pub mod ai_providers;
+pub mod delivery;
+pub mod preview;
+pub mod quota;
+pub mod audit;
+pub mod mastery;
+pub mod exam;
+pub mod cohort;
+pub mod tagging;
+pub mod alias;
+pub mod curriculum;
+pub mod xapi;
+pub mod guest;
+pub mod invite;
+pub mod undo;
+pub mod cache;
+pub mod encoding;
+pub mod certification;
+pub mod confidence;
+pub mod fact_check;
+pub mod educator;
+pub mod creator;
+pub mod ledger;
+pub mod experiment;
+pub mod history_import;
+pub mod email;
+pub mod bot_bridge;
+pub mod public_api;
+pub mod embed;
+pub mod license;
+pub mod refund;
+pub mod study_pack;
+pub mod concept_map;
+pub mod glossary;
+pub mod read_position;
+pub mod auth_session;
+pub mod chat_signal;
+pub mod event;
+pub mod oauth;
+pub mod topic_drift;
+pub mod rate_limit;
+pub mod persona_eval;
+pub mod daily_plan;
+pub mod milestone;
+pub mod activity;
+pub mod login_attempt;
+pub mod focus;
+pub mod daily_question;
+pub mod peer_tutoring;
+pub mod data_export;
+pub mod organization;
+pub mod org_report;
+pub mod learner_risk;
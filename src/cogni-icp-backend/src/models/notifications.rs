@@ -1,5 +1,7 @@
 use candid::{CandidType, Principal};
 use serde::{Deserialize, Serialize};
+use ic_stable_structures::storable::{Storable, Bound};
+use std::borrow::Cow;
 
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
 pub struct Notification {
@@ -11,4 +13,80 @@ pub struct Notification {
     pub source: String, // "tutor", "study_group", "achievement", etc.
     pub related_id: Option<u64>,
     pub timestamp: u64,
-} 
\ No newline at end of file
+}
+
+impl Storable for Notification {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+// Limits applied by notify_user and the push relay. Sources in exempt_sources (account,
+// billing and support notices) are never capped or held for a digest.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct NotificationConfig {
+    pub default_daily_limit: u32, // per source per local day; 0 means no limit
+    pub digest_hour: u8, // local hour pending digests go out
+    pub exempt_sources: Vec<String>,
+    pub push_enabled: bool,
+    pub push_relay_principals: Vec<Principal>, // the only callers allowed to claim pushes
+    pub push_batch_size: u32,
+}
+
+impl Default for NotificationConfig {
+    fn default() -> Self {
+        NotificationConfig {
+            default_daily_limit: 5,
+            digest_hour: 18,
+            exempt_sources: vec!["billing".to_string(), "support".to_string(), "system".to_string()],
+            push_enabled: false,
+            push_relay_principals: Vec::new(),
+            push_batch_size: 100,
+        }
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct SourceCount {
+    pub source: String,
+    pub count: u32,
+}
+
+// A notification held back by a cap or digest-only mode
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct DigestItem {
+    pub notification_type: String,
+    pub source: String,
+    pub content: String,
+    pub related_id: Option<u64>,
+    pub timestamp: u64,
+}
+
+// One per user: today's delivered counts and what is waiting for the next digest
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct NotificationLedger {
+    pub user_id: Principal,
+    pub day: u64, // local day number the counts belong to
+    pub delivered: Vec<SourceCount>,
+    pub pending: Vec<DigestItem>,
+    pub overflow: u32, // held items beyond what pending keeps
+    pub last_digest_day: u64,
+}
+
+impl Storable for NotificationLedger {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
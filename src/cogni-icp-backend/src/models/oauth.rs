@@ -0,0 +1,27 @@
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+
+// Provider identities are verified by HTTPS outcall: Google ID tokens against the tokeninfo
+// endpoint, GitHub OAuth tokens against the app's token check, which needs the client secret.
+// Tokens are rejected unless they were issued to the configured client id.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct OAuthConfig {
+    pub google_client_id: String,
+    pub google_tokeninfo_url: String,
+    pub github_client_id: String,
+    pub github_client_secret: String,
+    pub github_api_url: String,
+}
+
+impl Default for OAuthConfig {
+    fn default() -> Self {
+        OAuthConfig {
+            google_client_id: String::new(),
+            google_tokeninfo_url: "https://oauth2.googleapis.com/tokeninfo".to_string(),
+            github_client_id: String::new(),
+            github_client_secret: String::new(),
+            github_api_url: "https://api.github.com".to_string(),
+        }
+    }
+}
@@ -0,0 +1,62 @@
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+
+pub const ORG_REPORT_SCOPES: [&str; 2] = ["cohort", "course"];
+
+// One enrollment of an organization member. Only counts and scores are reported; chat
+// content, session topics and exam answers never leave the learner's own records.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct OrgLearnerReport {
+    pub user_id: Principal,
+    pub username: String,
+    pub email: String,
+    pub cohort_id: u64,
+    pub enrolled_at: u64,
+    pub completed_modules: u32,
+    pub total_modules: u32,
+    pub progress_percent: f64,
+    pub completed_at: Option<u64>,
+    pub minutes_spent: u64, // since enrolling, across all of the learner's study
+    pub average_comprehension: Option<f64>, // 0-1
+    pub average_exam_score: Option<f64>, // 0-100, exams with the course's tutor
+    pub last_active: u64,
+    pub risk_reasons: Vec<String>, // "inactive", "low_comprehension", "low_exam_scores", or a drop flagged by the learner risk job; empty when on track
+}
+
+// Aggregates over the organization's members in one cohort, or in every cohort of a course
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct OrgProgressReport {
+    pub org_id: u64,
+    pub scope: String, // "cohort" or "course"
+    pub scope_id: String, // the cohort id, or the course's tutor id
+    pub cohort_ids: Vec<u64>,
+    pub learners: u32,
+    pub completed: u32,
+    pub completion_rate: f64, // 0-100
+    pub average_progress: f64, // 0-100
+    pub average_comprehension: Option<f64>,
+    pub average_exam_score: Option<f64>,
+    pub total_minutes: u64,
+    pub average_minutes: f64,
+    pub modules: Vec<OrgModuleProgress>, // cohort reports only
+    pub at_risk: Vec<OrgLearnerReport>,
+    pub generated_at: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct OrgModuleProgress {
+    pub order: u32,
+    pub title: String,
+    pub unlocked: bool,
+    pub completed_count: u32,
+    pub completion_rate: f64, // 0-100
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct OrgReportCsvChunk {
+    pub csv: String, // the first chunk starts with a header line
+    pub offset: u32,
+    pub rows: u32,
+    pub total: u32,
+    pub next_offset: Option<u32>,
+}
@@ -0,0 +1,136 @@
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use ic_stable_structures::storable::{Storable, Bound};
+use std::borrow::Cow;
+
+pub const MAX_ORG_DOMAINS: usize = 20;
+pub const MAX_ORG_MEMBER_BATCH: usize = 100;
+pub const ORG_IMPORT_ROLES: [&str; 2] = ["member", "admin"];
+
+// An organization that signs its members in through its own identity provider. Its backend
+// holds a service key and asserts members by the provider's subject id; only the key's hash
+// is kept.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Organization {
+    pub id: u64,
+    pub name: String,
+    pub email_domains: Vec<String>, // member emails must be on one of these or a subdomain
+    pub admins: Vec<Principal>, // can query the organization's members
+    pub key_hash: String,
+    pub key_issued_at: u64,
+    pub key_last_used_at: Option<u64>,
+    pub active: bool,
+    pub created_by: Principal,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+// Keyed "{org_id:020}:{external_id}"
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct OrgMember {
+    pub org_id: u64,
+    pub external_id: String, // the identity provider's subject id
+    pub user_id: Principal,
+    pub email: String,
+    pub status: String, // "pending_link", "active", "declined", "deprovisioned"
+    pub linked_existing: bool, // the account existed before provisioning; its owner accepts the link and keeps control of it
+    pub provisioned_at: u64,
+    pub updated_at: u64,
+    pub deprovisioned_at: Option<u64>,
+    #[serde(default)]
+    pub invited_at: Option<u64>, // when the invitation email went out; imports only invite once
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct OrgMemberInput {
+    pub external_id: String,
+    pub email: String,
+    pub username: Option<String>,
+    pub first_name: Option<String>,
+    pub last_name: Option<String>,
+}
+
+// One entry per input of a bulk call, so a bad row doesn't fail the batch
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct OrgMemberResult {
+    pub external_id: String,
+    pub member: Option<OrgMember>,
+    pub error: Option<String>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct OrgMemberView {
+    pub member: OrgMember,
+    pub username: String,
+    pub is_active: bool,
+    pub last_active: Option<u64>,
+}
+
+// One line of an import. Lines are checked when their batch arrives; valid ones wait as
+// "pending" for the import job.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct OrgImportRow {
+    pub batch: u32,
+    pub line: u32, // counted across all batches, header lines excluded
+    pub name: String,
+    pub email: String,
+    pub role: String,
+    pub cohort_id: Option<u64>,
+    pub status: String, // "pending", "created", "link_requested", "updated", "unchanged", "failed"
+    pub error: Option<String>,
+    pub user_id: Option<Principal>,
+    pub invitation: String, // "none", "pending", "sent", "failed", "skipped"
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct OrgImport {
+    pub id: u64,
+    pub org_id: u64,
+    pub created_by: Principal,
+    pub batches_received: u32,
+    pub closed: bool, // the last batch has arrived
+    pub status: String, // "receiving", "processing", "completed"
+    pub rows: Vec<OrgImportRow>,
+    pub processed: u32,
+    pub failed: u32,
+    pub invitations_sent: u32,
+    pub created_at: u64,
+    pub updated_at: u64,
+    pub completed_at: Option<u64>,
+}
+
+impl Storable for Organization {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for OrgMember {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for OrgImport {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
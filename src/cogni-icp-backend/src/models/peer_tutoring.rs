@@ -0,0 +1,147 @@
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use ic_stable_structures::storable::{Storable, Bound};
+use std::borrow::Cow;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct PeerTutoringConfig {
+    pub enabled: bool,
+    pub platform_fee_percent: u32, // kept from each released payment
+    pub min_slot_minutes: u32,
+    pub max_slot_minutes: u32,
+    pub booking_window_days: u32, // how far ahead slots can be offered
+    pub payment_hold_minutes: u32, // an unpaid card booking holds its slot this long
+    pub cancellation_notice_hours: u32, // learners can cancel for a refund until this long before the start
+    pub confirm_window_hours: u32, // after the end; payment is released if nobody disputes by then
+}
+
+impl Default for PeerTutoringConfig {
+    fn default() -> Self {
+        PeerTutoringConfig {
+            enabled: true,
+            platform_fee_percent: 10,
+            min_slot_minutes: 15,
+            max_slot_minutes: 180,
+            booking_window_days: 60,
+            payment_hold_minutes: 30,
+            cancellation_notice_hours: 24,
+            confirm_window_hours: 48,
+        }
+    }
+}
+
+// A verified educator's offer of one-to-one tutoring
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct PeerTutorProfile {
+    pub user_id: Principal,
+    pub headline: String,
+    pub bio: String,
+    pub subjects: Vec<String>,
+    pub hourly_rate: u64, // minor unit of currency
+    pub currency: String,
+    pub token_hourly_rate: Option<u64>, // None when the tutor doesn't take tokens
+    pub active: bool,
+    pub rating_sum: u64,
+    pub rating_count: u32,
+    pub sessions_completed: u32,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct PeerSlotSpec {
+    pub starts_at: u64,
+    pub minutes: u32,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct PeerSlot {
+    pub id: u64,
+    pub tutor_id: Principal,
+    pub starts_at: u64,
+    pub ends_at: u64,
+    pub status: String, // "open", "booked"
+    pub booking_id: Option<u64>,
+    pub created_at: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct PeerDispute {
+    pub raised_by: Principal,
+    pub reason: String,
+    pub raised_at: u64,
+    pub resolution: Option<String>, // "released" or "refunded"
+    pub note: Option<String>,
+    pub resolved_by: Option<Principal>,
+    pub resolved_at: Option<u64>,
+}
+
+// The learner's payment stays in escrow until the learner confirms the session, the confirm
+// window passes without a dispute, or an admin resolves a dispute
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct PeerBooking {
+    pub id: u64,
+    pub slot_id: u64,
+    pub tutor_id: Principal,
+    pub learner_id: Principal,
+    pub starts_at: u64,
+    pub ends_at: u64,
+    pub topic: String,
+    pub payment_method: String, // "paystack" or "token"
+    pub amount: u64, // charged to the learner, tax included for card payments
+    pub currency: String,
+    pub platform_fee: u64,
+    pub transaction_id: u64,
+    pub status: String, // "pending_payment", "booked", "completed", "cancelled", "disputed"
+    pub escrow: String, // "awaiting_payment", "held", "releasing", "released", "refunding", "refunded", "none"
+    pub escrow_error: Option<String>, // last failed release or refund, until an admin retries it
+    pub ledger_block: Option<u64>,
+    pub learner_confirmed_at: Option<u64>,
+    pub tutor_confirmed_at: Option<u64>,
+    pub tutor_rating: Option<u8>, // given by the learner
+    pub tutor_review: Option<String>,
+    pub learner_rating: Option<u8>, // given by the tutor
+    pub learner_review: Option<String>,
+    pub dispute: Option<PeerDispute>,
+    pub cancelled_by: Option<Principal>,
+    pub cancel_reason: Option<String>,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+impl Storable for PeerTutorProfile {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for PeerSlot {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for PeerBooking {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
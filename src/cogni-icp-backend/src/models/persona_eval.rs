@@ -0,0 +1,75 @@
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+use ic_stable_structures::storable::{Storable, Bound};
+use std::borrow::Cow;
+
+// How often tutors' recent replies are judged against their declared personality and
+// teaching style. Owners are notified when a score falls below alert_below.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct PersonaEvalConfig {
+    pub enabled: bool,
+    pub interval_hours: u32, // per tutor
+    pub sample_size: u32,
+    pub min_replies: u32, // tutors with fewer recent replies are skipped
+    pub tutors_per_run: u32,
+    pub alert_below: f32,
+}
+
+impl Default for PersonaEvalConfig {
+    fn default() -> Self {
+        PersonaEvalConfig {
+            enabled: true,
+            interval_hours: 24 * 7,
+            sample_size: 6,
+            min_replies: 3,
+            tutors_per_run: 5,
+            alert_below: 0.6,
+        }
+    }
+}
+
+// Keyed "{tutor_id:020}:{id:020}". The revision is a hash of the persona fields the replies
+// were judged against, so scores can be compared before and after the owner edits them.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct PersonaEvaluation {
+    pub id: u64,
+    pub tutor_id: u64,
+    pub revision: String,
+    pub score: f32, // 0.0-1.0
+    pub sampled_replies: u32,
+    pub findings: Vec<String>,
+    pub suggestions: Vec<String>, // changes to the personality or teaching style text
+    pub evaluated_at: u64,
+}
+
+impl Storable for PersonaEvaluation {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct RevisionScore {
+    pub revision: String,
+    pub evaluations: u32,
+    pub average_score: f32,
+    pub first_evaluated_at: u64,
+    pub last_evaluated_at: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct PersonaConsistencyReport {
+    pub tutor_id: String,
+    pub current_revision: String,
+    pub latest: Option<PersonaEvaluation>,
+    pub change_since_previous: Option<f32>, // latest score minus the one before it
+    pub revisions: Vec<RevisionScore>, // oldest first
+    pub evaluations: Vec<PersonaEvaluation>, // newest first
+}
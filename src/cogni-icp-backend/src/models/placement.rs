@@ -0,0 +1,64 @@
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use ic_stable_structures::storable::{Storable, Bound};
+use std::borrow::Cow;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct PlacementTest {
+    pub id: u64,
+    pub user_id: Principal,
+    pub tutor_id: String,
+    pub topic: String,
+    pub items: Vec<PlacementItem>,
+    pub current_item: u32,
+    pub levels_passed: u32,
+    pub status: String, // "in_progress", "completed"
+    pub recommended_difficulty: Option<String>, // "beginner", "intermediate", "advanced"
+    pub created_at: u64,
+    pub updated_at: u64,
+    pub completed_at: Option<u64>,
+}
+
+impl Storable for PlacementTest {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct PlacementItem {
+    pub question: String,
+    pub options: Vec<String>,
+    pub correct_option: u32,
+    pub difficulty: u32, // 1 (easiest) to 5 (hardest)
+    pub kind: String, // "quiz", "self_assessment"
+    pub answer: Option<u32>,
+    pub is_correct: Option<bool>,
+}
+
+// Question as shown to the learner, without the answer key
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct PlacementQuestion {
+    pub test_id: u64,
+    pub item_index: u32,
+    pub question: String,
+    pub options: Vec<String>,
+    pub difficulty: u32,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct PlacementResult {
+    pub test_id: u64,
+    pub topic: String,
+    pub status: String,
+    pub levels_passed: u32,
+    pub questions_answered: u32,
+    pub recommended_difficulty: Option<String>,
+    pub next_question: Option<PlacementQuestion>,
+}
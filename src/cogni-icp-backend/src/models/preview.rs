@@ -0,0 +1,30 @@
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use crate::models::tutor::CourseOutline;
+
+// Previews live on the heap only; nothing is persisted until the token is confirmed
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct CoursePreview {
+    pub token: String,
+    pub user_id: Principal,
+    pub tutor_id: String,
+    pub topic: String,
+    pub outline: CourseOutline,
+    pub created_at: u64,
+    pub expires_at: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct TutorPersonaPreview {
+    pub token: String,
+    pub user_id: Principal,
+    pub name: String,
+    pub description: String,
+    pub teaching_style: String,
+    pub personality: String,
+    pub expertise: Vec<String>,
+    pub ai_generated: bool, // false when the AI reply could not be used and defaults were filled in
+    pub created_at: u64,
+    pub expires_at: u64,
+}
@@ -0,0 +1,75 @@
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use ic_stable_structures::storable::{Storable, Bound};
+use std::borrow::Cow;
+
+pub const API_SCOPES: [&str; 4] = ["catalog:tutors", "catalog:courses", "users:read", "users:write"];
+pub const MAX_EXTERNAL_USER_BATCH: usize = 100;
+
+// A key for the public catalog API or for machine callers of the external user endpoints. The
+// key itself is returned once at creation; only its hash is kept.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ApiToken {
+    pub id: u64,
+    pub name: String,
+    pub key_hash: String,
+    pub scopes: Vec<String>,
+    pub rate_limit_per_minute: u32,
+    pub allowed_origins: Vec<String>, // for CORS; "*" allows any site
+    pub created_by: Principal,
+    pub created_at: u64,
+    pub revoked_at: Option<u64>,
+    pub last_used_at: Option<u64>,
+}
+
+// Requests per token per day, keyed by "{token_id:020}:{day}"
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ApiUsageDay {
+    pub token_id: u64,
+    pub day: u64, // days since the epoch, UTC
+    pub requests: u64,
+    pub rate_limited: u64,
+    pub endpoints: Vec<(String, u64)>,
+}
+
+// Principals allowed to call the external user endpoints without a key, such as a backend
+// that signs its calls with its own identity
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct ExternalApiConfig {
+    pub allowed_principals: Vec<Principal>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ExternalUserInput {
+    pub email: String,
+    pub username: Option<String>,
+    pub first_name: Option<String>,
+    pub last_name: Option<String>,
+    pub avatar_url: Option<String>,
+    pub is_verified: Option<bool>, // only applied to accounts the call creates
+}
+
+impl Storable for ApiToken {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for ApiUsageDay {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
@@ -0,0 +1,42 @@
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+use crate::models::config::PlanLimits;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct QuotaStatus {
+    pub resource: String, // "ai_calls", "storage", "tutors", "group_memberships"
+    pub plan: String,
+    pub used: u64,
+    pub limit: Option<u64>, // None means unlimited
+    pub reset_at: Option<u64>, // None for quotas that do not reset
+    pub warning: Option<String>, // set once usage reaches the soft limit
+}
+
+// AI requests the user has running right now, so clients can hold the send button while at the cap
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct AiConcurrencyStatus {
+    pub plan: String,
+    pub in_flight: u32,
+    pub limit: Option<u32>, // None means unlimited
+    pub operations: Vec<String>, // operations currently running, e.g. "chat"
+    pub queued_replies: u32, // tutor replies waiting for a free slot
+    pub can_send: bool,
+}
+
+// Everything the user's plan allows next to what they have used, and what other plans add
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct PlanUsage {
+    pub plan: String,
+    pub resources: Vec<QuotaStatus>,
+    pub max_concurrent_ai: Option<u32>,
+    pub cycles_per_ai_call: u64, // the most one chat call may attach per attempt
+    pub estimated_ai_cycles_today: u64,
+    pub subscription_renews_at: Option<u64>,
+    pub upgrades: Vec<PlanUpgrade>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct PlanUpgrade {
+    pub limits: PlanLimits,
+    pub gains: Vec<String>, // e.g. "1000 AI calls a day instead of 50"
+}
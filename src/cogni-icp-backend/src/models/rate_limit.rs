@@ -0,0 +1,51 @@
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+use ic_stable_structures::storable::{Storable, Bound};
+use std::borrow::Cow;
+
+// Calls allowed per principal in each window, by method class ("chat" for messages to a
+// tutor, "generation" for other AI-backed calls). A class without a rule isn't limited.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    pub enabled: bool,
+    pub rules: Vec<RateLimitRule>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct RateLimitRule {
+    pub class: String,
+    pub max_calls: u32,
+    pub window_secs: u32,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            enabled: true,
+            rules: vec![
+                RateLimitRule { class: "chat".to_string(), max_calls: 20, window_secs: 60 },
+                RateLimitRule { class: "generation".to_string(), max_calls: 10, window_secs: 60 },
+            ],
+        }
+    }
+}
+
+// Keyed "{class}:{principal}"
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct RateWindow {
+    pub window_start: u64,
+    pub count: u32,
+}
+
+impl Storable for RateWindow {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
@@ -0,0 +1,29 @@
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use ic_stable_structures::storable::{Storable, Bound};
+use std::borrow::Cow;
+
+// Where a user stopped reading a session on one device, keyed
+// "{session_id}:{principal}:{device_id}"
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ReadCursor {
+    pub session_id: String,
+    pub user_id: Principal,
+    pub device_id: String,
+    pub message_id: String,
+    pub message_timestamp: u64, // places the cursor if the message is later pruned
+    pub updated_at: u64,
+}
+
+impl Storable for ReadCursor {
+    fn to_bytes(&self) -> Cow<[u8]> { Cow::Owned(serde_cbor::to_vec(&self).unwrap()) }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self { serde_cbor::from_slice(bytes.as_ref()).unwrap() }
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ReadPositions {
+    pub cursors: Vec<ReadCursor>, // one per device, most recently updated first
+    pub resume_at: Option<ReadCursor>, // the furthest position on any device
+    pub unread_count: u32,
+}
@@ -0,0 +1,53 @@
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use ic_stable_structures::storable::{Storable, Bound};
+use std::borrow::Cow;
+
+// Card payments are refunded through Paystack; token payments are sent back through the ledger
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct RefundConfig {
+    pub enabled: bool,
+    pub window_days: u32, // after the payment settled
+    pub paystack_url: String,
+    pub paystack_secret_key: String,
+    pub token_ledger: Option<Principal>, // ICRC-1 ledger token payments were made on
+}
+
+impl Default for RefundConfig {
+    fn default() -> Self {
+        RefundConfig {
+            enabled: true,
+            window_days: 14,
+            paystack_url: "https://api.paystack.co/refund".to_string(),
+            paystack_secret_key: String::new(),
+            token_ledger: None,
+        }
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct RefundRequest {
+    pub id: u64,
+    pub user_id: Principal,
+    pub transaction_id: u64,
+    pub amount: u64, // kobo, or tokens for token payments
+    pub currency: String,
+    pub method: String, // "paystack", "token"
+    pub reason: String,
+    pub status: String, // "requested", "processing", "refunded", "failed", "denied"
+    pub requested_at: u64,
+    pub reviewed_by: Option<Principal>,
+    pub reviewed_at: Option<u64>,
+    pub review_note: Option<String>, // the admin's reason, required when denying
+    pub ledger_block: Option<u64>,
+    pub error: Option<String>,
+    pub entitlement_changes: Vec<String>, // what was taken back once the refund went through
+    pub refunded_at: Option<u64>,
+}
+
+impl Storable for RefundRequest {
+    fn to_bytes(&self) -> Cow<[u8]> { Cow::Owned(serde_cbor::to_vec(&self).unwrap()) }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self { serde_cbor::from_slice(bytes.as_ref()).unwrap() }
+    const BOUND: Bound = Bound::Unbounded;
+}
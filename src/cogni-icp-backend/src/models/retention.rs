@@ -0,0 +1,73 @@
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use ic_stable_structures::storable::{Storable, Bound};
+use std::borrow::Cow;
+
+// Daily roll-up of LearningMetrics kept after the raw rows are pruned
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct MetricsAggregate {
+    pub user_id: Principal,
+    pub day: u64, // days since the Unix epoch on the user's local calendar
+    pub entries: u32,
+    pub time_spent_minutes: u64,
+    pub messages_sent: u64,
+    pub comprehension_sum: f64,
+    pub comprehension_samples: u32,
+    pub updated_at: u64,
+}
+
+impl Storable for MetricsAggregate {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct RetentionRunReport {
+    pub started_at: u64,
+    pub metrics_aggregated: u64,
+    pub metrics_deleted: u64,
+    pub aggregates_deleted: u64,
+    pub notifications_deleted: u64,
+    pub ai_diagnostics_deleted: u64,
+    pub skipped_on_hold: u64,
+    pub has_more: bool,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct CompactionRunReport {
+    pub started_at: u64,
+    pub lists_scanned: u64,
+    pub orphaned_lists_removed: u64, // message lists whose session no longer exists
+    pub empty_lists_removed: u64,
+    pub deliveries_removed: u64,
+    pub failed_replies_removed: u64, // failed placeholders past the failed_replies retention
+    pub reclaimed_bytes: u64,
+}
+
+// What delete_my_account or delete_user_admin removed, by kind of record
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct AccountDeletionSummary {
+    pub user_id: Principal,
+    pub tutors: u64,
+    pub tutor_courses: u64,
+    pub chat_sessions: u64,
+    pub chat_messages: u64,
+    pub knowledge_base_files: u64,
+    pub learning_progress: u64,
+    pub learning_metrics: u64,
+    pub module_completions: u64,
+    pub group_memberships: u64,
+    pub cohort_enrollments: u64,
+    pub connections: u64,
+    pub connection_requests: u64,
+    pub notifications: u64,
+    pub achievements: u64,
+    pub deleted_at: u64,
+}
@@ -0,0 +1,61 @@
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use crate::models::user::User;
+use crate::models::tutor::{Tutor, KnowledgeBaseFile, KnowledgeBaseLink, KnowledgeBaseFileVersion, ChatSession, ChatMessage};
+use crate::models::storage::StorageUsage;
+use crate::models::educator::EducatorVerification;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ShardingConfig {
+    pub registry_canister: Option<Principal>,
+    pub shards: Vec<ShardInfo>, // includes this canister when sharding is enabled
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ShardInfo {
+    pub canister_id: Principal,
+    pub accepting_new_users: bool,
+    pub added_at: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ShardRoute {
+    pub user_id: Principal,
+    pub canister_id: Principal,
+    pub is_local: bool,
+}
+
+// Everything a shard holds for one user, moved as a unit during migration
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct UserDataBundle {
+    pub user_id: Principal,
+    pub user: Option<User>,
+    pub tutors: Vec<Tutor>,
+    pub knowledge_base_files: Vec<KnowledgeBaseFile>,
+    pub chat_sessions: Vec<ChatSession>,
+    pub chat_messages: Vec<(String, Vec<ChatMessage>)>,
+    pub storage_usage: Option<StorageUsage>,
+    pub exported_at: u64,
+    #[serde(default)]
+    pub knowledge_base_links: Vec<KnowledgeBaseLink>,
+    #[serde(default)]
+    pub knowledge_base_file_versions: Vec<KnowledgeBaseFileVersion>,
+    #[serde(default)]
+    pub educator_verification: Option<EducatorVerification>,
+    #[serde(default)]
+    pub password_hash: Option<String>, // User's candid encoding leaves these out
+    #[serde(default)]
+    pub password_salt: Option<String>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct MigrationReport {
+    pub user_id: Principal,
+    pub source_canister: Principal,
+    pub target_canister: Principal,
+    pub tutors: u64,
+    pub chat_sessions: u64,
+    pub chat_messages: u64,
+    pub knowledge_base_files: u64,
+    pub registry_updated: bool,
+}
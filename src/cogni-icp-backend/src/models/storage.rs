@@ -0,0 +1,65 @@
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use ic_stable_structures::storable::{Storable, Bound};
+use std::borrow::Cow;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct StorageUsage {
+    pub message_bytes: u64,
+    pub knowledge_base_bytes: u64,
+    pub updated_at: u64,
+}
+
+impl StorageUsage {
+    pub fn total_bytes(&self) -> u64 {
+        self.message_bytes + self.knowledge_base_bytes
+    }
+}
+
+impl Storable for StorageUsage {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct StorageUsageReport {
+    pub user_id: Principal,
+    pub plan: String,
+    pub message_bytes: u64,
+    pub knowledge_base_bytes: u64,
+    pub total_bytes: u64,
+    pub quota_bytes: u64,
+    pub warning: Option<String>,
+}
+
+// One virtual memory from the stable memory registry
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct MemoryRegion {
+    pub memory_id: u8,
+    pub name: String,
+    pub range: String,
+    pub kind: String, // "map", "cell" or "retired"
+    pub size_bytes: u64,
+    pub entries: Option<u64>, // maps only
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct MemoryRangeUsage {
+    pub name: String,
+    pub first_id: u8,
+    pub last_id: u8,
+    pub used: u32,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct MemoryLayout {
+    pub regions: Vec<MemoryRegion>,
+    pub ranges: Vec<MemoryRangeUsage>, // reserved id ranges per subsystem
+}
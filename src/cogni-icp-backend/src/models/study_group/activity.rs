@@ -1,5 +1,7 @@
 use candid::{CandidType, Principal};
 use serde::{Deserialize, Serialize};
+use ic_stable_structures::storable::{Storable, Bound};
+use std::borrow::Cow;
 
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
 pub struct GroupActivity {
@@ -22,6 +24,35 @@ pub struct StudyResource {
     pub resource_url: Option<String>,
     pub content: Option<String>,
     pub created_at: u64,
+    #[serde(default)]
+    pub source_session_id: Option<String>, // set when published from a tutoring session
+}
+
+impl Storable for StudyResource {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// Generated study document awaiting the author's approval; lives on the heap only
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct SessionPublishDraft {
+    pub token: String,
+    pub user_id: Principal,
+    pub session_id: String,
+    pub group_id: u64,
+    pub title: String,
+    pub summary: String,
+    pub transcript: String,
+    pub ai_generated: bool,
+    pub created_at: u64,
+    pub expires_at: u64,
 }
 
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
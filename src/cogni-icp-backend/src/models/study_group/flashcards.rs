@@ -0,0 +1,91 @@
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use ic_stable_structures::storable::{Storable, Bound};
+use std::borrow::Cow;
+
+// A flashcard deck owned by a study group rather than a member
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct FlashcardDeck {
+    pub id: u64,
+    pub group_id: u64,
+    pub name: String,
+    pub description: Option<String>,
+    pub created_by: Principal,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+impl Storable for FlashcardDeck {
+    fn to_bytes(&self) -> Cow<[u8]> { Cow::Owned(serde_cbor::to_vec(&self).unwrap()) }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self { serde_cbor::from_slice(bytes.as_ref()).unwrap() }
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct DeckCard {
+    pub id: u64,
+    pub deck_id: u64,
+    pub group_id: u64,
+    pub front: String,
+    pub back: String,
+    pub contributed_by: Principal,
+    pub status: String, // "proposed" (waiting for a group admin), "approved", "rejected", "removed"
+    pub curated_by: Option<Principal>,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+impl Storable for DeckCard {
+    fn to_bytes(&self) -> Cow<[u8]> { Cow::Owned(serde_cbor::to_vec(&self).unwrap()) }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self { serde_cbor::from_slice(bytes.as_ref()).unwrap() }
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// One learner's SM-2 schedule for one card, keyed "{deck_id:020}:{principal}:{card_id:020}"
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct CardSchedule {
+    pub deck_id: u64,
+    pub card_id: u64,
+    pub user_id: Principal,
+    pub ease: u32, // in thousandths; starts at 2500
+    pub interval_days: u32,
+    pub repetitions: u32, // successful reviews in a row
+    pub reviews: u32,
+    pub lapses: u32, // reviews graded below 3
+    pub last_grade: u8,
+    pub last_reviewed_at: u64,
+    pub due_at: u64,
+}
+
+impl Storable for CardSchedule {
+    fn to_bytes(&self) -> Cow<[u8]> { Cow::Owned(serde_cbor::to_vec(&self).unwrap()) }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self { serde_cbor::from_slice(bytes.as_ref()).unwrap() }
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct DueCard {
+    pub card: DeckCard,
+    pub schedule: Option<CardSchedule>, // None for cards the learner hasn't seen
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct HardCard {
+    pub card_id: u64,
+    pub front: String,
+    pub learners: u32,
+    pub reviews: u32,
+    pub lapse_rate: f32, // lapses per review
+    pub average_ease: u32,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct DeckStats {
+    pub deck: FlashcardDeck,
+    pub approved_cards: u32,
+    pub proposed_cards: u32,
+    pub learners: u32, // active members who have reviewed at least one card
+    pub coverage: f32, // share of approved cards the average active member has reviewed, 0-1
+    pub due_now: u32, // reviews due across all learners
+    pub hardest: Vec<HardCard>,
+}
@@ -1,6 +1,7 @@
 pub mod activity;
 pub mod polls;
 pub mod sessions;
+pub mod flashcards;
 
 use candid::{CandidType, Principal};
 use serde::{Deserialize, Serialize};
@@ -22,6 +23,8 @@ pub struct StudyGroup {
     pub goals: Option<String>,
     pub created_at: u64,
     pub updated_at: u64,
+    #[serde(default)]
+    pub slug: Option<String>,
 }
 
 impl Storable for StudyGroup {
@@ -0,0 +1,33 @@
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use ic_stable_structures::storable::{Storable, Bound};
+use std::borrow::Cow;
+
+// A printable HTML document compiled from one session, keyed by an unguessable id that doubles
+// as the capability for its /study-pack/{id} route. The HTML is stored in STUDY_PACK_CHUNKS.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct StudyPack {
+    pub id: String,
+    pub session_id: String,
+    pub user_id: Principal,
+    pub title: String,
+    pub flashcards: Vec<Flashcard>,
+    pub explanations: u32, // bookmarked or highly rated tutor replies included
+    pub quiz_results: u32,
+    pub chunk_count: u32,
+    pub total_bytes: u64,
+    pub url_path: String,
+    pub created_at: u64,
+}
+
+impl Storable for StudyPack {
+    fn to_bytes(&self) -> Cow<[u8]> { Cow::Owned(serde_cbor::to_vec(&self).unwrap()) }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self { serde_cbor::from_slice(bytes.as_ref()).unwrap() }
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Flashcard {
+    pub front: String,
+    pub back: String,
+}
@@ -0,0 +1,51 @@
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use ic_stable_structures::storable::{Storable, Bound};
+use std::borrow::Cow;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct SupportTicket {
+    pub id: u64,
+    pub user_id: Principal,
+    pub category: String, // "billing", "technical", "account", "content", "other"
+    pub subject: String,
+    pub description: String,
+    pub related_entity_id: Option<String>,
+    pub status: String, // "open", "in_progress", "resolved"
+    pub messages: Vec<TicketMessage>,
+    pub created_at: u64,
+    pub updated_at: u64,
+    pub first_response_at: Option<u64>,
+    pub resolved_at: Option<u64>,
+}
+
+impl Storable for SupportTicket {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct TicketMessage {
+    pub id: u64,
+    pub author_id: Principal,
+    pub is_staff: bool,
+    pub content: String,
+    pub created_at: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct SupportMetrics {
+    pub open: u64,
+    pub in_progress: u64,
+    pub resolved: u64,
+    pub avg_first_response_secs: Option<u64>,
+    pub avg_resolution_secs: Option<u64>,
+    pub breaching_first_response_sla: u64,
+}
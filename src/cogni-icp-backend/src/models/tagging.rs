@@ -0,0 +1,60 @@
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+use ic_stable_structures::storable::{Storable, Bound};
+use std::borrow::Cow;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct TaggingConfig {
+    pub enabled: bool,
+    pub taxonomy: Vec<String>, // the only tags the AI may assign
+    pub min_confidence: f64, // AI tags below this wait in the admin review queue
+}
+
+impl Default for TaggingConfig {
+    fn default() -> Self {
+        TaggingConfig {
+            enabled: true,
+            taxonomy: [
+                "mathematics", "algebra", "calculus", "statistics", "physics", "chemistry", "biology",
+                "computer science", "programming", "web development", "data science", "machine learning",
+                "blockchain", "economics", "finance", "business", "marketing", "history", "geography",
+                "literature", "writing", "languages", "philosophy", "psychology", "music", "art", "design",
+                "health", "exam preparation", "study skills",
+            ]
+            .iter()
+            .map(|t| t.to_string())
+            .collect(),
+            min_confidence: 0.7,
+        }
+    }
+}
+
+// Tags for one tutor, cohort or study group, keyed by "{entity_type}:{entity_id}"
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct EntityTags {
+    pub entity_type: String, // "tutor", "cohort", "study_group"
+    pub entity_id: String,
+    pub tags: Vec<EntityTag>,
+    pub content_hash: String, // of the text last tagged; unchanged text is not re-tagged
+    pub tagged_at: u64,
+}
+
+impl Storable for EntityTags {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct EntityTag {
+    pub tag: String,
+    pub confidence: f64,
+    pub status: String, // "applied", "pending_review", "rejected"
+    pub reviewed: bool, // set once an admin has approved or rejected it; kept on re-tagging
+}
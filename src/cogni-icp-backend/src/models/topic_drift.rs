@@ -0,0 +1,48 @@
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+
+// Drift is rated by the AI from 0 (on the declared topic) to 1 (somewhere else entirely)
+// over the last window_messages messages, once every check_every_messages messages.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct TopicDriftConfig {
+    pub enabled: bool,
+    pub check_every_messages: u32,
+    pub window_messages: u32,
+    pub threshold: f32, // the tutor offers to refocus at or above this
+    pub reoffer_after_messages: u32, // quiet period after the learner answers an offer
+}
+
+impl Default for TopicDriftConfig {
+    fn default() -> Self {
+        TopicDriftConfig {
+            enabled: true,
+            check_every_messages: 8,
+            window_messages: 12,
+            threshold: 0.7,
+            reoffer_after_messages: 20,
+        }
+    }
+}
+
+// Kept on the session
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct TopicDrift {
+    pub score: f32,
+    pub emergent_topic: Option<String>,
+    pub checked_at: u64,
+    pub checked_message_count: u32, // messages in the session at the last check
+    pub offer_message_id: Option<String>, // the tutor's open refocus offer, if any
+    pub decided_message_count: u32, // messages in the session at the last decision
+    pub decisions: Vec<DriftDecision>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct DriftDecision {
+    pub action: String, // "refocus", "switch", "spawn" or "dismiss"
+    pub from_topic: String,
+    pub to_topic: Option<String>,
+    pub new_session_id: Option<String>, // for "spawn"
+    pub score: f32,
+    pub decided_at: u64,
+}
@@ -3,6 +3,10 @@ use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use ic_stable_structures::storable::{Storable, Bound};
 use std::borrow::Cow;
+use crate::models::curriculum::CourseAttribution;
+use crate::models::ai_providers::ModelParams;
+use crate::models::license::AttributionEntry;
+use crate::models::topic_drift::TopicDrift;
 
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
 pub struct Tutor {
@@ -21,15 +25,53 @@ pub struct Tutor {
     pub voice_settings: HashMap<String, String>,
     pub created_at: u64,
     pub updated_at: u64,
+    #[serde(default)]
+    pub default_topic: Option<String>,
+    #[serde(default)]
+    pub intake_questions: Vec<String>, // asked when a session starts; empty or 3-5 questions
+    #[serde(default = "default_welcome_mode")]
+    pub welcome_mode: String, // "ai", "async_ai", "template", "none"
+    #[serde(default)]
+    pub welcome_template: Option<String>, // falls back to the built-in template when unset
+    #[serde(default)]
+    pub slug: Option<String>,
+    #[serde(default)]
+    pub visibility: Visibility,
+    #[serde(default)]
+    pub kb_version_pins: Vec<KnowledgeBaseVersionPin>,
+    #[serde(default)]
+    pub high_stakes_domain: Option<String>, // "medicine", "law", "finance"; replies carry a disclaimer
+    #[serde(default)]
+    pub fact_check: bool, // second-pass verification of replies, for high-stakes tutors only
+    #[serde(default)]
+    pub model_params: Option<ModelParams>,
+    #[serde(default)]
+    pub license: Option<String>, // see models/license.rs; None is all rights reserved
+    #[serde(default)]
+    pub derived_from: Vec<AttributionEntry>, // set on copies of other creators' tutors
+}
+
+// Who besides the owner can read a tutor or session
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Visibility {
+    #[default]
+    Private,
+    Connections, // the owner's active connections
+    Group, // anyone sharing an active study group with the owner
+    Public,
+}
+
+fn default_welcome_mode() -> String {
+    "ai".to_string()
 }
 
 impl Storable for Tutor {
     fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+        Cow::Owned(crate::codec::encode(self))
     }
 
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+        crate::codec::decode(bytes.as_ref())
     }
 
     const BOUND: Bound = Bound::Unbounded;
@@ -80,6 +122,24 @@ pub struct TutorCourse {
     pub estimated_duration: String,
     pub created_at: u64,
     pub modules: Vec<CourseModule>,
+    #[serde(default)]
+    pub attribution: Option<CourseAttribution>, // set on courses imported from an existing curriculum
+    #[serde(default)]
+    pub license: Option<String>, // None follows the tutor's license
+    #[serde(default)]
+    pub derived_from: Vec<AttributionEntry>,
+}
+
+impl Storable for TutorCourse {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
 }
 
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
@@ -89,7 +149,7 @@ pub struct CourseModule {
     pub description: String,
     pub order: u32,
     pub content: Option<String>, // Storing as a JSON string
-    pub status: String, // "pending", "completed"
+    pub status: String, // "pending", "completed", "known" (skipped after a placement test)
 }
 
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
@@ -111,15 +171,60 @@ pub struct ChatSession {
     pub status: String, // "active", "completed", "archived"
     pub created_at: u64,
     pub updated_at: u64,
+    #[serde(default)]
+    pub summary: Option<String>, // rolling summary of messages pruned from the session
+    #[serde(default)]
+    pub intake_answers: Vec<IntakeAnswer>,
+    #[serde(default)]
+    pub visibility: Visibility,
+    #[serde(default)]
+    pub archived_at: Option<u64>, // set when the session is archived for inactivity
+    #[serde(default)]
+    pub co_learners: Vec<CoLearner>,
+    #[serde(default)]
+    pub owner_left_at: Option<u64>, // the owner stepped out while a co-learner carries on
+    #[serde(default)]
+    pub imported_from: Option<String>, // "chatgpt", "claude" or "generic"; imported sessions are read-only
+    #[serde(default)]
+    pub unread_count: u32, // for the caller, filled in by get_user_sessions; not kept up to date in storage
+    #[serde(default)]
+    pub topic_drift: Option<TopicDrift>,
+}
+
+// A connection invited into someone else's session
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct CoLearner {
+    pub user_id: Principal,
+    pub status: String, // "invited", "active", "declined", "left"
+    pub invited_at: u64,
+    pub joined_at: Option<u64>,
+    pub left_at: Option<u64>,
+}
+
+impl ChatSession {
+    pub fn active_co_learner(&self) -> Option<&CoLearner> {
+        self.co_learners.iter().find(|c| c.status == "active")
+    }
+
+    // Who may post: the owner unless they stepped out, and the active co-learner
+    pub fn is_participant(&self, user_id: Principal) -> bool {
+        (self.user_id == user_id && self.owner_left_at.is_none()) || self.active_co_learner().is_some_and(|c| c.user_id == user_id)
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct IntakeAnswer {
+    pub question: String,
+    pub answer: String,
 }
 
 impl Storable for ChatSession {
     fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+        Cow::Owned(crate::codec::encode(self))
     }
 
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+        crate::codec::decode(bytes.as_ref())
     }
 
     const BOUND: Bound = Bound::Unbounded;
@@ -133,15 +238,53 @@ pub struct ChatMessage {
     pub content: String,
     pub timestamp: u64,
     pub has_audio: Option<bool>,
+    #[serde(default = "default_delivery_status")]
+    pub delivery_status: String, // "pending", "queued", "deferred", "delivered", "failed"
+    #[serde(default)]
+    pub reading_grade: Option<f32>, // estimated US grade level of tutor replies
+    #[serde(default)]
+    pub author: Option<Principal>, // who sent a student message; matters once a session has co-learners
+    #[serde(default)]
+    pub citations: Vec<MessageCitation>,
+    #[serde(default)]
+    pub confidence: Option<f32>, // estimated for tutor replies, 0.0-1.0
+    #[serde(default)]
+    pub rating: Option<u8>, // learner's 1-5 rating of a tutor reply
+    #[serde(default)]
+    pub bookmarked: bool, // tutor replies the learner marked to keep, for study packs
+}
+
+// A knowledge base passage the client retrieved for a question and sends with the message
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct KnowledgeBaseExcerpt {
+    pub file_id: u64,
+    pub chunk_start: u32,
+    pub chunk_end: u32,
+    pub text: String,
+}
+
+// A source a tutor reply drew on; `marker` is the [n] used in the reply text
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct MessageCitation {
+    pub marker: u32,
+    pub file_id: u64,
+    pub file_name: String,
+    pub chunk_start: u32,
+    pub chunk_end: u32,
+    pub version: u32,
+}
+
+fn default_delivery_status() -> String {
+    "delivered".to_string()
 }
 
 impl Storable for ChatMessage {
     fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+        Cow::Owned(crate::codec::encode(self))
     }
 
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+        crate::codec::decode(bytes.as_ref())
     }
 
     const BOUND: Bound = Bound::Unbounded;
@@ -153,11 +296,11 @@ pub struct ChatMessageList(pub Vec<ChatMessage>);
 
 impl Storable for ChatMessageList {
     fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+        Cow::Owned(crate::codec::encode(self))
     }
 
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+        crate::codec::decode(bytes.as_ref())
     }
 
     const BOUND: Bound = Bound::Unbounded;
@@ -178,6 +321,51 @@ pub struct KnowledgeBaseFile {
     pub error_message: Option<String>,
     pub created_at: u64,
     pub updated_at: u64,
+    // Set when the owner deletes a file other tutors still link to; it is removed once unreferenced
+    #[serde(default)]
+    pub deleted_at: Option<u64>,
+    #[serde(default)]
+    pub version: u32, // 0 until an upload comes with chunk hashes
+}
+
+// One upload of a knowledge base file. Chunking and embedding happen off-canister, so only
+// the chunk hashes are kept, in document order.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct KnowledgeBaseFileVersion {
+    pub file_id: u64,
+    pub version: u32,
+    pub file_size: u64,
+    pub chunk_hashes: Vec<String>,
+    pub created_at: u64,
+}
+
+impl Storable for KnowledgeBaseFileVersion {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct KnowledgeBaseVersionPin {
+    pub file_id: u64,
+    pub version: u32,
+}
+
+// What the indexer has to change after an upload or a pin: embed the listed chunks of
+// `version` and drop chunks no current or pinned version uses any more
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct KnowledgeBaseReindexPlan {
+    pub file_id: u64,
+    pub version: u32,
+    pub embed_chunk_indexes: Vec<u32>,
+    pub drop_chunk_hashes: Vec<String>,
+    pub unchanged_chunks: u32,
 }
 
 impl Storable for KnowledgeBaseFile {
@@ -192,6 +380,46 @@ impl Storable for KnowledgeBaseFile {
     const BOUND: Bound = Bound::Unbounded;
 }
 
+// A tutor using another tutor's knowledge base by reference. Only the source tutor's own
+// files are shared, so links don't chain.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct KnowledgeBaseLink {
+    pub id: u64,
+    pub tutor_id: u64,
+    pub source_tutor_id: u64,
+    pub user_id: Principal,
+    pub excluded_file_ids: Vec<u64>,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+impl KnowledgeBaseLink {
+    pub fn includes(&self, file: &KnowledgeBaseFile) -> bool {
+        file.tutor_id == self.source_tutor_id && !self.excluded_file_ids.contains(&file.id)
+    }
+}
+
+impl Storable for KnowledgeBaseLink {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// A file in a tutor's effective knowledge base, with where it came from
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct TutorKnowledgeBaseEntry {
+    pub file: KnowledgeBaseFile,
+    pub link_id: Option<u64>,
+    pub source_tutor_id: Option<String>,
+    pub version: u32, // the pinned version, or the file's current one
+}
+
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
 pub struct LearningProgress {
     pub id: u64,
@@ -273,6 +501,8 @@ pub struct TopicSuggestion {
     pub description: String,
     pub difficulty: String, // "beginner", "intermediate", "advanced"
     pub expertise_area: String,
+    #[serde(default)]
+    pub degraded: bool, // templated because AI was unavailable
 }
 
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
@@ -281,6 +511,8 @@ pub struct TopicValidation {
     pub confidence: f64,
     pub reasoning: String,
     pub suggested_alternatives: Vec<String>,
+    #[serde(default)]
+    pub degraded: bool,
 }
 
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
@@ -291,6 +523,8 @@ pub struct CourseOutline {
     pub estimated_duration: String,
     pub difficulty_level: String,
     pub modules: Vec<CourseModule>,
+    #[serde(default)]
+    pub degraded: bool,
 }
 
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
@@ -298,4 +532,10 @@ pub struct ComprehensionAnalysis {
     pub comprehension_score: f64,
     pub difficulty_adjustment: String, // "simplify", "maintain", "deepen"
     pub timestamp: String,
+    #[serde(default)]
+    pub quota_warning: Option<String>,
+    #[serde(default)]
+    pub degraded: bool, // the reply is deferred until AI is available again
+    #[serde(default)]
+    pub pending_message_id: Option<String>, // set when the reply is still being generated
 } 
\ No newline at end of file
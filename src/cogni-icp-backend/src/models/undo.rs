@@ -0,0 +1,51 @@
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use ic_stable_structures::storable::{Storable, Bound};
+use std::borrow::Cow;
+use crate::models::tutor::{Tutor, ChatSession, ChatMessage};
+use crate::models::delivery::PendingDelivery;
+use crate::models::study_group::GroupMembership;
+use crate::models::connections::UserConnection;
+
+// A destructive action that can still be reversed until expires_at
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct UndoAction {
+    pub id: u64,
+    pub user_id: Principal,
+    pub action: String, // "delete_tutor", "delete_chat_session", "leave_group", "remove_connection"
+    pub target_id: String,
+    pub description: String,
+    pub created_at: u64,
+    pub expires_at: u64,
+}
+
+// Everything the action removed, so undo can put it back as it was
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum UndoSnapshot {
+    Tutor(Box<Tutor>), // boxed to keep the enum small
+    ChatSession {
+        session: Box<ChatSession>,
+        messages: Vec<ChatMessage>,
+        deliveries: Vec<PendingDelivery>,
+    },
+    GroupMembership(GroupMembership),
+    Connection(UserConnection),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StagedAction {
+    pub action: UndoAction,
+    pub snapshot: UndoSnapshot,
+}
+
+impl Storable for StagedAction {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
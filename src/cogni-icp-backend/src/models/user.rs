@@ -1,9 +1,10 @@
 use candid::{CandidType, Principal};
+use candid::types::{Serializer, Type};
 use serde::{Deserialize, Serialize};
 use ic_stable_structures::storable::{Storable, Bound};
 use std::borrow::Cow;
 
-#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct User {
     pub id: Principal,
     pub public_id: String,
@@ -25,13 +26,98 @@ pub struct User {
     pub blockchain_wallet_connected_at: Option<u64>,
     pub wallet_address: Option<String>, // Sui wallet
     pub public_key: Option<String>, // Sui public key
-    pub role: String, // "user", "tutor", "admin"
+    pub role: String, // "user", "moderator", "instructor" or "admin"; older records may say "tutor"
     pub status: String, // "active", "inactive", "suspended"
     pub location: Option<String>,
     pub subscription: String, // "free", "pro", "enterprise"
     pub last_active: u64,
     pub settings: UserSettings,
     pub password_hash: Option<String>, // For traditional email/password auth
+    #[serde(default)]
+    pub verified_educator: bool, // set only through the educator verification review
+    #[serde(default)]
+    pub billing_region: Option<String>, // ISO 3166 country code the user declared for pricing and tax
+    #[serde(default)]
+    pub password_salt: Option<String>, // hex, set alongside password_hash; None on legacy hashes
+}
+
+// Every endpoint that returns a User goes through this encoding, which leaves out the password
+// hash and salt. Shard migration carries them separately in UserDataBundle.
+#[derive(CandidType)]
+struct UserView<'a> {
+    id: &'a Principal,
+    public_id: &'a String,
+    email: &'a String,
+    username: &'a String,
+    first_name: &'a Option<String>,
+    last_name: &'a Option<String>,
+    is_active: bool,
+    is_verified: bool,
+    created_at: u64,
+    updated_at: u64,
+    last_login: Option<u64>,
+    oauth_provider: &'a Option<String>,
+    oauth_id: &'a Option<String>,
+    avatar_url: &'a Option<String>,
+    bio: &'a Option<String>,
+    blockchain_wallet_address: &'a Option<String>,
+    blockchain_wallet_type: &'a Option<String>,
+    blockchain_wallet_connected_at: Option<u64>,
+    wallet_address: &'a Option<String>,
+    public_key: &'a Option<String>,
+    role: &'a String,
+    status: &'a String,
+    location: &'a Option<String>,
+    subscription: &'a String,
+    last_active: u64,
+    settings: &'a UserSettings,
+    verified_educator: bool,
+    billing_region: &'a Option<String>,
+}
+
+impl<'a> From<&'a User> for UserView<'a> {
+    fn from(user: &'a User) -> Self {
+        UserView {
+            id: &user.id,
+            public_id: &user.public_id,
+            email: &user.email,
+            username: &user.username,
+            first_name: &user.first_name,
+            last_name: &user.last_name,
+            is_active: user.is_active,
+            is_verified: user.is_verified,
+            created_at: user.created_at,
+            updated_at: user.updated_at,
+            last_login: user.last_login,
+            oauth_provider: &user.oauth_provider,
+            oauth_id: &user.oauth_id,
+            avatar_url: &user.avatar_url,
+            bio: &user.bio,
+            blockchain_wallet_address: &user.blockchain_wallet_address,
+            blockchain_wallet_type: &user.blockchain_wallet_type,
+            blockchain_wallet_connected_at: user.blockchain_wallet_connected_at,
+            wallet_address: &user.wallet_address,
+            public_key: &user.public_key,
+            role: &user.role,
+            status: &user.status,
+            location: &user.location,
+            subscription: &user.subscription,
+            last_active: user.last_active,
+            settings: &user.settings,
+            verified_educator: user.verified_educator,
+            billing_region: &user.billing_region,
+        }
+    }
+}
+
+impl CandidType for User {
+    fn _ty() -> Type {
+        UserView::_ty()
+    }
+
+    fn idl_serialize<S: Serializer>(&self, serializer: S) -> Result<(), S::Error> {
+        UserView::from(self).idl_serialize(serializer)
+    }
 }
 
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
@@ -48,9 +134,85 @@ pub struct UserSettings {
     pub contrast: String,
     // AI Settings
     pub ai_interaction_style: String,
+    #[serde(default = "default_emoji_policy")]
+    pub ai_emoji_policy: String, // "allow", "none"
     // Privacy Settings
     pub profile_visibility: String,
     pub activity_sharing: String,
+    #[serde(default)]
+    pub allow_support_access: bool, // standing consent for admin read-only impersonation
+    #[serde(default = "default_timezone")]
+    pub timezone: String, // fixed UTC offset such as "UTC" or "+05:30"
+    #[serde(default)]
+    pub accessibility_mode: bool, // short paragraphs and described visuals in AI output
+    #[serde(default)]
+    pub dyslexia_friendly: bool,
+    #[serde(default)]
+    pub target_reading_level: Option<String>, // "grade_6", "grade_9", "college"
+    #[serde(default)]
+    pub quiet_hours: Option<QuietHours>,
+    #[serde(default)]
+    pub review_reminders_muted: bool,
+    #[serde(default)]
+    pub notification_preferences: NotificationPreferences,
+    #[serde(default)]
+    pub publish_activity: bool, // opt-in: completions, badges and published tutors post to the feed
+}
+
+// Local hours during which no reminders are sent and pushes are held; may wrap past midnight, e.g. 22 to 7
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct QuietHours {
+    pub start_hour: u8,
+    pub end_hour: u8,
+}
+
+impl QuietHours {
+    pub fn contains(&self, hour: u8) -> bool {
+        if self.start_hour <= self.end_hour {
+            (self.start_hour..self.end_hour).contains(&hour)
+        } else {
+            hour >= self.start_hour || hour < self.end_hour
+        }
+    }
+}
+
+// Per-source daily limits override the canister default; a limit of 0 sends everything from
+// that source to the digest. Digest-only users get a single daily summary instead.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct NotificationPreferences {
+    pub category_limits: Vec<CategoryLimit>,
+    pub digest_only: bool,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct CategoryLimit {
+    pub category: String, // a notification source such as "tutor" or "study_group"
+    pub max_per_day: u32,
+}
+
+impl NotificationPreferences {
+    pub fn limit_for(&self, category: &str) -> Option<u32> {
+        self.category_limits.iter().find(|l| l.category == category).map(|l| l.max_per_day)
+    }
+}
+
+fn default_timezone() -> String {
+    "UTC".to_string()
+}
+
+fn default_emoji_policy() -> String {
+    "allow".to_string()
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct DailyGoalProgress {
+    pub timezone: String,
+    pub day_start: u64, // local midnight at the start of today
+    pub resets_at: u64,
+    pub goal_minutes: u64,
+    pub minutes_spent: u64,
+    pub goal_met: bool,
+    pub streak_days: u32, // consecutive local days the goal was met, including today once met
 }
 
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
@@ -74,11 +236,11 @@ pub struct LoginSession {
 
 impl Storable for User {
     fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+        Cow::Owned(crate::codec::encode(self))
     }
 
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+        crate::codec::decode(bytes.as_ref())
     }
 
     const BOUND: Bound = Bound::Unbounded;
@@ -0,0 +1,59 @@
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use ic_stable_structures::storable::{Storable, Bound};
+use std::borrow::Cow;
+
+// Forwarding of xAPI statements to an institution's Learning Record Store
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct LrsConfig {
+    pub enabled: bool,
+    pub endpoint_url: String, // LRS base URL; statements are POSTed to {endpoint_url}/statements
+    pub authorization: String, // full Authorization header value, e.g. "Basic ..."
+    pub activity_base_iri: Option<String>, // defaults to this canister's URL
+    pub batch_size: u32,
+}
+
+impl Default for LrsConfig {
+    fn default() -> Self {
+        LrsConfig {
+            enabled: false,
+            endpoint_url: String::new(),
+            authorization: String::new(),
+            activity_base_iri: None,
+            batch_size: 50,
+        }
+    }
+}
+
+// A statement waiting to be sent to the LRS
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct XapiOutboxEntry {
+    pub statement_id: String,
+    pub user_id: Principal,
+    pub statement: String, // xAPI JSON
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    pub next_attempt_at: u64,
+    pub created_at: u64,
+}
+
+impl Storable for XapiOutboxEntry {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(serde_cbor::to_vec(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        serde_cbor::from_slice(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct LrsOutboxStatus {
+    pub queued: u64,
+    pub failing: u64, // entries that have failed at least once
+    pub oldest_queued_at: Option<u64>,
+    pub last_error: Option<String>,
+}
@@ -0,0 +1,76 @@
+// Password hashing for email/password accounts. Hashes are PBKDF2-HMAC-SHA256 over a random
+// per-user salt kept on the User record, stored as "pbkdf2-sha256$<iterations>$<hex>" so the
+// work factor can be raised later without breaking existing hashes. Accounts created before
+// this have an unsalted DefaultHasher hash and no salt; login_user verifies those the old
+// way once and replaces them, and does the same for hashes below the current work factor.
+
+use sha2::Sha256;
+
+const SCHEME: &str = "pbkdf2-sha256";
+// OWASP's figure for PBKDF2-HMAC-SHA256; a login spends a few billion instructions on it,
+// well inside the update call limit
+const ITERATIONS: u32 = 600_000;
+pub const SALT_BYTES: usize = 16;
+
+fn derive(password: &str, salt: &[u8], iterations: u32) -> Vec<u8> {
+    let mut out = [0u8; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, iterations, &mut out);
+    out.to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok()).collect()
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn legacy_hash(password: &str) -> String {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+    let mut hasher = DefaultHasher::new();
+    password.hash(&mut hasher);
+    format!("{:x}", hasher.finish())
+}
+
+// Returns the stored hash and the hex salt to keep next to it
+pub fn hash(password: &str, salt: &[u8]) -> (String, String) {
+    let derived = derive(password, salt, ITERATIONS);
+    (format!("{}${}${}", SCHEME, ITERATIONS, hex(&derived)), hex(salt))
+}
+
+fn is_legacy(hash: &str, salt: Option<&str>) -> bool {
+    salt.is_none() || !hash.starts_with(SCHEME)
+}
+
+// The iteration count and derived key of a current-scheme hash
+fn parse(hash: &str) -> Option<(u32, Vec<u8>)> {
+    let mut parts = hash.split('$');
+    let (Some(_), Some(iterations), Some(expected), None) = (parts.next(), parts.next(), parts.next(), parts.next()) else {
+        return None;
+    };
+    Some((iterations.parse().ok().filter(|i| *i > 0)?, unhex(expected)?))
+}
+
+// Legacy hashes and ones made with fewer iterations than ITERATIONS
+pub fn needs_rehash(hash: &str, salt: Option<&str>) -> bool {
+    is_legacy(hash, salt) || parse(hash).is_none_or(|(iterations, _)| iterations < ITERATIONS)
+}
+
+pub fn verify(password: &str, hash: &str, salt: Option<&str>) -> bool {
+    if is_legacy(hash, salt) {
+        return constant_time_eq(legacy_hash(password).as_bytes(), hash.as_bytes());
+    }
+    let (Some((iterations, expected)), Some(salt)) = (parse(hash), salt.and_then(unhex)) else {
+        return false;
+    };
+    constant_time_eq(&derive(password, &salt, iterations), &expected)
+}
@@ -0,0 +1,82 @@
+// Fixed-window rate limits on AI-backed update methods, so no principal can burn cycles on
+// provider outcalls by calling them in a loop. Counts are kept per principal and method class
+// in RATE_LIMITS and survive upgrades. Endpoints call check before doing any work; the
+// anonymous principal is one caller like any other and shares a single window.
+
+use candid::Principal;
+use crate::models::rate_limit::RateWindow;
+use crate::state::RATE_LIMITS;
+
+pub const RATE_LIMITED: &str = "rate limited";
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+fn key(class: &str, caller: Principal) -> String {
+    format!("{}:{}", class, caller)
+}
+
+pub fn check(caller: Principal, class: &str) -> Result<(), String> {
+    let config = crate::cache::config().rate_limits;
+    let Some(rule) = config.rules.iter().find(|r| r.class == class).filter(|_| config.enabled) else {
+        return Ok(());
+    };
+    let now = ic_cdk::api::time();
+    let window = rule.window_secs.max(1) as u64 * NANOS_PER_SECOND;
+    let key = key(class, caller);
+    
+    RATE_LIMITS.with(|limits| {
+        let mut limits = limits.borrow_mut();
+        let mut current = limits.get(&key)
+            .filter(|w| now.saturating_sub(w.window_start) < window)
+            .unwrap_or(RateWindow { window_start: now, count: 0 });
+        if current.count >= rule.max_calls {
+            return Err(RATE_LIMITED.to_string());
+        }
+        current.count += 1;
+        limits.insert(key, current);
+        Ok(())
+    })
+}
+
+// Moves a principal's open windows to the principal its account was linked to
+pub fn rekey(old: Principal, new: Principal) {
+    let suffix = format!(":{}", old);
+    RATE_LIMITS.with(|limits| {
+        let mut limits = limits.borrow_mut();
+        let owned: Vec<(String, RateWindow)> = limits.iter().filter(|(k, _)| k.ends_with(&suffix)).collect();
+        for (old_key, window) in owned {
+            limits.remove(&old_key);
+            limits.insert(key(&old_key[..old_key.len() - suffix.len()], new), window);
+        }
+    });
+}
+
+// Drops a deleted account's open windows
+pub fn remove(user_id: Principal) {
+    let suffix = format!(":{}", user_id);
+    RATE_LIMITS.with(|limits| {
+        let mut limits = limits.borrow_mut();
+        let owned: Vec<String> = limits.iter().filter(|(k, _)| k.ends_with(&suffix)).map(|(k, _)| k).collect();
+        for key in owned {
+            limits.remove(&key);
+        }
+    });
+}
+
+// Drops windows that have ended under every rule; removes at most `batch` per run
+pub fn prune(now: u64, batch: usize) {
+    let config = crate::cache::config().rate_limits;
+    let longest = config.rules.iter().map(|r| r.window_secs.max(1) as u64).max().unwrap_or(1) * NANOS_PER_SECOND;
+    let expired: Vec<String> = RATE_LIMITS.with(|limits| {
+        limits.borrow().iter()
+            .filter(|(_, w)| now.saturating_sub(w.window_start) >= longest)
+            .map(|(key, _)| key)
+            .take(batch)
+            .collect()
+    });
+    RATE_LIMITS.with(|limits| {
+        let mut limits = limits.borrow_mut();
+        for key in expired {
+            limits.remove(&key);
+        }
+    });
+}
@@ -0,0 +1,90 @@
+// Roles and the permissions they grant. User.role stays a string so existing records load
+// unchanged; anything unrecognised counts as a plain user, and the older "tutor" role reads
+// as instructor. Privileged endpoints check a permission with require() rather than
+// comparing role names, so a role can be widened without touching every call site.
+
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    User,
+    Moderator,
+    Instructor,
+    Admin,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Permission {
+    ManageSystem,        // canister config, AI providers, storage, sharding and integrations
+    ManageUsers,         // account status, retention holds and migrations
+    ManageRoles,
+    ImpersonateUsers,
+    ManageBilling,       // plans, grants, gifts, refunds and creator payouts
+    ModerateContent,     // tags, certificates, feedback, flagged replies and private groups
+    ManageSupport,
+    ReviewEducators,
+    ManageAnnouncements, // announcements and seasonal events
+    ManageInvites,
+    ManageExperiments,
+    ViewAuditLog,
+    CreateCohorts,
+}
+
+// Optional install and upgrade argument; listed principals are made admins
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct InitArgs {
+    pub admins: Vec<Principal>,
+}
+
+pub const ROLE_NAMES: [&str; 4] = ["user", "moderator", "instructor", "admin"];
+
+impl Role {
+    pub fn parse(name: &str) -> Option<Role> {
+        match name {
+            "user" => Some(Role::User),
+            "moderator" => Some(Role::Moderator),
+            "instructor" | "tutor" => Some(Role::Instructor),
+            "admin" => Some(Role::Admin),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::User => "user",
+            Role::Moderator => "moderator",
+            Role::Instructor => "instructor",
+            Role::Admin => "admin",
+        }
+    }
+
+    pub fn permissions(&self) -> Vec<Permission> {
+        use Permission::*;
+        match self {
+            Role::User => Vec::new(),
+            Role::Instructor => vec![CreateCohorts],
+            Role::Moderator => vec![ModerateContent, ManageSupport, ReviewEducators, ManageAnnouncements, ViewAuditLog],
+            Role::Admin => vec![
+                ManageSystem, ManageUsers, ManageRoles, ImpersonateUsers, ManageBilling, ModerateContent, ManageSupport,
+                ReviewEducators, ManageAnnouncements, ManageInvites, ManageExperiments, ViewAuditLog, CreateCohorts,
+            ],
+        }
+    }
+}
+
+pub fn role_of(principal: Principal) -> Role {
+    crate::cache::user(principal).and_then(|user| Role::parse(&user.role)).unwrap_or(Role::User)
+}
+
+pub fn has_permission(principal: Principal, permission: Permission) -> bool {
+    role_of(principal).permissions().contains(&permission)
+}
+
+pub fn require(principal: Principal, permission: Permission) -> Result<(), String> {
+    if has_permission(principal, permission) {
+        Ok(())
+    } else {
+        Err(format!("You don't have permission to perform this action ({:?} required)", permission))
+    }
+}
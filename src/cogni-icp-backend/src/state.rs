@@ -1,6 +1,6 @@
 use crate::models::{
     user::User,
-    tutor::{Tutor, TutorSession, LearningProgress, LearningMetrics, ModuleCompletion, KnowledgeBaseFile},
+    tutor::{Tutor, TutorSession, LearningProgress, LearningMetrics, ModuleCompletion, KnowledgeBaseFile, TutorCourse},
     learning_path::LearningPath,
     connections::{UserConnection, ConnectionRequest},
     study_group::{
@@ -11,9 +11,69 @@ use crate::models::{
     },
     billing::{SubscriptionPlan, UserSubscription, PaymentTransaction},
     gamification::{Achievement, UserAchievement, Task, UserTaskCompletion},
+    placement::PlacementTest,
+    certificate::Certificate,
+    announcements::{Announcement, AnnouncementDismissal},
+    support::SupportTicket,
+    notifications::Notification,
+    feedback::FeedbackItem,
+    retention::MetricsAggregate,
+    config::CanisterConfig,
+    storage::StorageUsage,
+    delivery::PendingDelivery,
+    audit::{AuditEntry, ImpersonationSession},
+    mastery::SkillProficiency,
+    exam::Exam,
+    cohort::{Cohort, CohortEnrollment, DiscussionPost},
+    tagging::EntityTags,
+    alias::IdAlias,
+    xapi::XapiOutboxEntry,
+    guest::GuestSession,
+    invite::{InviteCode, WaitlistEntry},
+    undo::StagedAction,
+    encoding::EncodingProgress,
+    certification::UserDataHash,
+    tutor::KnowledgeBaseLink,
+    tutor::KnowledgeBaseFileVersion,
+    confidence::LowConfidenceReply,
+    fact_check::FactCheckRecord,
+    billing::{GiftSubscription, PlanGrant, TrialRecord},
+    educator::EducatorVerification,
+    creator::{CreatorUsage, CreatorPayout},
+    experiment::{Experiment, ExperimentAssignment},
+    email::{TutorEmailAddress, EmailExchange},
+    bot_bridge::{BotBridge, BotLinkCode, BotLink, BotThread},
+    public_api::{ApiToken, ApiUsageDay},
+    embed::{EmbedToken, EmbedUsageDay},
+    refund::RefundRequest,
+    study_pack::StudyPack,
+    concept_map::ConceptMap,
+    glossary::GlossaryEntry,
+    ai_providers::AiCallDiagnostic,
+    read_position::ReadCursor,
+    auth_session::{AuthSession, PrincipalRekey},
+    study_group::flashcards::FlashcardDeck,
+    study_group::flashcards::DeckCard,
+    study_group::flashcards::CardSchedule,
+    event::SeasonalEvent,
+    event::EventParticipation,
+    mastery::ReviewReminder,
+    notifications::NotificationLedger,
+    rate_limit::RateWindow,
+    persona_eval::PersonaEvaluation,
+    daily_plan::PlanFraming,
+    milestone::Milestone,
+    activity::ActivityPost,
+    login_attempt::LoginAttempts,
+    focus::{FocusSession, FocusWeek},
+    daily_question::{DailyQuestion, DailyAnswer, DailyQuestionStats},
+    peer_tutoring::{PeerTutorProfile, PeerSlot, PeerBooking},
+    organization::{Organization, OrgMember, OrgImport},
+    learner_risk::LearnerRiskFlag,
 };
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
-use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, StableCell};
+use ic_stable_structures::{DefaultMemoryImpl, Memory as _, StableBTreeMap, StableCell};
+use crate::models::storage::{MemoryRegion, MemoryRangeUsage, MemoryLayout};
 use ic_stable_structures::storable::{Storable, Bound};
 use std::borrow::Cow;
 use std::cell::RefCell;
@@ -21,34 +81,224 @@ use candid::Principal;
 
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 
-const USER_MEMORY_ID: MemoryId = MemoryId::new(0);
-const TUTOR_MEMORY_ID: MemoryId = MemoryId::new(1);
-const TUTOR_SESSION_MEMORY_ID: MemoryId = MemoryId::new(2);
-const LEARNING_PATH_MEMORY_ID: MemoryId = MemoryId::new(3);
-const CONNECTION_MEMORY_ID: MemoryId = MemoryId::new(4);
-const CONNECTION_REQUEST_MEMORY_ID: MemoryId = MemoryId::new(5);
-const STUDY_GROUP_MEMORY_ID: MemoryId = MemoryId::new(6);
-const GROUP_MEMBERSHIP_MEMORY_ID: MemoryId = MemoryId::new(7);
-const SUBSCRIPTION_PLAN_MEMORY_ID: MemoryId = MemoryId::new(8);
-const USER_SUBSCRIPTION_MEMORY_ID: MemoryId = MemoryId::new(9);
-const PAYMENT_TRANSACTION_MEMORY_ID: MemoryId = MemoryId::new(10);
-const ACHIEVEMENT_MEMORY_ID: MemoryId = MemoryId::new(11);
-const USER_ACHIEVEMENT_MEMORY_ID: MemoryId = MemoryId::new(12);
-const TASK_MEMORY_ID: MemoryId = MemoryId::new(13);
-const USER_TASK_COMPLETION_MEMORY_ID: MemoryId = MemoryId::new(14);
-const MESSAGE_MEMORY_ID: MemoryId = MemoryId::new(15);
-const SESSION_MEMORY_ID: MemoryId = MemoryId::new(16);
-const CHAT_SESSION_MEMORY_ID: MemoryId = MemoryId::new(17);
-const CHAT_MESSAGE_MEMORY_ID: MemoryId = MemoryId::new(18);
-const LEARNING_PROGRESS_MEMORY_ID: MemoryId = MemoryId::new(19);
-const LEARNING_METRICS_MEMORY_ID: MemoryId = MemoryId::new(20);
-const MODULE_COMPLETION_MEMORY_ID: MemoryId = MemoryId::new(21);
-const KNOWLEDGE_BASE_FILE_MEMORY_ID: MemoryId = MemoryId::new(22);
-
-const ID_COUNTER_MEMORY_ID: MemoryId = MemoryId::new(30);
+const WASM_PAGE_SIZE: u64 = 65536;
+
+// Every virtual memory is listed once in the registry below. Duplicate ids fail to compile
+// (the variants are enum discriminants), and each id must fall inside the range of the
+// subsystem it belongs to. Retired ids stay listed so they are never handed out again.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemoryRange {
+    Core,
+    Analytics,
+    Integrations,
+    Sharding,
+    Scratch,
+}
+
+impl MemoryRange {
+    pub const ALL: [MemoryRange; 5] = [
+        MemoryRange::Core,
+        MemoryRange::Analytics,
+        MemoryRange::Integrations,
+        MemoryRange::Sharding,
+        MemoryRange::Scratch,
+    ];
+
+    // Inclusive; the memory manager supports ids up to 254
+    pub const fn bounds(self) -> (u8, u8) {
+        match self {
+            MemoryRange::Core => (0, 127),
+            MemoryRange::Analytics => (128, 159),
+            MemoryRange::Integrations => (160, 191),
+            MemoryRange::Sharding => (192, 223),
+            MemoryRange::Scratch => (224, 254),
+        }
+    }
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            MemoryRange::Core => "core",
+            MemoryRange::Analytics => "analytics",
+            MemoryRange::Integrations => "integrations",
+            MemoryRange::Sharding => "sharding",
+            MemoryRange::Scratch => "scratch",
+        }
+    }
+}
+
+macro_rules! memory_registry {
+    ($($variant:ident = $id:literal => $range:ident, $name:literal,)*) => {
+        #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+        #[repr(u8)]
+        pub enum StableMemory {
+            $($variant = $id,)*
+        }
+
+        impl StableMemory {
+            pub const ALL: &'static [StableMemory] = &[$(StableMemory::$variant,)*];
+
+            pub const fn id(self) -> MemoryId {
+                MemoryId::new(self as u8)
+            }
+
+            pub const fn range(self) -> MemoryRange {
+                match self {
+                    $(StableMemory::$variant => MemoryRange::$range,)*
+                }
+            }
+
+            pub const fn name(self) -> &'static str {
+                match self {
+                    $(StableMemory::$variant => $name,)*
+                }
+            }
+        }
+    };
+}
+
+memory_registry! {
+    Users = 0 => Core, "users",
+    Tutors = 1 => Core, "tutors",
+    TutorSessions = 2 => Core, "tutor_sessions",
+    LearningPaths = 3 => Core, "learning_paths",
+    Connections = 4 => Core, "connections",
+    ConnectionRequests = 5 => Core, "connection_requests",
+    StudyGroups = 6 => Core, "study_groups",
+    GroupMemberships = 7 => Core, "group_memberships",
+    SubscriptionPlans = 8 => Core, "subscription_plans",
+    UserSubscriptions = 9 => Core, "user_subscriptions",
+    PaymentTransactions = 10 => Core, "payment_transactions",
+    Achievements = 11 => Core, "achievements",
+    UserAchievements = 12 => Core, "user_achievements",
+    Tasks = 13 => Core, "tasks",
+    UserTaskCompletions = 14 => Core, "user_task_completions",
+    RetiredMessages = 15 => Core, "retired_messages",
+    RetiredSessions = 16 => Core, "retired_sessions",
+    ChatSessions = 17 => Core, "chat_sessions",
+    ChatMessages = 18 => Core, "chat_messages",
+    LearningProgress = 19 => Core, "learning_progress",
+    LearningMetrics = 20 => Core, "learning_metrics",
+    ModuleCompletions = 21 => Core, "module_completions",
+    KnowledgeBaseFiles = 22 => Core, "knowledge_base_files",
+    PlacementTests = 23 => Core, "placement_tests",
+    Certificates = 24 => Core, "certificates",
+    CertificateSigningKey = 25 => Core, "certificate_signing_key",
+    Announcements = 26 => Core, "announcements",
+    AnnouncementDismissals = 27 => Core, "announcement_dismissals",
+    SupportTickets = 28 => Core, "support_tickets",
+    Notifications = 29 => Core, "notifications",
+    IdCounters = 30 => Core, "id_counters",
+    FeedbackItems = 31 => Core, "feedback_items",
+    MetricsAggregates = 32 => Core, "metrics_aggregates",
+    Config = 33 => Core, "config",
+    StorageUsage = 34 => Core, "storage_usage",
+    UserShards = 35 => Core, "user_shards",
+    PendingDeliveries = 36 => Core, "pending_deliveries",
+    AiCallCounts = 37 => Core, "ai_call_counts",
+    AuditLog = 38 => Core, "audit_log",
+    ImpersonationSessions = 39 => Core, "impersonation_sessions",
+    StudyResources = 40 => Core, "study_resources",
+    SkillProficiency = 41 => Core, "skill_proficiency",
+    Exams = 42 => Core, "exams",
+    Cohorts = 43 => Core, "cohorts",
+    CohortEnrollments = 44 => Core, "cohort_enrollments",
+    DiscussionPosts = 45 => Core, "discussion_posts",
+    EntityTags = 46 => Core, "entity_tags",
+    TaggingQueue = 47 => Core, "tagging_queue",
+    IdAliases = 48 => Core, "id_aliases",
+    TutorCourses = 49 => Core, "tutor_courses",
+    XapiOutbox = 50 => Core, "xapi_outbox",
+    GuestSessions = 51 => Core, "guest_sessions",
+    InviteCodes = 52 => Core, "invite_codes",
+    Waitlist = 53 => Core, "waitlist",
+    UndoQueue = 54 => Core, "undo_queue",
+    ReencodeProgress = 55 => Core, "reencode_progress",
+    UserDataHashes = 56 => Core, "user_data_hashes",
+    CertifiedBuckets = 57 => Core, "certified_buckets",
+    KnowledgeBaseLinks = 58 => Core, "knowledge_base_links",
+    KnowledgeBaseFileVersions = 59 => Core, "knowledge_base_file_versions",
+    LowConfidenceReplies = 60 => Core, "low_confidence_replies",
+    FactChecks = 61 => Core, "fact_checks",
+    GiftSubscriptions = 62 => Core, "gift_subscriptions",
+    PlanGrants = 63 => Core, "plan_grants",
+    EducatorVerifications = 64 => Core, "educator_verifications",
+    CreatorUsage = 65 => Core, "creator_usage",
+    CreatorPayouts = 66 => Core, "creator_payouts",
+    TrialHistory = 67 => Core, "trial_history",
+    Experiments = 68 => Core, "experiments",
+    ExperimentAssignments = 69 => Core, "experiment_assignments",
+    RetiredTutorEmailAddresses = 70 => Core, "retired_tutor_email_addresses",
+    RetiredEmailExchanges = 71 => Core, "retired_email_exchanges",
+    RetiredBotBridges = 72 => Core, "retired_bot_bridges",
+    RetiredBotLinkCodes = 73 => Core, "retired_bot_link_codes",
+    RetiredBotLinks = 74 => Core, "retired_bot_links",
+    RetiredBotThreads = 75 => Core, "retired_bot_threads",
+    ApiTokens = 76 => Core, "api_tokens",
+    RetiredApiUsage = 77 => Core, "retired_api_usage",
+    EmbedTokens = 78 => Core, "embed_tokens",
+    RetiredEmbedUsage = 79 => Core, "retired_embed_usage",
+    RefundRequests = 80 => Core, "refund_requests",
+    StudyPacks = 81 => Core, "study_packs",
+    StudyPackChunks = 82 => Core, "study_pack_chunks",
+    ConceptMaps = 83 => Core, "concept_maps",
+    CourseGlossary = 84 => Core, "course_glossary",
+    RetiredAiDiagnostics = 85 => Core, "retired_ai_diagnostics",
+    ReadCursors = 86 => Core, "read_cursors",
+    AuthSessions = 87 => Core, "sessions",
+    FlashcardDecks = 88 => Core, "flashcard_decks",
+    DeckCards = 89 => Core, "deck_cards",
+    CardSchedules = 90 => Core, "card_schedules",
+    SeasonalEvents = 91 => Core, "seasonal_events",
+    EventParticipations = 92 => Core, "event_participation",
+    ReviewReminders = 93 => Core, "review_reminders",
+    OAuthIdentities = 94 => Core, "oauth_identities",
+    NotificationLedgers = 95 => Core, "notification_ledgers",
+    PushQueue = 96 => Core, "push_queue",
+    RateLimits = 97 => Core, "rate_limits",
+    PersonaEvaluations = 98 => Core, "persona_evaluations",
+    PlanFramings = 99 => Core, "plan_framings",
+    Milestones = 100 => Core, "milestones",
+    ActivityPosts = 101 => Core, "activity_posts",
+    LoginAttempts = 102 => Core, "login_attempts",
+    FocusSessions = 103 => Core, "focus_sessions",
+    FocusWeeks = 104 => Core, "focus_weeks",
+    DailyQuestions = 105 => Core, "daily_questions",
+    DailyAnswers = 106 => Core, "daily_answers",
+    DailyQuestionStats = 107 => Core, "daily_question_stats",
+    PeerTutors = 108 => Core, "peer_tutors",
+    PeerSlots = 109 => Core, "peer_slots",
+    PeerBookings = 110 => Core, "peer_bookings",
+    Organizations = 111 => Core, "organizations",
+    OrgMembers = 112 => Core, "org_members",
+    OrgImports = 113 => Core, "org_imports",
+    LearnerRiskFlags = 114 => Core, "learner_risk_flags",
+    PrincipalRekeys = 115 => Core, "principal_rekeys",
+    ApiUsage = 128 => Analytics, "api_usage",
+    EmbedUsage = 129 => Analytics, "embed_usage",
+    AiDiagnostics = 130 => Analytics, "ai_diagnostics",
+    TutorEmailAddresses = 160 => Integrations, "tutor_email_addresses",
+    EmailExchanges = 161 => Integrations, "email_exchanges",
+    BotBridges = 162 => Integrations, "bot_bridges",
+    BotLinkCodes = 163 => Integrations, "bot_link_codes",
+    BotLinks = 164 => Integrations, "bot_links",
+    BotThreads = 165 => Integrations, "bot_threads",
+    EmailQueue = 166 => Integrations, "email_queue",
+    EmailExpiry = 167 => Integrations, "email_expiry",
+}
+
+const _: () = {
+    let mut i = 0;
+    while i < StableMemory::ALL.len() {
+        let memory = StableMemory::ALL[i];
+        let (start, end) = memory.range().bounds();
+        assert!(memory as u8 >= start && memory as u8 <= end, "MemoryId outside its subsystem's reserved range");
+        i += 1;
+    }
+};
 
 
 #[derive(serde::Serialize, serde::Deserialize, Default, Clone)]
+#[serde(default)]
 struct IdCounters {
     user: u64,
     tutor: u64,
@@ -71,6 +321,44 @@ struct IdCounters {
     learning_metrics: u64,
     module_completion: u64,
     knowledge_base_file: u64,
+    placement_test: u64,
+    certificate: u64,
+    announcement: u64,
+    announcement_dismissal: u64,
+    support_ticket: u64,
+    notification: u64,
+    feedback_item: u64,
+    audit_entry: u64,
+    impersonation_session: u64,
+    study_resource: u64,
+    exam: u64,
+    cohort: u64,
+    cohort_enrollment: u64,
+    discussion_post: u64,
+    tutor_course: u64,
+    undo_action: u64,
+    knowledge_base_link: u64,
+    low_confidence_reply: u64,
+    gift_subscription: u64,
+    plan_grant: u64,
+    creator_payout: u64,
+    experiment: u64,
+    api_token: u64,
+    refund_request: u64,
+    ai_diagnostic: u64,
+    flashcard_deck: u64,
+    deck_card: u64,
+    seasonal_event: u64,
+    review_reminder: u64,
+    persona_evaluation: u64,
+    milestone: u64,
+    activity_post: u64,
+    focus_session: u64,
+    peer_slot: u64,
+    peer_booking: u64,
+    organization: u64,
+    org_import: u64,
+    learner_risk_flag: u64,
 }
 
 impl Storable for IdCounters {
@@ -93,149 +381,809 @@ thread_local! {
     // The users map stores User structs, keyed by their Principal.
     pub static USERS: RefCell<StableBTreeMap<Principal, User, Memory>> = RefCell::new(
         StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(USER_MEMORY_ID)),
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::Users.id())),
         )
     );
 
     // Stable storage for Tutors
     pub static TUTORS: RefCell<StableBTreeMap<u64, Tutor, Memory>> = RefCell::new(
         StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(TUTOR_MEMORY_ID)),
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::Tutors.id())),
         )
     );
 
     // Stable storage for Tutor Sessions
     pub static TUTOR_SESSIONS: RefCell<StableBTreeMap<u64, TutorSession, Memory>> = RefCell::new(
         StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(TUTOR_SESSION_MEMORY_ID)),
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::TutorSessions.id())),
         )
     );
 
     // Stable storage for Learning Paths
     pub static LEARNING_PATHS: RefCell<StableBTreeMap<u64, LearningPath, Memory>> = RefCell::new(
         StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(LEARNING_PATH_MEMORY_ID)),
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::LearningPaths.id())),
         )
     );
 
     // Stable storage for Connections
     pub static CONNECTIONS: RefCell<StableBTreeMap<u64, UserConnection, Memory>> = RefCell::new(
         StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(CONNECTION_MEMORY_ID)),
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::Connections.id())),
         )
     );
 
     // Stable storage for Connection Requests
     pub static CONNECTION_REQUESTS: RefCell<StableBTreeMap<u64, ConnectionRequest, Memory>> = RefCell::new(
         StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(CONNECTION_REQUEST_MEMORY_ID)),
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::ConnectionRequests.id())),
         )
     );
 
     // Stable storage for Study Groups
     pub static STUDY_GROUPS: RefCell<StableBTreeMap<u64, StudyGroup, Memory>> = RefCell::new(
         StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(STUDY_GROUP_MEMORY_ID)),
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::StudyGroups.id())),
         )
     );
 
     // Stable storage for Group Memberships
     pub static GROUP_MEMBERSHIPS: RefCell<StableBTreeMap<u64, GroupMembership, Memory>> = RefCell::new(
         StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(GROUP_MEMBERSHIP_MEMORY_ID)),
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::GroupMemberships.id())),
         )
     );
 
     // Stable storage for Billing
     pub static SUBSCRIPTION_PLANS: RefCell<StableBTreeMap<u64, SubscriptionPlan, Memory>> = RefCell::new(
         StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(SUBSCRIPTION_PLAN_MEMORY_ID)),
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::SubscriptionPlans.id())),
         )
     );
 
     pub static USER_SUBSCRIPTIONS: RefCell<StableBTreeMap<u64, UserSubscription, Memory>> = RefCell::new(
         StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(USER_SUBSCRIPTION_MEMORY_ID)),
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::UserSubscriptions.id())),
         )
     );
 
     pub static PAYMENT_TRANSACTIONS: RefCell<StableBTreeMap<u64, PaymentTransaction, Memory>> = RefCell::new(
         StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(PAYMENT_TRANSACTION_MEMORY_ID)),
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::PaymentTransactions.id())),
         )
     );
 
     // Stable storage for Gamification
     pub static ACHIEVEMENTS: RefCell<StableBTreeMap<u64, Achievement, Memory>> = RefCell::new(
         StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(ACHIEVEMENT_MEMORY_ID)),
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::Achievements.id())),
         )
     );
 
     pub static USER_ACHIEVEMENTS: RefCell<StableBTreeMap<u64, UserAchievement, Memory>> = RefCell::new(
         StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(USER_ACHIEVEMENT_MEMORY_ID)),
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::UserAchievements.id())),
         )
     );
 
     pub static TASKS: RefCell<StableBTreeMap<u64, Task, Memory>> = RefCell::new(
         StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(TASK_MEMORY_ID)),
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::Tasks.id())),
         )
     );
 
     pub static USER_TASK_COMPLETIONS: RefCell<StableBTreeMap<u64, UserTaskCompletion, Memory>> = RefCell::new(
         StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(USER_TASK_COMPLETION_MEMORY_ID)),
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::UserTaskCompletions.id())),
         )
     );
 
     // Stable storage for Chat Sessions
     pub static CHAT_SESSIONS: RefCell<StableBTreeMap<String, crate::models::tutor::ChatSession, Memory>> = RefCell::new(
         StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(CHAT_SESSION_MEMORY_ID)),
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::ChatSessions.id())),
         )
     );
 
     // Stable storage for Chat Messages
     pub static CHAT_MESSAGES: RefCell<StableBTreeMap<String, crate::models::tutor::ChatMessageList, Memory>> = RefCell::new(
         StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(CHAT_MESSAGE_MEMORY_ID)),
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::ChatMessages.id())),
         )
     );
 
     // Stable storage for Learning Progress
     pub static LEARNING_PROGRESS: RefCell<StableBTreeMap<u64, LearningProgress, Memory>> = RefCell::new(
         StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(LEARNING_PROGRESS_MEMORY_ID)),
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::LearningProgress.id())),
         )
     );
 
     // Stable storage for Learning Metrics
     pub static LEARNING_METRICS: RefCell<StableBTreeMap<u64, LearningMetrics, Memory>> = RefCell::new(
         StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(LEARNING_METRICS_MEMORY_ID)),
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::LearningMetrics.id())),
         )
     );
 
     // Stable storage for Module Completions
     pub static MODULE_COMPLETIONS: RefCell<StableBTreeMap<u64, ModuleCompletion, Memory>> = RefCell::new(
         StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MODULE_COMPLETION_MEMORY_ID)),
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::ModuleCompletions.id())),
         )
     );
 
     // Stable storage for Knowledge Base Files
     pub static KNOWLEDGE_BASE_FILES: RefCell<StableBTreeMap<u64, KnowledgeBaseFile, Memory>> = RefCell::new(
         StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(KNOWLEDGE_BASE_FILE_MEMORY_ID)),
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::KnowledgeBaseFiles.id())),
+        )
+    );
+
+    // Stable storage for Placement Tests
+    pub static PLACEMENT_TESTS: RefCell<StableBTreeMap<u64, PlacementTest, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::PlacementTests.id())),
+        )
+    );
+
+    // Stable storage for Certificates
+    pub static CERTIFICATES: RefCell<StableBTreeMap<u64, Certificate, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::Certificates.id())),
+        )
+    );
+
+    // Secret used to sign certificates, generated from raw_rand on first issuance
+    pub static CERTIFICATE_SIGNING_KEY: RefCell<StableCell<Vec<u8>, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::CertificateSigningKey.id())),
+            Vec::new()
+        ).expect("failed to init certificate signing key")
+    );
+
+    // Stable storage for Announcements
+    pub static ANNOUNCEMENTS: RefCell<StableBTreeMap<u64, Announcement, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::Announcements.id())),
+        )
+    );
+
+    // Stable storage for Announcement Dismissals
+    pub static ANNOUNCEMENT_DISMISSALS: RefCell<StableBTreeMap<u64, AnnouncementDismissal, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::AnnouncementDismissals.id())),
+        )
+    );
+
+    // Stable storage for Support Tickets
+    pub static SUPPORT_TICKETS: RefCell<StableBTreeMap<u64, SupportTicket, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::SupportTickets.id())),
+        )
+    );
+
+    // Stable storage for Notifications
+    pub static NOTIFICATIONS: RefCell<StableBTreeMap<u64, Notification, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::Notifications.id())),
+        )
+    );
+
+    // Stable storage for Feedback Items
+    pub static FEEDBACK_ITEMS: RefCell<StableBTreeMap<u64, FeedbackItem, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::FeedbackItems.id())),
+        )
+    );
+
+    // Stable storage for daily Metrics Aggregates, keyed by "{principal}:{day}"
+    pub static METRICS_AGGREGATES: RefCell<StableBTreeMap<String, MetricsAggregate, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::MetricsAggregates.id())),
+        )
+    );
+
+    // Stable cell for canister-wide configuration
+    pub static CONFIG: RefCell<StableCell<CanisterConfig, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::Config.id())),
+            CanisterConfig::default()
+        ).expect("failed to init config")
+    );
+
+    // Stable storage for per-user Storage Usage
+    pub static STORAGE_USAGE: RefCell<StableBTreeMap<Principal, StorageUsage, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::StorageUsage.id())),
+        )
+    );
+
+    // User to shard canister, recorded when a user is migrated off or onto this canister
+    pub static USER_SHARDS: RefCell<StableBTreeMap<Principal, Principal, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::UserShards.id())),
+        )
+    );
+
+    // Tutor replies awaiting delivery or retry
+    pub static PENDING_DELIVERIES: RefCell<StableBTreeMap<String, PendingDelivery, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::PendingDeliveries.id())),
+        )
+    );
+
+    // AI calls per user per day, keyed "{day:010}:{principal}" so old days sort first
+    pub static AI_CALL_COUNTS: RefCell<StableBTreeMap<String, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::AiCallCounts.id())),
+        )
+    );
+
+    // Append-only record of privileged actions
+    pub static AUDIT_LOG: RefCell<StableBTreeMap<u64, AuditEntry, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::AuditLog.id())),
+        )
+    );
+
+    // Admin impersonation requests and grants
+    pub static IMPERSONATION_SESSIONS: RefCell<StableBTreeMap<u64, ImpersonationSession, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::ImpersonationSessions.id())),
+        )
+    );
+
+    // Study group resource library
+    pub static STUDY_RESOURCES: RefCell<StableBTreeMap<u64, StudyResource, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::StudyResources.id())),
+        )
+    );
+
+    // Per-skill proficiency, keyed by "{principal}:{skill}"
+    pub static SKILL_PROFICIENCY: RefCell<StableBTreeMap<String, SkillProficiency, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::SkillProficiency.id())),
+        )
+    );
+
+    // Timed exams
+    pub static EXAMS: RefCell<StableBTreeMap<u64, Exam, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::Exams.id())),
+        )
+    );
+
+    // Cohort course runs
+    pub static COHORTS: RefCell<StableBTreeMap<u64, Cohort, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::Cohorts.id())),
+        )
+    );
+
+    // Cohort enrollments
+    pub static COHORT_ENROLLMENTS: RefCell<StableBTreeMap<u64, CohortEnrollment, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::CohortEnrollments.id())),
+        )
+    );
+
+    // Module discussion posts
+    pub static DISCUSSION_POSTS: RefCell<StableBTreeMap<u64, DiscussionPost, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::DiscussionPosts.id())),
+        )
+    );
+
+    // AI-assigned tags, keyed by "{entity_type}:{entity_id}"
+    pub static ENTITY_TAGS: RefCell<StableBTreeMap<String, EntityTags, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::EntityTags.id())),
+        )
+    );
+
+    // Entities waiting to be tagged, keyed like ENTITY_TAGS, with the time they are next due
+    pub static TAGGING_QUEUE: RefCell<StableBTreeMap<String, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::TaggingQueue.id())),
+        )
+    );
+
+    // Slugs and legacy public ids, keyed by "{entity_type}:{alias}"
+    pub static ID_ALIASES: RefCell<StableBTreeMap<String, IdAlias, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::IdAliases.id())),
+        )
+    );
+
+    // Courses imported from existing curricula, keyed by course id
+    pub static TUTOR_COURSES: RefCell<StableBTreeMap<u64, TutorCourse, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::TutorCourses.id())),
+        )
+    );
+
+    // xAPI statements waiting for delivery to the LRS, keyed by statement id
+    pub static XAPI_OUTBOX: RefCell<StableBTreeMap<String, XapiOutboxEntry, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::XapiOutbox.id())),
+        )
+    );
+
+    // Stable storage for guest demo sessions, keyed by token
+    pub static GUEST_SESSIONS: RefCell<StableBTreeMap<String, GuestSession, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::GuestSessions.id())),
+        )
+    );
+
+    // Stable storage for registration invite codes
+    pub static INVITE_CODES: RefCell<StableBTreeMap<String, InviteCode, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::InviteCodes.id())),
+        )
+    );
+
+    // Stable storage for the registration waitlist, keyed by lowercase email
+    pub static WAITLIST: RefCell<StableBTreeMap<String, WaitlistEntry, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::Waitlist.id())),
+        )
+    );
+
+    // Stable storage for destructive actions still inside their undo window
+    pub static UNDO_QUEUE: RefCell<StableBTreeMap<u64, StagedAction, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::UndoQueue.id())),
+        )
+    );
+
+    // Re-encoding progress per stable map
+    pub static REENCODE_PROGRESS: RefCell<StableBTreeMap<String, EncodingProgress, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::ReencodeProgress.id())),
+        )
+    );
+
+    // Certified message hashes per user, keyed by "<bucket hex>:<principal>"
+    pub static USER_DATA_HASHES: RefCell<StableBTreeMap<String, UserDataHash, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::UserDataHashes.id())),
+        )
+    );
+
+    // Hash of each certification bucket
+    pub static CERTIFIED_BUCKETS: RefCell<StableBTreeMap<u8, Vec<u8>, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::CertifiedBuckets.id())),
+        )
+    );
+
+    // Tutors using another tutor's knowledge base by reference
+    pub static KNOWLEDGE_BASE_LINKS: RefCell<StableBTreeMap<u64, KnowledgeBaseLink, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::KnowledgeBaseLinks.id())),
+        )
+    );
+
+    // Knowledge base file versions, keyed by zero-padded "file_id:version"
+    pub static KNOWLEDGE_BASE_FILE_VERSIONS: RefCell<StableBTreeMap<String, KnowledgeBaseFileVersion, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::KnowledgeBaseFileVersions.id())),
+        )
+    );
+
+    // Low-confidence tutor replies awaiting review
+    pub static LOW_CONFIDENCE_REPLIES: RefCell<StableBTreeMap<u64, LowConfidenceReply, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::LowConfidenceReplies.id())),
+        )
+    );
+
+    // Fact-check passes over high-stakes tutor replies, keyed by message id
+    pub static FACT_CHECKS: RefCell<StableBTreeMap<String, FactCheckRecord, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::FactChecks.id())),
+        )
+    );
+
+    // Plans bought for other users
+    pub static GIFT_SUBSCRIPTIONS: RefCell<StableBTreeMap<u64, GiftSubscription, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::GiftSubscriptions.id())),
+        )
+    );
+
+    // Plan time from gifts and comps, keyed by "principal:grant id"
+    pub static PLAN_GRANTS: RefCell<StableBTreeMap<String, PlanGrant, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::PlanGrants.id())),
+        )
+    );
+
+    // Educator verification applications, one per user
+    pub static EDUCATOR_VERIFICATIONS: RefCell<StableBTreeMap<Principal, EducatorVerification, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::EducatorVerifications.id())),
+        )
+    );
+
+    // Premium usage of published tutors, keyed {month}:{tutor public id}
+    pub static CREATOR_USAGE: RefCell<StableBTreeMap<String, CreatorUsage, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::CreatorUsage.id())),
+        )
+    );
+
+    // Monthly creator payouts
+    pub static CREATOR_PAYOUTS: RefCell<StableBTreeMap<u64, CreatorPayout, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::CreatorPayouts.id())),
+        )
+    );
+
+    // Trials started, by email and by principal
+    pub static TRIAL_HISTORY: RefCell<StableBTreeMap<String, TrialRecord, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::TrialHistory.id())),
+        )
+    );
+
+    // A/B experiments on tutor prompts and models
+    pub static EXPERIMENTS: RefCell<StableBTreeMap<u64, Experiment, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::Experiments.id())),
+        )
+    );
+
+    // Sticky experiment variants per learner, keyed {experiment id:020}:{principal}
+    pub static EXPERIMENT_ASSIGNMENTS: RefCell<StableBTreeMap<String, ExperimentAssignment, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::ExperimentAssignments.id())),
+        )
+    );
+
+    // Learners' tutor email addresses, keyed by token
+    pub static TUTOR_EMAIL_ADDRESSES: RefCell<StableBTreeMap<String, TutorEmailAddress, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::TutorEmailAddresses.id())),
+        )
+    );
+
+    // Inbound emails and their replies, keyed by the inbound Message-ID
+    pub static EMAIL_EXCHANGES: RefCell<StableBTreeMap<String, EmailExchange, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::EmailExchanges.id())),
+        )
+    );
+
+    // Queued replies keyed by next attempt time and inbound Message-ID; the value is the Message-ID
+    pub static EMAIL_QUEUE: RefCell<StableBTreeMap<String, String, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::EmailQueue.id())),
+        )
+    );
+
+    // Every exchange keyed by receipt time and inbound Message-ID, for expiry
+    pub static EMAIL_EXPIRY: RefCell<StableBTreeMap<String, String, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::EmailExpiry.id())),
+        )
+    );
+
+    // Chat bot bridges, keyed by name
+    pub static BOT_BRIDGES: RefCell<StableBTreeMap<String, BotBridge, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::BotBridges.id())),
+        )
+    );
+
+    // Pending bot account link codes, keyed by code
+    pub static BOT_LINK_CODES: RefCell<StableBTreeMap<String, BotLinkCode, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::BotLinkCodes.id())),
+        )
+    );
+
+    // Linked chat platform accounts, keyed by platform and external user id
+    pub static BOT_LINKS: RefCell<StableBTreeMap<String, BotLink, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::BotLinks.id())),
+        )
+    );
+
+    // Chat platform threads relaying to sessions, keyed by platform and thread id
+    pub static BOT_THREADS: RefCell<StableBTreeMap<String, BotThread, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::BotThreads.id())),
+        )
+    );
+
+    // Public API tokens
+    pub static API_TOKENS: RefCell<StableBTreeMap<u64, ApiToken, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::ApiTokens.id())),
+        )
+    );
+
+    // Public API usage per token and day
+    pub static API_USAGE: RefCell<StableBTreeMap<String, ApiUsageDay, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::ApiUsage.id())),
+        )
+    );
+
+    // Tutor embed tokens, keyed by token
+    pub static EMBED_TOKENS: RefCell<StableBTreeMap<String, EmbedToken, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::EmbedTokens.id())),
+        )
+    );
+
+    // Embed usage per token and day
+    pub static EMBED_USAGE: RefCell<StableBTreeMap<String, EmbedUsageDay, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::EmbedUsage.id())),
+        )
+    );
+
+    // Refund requests against payment transactions
+    pub static REFUND_REQUESTS: RefCell<StableBTreeMap<u64, RefundRequest, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::RefundRequests.id())),
+        )
+    );
+
+    // Printable study packs by id
+    pub static STUDY_PACKS: RefCell<StableBTreeMap<String, StudyPack, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::StudyPacks.id())),
+        )
+    );
+
+    // Study pack HTML in chunks, keyed "{pack_id}:{index:04}"
+    pub static STUDY_PACK_CHUNKS: RefCell<StableBTreeMap<String, String, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::StudyPackChunks.id())),
+        )
+    );
+
+    // Concept maps by "{principal}:{source_type}:{source_id}"
+    pub static CONCEPT_MAPS: RefCell<StableBTreeMap<String, ConceptMap, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::ConceptMaps.id())),
+        )
+    );
+
+    // Course glossary terms by "{course_id:020}:{term}"
+    pub static COURSE_GLOSSARY: RefCell<StableBTreeMap<String, GlossaryEntry, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::CourseGlossary.id())),
+        )
+    );
+
+    // AI call diagnostics by id, oldest first
+    pub static AI_DIAGNOSTICS: RefCell<StableBTreeMap<u64, AiCallDiagnostic, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::AiDiagnostics.id())),
+        )
+    );
+
+    // Per-device read positions by "{session_id}:{principal}:{device_id}"
+    pub static READ_CURSORS: RefCell<StableBTreeMap<String, ReadCursor, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::ReadCursors.id())),
+        )
+    );
+
+    // Password login tokens, keyed by token
+    pub static SESSIONS: RefCell<StableBTreeMap<String, AuthSession, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::AuthSessions.id())),
+        )
+    );
+
+    // Group-owned flashcard decks
+    pub static FLASHCARD_DECKS: RefCell<StableBTreeMap<u64, FlashcardDeck, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::FlashcardDecks.id())),
+        )
+    );
+
+    // Cards in group flashcard decks
+    pub static DECK_CARDS: RefCell<StableBTreeMap<u64, DeckCard, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::DeckCards.id())),
+        )
+    );
+
+    // Per-learner review schedules for deck cards
+    pub static CARD_SCHEDULES: RefCell<StableBTreeMap<String, CardSchedule, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::CardSchedules.id())),
+        )
+    );
+
+    // Seasonal events and limited-time challenges
+    pub static SEASONAL_EVENTS: RefCell<StableBTreeMap<u64, SeasonalEvent, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::SeasonalEvents.id())),
+        )
+    );
+
+    // Event points per user, keyed by event then principal
+    pub static EVENT_PARTICIPATION: RefCell<StableBTreeMap<String, EventParticipation, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::EventParticipations.id())),
+        )
+    );
+
+    // Forgetting-curve review reminders, keyed by user then id
+    pub static REVIEW_REMINDERS: RefCell<StableBTreeMap<String, ReviewReminder, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::ReviewReminders.id())),
+        )
+    );
+
+    // Verified provider identities, keyed "{provider}:{subject}"
+    pub static OAUTH_IDENTITIES: RefCell<StableBTreeMap<String, Principal, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::OAuthIdentities.id())),
+        )
+    );
+
+    // Per-user notification counts and held digest items
+    pub static NOTIFICATION_LEDGERS: RefCell<StableBTreeMap<Principal, NotificationLedger, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::NotificationLedgers.id())),
+        )
+    );
+
+    // Pushes waiting for the relay, keyed by due time and notification id
+    pub static PUSH_QUEUE: RefCell<StableBTreeMap<String, Principal, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::PushQueue.id())),
+        )
+    );
+
+    // Per-principal call counts for the current rate limit window
+    pub static RATE_LIMITS: RefCell<StableBTreeMap<String, RateWindow, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::RateLimits.id())),
+        )
+    );
+
+    // Personality consistency scores, keyed by tutor and evaluation id
+    pub static PERSONA_EVALUATIONS: RefCell<StableBTreeMap<String, PersonaEvaluation, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::PersonaEvaluations.id())),
+        )
+    );
+
+    // Today's AI framing of each user's daily plan
+    pub static PLAN_FRAMINGS: RefCell<StableBTreeMap<Principal, PlanFraming, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::PlanFramings.id())),
+        )
+    );
+
+    // Course completions and streaks, each with a shareable card
+    pub static MILESTONES: RefCell<StableBTreeMap<u64, Milestone, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::Milestones.id())),
+        )
+    );
+
+    // Posts shown in the author's connections' activity feeds
+    pub static ACTIVITY_POSTS: RefCell<StableBTreeMap<u64, ActivityPost, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::ActivityPosts.id())),
+        )
+    );
+
+    // Failed password logins and lockouts by email
+    pub static LOGIN_ATTEMPTS: RefCell<StableBTreeMap<String, LoginAttempts, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::LoginAttempts.id())),
+        )
+    );
+
+    // Focus sessions by user
+    pub static FOCUS_SESSIONS: RefCell<StableBTreeMap<String, FocusSession, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::FocusSessions.id())),
+        )
+    );
+
+    // Weekly focus totals by user
+    pub static FOCUS_WEEKS: RefCell<StableBTreeMap<String, FocusWeek, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::FocusWeeks.id())),
+        )
+    );
+
+    // Questions of the day by day and area
+    pub static DAILY_QUESTIONS: RefCell<StableBTreeMap<String, DailyQuestion, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::DailyQuestions.id())),
+        )
+    );
+
+    // Answers to questions of the day by user
+    pub static DAILY_ANSWERS: RefCell<StableBTreeMap<String, DailyAnswer, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::DailyAnswers.id())),
+        )
+    );
+
+    // Question of the day totals and streaks by user
+    pub static DAILY_QUESTION_STATS: RefCell<StableBTreeMap<Principal, DailyQuestionStats, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::DailyQuestionStats.id())),
+        )
+    );
+
+    // Peer tutoring offers by tutor
+    pub static PEER_TUTORS: RefCell<StableBTreeMap<Principal, PeerTutorProfile, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::PeerTutors.id())),
+        )
+    );
+
+    // Peer tutoring availability slots
+    pub static PEER_SLOTS: RefCell<StableBTreeMap<u64, PeerSlot, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::PeerSlots.id())),
+        )
+    );
+
+    // Peer tutoring bookings and their escrow
+    pub static PEER_BOOKINGS: RefCell<StableBTreeMap<u64, PeerBooking, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::PeerBookings.id())),
+        )
+    );
+
+    // Organizations using their own identity provider
+    pub static ORGANIZATIONS: RefCell<StableBTreeMap<u64, Organization, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::Organizations.id())),
+        )
+    );
+
+    // Organization members by external id
+    pub static ORG_MEMBERS: RefCell<StableBTreeMap<String, OrgMember, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::OrgMembers.id())),
+        )
+    );
+
+    // Organization member imports and their per-line results
+    pub static ORG_IMPORTS: RefCell<StableBTreeMap<u64, OrgImport, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::OrgImports.id())),
+        )
+    );
+
+    // At-risk learner flags and their intervention outcomes
+    pub static LEARNER_RISK_FLAGS: RefCell<StableBTreeMap<u64, LearnerRiskFlag, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::LearnerRiskFlags.id())),
+        )
+    );
+
+    // Linked accounts whose records are still moving to the new principal
+    pub static PRINCIPAL_REKEYS: RefCell<StableBTreeMap<Principal, PrincipalRekey, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::PrincipalRekeys.id())),
         )
     );
 
     // Stable cell for ID counters
     pub static ID_COUNTERS: RefCell<StableCell<IdCounters, Memory>> = RefCell::new(
         StableCell::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(ID_COUNTER_MEMORY_ID)),
+            MEMORY_MANAGER.with(|m| m.borrow().get(StableMemory::IdCounters.id())),
             IdCounters::default()
         ).expect("failed to init id counters")
     );
@@ -352,7 +1300,352 @@ pub fn next_id(entity: &str) -> u64 {
                 writer.set(current_counters).unwrap();
                 writer.get().knowledge_base_file
             }
+            "placement_test" => {
+                current_counters.placement_test += 1;
+                writer.set(current_counters).unwrap();
+                writer.get().placement_test
+            }
+            "certificate" => {
+                current_counters.certificate += 1;
+                writer.set(current_counters).unwrap();
+                writer.get().certificate
+            }
+            "announcement" => {
+                current_counters.announcement += 1;
+                writer.set(current_counters).unwrap();
+                writer.get().announcement
+            }
+            "announcement_dismissal" => {
+                current_counters.announcement_dismissal += 1;
+                writer.set(current_counters).unwrap();
+                writer.get().announcement_dismissal
+            }
+            "support_ticket" => {
+                current_counters.support_ticket += 1;
+                writer.set(current_counters).unwrap();
+                writer.get().support_ticket
+            }
+            "notification" => {
+                current_counters.notification += 1;
+                writer.set(current_counters).unwrap();
+                writer.get().notification
+            }
+            "feedback_item" => {
+                current_counters.feedback_item += 1;
+                writer.set(current_counters).unwrap();
+                writer.get().feedback_item
+            }
+            "audit_entry" => {
+                current_counters.audit_entry += 1;
+                writer.set(current_counters).unwrap();
+                writer.get().audit_entry
+            }
+            "impersonation_session" => {
+                current_counters.impersonation_session += 1;
+                writer.set(current_counters).unwrap();
+                writer.get().impersonation_session
+            }
+            "study_resource" => {
+                current_counters.study_resource += 1;
+                writer.set(current_counters).unwrap();
+                writer.get().study_resource
+            }
+            "exam" => {
+                current_counters.exam += 1;
+                writer.set(current_counters).unwrap();
+                writer.get().exam
+            }
+            "cohort" => {
+                current_counters.cohort += 1;
+                writer.set(current_counters).unwrap();
+                writer.get().cohort
+            }
+            "cohort_enrollment" => {
+                current_counters.cohort_enrollment += 1;
+                writer.set(current_counters).unwrap();
+                writer.get().cohort_enrollment
+            }
+            "discussion_post" => {
+                current_counters.discussion_post += 1;
+                writer.set(current_counters).unwrap();
+                writer.get().discussion_post
+            }
+            "tutor_course" => {
+                current_counters.tutor_course += 1;
+                writer.set(current_counters).unwrap();
+                writer.get().tutor_course
+            }
+            "undo_action" => {
+                current_counters.undo_action += 1;
+                writer.set(current_counters).unwrap();
+                writer.get().undo_action
+            }
+            "knowledge_base_link" => {
+                current_counters.knowledge_base_link += 1;
+                writer.set(current_counters).unwrap();
+                writer.get().knowledge_base_link
+            }
+            "low_confidence_reply" => {
+                current_counters.low_confidence_reply += 1;
+                writer.set(current_counters).unwrap();
+                writer.get().low_confidence_reply
+            }
+            "gift_subscription" => {
+                current_counters.gift_subscription += 1;
+                writer.set(current_counters).unwrap();
+                writer.get().gift_subscription
+            }
+            "plan_grant" => {
+                current_counters.plan_grant += 1;
+                writer.set(current_counters).unwrap();
+                writer.get().plan_grant
+            }
+            "creator_payout" => {
+                current_counters.creator_payout += 1;
+                writer.set(current_counters).unwrap();
+                writer.get().creator_payout
+            }
+            "experiment" => {
+                current_counters.experiment += 1;
+                writer.set(current_counters).unwrap();
+                writer.get().experiment
+            }
+            "api_token" => {
+                current_counters.api_token += 1;
+                writer.set(current_counters).unwrap();
+                writer.get().api_token
+            }
+            "refund_request" => {
+                current_counters.refund_request += 1;
+                writer.set(current_counters).unwrap();
+                writer.get().refund_request
+            }
+            "ai_diagnostic" => {
+                current_counters.ai_diagnostic += 1;
+                writer.set(current_counters).unwrap();
+                writer.get().ai_diagnostic
+            }
+            "flashcard_deck" => {
+                current_counters.flashcard_deck += 1;
+                writer.set(current_counters).unwrap();
+                writer.get().flashcard_deck
+            }
+            "deck_card" => {
+                current_counters.deck_card += 1;
+                writer.set(current_counters).unwrap();
+                writer.get().deck_card
+            }
+            "seasonal_event" => {
+                current_counters.seasonal_event += 1;
+                writer.set(current_counters).unwrap();
+                writer.get().seasonal_event
+            }
+            "review_reminder" => {
+                current_counters.review_reminder += 1;
+                writer.set(current_counters).unwrap();
+                writer.get().review_reminder
+            }
+            "persona_evaluation" => {
+                current_counters.persona_evaluation += 1;
+                writer.set(current_counters).unwrap();
+                writer.get().persona_evaluation
+            }
+            "milestone" => {
+                current_counters.milestone += 1;
+                writer.set(current_counters).unwrap();
+                writer.get().milestone
+            }
+            "activity_post" => {
+                current_counters.activity_post += 1;
+                writer.set(current_counters).unwrap();
+                writer.get().activity_post
+            }
+            "focus_session" => {
+                current_counters.focus_session += 1;
+                writer.set(current_counters).unwrap();
+                writer.get().focus_session
+            }
+            "peer_slot" => {
+                current_counters.peer_slot += 1;
+                writer.set(current_counters).unwrap();
+                writer.get().peer_slot
+            }
+            "peer_booking" => {
+                current_counters.peer_booking += 1;
+                writer.set(current_counters).unwrap();
+                writer.get().peer_booking
+            }
+            "organization" => {
+                current_counters.organization += 1;
+                writer.set(current_counters).unwrap();
+                writer.get().organization
+            }
+            "org_import" => {
+                current_counters.org_import += 1;
+                writer.set(current_counters).unwrap();
+                writer.get().org_import
+            }
+            "learner_risk_flag" => {
+                current_counters.learner_risk_flag += 1;
+                writer.set(current_counters).unwrap();
+                writer.get().learner_risk_flag
+            }
             _ => panic!("Unknown entity type for ID generation"),
         }
     })
-} 
\ No newline at end of file
+} 
+fn entry_count(memory: StableMemory) -> Option<u64> {
+    match memory {
+        StableMemory::Users => Some(USERS.with(|m| m.borrow().len())),
+        StableMemory::Tutors => Some(TUTORS.with(|m| m.borrow().len())),
+        StableMemory::TutorSessions => Some(TUTOR_SESSIONS.with(|m| m.borrow().len())),
+        StableMemory::LearningPaths => Some(LEARNING_PATHS.with(|m| m.borrow().len())),
+        StableMemory::Connections => Some(CONNECTIONS.with(|m| m.borrow().len())),
+        StableMemory::ConnectionRequests => Some(CONNECTION_REQUESTS.with(|m| m.borrow().len())),
+        StableMemory::StudyGroups => Some(STUDY_GROUPS.with(|m| m.borrow().len())),
+        StableMemory::GroupMemberships => Some(GROUP_MEMBERSHIPS.with(|m| m.borrow().len())),
+        StableMemory::SubscriptionPlans => Some(SUBSCRIPTION_PLANS.with(|m| m.borrow().len())),
+        StableMemory::UserSubscriptions => Some(USER_SUBSCRIPTIONS.with(|m| m.borrow().len())),
+        StableMemory::PaymentTransactions => Some(PAYMENT_TRANSACTIONS.with(|m| m.borrow().len())),
+        StableMemory::Achievements => Some(ACHIEVEMENTS.with(|m| m.borrow().len())),
+        StableMemory::UserAchievements => Some(USER_ACHIEVEMENTS.with(|m| m.borrow().len())),
+        StableMemory::Tasks => Some(TASKS.with(|m| m.borrow().len())),
+        StableMemory::UserTaskCompletions => Some(USER_TASK_COMPLETIONS.with(|m| m.borrow().len())),
+        StableMemory::ChatSessions => Some(CHAT_SESSIONS.with(|m| m.borrow().len())),
+        StableMemory::ChatMessages => Some(CHAT_MESSAGES.with(|m| m.borrow().len())),
+        StableMemory::LearningProgress => Some(LEARNING_PROGRESS.with(|m| m.borrow().len())),
+        StableMemory::LearningMetrics => Some(LEARNING_METRICS.with(|m| m.borrow().len())),
+        StableMemory::ModuleCompletions => Some(MODULE_COMPLETIONS.with(|m| m.borrow().len())),
+        StableMemory::KnowledgeBaseFiles => Some(KNOWLEDGE_BASE_FILES.with(|m| m.borrow().len())),
+        StableMemory::PlacementTests => Some(PLACEMENT_TESTS.with(|m| m.borrow().len())),
+        StableMemory::Certificates => Some(CERTIFICATES.with(|m| m.borrow().len())),
+        StableMemory::Announcements => Some(ANNOUNCEMENTS.with(|m| m.borrow().len())),
+        StableMemory::AnnouncementDismissals => Some(ANNOUNCEMENT_DISMISSALS.with(|m| m.borrow().len())),
+        StableMemory::SupportTickets => Some(SUPPORT_TICKETS.with(|m| m.borrow().len())),
+        StableMemory::Notifications => Some(NOTIFICATIONS.with(|m| m.borrow().len())),
+        StableMemory::FeedbackItems => Some(FEEDBACK_ITEMS.with(|m| m.borrow().len())),
+        StableMemory::MetricsAggregates => Some(METRICS_AGGREGATES.with(|m| m.borrow().len())),
+        StableMemory::StorageUsage => Some(STORAGE_USAGE.with(|m| m.borrow().len())),
+        StableMemory::UserShards => Some(USER_SHARDS.with(|m| m.borrow().len())),
+        StableMemory::PendingDeliveries => Some(PENDING_DELIVERIES.with(|m| m.borrow().len())),
+        StableMemory::AiCallCounts => Some(AI_CALL_COUNTS.with(|m| m.borrow().len())),
+        StableMemory::AuditLog => Some(AUDIT_LOG.with(|m| m.borrow().len())),
+        StableMemory::ImpersonationSessions => Some(IMPERSONATION_SESSIONS.with(|m| m.borrow().len())),
+        StableMemory::StudyResources => Some(STUDY_RESOURCES.with(|m| m.borrow().len())),
+        StableMemory::SkillProficiency => Some(SKILL_PROFICIENCY.with(|m| m.borrow().len())),
+        StableMemory::Exams => Some(EXAMS.with(|m| m.borrow().len())),
+        StableMemory::Cohorts => Some(COHORTS.with(|m| m.borrow().len())),
+        StableMemory::CohortEnrollments => Some(COHORT_ENROLLMENTS.with(|m| m.borrow().len())),
+        StableMemory::DiscussionPosts => Some(DISCUSSION_POSTS.with(|m| m.borrow().len())),
+        StableMemory::EntityTags => Some(ENTITY_TAGS.with(|m| m.borrow().len())),
+        StableMemory::TaggingQueue => Some(TAGGING_QUEUE.with(|m| m.borrow().len())),
+        StableMemory::IdAliases => Some(ID_ALIASES.with(|m| m.borrow().len())),
+        StableMemory::TutorCourses => Some(TUTOR_COURSES.with(|m| m.borrow().len())),
+        StableMemory::XapiOutbox => Some(XAPI_OUTBOX.with(|m| m.borrow().len())),
+        StableMemory::GuestSessions => Some(GUEST_SESSIONS.with(|m| m.borrow().len())),
+        StableMemory::InviteCodes => Some(INVITE_CODES.with(|m| m.borrow().len())),
+        StableMemory::Waitlist => Some(WAITLIST.with(|m| m.borrow().len())),
+        StableMemory::UndoQueue => Some(UNDO_QUEUE.with(|m| m.borrow().len())),
+        StableMemory::ReencodeProgress => Some(REENCODE_PROGRESS.with(|m| m.borrow().len())),
+        StableMemory::UserDataHashes => Some(USER_DATA_HASHES.with(|m| m.borrow().len())),
+        StableMemory::CertifiedBuckets => Some(CERTIFIED_BUCKETS.with(|m| m.borrow().len())),
+        StableMemory::KnowledgeBaseLinks => Some(KNOWLEDGE_BASE_LINKS.with(|m| m.borrow().len())),
+        StableMemory::KnowledgeBaseFileVersions => Some(KNOWLEDGE_BASE_FILE_VERSIONS.with(|m| m.borrow().len())),
+        StableMemory::LowConfidenceReplies => Some(LOW_CONFIDENCE_REPLIES.with(|m| m.borrow().len())),
+        StableMemory::FactChecks => Some(FACT_CHECKS.with(|m| m.borrow().len())),
+        StableMemory::GiftSubscriptions => Some(GIFT_SUBSCRIPTIONS.with(|m| m.borrow().len())),
+        StableMemory::PlanGrants => Some(PLAN_GRANTS.with(|m| m.borrow().len())),
+        StableMemory::EducatorVerifications => Some(EDUCATOR_VERIFICATIONS.with(|m| m.borrow().len())),
+        StableMemory::CreatorUsage => Some(CREATOR_USAGE.with(|m| m.borrow().len())),
+        StableMemory::CreatorPayouts => Some(CREATOR_PAYOUTS.with(|m| m.borrow().len())),
+        StableMemory::TrialHistory => Some(TRIAL_HISTORY.with(|m| m.borrow().len())),
+        StableMemory::Experiments => Some(EXPERIMENTS.with(|m| m.borrow().len())),
+        StableMemory::ExperimentAssignments => Some(EXPERIMENT_ASSIGNMENTS.with(|m| m.borrow().len())),
+        StableMemory::TutorEmailAddresses => Some(TUTOR_EMAIL_ADDRESSES.with(|m| m.borrow().len())),
+        StableMemory::EmailExchanges => Some(EMAIL_EXCHANGES.with(|m| m.borrow().len())),
+        StableMemory::EmailQueue => Some(EMAIL_QUEUE.with(|m| m.borrow().len())),
+        StableMemory::EmailExpiry => Some(EMAIL_EXPIRY.with(|m| m.borrow().len())),
+        StableMemory::BotBridges => Some(BOT_BRIDGES.with(|m| m.borrow().len())),
+        StableMemory::BotLinkCodes => Some(BOT_LINK_CODES.with(|m| m.borrow().len())),
+        StableMemory::BotLinks => Some(BOT_LINKS.with(|m| m.borrow().len())),
+        StableMemory::BotThreads => Some(BOT_THREADS.with(|m| m.borrow().len())),
+        StableMemory::ApiTokens => Some(API_TOKENS.with(|m| m.borrow().len())),
+        StableMemory::ApiUsage => Some(API_USAGE.with(|m| m.borrow().len())),
+        StableMemory::EmbedTokens => Some(EMBED_TOKENS.with(|m| m.borrow().len())),
+        StableMemory::EmbedUsage => Some(EMBED_USAGE.with(|m| m.borrow().len())),
+        StableMemory::RefundRequests => Some(REFUND_REQUESTS.with(|m| m.borrow().len())),
+        StableMemory::StudyPacks => Some(STUDY_PACKS.with(|m| m.borrow().len())),
+        StableMemory::StudyPackChunks => Some(STUDY_PACK_CHUNKS.with(|m| m.borrow().len())),
+        StableMemory::ConceptMaps => Some(CONCEPT_MAPS.with(|m| m.borrow().len())),
+        StableMemory::CourseGlossary => Some(COURSE_GLOSSARY.with(|m| m.borrow().len())),
+        StableMemory::AiDiagnostics => Some(AI_DIAGNOSTICS.with(|m| m.borrow().len())),
+        StableMemory::ReadCursors => Some(READ_CURSORS.with(|m| m.borrow().len())),
+        StableMemory::AuthSessions => Some(SESSIONS.with(|m| m.borrow().len())),
+        StableMemory::FlashcardDecks => Some(FLASHCARD_DECKS.with(|m| m.borrow().len())),
+        StableMemory::DeckCards => Some(DECK_CARDS.with(|m| m.borrow().len())),
+        StableMemory::CardSchedules => Some(CARD_SCHEDULES.with(|m| m.borrow().len())),
+        StableMemory::SeasonalEvents => Some(SEASONAL_EVENTS.with(|m| m.borrow().len())),
+        StableMemory::EventParticipations => Some(EVENT_PARTICIPATION.with(|m| m.borrow().len())),
+        StableMemory::ReviewReminders => Some(REVIEW_REMINDERS.with(|m| m.borrow().len())),
+        StableMemory::OAuthIdentities => Some(OAUTH_IDENTITIES.with(|m| m.borrow().len())),
+        StableMemory::NotificationLedgers => Some(NOTIFICATION_LEDGERS.with(|m| m.borrow().len())),
+        StableMemory::PushQueue => Some(PUSH_QUEUE.with(|m| m.borrow().len())),
+        StableMemory::RateLimits => Some(RATE_LIMITS.with(|m| m.borrow().len())),
+        StableMemory::PersonaEvaluations => Some(PERSONA_EVALUATIONS.with(|m| m.borrow().len())),
+        StableMemory::PlanFramings => Some(PLAN_FRAMINGS.with(|m| m.borrow().len())),
+        StableMemory::Milestones => Some(MILESTONES.with(|m| m.borrow().len())),
+        StableMemory::ActivityPosts => Some(ACTIVITY_POSTS.with(|m| m.borrow().len())),
+        StableMemory::LoginAttempts => Some(LOGIN_ATTEMPTS.with(|m| m.borrow().len())),
+        StableMemory::FocusSessions => Some(FOCUS_SESSIONS.with(|m| m.borrow().len())),
+        StableMemory::FocusWeeks => Some(FOCUS_WEEKS.with(|m| m.borrow().len())),
+        StableMemory::DailyQuestions => Some(DAILY_QUESTIONS.with(|m| m.borrow().len())),
+        StableMemory::DailyAnswers => Some(DAILY_ANSWERS.with(|m| m.borrow().len())),
+        StableMemory::DailyQuestionStats => Some(DAILY_QUESTION_STATS.with(|m| m.borrow().len())),
+        StableMemory::PeerTutors => Some(PEER_TUTORS.with(|m| m.borrow().len())),
+        StableMemory::PeerSlots => Some(PEER_SLOTS.with(|m| m.borrow().len())),
+        StableMemory::PeerBookings => Some(PEER_BOOKINGS.with(|m| m.borrow().len())),
+        StableMemory::Organizations => Some(ORGANIZATIONS.with(|m| m.borrow().len())),
+        StableMemory::OrgMembers => Some(ORG_MEMBERS.with(|m| m.borrow().len())),
+        StableMemory::OrgImports => Some(ORG_IMPORTS.with(|m| m.borrow().len())),
+        StableMemory::LearnerRiskFlags => Some(LEARNER_RISK_FLAGS.with(|m| m.borrow().len())),
+        StableMemory::PrincipalRekeys => Some(PRINCIPAL_REKEYS.with(|m| m.borrow().len())),
+        StableMemory::CertificateSigningKey | StableMemory::Config | StableMemory::IdCounters => None,
+        StableMemory::RetiredMessages | StableMemory::RetiredSessions
+        | StableMemory::RetiredTutorEmailAddresses | StableMemory::RetiredEmailExchanges | StableMemory::RetiredBotBridges | StableMemory::RetiredBotLinkCodes | StableMemory::RetiredBotLinks | StableMemory::RetiredBotThreads
+        | StableMemory::RetiredApiUsage
+        | StableMemory::RetiredEmbedUsage
+        | StableMemory::RetiredAiDiagnostics => None,
+    }
+}
+
+pub fn memory_layout() -> MemoryLayout {
+    let regions: Vec<MemoryRegion> = StableMemory::ALL.iter().map(|&memory| {
+        let kind = match memory {
+            StableMemory::CertificateSigningKey | StableMemory::Config | StableMemory::IdCounters => "cell",
+            StableMemory::RetiredMessages | StableMemory::RetiredSessions
+            | StableMemory::RetiredTutorEmailAddresses | StableMemory::RetiredEmailExchanges | StableMemory::RetiredBotBridges | StableMemory::RetiredBotLinkCodes | StableMemory::RetiredBotLinks | StableMemory::RetiredBotThreads
+            | StableMemory::RetiredApiUsage
+            | StableMemory::RetiredEmbedUsage
+            | StableMemory::RetiredAiDiagnostics => "retired",
+            _ => "map",
+        };
+        MemoryRegion {
+            memory_id: memory as u8,
+            name: memory.name().to_string(),
+            range: memory.range().name().to_string(),
+            kind: kind.to_string(),
+            size_bytes: MEMORY_MANAGER.with(|m| m.borrow().get(memory.id()).size()) * WASM_PAGE_SIZE,
+            entries: entry_count(memory),
+        }
+    }).collect();
+    let ranges = MemoryRange::ALL.iter().map(|&range| {
+        let (first_id, last_id) = range.bounds();
+        MemoryRangeUsage {
+            name: range.name().to_string(),
+            first_id,
+            last_id,
+            used: StableMemory::ALL.iter().filter(|m| m.range() == range).count() as u32,
+        }
+    }).collect();
+    MemoryLayout { regions, ranges }
+}
@@ -0,0 +1,123 @@
+// Conversions between raw IC timestamps and a user's local calendar days. Time zones are
+// fixed UTC offsets ("UTC", "+05:30", "-08:00"); there is no tz database on the canister,
+// so users in zones with daylight saving update their offset when the clocks change.
+
+use candid::Principal;
+
+pub const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+const NANOS_PER_MINUTE: i64 = 60 * 1_000_000_000;
+const MAX_OFFSET_MINUTES: i64 = 14 * 60;
+
+// Returns the offset from UTC in minutes, or an error for anything that isn't a valid offset
+pub fn parse_utc_offset(timezone: &str) -> Result<i64, String> {
+    let tz = timezone.trim();
+    let tz = tz.strip_prefix("UTC").or_else(|| tz.strip_prefix("GMT")).unwrap_or(tz);
+    if tz.is_empty() || tz == "Z" {
+        return Ok(0);
+    }
+
+    let invalid = || format!("'{}' is not a valid time zone; use an offset like +05:30 or -08:00", timezone);
+    let (sign, rest) = if let Some(rest) = tz.strip_prefix('+') {
+        (1, rest)
+    } else if let Some(rest) = tz.strip_prefix('-') {
+        (-1, rest)
+    } else {
+        return Err(invalid());
+    };
+    if !rest.chars().all(|c| c.is_ascii_digit() || c == ':') {
+        return Err(invalid());
+    }
+    let (hours, minutes) = match rest.split_once(':') {
+        Some(parts) => parts,
+        None if rest.len() == 4 => rest.split_at(2),
+        None => (rest, "0"),
+    };
+    let hours: i64 = hours.parse().map_err(|_| invalid())?;
+    let minutes: i64 = minutes.parse().map_err(|_| invalid())?;
+    let offset = hours * 60 + minutes;
+    if !(0..60).contains(&minutes) || !(0..=MAX_OFFSET_MINUTES).contains(&offset) {
+        return Err(invalid());
+    }
+    Ok(sign * offset)
+}
+
+// Offset in nanoseconds for a user; unknown users and unparsable settings fall back to UTC
+pub fn user_offset_ns(user_id: Principal) -> i64 {
+    crate::cache::user(user_id)
+        .and_then(|user| parse_utc_offset(&user.settings.timezone).ok())
+        .unwrap_or(0) * NANOS_PER_MINUTE
+}
+
+// Days since the Unix epoch on the user's local calendar
+pub fn local_day(timestamp: u64, offset_ns: i64) -> u64 {
+    timestamp.saturating_add_signed(offset_ns) / NANOS_PER_DAY
+}
+
+// Timestamp of local midnight at the start of `day`
+pub fn local_day_start(day: u64, offset_ns: i64) -> u64 {
+    (day * NANOS_PER_DAY).saturating_add_signed(-offset_ns)
+}
+
+pub fn next_local_midnight(timestamp: u64, offset_ns: i64) -> u64 {
+    local_day_start(local_day(timestamp, offset_ns) + 1, offset_ns)
+}
+
+// UTC timestamp as ISO 8601 with millisecond precision, e.g. "2024-05-01T09:30:00.000Z"
+pub fn iso8601(timestamp: u64) -> String {
+    let days = (timestamp / NANOS_PER_DAY) as i64;
+    let nanos_of_day = timestamp % NANOS_PER_DAY;
+    let seconds = nanos_of_day / 1_000_000_000;
+    let millis = nanos_of_day % 1_000_000_000 / 1_000_000;
+    
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year, month, day, seconds / 3600, seconds % 3600 / 60, seconds % 60, millis
+    )
+}
+
+// Parses ISO 8601 timestamps such as "2024-05-01T09:30:00Z" or "2024-05-01T09:30:00.123456+01:00"
+pub fn parse_iso8601(text: &str) -> Option<u64> {
+    let (date, rest) = text.trim().split_once('T')?;
+    let mut date = date.splitn(3, '-').map(|p| p.parse::<i64>().ok());
+    let (year, month, day) = (date.next()??, date.next()??, date.next()??);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    let (time, offset_minutes) = match rest.strip_suffix('Z') {
+        Some(time) => (time, 0),
+        None => match rest.rfind(['+', '-']) {
+            Some(at) => (&rest[..at], parse_utc_offset(&rest[at..]).ok()?),
+            None => (rest, 0),
+        },
+    };
+    let (time, fraction) = time.split_once('.').unwrap_or((time, ""));
+    let mut time = time.splitn(3, ':').map(|p| p.parse::<i64>().ok());
+    let (hours, minutes, seconds) = (time.next()??, time.next()??, time.next().unwrap_or(Some(0))?);
+    if !(0..24).contains(&hours) || !(0..60).contains(&minutes) || !(0..=60).contains(&seconds) {
+        return None;
+    }
+    let fraction = &fraction[..fraction.len().min(9)];
+    let nanos: u64 = if fraction.is_empty() { 0 } else { format!("{:0<9}", fraction).parse().ok()? };
+    
+    // Days since 1970-01-01 from the civil date, the inverse of iso8601 above
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+    
+    let total_seconds = days * 86_400 + hours * 3600 + minutes * 60 + seconds - offset_minutes * 60;
+    u64::try_from(total_seconds).ok().map(|s| s * 1_000_000_000 + nanos)
+}